@@ -163,6 +163,7 @@
               TEST_SYSTEMD = pkgs.systemd;
               nativeCheckInputs = with pkgs; [
                 binutils-unwrapped
+                dosfstools
                 sbsigntool
               ];
             };
@@ -170,6 +171,44 @@
 
           tool = toolCrane.package;
 
+          # The QEMU harness in rust/tests. It depends on the tool and on the test keys in
+          # nix/tests/fixtures, so it is built from the whole repository.
+          vmTestsArgs = {
+            pname = "lanzaboote-vm-tests";
+            src = lib.cleanSource ./.;
+            cargoLock = ./rust/tests/Cargo.lock;
+            cargoToml = ./rust/tests/Cargo.toml;
+            postUnpack = ''
+              cd $sourceRoot/rust/tests
+              sourceRoot="."
+            '';
+          };
+
+          vmTestsInitrd = pkgs.makeInitrd {
+            contents = [{
+              object = "${pkgs.pkgsStatic.busybox}/bin/busybox";
+              symlink = "/init";
+            }];
+          };
+
+          vmTests = craneLib.cargoTest (vmTestsArgs // {
+            cargoArtifacts = craneLib.buildDepsOnly vmTestsArgs;
+            cargoTestExtraArgs = "-- --ignored";
+            requiredSystemFeatures = [ "kvm" ];
+            nativeBuildInputs = with pkgs; [
+              binutils-unwrapped
+              qemu
+              sbsigntool
+              python3Packages.virt-firmware
+            ];
+
+            LANZABOOTE_STUB = "${stub}/bin/lanzaboote_stub.efi";
+            TEST_OVMF_CODE = "${(pkgs.OVMF.override { secureBoot = true; }).fd}/FV/OVMF_CODE.fd";
+            TEST_OVMF_VARS = "${(pkgs.OVMF.override { secureBoot = true; }).fd}/FV/OVMF_VARS.fd";
+            TEST_KERNEL = "${pkgs.linuxPackages.kernel}/${pkgs.stdenv.hostPlatform.linux-kernel.target}";
+            TEST_INITRD = "${vmTestsInitrd}/initrd";
+          });
+
           wrappedTool = pkgs.runCommand "lzbt"
             {
               nativeBuildInputs = [ pkgs.makeWrapper ];
@@ -180,8 +219,15 @@
             # Clean PATH to only contain what we need to do objcopy. Also
             # tell lanzatool where to find our UEFI binaries.
             makeWrapper ${tool}/bin/lzbt-systemd $out/bin/lzbt \
-              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
+              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.curl pkgs.openssl pkgs.sbsigntool ]} \
               --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
+
+            mkdir -p $out/share/bash-completion/completions $out/share/zsh/site-functions \
+              $out/share/fish/vendor_completions.d $out/share/man/man8
+            $out/bin/lzbt completions bash > $out/share/bash-completion/completions/lzbt
+            $out/bin/lzbt completions zsh > $out/share/zsh/site-functions/_lzbt
+            $out/bin/lzbt completions fish > $out/share/fish/vendor_completions.d/lzbt.fish
+            $out/bin/lzbt man > $out/share/man/man8/lzbt.8
           '';
         in
         {
@@ -201,6 +247,9 @@
             fatStubClippy = fatStubCrane.clippy;
             toolFmt = toolCrane.rustfmt;
             stubFmt = stubCrane.rustfmt;
+          } // lib.optionalAttrs (system == "x86_64-linux") {
+            # The harness only drives qemu-system-x86_64.
+            inherit vmTests;
           } // (import ./nix/tests {
             inherit pkgs;
             extraBaseModules = {
@@ -224,6 +273,7 @@
               # Needed for `cargo test` in rust/tool. We also need
               # TEST_SYSTEMD below for that.
               pkgs.sbsigntool
+              pkgs.dosfstools
             ];
 
             inputsFrom = [
@@ -15,6 +15,91 @@ let
   loaderConfigFile = loaderSettingsFormat.generate "loader.conf" cfg.settings;
 
   configurationLimit = if cfg.configurationLimit == null then 0 else cfg.configurationLimit;
+
+  # The stub can only display uncompressed BMP images, so convert everything else.
+  splashBmp =
+    if cfg.splash == null || hasSuffix ".bmp" (toLower (toString cfg.splash))
+    then cfg.splash
+    else
+      pkgs.runCommand "lanzaboote-splash.bmp" { nativeBuildInputs = [ pkgs.imagemagick ]; } ''
+        magick ${cfg.splash} BMP3:$out
+      '';
+
+  # A PKCS#12 bundle is passed as is, lzbt unpacks it in memory.
+  pkcs12Bundle = cfg.pkiBundle != null
+    && (hasSuffix ".p12" (toString cfg.pkiBundle) || hasSuffix ".pfx" (toString cfg.pkiBundle));
+
+  keyArgs =
+    if pkcs12Bundle then ''
+      --pki-bundle ${cfg.pkiBundle} \
+      ${optionalString (cfg.pkiBundlePasswordFile != null) "--pki-bundle-password-file ${cfg.pkiBundlePasswordFile}"} \
+    '' else ''
+      --public-key ${cfg.publicKeyFile} \
+      --private-key ${cfg.privateKeyFile} \
+    '';
+
+  # The options of `lzbt install`, which `lzbt daemon` takes as well.
+  #
+  # Use the system from the kernel's hostPlatform because this should always,
+  # even in the cross compilation case, be the right system.
+  installArgs = ''
+    --system ${config.boot.kernelPackages.stdenv.hostPlatform.system} \
+    --systemd ${config.systemd.package} \
+    --systemd-boot-loader-config ${loaderConfigFile} \
+    ${keyArgs}
+    ${optionalString (cfg.timestampUrl != null) "--timestamp-url ${lib.escapeShellArg cfg.timestampUrl}"} \
+    ${optionalString cfg.allowInvalidCertificate "--allow-invalid-certificate"} \
+    ${optionalString cfg.insecurePermissions "--insecure-permissions"} \
+    ${optionalString cfg.manifest "--manifest"} \
+    ${optionalString (cfg.cacheDirectory != null) "--cache-dir ${cfg.cacheDirectory}"} \
+    ${optionalString (cfg.cacheDirectory != null && cfg.blake3) "--blake3"} \
+    --configuration-limit ${toString configurationLimit} \
+    --wait \
+    ${optionalString cfg.simulateSecureBoot "--simulate-secure-boot"} \
+    ${optionalString cfg.firstStage "--first-stage"} \
+    ${optionalString (cfg.payloadPartition != null) "--payload-dir ${cfg.payloadPartition.mountPoint} --payload-partition ${lib.escapeShellArg cfg.payloadPartition.selector} ${concatMapStringsSep " " (driver: "--fs-driver ${driver}") cfg.payloadPartition.drivers}"} \
+    ${optionalString (cfg.payloadPartition != null && cfg.payloadPartition.luks.enable) "--payload-luks"} \
+    ${optionalString (cfg.payloadPartition != null && cfg.payloadPartition.luks.tpm2Handle != null) "--payload-luks-tpm2-handle ${cfg.payloadPartition.luks.tpm2Handle} --payload-luks-tpm2-pcrs ${concatMapStringsSep "+" toString cfg.payloadPartition.luks.tpm2Pcrs}"} \
+    --insecure-boot-policy ${cfg.insecureBootPolicy} \
+    --stub-verbosity ${cfg.stubVerbosity} \
+    --stub-locale ${cfg.stubLocale} \
+    --entry-title ${lib.escapeShellArg cfg.entryTitle} \
+    --entry-sort ${lib.escapeShellArg cfg.entrySort} \
+    --other-os ${cfg.otherOperatingSystems} \
+    ${optionalString cfg.clearScreen "--clear-screen"} \
+    ${optionalString cfg.serialConsole "--serial-console"} \
+    ${optionalString cfg.stubLogFile "--stub-log-file"} \
+    ${optionalString (splashBmp != null) "--splash ${splashBmp}"} \
+    ${optionalString cfg.recoveryEntries "--recovery-entries"} \
+    --revocation-list ${cfg.revocationList} \
+    ${optionalString cfg.safeUpgrades "--tentative"} \
+    ${optionalString cfg.abSlots.enable "--ab-slots --slot-tries ${toString cfg.abSlots.tries}"} \
+    ${optionalString cfg.namespaceByMachineId "--namespace-by-machine-id"} \
+    ${optionalString (cfg.watchdogTimeout != null) "--watchdog-timeout ${toString cfg.watchdogTimeout}"} \
+    ${concatMapStringsSep " " (certificate: "--authcert ${certificate}") (toList cfg.authorizedCertificate)} \
+    ${optionalString (cfg.authorizedCertificateThreshold != null) "--authcert-threshold ${toString cfg.authorizedCertificateThreshold}"} \
+    ${optionalString cfg.allowSmbiosCmdline "--allow-smbios-cmdline"} \
+    ${optionalString cfg.allowFwCfg "--allow-fw-cfg"} \
+    ${optionalString cfg.instanceMetadata "--instance-metadata"} \
+    ${optionalString cfg.refuseMismatchedResume "--refuse-mismatched-resume"} \
+    --clock-check ${cfg.clockCheck} \
+    --lockdown ${cfg.lockdown} \
+    ${optionalString cfg.moduleSigEnforce "--module-sig-enforce"} \
+    ${optionalString (cfg.requireSecureBoot != null) "--require-secure-boot=${cfg.requireSecureBoot}"} \
+    ${optionalString (cfg.firmwareUpdater != null) "--firmware-updater ${cfg.firmwareUpdater}"} \
+    ${optionalString (cfg.metricsFile != null) "--metrics-file ${cfg.metricsFile}"} \
+    ${lib.concatMapStringsSep " " (hook: "--hook pre-install=${hook}") cfg.hooks.preInstall} \
+    ${lib.concatMapStringsSep " " (hook: "--hook post-sign=${hook}") cfg.hooks.postSign} \
+    ${lib.concatMapStringsSep " " (hook: "--hook post-install=${hook}") cfg.hooks.postInstall} \
+    ${optionalString (cfg.attestationHook != null) "--attestation-hook ${cfg.attestationHook}"} \
+    ${optionalString (cfg.cmdlineFile != null) "--cmdline-file ${cfg.cmdlineFile}"} \
+    ${optionalString (cfg.cmdlineOverridesFile != null) "--cmdline-overrides ${cfg.cmdlineOverridesFile}"} \
+    ${optionalString (cfg.cmdlineDir != null) "--cmdline-dir ${cfg.cmdlineDir}"} \
+    ${lib.concatMapStringsSep " " (step: "--initrd-step ${lib.escapeShellArg step}") cfg.initrdSteps} \
+    ${optionalString (cfg.initrdKeyFile != null) "--initrd-key ${cfg.initrdKeyFile}"} \
+    --profile system \
+    ${config.boot.loader.efi.efiSysMountPoint}
+  '';
 in
 {
   options.boot.lanzaboote = {
@@ -22,6 +107,57 @@ in
 
     enrollKeys = mkEnableOption "Automatic enrollment of the keys using sbctl";
 
+    enrollMicrosoftKeys = mkOption {
+      type = types.enum [ "auto" "always" "never" ];
+      default = "auto";
+      description = ''
+        Whether {option}`boot.lanzaboote.enrollKeys` also enrolls Microsoft's
+        certificates. Option ROMs, e.g. of GPUs, contain UEFI drivers that are
+        usually signed by Microsoft. Without its certificates, the firmware
+        does not load them with Secure Boot enabled, which can leave the
+        machine without display output. With `auto`, they are enrolled if
+        `lzbt check-oprom` finds such option ROMs.
+      '';
+    };
+
+    enrollKeysWithWindows = mkOption {
+      type = types.enum [ "warn" "skip" ];
+      default = "warn";
+      description = ''
+        What {option}`boot.lanzaboote.enrollKeys` does if the Windows Boot
+        Manager is on the ESP. Enrolling the keys changes PCR 7, so BitLocker
+        asks for its recovery key on the next boot of Windows. With `warn`, the
+        keys are enrolled after printing the steps to suspend BitLocker or to
+        recover from it. With `skip`, the keys are not enrolled, so that they
+        can be enrolled manually after suspending BitLocker.
+      '';
+    };
+
+    simulateSecureBoot = mkEnableOption ''
+      enforcing the Secure Boot policy in the stub even if Secure Boot is disabled.
+      Hash mismatches stop the boot and the command line passed by the boot loader is ignored.
+      This is useful to validate a setup before enrolling keys
+    '';
+
+    firstStage = mkEnableOption ''
+      installing a first stage as the EFI fallback instead of systemd-boot.
+      The first stage is a signed stub that verifies the hash of systemd-boot
+      before starting it, for firmwares that only trust a single signed binary.
+      The firmware must boot the EFI fallback for this to protect systemd-boot
+    '';
+
+    insecureBootPolicy = mkOption {
+      type = types.enum [ "warn" "confirm" "refuse" ];
+      default = "warn";
+      description = ''
+        What the stub does if Secure Boot is disabled. `confirm` boots only
+        after a key press and `refuse` does not boot at all, which makes it
+        visible if someone disabled Secure Boot in the firmware settings. Only
+        set this once Secure Boot is enabled with your keys, or the machine
+        will not boot without someone at the keyboard.
+      '';
+    };
+
     configurationLimit = mkOption {
       default = config.boot.loader.systemd-boot.configurationLimit;
       defaultText = "config.boot.loader.systemd-boot.configurationLimit";
@@ -36,9 +172,569 @@ in
       '';
     };
 
+    stubVerbosity = mkOption {
+      type = types.enum [ "quiet" "normal" "debug" ];
+      default = "normal";
+      description = ''
+        How much the stub logs to the console. `quiet` only shows errors, which
+        together with {option}`boot.lanzaboote.clearScreen` being disabled
+        gives a flicker-free boot. `debug` shows verbose diagnostics.
+      '';
+    };
+
+    stubLocale = mkOption {
+      type = types.enum [ "en" "de" "fr" "es" ];
+      default = "en";
+      example = "de";
+      description = ''
+        The language of the messages that the stub displays when the boot
+        fails or needs attention, e.g. a hash mismatch or the confirmation of
+        {option}`boot.lanzaboote.insecureBootPolicy`. Diagnostics stay in
+        English.
+      '';
+    };
+
+    otherOperatingSystems = mkOption {
+      type = types.enum [ "ignore" "entries" "sign" ];
+      default = "ignore";
+      description = ''
+        How to provide boot entries for other operating systems on the ESP.
+        `entries` adds entries for other Linux installations, which
+        systemd-boot does not detect by itself. `sign` also signs copies of
+        their boot loaders, including the Windows Boot Manager, so that they
+        still boot if Microsoft's keys are not enrolled. Windows may then ask
+        for the BitLocker recovery key once.
+      '';
+    };
+
+    clearScreen = mkEnableOption ''
+      clearing the screen when the stub starts instead of keeping the firmware splash
+    '';
+
+    serialConsole = mkEnableOption ''
+      mirroring the output of the stub to all serial ports, even if the firmware
+      console already includes a serial terminal. Without it, the stub only
+      mirrors its output if the firmware console does not include one, e.g.
+      for IPMI Serial-over-LAN on headless servers
+    '';
+
+    stubLogFile = mkEnableOption ''
+      appending the log of the stub to `loader/lanzaboote-debug.log` on the ESP,
+      to analyze boots that end in a reset. The file is written once per boot
+      and capped at 64 KiB
+    '';
+
+    splash = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression "./logo.png";
+      description = ''
+        Image that the stub displays while the kernel is loaded. Images that
+        are not BMP files (e.g. PNG) are converted at build time. If this is
+        `null`, the logo of the firmware is kept on screen.
+      '';
+    };
+
+    recoveryEntries = mkEnableOption ''
+      an additional boot entry per generation that boots into `rescue.target`.
+      As the kernel command line cannot be edited at boot when Secure Boot is
+      active, this keeps a recovery option available
+    '';
+
+    securityVersion = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 2;
+      description = ''
+        Security version number (SVN) of this configuration for anti-rollback
+        protection. Increase it whenever a configuration fixes a vulnerability,
+        e.g. in the kernel, that older configurations must not be able to boot
+        into anymore. See {option}`boot.lanzaboote.minimumSecurityVersion`.
+      '';
+    };
+
+    minimumSecurityVersion = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 2;
+      description = ''
+        Once this configuration has booted, the firmware records this value
+        and generations with a lower {option}`boot.lanzaboote.securityVersion`
+        refuse to boot. The floor can never be lowered again, so only raise
+        this once the new configuration is known to work.
+      '';
+    };
+
+    cmdlineFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/etc/lanzaboote/cmdline";
+      description = ''
+        File with kernel parameters that are added to the command line of every
+        entry after the ones of the generation. Unlike
+        {option}`boot.kernelParams`, changing it only needs a reinstallation,
+        not a new generation. A missing file is ignored.
+      '';
+    };
+
+    cmdlineOverridesFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/etc/lanzaboote/cmdline-overrides";
+      description = ''
+        File with kernel parameters for some generations or specialisations,
+        added after the ones of {option}`boot.lanzaboote.cmdlineFile`. Lines
+        are `<generations> [<specialisation>]: <parameters>`, e.g.
+        `10- gaming: mitigations=off`, where `<generations>` is a generation, a
+        range or `*`. A missing file is ignored.
+      '';
+    };
+
+    cmdlineDir = mkOption {
+      type = types.nullOr types.str;
+      default = "/etc/lanzaboote/cmdline.d";
+      description = ''
+        Directory of `*.conf` fragments in the format of
+        {option}`boot.lanzaboote.cmdlineOverridesFile`, applied after it in the
+        order of their names. Generations whose command line changes are
+        assembled and signed again, which `lzbt install` reports before it
+        writes anything. A missing directory is ignored.
+      '';
+    };
+
+    verityRootHash = mkOption {
+      type = types.nullOr (types.strMatching "([0-9a-fA-F]{2})+");
+      default = null;
+      example = "0b1a2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9";
+      description = ''
+        dm-verity root hash of the root file system of an image-based system.
+        It is embedded into the signed stub and passed as `roothash=` on the
+        kernel command line, so that the integrity of the root file system
+        chains up to the Secure Boot signature. The stub restores it if addons
+        or SMBIOS try to replace it.
+      '';
+    };
+
+    migrateExistingBootloader = mkEnableOption ''
+      migrating from an existing systemd-boot or GRUB installation on the
+      ESP. The old entries are replaced by signed entries for all
+      generations. The old boot loader is backed up on the ESP, and can be
+      restored with `lzbt migrate restore`, until a Lanzaboote entry booted
+      successfully. Once it is removed, rebuilds install normally
+    '';
+
+    measureBootPhases = mkEnableOption ''
+      measuring the boot phases into PCR 11 with `lzbt pcrphase`, like systemd-pcrphase.
+      Secrets that are sealed to the value of PCR 11 after `enter-initrd`, e.g. of LUKS, can then
+      only be unsealed in the initrd. `lzbt attest-reference` prints the expected values.
+      This requires the systemd initrd
+    '';
+
+    safeUpgrades = mkEnableOption ''
+      booting new generations only once until they are confirmed. The last
+      generation that booted successfully stays the default entry and is kept
+      installed regardless of {option}`boot.lanzaboote.configurationLimit`.
+      A new generation becomes the default once `multi-user.target` is reached,
+      so a generation that fails to boot is left by simply rebooting
+    '';
+
+    abSlots = {
+      enable = mkEnableOption ''
+        image-style A/B updates for appliances. The newest generation is also
+        installed into one of two slots, and systemd-boot starts a selector that
+        boots the active slot. A new generation goes into the inactive slot,
+        which becomes active for {option}`boot.lanzaboote.abSlots.tries` boots.
+        It is confirmed once `multi-user.target` is reached. Otherwise, the
+        selector falls back to the other slot for good
+      '';
+
+      tries = mkOption {
+        type = types.ints.between 1 255;
+        default = 3;
+        description = ''
+          How often a new slot is booted until it must be confirmed. Combine
+          this with {option}`boot.lanzaboote.watchdogTimeout` to also fall back
+          from kernels that hang.
+        '';
+      };
+    };
+
+    namespaceByMachineId = mkEnableOption ''
+      namespacing the kernels, initrds and entries on the ESP by the machine ID,
+      like `bootctl` does with its entry token. Enable this on every NixOS
+      installation that shares the ESP, e.g. for multi-boot, so that they do
+      not replace or garbage collect each other's files. The machine ID must
+      be stable, see {option}`environment.etc."machine-id"`
+    '';
+
+    watchdogTimeout = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 60;
+      description = ''
+        Timeout in seconds of the firmware watchdog that the stub arms right
+        before it starts the kernel. A kernel that hangs before it exits boot
+        services then resets the machine, which together with
+        {option}`boot.lanzaboote.safeUpgrades` boots the previous generation.
+        As the firmware stops the watchdog when the kernel exits boot services,
+        later hangs need a hardware watchdog, e.g. `systemd.watchdog.runtimeTime`.
+        If this is `null`, the watchdog of the firmware is kept.
+      '';
+    };
+
+    authorizedCertificate = mkOption {
+      type = types.nullOr (types.either types.path (types.listOf types.path));
+      default = null;
+      example = "/var/lib/sbctl/keys/addons/addons.pem";
+      description = ''
+        Certificate (PEM or DER), or a list of them, that is pinned in the
+        stub. Addons and credentials must then be signed with the key of one
+        of these certificates, independent of the Secure Boot database.
+        Credentials are signed by a detached PKCS#7 signature next to them,
+        e.g. `foo.cred.p7s`. If this is `null`, addons are verified by the
+        firmware and credentials are not verified.
+      '';
+    };
+
+    authorizedCertificateThreshold = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 2;
+      description = ''
+        How many of the `authorizedCertificate`s must have signed an addon
+        or a credential. By default, one of them is enough.
+      '';
+    };
+
+    allowSmbiosCmdline = mkEnableOption ''
+      extending the kernel command line with the
+      `io.systemd.stub.kernel-cmdline-extra` SMBIOS OEM string even if Secure
+      Boot is active. This allows VMs that share a signed image to receive
+      per-instance parameters from the hypervisor, e.g. with QEMU's
+      `-smbios type=11,value=io.systemd.stub.kernel-cmdline-extra=...`.
+      Anyone who controls the SMBIOS tables can then add kernel parameters.
+      Without Secure Boot, the OEM string is always honored
+    '';
+
+    allowFwCfg = mkEnableOption ''
+      taking the kernel command line and an additional initrd from QEMU's
+      fw_cfg even if Secure Boot is active. This lets the host boot arbitrary
+      configurations, so only enable it in development and CI VMs. Without
+      Secure Boot, fw_cfg is always honored
+    '';
+
+    instanceMetadata = mkEnableOption ''
+      passing the metadata of the cloud instance to the initrd as
+      credentials: the SMBIOS serial number as `instance.serial`, and from an
+      OpenStack config drive the metadata, the instance ID, the host name and
+      the SSH keys of root. The metadata is not authenticated
+    '';
+
+    refuseMismatchedResume = mkEnableOption ''
+      refusing to boot a generation whose kernel differs from the one that
+      the system hibernated with. Resuming with a different kernel can
+      corrupt the file systems. By default, the stub only warns and waits a
+      few seconds before it boots such a generation. In both cases, you can
+      pick the generation that the system hibernated with instead
+    '';
+
+    clockCheck = mkOption {
+      type = types.enum [ "off" "warn" "set" ];
+      default = "warn";
+      description = ''
+        What the stub does if the firmware clock is before the build date of
+        the generation, e.g. after the CMOS battery died. Such a clock breaks
+        certificates and time-based TPM policies. `warn` prints a warning and
+        `set` also moves the clock forward to the build date, which is still
+        behind the real time but close enough for most certificates.
+      '';
+    };
+
+    lockdown = mkOption {
+      type = types.enum [ "none" "integrity" "confidentiality" ];
+      default = "none";
+      description = ''
+        The kernel lockdown mode that the stub enforces. The stub adds
+        `lockdown=` to the command line again if the boot loader, SMBIOS or an
+        addon changed it, so the mode is enforced by the signature of the stub
+        rather than by convention.
+      '';
+    };
+
+    moduleSigEnforce = mkEnableOption ''
+      enforcing that the kernel only loads signed modules (`module.sig_enforce=1`) in the stub.
+      Only enable this with a kernel whose modules are signed, or no module will load
+    '';
+
+    requireSecureBoot = mkOption {
+      type = types.nullOr (types.enum [ "warn" "abort" ]);
+      default = null;
+      example = "abort";
+      description = ''
+        Whether to abort or warn when installing while Secure Boot is
+        disabled or the signing key is not enrolled in the firmware's db, e.g.
+        because it only contains Microsoft's keys. In the latter case, the
+        installed entries do not boot. Even with `null`, that case is warned
+        about. Only set this once the keys are enrolled.
+      '';
+    };
+
+    hooks = lib.genAttrs [ "preInstall" "postSign" "postInstall" ] (point: mkOption {
+      type = types.listOf types.path;
+      default = [ ];
+      description = ''
+        Programs to run at the ${point} hook point of `lzbt install`, in the
+        given order. They are called with the name of the hook point as their
+        argument and a JSON description of the planned generations, the signed
+        files, or the installed entries on stdin. A failing hook aborts the
+        installation.
+      '';
+    });
+
+    metricsFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/node_exporter/lanzaboote.prom";
+      description = ''
+        File to write Prometheus metrics about the installation to, e.g. for
+        the textfile collector of the node exporter. They include the
+        installed generations, the free space on the ESP, the time of the last
+        successful installation and the number of failed installations.
+      '';
+    };
+
+    daemon.enable = mkEnableOption ''
+      `lzbt daemon`, which installs and signs on behalf of other services on
+      the varlink socket `/run/lzbt.sock`, e.g. with
+      `varlinkctl call /run/lzbt.sock org.nixos.lanzaboote.Install '{}'`.
+      Only root may install and sign. Other users may query the Secure Boot
+      status, verify the signatures of the entries and reboot into another
+      generation if polkit authorizes them, e.g. from a desktop settings panel
+    '';
+
+    dbus.enable = mkEnableOption ''
+      the read-only D-Bus service `org.nixos.Lanzaboote` on the system bus,
+      which exposes the Secure Boot state, the installed generations and the
+      default entry as properties and verifies the signatures of the entries,
+      e.g. for desktop integration
+    '';
+
+    firmwareUpdater = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression ''"''${pkgs.fwupd-efi}/libexec/fwupd/efi/fwupdx64.efi"'';
+      description = ''
+        UEFI firmware updater, e.g. the one of fwupd, to install a signed boot
+        entry for, so that firmware updates keep working with Secure Boot
+        enabled. After fwupd staged an update, boot the entry once with
+        `lzbt firmware-update --updater`. Capsules can also be staged directly
+        with `lzbt firmware-update --stage`.
+      '';
+    };
+
+    initrdSteps = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      example = literalExpression ''
+        [ "prepend:''${pkgs.microcode-intel}/intel-ucode.img" ]
+      '';
+      description = ''
+        Steps that transform the initrd of every generation before it is
+        installed, in the given order. A step is `prepend:<archive>`,
+        `append:<archive>` or `exec:<program>`, where the program is called
+        with the path of the initrd and modifies it in place, e.g. to
+        recompress it. The hash embedded into the stub covers the result.
+        Initrd secrets are always appended last.
+      '';
+    };
+
+    initrdKeyFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/lanzaboote/initrd.key";
+      description = ''
+        Path of a 32-byte key with which the initrds on the ESP are
+        encrypted (AES-256-GCM), so that initrd secrets cannot be read from
+        the ESP without the machine. If the file does not exist, a new key is
+        created and provisioned in an EFI variable, which the stub moves into
+        a variable that only the firmware can read on the next boot. Keep the
+        key on an encrypted file system. This relies on Secure Boot: the
+        firmware hands the key to any boot-time code it starts.
+      '';
+    };
+
+    attestationHook = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression ''
+        pkgs.writeShellScript "update-keylime-policy" '''
+          keylime_tenant -c update -u "$(cat /etc/keylime-agent-uuid)" --tpm_policy "$(cat "$2")"
+        '''
+      '';
+      description = ''
+        Program that is run after every installation with the paths of the
+        attestation reference values of all installed entries, as printed by
+        `lzbt attest-reference`, and of a Keylime TPM policy that accepts all
+        of them. This can be used to push the expected PCR values to a remote
+        attestation verifier.
+      '';
+    };
+
+    revocationList = mkOption {
+      type = types.str;
+      default = "/var/lib/lanzaboote/revoked-hashes";
+      description = ''
+        Path of the list of revoked kernel and initrd hashes, one SHA256 hash in
+        hex per line. The list is embedded into every stub, which refuses to
+        boot revoked kernels and initrds even if they are correctly signed.
+        Generations using them are not installed. Manage the list with
+        `lzbt revoke`, which re-installs the boot loader afterwards.
+      '';
+    };
+
     pkiBundle = mkOption {
       type = types.nullOr types.path;
-      description = "PKI bundle containing db, PK, KEK";
+      description = ''
+        PKI bundle containing db, PK, KEK, or a password-protected PKCS#12
+        file (`.p12` or `.pfx`) with the db certificate and key to sign with.
+        A PKCS#12 file is unpacked in memory. Services that only verify
+        signatures still use `publicKeyFile`, which must then be set.
+      '';
+    };
+
+    timestampUrl = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "http://timestamp.digicert.com";
+      description = ''
+        RFC 3161 timestamp authority that timestamps the signatures of the
+        boot files, so that they stay verifiable after the signing
+        certificate expired. Installing new generations then requires
+        network access.
+      '';
+    };
+
+    payloadPartition = mkOption {
+      type = types.nullOr (types.submodule {
+        options = {
+          mountPoint = mkOption {
+            type = types.str;
+            example = "/boot-data";
+            description = "Where the partition is mounted.";
+          };
+          selector = mkOption {
+            type = types.str;
+            example = "PARTUUID=0fc63daf-8483-4772-8e79-3d69d8477de4";
+            description = ''
+              How the stub finds the partition, as `PARTUUID=<GUID>` or
+              `LABEL=<label>`.
+            '';
+          };
+          drivers = mkOption {
+            type = types.listOf types.path;
+            default = [ ];
+            example = literalExpression ''[ "''${pkgs.efifs}/share/efifs/ext2_x64.efi" ]'';
+            description = ''
+              UEFI file system drivers that the stub loads if the firmware
+              cannot read the file system of the partition, e.g. ext4 or
+              btrfs. They are signed and installed to the ESP.
+            '';
+          };
+          luks = {
+            enable = mkEnableOption ''
+              unlocking the partition, which is a LUKS2 volume, in the stub.
+              `mountPoint` is where the opened volume is mounted. The stub can
+              only open keyslots with the pbkdf2 KDF, which
+              `cryptsetup luksAddKey --pbkdf pbkdf2` adds
+            '';
+            tpm2Handle = mkOption {
+              type = types.nullOr types.str;
+              default = null;
+              example = "0x81000001";
+              description = ''
+                The persistent handle of a TPM2 object with the passphrase,
+                sealed to `tpm2Pcrs` without an authorization value. Without
+                it, or if unsealing fails, the stub asks for the passphrase.
+              '';
+            };
+            tpm2Pcrs = mkOption {
+              type = types.listOf types.int;
+              default = [ 7 ];
+              description = "The PCRs of the SHA256 bank that the object is sealed to.";
+            };
+          };
+        };
+      });
+      default = null;
+      description = ''
+        A partition to install the kernels and initrds to instead of the ESP,
+        e.g. because the ESP is too small. The stubs stay on the ESP and load
+        the kernel and initrd from this partition, whose file system the
+        firmware must be able to read, which usually means FAT, unless a
+        driver for it is given in `drivers`.
+      '';
+    };
+
+    cacheDirectory = mkOption {
+      type = types.nullOr types.str;
+      default = "/var/lib/lanzaboote/cache";
+      description = ''
+        Directory in which hashes of kernels and initrds and the signed stubs
+        are cached between installations. Generations whose stubs are cached
+        are installed without assembling and signing them again. Set to null
+        to disable the cache.
+      '';
+    };
+
+    blake3 = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether to recognize files in the cache by a BLAKE3 hash that is
+        computed on all cores. Kernels and initrds with the same contents as
+        a cached file are then not hashed with the slower SHA256 again.
+        Requires `cacheDirectory`.
+      '';
+    };
+
+    manifest = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether to sign a manifest of the files that Lanzaboote manages on
+        the ESP with the signing key. Every installation checks it first and
+        installs files that were modified since the last installation again.
+        A missing or invalid manifest counts as a modification of every file.
+        `lzbt verify --manifest` reports such modifications as well.
+      '';
+    };
+
+    allowInvalidCertificate = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether to sign with a certificate that expired or is not yet valid.
+        By default, installing fails then, because some firmware refuses to
+        boot such binaries. Installing warns 30 days before the certificate
+        expires.
+      '';
+    };
+
+    insecurePermissions = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether to sign with keys that other users can read or replace, e.g.
+        keys in the Nix store. By default, installing fails then. Run
+        `lzbt fix-permissions` to restrict the permissions of the keys.
+      '';
+    };
+
+    pkiBundlePasswordFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = "/run/secrets/secureboot-p12-password";
+      description = "File with the password of a PKCS#12 `pkiBundle`";
     };
 
     publicKeyFile = mkOption {
@@ -110,13 +806,65 @@ in
         https://uapi-group.org/specifications/specs/boot_loader_specification/#sorting
       '';
     };
+
+    entryTitle = mkOption {
+      type = types.str;
+      default = "{label} ({description})";
+      example = "NixOS {nixos_version} - Generation {generation} {specialisation}";
+      description = ''
+        The title of the NixOS entries in systemd-boot. The placeholders
+        `{label}`, `{nixos_version}`, `{generation}`, `{specialisation}`,
+        `{date}` and `{description}` are replaced with the values of each
+        generation.
+      '';
+    };
+
+    entrySort = mkOption {
+      type = types.str;
+      default = "generation";
+      example = "nixos-version";
+      description = ''
+        How systemd-boot orders the NixOS entries, which share the sort key
+        {option}`boot.lanzaboote.sortKey`: `generation` puts the newest
+        generation first and `nixos-version` the newest NixOS version. Any
+        other value is a format string with the placeholders of
+        {option}`boot.lanzaboote.entryTitle`, whose result systemd-boot
+        compares like a version, newest first.
+      '';
+    };
   };
 
   config = mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.minimumSecurityVersion == null
+          || (cfg.securityVersion != null && cfg.minimumSecurityVersion <= cfg.securityVersion);
+        message = "boot.lanzaboote.minimumSecurityVersion must not be higher than boot.lanzaboote.securityVersion.";
+      }
+      {
+        assertion = !(cfg.abSlots.enable && cfg.safeUpgrades);
+        message = "boot.lanzaboote.abSlots and boot.lanzaboote.safeUpgrades cannot be enabled at the same time.";
+      }
+      {
+        assertion = cfg.measureBootPhases -> config.boot.initrd.systemd.enable;
+        message = "boot.lanzaboote.measureBootPhases requires boot.initrd.systemd.enable.";
+      }
+    ];
+
+    boot.lanzaboote.settings.default = lib.mkIf cfg.abSlots.enable (lib.mkDefault "nixos-slots.efi");
+
     boot.bootspec = {
       enable = true;
       extensions."org.nix-community.lanzaboote" = {
         sort_key = config.boot.lanzaboote.sortKey;
+        security_version = cfg.securityVersion;
+        minimum_security_version = cfg.minimumSecurityVersion;
+        verity_root_hash = cfg.verityRootHash;
+        # Lets lzbt append the secrets reproducibly instead of running
+        # `append-initrd-secrets`.
+        initrd_secrets = lib.mapAttrs
+          (dest: source: if source == null then dest else toString source)
+          config.boot.initrd.secrets;
       };
     };
     boot.loader.supportsInitrdSecrets = true;
@@ -126,23 +874,152 @@ in
         ${optionalString cfg.enrollKeys ''
           mkdir -p /tmp/pki
           cp -r ${cfg.pkiBundle}/* /tmp/pki
-          ${lib.getExe sbctlWithPki} enroll-keys --yes-this-might-brick-my-machine
+          microsoft=${optionalString (cfg.enrollMicrosoftKeys == "always") "--microsoft"}
+          ${optionalString (cfg.enrollMicrosoftKeys == "auto") ''
+            ${lib.getExe cfg.package} check-oprom
+            if [ $? -eq 2 ]; then
+              echo "Enrolling Microsoft's certificates for the option ROMs."
+              microsoft=--microsoft
+            fi
+          ''}
+          ${lib.getExe cfg.package} check-bitlocker --esp ${config.boot.loader.efi.efiSysMountPoint} --guidance
+          if [ $? -eq 2 ] && [ ${cfg.enrollKeysWithWindows} = skip ]; then
+            echo "Not enrolling the keys, because Windows is on the ESP. Enroll them after suspending BitLocker."
+          else
+            ${lib.getExe sbctlWithPki} enroll-keys --yes-this-might-brick-my-machine $microsoft
+          fi
         ''}
 
-        # Use the system from the kernel's hostPlatform because this should
-        # always, even in the cross compilation case, be the right system.
-        ${lib.getExe cfg.package} install \
-          --system ${config.boot.kernelPackages.stdenv.hostPlatform.system} \
-          --systemd ${config.systemd.package} \
-          --systemd-boot-loader-config ${loaderConfigFile} \
-          --public-key ${cfg.publicKeyFile} \
-          --private-key ${cfg.privateKeyFile} \
-          --configuration-limit ${toString configurationLimit} \
-          ${config.boot.loader.efi.efiSysMountPoint} \
-          /nix/var/nix/profiles/system-*-link
+        ${lib.getExe cfg.package} ${if cfg.migrateExistingBootloader then "migrate start" else "install"} \
+          ${installArgs}
+      '';
+    };
+
+    systemd.services.lanzaboote-migration-finish = lib.mkIf cfg.migrateExistingBootloader {
+      description = "Remove the backup of the boot loader Lanzaboote replaced";
+      wantedBy = [ "multi-user.target" ];
+      after = [ "multi-user.target" ];
+      unitConfig.ConditionPathExists = "${config.boot.loader.efi.efiSysMountPoint}/lanzaboote-migration";
+      serviceConfig = {
+        Type = "oneshot";
+        ExecStart = "${lib.getExe cfg.package} migrate finish ${config.boot.loader.efi.efiSysMountPoint}";
+      };
+    };
+
+    systemd.sockets.lanzaboote = lib.mkIf cfg.daemon.enable {
+      description = "Lanzaboote installation and signing socket";
+      wantedBy = [ "sockets.target" ];
+      socketConfig = {
+        ListenStream = "/run/lzbt.sock";
+        # The daemon checks the credentials of every client itself.
+        SocketMode = "0666";
+      };
+    };
+
+    systemd.services.lanzaboote = lib.mkIf cfg.daemon.enable {
+      description = "Lanzaboote installation and signing service";
+      requires = [ "lanzaboote.socket" ];
+      after = [ "lanzaboote.socket" ];
+      path = [ config.security.polkit.package ];
+      script = ''
+        exec ${lib.getExe cfg.package} daemon \
+          ${installArgs}
       '';
     };
 
+    security.polkit.enable = lib.mkIf cfg.daemon.enable true;
+    environment.systemPackages = lib.mkIf cfg.daemon.enable [
+      (pkgs.writeTextDir "share/polkit-1/actions/org.nixos.lanzaboote.policy"
+        (builtins.readFile ./org.nixos.lanzaboote.policy))
+    ];
+
+    systemd.services.lanzaboote-dbus = lib.mkIf cfg.dbus.enable {
+      description = "Lanzaboote D-Bus service";
+      serviceConfig = {
+        Type = "dbus";
+        BusName = "org.nixos.Lanzaboote";
+        ExecStart = "${lib.getExe cfg.package} dbus --esp ${config.boot.loader.efi.efiSysMountPoint} --system ${config.boot.kernelPackages.stdenv.hostPlatform.system} --public-key ${cfg.publicKeyFile}";
+      };
+    };
+
+    services.dbus.packages = lib.mkIf cfg.dbus.enable [
+      (pkgs.writeTextDir "share/dbus-1/system.d/org.nixos.Lanzaboote.conf"
+        (builtins.readFile ./org.nixos.Lanzaboote.conf))
+      (pkgs.writeTextDir "share/dbus-1/system-services/org.nixos.Lanzaboote.service" ''
+        [D-BUS Service]
+        Name=org.nixos.Lanzaboote
+        Exec=/run/current-system/sw/bin/false
+        User=root
+        SystemdService=lanzaboote-dbus.service
+      '')
+    ];
+
+    # The same ordering as the units of systemd-pcrphase.
+    boot.initrd.systemd.services.lanzaboote-pcrphase-initrd = lib.mkIf cfg.measureBootPhases {
+      description = "Measure the initrd boot phases into PCR 11";
+      wantedBy = [ "initrd.target" ];
+      after = [ "systemd-modules-load.service" "tpm2.target" ];
+      before = [ "sysinit.target" "cryptsetup-pre.target" "cryptsetup.target" "shutdown.target" "initrd-switch-root.target" ];
+      conflicts = [ "shutdown.target" "initrd-switch-root.target" ];
+      unitConfig.DefaultDependencies = false;
+      serviceConfig = {
+        Type = "oneshot";
+        RemainAfterExit = true;
+        ExecStart = "${lib.getExe cfg.package} pcrphase enter-initrd";
+        # Stopped before switching root.
+        ExecStop = "${lib.getExe cfg.package} pcrphase leave-initrd";
+      };
+    };
+
+    systemd.services.lanzaboote-pcrphase-sysinit = lib.mkIf cfg.measureBootPhases {
+      description = "Measure the sysinit boot phase into PCR 11";
+      wantedBy = [ "sysinit.target" ];
+      after = [ "sysinit.target" ];
+      before = [ "basic.target" "shutdown.target" ];
+      conflicts = [ "shutdown.target" ];
+      unitConfig.DefaultDependencies = false;
+      serviceConfig = {
+        Type = "oneshot";
+        RemainAfterExit = true;
+        ExecStart = "${lib.getExe cfg.package} pcrphase sysinit";
+      };
+    };
+
+    systemd.services.lanzaboote-pcrphase = lib.mkIf cfg.measureBootPhases {
+      description = "Measure the ready and shutdown boot phases into PCR 11";
+      wantedBy = [ "sysinit.target" ];
+      after = [ "remote-fs.target" "remote-cryptsetup.target" ];
+      before = [ "systemd-user-sessions.service" ];
+      serviceConfig = {
+        Type = "oneshot";
+        RemainAfterExit = true;
+        ExecStart = "${lib.getExe cfg.package} pcrphase ready";
+        ExecStop = "${lib.getExe cfg.package} pcrphase shutdown";
+      };
+    };
+
+    systemd.services.lanzaboote-mark-good = lib.mkIf cfg.safeUpgrades {
+      description = "Make the booted generation the default boot entry";
+      wantedBy = [ "multi-user.target" ];
+      after = [ "multi-user.target" ];
+      serviceConfig = {
+        Type = "oneshot";
+        ExecStart = "${lib.getExe cfg.package} mark-good";
+      };
+    };
+
+    systemd.services.lanzaboote-slot-mark-good = lib.mkIf cfg.abSlots.enable {
+      description = "Confirm the booted A/B slot";
+      wantedBy = [ "multi-user.target" ];
+      after = [ "multi-user.target" ];
+      # Only set if the slot selector booted the system.
+      unitConfig.ConditionPathExists = "/sys/firmware/efi/efivars/LanzabooteBootedSlot-14406d1c-93f7-4a09-a0d5-d4863451bd7e";
+      serviceConfig = {
+        Type = "oneshot";
+        ExecStart = "${lib.getExe cfg.package} slot mark-good";
+      };
+    };
+
     systemd.services.fwupd = lib.mkIf config.services.fwupd.enable {
       # Tell fwupd to load its efi files from /run
       environment.FWUPD_EFIAPPDIR = "/run/fwupd-efi";
@@ -16,6 +16,8 @@
       enable = true;
       enrollKeys = config.virtualisation.useSecureBoot;
       pkiBundle = ../../fixtures/uefi-keys;
+      # The fixtures are in the Nix store, which everyone can read.
+      insecurePermissions = true;
     };
   };
 
@@ -6,6 +6,9 @@
 # make integrity verification fail, we actually have to modify the initrd.
 # Appending crap to the end is a harmless way that would make the kernel still
 # accept it.
+#
+# With simulated Secure Boot, the stub must behave as if Secure Boot was
+# enabled.
 
 {
 
@@ -22,6 +25,12 @@
       imports = [ ./common/lanzaboote.nix ];
     };
 
+    brokenInitrdSimulatedSecureBoot = { lib, ... }: {
+      imports = [ ./common/lanzaboote.nix ];
+      virtualisation.useSecureBoot = lib.mkForce false;
+      boot.lanzaboote.simulateSecureBoot = true;
+    };
+
     brokenKernel = { lib, ... }: {
       imports = [ ./common/lanzaboote.nix ];
       virtualisation.useSecureBoot = lib.mkForce false;
@@ -49,11 +58,13 @@
 
     prepare(brokenInitrd, initrdGlob)
     prepare(brokenInitrdSecureBoot, initrdGlob)
+    prepare(brokenInitrdSimulatedSecureBoot, initrdGlob)
     prepare(brokenKernel, kernelGlob)
     prepare(brokenKernelSecureBoot, kernelGlob)
 
     brokenInitrd.succeed("bootctl", timeout=120)
     brokenInitrdSecureBoot.wait_for_console_text("hash does not match")
+    brokenInitrdSimulatedSecureBoot.wait_for_console_text("hash does not match")
     brokenKernel.succeed("bootctl", timeout=120)
     brokenKernelSecureBoot.wait_for_console_text("hash does not match")
   '';
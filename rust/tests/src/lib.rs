@@ -0,0 +1,299 @@
+//! A harness to boot the lanzaboote stub in QEMU with OVMF.
+//!
+//! The harness assembles a stub for a given kernel and initrd, signs it with the throwaway keys
+//! from `nix/tests/fixtures/uefi-keys`, enrolls these keys into a copy of the OVMF variable store
+//! and boots the result while watching the serial console.
+//!
+//! The following environment variables need to be set:
+//!
+//! - `LANZABOOTE_STUB`: the thin stub to test.
+//! - `TEST_OVMF_CODE` and `TEST_OVMF_VARS`: the OVMF firmware and its variable store template.
+//!   The firmware must be built with Secure Boot support.
+//! - `TEST_KERNEL` and `TEST_INITRD`: the kernel and initrd to boot.
+//!
+//! In addition, `qemu-system-x86_64`, `objcopy`, `sbsign` and `virt-fw-vars` (from
+//! virt-firmware) need to be on PATH.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use lanzaboote_tool::pe::{lanzaboote_image, StubParameters};
+use lanzaboote_tool::signature::{local::LocalKeyPair, Signer};
+
+/// The default time a VM is given to produce the expected output.
+pub const DEFAULT_BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Read a mandatory environment variable pointing to a file or directory.
+pub fn path_from_env(name: &str) -> Result<PathBuf> {
+    env::var_os(name)
+        .map(PathBuf::from)
+        .with_context(|| format!("The {name} environment variable is not set. See the documentation of the lanzaboote-vm-tests crate."))
+}
+
+/// The throwaway Secure Boot PKI used by the NixOS tests.
+pub struct TestKeys {
+    dir: PathBuf,
+}
+
+impl TestKeys {
+    pub fn fixtures() -> Self {
+        Self {
+            dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("../../nix/tests/fixtures/uefi-keys"),
+        }
+    }
+
+    fn guid(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.dir.join("GUID"))
+            .context("Failed to read the owner GUID of the test keys")?
+            .trim()
+            .to_string())
+    }
+
+    fn certificate(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("keys/{name}/{name}.pem"))
+    }
+
+    /// The key pair that signs boot files, i.e. the one enrolled into db.
+    pub fn db(&self) -> LocalKeyPair {
+        LocalKeyPair::new(&self.certificate("db"), &self.dir.join("keys/db/db.key"))
+    }
+}
+
+/// How the stub is signed before it is put on the ESP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signing {
+    /// Signed with the key that is enrolled into db.
+    Enrolled,
+    /// Not signed at all.
+    Unsigned,
+}
+
+/// A directory that is presented to the VM as its ESP.
+pub struct Esp {
+    dir: TempDir,
+}
+
+impl Esp {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            dir: TempDir::new().context("Failed to create ESP directory")?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Install kernel, initrd and an assembled stub as the removable media boot loader.
+    pub fn install(
+        &self,
+        kernel: &Path,
+        initrd: &Path,
+        cmdline: &[String],
+        signing: Signing,
+    ) -> Result<()> {
+        self.install_with(kernel, initrd, cmdline, signing, |parameters| parameters)
+    }
+
+    /// Like [`Esp::install`], but allows adjusting the stub parameters before assembly.
+    pub fn install_with(
+        &self,
+        kernel: &Path,
+        initrd: &Path,
+        cmdline: &[String],
+        signing: Signing,
+        customize: impl FnOnce(StubParameters) -> StubParameters,
+    ) -> Result<()> {
+        let nixos = self.path().join("EFI/nixos");
+        let boot = self.path().join("EFI/BOOT");
+        fs::create_dir_all(&nixos)?;
+        fs::create_dir_all(&boot)?;
+
+        let kernel_target = nixos.join("kernel.efi");
+        let initrd_target = nixos.join("initrd.efi");
+        fs::copy(kernel, &kernel_target).context("Failed to copy the kernel to the ESP")?;
+        fs::copy(initrd, &initrd_target).context("Failed to copy the initrd to the ESP")?;
+
+        let stub = path_from_env("LANZABOOTE_STUB")?;
+        let parameters = StubParameters::new(
+            &stub,
+            kernel,
+            initrd,
+            &kernel_target,
+            &initrd_target,
+            self.path(),
+        )?
+        .with_cmdline(cmdline)
+        .with_os_release_contents(b"ID=lanzaboote\nPRETTY_NAME=VM test\n");
+        let parameters = customize(parameters);
+
+        let tempdir = TempDir::new()?;
+        let image = lanzaboote_image(&tempdir, &parameters)?;
+        let target = boot.join("BOOTX64.EFI");
+
+        match signing {
+            Signing::Enrolled => TestKeys::fixtures().db().sign_and_copy(&image, &target)?,
+            Signing::Unsigned => {
+                fs::copy(&image, &target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Append garbage to a file on the ESP to break its embedded hash.
+    pub fn tamper(&self, relative_path: &str) -> Result<()> {
+        let path = self.path().join(relative_path);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(b"some_garbage_to_change_the_hash"))
+            .with_context(|| format!("Failed to tamper with {path:?}"))
+    }
+}
+
+/// The result of booting a VM.
+pub struct BootOutcome {
+    /// The first expected pattern that appeared on the serial console, if any.
+    pub matched: Option<String>,
+    /// Everything the VM wrote to its serial console.
+    pub serial: String,
+}
+
+/// An x86_64 QEMU VM with OVMF firmware.
+pub struct Vm {
+    ovmf_code: PathBuf,
+    vars_dir: TempDir,
+}
+
+impl Vm {
+    /// Prepare a VM.
+    ///
+    /// If `secure_boot` is set, the test keys are enrolled and Secure Boot is enabled.
+    pub fn new(secure_boot: bool) -> Result<Self> {
+        let ovmf_code = path_from_env("TEST_OVMF_CODE")?;
+        let ovmf_vars = path_from_env("TEST_OVMF_VARS")?;
+        let vars_dir = TempDir::new()?;
+        let vars = vars_dir.path().join("OVMF_VARS.fd");
+
+        if secure_boot {
+            let keys = TestKeys::fixtures();
+            let guid = keys.guid()?;
+            let status = Command::new("virt-fw-vars")
+                .arg("--input")
+                .arg(&ovmf_vars)
+                .arg("--output")
+                .arg(&vars)
+                .arg("--set-pk")
+                .arg(&guid)
+                .arg(keys.certificate("PK"))
+                .arg("--add-kek")
+                .arg(&guid)
+                .arg(keys.certificate("KEK"))
+                .arg("--add-db")
+                .arg(&guid)
+                .arg(keys.certificate("db"))
+                .arg("--secure-boot")
+                .status()
+                .context("Failed to run virt-fw-vars. Most likely, the binary is not on PATH.")?;
+            if !status.success() {
+                bail!("Failed to enroll the test keys into {vars:?}");
+            }
+        } else {
+            fs::copy(&ovmf_vars, &vars).context("Failed to copy the OVMF variable store")?;
+        }
+
+        Ok(Self {
+            ovmf_code,
+            vars_dir,
+        })
+    }
+
+    /// Boot the VM from `esp` until one of the `expected` patterns appears on the serial console
+    /// or `timeout` expires. The VM is killed afterwards.
+    pub fn boot(&self, esp: &Esp, expected: &[&str], timeout: Duration) -> Result<BootOutcome> {
+        let vars = self.vars_dir.path().join("OVMF_VARS.fd");
+        let mut child = Command::new("qemu-system-x86_64")
+            .args(["-machine", "q35,smm=on", "-accel", "kvm", "-accel", "tcg"])
+            .args([
+                "-m",
+                "1024",
+                "-nodefaults",
+                "-display",
+                "none",
+                "-serial",
+                "stdio",
+            ])
+            .args(["-global", "driver=cfi.pflash01,property=secure,value=on"])
+            .arg("-drive")
+            .arg(pflash(&self.ovmf_code, 0, true))
+            .arg("-drive")
+            .arg(pflash(&vars, 1, false))
+            .arg("-drive")
+            .arg(format!("format=raw,file=fat:rw:{}", esp.path().display()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context("Failed to run QEMU. Most likely, qemu-system-x86_64 is not on PATH.")?;
+
+        let mut stdout = child
+            .stdout
+            .take()
+            .context("Failed to capture QEMU output")?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok(n) = stdout.read(&mut buffer) {
+                if n == 0 || sender.send(buffer[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let deadline = Instant::now() + timeout;
+        let mut serial = Vec::new();
+        let mut matched = None;
+        while matched.is_none() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            match receiver.recv_timeout(remaining) {
+                Ok(chunk) => serial.extend_from_slice(&chunk),
+                // Either the timeout expired or QEMU exited.
+                Err(_) => break,
+            }
+            let text = String::from_utf8_lossy(&serial);
+            matched = expected
+                .iter()
+                .find(|pattern| text.contains(*pattern))
+                .map(|pattern| pattern.to_string());
+        }
+
+        child.kill().ok();
+        child.wait().ok();
+
+        let serial = String::from_utf8_lossy(&serial).into_owned();
+        // Make the console output available when a test fails.
+        print!("{serial}");
+
+        Ok(BootOutcome { matched, serial })
+    }
+}
+
+fn pflash(path: &Path, unit: u8, readonly: bool) -> String {
+    format!(
+        "if=pflash,format=raw,unit={unit},readonly={},file={}",
+        if readonly { "on" } else { "off" },
+        path.display()
+    )
+}
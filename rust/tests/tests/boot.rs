@@ -0,0 +1,99 @@
+//! Boot scenarios for the thin stub.
+//!
+//! These tests need QEMU, OVMF and a kernel. They are ignored by default; see the crate
+//! documentation for how to run them.
+
+use anyhow::Result;
+
+use lanzaboote_vm_tests::{path_from_env, Esp, Signing, Vm, DEFAULT_BOOT_TIMEOUT};
+
+/// Printed by the kernel as soon as it starts.
+const KERNEL_STARTED: &str = "Linux version";
+/// Printed by the stub when an embedded hash does not match.
+const HASH_MISMATCH: &str = "hash does not match";
+/// Printed by OVMF when it refuses to start an image.
+const ACCESS_DENIED: &str = "Access Denied";
+
+/// Log to the serial console and stop the VM instead of hanging if the kernel panics.
+fn cmdline() -> Vec<String> {
+    vec!["console=ttyS0".to_string(), "panic=-1".to_string()]
+}
+
+fn setup_esp(signing: Signing) -> Result<Esp> {
+    let esp = Esp::new()?;
+    esp.install(
+        &path_from_env("TEST_KERNEL")?,
+        &path_from_env("TEST_INITRD")?,
+        &cmdline(),
+        signing,
+    )?;
+    Ok(esp)
+}
+
+#[test]
+#[ignore = "needs QEMU and OVMF"]
+fn boot_signed_stub_with_secure_boot() -> Result<()> {
+    let esp = setup_esp(Signing::Enrolled)?;
+    let outcome = Vm::new(true)?.boot(
+        &esp,
+        &[KERNEL_STARTED, HASH_MISMATCH, ACCESS_DENIED],
+        DEFAULT_BOOT_TIMEOUT,
+    )?;
+
+    assert_eq!(outcome.matched.as_deref(), Some(KERNEL_STARTED));
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs QEMU and OVMF"]
+fn refuse_tampered_initrd_with_secure_boot() -> Result<()> {
+    let esp = setup_esp(Signing::Enrolled)?;
+    esp.tamper("EFI/nixos/initrd.efi")?;
+    let outcome =
+        Vm::new(true)?.boot(&esp, &[KERNEL_STARTED, HASH_MISMATCH], DEFAULT_BOOT_TIMEOUT)?;
+
+    assert_eq!(outcome.matched.as_deref(), Some(HASH_MISMATCH));
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs QEMU and OVMF"]
+fn boot_tampered_kernel_without_secure_boot() -> Result<()> {
+    let esp = setup_esp(Signing::Unsigned)?;
+    esp.tamper("EFI/nixos/kernel.efi")?;
+    let outcome = Vm::new(false)?.boot(&esp, &[KERNEL_STARTED], DEFAULT_BOOT_TIMEOUT)?;
+
+    assert_eq!(outcome.matched.as_deref(), Some(KERNEL_STARTED));
+    assert!(outcome.serial.contains(HASH_MISMATCH));
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs QEMU and OVMF"]
+fn refuse_tampered_initrd_with_simulated_secure_boot() -> Result<()> {
+    let esp = Esp::new()?;
+    esp.install_with(
+        &path_from_env("TEST_KERNEL")?,
+        &path_from_env("TEST_INITRD")?,
+        &cmdline(),
+        Signing::Unsigned,
+        |parameters| parameters.with_simulate_secure_boot(true),
+    )?;
+    esp.tamper("EFI/nixos/initrd.efi")?;
+    let outcome =
+        Vm::new(false)?.boot(&esp, &[KERNEL_STARTED, HASH_MISMATCH], DEFAULT_BOOT_TIMEOUT)?;
+
+    assert_eq!(outcome.matched.as_deref(), Some(HASH_MISMATCH));
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs QEMU and OVMF"]
+fn refuse_unsigned_stub_with_secure_boot() -> Result<()> {
+    let esp = setup_esp(Signing::Unsigned)?;
+    let outcome =
+        Vm::new(true)?.boot(&esp, &[KERNEL_STARTED, ACCESS_DENIED], DEFAULT_BOOT_TIMEOUT)?;
+
+    assert_eq!(outcome.matched.as_deref(), Some(ACCESS_DENIED));
+    Ok(())
+}
@@ -0,0 +1,56 @@
+/*
+ * C interface to the Lanzaboote image assembly and signing core.
+ *
+ * All functions return 0 on success and -1 on failure. After a failure,
+ * lzbt_last_error() returns a human-readable description of the error.
+ */
+
+#ifndef LANZABOOTE_H
+#define LANZABOOTE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Size of a PCR value written by lzbt_compute_expected_pcrs(). */
+#define LZBT_PCR_SIZE 32
+
+/*
+ * Description of the last error on the calling thread, or NULL.
+ *
+ * The string is owned by the library and valid until the next call into the
+ * library on the same thread.
+ */
+const char *lzbt_last_error(void);
+
+/*
+ * Assemble an unsigned lanzaboote stub image at `output`.
+ *
+ * `kernel_target` and `initrd_target` are the paths where kernel and initrd
+ * will be installed and must be below `esp`. `cmdline` and `os_release` may be
+ * NULL.
+ */
+int lzbt_assemble_stub(const char *stub, const char *kernel, const char *initrd,
+                       const char *kernel_target, const char *initrd_target,
+                       const char *esp, const char *cmdline,
+                       const char *os_release, const char *output);
+
+/* Sign the PE binary `input` with a local key pair and write it to `output`. */
+int lzbt_sign_file(const char *public_key, const char *private_key,
+                   const char *input, const char *output);
+
+/*
+ * Compute the PCR values the stub produces when booting `image`.
+ *
+ * Currently only PCR 11 is computed and written to `pcr11`, which must hold
+ * at least LZBT_PCR_SIZE bytes.
+ */
+int lzbt_compute_expected_pcrs(const char *image, uint8_t *pcr11);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LANZABOOTE_H */
@@ -0,0 +1,192 @@
+//! C bindings for the image assembly and signing core of Lanzaboote.
+//!
+//! This library lets tooling that is not written in Rust produce the same images `lzbt` does. The
+//! C declarations live in `include/lanzaboote.h`.
+//!
+//! All functions return `0` on success and `-1` on failure. After a failure, a description of the
+//! error can be retrieved with [`lzbt_last_error`].
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+
+use lanzaboote_tool::pcr;
+use lanzaboote_tool::pe::{lanzaboote_image, StubParameters};
+use lanzaboote_tool::signature::{local::LocalKeyPair, Signer};
+
+/// The size of a PCR value as written by [`lzbt_compute_expected_pcrs`].
+pub const LZBT_PCR_SIZE: usize = 32;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run `f` and translate its result into a C return code.
+///
+/// Errors are stored in `LAST_ERROR` so that they can be retrieved by the caller.
+fn ffi_call(f: impl FnOnce() -> Result<()>) -> c_int {
+    match f() {
+        Ok(()) => 0,
+        Err(e) => {
+            // Interior NUL bytes cannot be represented in a C string, so replace them.
+            let message = format!("{e:#}").replace('\0', "\\0");
+            LAST_ERROR.with(|last| {
+                *last.borrow_mut() = Some(CString::new(message).expect("NUL bytes were removed"))
+            });
+            -1
+        }
+    }
+}
+
+/// Convert a C string argument to a path.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string.
+unsafe fn path_arg(ptr: *const c_char, name: &str) -> Result<PathBuf> {
+    Ok(PathBuf::from(unsafe { str_arg(ptr, name) }?))
+}
+
+/// Convert a C string argument to a Rust string.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string.
+unsafe fn str_arg(ptr: *const c_char, name: &str) -> Result<String> {
+    if ptr.is_null() {
+        anyhow::bail!("Argument {name} must not be NULL");
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .with_context(|| format!("Argument {name} is not valid UTF-8"))?
+        .to_owned())
+}
+
+/// Return a description of the last error that happened on the calling thread.
+///
+/// The returned string is owned by the library and valid until the next call into the library on
+/// the same thread. NULL is returned if no error happened yet.
+#[no_mangle]
+pub extern "C" fn lzbt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |e| e.as_ptr())
+    })
+}
+
+/// Assemble an unsigned lanzaboote stub image.
+///
+/// `kernel_target` and `initrd_target` are the locations where the kernel and initrd will be
+/// installed and must be below `esp`. `cmdline` and `os_release` may be NULL.
+///
+/// # Safety
+///
+/// All arguments must be NULL or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lzbt_assemble_stub(
+    stub: *const c_char,
+    kernel: *const c_char,
+    initrd: *const c_char,
+    kernel_target: *const c_char,
+    initrd_target: *const c_char,
+    esp: *const c_char,
+    cmdline: *const c_char,
+    os_release: *const c_char,
+    output: *const c_char,
+) -> c_int {
+    ffi_call(|| {
+        let mut parameters = unsafe {
+            StubParameters::new(
+                &path_arg(stub, "stub")?,
+                &path_arg(kernel, "kernel")?,
+                &path_arg(initrd, "initrd")?,
+                &path_arg(kernel_target, "kernel_target")?,
+                &path_arg(initrd_target, "initrd_target")?,
+                &path_arg(esp, "esp")?,
+            )?
+        };
+        if !cmdline.is_null() {
+            parameters = parameters.with_cmdline(&[unsafe { str_arg(cmdline, "cmdline") }?]);
+        }
+        if !os_release.is_null() {
+            parameters = parameters
+                .with_os_release_contents(unsafe { str_arg(os_release, "os_release") }?.as_bytes());
+        }
+        let output = unsafe { path_arg(output, "output") }?;
+
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let image = lanzaboote_image(&tempdir, &parameters)
+            .context("Failed to assemble lanzaboote image.")?;
+        fs::copy(&image, &output)
+            .with_context(|| format!("Failed to copy assembled image to {output:?}"))?;
+        Ok(())
+    })
+}
+
+/// Sign a PE binary with a local key pair and write the signed binary to `output`.
+///
+/// # Safety
+///
+/// All arguments must be NULL or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lzbt_sign_file(
+    public_key: *const c_char,
+    private_key: *const c_char,
+    input: *const c_char,
+    output: *const c_char,
+) -> c_int {
+    ffi_call(|| {
+        let signer = unsafe {
+            LocalKeyPair::new(
+                &path_arg(public_key, "public_key")?,
+                &path_arg(private_key, "private_key")?,
+            )
+        };
+        let (input, output) = unsafe { (path_arg(input, "input")?, path_arg(output, "output")?) };
+        signer.sign_and_copy(&input, &output)
+    })
+}
+
+/// Compute the PCR values the stub produces when it boots `image`.
+///
+/// Currently, only PCR 11 is computed. `pcr11` must point to a buffer of at least
+/// `LZBT_PCR_SIZE` bytes.
+///
+/// # Safety
+///
+/// `image` must be NULL or point to a NUL-terminated string. `pcr11` must be NULL or be valid
+/// for writes of `LZBT_PCR_SIZE` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn lzbt_compute_expected_pcrs(image: *const c_char, pcr11: *mut u8) -> c_int {
+    ffi_call(|| {
+        if pcr11.is_null() {
+            anyhow::bail!("Argument pcr11 must not be NULL");
+        }
+        let image = unsafe { path_arg(image, "image") }?;
+        let data = fs::read(&image).with_context(|| format!("Failed to read {image:?}"))?;
+        let value = pcr::expected_pcr11(&data)?;
+
+        unsafe { std::slice::from_raw_parts_mut(pcr11, LZBT_PCR_SIZE) }.copy_from_slice(&value);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_reported_through_last_error() {
+        let mut pcr = [0u8; LZBT_PCR_SIZE];
+        let status = unsafe { lzbt_compute_expected_pcrs(std::ptr::null(), pcr.as_mut_ptr()) };
+        assert_eq!(status, -1);
+
+        let error = unsafe { CStr::from_ptr(lzbt_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "Argument image must not be NULL");
+    }
+}
@@ -0,0 +1,11 @@
+//! The bootspec documents of generations come from the Nix store, which anyone who can build a
+//! system configuration can write to.
+
+#![no_main]
+
+use lanzaboote_tool::generation::ExtendedBootJson;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ExtendedBootJson::parse(data);
+});
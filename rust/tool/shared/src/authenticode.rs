@@ -0,0 +1,288 @@
+use anyhow::{bail, Context, Result};
+use goblin::pe::PE;
+use sha2::{Digest, Sha256};
+
+/// Offset of the pointer to the PE header in the DOS header.
+const PE_POINTER_OFFSET: usize = 0x3c;
+
+/// Size of the PE signature and the COFF file header that precede the optional header.
+const COFF_HEADER_SIZE: usize = 4 + 20;
+
+/// The revision of `WIN_CERTIFICATE` that Authenticode uses.
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+
+/// `WIN_CERT_TYPE_PKCS_SIGNED_DATA`
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// The offsets of the checksum and of the certificate table directory entry in a PE binary.
+fn header_offsets(image: &[u8]) -> Result<(usize, usize)> {
+    let truncated = || anyhow::anyhow!("PE binary is truncated");
+    let optional_header_offset =
+        usize::try_from(read_u32(image, PE_POINTER_OFFSET).ok_or_else(truncated)?)?
+            + COFF_HEADER_SIZE;
+    let checksum_offset = optional_header_offset + 64;
+    let certificate_entry_offset = optional_header_offset
+        + match read_u16(image, optional_header_offset).ok_or_else(truncated)? {
+            // PE32
+            0x10b => 128,
+            // PE32+
+            0x20b => 144,
+            magic => bail!("Unknown optional header magic {magic:#x}"),
+        };
+    Ok((checksum_offset, certificate_entry_offset))
+}
+
+/// The offset and the size of the certificate table of a signed PE binary.
+fn certificate_table(image: &[u8]) -> Result<(usize, usize)> {
+    let (_, certificate_entry_offset) = header_offsets(image)?;
+    let (Some(offset), Some(size)) = (
+        read_u32(image, certificate_entry_offset),
+        read_u32(image, certificate_entry_offset + 4),
+    ) else {
+        bail!("PE binary is truncated");
+    };
+    let (offset, size) = (usize::try_from(offset)?, usize::try_from(size)?);
+    if size == 0 {
+        bail!("The PE binary is not signed");
+    }
+    if offset.checked_add(size) != Some(image.len()) {
+        bail!("The certificate table is not at the end of the PE binary");
+    }
+    Ok((offset, size))
+}
+
+/// The DER encoded PKCS#7 signature of a signed PE binary.
+///
+/// Only the first `WIN_CERTIFICATE` is considered, which is the only one that `sbsign` creates.
+pub fn signature(image: &[u8]) -> Result<&[u8]> {
+    let (offset, size) = certificate_table(image)?;
+    let table = &image[offset..offset + size];
+    let length = read_u32(table, 0).context("The certificate table is truncated")?;
+    if read_u16(table, 6) != Some(WIN_CERT_TYPE_PKCS_SIGNED_DATA) {
+        bail!("The PE binary does not have an Authenticode signature");
+    }
+    table
+        .get(8..usize::try_from(length)?)
+        .context("The certificate table is truncated")
+}
+
+/// Replace the signature of a signed PE binary with another DER encoded PKCS#7 signature.
+///
+/// This is meant for signatures that only differ in their unauthenticated attributes, e.g. a
+/// timestamp, because the signature must still cover the Authenticode hash of the binary. The
+/// checksum of the binary is updated.
+pub fn replace_signature(image: &[u8], pkcs7: &[u8]) -> Result<Vec<u8>> {
+    let (offset, _) = certificate_table(image)?;
+    let (checksum_offset, certificate_entry_offset) = header_offsets(image)?;
+
+    // WIN_CERTIFICATE, padded to 8 bytes.
+    let length = 8 + pkcs7.len();
+    let mut table = Vec::with_capacity(length.next_multiple_of(8));
+    table.extend_from_slice(&u32::try_from(length)?.to_le_bytes());
+    table.extend_from_slice(&WIN_CERT_REVISION_2_0.to_le_bytes());
+    table.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+    table.extend_from_slice(pkcs7);
+    table.resize(length.next_multiple_of(8), 0);
+
+    let mut signed = image[..offset].to_vec();
+    signed[certificate_entry_offset + 4..certificate_entry_offset + 8]
+        .copy_from_slice(&u32::try_from(table.len())?.to_le_bytes());
+    signed.extend_from_slice(&table);
+
+    let checksum = pe_checksum(&signed, checksum_offset);
+    signed[checksum_offset..checksum_offset + 4].copy_from_slice(&checksum.to_le_bytes());
+    Ok(signed)
+}
+
+/// The checksum of a PE binary, a 16-bit one's complement sum plus the size of the binary.
+fn pe_checksum(image: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (i, word) in image.chunks(2).enumerate() {
+        // The checksum itself is not part of the sum.
+        if i * 2 == checksum_offset || i * 2 == checksum_offset + 2 {
+            continue;
+        }
+        sum += u64::from(word[0]) | u64::from(*word.get(1).unwrap_or(&0)) << 8;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    (sum as u32 & 0xffff) + image.len() as u32
+}
+
+/// Compute the Authenticode SHA256 hash of a PE binary.
+///
+/// This is the hash that the firmware looks up in `db` and `dbx`. It covers the whole image except
+/// for the checksum, the certificate table directory entry and the certificate table itself, so
+/// signing an image does not change it.
+pub fn authenticode_sha256(image: &[u8]) -> Result<[u8; 32]> {
+    let pe = PE::parse(image).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+
+    let truncated = || anyhow::anyhow!("PE binary is truncated");
+
+    let (checksum_offset, certificate_entry_offset) = header_offsets(image)?;
+    let size_of_headers = usize::try_from(optional_header.windows_fields.size_of_headers)?;
+
+    let (certificate_table_size, certificate_table_offset) =
+        match optional_header.data_directories.get_certificate_table() {
+            Some(table) if table.size > 0 => (
+                usize::try_from(table.size)?,
+                usize::try_from(table.virtual_address)?,
+            ),
+            _ => (0, image.len()),
+        };
+
+    let mut hasher = Sha256::new();
+    hasher.update(image.get(..checksum_offset).ok_or_else(truncated)?);
+    hasher.update(
+        image
+            .get(checksum_offset + 4..certificate_entry_offset)
+            .ok_or_else(truncated)?,
+    );
+    hasher.update(
+        image
+            .get(certificate_entry_offset + 8..size_of_headers)
+            .ok_or_else(truncated)?,
+    );
+
+    let mut sections = pe
+        .sections
+        .iter()
+        .filter(|s| s.size_of_raw_data > 0)
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|s| s.pointer_to_raw_data);
+
+    let mut bytes_hashed = size_of_headers;
+    for section in sections {
+        let start = usize::try_from(section.pointer_to_raw_data)?;
+        let size = usize::try_from(section.size_of_raw_data)?;
+        hasher.update(image.get(start..start + size).ok_or_else(truncated)?);
+        bytes_hashed += size;
+    }
+
+    // Data behind the sections is hashed as well, except for the certificate table at the end.
+    let trailing_end = image
+        .len()
+        .checked_sub(certificate_table_size)
+        .filter(|end| *end <= certificate_table_offset)
+        .context("The certificate table is not at the end of the PE binary")?;
+    if trailing_end > bytes_hashed {
+        hasher.update(&image[bytes_hashed..trailing_end]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE_OF_HEADERS: usize = 0x200;
+    const SECTION_SIZE: usize = 0x200;
+
+    /// Build a minimal PE32+ binary with a single section.
+    fn minimal_pe() -> Vec<u8> {
+        let mut image = vec![0u8; SIZE_OF_HEADERS + SECTION_SIZE];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+
+        // COFF header
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x46..0x48].copy_from_slice(&1u16.to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        image[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+
+        // Optional header
+        let optional = 0x58;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 32..optional + 36].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[optional + 36..optional + 40].copy_from_slice(&0x200u32.to_le_bytes());
+        image[optional + 56..optional + 60].copy_from_slice(&0x2000u32.to_le_bytes());
+        image[optional + 60..optional + 64]
+            .copy_from_slice(&(SIZE_OF_HEADERS as u32).to_le_bytes());
+        image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+        // Section header
+        let section = optional + 240;
+        image[section..section + 5].copy_from_slice(b".text");
+        image[section + 8..section + 12].copy_from_slice(&(SECTION_SIZE as u32).to_le_bytes());
+        image[section + 12..section + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[section + 16..section + 20].copy_from_slice(&(SECTION_SIZE as u32).to_le_bytes());
+        image[section + 20..section + 24].copy_from_slice(&(SIZE_OF_HEADERS as u32).to_le_bytes());
+
+        image[SIZE_OF_HEADERS..].fill(0xcc);
+        image
+    }
+
+    /// Attach a fake certificate table and checksum like signing does.
+    fn sign(mut image: Vec<u8>) -> Vec<u8> {
+        // A WIN_CERTIFICATE with revision 2 and type PKCS_SIGNED_DATA, but bogus contents.
+        let mut certificate_table = vec![0x5a; 64];
+        certificate_table[0..4].copy_from_slice(&64u32.to_le_bytes());
+        certificate_table[4..6].copy_from_slice(&0x0200u16.to_le_bytes());
+        certificate_table[6..8].copy_from_slice(&0x0002u16.to_le_bytes());
+        let offset = image.len() as u32;
+        let entry = 0x58 + 144;
+        image[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+        image[entry + 4..entry + 8]
+            .copy_from_slice(&(certificate_table.len() as u32).to_le_bytes());
+        image[0x58 + 64..0x58 + 68].copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        image.extend_from_slice(&certificate_table);
+        image
+    }
+
+    #[test]
+    fn signing_does_not_change_the_hash() -> Result<()> {
+        let unsigned = minimal_pe();
+        assert_eq!(
+            authenticode_sha256(&unsigned)?,
+            authenticode_sha256(&sign(unsigned.clone()))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replace_the_signature() -> Result<()> {
+        let signed = sign(minimal_pe());
+        assert_eq!(signature(&signed)?, &[0x5a; 56]);
+
+        let resigned = replace_signature(&signed, &[0x30, 0x03, 1, 2, 3])?;
+        assert_eq!(signature(&resigned)?, &[0x30, 0x03, 1, 2, 3]);
+        // The table is padded to 8 bytes.
+        assert_eq!(resigned.len(), signed.len() - 64 + 16);
+        assert_eq!(
+            authenticode_sha256(&signed)?,
+            authenticode_sha256(&resigned)?
+        );
+        assert!(signature(&minimal_pe()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hash_covers_section_data() -> Result<()> {
+        let original = minimal_pe();
+        let mut modified = original.clone();
+        modified[SIZE_OF_HEADERS] = 0x90;
+        assert_ne!(
+            authenticode_sha256(&original)?,
+            authenticode_sha256(&modified)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// The object identifier of the common name (CN) attribute, 2.5.4.3.
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// The object identifier of the organization (O) attribute, 2.5.4.10.
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0a];
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_UTF8_STRING: u8 = 0x0c;
+const TAG_PRINTABLE_STRING: u8 = 0x13;
+const TAG_T61_STRING: u8 = 0x14;
+const TAG_IA5_STRING: u8 = 0x16;
+const TAG_BMP_STRING: u8 = 0x1e;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+/// The explicitly tagged version at the start of a v3 certificate.
+const TAG_VERSION: u8 = 0xa0;
+
+/// Read an X.509 certificate and return it DER encoded.
+///
+/// The certificate can either be PEM or DER encoded. Of a PEM file, only the first certificate is
+/// read.
+pub fn read_der_certificate(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read certificate {path:?}"))?;
+    der_certificate(&data).with_context(|| format!("Failed to parse certificate {path:?}"))
+}
+
+fn der_certificate(data: &[u8]) -> Result<Vec<u8>> {
+    let der = match std::str::from_utf8(data) {
+        Ok(pem) if pem.contains(PEM_BEGIN) => {
+            let (_, rest) = pem.split_once(PEM_BEGIN).expect("Checked above");
+            let Some((body, _)) = rest.split_once(PEM_END) else {
+                bail!("The PEM certificate is not terminated");
+            };
+            decode_base64(body)?
+        }
+        _ => data.to_vec(),
+    };
+
+    // Every certificate is a DER SEQUENCE.
+    if der.first() != Some(&0x30) {
+        bail!("Not a PEM or DER encoded certificate");
+    }
+    Ok(der)
+}
+
+/// The period in which a certificate is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+}
+
+/// The fields of the `TBSCertificate` of a DER encoded certificate after the optional version,
+/// starting with the serial number.
+fn tbs_fields(der: &[u8]) -> Option<&[u8]> {
+    let (TAG_SEQUENCE, certificate, _) = read_element(der)? else {
+        return None;
+    };
+    let (TAG_SEQUENCE, tbs, _) = read_element(certificate)? else {
+        return None;
+    };
+    match read_element(tbs)? {
+        (TAG_VERSION, _, rest) => Some(rest),
+        _ => Some(tbs),
+    }
+}
+
+/// The validity period of a DER encoded certificate.
+pub fn validity(der: &[u8]) -> Option<Validity> {
+    // The validity follows the serial number, the signature algorithm and the issuer.
+    let mut tbs = tbs_fields(der)?;
+    for _ in 0..3 {
+        tbs = read_element(tbs)?.2;
+    }
+    let (TAG_SEQUENCE, validity, _) = read_element(tbs)? else {
+        return None;
+    };
+    let (not_before_tag, not_before, rest) = read_element(validity)?;
+    let (not_after_tag, not_after, _) = read_element(rest)?;
+    Some(Validity {
+        not_before: decode_time(not_before_tag, not_before)?,
+        not_after: decode_time(not_after_tag, not_after)?,
+    })
+}
+
+/// Decode a UTCTime (`YYMMDDHHMMSSZ`) or a GeneralizedTime (`YYYYMMDDHHMMSSZ`).
+fn decode_time(tag: u8, contents: &[u8]) -> Option<OffsetDateTime> {
+    let text = std::str::from_utf8(contents).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        TAG_UTC_TIME => {
+            let (year, rest) = text.split_at_checked(2)?;
+            let year: i32 = year.parse().ok()?;
+            // RFC 5280 maps two-digit years to 1950 to 2049.
+            (if year >= 50 { 1900 + year } else { 2000 + year }, rest)
+        }
+        TAG_GENERALIZED_TIME => {
+            let (year, rest) = text.split_at_checked(4)?;
+            (year.parse().ok()?, rest)
+        }
+        _ => return None,
+    };
+    if rest.len() != 10 || !rest.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |i: usize| rest[i..i + 2].parse::<u8>().ok();
+    let date = Date::from_calendar_date(year, Month::try_from(field(0)?).ok()?, field(2)?).ok()?;
+    let time = Time::from_hms(field(4)?, field(6)?, field(8)?).ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+/// The name of the subject of a DER encoded certificate, e.g. `Microsoft UEFI CA 2023`.
+///
+/// This is the common name, or the organization if there is none.
+pub fn subject_name(der: &[u8]) -> Option<String> {
+    // The subject follows the serial number, the signature algorithm, the issuer and the
+    // validity.
+    let mut tbs = tbs_fields(der)?;
+    for _ in 0..4 {
+        tbs = read_element(tbs)?.2;
+    }
+    let (TAG_SEQUENCE, subject, _) = read_element(tbs)? else {
+        return None;
+    };
+
+    let mut common_name = None;
+    let mut organization = None;
+    let mut rest = subject;
+    while !rest.is_empty() {
+        let (TAG_SET, set, next) = read_element(rest)? else {
+            return None;
+        };
+        rest = next;
+        let (TAG_SEQUENCE, attribute, _) = read_element(set)? else {
+            return None;
+        };
+        let (TAG_OID, oid, value) = read_element(attribute)? else {
+            return None;
+        };
+        let value = decode_string(value);
+        match oid {
+            OID_COMMON_NAME => common_name = common_name.or(value),
+            OID_ORGANIZATION => organization = organization.or(value),
+            _ => (),
+        }
+    }
+    common_name.or(organization)
+}
+
+/// Read a DER element. Returns its tag, its contents and the data after it.
+pub(crate) fn read_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&length, mut rest) = rest.split_first()?;
+    let length = if length < 0x80 {
+        usize::from(length)
+    } else {
+        // The long form gives the number of length bytes, of which we support up to four.
+        let count = usize::from(length & 0x7f);
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let (bytes, after) = rest.split_at_checked(count)?;
+        rest = after;
+        bytes
+            .iter()
+            .fold(0usize, |length, &b| (length << 8) | usize::from(b))
+    };
+    let (contents, rest) = rest.split_at_checked(length)?;
+    Some((tag, contents, rest))
+}
+
+/// Decode a DER string, like the values of name attributes.
+fn decode_string(data: &[u8]) -> Option<String> {
+    let (tag, contents, _) = read_element(data)?;
+    match tag {
+        TAG_UTF8_STRING | TAG_PRINTABLE_STRING | TAG_T61_STRING | TAG_IA5_STRING => {
+            Some(String::from_utf8_lossy(contents).into_owned())
+        }
+        TAG_BMP_STRING => Some(String::from_utf16_lossy(
+            &contents
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        )),
+        _ => None,
+    }
+}
+
+/// Decode standard base64, ignoring whitespace.
+fn decode_base64(encoded: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in encoded.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => bail!("Invalid base64 character {:?}", char::from(c)),
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed certificate with the subject `O=Lanzaboote, CN=Lanzaboote Test DB`.
+    const TEST_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBuDCCAV+gAwIBAgIUdIKZqrTm/m8fkqeL11/0lZdRyIMwCgYIKoZIzj0EAwIw
+MjETMBEGA1UECgwKTGFuemFib290ZTEbMBkGA1UEAwwSTGFuemFib290ZSBUZXN0
+IERCMB4XDTI2MTAxNTA0Mzg0OVoXDTI2MTAxNjA0Mzg0OVowMjETMBEGA1UECgwK
+TGFuemFib290ZTEbMBkGA1UEAwwSTGFuemFib290ZSBUZXN0IERCMFkwEwYHKoZI
+zj0CAQYIKoZIzj0DAQcDQgAEcl7F7NlA+DVx67xxfYLR7gCMAW25i0xBSTOj5Qvt
+PnSxIo0k7pjZmUOnuhFOb6+X+kEiWvThvPnbu5QbuFFREqNTMFEwHQYDVR0OBBYE
+FLyM84t4Seo/8UogyT/UokydWLnHMB8GA1UdIwQYMBaAFLyM84t4Seo/8UogyT/U
+okydWLnHMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIgBYiaDyZD
+JeJGozQTy2obOFm3uGAnYf/hZGTQDlWNSzYCIEMyqXNafO/y28hmKFMFCS5CA75Y
+I6QsjElqDhitwJis
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn decode_base64_with_padding() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64("Zm9v\nYmFy\n").unwrap(), b"foobar");
+        assert!(decode_base64("Zm9v!").is_err());
+    }
+
+    #[test]
+    fn read_subject_name() {
+        let certificate = der_certificate(TEST_CERTIFICATE.as_bytes()).unwrap();
+        assert_eq!(
+            subject_name(&certificate).as_deref(),
+            Some("Lanzaboote Test DB")
+        );
+        assert_eq!(subject_name(&certificate[..100]), None);
+        assert_eq!(subject_name(&[0x30, 0x03, 0x02, 0x01, 0x01]), None);
+    }
+
+    #[test]
+    fn read_validity() {
+        let certificate = der_certificate(TEST_CERTIFICATE.as_bytes()).unwrap();
+        let validity = validity(&certificate).unwrap();
+        assert_eq!(validity.not_before.unix_timestamp(), 1_792_039_129);
+        assert_eq!(validity.not_after.unix_timestamp(), 1_792_125_529);
+
+        assert_eq!(
+            decode_time(TAG_GENERALIZED_TIME, b"20491231235959Z").map(|t| t.year()),
+            Some(2049)
+        );
+        assert_eq!(
+            decode_time(TAG_UTC_TIME, b"500101000000Z").map(|t| t.year()),
+            Some(1950)
+        );
+        assert_eq!(decode_time(TAG_UTC_TIME, b"501301000000Z"), None);
+    }
+
+    #[test]
+    fn pem_and_der_certificates_are_equivalent() {
+        let pem = format!("{PEM_BEGIN}\nMAMCAQE=\n{PEM_END}\n");
+        assert_eq!(
+            der_certificate(pem.as_bytes()).unwrap(),
+            [0x30, 0x03, 0x02, 0x01, 0x01]
+        );
+        assert_eq!(
+            der_certificate(&[0x30, 0x03, 0x02, 0x01, 0x01]).unwrap(),
+            [0x30, 0x03, 0x02, 0x01, 0x01]
+        );
+        assert!(der_certificate(b"not a certificate").is_err());
+        assert!(der_certificate(format!("{PEM_BEGIN}\nMAMCAQE=\n").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::signature_list::Guid;
+
+/// Where efivarfs is mounted.
+const EFIVARFS: &str = "/sys/firmware/efi/efivars";
+
+/// `EFI_GLOBAL_VARIABLE`: the vendor of the variables defined by the UEFI specification, e.g.
+/// `SecureBoot`.
+pub const EFI_GLOBAL_VARIABLE_GUID: Guid = Guid::new(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+/// `EFI_IMAGE_SECURITY_DATABASE_GUID`: the vendor of `db` and `dbx`.
+pub const EFI_IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::new(
+    0xd719b2cb,
+    0x3d3a,
+    0x4596,
+    [0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+
+/// The vendor of the variables of the Boot Loader Interface, e.g. `LoaderEntrySelected`.
+pub const LOADER_GUID: Guid = Guid::new(
+    0x4a67b082,
+    0x0a4c,
+    0x41cf,
+    [0xb6, 0xc7, 0x44, 0x0b, 0x29, 0xbb, 0x8c, 0x4f],
+);
+
+/// The vendor of the variables owned by Lanzaboote, e.g. `LanzabooteAttemptedEntry`.
+pub const LANZABOOTE_GUID: Guid = Guid::new(
+    0x14406d1c,
+    0x93f7,
+    0x4a09,
+    [0xa0, 0xd5, 0xd4, 0x86, 0x34, 0x51, 0xbd, 0x7e],
+);
+
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+pub const EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+pub const EFI_VARIABLE_APPEND_WRITE: u32 = 0x40;
+
+/// `FS_IMMUTABLE_FL`
+const FS_IMMUTABLE_FL: nix::libc::c_long = 0x10;
+
+nix::ioctl_read!(fs_ioc_getflags, b'f', 1, nix::libc::c_long);
+nix::ioctl_write_ptr!(fs_ioc_setflags, b'f', 2, nix::libc::c_long);
+
+fn variable_path(name: &str, vendor: &Guid) -> PathBuf {
+    Path::new(EFIVARFS).join(format!("{name}-{vendor}"))
+}
+
+/// Read an EFI variable. Returns `None` if the variable does not exist.
+pub fn read_variable(name: &str, vendor: &Guid) -> Result<Option<Vec<u8>>> {
+    let path = variable_path(name, vendor);
+    match fs::read(&path) {
+        // efivarfs prefixes the contents with the attributes of the variable.
+        Ok(data) => Ok(Some(data.get(4..).unwrap_or_default().to_vec())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read the EFI variable {path:?}")),
+    }
+}
+
+/// Read an EFI variable that contains a NUL-terminated UTF-16 string, like the variables of the
+/// Boot Loader Interface.
+pub fn read_string_variable(name: &str, vendor: &Guid) -> Result<Option<String>> {
+    let Some(data) = read_variable(name, vendor)? else {
+        return Ok(None);
+    };
+    let utf16 = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16(&utf16)
+        .map(Some)
+        .with_context(|| format!("The EFI variable {name} is not a valid UTF-16 string"))
+}
+
+/// Write an EFI variable with the given attributes.
+///
+/// efivarfs marks most existing variables immutable to protect against accidental writes. This
+/// flag is removed for the write and restored afterwards.
+pub fn write_variable(name: &str, vendor: &Guid, attributes: u32, data: &[u8]) -> Result<()> {
+    let path = variable_path(name, vendor);
+
+    let immutable = match File::open(&path) {
+        Ok(file) => Some(set_immutable(&file, false).with_context(|| {
+            format!("Failed to remove the immutable flag from the EFI variable {path:?}")
+        })?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to open the EFI variable {path:?}"))
+        }
+    };
+
+    let mut contents = attributes.to_le_bytes().to_vec();
+    contents.extend_from_slice(data);
+    // efivarfs requires the whole variable to be written with a single write call.
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(attributes & EFI_VARIABLE_APPEND_WRITE != 0)
+        .open(&path)
+        .and_then(|mut file| match file.write(&contents)? {
+            n if n == contents.len() => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::WriteZero)),
+        })
+        .with_context(|| format!("Failed to write the EFI variable {path:?}"));
+
+    if immutable == Some(true) {
+        if let Err(e) = File::open(&path)
+            .map_err(Into::into)
+            .and_then(|file| set_immutable(&file, true))
+        {
+            log::warn!("Failed to restore the immutable flag of the EFI variable {path:?}: {e:#}");
+        }
+    }
+
+    result
+}
+
+/// Write a persistent EFI variable that contains a NUL-terminated UTF-16 string, like the
+/// variables of the Boot Loader Interface.
+pub fn write_string_variable(name: &str, vendor: &Guid, value: &str) -> Result<()> {
+    let data = value
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<_>>();
+    write_variable(
+        name,
+        vendor,
+        EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS,
+        &data,
+    )
+}
+
+/// Delete an EFI variable. Deleting a variable that does not exist succeeds.
+pub fn delete_variable(name: &str, vendor: &Guid) -> Result<()> {
+    let path = variable_path(name, vendor);
+    match File::open(&path) {
+        Ok(file) => {
+            set_immutable(&file, false).with_context(|| {
+                format!("Failed to remove the immutable flag from the EFI variable {path:?}")
+            })?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to open the EFI variable {path:?}"))
+        }
+    }
+    fs::remove_file(&path).with_context(|| format!("Failed to delete the EFI variable {path:?}"))
+}
+
+/// Set or clear the immutable flag of a file. Returns whether it was set before.
+fn set_immutable(file: &File, immutable: bool) -> Result<bool> {
+    let mut flags: nix::libc::c_long = 0;
+    // SAFETY: The ioctl writes the flags of the open file to the provided integer.
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }?;
+    let was_immutable = flags & FS_IMMUTABLE_FL != 0;
+
+    if was_immutable != immutable {
+        flags ^= FS_IMMUTABLE_FL;
+        // SAFETY: The ioctl only reads the provided integer.
+        unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
+    }
+    Ok(was_immutable)
+}
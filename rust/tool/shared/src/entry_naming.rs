@@ -0,0 +1,198 @@
+//! How boot entries are titled and ordered in systemd-boot.
+//!
+//! systemd-boot takes the title of an entry from `PRETTY_NAME` in its `.osrel` section. It sorts
+//! entries by their sort key, `ID`, and then by their version, `VERSION_ID`, newest first, where
+//! numbers in versions are compared numerically. The sort key is the `sort_key` of the bootspec
+//! extension and orders NixOS relative to other operating systems. The title and the version are
+//! built from format strings with these placeholders:
+//!
+//! - `{label}`: the label of the bootspec, e.g. `NixOS 24.05.20240501.abcdef (Linux 6.8.9)`
+//! - `{nixos_version}`: the NixOS version, e.g. `24.05.20240501.abcdef`
+//! - `{generation}`: the generation number
+//! - `{specialisation}`: the name of the specialisation, empty if there is none
+//! - `{date}`: the build date of the generation
+//! - `{description}`: e.g. `Generation 42-debug, 2024-05-01`
+
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::generation::Generation;
+
+const PLACEHOLDERS: [&str; 6] = [
+    "label",
+    "nixos_version",
+    "generation",
+    "specialisation",
+    "date",
+    "description",
+];
+
+/// The title of entries, e.g. `{label} ({description})`, see the [module](self) documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryTitle(String);
+
+impl EntryTitle {
+    /// The title of `generation`.
+    pub fn format(&self, generation: &Generation) -> String {
+        format_entry(&self.0, generation)
+    }
+}
+
+impl Default for EntryTitle {
+    /// The title is unique per generation. systemd-boot would otherwise show `VERSION_ID` as
+    /// well, which is confusing to users (see #220).
+    fn default() -> Self {
+        Self("{label} ({description})".to_string())
+    }
+}
+
+impl fmt::Display for EntryTitle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for EntryTitle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        check_format(s)?;
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// How entries with the same sort key are ordered, i.e. what their version is.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum EntrySort {
+    /// The newest generation first.
+    #[default]
+    Generation,
+    /// The newest NixOS version first and the newest generation of each version first.
+    NixosVersion,
+    /// A format string for the version, see the [module](self) documentation.
+    Format(String),
+}
+
+impl EntrySort {
+    fn version_format(&self) -> &str {
+        match self {
+            Self::Generation => "{description}",
+            Self::NixosVersion => "{nixos_version} {description}",
+            Self::Format(format) => format,
+        }
+    }
+
+    /// The version of the entry of `generation`.
+    pub fn format(&self, generation: &Generation) -> String {
+        format_entry(self.version_format(), generation)
+    }
+}
+
+impl fmt::Display for EntrySort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Generation => f.write_str("generation"),
+            Self::NixosVersion => f.write_str("nixos-version"),
+            Self::Format(format) => f.write_str(format),
+        }
+    }
+}
+
+impl FromStr for EntrySort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "generation" => Ok(Self::Generation),
+            "nixos-version" => Ok(Self::NixosVersion),
+            format if format.contains('{') => {
+                check_format(format)?;
+                Ok(Self::Format(format.to_string()))
+            }
+            _ => bail!(
+                "Unknown entry sort {s:?}, expected generation, nixos-version or a format string"
+            ),
+        }
+    }
+}
+
+/// Check that a format string only contains known placeholders.
+fn check_format(format: &str) -> Result<()> {
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unterminated placeholder in {format:?}");
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            bail!(
+                "Unknown placeholder {{{placeholder}}} in {format:?}, expected one of {}",
+                PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", ")
+            );
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// The NixOS version of a generation, or its label if the toplevel does not record the version.
+fn nixos_version(generation: &Generation) -> String {
+    let bootspec = &generation.spec.bootspec.bootspec;
+    fs::read_to_string(bootspec.toplevel.0.join("nixos-version"))
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|_| bootspec.label.clone())
+}
+
+fn format_entry(format: &str, generation: &Generation) -> String {
+    let mut formatted = format.to_string();
+    if formatted.contains("{nixos_version}") {
+        formatted = formatted.replace("{nixos_version}", &nixos_version(generation));
+    }
+    let specialisation = generation
+        .specialisation_name
+        .as_ref()
+        .map(|name| name.0.clone())
+        .unwrap_or_default();
+    let date = generation
+        .build_time
+        .map(|date| date.to_string())
+        .unwrap_or_else(|| "Unknown".to_string());
+    for (placeholder, value) in [
+        ("{label}", generation.spec.bootspec.bootspec.label.clone()),
+        ("{generation}", generation.version.to_string()),
+        ("{specialisation}", specialisation),
+        ("{date}", date),
+        ("{description}", generation.describe()),
+    ] {
+        formatted = formatted.replace(placeholder, &value);
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!(
+            "{label} #{generation}".parse::<EntryTitle>().unwrap(),
+            EntryTitle("{label} #{generation}".to_string())
+        );
+        assert!("{name}".parse::<EntryTitle>().is_err());
+        assert!("{label".parse::<EntryTitle>().is_err());
+
+        assert_eq!(
+            "nixos-version".parse::<EntrySort>().unwrap(),
+            EntrySort::NixosVersion
+        );
+        assert_eq!(
+            "{date} {generation}".parse::<EntrySort>().unwrap(),
+            EntrySort::Format("{date} {generation}".to_string())
+        );
+        assert!("newest".parse::<EntrySort>().is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::prelude::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::utils::{file_hash, Hash};
+
+/// Abstraction over the filesystem operations performed on the ESP.
+///
+/// All paths are absolute, i.e. they include the ESP mountpoint. Files that are read from
+/// elsewhere (e.g. the Nix store) do not go through this trait.
+///
+/// This exists so that the install and garbage collection logic can be exercised without a
+/// mounted ESP. Use [`PhysicalEspFilesystem`] for the real thing and [`InMemoryEspFilesystem`] in
+/// tests.
+pub trait EspFilesystem {
+    /// Read the whole contents of a file.
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// The SHA256 hash of a file.
+    fn hash(&self, path: &Path) -> Result<Hash> {
+        Ok(Sha256::digest(self.read(path)?))
+    }
+
+    /// Create or overwrite a file. Missing parent directories are created.
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<()>;
+
+    /// Rename a file, replacing the destination if it exists.
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Delete a file or a directory including all of its children.
+    fn delete(&mut self, path: &Path) -> Result<()>;
+
+    /// List the direct children of a directory in lexicographic order.
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>>;
+
+    /// Whether a file or directory exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Whether `path` is a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Flush all writes below `path` to persistent storage.
+    fn sync(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The ESP as mounted on the running system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PhysicalEspFilesystem;
+
+impl EspFilesystem for PhysicalEspFilesystem {
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).with_context(|| format!("Failed to read {path:?}"))
+    }
+
+    /// Hash the file without reading it into memory, see [`file_hash`].
+    fn hash(&self, path: &Path) -> Result<Hash> {
+        file_hash(path)
+    }
+
+    /// Write a file and sync it to disk.
+    ///
+    /// The permission bits of the file are set to 0o755, the expected permissions for a vfat ESP.
+    /// This is useful for producing file systems trees which can then be converted to a file
+    /// system image.
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create the file {path:?}"))?;
+        file.write_all(contents)
+            .with_context(|| format!("Failed to write to {path:?}"))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync the file {path:?}"))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to set permission bits to 0o755 on file: {path:?}"))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to).with_context(|| format!("Failed to move {from:?} to {to:?}"))
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+                .with_context(|| format!("Failed to remove directory: {path:?}"))
+        } else {
+            fs::remove_file(path).with_context(|| format!("Failed to remove file: {path:?}"))
+        }
+    }
+
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut children = fs::read_dir(path)
+            .with_context(|| format!("Failed to read directory {path:?}"))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()
+            .with_context(|| format!("Failed to read directory entries of {path:?}"))?;
+        children.sort();
+        Ok(children)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn sync(&self, path: &Path) -> Result<()> {
+        let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        nix::unistd::syncfs(file.as_raw_fd())
+            .with_context(|| format!("Failed to sync the filesystem containing {path:?}"))
+    }
+}
+
+/// An ESP that only lives in memory.
+///
+/// Directories are tracked explicitly, so that empty directories behave like they do on a real
+/// filesystem.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryEspFilesystem {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    directories: BTreeSet<PathBuf>,
+}
+
+impl InMemoryEspFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a directory and all its parents.
+    pub fn create_dir_all(&mut self, path: &Path) {
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            self.directories.insert(ancestor.to_path_buf());
+        }
+    }
+
+    /// All files currently stored, in lexicographic order.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+}
+
+impl EspFilesystem for InMemoryEspFilesystem {
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        self.files
+            .get(path)
+            .cloned()
+            .with_context(|| format!("Failed to read {path:?}: no such file"))
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        if self.directories.contains(path) {
+            bail!("Failed to write {path:?}: is a directory");
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent);
+        }
+        self.files.insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if self.directories.contains(to) {
+            bail!("Failed to move {from:?} to {to:?}: destination is a directory");
+        }
+        let contents = self
+            .files
+            .remove(from)
+            .with_context(|| format!("Failed to move {from:?} to {to:?}: no such file"))?;
+        self.write(to, &contents)
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<()> {
+        if self.files.remove(path).is_some() {
+            return Ok(());
+        }
+        if !self.directories.contains(path) {
+            bail!("Failed to remove {path:?}: no such file or directory");
+        }
+        self.files.retain(|p, _| !p.starts_with(path));
+        self.directories.retain(|p| !p.starts_with(path));
+        Ok(())
+    }
+
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        if !self.directories.contains(path) {
+            bail!("Failed to read directory {path:?}: no such directory");
+        }
+        let children = self
+            .files
+            .keys()
+            .chain(self.directories.iter())
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect::<BTreeSet<PathBuf>>();
+        Ok(children.into_iter().collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path) || self.directories.contains(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.directories.contains(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_memory_write_creates_parents() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        esp.write(Path::new("/esp/EFI/nixos/kernel.efi"), b"kernel")?;
+
+        assert!(esp.is_dir(Path::new("/esp/EFI/nixos")));
+        assert!(esp.is_dir(Path::new("/esp")));
+        assert_eq!(esp.read(Path::new("/esp/EFI/nixos/kernel.efi"))?, b"kernel");
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_rename_replaces_destination() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        esp.write(Path::new("/esp/a.tmp"), b"new")?;
+        esp.write(Path::new("/esp/a"), b"old")?;
+        esp.rename(Path::new("/esp/a.tmp"), Path::new("/esp/a"))?;
+
+        assert!(!esp.exists(Path::new("/esp/a.tmp")));
+        assert_eq!(esp.read(Path::new("/esp/a"))?, b"new");
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_delete_directory_removes_children() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        esp.write(Path::new("/esp/dir/sub/file"), b"")?;
+        esp.write(Path::new("/esp/other"), b"")?;
+        esp.delete(Path::new("/esp/dir"))?;
+
+        assert!(!esp.exists(Path::new("/esp/dir/sub/file")));
+        assert!(!esp.exists(Path::new("/esp/dir/sub")));
+        assert!(esp.exists(Path::new("/esp/other")));
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_list_returns_direct_children() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        esp.write(Path::new("/esp/b"), b"")?;
+        esp.write(Path::new("/esp/a/nested"), b"")?;
+        esp.create_dir_all(Path::new("/esp/c"));
+
+        assert_eq!(
+            esp.list(Path::new("/esp"))?,
+            vec![
+                PathBuf::from("/esp/a"),
+                PathBuf::from("/esp/b"),
+                PathBuf::from("/esp/c")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn physical_and_in_memory_agree_on_listing() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        let mut physical = PhysicalEspFilesystem;
+        let mut in_memory = InMemoryEspFilesystem::new();
+        in_memory.create_dir_all(root);
+
+        for esp in [
+            &mut physical as &mut dyn EspFilesystem,
+            &mut in_memory as &mut dyn EspFilesystem,
+        ] {
+            esp.write(&root.join("EFI/Linux/entry.efi"), b"stub")?;
+            esp.write(&root.join("loader/loader.conf"), b"timeout 0")?;
+        }
+
+        assert_eq!(physical.list(root)?, in_memory.list(root)?);
+        assert_eq!(
+            physical.list(&root.join("EFI/Linux"))?,
+            in_memory.list(&root.join("EFI/Linux"))?
+        );
+        Ok(())
+    }
+}
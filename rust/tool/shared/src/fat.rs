@@ -0,0 +1,758 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fatfs::{Date, DateTime, Dir, FatType, FileSystem, FormatVolumeOptions, FsOptions, Time};
+use fscommon::StreamSlice;
+use gptman::GPT;
+use time::OffsetDateTime;
+
+use crate::esp_fs::EspFilesystem;
+
+/// The partition type of an ESP in a GPT, in its on-disk byte order.
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// The characters besides upper case letters and digits that short names may contain.
+const SHORT_NAME_SPECIAL_CHARACTERS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// The part of the device that holds the file system.
+type Partition = StreamSlice<File>;
+
+/// An ESP that is written to its block device or disk image without mounting it.
+///
+/// The file system is accessed with the `fatfs` crate, which supports FAT12, FAT16 and FAT32 with
+/// long file names. The device may also be a whole disk with a GPT, in which case its EFI system
+/// partition is used. Paths are resolved below `root`, the path at which the ESP is presented to
+/// the installer, e.g. `/boot`.
+///
+/// The file system is marked as dirty while it is open and unmounted when this is dropped, so that
+/// `fsck.fat` notices an interrupted installation.
+pub struct FatEspFilesystem {
+    fs: FileSystem<Partition>,
+    /// Another handle of the device, to sync it.
+    device: File,
+    root: PathBuf,
+}
+
+impl FatEspFilesystem {
+    /// Open the FAT file system on `device`, which may be a partition, a disk with a GPT, or an
+    /// image of either.
+    pub fn open(device: &Path, root: &Path) -> Result<Self> {
+        let mut device_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device)
+            .with_context(|| format!("Failed to open {device:?}"))?;
+        let partition = esp_range(&mut device_file)
+            .with_context(|| format!("Failed to locate the ESP on {device:?}"))?;
+
+        let partition = StreamSlice::new(device_file.try_clone()?, partition.start, partition.end)
+            .with_context(|| format!("Failed to open {device:?}"))?;
+        let options = FsOptions::new().time_provider(&CLOCK);
+        let fs = FileSystem::new(partition, options)
+            .with_context(|| format!("{device:?} does not contain a supported FAT file system"))?;
+
+        Ok(Self {
+            fs,
+            device: device_file,
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// The path of `path` relative to the root of the file system, with `/` as the separator.
+    ///
+    /// The root itself is the empty string.
+    fn relative(&self, path: &Path) -> Result<String> {
+        let relative = path
+            .strip_prefix(&self.root)
+            .with_context(|| format!("{path:?} is not on the ESP at {:?}", self.root))?;
+        let components = relative
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => name
+                    .to_str()
+                    .with_context(|| format!("{path:?} is not valid UTF-8")),
+                _ => bail!("{path:?} is not a normalized path"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(components.join("/"))
+    }
+
+    /// Split a path into its parent directory and its file name, both relative to the root.
+    fn split<'a>(&self, path: &'a Path) -> Result<(String, &'a str)> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+        else {
+            bail!("{path:?} is not a file name");
+        };
+        Ok((self.relative(parent)?, name))
+    }
+
+    /// Open the directory at `relative`, if it is one.
+    fn directory(&self, relative: &str) -> io::Result<Dir<'_, Partition>> {
+        let root = self.fs.root_dir();
+        if relative.is_empty() {
+            Ok(root)
+        } else {
+            root.open_dir(relative)
+        }
+    }
+
+    /// Open the directory at `relative` and create it and its parents if they are missing.
+    fn create_dir_all(&self, relative: &str) -> Result<Dir<'_, Partition>> {
+        let mut directory = self.fs.root_dir();
+        for name in relative.split('/').filter(|name| !name.is_empty()) {
+            directory = match directory.open_dir(name) {
+                Ok(child) => child,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => directory
+                    .create_dir(name)
+                    .with_context(|| format!("Failed to create the directory {name:?}"))?,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to open the directory {name:?}"))
+                }
+            };
+        }
+        Ok(directory)
+    }
+
+    /// Delete the children of a directory, recursively.
+    fn delete_children(directory: &Dir<'_, Partition>) -> Result<()> {
+        for entry in directory.iter() {
+            let entry = entry.context("Failed to read a directory")?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            if entry.is_dir() {
+                Self::delete_children(&entry.to_dir())?;
+            }
+            directory
+                .remove(&name)
+                .with_context(|| format!("Failed to remove {name:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl EspFilesystem for FatEspFilesystem {
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let relative = self.relative(path)?;
+        if self.is_dir(path) {
+            bail!("Failed to read {path:?}: is a directory");
+        }
+        let mut contents = Vec::new();
+        self.fs
+            .root_dir()
+            .open_file(&relative)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(contents)
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        let (parent, name) = self.split(path)?;
+        if u32::try_from(contents.len()).is_err() {
+            bail!("Failed to write {path:?}: too large for FAT");
+        }
+        if self.is_dir(path) {
+            bail!("Failed to write {path:?}: is a directory");
+        }
+
+        let directory = self
+            .create_dir_all(&parent)
+            .with_context(|| format!("Failed to write {path:?}"))?;
+        directory
+            .create_file(name)
+            .and_then(|mut file| {
+                file.truncate()?;
+                file.write_all(contents)?;
+                file.flush()
+            })
+            .with_context(|| format!("Failed to write {path:?}"))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let (from_parent, from_name) = self.split(from)?;
+        let (to_parent, to_name) = self.split(to)?;
+        if !self.exists(from) {
+            bail!("Failed to move {from:?} to {to:?}: no such file");
+        }
+        if self.is_dir(from) {
+            bail!("Failed to move {from:?} to {to:?}: source is a directory");
+        }
+        if self.is_dir(to) {
+            bail!("Failed to move {from:?} to {to:?}: destination is a directory");
+        }
+        // FAT is case-insensitive, so both paths may name the same file.
+        if from_parent.eq_ignore_ascii_case(&to_parent)
+            && from_name.to_lowercase() == to_name.to_lowercase()
+        {
+            return Ok(());
+        }
+
+        let from_directory = self.directory(&from_parent)?;
+        let to_directory = self.create_dir_all(&to_parent)?;
+        // fatfs does not replace files when renaming.
+        if self.exists(to) {
+            to_directory
+                .remove(to_name)
+                .with_context(|| format!("Failed to move {from:?} to {to:?}"))?;
+        }
+        from_directory
+            .rename(from_name, &to_directory, to_name)
+            .with_context(|| format!("Failed to move {from:?} to {to:?}"))
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<()> {
+        if self.relative(path)?.is_empty() {
+            bail!("Failed to remove {path:?}: it is the root of the ESP");
+        }
+        if !self.exists(path) {
+            bail!("Failed to remove {path:?}: no such file or directory");
+        }
+
+        let (parent, name) = self.split(path)?;
+        let directory = self.directory(&parent)?;
+        if self.is_dir(path) {
+            Self::delete_children(&directory.open_dir(name)?)
+                .with_context(|| format!("Failed to remove {path:?}"))?;
+        }
+        directory
+            .remove(name)
+            .with_context(|| format!("Failed to remove {path:?}"))
+    }
+
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let directory = self
+            .relative(path)
+            .ok()
+            .and_then(|relative| self.directory(&relative).ok())
+            .with_context(|| format!("Failed to read directory {path:?}: no such directory"))?;
+        let mut children = Vec::new();
+        for entry in directory.iter() {
+            let name = entry
+                .with_context(|| format!("Failed to read directory {path:?}"))?
+                .file_name();
+            if name != "." && name != ".." {
+                children.push(path.join(name));
+            }
+        }
+        children.sort();
+        Ok(children)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_dir(path)
+            || self
+                .relative(path)
+                .is_ok_and(|relative| self.fs.root_dir().open_file(&relative).is_ok())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.relative(path)
+            .is_ok_and(|relative| self.directory(&relative).is_ok())
+    }
+
+    /// Sync the device. The file system is only marked as clean when it is dropped.
+    fn sync(&self, _path: &Path) -> Result<()> {
+        self.device
+            .sync_all()
+            .context("Failed to sync the ESP device")
+    }
+}
+
+/// Create an empty FAT file system of `size` bytes at `offset` on `device`, like `mkfs.fat`.
+///
+/// FAT32 is used if the file system is large enough for it, which the UEFI specification
+/// recommends for ESPs, and FAT16 or FAT12 otherwise. With `SOURCE_DATE_EPOCH` and a fixed
+/// `volume_id`, the image is reproducible.
+pub fn format(device: &File, offset: u64, size: u64, label: &str, volume_id: u32) -> Result<()> {
+    let label = volume_label(label)?;
+    let end = offset
+        .checked_add(size)
+        .context("The file system is too large")?;
+    let partition = StreamSlice::new(device, offset, end).context("Failed to open the device")?;
+    // This only selects the cluster size of FAT32. fatfs falls back to FAT16 and FAT12 if there
+    // are too few clusters for it.
+    let options = FormatVolumeOptions::new()
+        .fat_type(FatType::Fat32)
+        .volume_label(label)
+        .volume_id(volume_id);
+    fatfs::format_volume(partition, options).context("Failed to create the file system")?;
+    device.sync_all().context("Failed to sync the file system")
+}
+
+/// A volume ID that is derived from the time, like DOS did, or `SOURCE_DATE_EPOCH`.
+pub fn default_volume_id() -> u32 {
+    let DateTime { date, time } = now();
+    let date = (date.year - 1980) << 9 | date.month << 5 | date.day;
+    let time = time.hour << 11 | time.min << 5 | (time.sec / 2);
+    u32::from(date) << 16 | u32::from(time)
+}
+
+/// The label of a file system as stored in its boot sector and root directory.
+fn volume_label(label: &str) -> Result<[u8; 11]> {
+    let label = label.to_ascii_uppercase();
+    if label.is_empty()
+        || label.len() > 11
+        || !label
+            .bytes()
+            .all(|c| c == b' ' || is_short_name_character(c))
+    {
+        bail!("Invalid file system label {label:?}: it must be up to 11 characters that are valid in FAT short names");
+    }
+    let mut volume_label = [b' '; 11];
+    volume_label[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(volume_label)
+}
+
+fn is_short_name_character(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL_CHARACTERS.contains(&c)
+}
+
+/// Find the bytes of the device that hold the FAT file system.
+///
+/// If the device contains a GPT, this is its EFI system partition. Otherwise the device is
+/// expected to be the partition itself.
+fn esp_range(device: &mut File) -> Result<std::ops::Range<u64>> {
+    let Some(gpt) = read_gpt(device)? else {
+        let end = device.seek(SeekFrom::End(0))?;
+        device.rewind()?;
+        return Ok(0..end);
+    };
+    let (_, esp) = gpt
+        .iter()
+        .find(|(_, entry)| entry.partition_type_guid == ESP_TYPE_GUID)
+        .context("The GPT has no EFI system partition")?;
+    Ok(esp.starting_lba * gpt.sector_size..(esp.ending_lba + 1) * gpt.sector_size)
+}
+
+/// Whether partition `number`, counting from 1, of the disk `disk` is an EFI system partition.
+///
+/// Returns `None` if the disk has no GPT, e.g. because it is partitioned with an MBR.
+pub fn is_esp_partition(disk: &Path, number: usize) -> Result<Option<bool>> {
+    let mut device = File::open(disk).with_context(|| format!("Failed to open {disk:?}"))?;
+    let Some(gpt) = read_gpt(&mut device).with_context(|| format!("Failed to read {disk:?}"))?
+    else {
+        return Ok(None);
+    };
+    let (_, entry) = gpt
+        .iter()
+        .find(|(index, entry)| *index as usize == number && entry.is_used())
+        .with_context(|| format!("The GPT of {disk:?} has no partition {number}"))?;
+    Ok(Some(entry.partition_type_guid == ESP_TYPE_GUID))
+}
+
+/// Read the GPT of a disk, if it has one.
+fn read_gpt(device: &mut File) -> Result<Option<GPT>> {
+    match GPT::find_from(device) {
+        Ok(gpt) => Ok(Some(gpt)),
+        Err(gptman::Error::InvalidSignature) => Ok(None),
+        Err(e) => Err(e).context("Failed to read the GPT"),
+    }
+}
+
+/// Provides the timestamps of files, see [`now`].
+#[derive(Debug)]
+struct Clock;
+
+static CLOCK: Clock = Clock;
+
+impl fatfs::TimeProvider for Clock {
+    fn get_current_date(&self) -> Date {
+        now().date
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        now()
+    }
+}
+
+/// The current date and time, or those of `SOURCE_DATE_EPOCH` for reproducible images.
+fn now() -> DateTime {
+    let now = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| OffsetDateTime::from_unix_timestamp(epoch).ok())
+        .unwrap_or_else(OffsetDateTime::now_utc);
+    // FAT cannot represent dates before 1980.
+    if now.year() < 1980 {
+        return DateTime {
+            date: Date {
+                year: 1980,
+                month: 1,
+                day: 1,
+            },
+            time: Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        };
+    }
+
+    DateTime {
+        date: Date {
+            year: now.year().min(2107) as u16,
+            month: u16::from(u8::from(now.month())),
+            day: u16::from(now.day()),
+        },
+        time: Time {
+            hour: u16::from(now.hour()),
+            min: u16::from(now.minute()),
+            sec: u16::from(now.second()),
+            millis: now.millisecond(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    /// Create a file system image of `size` bytes at `offset` in `image`.
+    fn format_image(image: &Path, offset: u64, size: u64) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(image)?;
+        file.set_len(offset + size)?;
+        format(&file, offset, size, "ESP", 0x1234_5678)
+    }
+
+    fn free_clusters(esp: &FatEspFilesystem) -> Result<u32> {
+        Ok(esp.fs.stats()?.free_clusters())
+    }
+
+    /// Whether dosfstools is installed. The tests that check images with it are skipped
+    /// otherwise; the flake always provides it.
+    fn has_dosfstools() -> bool {
+        let installed = Command::new("fsck.fat").arg("--help").output().is_ok();
+        if !installed {
+            eprintln!("dosfstools is not installed, skipping the checks with fsck.fat");
+        }
+        installed
+    }
+
+    /// Check an image with `fsck.fat` without repairing it.
+    fn fsck(image: &Path) -> Result<()> {
+        let output = Command::new("fsck.fat").arg("-n").arg(image).output()?;
+        if !output.status.success() {
+            bail!(
+                "fsck.fat found problems in {image:?}:\n{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+
+    /// Write, overwrite, rename and delete files like an installation does, so that directories
+    /// grow, files are fragmented and the FAT is updated all over. Returns the files that remain.
+    fn populate(image: &Path, root: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        let mut esp = FatEspFilesystem::open(image, root)?;
+        let names = (0..60)
+            .map(|i| root.join(format!("EFI/nixos/nixos-generation-{i}-long-name.efi")))
+            .collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            esp.write(name, &vec![i as u8; 700 + 37 * i])?;
+        }
+        for name in names.iter().step_by(2) {
+            esp.delete(name)?;
+        }
+        let large = (0..30_000u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        esp.write(&root.join("EFI/nixos/large.efi.tmp"), &large)?;
+        esp.rename(
+            &root.join("EFI/nixos/large.efi.tmp"),
+            &root.join("EFI/nixos/large.efi"),
+        )?;
+        esp.write(&root.join("EFI/BOOT/BOOTX64.EFI"), b"systemd-boot")?;
+        esp.write(&root.join("loader/loader.conf"), b"timeout 0\n")?;
+        esp.write(&root.join("loader/loader.conf"), b"timeout 5\n")?;
+        esp.write(&root.join("loader/entries/old.conf"), b"old")?;
+        esp.delete(&root.join("loader/entries"))?;
+        esp.sync(root)?;
+
+        let mut files = names
+            .into_iter()
+            .enumerate()
+            .skip(1)
+            .step_by(2)
+            .map(|(i, name)| (name, vec![i as u8; 700 + 37 * i]))
+            .collect::<Vec<_>>();
+        files.push((root.join("EFI/nixos/large.efi"), large));
+        files.push((root.join("EFI/BOOT/BOOTX64.EFI"), b"systemd-boot".to_vec()));
+        files.push((root.join("loader/loader.conf"), b"timeout 5\n".to_vec()));
+        Ok(files)
+    }
+
+    fn check_files(image: &Path, root: &Path, files: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+        let esp = FatEspFilesystem::open(image, root)?;
+        for (path, contents) in files {
+            assert_eq!(&esp.read(path)?, contents, "{path:?}");
+        }
+        assert_eq!(esp.list(&root.join("EFI/nixos"))?.len(), 31);
+        assert_eq!(
+            esp.list(&root.join("loader"))?,
+            [root.join("loader/loader.conf")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_back() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        for (size, fat_type) in [(16 << 20, FatType::Fat16), (40 << 20, FatType::Fat32)] {
+            let image = tempdir.path().join(format!("{fat_type:?}.img"));
+            format_image(&image, 0, size)?;
+            let root = Path::new("/boot");
+
+            let mut esp = FatEspFilesystem::open(&image, root)?;
+            assert_eq!(esp.fs.fat_type(), fat_type);
+            let kernel = (0..10_000u32)
+                .flat_map(u32::to_le_bytes)
+                .collect::<Vec<_>>();
+            esp.write(&root.join("EFI/nixos/kernel-6.1.1-abc.efi"), &kernel)?;
+            esp.write(&root.join("EFI/BOOT/BOOTX64.EFI"), b"systemd-boot")?;
+            esp.write(&root.join("loader/loader.conf"), b"timeout 0\n")?;
+            esp.write(&root.join("loader/entries.srel"), b"")?;
+            drop(esp);
+
+            // Everything is still there after reopening the file system.
+            let esp = FatEspFilesystem::open(&image, root)?;
+            assert_eq!(
+                esp.read(&root.join("EFI/nixos/kernel-6.1.1-abc.efi"))?,
+                kernel
+            );
+            assert_eq!(esp.read(&root.join("loader/entries.srel"))?, b"");
+            // FAT is case-insensitive.
+            assert_eq!(
+                esp.read(&root.join("efi/boot/bootx64.efi"))?,
+                b"systemd-boot"
+            );
+            assert_eq!(esp.list(root)?, [root.join("EFI"), root.join("loader")]);
+            assert_eq!(
+                esp.list(&root.join("loader"))?,
+                [
+                    root.join("loader/entries.srel"),
+                    root.join("loader/loader.conf")
+                ]
+            );
+            assert!(esp.is_dir(&root.join("EFI/nixos")));
+            assert!(!esp.is_dir(&root.join("loader/loader.conf")));
+            assert!(!esp.exists(&root.join("loader/loader.conf/nested")));
+            assert!(esp.read(Path::new("/efi/loader/loader.conf")).is_err());
+            assert!(esp.read(&root.join("EFI")).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn overwrite_rename_and_delete_free_space() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = tempdir.path().join("esp.img");
+        format_image(&image, 0, 40 << 20)?;
+        let root = Path::new("/boot");
+        let mut esp = FatEspFilesystem::open(&image, root)?;
+        let free = free_clusters(&esp)?;
+
+        esp.write(&root.join("EFI/nixos/stub.efi"), &[1; 5000])?;
+        esp.write(&root.join("EFI/nixos/stub.efi"), &[2; 3000])?;
+        esp.write(&root.join("EFI/nixos/stub.efi.tmp"), &[3; 2000])?;
+        esp.rename(
+            &root.join("EFI/nixos/stub.efi.tmp"),
+            &root.join("EFI/nixos/stub.efi"),
+        )?;
+        assert!(!esp.exists(&root.join("EFI/nixos/stub.efi.tmp")));
+        assert_eq!(esp.read(&root.join("EFI/nixos/stub.efi"))?, [3; 2000]);
+        // Two directories and the four clusters of the file.
+        assert_eq!(free_clusters(&esp)?, free - 6);
+
+        // Renaming a file to itself in another case keeps it.
+        esp.rename(
+            &root.join("EFI/nixos/stub.efi"),
+            &root.join("efi/NIXOS/STUB.EFI"),
+        )?;
+        assert_eq!(esp.read(&root.join("EFI/nixos/stub.efi"))?, [3; 2000]);
+
+        esp.rename(
+            &root.join("EFI/nixos/stub.efi"),
+            &root.join("EFI/Linux/moved.efi"),
+        )?;
+        assert_eq!(esp.read(&root.join("EFI/Linux/moved.efi"))?, [3; 2000]);
+        assert!(esp
+            .rename(&root.join("EFI/Linux/moved.efi"), &root.join("EFI"))
+            .is_err());
+        assert!(esp
+            .write(&root.join("EFI/Linux"), b"not a directory")
+            .is_err());
+
+        esp.delete(&root.join("EFI"))?;
+        assert!(!esp.exists(&root.join("EFI/Linux/moved.efi")));
+        assert_eq!(esp.list(root)?, Vec::<PathBuf>::new());
+        assert_eq!(free_clusters(&esp)?, free);
+        assert!(esp.delete(&root.join("EFI")).is_err());
+        assert!(esp.delete(root).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn grow_directories_and_fragment_files() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = tempdir.path().join("esp.img");
+        format_image(&image, 0, 40 << 20)?;
+        let root = Path::new("/boot");
+
+        let files = populate(&image, root)?;
+        check_files(&image, root, &files)
+    }
+
+    #[test]
+    fn format_file_systems_of_any_size() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Path::new("/boot");
+        for (size, fat_type, cluster_size) in [
+            (1 << 20, FatType::Fat12, 512),
+            (8 << 20, FatType::Fat16, 512),
+            (32 << 20, FatType::Fat16, 512),
+            (34 << 20, FatType::Fat32, 512),
+            (300 << 20, FatType::Fat32, 4096),
+        ] {
+            let image = tempdir.path().join(format!("{size}.img"));
+            let file = File::options()
+                .create_new(true)
+                .read(true)
+                .write(true)
+                .open(&image)?;
+            file.set_len(size)?;
+            format(&file, 0, size, "nixos-esp", 1)?;
+
+            let mut esp = FatEspFilesystem::open(&image, root)?;
+            assert_eq!(esp.fs.fat_type(), fat_type, "{size}");
+            assert_eq!(esp.fs.cluster_size(), cluster_size, "{size}");
+            assert_eq!(esp.fs.volume_id(), 1);
+            assert_eq!(esp.fs.volume_label(), "NIXOS-ESP");
+            // The volume label is not a file.
+            assert_eq!(esp.list(root)?, Vec::<PathBuf>::new());
+            esp.write(&root.join("loader/loader.conf"), b"timeout 0\n")?;
+            assert_eq!(esp.list(root)?, [root.join("loader")]);
+        }
+
+        let file = File::create(tempdir.path().join("small.img"))?;
+        file.set_len(8 << 20)?;
+        assert!(format(&file, 0, 8 << 20, "a/b", 1).is_err());
+        assert!(format(&file, 0, 8 << 20, "twelve-chars", 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn find_esp_in_disk_image() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = tempdir.path().join("disk.img");
+        let (start, end) = (2048, 2048 + (40 << 20) / 512 - 1);
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&image)?;
+        file.set_len((end + 2048) * 512)?;
+
+        let mut gpt = GPT::new_from(&mut file, 512, [0xab; 16])?;
+        // The ESP is the second partition.
+        gpt[1] = gptman::GPTPartitionEntry {
+            partition_type_guid: [0x11; 16],
+            unique_partition_guid: [0x22; 16],
+            starting_lba: 64,
+            ending_lba: start - 1,
+            attribute_bits: 0,
+            partition_name: "data".into(),
+        };
+        gpt[2] = gptman::GPTPartitionEntry {
+            partition_type_guid: ESP_TYPE_GUID,
+            unique_partition_guid: [0x33; 16],
+            starting_lba: start,
+            ending_lba: end,
+            attribute_bits: 0,
+            partition_name: "ESP".into(),
+        };
+        gpt.write_into(&mut file)?;
+        format_image(&image, start * 512, (end + 1 - start) * 512)?;
+
+        let root = Path::new("/boot");
+        let mut esp = FatEspFilesystem::open(&image, root)?;
+        esp.write(&root.join("loader/loader.conf"), b"timeout 0\n")?;
+        drop(esp);
+
+        assert_eq!(
+            FatEspFilesystem::open(&image, root)?.read(&root.join("loader/loader.conf"))?,
+            b"timeout 0\n"
+        );
+        assert_eq!(is_esp_partition(&image, 1)?, Some(false));
+        assert_eq!(is_esp_partition(&image, 2)?, Some(true));
+        assert!(is_esp_partition(&image, 3).is_err());
+
+        let partition = tempdir.path().join("partition.img");
+        format_image(&partition, 0, 8 << 20)?;
+        assert_eq!(is_esp_partition(&partition, 1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn images_pass_fsck() -> Result<()> {
+        if !has_dosfstools() {
+            return Ok(());
+        }
+        let tempdir = tempfile::tempdir()?;
+        let root = Path::new("/boot");
+        for size in [2 << 20, 16 << 20, 40 << 20] {
+            let image = tempdir.path().join(format!("{size}.img"));
+            format_image(&image, 0, size)?;
+            fsck(&image)?;
+
+            let files = populate(&image, root)?;
+            fsck(&image)?;
+            check_files(&image, root, &files)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_mkfs_fat_images() -> Result<()> {
+        if !has_dosfstools() {
+            return Ok(());
+        }
+        let tempdir = tempfile::tempdir()?;
+        let root = Path::new("/boot");
+        for (bits, size_kib, fat_type) in [
+            ("12", 2048, FatType::Fat12),
+            ("16", 16 * 1024, FatType::Fat16),
+            ("32", 64 * 1024, FatType::Fat32),
+        ] {
+            let image = tempdir.path().join(format!("fat{bits}.img"));
+            let status = Command::new("mkfs.fat")
+                .args(["-C", "-F", bits, "-n", "ESP"])
+                .arg(&image)
+                .arg(size_kib.to_string())
+                .status()?;
+            assert!(status.success(), "mkfs.fat -F {bits} failed");
+            assert_eq!(
+                FatEspFilesystem::open(&image, root)?.fs.fat_type(),
+                fat_type
+            );
+
+            let files = populate(&image, root)?;
+            fsck(&image)?;
+            check_files(&image, root, &files)?;
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
 use std::collections::HashSet;
-use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
-use walkdir::{DirEntry, WalkDir};
+
+use crate::esp_fs::EspFilesystem;
 
 /// Keeps track of the garbage collection roots.
 ///
@@ -27,15 +27,22 @@ impl Roots {
         self.0.extend(other.into_iter().cloned());
     }
 
-    fn in_use(&self, entry: Option<&DirEntry>) -> bool {
-        match entry {
-            Some(e) => self.0.contains(e.path()),
-            None => false,
-        }
+    /// The garbage collection roots, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.0.iter()
+    }
+
+    fn in_use(&self, path: &Path) -> bool {
+        self.0.contains(path)
     }
 
-    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<()> {
-        self.collect_garbage_with_filter(directory, |_| true)
+    /// Delete the unused paths in `directory` and return them.
+    pub fn collect_garbage(
+        &self,
+        esp: &mut impl EspFilesystem,
+        directory: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>> {
+        self.collect_garbage_with_filter(esp, directory, |_| true)
     }
 
     /// Collect garbage with an additional filter.
@@ -45,35 +52,46 @@ impl Roots {
     /// that are unused AND for which the filter function returns true are deleted.
     pub fn collect_garbage_with_filter<P>(
         &self,
+        esp: &mut impl EspFilesystem,
         directory: impl AsRef<Path>,
         mut predicate: P,
+    ) -> Result<Vec<PathBuf>>
+    where
+        P: FnMut(&Path) -> bool,
+    {
+        let directory = directory.as_ref();
+        let mut deleted = Vec::new();
+        if esp.exists(directory) {
+            self.collect_garbage_recursive(esp, directory, &mut predicate, &mut deleted)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Remove `path` if it is not in use, otherwise descend into it.
+    ///
+    /// If a directory is marked as unused all its children are deleted too.
+    fn collect_garbage_recursive<P>(
+        &self,
+        esp: &mut impl EspFilesystem,
+        path: &Path,
+        predicate: &mut P,
+        deleted: &mut Vec<PathBuf>,
     ) -> Result<()>
     where
         P: FnMut(&Path) -> bool,
     {
-        // Find all the paths not used anymore.
-        let entries_not_in_use = WalkDir::new(directory.as_ref())
-            .into_iter()
-            .filter(|e| !self.in_use(e.as_ref().ok()))
-            .filter(|e| match e.as_ref().ok() {
-                Some(e) => predicate(e.path()),
-                None => false,
-            });
-
-        // Remove all entries not in use.
-        for e in entries_not_in_use {
-            let entry = e?;
-            let path = entry.path();
+        if !self.in_use(path) && predicate(path) {
             log::debug!("Garbage collecting {path:?}...");
+            esp.delete(path)
+                .with_context(|| format!("Failed to garbage collect {path:?}"))?;
+            deleted.push(path.to_path_buf());
+            return Ok(());
+        }
 
-            if path.is_dir() {
-                // If a directory is marked as unused all its children can be deleted too.
-                fs::remove_dir_all(path)
-                    .with_context(|| format!("Failed to remove directory: {:?}", path))?;
-            } else {
-                // Ignore failing to remove path because the parent directory might have been removed before.
-                fs::remove_file(path).ok();
-            };
+        if esp.is_dir(path) {
+            for child in esp.list(path)? {
+                self.collect_garbage_recursive(esp, &child, predicate, deleted)?;
+            }
         }
 
         Ok(())
@@ -89,6 +107,7 @@ impl Default for Roots {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::esp_fs::PhysicalEspFilesystem;
     use std::fs;
 
     #[test]
@@ -100,7 +119,7 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir, &used_file]);
-        roots.collect_garbage(&rootdir)?;
+        roots.collect_garbage(&mut PhysicalEspFilesystem, &rootdir)?;
 
         assert!(used_file.exists());
         Ok(())
@@ -115,9 +134,10 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir]);
-        roots.collect_garbage(&rootdir)?;
+        let deleted = roots.collect_garbage(&mut PhysicalEspFilesystem, &rootdir)?;
 
         assert!(!unused_file.exists());
+        assert_eq!(deleted, [unused_file]);
         Ok(())
     }
 
@@ -130,7 +150,7 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir]);
-        roots.collect_garbage(&rootdir)?;
+        roots.collect_garbage(&mut PhysicalEspFilesystem, &rootdir)?;
 
         assert!(!unused_directory.exists());
         Ok(())
@@ -147,7 +167,7 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir]);
-        roots.collect_garbage(&rootdir)?;
+        roots.collect_garbage(&mut PhysicalEspFilesystem, &rootdir)?;
 
         assert!(!unused_directory.exists());
         assert!(!unused_file_in_directory.exists());
@@ -166,7 +186,7 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir, &used_directory, &used_file_in_directory]);
-        roots.collect_garbage(&rootdir)?;
+        roots.collect_garbage(&mut PhysicalEspFilesystem, &rootdir)?;
 
         assert!(used_directory.exists());
         assert!(used_file_in_directory.exists());
@@ -184,7 +204,7 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir]);
-        roots.collect_garbage_with_filter(&rootdir, |p| {
+        roots.collect_garbage_with_filter(&mut PhysicalEspFilesystem, &rootdir, |p| {
             p.file_name()
                 .and_then(|n| n.to_str())
                 .map_or(false, |n| n.starts_with("prefix_"))
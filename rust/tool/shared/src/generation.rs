@@ -1,9 +1,11 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bootspec::BootJson;
 use bootspec::BootSpec;
 use bootspec::SpecialisationName;
@@ -23,12 +25,57 @@ pub struct ExtendedBootJson {
 #[derive(Debug, Clone, Deserialize)]
 pub struct LanzabooteExtension {
     pub sort_key: String,
+    /// The security version number (SVN) of the generation used for anti-rollback protection.
+    #[serde(default)]
+    pub security_version: Option<u64>,
+    /// Once this generation has booted, generations with a lower SVN are refused.
+    #[serde(default)]
+    pub minimum_security_version: Option<u64>,
+    /// The initrd secrets of the generation, mapping their path in stage 1 to their source.
+    ///
+    /// If present, they are appended by lzbt instead of the `append-initrd-secrets` script of the
+    /// bootspec, which makes the resulting initrd reproducible.
+    #[serde(default)]
+    pub initrd_secrets: Option<BTreeMap<String, PathBuf>>,
+    /// The dm-verity root hash of the root file system of an image-based system.
+    #[serde(default)]
+    pub verity_root_hash: Option<String>,
+}
+
+impl ExtendedBootJson {
+    /// Parse a bootspec document, including the lanzaboote extension.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let boot_json: BootJson =
+            serde_json::from_slice(raw).context("Failed to read bootspec JSON")?;
+        Self::from_boot_json(boot_json)
+    }
+
+    /// Validate a bootspec document and extract the lanzaboote extension.
+    ///
+    /// A missing or malformed extension is replaced by the default one.
+    pub fn from_boot_json(boot_json: BootJson) -> Result<Self> {
+        let bootspec: BootSpec = boot_json.generation.try_into()?;
+        let lanzaboote_extension = boot_json
+            .extensions
+            .get("org.nix-community.lanzaboote")
+            .and_then(|v| serde_json::from_value::<LanzabooteExtension>(v.clone()).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            bootspec,
+            lanzaboote_extension,
+        })
+    }
 }
 
 impl Default for LanzabooteExtension {
     fn default() -> Self {
         Self {
             sort_key: String::from("lanzaboote"),
+            security_version: None,
+            minimum_security_version: None,
+            initrd_secrets: None,
+            verity_root_hash: None,
         }
     }
 }
@@ -62,21 +109,11 @@ impl Generation {
             .or_else(|_err| BootJson::synthesize_latest(&link.path)
                     .context("Failed to read a bootspec (missing bootspec?) and failed to synthesize a valid replacement bootspec."))?;
 
-        let bootspec: BootSpec = boot_json.generation.try_into()?;
-        let lanzaboote_extension = boot_json
-            .extensions
-            .get("org.nix-community.lanzaboote")
-            .and_then(|v| serde_json::from_value::<LanzabooteExtension>(v.clone()).ok())
-            .unwrap_or_default();
-
         Ok(Self {
             version: link.version,
             build_time: link.build_time,
             specialisation_name: None,
-            spec: ExtendedBootJson {
-                bootspec,
-                lanzaboote_extension,
-            },
+            spec: ExtendedBootJson::from_boot_json(boot_json)?,
         })
     }
 
@@ -160,6 +197,49 @@ impl GenerationLink {
     }
 }
 
+/// A range of generation versions, e.g. `42`, `10-20` or `10-` for all from 10 on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl GenerationRange {
+    pub fn contains(&self, version: u64) -> bool {
+        version >= self.start && self.end.is_none_or(|end| version <= end)
+    }
+}
+
+impl FromStr for GenerationRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |version: &str| {
+            version
+                .parse::<u64>()
+                .with_context(|| format!("Invalid generation {version:?} in {s:?}"))
+        };
+        let range = match s.split_once('-') {
+            None => Self {
+                start: parse(s)?,
+                end: Some(parse(s)?),
+            },
+            Some((start, "")) => Self {
+                start: parse(start)?,
+                end: None,
+            },
+            Some((start, end)) => Self {
+                start: parse(start)?,
+                end: Some(parse(end)?),
+            },
+        };
+        if range.end.is_some_and(|end| end < range.start) {
+            bail!("Generation range {s:?} is empty");
+        }
+        Ok(range)
+    }
+}
+
 /// Parse version number from a path.
 ///
 /// Expects a path in the format of "system-{version}-link".
@@ -185,4 +265,54 @@ mod tests {
         let parsed_version = parse_version(path).unwrap();
         assert_eq!(parsed_version, 2,);
     }
+
+    #[test]
+    fn parse_generation_ranges() -> Result<()> {
+        let single: GenerationRange = "42".parse()?;
+        assert!(single.contains(42));
+        assert!(!single.contains(41) && !single.contains(43));
+
+        let closed: GenerationRange = "10-20".parse()?;
+        assert!(closed.contains(10) && closed.contains(20));
+        assert!(!closed.contains(21));
+
+        let open: GenerationRange = "10-".parse()?;
+        assert!(open.contains(u64::MAX));
+        assert!(!open.contains(9));
+
+        for invalid in ["", "-", "-10", "20-10", "ten", "1-2-3"] {
+            assert!(invalid.parse::<GenerationRange>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bootspec_with_extension() -> Result<()> {
+        let raw = br#"{
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/init",
+                "initrd": "/nix/store/initrd",
+                "kernel": "/nix/store/kernel",
+                "kernelParams": ["quiet"],
+                "label": "LanzaOS",
+                "toplevel": "/nix/store/toplevel",
+                "system": "x86_64-linux"
+            },
+            "org.nix-community.lanzaboote": {
+                "sort_key": "lanzaos",
+                "security_version": 3
+            }
+        }"#;
+        let boot_json = ExtendedBootJson::parse(raw)?;
+        assert_eq!(boot_json.bootspec.bootspec.label, "LanzaOS");
+        assert_eq!(boot_json.lanzaboote_extension.sort_key, "lanzaos");
+        assert_eq!(boot_json.lanzaboote_extension.security_version, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_bootspec() {
+        assert!(ExtendedBootJson::parse(b"").is_err());
+        assert!(ExtendedBootJson::parse(br#"{"org.nixos.bootspec.v1": {}}"#).is_err());
+    }
 }
@@ -0,0 +1,174 @@
+//! Encryption of the initrd on the ESP with AES-256-GCM.
+//!
+//! The key is held by the firmware: the stub moves it from a variable that lzbt writes into a
+//! variable that is only accessible during boot services, so that it cannot be read from the
+//! operating system or from the ESP. An encrypted initrd is stored as the header, a nonce, the
+//! ciphertext and the tag. The header is authenticated as associated data.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit};
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::efivars::{
+    write_variable, EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_NON_VOLATILE,
+    EFI_VARIABLE_RUNTIME_ACCESS, LANZABOOTE_GUID,
+};
+use crate::utils::zeroize;
+
+/// The header of an encrypted initrd, including the version of the format.
+pub const HEADER: &[u8; 8] = b"LZBTENC\x01";
+
+/// The variable from which the stub takes a new key.
+const PROVISION_VARIABLE: &str = "LanzabooteInitrdKeyProvision";
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// A 256-bit AES key. It is zeroed when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct InitrdKey([u8; 32]);
+
+impl Drop for InitrdKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl InitrdKey {
+    /// Read the key, or create and provision a new one if the file does not exist.
+    ///
+    /// A new key is passed to the stub in an EFI variable, which the stub moves out of reach of
+    /// the operating system on the next boot. Until then, the initrds cannot be decrypted by
+    /// previously installed stubs.
+    pub fn load_or_provision(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(mut data) => {
+                let key = data.as_slice().try_into().map(Self);
+                zeroize(&mut data);
+                key.map_err(|_| anyhow::anyhow!("The initrd key {path:?} must be exactly 32 bytes"))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Zeroed on drop, even if provisioning fails.
+                let mut key = Self([0; 32]);
+                fs::File::open("/dev/urandom")
+                    .and_then(|mut urandom| urandom.read_exact(&mut key.0))
+                    .context("Failed to generate the initrd key")?;
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(&key.0))
+                    .with_context(|| format!("Failed to write the initrd key {path:?}"))?;
+                write_variable(
+                    PROVISION_VARIABLE,
+                    &LANZABOOTE_GUID,
+                    EFI_VARIABLE_NON_VOLATILE
+                        | EFI_VARIABLE_BOOTSERVICE_ACCESS
+                        | EFI_VARIABLE_RUNTIME_ACCESS,
+                    &key.0,
+                )
+                .context("Failed to provision the initrd key")?;
+                log::info!("Created and provisioned a new initrd key {path:?}.");
+                Ok(key)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read the initrd key {path:?}")),
+        }
+    }
+
+    /// A hash that identifies the key without revealing it.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        Sha256::new()
+            .chain_update(b"lanzaboote initrd key")
+            .chain_update(self.0)
+            .finalize()
+            .to_vec()
+    }
+}
+
+/// Encrypt an initrd.
+///
+/// The nonce is derived from the key and the initrd, so that encrypting the same initrd again
+/// yields the same file, which keeps the content-addressed file names on the ESP stable. Only
+/// identical initrds share a nonce.
+pub fn encrypt_initrd(key: &InitrdKey, initrd: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_SIZE] = Sha256::new()
+        .chain_update(key.0)
+        .chain_update(initrd)
+        .finalize()[..NONCE_SIZE]
+        .try_into()
+        .expect("The nonce is shorter than a SHA256 hash");
+
+    let mut encrypted = Vec::with_capacity(HEADER.len() + NONCE_SIZE + initrd.len() + TAG_SIZE);
+    encrypted.extend_from_slice(HEADER);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(initrd);
+    let tag = Aes256Gcm::new(&key.0.into())
+        .encrypt_in_place_detached(
+            &nonce.into(),
+            HEADER,
+            &mut encrypted[HEADER.len() + NONCE_SIZE..],
+        )
+        .expect("The initrd is smaller than the limit of AES-GCM");
+    encrypted.extend_from_slice(&tag);
+    encrypted
+}
+
+/// Decrypt an initrd. Only needed to verify encrypted initrds, the stub decrypts them at boot.
+pub fn decrypt_initrd(key: &InitrdKey, encrypted: &[u8]) -> Result<Vec<u8>> {
+    let Some(rest) = encrypted.strip_prefix(HEADER) else {
+        bail!("Not an encrypted initrd");
+    };
+    if rest.len() < NONCE_SIZE + TAG_SIZE {
+        bail!("The encrypted initrd is truncated");
+    }
+    let (nonce, rest) = rest.split_at(NONCE_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+    let mut initrd = ciphertext.to_vec();
+    Aes256Gcm::new(&key.0.into())
+        .decrypt_in_place_detached(nonce.into(), HEADER, &mut initrd, tag.into())
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "The encrypted initrd is corrupted or was encrypted with a different key"
+            )
+        })?;
+    Ok(initrd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encryption_matches_the_stub() {
+        // The stub tests its decryption with the same initrd.
+        assert_eq!(
+            encrypt_initrd(&InitrdKey([7; 32]), b"initrd"),
+            b"LZBTENC\x01\
+              \x99\x29\xcf\xb2\xb5\x83\x7f\x66\xb0\xd7\x6a\x87\
+              \xf3\x76\x07\xcd\xa2\xba\
+              \xc9\xa5\x04\xd7\x86\x30\xa2\x63\xa6\x52\x03\x66\xf7\x2c\xf9\xc5"
+        );
+    }
+
+    #[test]
+    fn encryption_roundtrips_and_is_reproducible() {
+        let key = InitrdKey([7; 32]);
+        let initrd = b"070701 not really an initrd, but long enough to need several blocks";
+
+        let encrypted = encrypt_initrd(&key, initrd);
+        assert_eq!(encrypted, encrypt_initrd(&key, initrd));
+        assert_eq!(decrypt_initrd(&key, &encrypted).unwrap(), initrd);
+
+        assert!(decrypt_initrd(&InitrdKey([8; 32]), &encrypted).is_err());
+        let mut tampered = encrypted.clone();
+        tampered[30] ^= 1;
+        assert!(decrypt_initrd(&key, &tampered).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use crate::initrd_secrets::append_secrets_archive;
+use crate::mmap::{write_all_vectored, MappedFile};
+use crate::utils::{tmpname, SecureTempDirExt};
+
+/// The kernel skips the padding between the archives of an initrd up to this alignment.
+const ARCHIVE_ALIGNMENT: usize = 4;
+
+/// A transformation of the initrd before it is installed.
+///
+/// Every step modifies the initrd in place. The installed initrd, and thus its hash embedded into
+/// the stub, is the result of all steps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitrdStep {
+    /// Append initrd secrets, mapping their path in stage 1 to their source, reproducibly.
+    AppendSecrets(BTreeMap<String, PathBuf>),
+    /// Append initrd secrets with NixOS's `append-initrd-secrets` script.
+    AppendSecretsScript(PathBuf),
+    /// Prepend an archive, e.g. early microcode.
+    Prepend(PathBuf),
+    /// Append an archive, e.g. additional firmware.
+    Append(PathBuf),
+    /// Run a program with the path of the initrd, e.g. to recompress or strip it.
+    Exec(PathBuf),
+}
+
+impl InitrdStep {
+    fn apply(&self, initrd: &Path) -> Result<()> {
+        match self {
+            Self::AppendSecrets(secrets) => append_secrets_archive(initrd, secrets),
+            Self::AppendSecretsScript(program) | Self::Exec(program) => {
+                let status = Command::new(program)
+                    .arg(initrd)
+                    .status()
+                    .with_context(|| format!("Failed to run {program:?}"))?;
+                if !status.success() {
+                    bail!("{program:?} failed with {status}");
+                }
+                Ok(())
+            }
+            Self::Prepend(archive) => {
+                let archive = map_archive(archive)?;
+                let data = MappedFile::open(initrd).context("Failed to read the initrd")?;
+                // The result replaces the initrd, which is mapped until then.
+                let prepended = initrd.with_file_name(tmpname());
+                let result = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&prepended)
+                    .and_then(|mut file| {
+                        write_all_vectored(&mut file, &[&archive, padding(archive.len()), &data])
+                    })
+                    .and_then(|()| fs::rename(&prepended, initrd));
+                if result.is_err() {
+                    let _ = fs::remove_file(&prepended);
+                }
+                result.context("Failed to write the initrd")
+            }
+            Self::Append(archive) => append_archive(initrd, &map_archive(archive)?),
+        }
+    }
+}
+
+fn map_archive(archive: &Path) -> Result<MappedFile> {
+    MappedFile::open(archive).with_context(|| format!("Failed to read {archive:?}"))
+}
+
+/// The zeros after an archive of `length` bytes that align the next one.
+fn padding(length: usize) -> &'static [u8] {
+    &[0; ARCHIVE_ALIGNMENT][..length.next_multiple_of(ARCHIVE_ALIGNMENT) - length]
+}
+
+/// Append an archive to the initrd in place, without reading the initrd.
+pub(crate) fn append_archive(initrd: &Path, archive: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(initrd)
+        .with_context(|| format!("Failed to open the initrd {initrd:?}"))?;
+    let length = file
+        .metadata()
+        .with_context(|| format!("Failed to read the initrd {initrd:?}"))?
+        .len();
+    write_all_vectored(&mut file, &[padding(length as usize), archive])
+        .with_context(|| format!("Failed to write the initrd {initrd:?}"))
+}
+
+impl fmt::Display for InitrdStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AppendSecrets(_) => write!(f, "append-secrets"),
+            Self::AppendSecretsScript(program) => write!(f, "append-secrets:{}", program.display()),
+            Self::Prepend(archive) => write!(f, "prepend:{}", archive.display()),
+            Self::Append(archive) => write!(f, "append:{}", archive.display()),
+            Self::Exec(program) => write!(f, "exec:{}", program.display()),
+        }
+    }
+}
+
+/// Parse a step that can be configured, i.e. `prepend:<archive>`, `append:<archive>` or
+/// `exec:<program>`.
+impl FromStr for InitrdStep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((kind, path)) = s.split_once(':').filter(|(_, path)| !path.is_empty()) else {
+            bail!("Expected an initrd step of the form <kind>:<path>, got {s:?}");
+        };
+        let path = PathBuf::from(path);
+        match kind {
+            "prepend" => Ok(Self::Prepend(path)),
+            "append" => Ok(Self::Append(path)),
+            "exec" => Ok(Self::Exec(path)),
+            _ => bail!("Unknown initrd step {kind:?}, expected prepend, append or exec"),
+        }
+    }
+}
+
+/// The steps that turn the initrd of a generation into the installed initrd.
+#[derive(Debug, Default, Clone)]
+pub struct InitrdPipeline {
+    steps: Vec<InitrdStep>,
+}
+
+impl InitrdPipeline {
+    pub fn new(steps: Vec<InitrdStep>) -> Self {
+        Self { steps }
+    }
+
+    /// Add a step after the existing ones.
+    pub fn with_step(mut self, step: InitrdStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Run all steps on a copy of the initrd in the temporary directory.
+    ///
+    /// Returns the path of the resulting initrd, which is the initrd itself if there are no steps.
+    pub fn run(&self, initrd: &Path, tempdir: &TempDir) -> Result<PathBuf> {
+        if self.steps.is_empty() {
+            return Ok(initrd.to_path_buf());
+        }
+
+        // The initrd may end up containing secrets. The copy is made by the kernel without
+        // reading the initrd into memory.
+        let processed = tempdir.path().join(tmpname());
+        File::open(initrd)
+            .and_then(|mut source| {
+                let mut copy = tempdir
+                    .create_secure_file(&processed)
+                    .map_err(io::Error::other)?;
+                io::copy(&mut source, &mut copy)
+            })
+            .context("Failed to copy the initrd to the temporary directory.")?;
+        for step in &self.steps {
+            step.apply(&processed)
+                .with_context(|| format!("Initrd step {step} failed"))?;
+        }
+
+        Ok(processed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_configured_steps() {
+        assert_eq!(
+            "prepend:/run/microcode.cpio".parse::<InitrdStep>().unwrap(),
+            InitrdStep::Prepend(PathBuf::from("/run/microcode.cpio"))
+        );
+        assert_eq!(
+            "exec:/bin/recompress".parse::<InitrdStep>().unwrap(),
+            InitrdStep::Exec(PathBuf::from("/bin/recompress"))
+        );
+        assert!("exec:".parse::<InitrdStep>().is_err());
+        assert!("strip:/bin/strip".parse::<InitrdStep>().is_err());
+        assert!("/bin/strip".parse::<InitrdStep>().is_err());
+    }
+
+    #[test]
+    fn steps_are_applied_in_order() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let initrd = tempdir.path().join("initrd");
+        let microcode = tempdir.path().join("microcode");
+        let firmware = tempdir.path().join("firmware");
+        fs::write(&initrd, "initrd")?;
+        fs::write(&microcode, "ucode")?;
+        fs::write(&firmware, "firmware")?;
+
+        let pipeline = InitrdPipeline::default()
+            .with_step(InitrdStep::Append(firmware))
+            .with_step(InitrdStep::Prepend(microcode));
+        let processed = pipeline.run(&initrd, &tempdir)?;
+
+        assert_eq!(fs::read(processed)?, b"ucode\0\0\0initrd\0\0firmware");
+        assert_eq!(fs::read(&initrd)?, b"initrd");
+        Ok(())
+    }
+
+    #[test]
+    fn no_steps_keep_the_initrd() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let initrd = Path::new("/nix/store/initrd");
+        assert_eq!(InitrdPipeline::default().run(initrd, &tempdir)?, initrd);
+        Ok(())
+    }
+}
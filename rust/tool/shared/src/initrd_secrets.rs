@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::initrd_pipeline::append_archive;
+use crate::utils::zeroize;
+
+/// The directory in the initrd that stage 1 copies the secrets from.
+const SECRETS_DIR: &str = ".initrd-secrets";
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Build a newc cpio archive of initrd secrets, like NixOS's `append-initrd-secrets` does.
+///
+/// `secrets` maps the path of a secret in stage 1 to its source on the host. Unlike the archive of
+/// `append-initrd-secrets`, the archive only depends on the contents, permissions and link targets
+/// of the secrets, so that installing a generation again yields the same initrd. The archive is
+/// not compressed, which the kernel supports for appended archives.
+pub fn secrets_archive(secrets: &BTreeMap<String, PathBuf>) -> Result<Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    entries.insert(SECRETS_DIR.to_string(), Entry::Directory(0o755));
+
+    for (destination, source) in secrets {
+        let mut name = PathBuf::from(SECRETS_DIR);
+        for component in Path::new(destination).components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(component) => {
+                    // Like `mkdir -p`, parents are created with the default permissions.
+                    entries
+                        .entry(archive_name(&name)?)
+                        .or_insert(Entry::Directory(0o755));
+                    name.push(component);
+                }
+                _ => bail!("Invalid initrd secret destination {destination:?}"),
+            }
+        }
+        if name == Path::new(SECRETS_DIR) {
+            bail!("Invalid initrd secret destination {destination:?}");
+        }
+        add_entries(&mut entries, &name, source)
+            .with_context(|| format!("Failed to read initrd secret {source:?}"))?;
+    }
+
+    let mut archive = Vec::new();
+    for (inode, (name, entry)) in (1..).zip(&entries) {
+        let (mode, nlink, data) = match entry {
+            Entry::Directory(mode) => (S_IFDIR | mode, 2, &[][..]),
+            Entry::File(mode, data) => (S_IFREG | mode, 1, &data[..]),
+            Entry::Symlink(target) => (S_IFLNK | 0o777, 1, &target[..]),
+        };
+        write_entry(&mut archive, inode, mode, nlink, name, data)?;
+    }
+    write_entry(&mut archive, 0, 0, 1, "TRAILER!!!", &[])?;
+
+    Ok(archive)
+}
+
+/// Append initrd secrets to a copy of an initrd.
+pub fn append_secrets_archive(initrd: &Path, secrets: &BTreeMap<String, PathBuf>) -> Result<()> {
+    let mut archive = secrets_archive(secrets)?;
+    let written = append_archive(initrd, &archive);
+    zeroize(&mut archive);
+    written
+}
+
+enum Entry {
+    Directory(u32),
+    File(u32, Vec<u8>),
+    Symlink(Vec<u8>),
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if let Self::File(_, data) = self {
+            zeroize(data);
+        }
+    }
+}
+
+fn archive_name(path: &Path) -> Result<String> {
+    path.to_str()
+        .map(str::to_string)
+        .with_context(|| format!("Initrd secret path {path:?} is not valid UTF-8"))
+}
+
+/// Add a secret, which may be a directory, like `cp -a` does.
+fn add_entries(entries: &mut BTreeMap<String, Entry>, name: &Path, source: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    let mode = metadata.permissions().mode() & 0o7777;
+
+    let entry = if metadata.is_symlink() {
+        Entry::Symlink(fs::read_link(source)?.as_os_str().as_bytes().to_vec())
+    } else if metadata.is_dir() {
+        for child in fs::read_dir(source)? {
+            let child = child?;
+            add_entries(entries, &name.join(child.file_name()), &child.path())?;
+        }
+        Entry::Directory(mode)
+    } else {
+        Entry::File(mode, fs::read(source)?)
+    };
+    entries.insert(archive_name(name)?, entry);
+
+    Ok(())
+}
+
+fn write_entry(
+    archive: &mut Vec<u8>,
+    inode: u32,
+    mode: u32,
+    nlink: u32,
+    name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let size = u32::try_from(data.len()).context("Initrd secret is too large")?;
+    let name_size = u32::try_from(name.len() + 1).context("Initrd secret path is too long")?;
+
+    archive.extend_from_slice(b"070701");
+    // The owner, modification time and device numbers are always 0.
+    for field in [inode, mode, 0, 0, nlink, 0, size, 0, 0, 0, 0, name_size, 0] {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names of the entries of a newc archive.
+    fn names(mut archive: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        loop {
+            let field = |index: usize| {
+                let hex = std::str::from_utf8(&archive[6 + index * 8..6 + index * 8 + 8]).unwrap();
+                usize::from_str_radix(hex, 16).unwrap()
+            };
+            let (size, name_size) = (field(6), field(11));
+            let name = std::str::from_utf8(&archive[110..110 + name_size - 1]).unwrap();
+            if name == "TRAILER!!!" {
+                return names;
+            }
+            names.push(name.to_string());
+            let data_start = (110 + name_size).next_multiple_of(4);
+            archive = &archive[(data_start + size).next_multiple_of(4)..];
+        }
+    }
+
+    #[test]
+    fn archive_contains_secrets_and_parents() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let secret = tempdir.path().join("secret");
+        fs::write(&secret, "hunter2")?;
+        let secrets = BTreeMap::from([("/etc/ssh/host_key".to_string(), secret)]);
+
+        let archive = secrets_archive(&secrets)?;
+
+        assert_eq!(
+            names(&archive),
+            [
+                ".initrd-secrets",
+                ".initrd-secrets/etc",
+                ".initrd-secrets/etc/ssh",
+                ".initrd-secrets/etc/ssh/host_key",
+            ]
+        );
+        assert_eq!(archive.len() % 4, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn archive_is_reproducible() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let secrets = BTreeMap::from([("/secret".to_string(), tempdir.path().join("secret"))]);
+
+        fs::write(&secrets["/secret"], "hunter2")?;
+        let first = secrets_archive(&secrets)?;
+        fs::remove_file(&secrets["/secret"])?;
+        fs::write(&secrets["/secret"], "hunter2")?;
+
+        assert_eq!(first, secrets_archive(&secrets)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_destinations_outside_of_the_secrets_directory() {
+        let secrets = BTreeMap::from([("/../etc/shadow".to_string(), PathBuf::from("/dev/null"))]);
+        assert!(secrets_archive(&secrets).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+const SETUP_HEADER_MAGIC: &[u8] = b"HdrS";
+const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+const VERSION_OFFSET: usize = 0x206;
+const KERNEL_VERSION_OFFSET: usize = 0x20e;
+/// `kernel_version` points into the setup code, which starts after the boot sector.
+const KERNEL_VERSION_BASE: usize = 0x200;
+/// `kernel_version` was added in boot protocol 2.00, but is only reliable since 2.01.
+const VERSION_2_01: u16 = 0x0201;
+
+/// The setup code, which contains the version string, is at most 64 sectors long.
+const SETUP_CODE_MAX_SIZE: u64 = 0x200 * 65;
+
+/// Read the release of a kernel, i.e. what `uname -r` reports when it runs.
+///
+/// Only x86 kernels record it in their setup header, so this is `None` for other kernels.
+pub fn kernel_release(kernel: &Path) -> Result<Option<String>> {
+    let mut data = Vec::new();
+    File::open(kernel)
+        .and_then(|file| file.take(SETUP_CODE_MAX_SIZE).read_to_end(&mut data))
+        .with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+
+    Ok(release_from_setup_header(&data))
+}
+
+/// Find the release in the `kernel_version` string of the setup header, e.g.
+/// `6.1.1 (nixbld@localhost) #1-NixOS SMP PREEMPT_DYNAMIC Tue Jan 1 00:00:00 UTC 1980`.
+fn release_from_setup_header(data: &[u8]) -> Option<String> {
+    if data.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4)? != SETUP_HEADER_MAGIC {
+        return None;
+    }
+    let version = read_u16(data, VERSION_OFFSET)?;
+    let pointer = read_u16(data, KERNEL_VERSION_OFFSET)?;
+    if version < VERSION_2_01 || pointer == 0 {
+        return None;
+    }
+
+    let string = data.get(KERNEL_VERSION_BASE + usize::from(pointer)..)?;
+    let string = &string[..string.iter().position(|&b| b == 0)?];
+    let release = std::str::from_utf8(string)
+        .ok()?
+        .split_whitespace()
+        .next()?;
+    Some(release.to_string())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bzimage(version: u16, kernel_version: &[u8]) -> Vec<u8> {
+        let mut kernel = vec![0; 0x600];
+        kernel[SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4]
+            .copy_from_slice(SETUP_HEADER_MAGIC);
+        kernel[VERSION_OFFSET..VERSION_OFFSET + 2].copy_from_slice(&version.to_le_bytes());
+        kernel[KERNEL_VERSION_OFFSET..KERNEL_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x300u16.to_le_bytes());
+        kernel[0x500..0x500 + kernel_version.len()].copy_from_slice(kernel_version);
+        kernel
+    }
+
+    #[test]
+    fn read_release_of_bzimage() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let kernel = tempdir.path().join("bzImage");
+        std::fs::write(
+            &kernel,
+            bzimage(0x020f, b"6.1.1 (nixbld@localhost) #1-NixOS SMP\0"),
+        )?;
+        assert_eq!(kernel_release(&kernel)?.as_deref(), Some("6.1.1"));
+        Ok(())
+    }
+
+    #[test]
+    fn other_kernels_have_no_release() {
+        assert_eq!(release_from_setup_header(b"MZ\0\0PE\0\0"), None);
+        assert_eq!(
+            release_from_setup_header(&bzimage(0x0200, b"6.1.1\0")),
+            None
+        );
+        // The string is not terminated within the kernel.
+        assert_eq!(
+            release_from_setup_header(&bzimage(0x020f, b"6.1.1")[..0x505]),
+            None
+        );
+    }
+}
@@ -1,8 +1,27 @@
 pub mod architecture;
+pub mod authenticode;
+pub mod certificate;
+pub mod efivars;
+pub mod entry_naming;
 pub mod esp;
+pub mod esp_fs;
+pub mod fat;
 pub mod gc;
 pub mod generation;
+pub mod initrd_encryption;
+pub mod initrd_pipeline;
+pub mod initrd_secrets;
+pub mod kernel;
+pub mod lock;
+pub mod mmap;
 pub mod os_release;
+pub mod pcr;
 pub mod pe;
+pub mod profile;
+pub mod revocation;
 pub mod signature;
+pub mod signature_list;
+pub mod splash;
+pub mod timestamp;
 pub mod utils;
+pub mod zboot;
@@ -0,0 +1,140 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+/// The lock file that serializes concurrent installations, e.g. from overlapping
+/// `nixos-rebuild` runs.
+pub const DEFAULT_LOCK_FILE: &str = "/run/lzbt.lock";
+
+/// The lock file to use if none is given.
+///
+/// Build sandboxes, e.g. when populating an image, have no `/run`, so the temporary directory is
+/// used there instead.
+pub fn default_lock_file() -> PathBuf {
+    let lock_file = PathBuf::from(DEFAULT_LOCK_FILE);
+    match lock_file.parent() {
+        Some(parent) if parent.is_dir() => lock_file,
+        _ => std::env::temp_dir().join("lzbt.lock"),
+    }
+}
+
+/// An exclusive advisory lock on a lock file, which is released when this is dropped.
+///
+/// The lock file contains the PID of the holder while it is held and is emptied when the lock is
+/// released. The kernel releases the lock itself if the holder dies, so a lock file can never
+/// block forever. A PID in the lock file of an acquired lock is stale: its run did not finish.
+pub struct InstallLock {
+    file: Flock<File>,
+    path: PathBuf,
+    stale_pid: Option<u32>,
+}
+
+impl InstallLock {
+    /// Acquire the lock.
+    ///
+    /// If another process holds it, either wait for it to be released or fail.
+    pub fn acquire(path: &Path, wait: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .with_context(|| format!("Failed to open the lock file {path:?}"))?;
+
+        let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((file, Errno::EWOULDBLOCK)) => {
+                let holder = match read_pid(&file) {
+                    Some(pid) => format!("Another lzbt process (PID {pid})"),
+                    None => "Another lzbt process".to_string(),
+                };
+                if !wait {
+                    bail!("{holder} is installing. Use --wait to wait for it to finish.");
+                }
+                log::info!("{holder} is installing. Waiting for it to finish...");
+                Flock::lock(file, FlockArg::LockExclusive)
+                    .map_err(|(_, errno)| errno)
+                    .with_context(|| format!("Failed to wait for the lock file {path:?}"))?
+            }
+            Err((_, errno)) => {
+                return Err(errno).with_context(|| format!("Failed to lock {path:?}"));
+            }
+        };
+
+        let stale_pid = read_pid(&file);
+        file.set_len(0)
+            .and_then(|()| file.write_all_at(format!("{}\n", std::process::id()).as_bytes(), 0))
+            .with_context(|| format!("Failed to write the lock file {path:?}"))?;
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            stale_pid,
+        })
+    }
+
+    /// The PID of a previous holder that did not release the lock cleanly, e.g. because it was
+    /// killed in the middle of an installation.
+    pub fn stale_pid(&self) -> Option<u32> {
+        self.stale_pid
+    }
+}
+
+impl Drop for InstallLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.set_len(0) {
+            log::warn!("Failed to clear the lock file {:?}: {e}", self.path);
+        }
+    }
+}
+
+/// Read the PID of the holder of a lock file.
+fn read_pid(file: &File) -> Option<u32> {
+    let mut contents = [0; 16];
+    let length = file.read_at(&mut contents, 0).ok()?;
+    std::str::from_utf8(&contents[..length])
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_concurrent_lock() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("lzbt.lock");
+
+        let lock = InstallLock::acquire(&path, false)?;
+        assert_eq!(read_pid(&File::open(&path)?), Some(std::process::id()));
+        let error = InstallLock::acquire(&path, false)
+            .err()
+            .context("Lock was acquired twice")?;
+        assert!(format!("{error:#}").contains(&format!("PID {}", std::process::id())));
+
+        drop(lock);
+        let lock = InstallLock::acquire(&path, false)?;
+        assert_eq!(lock.stale_pid(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_stale_lock() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("lzbt.lock");
+        std::fs::write(&path, "4242\n")?;
+
+        let lock = InstallLock::acquire(&path, false)?;
+        assert_eq!(lock.stale_pid(), Some(4242));
+        Ok(())
+    }
+}
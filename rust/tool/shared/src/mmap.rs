@@ -0,0 +1,164 @@
+//! Memory-mapped input files and vectored output, so that kernels and initrds of hundreds of
+//! megabytes are hashed, copied and concatenated without reading them into memory first.
+//!
+//! The pages of a mapping are read on demand and can be dropped by the kernel at any time, so the
+//! resident memory of lzbt stays about the same regardless of the size of the files.
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::{self, IoSlice, Write};
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::path::Path;
+use std::ptr::NonNull;
+
+use anyhow::{Context, Result};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+/// A file that is mapped read-only into memory.
+///
+/// The file must not be truncated while it is mapped, otherwise accessing the missing pages
+/// crashes the process. This holds for files in the Nix store and for files that lzbt itself
+/// created.
+#[derive(Debug)]
+pub struct MappedFile {
+    /// The mapping, unless the file is empty, which cannot be mapped.
+    mapping: Option<(NonNull<c_void>, NonZeroUsize)>,
+}
+
+// SAFETY: The mapping is read-only and owned by this value.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        let length = file
+            .metadata()
+            .with_context(|| format!("Failed to read the metadata of {path:?}"))?
+            .len();
+        let length = usize::try_from(length)
+            .with_context(|| format!("{path:?} is too large to be mapped"))?;
+        let Some(length) = NonZeroUsize::new(length) else {
+            return Ok(Self { mapping: None });
+        };
+        // SAFETY: The mapping is private and read-only and it is unmapped when this value is
+        // dropped. See above for why the file is not truncated.
+        let address = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                &file,
+                0,
+            )
+        }
+        .with_context(|| format!("Failed to map {path:?} into memory"))?;
+        Ok(Self {
+            mapping: Some((address, length)),
+        })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.mapping {
+            // SAFETY: The mapping is readable and `length` bytes long while this value lives.
+            Some((address, length)) => unsafe {
+                std::slice::from_raw_parts(address.as_ptr().cast(), length.get())
+            },
+            None => &[],
+        }
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Some((address, length)) = self.mapping {
+            // SAFETY: The mapping was created by `mmap` with this length and is not used anymore.
+            let _ = unsafe { munmap(address, length.get()) };
+        }
+    }
+}
+
+/// Write all buffers one after another with as few system calls as possible.
+///
+/// This is `Write::write_all_vectored`, which is not stable yet.
+pub fn write_all_vectored(writer: &mut impl Write, buffers: &[&[u8]]) -> io::Result<()> {
+    let mut buffers = buffers
+        .iter()
+        .copied()
+        .filter(|b| !b.is_empty())
+        .collect::<Vec<_>>();
+    let mut start = 0;
+    while start < buffers.len() {
+        let slices = buffers[start..]
+            .iter()
+            .map(|b| IoSlice::new(b))
+            .collect::<Vec<_>>();
+        let mut written = match writer.write_vectored(&slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => written,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Skip what was written, which may end in the middle of a buffer.
+        while start < buffers.len() && written >= buffers[start].len() {
+            written -= buffers[start].len();
+            start += 1;
+        }
+        if written > 0 {
+            buffers[start] = &buffers[start][written..];
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("initrd");
+        std::fs::write(&path, b"070701")?;
+        assert_eq!(&*MappedFile::open(&path)?, b"070701");
+        std::fs::write(&path, b"")?;
+        assert!(MappedFile::open(&path)?.is_empty());
+        assert!(MappedFile::open(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    /// A writer that writes at most 3 bytes at once.
+    struct Slow(Vec<u8>);
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let length = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..length]);
+            Ok(length)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_buffers_partially() -> io::Result<()> {
+        let mut writer = Slow(Vec::new());
+        write_all_vectored(&mut writer, &[b"micro", b"", b"code", b"\0\0\0", b"initrd"])?;
+        assert_eq!(writer.0, b"microcode\0\0\0initrd");
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ use std::{collections::BTreeMap, str::FromStr};
 
 use anyhow::Result;
 
+use crate::entry_naming::{EntrySort, EntryTitle};
 use crate::generation::Generation;
 
 /// An os-release file represented by a BTreeMap.
@@ -16,7 +17,12 @@ use crate::generation::Generation;
 pub struct OsRelease(pub BTreeMap<String, String>);
 
 impl OsRelease {
-    pub fn from_generation(generation: &Generation) -> Result<Self> {
+    /// The os-release of the entry of `generation`, titled and ordered as given.
+    pub fn from_generation(
+        generation: &Generation,
+        title: &EntryTitle,
+        sort: &EntrySort,
+    ) -> Result<Self> {
         let mut map = BTreeMap::new();
 
         // Because of a null pointer dereference, `bootctl` segfaults when no ID field is present
@@ -30,24 +36,27 @@ impl OsRelease {
             generation.spec.lanzaboote_extension.sort_key.clone(),
         );
 
-        // systemd-boot will only show VERSION_ID when PRETTY_NAME is not unique. This is
-        // confusing to users. Make sure that our PRETTY_NAME is unique, so we get a consistent
-        // user experience.
-        //
-        // See #220.
-        map.insert(
-            "PRETTY_NAME".into(),
-            format!(
-                "{} ({})",
-                generation.spec.bootspec.bootspec.label,
-                generation.describe()
-            ),
-        );
+        map.insert("PRETTY_NAME".into(), title.format(generation));
 
-        map.insert("VERSION_ID".into(), generation.describe());
+        // systemd-boot orders entries with the same ID by VERSION_ID, newest first.
+        map.insert("VERSION_ID".into(), sort.format(generation));
 
         Ok(Self(map))
     }
+
+    /// Turn the os-release of a generation into the one of its recovery entry.
+    ///
+    /// The recovery entry gets a distinct title. Its ID is used as the sort key by systemd-boot,
+    /// so changing it sorts recovery entries after the regular ones. This keeps them from being
+    /// selected as the default entry.
+    pub fn into_recovery(mut self) -> Self {
+        for (key, suffix) in [("ID", "-recovery"), ("PRETTY_NAME", " - Recovery")] {
+            if let Some(value) = self.0.get_mut(key) {
+                value.push_str(suffix);
+            }
+        }
+        self
+    }
 }
 
 impl FromStr for OsRelease {
@@ -238,6 +247,20 @@ mod tests {
     use super::*;
     use std::ffi::CStr;
 
+    #[test]
+    fn recovery_entries_have_distinct_title_and_sort_key() -> Result<()> {
+        let os_release = OsRelease::from_str("ID=lanza\nPRETTY_NAME=\"NixOS (Generation 1)\"\n")?
+            .into_recovery();
+
+        assert_eq!(os_release.0["ID"], "lanza-recovery");
+        assert_eq!(
+            os_release.0["PRETTY_NAME"],
+            "NixOS (Generation 1) - Recovery"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn parses_correctly_from_str() -> Result<()> {
         let os_release_cstr = CStr::from_bytes_with_nul(b"ID=systemd-boot\nVERSION=\"252.1\"\n\0")?;
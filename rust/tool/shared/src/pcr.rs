@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use goblin::pe::PE;
+use sha2::{Digest, Sha256};
+
+type Hash = sha2::digest::Output<Sha256>;
+
+/// The PCR the stub measures its unified sections into.
+pub const TPM_PCR_INDEX_KERNEL_IMAGE: u32 = 11;
+
+/// The PE sections that the stub measures into PCR 11.
+///
+/// This mirrors `UnifiedSection` in the stub. `.pcrsig` is deliberately missing, because it is
+/// never measured.
+const MEASURED_SECTIONS: [&str; 7] = [
+    ".linux", ".osrel", ".cmdline", ".initrd", ".splash", ".dtb", ".pcrpkey",
+];
+
+/// The boot phases that are measured into PCR 11 after the stub, in the order of a boot.
+///
+/// These are the same words as those of `systemd-pcrphase`, so policies that are bound to a phase
+/// work with either. Secrets sealed to the value after `enter-initrd` can then only be unsealed
+/// in the initrd, because `leave-initrd` changes PCR 11 before the system switches root.
+pub const BOOT_PHASES: [&str; 6] = [
+    "enter-initrd",
+    "leave-initrd",
+    "sysinit",
+    "ready",
+    "shutdown",
+    "final",
+];
+
+/// Extend a PCR value with a digest the same way a TPM does.
+pub fn extend(pcr: &Hash, digest: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(pcr);
+    hasher.update(digest);
+    hasher.finalize()
+}
+
+/// Compute the digests the stub extends into PCR 11 when it boots the given image.
+///
+/// The stub measures sections in the order in which they appear in the PE section table, so this
+/// function preserves that order.
+pub fn measured_sections(image: &[u8]) -> Result<Vec<(String, Hash)>> {
+    let pe = PE::parse(image).context("Failed to parse PE binary")?;
+
+    let mut measurements = Vec::new();
+    for section in &pe.sections {
+        let name = section
+            .name()
+            .context("Failed to read the name of a PE section")?;
+        if !MEASURED_SECTIONS.contains(&name) {
+            continue;
+        }
+        // The stub cannot read sections that are padded with zeroes in memory and does not
+        // measure them, see `pe_section_data`.
+        if section.virtual_size > section.size_of_raw_data {
+            continue;
+        }
+
+        let start = usize::try_from(section.pointer_to_raw_data)?;
+        let data = start
+            .checked_add(usize::try_from(section.virtual_size)?)
+            .and_then(|end| image.get(start..end))
+            .with_context(|| format!("Section {name} is out of bounds"))?;
+
+        measurements.push((name.to_string(), Sha256::digest(data)));
+    }
+
+    Ok(measurements)
+}
+
+/// Compute the expected value of PCR 11 after the stub has measured the given image.
+///
+/// This assumes that nothing else has been measured into PCR 11 before the stub runs, which is
+/// true for the lanzaboote boot flow.
+pub fn expected_pcr11(image: &[u8]) -> Result<Hash> {
+    Ok(measured_sections(image)?
+        .iter()
+        .fold(Hash::default(), |pcr, (_, digest)| extend(&pcr, digest)))
+}
+
+/// The digest that a boot phase is measured with.
+pub fn phase_digest(phase: &str) -> Hash {
+    Sha256::digest(phase.as_bytes())
+}
+
+/// The values of a PCR that starts out at `pcr` after each boot phase of [`BOOT_PHASES`].
+fn phase_values(mut pcr: Hash) -> Vec<(&'static str, Hash)> {
+    BOOT_PHASES
+        .iter()
+        .map(|phase| {
+            pcr = extend(&pcr, &phase_digest(phase));
+            (*phase, pcr)
+        })
+        .collect()
+}
+
+/// Compute the expected values of PCR 11 after each boot phase of [`BOOT_PHASES`].
+pub fn expected_pcr11_phases(image: &[u8]) -> Result<Vec<(&'static str, Hash)>> {
+    Ok(phase_values(expected_pcr11(image)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_matches_tpm_semantics() {
+        let pcr = extend(&Hash::default(), &Sha256::digest(b"hello"));
+        assert_eq!(
+            format!("{pcr:x}"),
+            "9851312028952521510e8eaab5be94e7dc24b5fc292b2e9781173cf11ffa9878"
+        );
+
+        let pcr = extend(&pcr, &Sha256::digest(b"world"));
+        assert_eq!(
+            format!("{pcr:x}"),
+            "98d128df384d428ffe76af3c0198ff1e8945ef71e741ba440bafff0510da8f22"
+        );
+    }
+
+    #[test]
+    fn phases_extend_in_order() {
+        let values = phase_values(Hash::default());
+        let enter = extend(&Hash::default(), &Sha256::digest(b"enter-initrd"));
+        assert_eq!(values[0], ("enter-initrd", enter));
+        assert_eq!(
+            values[1],
+            (
+                "leave-initrd",
+                extend(&enter, &Sha256::digest(b"leave-initrd"))
+            )
+        );
+        assert_eq!(values.len(), BOOT_PHASES.len());
+    }
+
+    /// Build a minimal PE32+ binary with a `.linux` section and an `.initrd` section that is
+    /// larger in memory than in the file.
+    fn padded_pe() -> Vec<u8> {
+        let mut image = vec![0u8; 0x500];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+
+        // COFF header
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x46..0x48].copy_from_slice(&2u16.to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        image[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+
+        // Optional header
+        let optional = 0x58;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 32..optional + 36].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[optional + 36..optional + 40].copy_from_slice(&0x200u32.to_le_bytes());
+        image[optional + 56..optional + 60].copy_from_slice(&0x3000u32.to_le_bytes());
+        image[optional + 60..optional + 64].copy_from_slice(&0x200u32.to_le_bytes());
+        image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+        // Section headers: name, virtual size, virtual address, raw size, raw offset
+        let sections: [(&[u8], u32, u32, u32, u32); 2] = [
+            (b".linux", 0x180, 0x1000, 0x200, 0x200),
+            (b".initrd", 0x200, 0x2000, 0x100, 0x400),
+        ];
+        for (index, (name, virtual_size, virtual_address, raw_size, raw_offset)) in
+            sections.into_iter().enumerate()
+        {
+            let header = optional + 240 + index * 40;
+            image[header..header + name.len()].copy_from_slice(name);
+            image[header + 8..header + 12].copy_from_slice(&virtual_size.to_le_bytes());
+            image[header + 12..header + 16].copy_from_slice(&virtual_address.to_le_bytes());
+            image[header + 16..header + 20].copy_from_slice(&raw_size.to_le_bytes());
+            image[header + 20..header + 24].copy_from_slice(&raw_offset.to_le_bytes());
+        }
+
+        image[0x200..0x400].fill(0xcc);
+        image[0x400..].fill(0xdd);
+        image
+    }
+
+    #[test]
+    fn skip_padded_sections() -> Result<()> {
+        let image = padded_pe();
+        let measurements = measured_sections(&image)?;
+        assert_eq!(
+            measurements,
+            vec![(".linux".to_string(), Sha256::digest(&image[0x200..0x380]))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_non_pe_images() {
+        assert!(expected_pcr11(b"not a PE binary").is_err());
+    }
+}
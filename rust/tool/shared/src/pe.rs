@@ -1,16 +1,324 @@
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use goblin::pe::PE;
 use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 
+use crate::revocation::{RevocationList, RevokedHash};
+use crate::splash::check_bmp;
 use crate::utils::{file_hash, tmpname, SecureTempDirExt};
 
+/// How much the stub logs to the console.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StubVerbosity {
+    /// Only log errors.
+    Quiet,
+    /// The default log level of the stub.
+    #[default]
+    Normal,
+    /// Log debug information as well.
+    Debug,
+}
+
+impl StubVerbosity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Quiet => "quiet",
+            Self::Normal => "normal",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+impl fmt::Display for StubVerbosity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StubVerbosity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "quiet" => Ok(Self::Quiet),
+            "normal" => Ok(Self::Normal),
+            "debug" => Ok(Self::Debug),
+            _ => bail!("Unknown stub verbosity {s:?}, expected quiet, normal or debug"),
+        }
+    }
+}
+
+/// The language of the messages that the stub displays when the boot fails or needs attention.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StubLocale {
+    #[default]
+    En,
+    De,
+    Fr,
+    Es,
+}
+
+impl StubLocale {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::De => "de",
+            Self::Fr => "fr",
+            Self::Es => "es",
+        }
+    }
+}
+
+impl fmt::Display for StubLocale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StubLocale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "en" => Ok(Self::En),
+            "de" => Ok(Self::De),
+            "fr" => Ok(Self::Fr),
+            "es" => Ok(Self::Es),
+            _ => bail!("Unknown stub locale {s:?}, expected en, de, fr or es"),
+        }
+    }
+}
+
+/// What the stub does if Secure Boot is disabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InsecureBootPolicy {
+    /// Boot with a warning.
+    #[default]
+    Warn,
+    /// Boot only after the user confirmed it with a key press.
+    Confirm,
+    /// Refuse to boot.
+    Refuse,
+}
+
+impl InsecureBootPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Confirm => "confirm",
+            Self::Refuse => "refuse",
+        }
+    }
+}
+
+impl fmt::Display for InsecureBootPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for InsecureBootPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "confirm" => Ok(Self::Confirm),
+            "refuse" => Ok(Self::Refuse),
+            _ => bail!("Unknown insecure boot policy {s:?}, expected warn, confirm or refuse"),
+        }
+    }
+}
+
+/// What the stub does if the firmware clock is before the build date of the generation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockCheck {
+    /// Do not check the clock.
+    Off,
+    /// Warn about the clock.
+    #[default]
+    Warn,
+    /// Warn and set the clock to the build date.
+    Set,
+}
+
+impl ClockCheck {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Warn => "warn",
+            Self::Set => "set",
+        }
+    }
+}
+
+impl fmt::Display for ClockCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ClockCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "set" => Ok(Self::Set),
+            _ => bail!("Unknown clock check {s:?}, expected off, warn or set"),
+        }
+    }
+}
+
+/// The kernel lockdown mode that the stub enforces on the command line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockdownMode {
+    /// Leave the lockdown mode to the command line and the kernel configuration.
+    #[default]
+    None,
+    /// Block changes to the running kernel, e.g. unsigned modules and kexec.
+    Integrity,
+    /// Also block reading kernel memory, e.g. through /dev/mem and kprobes.
+    Confidentiality,
+}
+
+impl LockdownMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Integrity => "integrity",
+            Self::Confidentiality => "confidentiality",
+        }
+    }
+}
+
+impl fmt::Display for LockdownMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LockdownMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "integrity" => Ok(Self::Integrity),
+            "confidentiality" => Ok(Self::Confidentiality),
+            _ => bail!("Unknown lockdown mode {s:?}, expected none, integrity or confidentiality"),
+        }
+    }
+}
+
+/// The partition that the stub loads the kernel and initrd from instead of its own, written like
+/// in `/etc/fstab`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PartitionSelector {
+    /// The unique GUID of a GPT partition, i.e. `PARTUUID=<GUID>`.
+    PartUuid(String),
+    /// The label of the file system, i.e. `LABEL=<label>`.
+    Label(String),
+}
+
+impl fmt::Display for PartitionSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PartUuid(uuid) => write!(f, "PARTUUID={uuid}"),
+            Self::Label(label) => write!(f, "LABEL={label}"),
+        }
+    }
+}
+
+impl FromStr for PartitionSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(uuid) = s.strip_prefix("PARTUUID=") {
+            let groups = uuid.split('-').map(str::len).collect::<Vec<_>>();
+            if groups != [8, 4, 4, 4, 12]
+                || !uuid.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+            {
+                bail!("The partition GUID {uuid:?} is not a GUID");
+            }
+            Ok(Self::PartUuid(uuid.to_ascii_lowercase()))
+        } else if let Some(label) = s.strip_prefix("LABEL=") {
+            if label.is_empty() {
+                bail!("The file system label is empty");
+            }
+            Ok(Self::Label(label.to_string()))
+        } else {
+            bail!("Unknown partition {s:?}, expected PARTUUID=<GUID> or LABEL=<label>")
+        }
+    }
+}
+
+/// How the stub unlocks a LUKS2-encrypted payload partition.
+///
+/// Without a TPM2 handle, the stub asks for the passphrase on the console.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LuksUnlock {
+    /// The persistent handle of a TPM2 object that holds the passphrase.
+    pub tpm2_handle: Option<u32>,
+    /// The PCRs of the SHA256 bank that the object is sealed to.
+    pub tpm2_pcrs: Vec<u32>,
+}
+
+impl LuksUnlock {
+    /// Parse a persistent TPM2 handle, e.g. `0x81000001`.
+    pub fn parse_tpm2_handle(s: &str) -> Result<u32> {
+        let handle = s
+            .strip_prefix("0x")
+            .and_then(|handle| u32::from_str_radix(handle, 16).ok())
+            .with_context(|| format!("The TPM2 handle {s:?} is not a hexadecimal number"))?;
+        if handle >> 24 != 0x81 {
+            bail!("The TPM2 handle {s} is not a persistent handle, i.e. 0x81xxxxxx");
+        }
+        Ok(handle)
+    }
+
+    /// Parse a list of PCRs like systemd-cryptenroll, e.g. `7+11`.
+    pub fn parse_tpm2_pcrs(s: &str) -> Result<Vec<u32>> {
+        s.split('+')
+            .map(|pcr| match pcr.parse() {
+                Ok(pcr) if pcr < 24 => Ok(pcr),
+                _ => bail!("Invalid PCR {pcr:?}, expected a number below 24"),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for LuksUnlock {
+    /// The contents of the `.luks` section.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "luks2")?;
+        if let Some(handle) = self.tpm2_handle {
+            writeln!(f, "tpm2-handle={handle:#010x}")?;
+            let pcrs = self
+                .tpm2_pcrs
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>();
+            writeln!(f, "tpm2-pcrs={}", pcrs.join("+"))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StubParameters {
     pub lanzaboote_store_path: PathBuf,
@@ -25,6 +333,89 @@ pub struct StubParameters {
     pub kernel_path_at_esp: String,
     /// Same as kernel.
     pub initrd_path_at_esp: String,
+    /// Enforce the Secure Boot policy in the stub even if Secure Boot is disabled.
+    #[serde(default)]
+    pub simulate_secure_boot: bool,
+    /// What the stub does if Secure Boot is disabled.
+    #[serde(default)]
+    pub insecure_boot_policy: InsecureBootPolicy,
+    /// How much the stub logs to the console.
+    #[serde(default)]
+    pub verbosity: StubVerbosity,
+    /// The language of the messages of the stub.
+    #[serde(default)]
+    pub locale: StubLocale,
+    /// Clear the screen when the stub starts instead of keeping the firmware splash.
+    #[serde(default)]
+    pub clear_screen: bool,
+    /// Mirror the output of the stub to serial ports even if the firmware console includes one.
+    #[serde(default)]
+    pub serial_console: bool,
+    /// Append the log of the stub to `loader/lanzaboote-debug.log` on the ESP.
+    #[serde(default)]
+    pub log_file: bool,
+    /// A BMP image that the stub displays while the kernel is loaded.
+    #[serde(default)]
+    pub splash: Option<PathBuf>,
+    /// The security version number of the generation, see [`Self::with_security_version`].
+    #[serde(default)]
+    pub security_version: Option<u64>,
+    #[serde(default)]
+    pub minimum_security_version: Option<u64>,
+    /// Hashes of kernels and initrds that the stub refuses to boot.
+    #[serde(default)]
+    pub revoked_hashes: Vec<RevokedHash>,
+    /// The timeout in seconds of the watchdog that the stub arms before starting the kernel.
+    #[serde(default)]
+    pub watchdog_timeout: Option<u64>,
+    /// Concatenated DER encoded certificates that addons and credentials must be signed with.
+    #[serde(default)]
+    pub authcert: Option<Vec<u8>>,
+    /// How many of the certificates in `authcert` must have signed an addon or a credential.
+    #[serde(default)]
+    pub authcert_threshold: Option<usize>,
+    /// Extend the command line from SMBIOS OEM strings even if Secure Boot is active.
+    #[serde(default)]
+    pub allow_smbios_cmdline: bool,
+    /// Use the command line and initrd from QEMU's fw_cfg even if Secure Boot is active.
+    #[serde(default)]
+    pub allow_fw_cfg: bool,
+    /// Pass the metadata of the cloud instance to the initrd as credentials.
+    #[serde(default)]
+    pub instance_metadata: bool,
+    /// The initrd is encrypted with the key that is held by the firmware.
+    #[serde(default)]
+    pub encrypted_initrd: bool,
+    /// The release of the kernel, which the stub compares with the one of a hibernated system.
+    #[serde(default)]
+    pub kernel_release: Option<String>,
+    /// Refuse to resume a system that hibernated with a different kernel instead of warning.
+    #[serde(default)]
+    pub refuse_mismatched_resume: bool,
+    /// The dm-verity root hash of the root file system, as hex digits.
+    #[serde(default)]
+    pub verity_root_hash: Option<String>,
+    /// What the stub does if the firmware clock is before `not_before`.
+    #[serde(default)]
+    pub clock_check: ClockCheck,
+    /// The build date of the generation as a Unix timestamp.
+    #[serde(default)]
+    pub not_before: Option<u64>,
+    /// The kernel lockdown mode that the stub enforces.
+    #[serde(default)]
+    pub lockdown: LockdownMode,
+    /// Make the stub enforce that the kernel only loads signed modules.
+    #[serde(default)]
+    pub module_sig_enforce: bool,
+    /// The partition that the kernel and initrd paths are relative to, if it is not the ESP.
+    #[serde(default)]
+    pub payload_partition: Option<PartitionSelector>,
+    /// The UEFI paths of the file system drivers for the payload partition.
+    #[serde(default)]
+    pub fs_drivers: Vec<String>,
+    /// How the stub unlocks the payload partition if it is encrypted.
+    #[serde(default)]
+    pub payload_luks: Option<LuksUnlock>,
 }
 
 impl StubParameters {
@@ -48,6 +439,34 @@ impl StubParameters {
             initrd_path_at_esp: esp_relative_uefi_path(esp, initrd_target)?,
             kernel_cmdline: Vec::new(),
             os_release_contents: Vec::new(),
+            simulate_secure_boot: false,
+            insecure_boot_policy: InsecureBootPolicy::default(),
+            verbosity: StubVerbosity::default(),
+            locale: StubLocale::default(),
+            clear_screen: false,
+            serial_console: false,
+            log_file: false,
+            splash: None,
+            security_version: None,
+            minimum_security_version: None,
+            revoked_hashes: Vec::new(),
+            watchdog_timeout: None,
+            authcert: None,
+            authcert_threshold: None,
+            allow_smbios_cmdline: false,
+            allow_fw_cfg: false,
+            instance_metadata: false,
+            encrypted_initrd: false,
+            kernel_release: None,
+            refuse_mismatched_resume: false,
+            verity_root_hash: None,
+            clock_check: ClockCheck::default(),
+            not_before: None,
+            lockdown: LockdownMode::default(),
+            module_sig_enforce: false,
+            payload_partition: None,
+            fs_drivers: Vec::new(),
+            payload_luks: None,
         })
     }
 
@@ -60,29 +479,195 @@ impl StubParameters {
         self.kernel_cmdline = cmdline.to_vec();
         self
     }
-}
 
-/// Performs the evil operation
-/// of calling the appender script to append
-/// initrd "secrets" (not really) to the initrd.
-pub fn append_initrd_secrets(
-    append_initrd_secrets_path: &Path,
-    initrd_path: &PathBuf,
-    generation_version: u64,
-) -> Result<()> {
-    let status = Command::new(append_initrd_secrets_path)
-        .args(vec![initrd_path])
-        .status()
-        .context("Failed to append initrd secrets")?;
-    if !status.success() {
-        return Err(anyhow::anyhow!(
-            "Failed to append initrd secrets for generation {} with args `{:?}`",
-            generation_version,
-            vec![append_initrd_secrets_path, initrd_path]
-        ));
+    pub fn with_simulate_secure_boot(mut self, simulate_secure_boot: bool) -> Self {
+        self.simulate_secure_boot = simulate_secure_boot;
+        self
     }
 
-    Ok(())
+    pub fn with_insecure_boot_policy(mut self, insecure_boot_policy: InsecureBootPolicy) -> Self {
+        self.insecure_boot_policy = insecure_boot_policy;
+        self
+    }
+
+    pub fn with_verbosity(mut self, verbosity: StubVerbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    pub fn with_locale(mut self, locale: StubLocale) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn with_clear_screen(mut self, clear_screen: bool) -> Self {
+        self.clear_screen = clear_screen;
+        self
+    }
+
+    pub fn with_serial_console(mut self, serial_console: bool) -> Self {
+        self.serial_console = serial_console;
+        self
+    }
+
+    pub fn with_log_file(mut self, log_file: bool) -> Self {
+        self.log_file = log_file;
+        self
+    }
+
+    pub fn with_splash(mut self, splash: &Path) -> Self {
+        self.splash = Some(splash.to_path_buf());
+        self
+    }
+
+    /// Embed a security version number for anti-rollback protection.
+    ///
+    /// The stub refuses to boot if its security version is below the floor recorded in the
+    /// firmware. Booting the stub raises the floor to `minimum_security_version`.
+    pub fn with_security_version(
+        mut self,
+        security_version: Option<u64>,
+        minimum_security_version: Option<u64>,
+    ) -> Self {
+        self.security_version = security_version;
+        self.minimum_security_version = minimum_security_version;
+        self
+    }
+
+    pub fn with_revocation_list(mut self, revocation_list: &RevocationList) -> Self {
+        self.revoked_hashes = revocation_list.iter().copied().collect();
+        self
+    }
+
+    /// Make the stub arm the firmware watchdog right before it starts the kernel.
+    ///
+    /// If the kernel hangs before it exits boot services, the firmware resets the machine.
+    pub fn with_watchdog_timeout(mut self, watchdog_timeout: Option<u64>) -> Self {
+        self.watchdog_timeout = watchdog_timeout;
+        self
+    }
+
+    /// Pin the certificates that addons and credentials must be signed with, as concatenated DER.
+    ///
+    /// The stub then verifies them against these certificates instead of the Secure Boot database.
+    pub fn with_authcert(mut self, authcert: Option<&[u8]>) -> Self {
+        self.authcert = authcert.map(<[u8]>::to_vec);
+        self
+    }
+
+    /// Require addons and credentials to be signed with this many of the pinned certificates
+    /// instead of any one of them.
+    pub fn with_authcert_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.authcert_threshold = threshold;
+        self
+    }
+
+    /// Let the stub append the `io.systemd.stub.kernel-cmdline-extra` SMBIOS OEM string to the
+    /// command line even if Secure Boot is active.
+    pub fn with_allow_smbios_cmdline(mut self, allow_smbios_cmdline: bool) -> Self {
+        self.allow_smbios_cmdline = allow_smbios_cmdline;
+        self
+    }
+
+    /// Let the stub take the command line and an additional initrd from QEMU's fw_cfg even if
+    /// Secure Boot is active.
+    pub fn with_allow_fw_cfg(mut self, allow_fw_cfg: bool) -> Self {
+        self.allow_fw_cfg = allow_fw_cfg;
+        self
+    }
+
+    /// Let the stub pass the metadata of the cloud instance, e.g. from an OpenStack config
+    /// drive, to the initrd as credentials.
+    pub fn with_instance_metadata(mut self, instance_metadata: bool) -> Self {
+        self.instance_metadata = instance_metadata;
+        self
+    }
+
+    /// Make the stub decrypt the initrd with the key that is held by the firmware.
+    pub fn with_encrypted_initrd(mut self, encrypted_initrd: bool) -> Self {
+        self.encrypted_initrd = encrypted_initrd;
+        self
+    }
+
+    /// Record the release of the kernel, see [`crate::kernel::kernel_release`].
+    ///
+    /// If the system hibernated with another kernel, the stub warns before it boots this one.
+    pub fn with_kernel_release(mut self, kernel_release: Option<String>) -> Self {
+        self.kernel_release = kernel_release;
+        self
+    }
+
+    /// Make the stub refuse to boot if the system hibernated with a different kernel.
+    pub fn with_refuse_mismatched_resume(mut self, refuse_mismatched_resume: bool) -> Self {
+        self.refuse_mismatched_resume = refuse_mismatched_resume;
+        self
+    }
+
+    /// Embed the dm-verity root hash of an image-based system.
+    ///
+    /// It is added to the command line as `roothash=`, and the stub makes sure that addons and
+    /// SMBIOS cannot replace it, so the integrity of the root file system chains up to the
+    /// signature of the stub.
+    pub fn with_verity_root_hash(mut self, verity_root_hash: Option<&str>) -> Self {
+        self.verity_root_hash = verity_root_hash.map(str::to_string);
+        self
+    }
+
+    /// Make the stub check that the firmware clock is not before `not_before`, a Unix timestamp.
+    ///
+    /// A clock that was reset, e.g. by a dead CMOS battery, makes certificates and time-based TPM
+    /// policies fail later in the boot.
+    pub fn with_clock_check(mut self, clock_check: ClockCheck, not_before: Option<u64>) -> Self {
+        self.clock_check = clock_check;
+        self.not_before = not_before;
+        self
+    }
+
+    /// Enforce a hardening policy on the command line of the kernel.
+    ///
+    /// The parameters are added to the command line, and the stub appends them again if the boot
+    /// loader, SMBIOS or an addon weakened them, so the policy is enforced by the signature of the
+    /// stub rather than by convention.
+    pub fn with_lockdown(mut self, lockdown: LockdownMode, module_sig_enforce: bool) -> Self {
+        self.lockdown = lockdown;
+        self.module_sig_enforce = module_sig_enforce;
+        self
+    }
+
+    /// Make the stub load the kernel and initrd from another partition than its own.
+    ///
+    /// The targets passed to [`Self::new`] must then be relative to the mount point of that
+    /// partition instead of the ESP.
+    pub fn with_payload_partition(mut self, payload_partition: Option<PartitionSelector>) -> Self {
+        self.payload_partition = payload_partition;
+        self
+    }
+
+    /// Make the stub load these file system drivers if it does not find the payload partition.
+    ///
+    /// The drivers are given as UEFI paths on the ESP, see [`esp_relative_uefi_path`].
+    pub fn with_fs_drivers(mut self, fs_drivers: &[String]) -> Self {
+        self.fs_drivers = fs_drivers.to_vec();
+        self
+    }
+
+    /// Make the stub unlock the payload partition, which must then be a LUKS2 volume.
+    pub fn with_payload_luks(mut self, payload_luks: Option<LuksUnlock>) -> Self {
+        self.payload_luks = payload_luks;
+        self
+    }
+
+    /// The kernel parameters of the hardening policy, see [`Self::with_lockdown`].
+    pub fn lockdown_policy(&self) -> Vec<String> {
+        let mut policy = Vec::new();
+        if self.lockdown != LockdownMode::None {
+            policy.push(format!("lockdown={}", self.lockdown));
+        }
+        if self.module_sig_enforce {
+            policy.push("module.sig_enforce=1".to_string());
+        }
+        policy
+    }
 }
 
 /// Assemble a lanzaboote image.
@@ -94,8 +679,16 @@ pub fn lanzaboote_image(
 ) -> Result<PathBuf> {
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
-    let kernel_cmdline_file =
-        tempdir.write_secure_file(stub_parameters.kernel_cmdline.join(" "))?;
+    let mut kernel_cmdline = stub_parameters.kernel_cmdline.clone();
+    if let Some(root_hash) = &stub_parameters.verity_root_hash {
+        if root_hash.len() % 2 != 0 || !root_hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("The dm-verity root hash {root_hash:?} is not a hex string");
+        }
+        kernel_cmdline.push(format!("roothash={root_hash}"));
+    }
+    let lockdown_policy = stub_parameters.lockdown_policy();
+    kernel_cmdline.extend(lockdown_policy.iter().cloned());
+    let kernel_cmdline_file = tempdir.write_secure_file(kernel_cmdline.join(" "))?;
 
     let kernel_path_file = tempdir.write_secure_file(&stub_parameters.kernel_path_at_esp)?;
     let kernel_hash_file =
@@ -106,28 +699,257 @@ pub fn lanzaboote_image(
         tempdir.write_secure_file(file_hash(&stub_parameters.initrd_store_path)?.as_slice())?;
 
     let os_release = tempdir.write_secure_file(&stub_parameters.os_release_contents)?;
-    let os_release_offs = stub_offset(&stub_parameters.lanzaboote_store_path)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(&os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrd", initrd_path_file, initrd_path_offs),
-        s(".linux", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".linuxh", kernel_hash_file, kernel_hash_offs),
+
+    let mut section_files = vec![
+        (".osrel", os_release),
+        (".cmdline", kernel_cmdline_file),
+        (".initrd", initrd_path_file),
+        (".linux", kernel_path_file),
+        (".initrdh", initrd_hash_file),
+        (".linuxh", kernel_hash_file),
     ];
 
-    let image_path = tempdir.path().join(tmpname());
-    wrap_in_pe(
+    if let Some(splash) = &stub_parameters.splash {
+        let data = fs::read(splash)
+            .with_context(|| format!("Failed to read the splash image {splash:?}"))?;
+        check_bmp(&data).with_context(|| format!("Unsupported splash image {splash:?}"))?;
+        section_files.push((".splash", splash.clone()));
+    }
+
+    if let Some(minimum) = stub_parameters.minimum_security_version {
+        if minimum > stub_parameters.security_version.unwrap_or(0) {
+            bail!(
+                "The minimum security version {minimum} is higher than the security version of \
+                 the generation itself"
+            );
+        }
+        section_files.push((".svnmin", tempdir.write_secure_file(minimum.to_string())?));
+    }
+    if let Some(security_version) = stub_parameters.security_version {
+        section_files.push((
+            ".svn",
+            tempdir.write_secure_file(security_version.to_string())?,
+        ));
+    }
+
+    if !stub_parameters.revoked_hashes.is_empty() {
+        section_files.push((
+            ".revoked",
+            tempdir.write_secure_file(stub_parameters.revoked_hashes.concat())?,
+        ));
+    }
+
+    // Without this section, the stub loads the kernel and initrd from its own partition.
+    if let Some(partition) = &stub_parameters.payload_partition {
+        section_files.push((
+            ".partsel",
+            tempdir.write_secure_file(partition.to_string())?,
+        ));
+    }
+    if let Some(luks) = &stub_parameters.payload_luks {
+        section_files.push((".luks", tempdir.write_secure_file(luks.to_string())?));
+    }
+    if !stub_parameters.fs_drivers.is_empty() {
+        section_files.push((
+            ".drivers",
+            tempdir.write_secure_file(stub_parameters.fs_drivers.join("\n"))?,
+        ));
+    }
+
+    // Without this section, the stub keeps the watchdog of the firmware.
+    if let Some(timeout) = stub_parameters.watchdog_timeout {
+        section_files.push((".wdog", tempdir.write_secure_file(timeout.to_string())?));
+    }
+
+    // Same as in a UKI. Without it, the stub cannot compare the kernel with a hibernated one.
+    if let Some(kernel_release) = &stub_parameters.kernel_release {
+        section_files.push((".uname", tempdir.write_secure_file(kernel_release)?));
+    }
+
+    // The stub appends it to the command line again if anything else replaced it.
+    if let Some(root_hash) = &stub_parameters.verity_root_hash {
+        section_files.push((".roothash", tempdir.write_secure_file(root_hash)?));
+    }
+
+    // Like the root hash, the stub appends these parameters again if anything weakened them.
+    if !lockdown_policy.is_empty() {
+        section_files.push((
+            ".lockdown",
+            tempdir.write_secure_file(lockdown_policy.join("\n"))?,
+        ));
+    }
+
+    // Without this section, the stub does not check the firmware clock.
+    if let Some(not_before) = stub_parameters.not_before {
+        if stub_parameters.clock_check != ClockCheck::Off {
+            section_files.push((
+                ".notbefore",
+                tempdir.write_secure_file(not_before.to_string())?,
+            ));
+        }
+    }
+
+    // Without this section, addons are verified by the firmware and credentials are not verified.
+    if let Some(authcert) = &stub_parameters.authcert {
+        section_files.push((".authcert", tempdir.write_secure_file(authcert)?));
+        if let Some(threshold) = stub_parameters.authcert_threshold {
+            section_files.push((
+                ".auththreshold",
+                tempdir.write_secure_file(threshold.to_string())?,
+            ));
+        }
+    }
+
+    // The stub only checks for the presence of these sections.
+    if stub_parameters.simulate_secure_boot {
+        section_files.push((".sbsim", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.clear_screen {
+        section_files.push((".clrscr", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.serial_console {
+        section_files.push((".serial", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.log_file {
+        section_files.push((".logfile", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.allow_smbios_cmdline {
+        section_files.push((".smbcmd", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.allow_fw_cfg {
+        section_files.push((".fwcfg", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.instance_metadata {
+        section_files.push((".imds", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.encrypted_initrd {
+        section_files.push((".initrdenc", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.refuse_mismatched_resume {
+        section_files.push((".hibchk", tempdir.write_secure_file("1")?));
+    }
+    if stub_parameters.clock_check == ClockCheck::Set {
+        section_files.push((".setclock", tempdir.write_secure_file("1")?));
+    }
+    // Without this section, the stub boots with a warning if Secure Boot is disabled.
+    if stub_parameters.insecure_boot_policy != InsecureBootPolicy::Warn {
+        section_files.push((
+            ".insecure",
+            tempdir.write_secure_file(stub_parameters.insecure_boot_policy.as_str())?,
+        ));
+    }
+    // Without this section, the stub uses its normal verbosity.
+    if stub_parameters.verbosity != StubVerbosity::Normal {
+        section_files.push((
+            ".loglvl",
+            tempdir.write_secure_file(stub_parameters.verbosity.as_str())?,
+        ));
+    }
+    // Without this section, the stub displays its messages in English.
+    if stub_parameters.locale != StubLocale::En {
+        section_files.push((
+            ".locale",
+            tempdir.write_secure_file(stub_parameters.locale.as_str())?,
+        ));
+    }
+
+    assemble_image(
+        tempdir,
         &stub_parameters.lanzaboote_store_path,
-        sections,
-        &image_path,
-    )?;
+        section_files,
+    )
+}
+
+/// Assemble an addon that extends boot entries with a command line and/or an initrd.
+///
+/// The stub discovers addons in the drop-in directory of an entry. `addon_stub` is a minimal PE
+/// binary to attach the sections to, e.g. the addon stub of systemd.
+pub fn addon_image(
+    tempdir: &TempDir,
+    addon_stub: &Path,
+    cmdline: Option<&str>,
+    initrd: Option<&Path>,
+) -> Result<PathBuf> {
+    let mut section_files = Vec::new();
+    if let Some(cmdline) = cmdline {
+        section_files.push((".cmdline", tempdir.write_secure_file(cmdline)?));
+    }
+    if let Some(initrd) = initrd {
+        section_files.push((".initrd", initrd.to_path_buf()));
+    }
+    if section_files.is_empty() {
+        bail!("An addon needs a command line or an initrd");
+    }
+
+    assemble_image(tempdir, addon_stub, section_files)
+}
+
+/// Assemble a slot selector, which chainloads the payload of slot A or B, see `lzbt slot`.
+///
+/// `slot_paths` are the UEFI paths of the payloads relative to the ESP. The selector is the
+/// Lanzaboote stub without a kernel, so it carries the same code that boots the payloads.
+pub fn slot_selector_image(
+    tempdir: &TempDir,
+    lanzaboote_stub: &Path,
+    esp: &Path,
+    slot_paths: &[PathBuf; 2],
+    os_release_contents: &[u8],
+) -> Result<PathBuf> {
+    let slots = slot_paths
+        .iter()
+        .map(|path| esp_relative_uefi_path(esp, path))
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
+    let section_files = vec![
+        (".osrel", tempdir.write_secure_file(os_release_contents)?),
+        (".abslots", tempdir.write_secure_file(slots)?),
+    ];
+    assemble_image(tempdir, lanzaboote_stub, section_files)
+}
+
+/// Assemble a first stage, which verifies systemd-boot by its SHA256 hash and chainloads it.
+///
+/// The first stage is the Lanzaboote stub without a kernel, installed as the binary that the
+/// firmware starts, for firmwares that only trust a single signed binary. `systemd_boot_hash` is
+/// the hash of the installed binary at `systemd_boot`, so that it covers its signature as well.
+pub fn first_stage_image(
+    tempdir: &TempDir,
+    lanzaboote_stub: &Path,
+    esp: &Path,
+    systemd_boot: &Path,
+    systemd_boot_hash: &[u8],
+    simulate_secure_boot: bool,
+) -> Result<PathBuf> {
+    let mut section_files = vec![
+        (
+            ".sdboot",
+            tempdir.write_secure_file(esp_relative_uefi_path(esp, systemd_boot)?)?,
+        ),
+        (".sdbooth", tempdir.write_secure_file(systemd_boot_hash)?),
+    ];
+    if simulate_secure_boot {
+        section_files.push((".sbsim", tempdir.write_secure_file("1")?));
+    }
+    assemble_image(tempdir, lanzaboote_stub, section_files)
+}
+
+/// Attach sections to a stub and write the result to a new file in `tempdir`.
+fn assemble_image(
+    tempdir: &TempDir,
+    stub: &Path,
+    section_files: Vec<(&'static str, PathBuf)>,
+) -> Result<PathBuf> {
+    // Place the sections one after another behind the last section of the stub.
+    let mut offset = stub_offset(stub)?;
+    let mut sections = Vec::new();
+    for (name, file_path) in section_files {
+        let size = file_size(&file_path)?;
+        sections.push(s(name, file_path, offset));
+        offset += size;
+    }
+
+    let image_path = tempdir.path().join(tmpname());
+    wrap_in_pe(stub, sections, &image_path)?;
     Ok(image_path)
 }
 
@@ -188,7 +1010,7 @@ fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
 }
 
 /// Convert a path to an UEFI path relative to the specified ESP.
-fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
+pub fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
     let relative_path = path
         .strip_prefix(esp)
         .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
@@ -273,6 +1095,74 @@ mod tests {
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn parse_stub_verbosity() {
+        for verbosity in [
+            StubVerbosity::Quiet,
+            StubVerbosity::Normal,
+            StubVerbosity::Debug,
+        ] {
+            assert_eq!(
+                verbosity.to_string().parse::<StubVerbosity>().unwrap(),
+                verbosity
+            );
+        }
+        assert!("loud".parse::<StubVerbosity>().is_err());
+    }
+
+    #[test]
+    fn lockdown_policy_parameters() -> Result<()> {
+        let parameters = StubParameters::new(
+            Path::new("/stub.efi"),
+            Path::new("/kernel"),
+            Path::new("/initrd"),
+            Path::new("/esp/EFI/nixos/kernel.efi"),
+            Path::new("/esp/EFI/nixos/initrd.efi"),
+            Path::new("/esp"),
+        )?;
+        assert!(parameters.lockdown_policy().is_empty());
+
+        let parameters = parameters.with_lockdown("integrity".parse()?, true);
+        assert_eq!(
+            parameters.lockdown_policy(),
+            ["lockdown=integrity", "module.sig_enforce=1"]
+        );
+        assert!("lax".parse::<LockdownMode>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_partition_selector() -> Result<()> {
+        assert_eq!(
+            "PARTUUID=0FC63DAF-8483-4772-8E79-3D69D8477DE4".parse::<PartitionSelector>()?,
+            PartitionSelector::PartUuid("0fc63daf-8483-4772-8e79-3d69d8477de4".to_string())
+        );
+        assert_eq!(
+            "LABEL=boot-data".parse::<PartitionSelector>()?.to_string(),
+            "LABEL=boot-data"
+        );
+        assert!("PARTUUID=0fc63daf".parse::<PartitionSelector>().is_err());
+        assert!("UUID=1234-ABCD".parse::<PartitionSelector>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn format_luks_section() -> Result<()> {
+        assert_eq!(LuksUnlock::default().to_string(), "luks2\n");
+        let luks = LuksUnlock {
+            tpm2_handle: Some(LuksUnlock::parse_tpm2_handle("0x81000001")?),
+            tpm2_pcrs: LuksUnlock::parse_tpm2_pcrs("7+11")?,
+        };
+        assert_eq!(
+            luks.to_string(),
+            "luks2\ntpm2-handle=0x81000001\ntpm2-pcrs=7+11\n"
+        );
+        assert!(LuksUnlock::parse_tpm2_handle("0x01000001").is_err());
+        assert!(LuksUnlock::parse_tpm2_handle("81000001").is_err());
+        assert!(LuksUnlock::parse_tpm2_pcrs("7+24").is_err());
+        Ok(())
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
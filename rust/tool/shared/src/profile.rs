@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// The directory that contains the profiles of the system, e.g. `system` and its generations.
+pub const PROFILES_DIRECTORY: &str = "/nix/var/nix/profiles";
+
+/// Locate a profile by its name, e.g. `system`, or by its path.
+pub fn profile_path(profile: &str) -> PathBuf {
+    if profile.contains('/') {
+        PathBuf::from(profile)
+    } else {
+        Path::new(PROFILES_DIRECTORY).join(profile)
+    }
+}
+
+/// Find the generation links of a profile, sorted from oldest to newest.
+///
+/// Nix keeps the generations of a profile like `/nix/var/nix/profiles/system` as
+/// `system-{version}-link` next to it. Links whose target does not exist anymore, e.g. because
+/// the garbage collector raced with us, are skipped.
+pub fn generation_links(profile: &Path) -> Result<Vec<PathBuf>> {
+    let directory = profile
+        .parent()
+        .with_context(|| format!("Profile {profile:?} has no parent directory"))?;
+    let name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Profile {profile:?} has no valid name"))?;
+
+    let mut links = Vec::new();
+    for entry in fs::read_dir(directory)
+        .with_context(|| format!("Failed to read the profiles directory {directory:?}"))?
+    {
+        let path = entry?.path();
+        let Some(version) = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| link_version(name, file_name))
+        else {
+            continue;
+        };
+
+        match fs::canonicalize(&path) {
+            Ok(target) => log::debug!("Found generation {version} at {target:?}."),
+            Err(e) => {
+                log::warn!("Skipping generation {version}, {path:?} cannot be resolved: {e}");
+                continue;
+            }
+        }
+        links.push((version, path));
+    }
+
+    if links.is_empty() {
+        log::warn!("Profile {profile:?} has no generations.");
+    }
+    links.sort();
+    Ok(links.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The versions of the generations of a profile, sorted from oldest to newest.
+pub fn generation_versions(profile: &Path) -> Result<Vec<u64>> {
+    let name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Profile {profile:?} has no valid name"))?;
+    Ok(generation_links(profile)?
+        .iter()
+        .filter_map(|link| link_version(name, link.file_name()?.to_str()?))
+        .collect())
+}
+
+/// The names of the profiles in `directory`, e.g. [`PROFILES_DIRECTORY`], sorted by name.
+///
+/// A profile is a symlink to its current generation. The links of the generations themselves are
+/// not profiles.
+pub fn profile_names(directory: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(directory)
+        .with_context(|| format!("Failed to read the profiles directory {directory:?}"))?
+    {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let is_generation = name
+            .strip_suffix("-link")
+            .and_then(|name| name.rsplit_once('-'))
+            .is_some_and(|(_, version)| version.parse::<u64>().is_ok());
+        if entry.file_type()?.is_symlink() && !is_generation {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Parse the version from the name of a generation link of the profile `profile`.
+fn link_version(profile: &str, file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(profile)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn parse_link_versions() {
+        assert_eq!(link_version("system", "system-12-link"), Some(12));
+        assert_eq!(link_version("system", "system-12"), None);
+        assert_eq!(link_version("system", "system-profiles-link"), None);
+        assert_eq!(link_version("system", "other-12-link"), None);
+        // A profile named `system-1` is a different profile.
+        assert_eq!(link_version("system", "system-1-2-link"), None);
+    }
+
+    #[test]
+    fn locate_profiles() {
+        assert_eq!(
+            profile_path("system"),
+            Path::new("/nix/var/nix/profiles/system")
+        );
+        assert_eq!(
+            profile_path("/tmp/profiles/system"),
+            Path::new("/tmp/profiles/system")
+        );
+    }
+
+    #[test]
+    fn discover_generations_in_order() -> Result<()> {
+        let profiles = tempfile::tempdir()?;
+        let store = tempfile::tempdir()?;
+        for version in [10, 2, 1] {
+            let toplevel = store.path().join(format!("nixos-system-{version}"));
+            fs::create_dir(&toplevel)?;
+            symlink(
+                &toplevel,
+                profiles.path().join(format!("system-{version}-link")),
+            )?;
+        }
+        symlink("system-10-link", profiles.path().join("system"))?;
+        symlink(
+            store.path().join("nixos-system-10"),
+            profiles.path().join("other-3-link"),
+        )?;
+        // A generation that was garbage collected while we looked.
+        symlink(
+            store.path().join("nixos-system-11"),
+            profiles.path().join("system-11-link"),
+        )?;
+
+        let links = generation_links(&profiles.path().join("system"))?;
+        assert_eq!(
+            links,
+            [1, 2, 10].map(|version| profiles.path().join(format!("system-{version}-link")))
+        );
+        assert_eq!(
+            generation_versions(&profiles.path().join("system"))?,
+            [1, 2, 10]
+        );
+        assert_eq!(profile_names(profiles.path())?, ["system"]);
+        Ok(())
+    }
+}
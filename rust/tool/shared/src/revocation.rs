@@ -0,0 +1,177 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::utils::file_hash;
+
+/// A SHA256 hash of a kernel or initrd.
+pub type RevokedHash = [u8; 32];
+
+/// The default location of the revocation list.
+pub const DEFAULT_REVOCATION_LIST: &str = "/var/lib/lanzaboote/revoked-hashes";
+
+/// A list of kernel and initrd hashes that must not be booted anymore, e.g. because the kernel
+/// has a known privilege escalation.
+///
+/// On disk, the list contains one SHA256 hash in hex per line. Empty lines and lines starting with
+/// `#` are ignored. The list is embedded into every stub, which refuses to boot any kernel or
+/// initrd on it, even if it is correctly signed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RevocationList {
+    hashes: BTreeSet<RevokedHash>,
+}
+
+impl RevocationList {
+    /// Read a revocation list from a file. A missing file is an empty list.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .parse()
+                .with_context(|| format!("Failed to parse the revocation list {path:?}")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read the revocation list {path:?}"))
+            }
+        }
+    }
+
+    /// Write the revocation list to a file, replacing it atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create the directory {parent:?}"))?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_string())
+            .with_context(|| format!("Failed to write the revocation list {tmp:?}"))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to move the revocation list to {path:?}"))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        hash.try_into()
+            .is_ok_and(|hash: RevokedHash| self.hashes.contains(&hash))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RevokedHash> {
+        self.hashes.iter()
+    }
+
+    /// Add a hash. Returns whether it was not on the list before.
+    pub fn insert(&mut self, hash: RevokedHash) -> bool {
+        self.hashes.insert(hash)
+    }
+
+    /// Remove a hash. Returns whether it was on the list.
+    pub fn remove(&mut self, hash: &RevokedHash) -> bool {
+        self.hashes.remove(hash)
+    }
+
+    /// The contents of the `.revoked` section of the stub: the concatenated raw hashes.
+    pub fn to_section(&self) -> Vec<u8> {
+        self.hashes.iter().flatten().copied().collect()
+    }
+}
+
+impl std::str::FromStr for RevocationList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hashes = s
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(index, line)| {
+                parse_hash(line).with_context(|| format!("Invalid hash in line {}", index + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { hashes })
+    }
+}
+
+impl std::fmt::Display for RevocationList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for hash in &self.hashes {
+            writeln!(f, "{}", format_hash(hash))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a SHA256 hash from its hex representation.
+pub fn parse_hash(s: &str) -> Result<RevokedHash> {
+    if s.len() != 64 || !s.is_ascii() {
+        bail!("{s:?} is not a SHA256 hash in hex");
+    }
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        // The input is ASCII, so the digits are always valid UTF-8.
+        let digits = std::str::from_utf8(digits).expect("ASCII is valid UTF-8");
+        *byte = u8::from_str_radix(digits, 16)
+            .with_context(|| format!("{s:?} is not a SHA256 hash in hex"))?;
+    }
+    Ok(hash)
+}
+
+/// Format a SHA256 hash as lowercase hex.
+pub fn format_hash(hash: &[u8]) -> String {
+    hash.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Interpret an argument as either a hash in hex or the path of a file to hash.
+pub fn hash_from_argument(argument: &str) -> Result<RevokedHash> {
+    let path = Path::new(argument);
+    if path.is_file() {
+        Ok(file_hash(path)?.into())
+    } else {
+        parse_hash(argument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+    #[test]
+    fn roundtrip_revocation_list() -> Result<()> {
+        let list: RevocationList = format!("# Kernel with a known LPE\n\n{HASH}\n").parse()?;
+        assert!(list.contains(&parse_hash(HASH)?));
+        assert_eq!(list.to_string(), format!("{HASH}\n"));
+        assert_eq!(list.to_section(), parse_hash(HASH)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_hashes() {
+        assert!(parse_hash("2c26b4").is_err());
+        assert!(parse_hash(&HASH.replace('2', "g")).is_err());
+        assert!("not a hash".parse::<RevocationList>().is_err());
+    }
+
+    #[test]
+    fn missing_revocation_list_is_empty() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("lanzaboote/revoked-hashes");
+        assert!(RevocationList::load(&path)?.is_empty());
+
+        let mut list = RevocationList::default();
+        list.insert(parse_hash(HASH)?);
+        list.save(&path)?;
+        assert_eq!(RevocationList::load(&path)?, list);
+        Ok(())
+    }
+}
@@ -1,13 +1,16 @@
 use crate::pe::lanzaboote_image;
+use crate::timestamp::timestamp_pe;
 use crate::utils::SecureTempDirExt;
 use std::ffi::OsString;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use tempfile::tempdir;
 
+use super::pkcs12::Pkcs12Bundle;
 use super::Signer;
 
 /// A local keypair is a signer that reuses private key material
@@ -24,6 +27,10 @@ use super::Signer;
 pub struct LocalKeyPair {
     pub private_key: PathBuf,
     pub public_key: PathBuf,
+    /// Keeps the in-memory files of a PKCS#12 bundle open that the paths refer to.
+    _bundle: Option<Arc<Pkcs12Bundle>>,
+    /// The RFC 3161 timestamp authority that timestamps the signatures.
+    timestamp_url: Option<String>,
 }
 
 impl LocalKeyPair {
@@ -31,8 +38,104 @@ impl LocalKeyPair {
         Self {
             public_key: public_key.into(),
             private_key: private_key.into(),
+            _bundle: None,
+            timestamp_url: None,
         }
     }
+
+    /// A key pair with the certificate and the private key of a PKCS#12 bundle.
+    pub fn from_pkcs12(bundle: Pkcs12Bundle) -> Self {
+        Self {
+            public_key: bundle.certificate_path(),
+            private_key: bundle.private_key_path(),
+            _bundle: Some(Arc::new(bundle)),
+            timestamp_url: None,
+        }
+    }
+
+    /// Timestamp the signatures with the RFC 3161 timestamp authority at this URL.
+    ///
+    /// See [`crate::timestamp`].
+    pub fn with_timestamp_url(mut self, timestamp_url: Option<String>) -> Self {
+        self.timestamp_url = timestamp_url;
+        self
+    }
+
+    /// A key pair without the private key, which can only verify signatures.
+    ///
+    /// This is meant for services that must not have access to the private key.
+    pub fn public_only(public_key: &Path) -> Self {
+        Self {
+            public_key: public_key.into(),
+            private_key: PathBuf::new(),
+            _bundle: None,
+            timestamp_url: None,
+        }
+    }
+
+    /// Sign arbitrary data, i.e. not a PE binary, and return a detached DER encoded CMS
+    /// signature.
+    ///
+    /// Currently, this happens via `openssl cms`.
+    pub fn sign_detached(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let working_tree = tempdir().context("Failed to get a temporary working tree")?;
+        let input = working_tree.write_secure_file(data)?;
+        let output = Command::new("openssl")
+            .args([
+                "cms", "-sign", "-binary", "-md", "sha256", "-outform", "DER",
+            ])
+            .arg("-in")
+            .arg(&input)
+            .arg("-signer")
+            .arg(&self.public_key)
+            .arg("-inkey")
+            .arg(&self.private_key)
+            .output()
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to sign: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    /// Verify a detached signature of [`Self::sign_detached`] with the certificate of this key
+    /// pair alone. Return true if the signature was verified.
+    pub fn verify_detached(&self, data: &[u8], signature: &[u8]) -> Result<bool> {
+        let working_tree = tempdir().context("Failed to get a temporary working tree")?;
+        let content = working_tree.write_secure_file(data)?;
+        let signature = working_tree.write_secure_file(signature)?;
+        let output = Command::new("openssl")
+            // The certificate is self-signed or its CA is not at hand, so the chain is not
+            // verified, but only the given certificate is accepted as the signer.
+            .args([
+                "cms",
+                "-verify",
+                "-binary",
+                "-inform",
+                "DER",
+                "-noverify",
+                "-nointern",
+            ])
+            .arg("-in")
+            .arg(&signature)
+            .arg("-content")
+            .arg(&content)
+            .arg("-certfile")
+            .arg(&self.public_key)
+            .output()
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+        if !output.status.success() {
+            log::debug!(
+                "openssl cms -verify failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
 }
 
 impl Signer for LocalKeyPair {
@@ -64,6 +167,12 @@ impl Signer for LocalKeyPair {
             return Err(anyhow::anyhow!("Failed to sign {to:?}."));
         }
 
+        if let Some(url) = &self.timestamp_url {
+            let signed = std::fs::read(to).with_context(|| format!("Failed to read {to:?}"))?;
+            std::fs::write(to, timestamp_pe(&signed, url)?)
+                .with_context(|| format!("Failed to write {to:?}"))?;
+        }
+
         Ok(())
     }
 
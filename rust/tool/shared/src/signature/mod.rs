@@ -56,3 +56,4 @@ pub trait Signer {
 }
 
 pub mod local;
+pub mod pkcs12;
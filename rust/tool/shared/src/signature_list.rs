@@ -0,0 +1,242 @@
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+
+/// `EFI_CERT_SHA256_GUID`: the signature is the Authenticode SHA256 hash of a PE binary.
+pub const EFI_CERT_SHA256_GUID: Guid = Guid::new(
+    0xc1c41626,
+    0x504c,
+    0x4092,
+    [0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28],
+);
+
+/// `EFI_CERT_X509_GUID`: the signature is a DER encoded X.509 certificate.
+pub const EFI_CERT_X509_GUID: Guid = Guid::new(
+    0xa5c059a1,
+    0x94e4,
+    0x4aa7,
+    [0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72],
+);
+
+/// `EFI_CERT_TYPE_PKCS7_GUID`: the certificate type of authenticated variable updates.
+const EFI_CERT_TYPE_PKCS7_GUID: Guid = Guid::new(
+    0x4aafd29d,
+    0x68df,
+    0x49ee,
+    [0x8a, 0xa9, 0x34, 0x7d, 0x37, 0x56, 0x65, 0xa7],
+);
+
+/// `WIN_CERT_TYPE_EFI_GUID`
+const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0ef1;
+
+/// The size of an `EFI_SIGNATURE_LIST` header.
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+
+/// The size of an `EFI_TIME`.
+const EFI_TIME_SIZE: usize = 16;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// An EFI GUID in its mixed-endian binary representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    fn from_slice(data: &[u8]) -> Option<Self> {
+        Some(Self(data.get(..16)?.try_into().ok()?))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+/// A single entry of an EFI signature database like `db` or `dbx`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// The type of the signature, e.g. [`EFI_CERT_SHA256_GUID`].
+    pub signature_type: Guid,
+    /// The agent that added the signature, e.g. Microsoft.
+    pub owner: Guid,
+    pub data: Vec<u8>,
+}
+
+impl Signature {
+    /// The Authenticode SHA256 hash, if this is a hash entry.
+    pub fn sha256(&self) -> Option<&[u8]> {
+        (self.signature_type == EFI_CERT_SHA256_GUID && self.data.len() == 32)
+            .then_some(self.data.as_slice())
+    }
+
+    /// A human readable name of the signature type.
+    pub fn type_name(&self) -> String {
+        match self.signature_type {
+            EFI_CERT_SHA256_GUID => "SHA256".to_string(),
+            EFI_CERT_X509_GUID => "X509".to_string(),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Parse a sequence of `EFI_SIGNATURE_LIST`s, as stored in a signature database variable.
+pub fn parse_signature_lists(data: &[u8]) -> Result<Vec<Signature>> {
+    let mut signatures = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let malformed = || anyhow::anyhow!("Malformed EFI signature list");
+        let signature_type = Guid::from_slice(rest).ok_or_else(malformed)?;
+        let list_size = usize::try_from(read_u32(rest, 16).ok_or_else(malformed)?)?;
+        let header_size = usize::try_from(read_u32(rest, 20).ok_or_else(malformed)?)?;
+        let signature_size = usize::try_from(read_u32(rest, 24).ok_or_else(malformed)?)?;
+
+        // Every signature starts with the GUID of its owner.
+        if signature_size <= 16 {
+            bail!("EFI signature list has an invalid signature size {signature_size}");
+        }
+        let entries = rest
+            .get(SIGNATURE_LIST_HEADER_SIZE + header_size..list_size)
+            .ok_or_else(malformed)?;
+        if entries.len() % signature_size != 0 {
+            bail!("EFI signature list contains a truncated signature");
+        }
+
+        signatures.extend(entries.chunks_exact(signature_size).map(|entry| Signature {
+            signature_type,
+            owner: Guid::from_slice(entry).expect("Signatures are larger than a GUID"),
+            data: entry[16..].to_vec(),
+        }));
+        rest = &rest[list_size..];
+    }
+
+    Ok(signatures)
+}
+
+/// Extract the signature lists from an authenticated variable update.
+///
+/// Updates like the UEFI revocation list for `dbx` are prefixed with an
+/// `EFI_VARIABLE_AUTHENTICATION_2` header that contains a timestamp and a PKCS7 signature. Data
+/// without such a header is returned unchanged.
+pub fn strip_authentication_header(update: &[u8]) -> Result<&[u8]> {
+    let certificate = match update.get(EFI_TIME_SIZE..) {
+        Some(certificate) => certificate,
+        None => return Ok(update),
+    };
+    let is_authenticated = read_u16(certificate, 6) == Some(WIN_CERT_TYPE_EFI_GUID)
+        && certificate.get(8..24).and_then(Guid::from_slice) == Some(EFI_CERT_TYPE_PKCS7_GUID);
+    if !is_authenticated {
+        return Ok(update);
+    }
+
+    // The length includes the header of the certificate itself.
+    let length = usize::try_from(read_u32(certificate, 0).context("Truncated certificate")?)?;
+    certificate
+        .get(length..)
+        .context("The authentication header is longer than the update")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MICROSOFT: Guid = Guid::new(
+        0x77fa9abd,
+        0x0359,
+        0x4d32,
+        [0xbd, 0x60, 0x28, 0xf4, 0xe7, 0x8f, 0x78, 0x4b],
+    );
+
+    fn sha256_list(hashes: &[[u8; 32]]) -> Vec<u8> {
+        let signature_size = 16 + 32;
+        let mut list = Vec::new();
+        list.extend_from_slice(&EFI_CERT_SHA256_GUID.0);
+        list.extend_from_slice(
+            &((SIGNATURE_LIST_HEADER_SIZE + hashes.len() * signature_size) as u32).to_le_bytes(),
+        );
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        for hash in hashes {
+            list.extend_from_slice(&MICROSOFT.0);
+            list.extend_from_slice(hash);
+        }
+        list
+    }
+
+    #[test]
+    fn format_guid() {
+        assert_eq!(
+            EFI_CERT_SHA256_GUID.to_string(),
+            "c1c41626-504c-4092-aca9-41f936934328"
+        );
+    }
+
+    #[test]
+    fn parse_multiple_signature_lists() -> Result<()> {
+        let mut data = sha256_list(&[[1; 32], [2; 32]]);
+        data.extend(sha256_list(&[[3; 32]]));
+
+        let signatures = parse_signature_lists(&data)?;
+        assert_eq!(signatures.len(), 3);
+        assert!(signatures.iter().all(|s| s.owner == MICROSOFT));
+        assert_eq!(signatures[2].sha256(), Some([3; 32].as_slice()));
+
+        data.pop();
+        assert!(parse_signature_lists(&data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn strip_authentication_header_of_update() -> Result<()> {
+        let lists = sha256_list(&[[1; 32]]);
+        let pkcs7 = [0x30; 20];
+
+        let mut update = vec![0; EFI_TIME_SIZE];
+        update.extend_from_slice(&(24 + pkcs7.len() as u32).to_le_bytes());
+        update.extend_from_slice(&0x0200u16.to_le_bytes());
+        update.extend_from_slice(&WIN_CERT_TYPE_EFI_GUID.to_le_bytes());
+        update.extend_from_slice(&EFI_CERT_TYPE_PKCS7_GUID.0);
+        update.extend_from_slice(&pkcs7);
+        update.extend_from_slice(&lists);
+
+        assert_eq!(strip_authentication_header(&update)?, lists);
+        assert_eq!(strip_authentication_header(&lists)?, lists);
+        Ok(())
+    }
+}
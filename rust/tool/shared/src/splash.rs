@@ -0,0 +1,101 @@
+use anyhow::{bail, Context, Result};
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Check that a splash image can be displayed by the stub.
+///
+/// The stub only supports uncompressed BMP files with 24 or 32 bits per pixel. Catching
+/// unsupported images here is much friendlier than silently not showing them at boot.
+pub fn check_bmp(data: &[u8]) -> Result<()> {
+    if !data.starts_with(b"BM") {
+        bail!("Splash image is not a BMP file");
+    }
+
+    let header = || -> Option<(u32, u32, i32, i32, u16, u32)> {
+        Some((
+            read_u32(data, 10)?,
+            read_u32(data, 14)?,
+            read_u32(data, 18)? as i32,
+            read_u32(data, 22)? as i32,
+            read_u16(data, 28)?,
+            read_u32(data, 30)?,
+        ))
+    };
+    let (pixel_offset, dib_header_size, width, height, bits_per_pixel, compression) =
+        header().context("Splash image has a truncated BMP header")?;
+
+    if dib_header_size < 40 {
+        bail!("Splash image uses an unsupported BMP header version");
+    }
+    let bytes_per_pixel = match (bits_per_pixel, compression) {
+        (24, 0) => 3,
+        (32, 0 | 3) => 4,
+        _ => bail!(
+            "Splash image must be an uncompressed BMP with 24 or 32 bits per pixel, \
+             found {bits_per_pixel} bits per pixel with compression {compression}"
+        ),
+    };
+    if width <= 0 || height == 0 {
+        bail!("Splash image has invalid dimensions {width}x{height}");
+    }
+
+    let stride = (width.unsigned_abs() as u64 * bytes_per_pixel + 3) & !3;
+    let pixel_data_end = u64::from(pixel_offset) + stride * u64::from(height.unsigned_abs());
+    if pixel_data_end > data.len() as u64 {
+        bail!("Splash image is truncated");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a BMP image with the given dimensions and bits per pixel.
+    fn bmp(width: i32, height: i32, bits_per_pixel: u16) -> Vec<u8> {
+        let stride = ((width as usize * usize::from(bits_per_pixel / 8)) + 3) & !3;
+        let pixel_data_size = stride * height.unsigned_abs() as usize;
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&(54 + pixel_data_size as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bits_per_pixel.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data.resize(54 + pixel_data_size, 0xff);
+        data
+    }
+
+    #[test]
+    fn accept_supported_bmps() {
+        assert!(check_bmp(&bmp(3, 2, 24)).is_ok());
+        assert!(check_bmp(&bmp(3, -2, 32)).is_ok());
+    }
+
+    #[test]
+    fn reject_unsupported_images() {
+        assert!(check_bmp(b"\x89PNG\r\n\x1a\n").is_err());
+        assert!(check_bmp(&bmp(3, 2, 8)).is_err());
+        assert!(check_bmp(&bmp(0, 2, 24)).is_err());
+
+        let mut truncated = bmp(3, 2, 24);
+        truncated.pop();
+        assert!(check_bmp(&truncated).is_err());
+    }
+}
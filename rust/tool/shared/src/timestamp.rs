@@ -0,0 +1,283 @@
+//! Trusted timestamps (RFC 3161) for Authenticode signatures.
+//!
+//! A timestamp authority (TSA) signs the time at which it saw the signature of a PE binary. With
+//! it, the signature can still be verified after the signing certificate expired, because it
+//! proves that the signature was made while the certificate was valid. Like `signtool /tr` and
+//! `osslsigncode -ts`, the timestamp token is added to the signature as the unauthenticated
+//! attribute `szOID_RFC3161_counterSign`. It covers the signature value, not the binary, so the
+//! Authenticode hash and the signature itself stay the same.
+//!
+//! The request is sent with `curl`.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::authenticode;
+use crate::certificate::read_element;
+
+/// `szOID_RFC3161_counterSign`, 1.3.6.1.4.1.311.3.3.1
+const OID_RFC3161_COUNTER_SIGN: &[u8] =
+    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x03, 0x03, 0x01];
+/// SHA-256, 2.16.840.1.101.3.4.2.1
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+/// The explicitly tagged content of a `ContentInfo`.
+const TAG_CONTENT: u8 = 0xa0;
+/// The implicitly tagged `authenticatedAttributes` of a `SignerInfo`.
+const TAG_AUTHENTICATED_ATTRIBUTES: u8 = 0xa0;
+/// The implicitly tagged `unauthenticatedAttributes` of a `SignerInfo`.
+const TAG_UNAUTHENTICATED_ATTRIBUTES: u8 = 0xa1;
+
+/// Encode a DER element.
+fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    let length = contents.len();
+    if length < 0x80 {
+        element.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        element.push(0x80 | (bytes.len() - skip) as u8);
+        element.extend_from_slice(&bytes[skip..]);
+    }
+    element.extend_from_slice(contents);
+    element
+}
+
+/// Split DER elements into their encodings, i.e. including tag and length.
+fn elements(mut data: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (_, _, rest) = read_element(data).context("Malformed DER")?;
+        elements.push(&data[..data.len() - rest.len()]);
+        data = rest;
+    }
+    Ok(elements)
+}
+
+/// Read a DER element with the expected tag and return its contents.
+fn expect<'a>(data: &'a [u8], tag: u8, what: &str) -> Result<&'a [u8]> {
+    match read_element(data) {
+        Some((actual, contents, _)) if actual == tag => Ok(contents),
+        _ => bail!("Malformed {what}"),
+    }
+}
+
+/// Build a `TimeStampReq` for the SHA-256 hash of the signature value.
+fn request(hash: &[u8; 32], nonce: u64) -> Vec<u8> {
+    let algorithm = [encode(TAG_OID, OID_SHA256), encode(TAG_NULL, &[])].concat();
+    let message_imprint = [
+        encode(TAG_SEQUENCE, &algorithm),
+        encode(TAG_OCTET_STRING, hash),
+    ]
+    .concat();
+    // DER integers are minimal, with a leading zero byte only to keep them positive.
+    let nonce = nonce.to_be_bytes();
+    let skip = nonce.iter().take_while(|&&b| b == 0).count().min(7);
+    let nonce = if nonce[skip] & 0x80 != 0 {
+        [&[0][..], &nonce[skip..]].concat()
+    } else {
+        nonce[skip..].to_vec()
+    };
+    encode(
+        TAG_SEQUENCE,
+        &[
+            encode(TAG_INTEGER, &[1]),
+            encode(TAG_SEQUENCE, &message_imprint),
+            encode(TAG_INTEGER, &nonce),
+            // Ask for the certificate of the TSA, so that the token can be verified on its own.
+            encode(TAG_BOOLEAN, &[0xff]),
+        ]
+        .concat(),
+    )
+}
+
+/// Extract the timestamp token, a `ContentInfo`, from a `TimeStampResp`.
+fn token(response: &[u8], hash: &[u8; 32]) -> Result<Vec<u8>> {
+    let response = elements(expect(response, TAG_SEQUENCE, "timestamp response")?)?;
+    let [status, token, ..] = response[..] else {
+        bail!("The timestamp authority did not return a timestamp");
+    };
+    let status = expect(status, TAG_SEQUENCE, "timestamp status")?;
+    let status = expect(status, TAG_INTEGER, "timestamp status")?;
+    // granted (0) or grantedWithMods (1)
+    if status != [0] && status != [1] {
+        bail!("The timestamp authority rejected the request with status {status:?}");
+    }
+    // The token is a signed TSTInfo that contains the message imprint of the request.
+    if !token.windows(hash.len()).any(|window| window == hash) {
+        bail!("The timestamp does not cover the signature");
+    }
+    Ok(token.to_vec())
+}
+
+/// Add the timestamp token to the first `SignerInfo` of a PKCS#7 `ContentInfo`.
+///
+/// `token` is called with the signature value of the `SignerInfo` to request the token.
+fn add_token(pkcs7: &[u8], token: impl FnOnce(&[u8]) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let content_info = elements(expect(pkcs7, TAG_SEQUENCE, "signature")?)?;
+    let [content_type, content] = content_info[..] else {
+        bail!("Malformed signature");
+    };
+    let signed_data = expect(content, TAG_CONTENT, "signature")?;
+    let mut signed_data = elements(expect(signed_data, TAG_SEQUENCE, "signed data")?)?;
+    // The signer infos are the last field, after the optional certificates and CRLs.
+    let signer_infos = signed_data.pop().context("Malformed signed data")?;
+    let mut signer_infos = elements(expect(signer_infos, TAG_SET, "signer infos")?)?;
+    if signer_infos.is_empty() {
+        bail!("The signature has no signer");
+    }
+
+    let mut signer_info = elements(expect(signer_infos[0], TAG_SEQUENCE, "signer info")?)?;
+    if signer_info
+        .last()
+        .is_some_and(|field| field[0] == TAG_UNAUTHENTICATED_ATTRIBUTES)
+    {
+        bail!("The signature is already countersigned");
+    }
+    // version, issuerAndSerialNumber, digestAlgorithm, authenticatedAttributes,
+    // digestEncryptionAlgorithm, encryptedDigest
+    if signer_info.len() != 6 || signer_info[3][0] != TAG_AUTHENTICATED_ATTRIBUTES {
+        bail!("Unsupported signer info");
+    }
+    let signature_value = expect(signer_info[5], TAG_OCTET_STRING, "signature value")?;
+
+    let token = token(signature_value)?;
+    let attribute = encode(
+        TAG_SEQUENCE,
+        &[
+            encode(TAG_OID, OID_RFC3161_COUNTER_SIGN),
+            encode(TAG_SET, &token),
+        ]
+        .concat(),
+    );
+    let unauthenticated_attributes = encode(TAG_UNAUTHENTICATED_ATTRIBUTES, &attribute);
+    signer_info.push(&unauthenticated_attributes);
+
+    let signer_info = encode(TAG_SEQUENCE, &signer_info.concat());
+    signer_infos[0] = &signer_info;
+    let signer_infos = encode(TAG_SET, &signer_infos.concat());
+    signed_data.push(&signer_infos);
+    let signed_data = encode(TAG_SEQUENCE, &signed_data.concat());
+    Ok(encode(
+        TAG_SEQUENCE,
+        &[content_type, &encode(TAG_CONTENT, &signed_data)].concat(),
+    ))
+}
+
+/// Send a `TimeStampReq` to the timestamp authority at `url` and return its response.
+fn send(url: &str, request: &[u8]) -> Result<Vec<u8>> {
+    let mut curl = Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "60",
+            "--header",
+            "Content-Type: application/timestamp-query",
+            "--data-binary",
+            "@-",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run curl. Most likely, the binary is not on PATH.")?;
+    curl.stdin
+        .take()
+        .context("curl has no stdin")?
+        .write_all(request)
+        .context("Failed to pass the timestamp request to curl")?;
+    let output = curl.wait_with_output().context("Failed to wait for curl")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to request a timestamp from {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Add a timestamp from the timestamp authority at `url` to the signature of a signed PE binary.
+pub fn timestamp_pe(image: &[u8], url: &str) -> Result<Vec<u8>> {
+    let pkcs7 = add_token(authenticode::signature(image)?, |signature_value| {
+        let hash: [u8; 32] = Sha256::digest(signature_value).into();
+        let response = send(url, &request(&hash, fastrand::u64(..)))?;
+        token(&response, &hash)
+    })
+    .with_context(|| format!("Failed to timestamp the signature with {url}"))?;
+    authenticode::replace_signature(image, &pkcs7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_lengths() {
+        assert_eq!(encode(TAG_NULL, &[]), [0x05, 0x00]);
+        assert_eq!(&encode(TAG_OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+        assert_eq!(
+            &encode(TAG_OCTET_STRING, &[0; 300])[..4],
+            [0x04, 0x82, 1, 44]
+        );
+        let request = request(&[0xab; 32], 7);
+        assert_eq!(
+            read_element(&request).map(|(_, _, rest)| rest.len()),
+            Some(0)
+        );
+        assert!(request.ends_with(&[0x02, 0x01, 0x07, 0x01, 0x01, 0xff]));
+        assert!(
+            super::request(&[0; 32], 0x80).ends_with(&[0x02, 0x02, 0x00, 0x80, 0x01, 0x01, 0xff])
+        );
+    }
+
+    #[test]
+    fn add_token_to_signer_info() -> Result<()> {
+        let field = |tag, contents: &[u8]| encode(tag, contents);
+        let signer_info = encode(
+            TAG_SEQUENCE,
+            &[
+                field(TAG_INTEGER, &[1]),
+                field(TAG_SEQUENCE, &[]),
+                field(TAG_SEQUENCE, &[]),
+                field(TAG_AUTHENTICATED_ATTRIBUTES, &[]),
+                field(TAG_SEQUENCE, &[]),
+                field(TAG_OCTET_STRING, b"signature"),
+            ]
+            .concat(),
+        );
+        let signed_data = encode(
+            TAG_SEQUENCE,
+            &[field(TAG_INTEGER, &[1]), field(TAG_SET, &signer_info)].concat(),
+        );
+        let pkcs7 = encode(
+            TAG_SEQUENCE,
+            &[field(TAG_OID, &[0x2a]), encode(TAG_CONTENT, &signed_data)].concat(),
+        );
+
+        let timestamped = add_token(&pkcs7, |signature_value| {
+            assert_eq!(signature_value, b"signature");
+            Ok(field(TAG_SEQUENCE, b"token"))
+        })?;
+        assert!(timestamped.len() > pkcs7.len());
+        assert!(timestamped
+            .windows(OID_RFC3161_COUNTER_SIGN.len())
+            .any(|window| window == OID_RFC3161_COUNTER_SIGN));
+        // A signature is only timestamped once.
+        assert!(add_token(&timestamped, |_| unreachable!()).is_err());
+        Ok(())
+    }
+}
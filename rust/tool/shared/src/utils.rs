@@ -9,6 +9,8 @@ use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
+use crate::mmap::MappedFile;
+
 /// The number of random alphanumeric characters in the tempfiles.
 const TEMPFILE_RANDOM_LENGTH: usize = 32;
 
@@ -67,11 +69,25 @@ pub fn tmpname() -> OsString {
     buf
 }
 
-type Hash = sha2::digest::Output<Sha256>;
+pub type Hash = sha2::digest::Output<Sha256>;
 
 /// Compute the SHA 256 hash of a file.
 pub fn file_hash(file: &Path) -> Result<Hash> {
-    Ok(Sha256::digest(fs::read(file).with_context(|| {
-        format!("Failed to read file to hash: {file:?}")
-    })?))
+    Ok(Sha256::digest(MappedFile::open(file).with_context(
+        || format!("Failed to read file to hash: {file:?}"),
+    )?))
+}
+
+/// Overwrite a buffer that contains secrets, e.g. initrd secrets or keys, before it is freed.
+pub fn zeroize(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        // SAFETY: `byte` is a valid, aligned reference.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Prevent core dumps of this process, which could contain initrd secrets or keys.
+pub fn disable_core_dumps() -> Result<()> {
+    nix::sys::prctl::set_dumpable(false).context("Failed to disable core dumps")
 }
@@ -0,0 +1,138 @@
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+const ZBOOT_MAGIC: &[u8] = b"zimg";
+const COMPRESSION_TYPE_OFFSET: usize = 24;
+const COMPRESSION_TYPE_SIZE: usize = 32;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The compression types of zboot kernels that the stub decompresses itself.
+///
+/// Kernels with other compression types are started as they are, and decompress themselves.
+pub const STUB_COMPRESSION_TYPES: &[&str] = &["gzip"];
+
+/// The header of an EFI zboot kernel, i.e. a `vmlinuz.efi` that contains the compressed kernel
+/// image and the code to decompress it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZbootHeader {
+    /// Where the compressed kernel image is in the kernel file.
+    pub payload: Range<usize>,
+    /// How the kernel image is compressed, e.g. `gzip` or `zstd`.
+    pub compression_type: String,
+}
+
+impl ZbootHeader {
+    /// Parse the header of a zboot kernel. Returns `None` for other kernels.
+    pub fn parse(kernel: &[u8]) -> Result<Option<Self>> {
+        if kernel.get(..2) != Some(b"MZ") || kernel.get(4..8) != Some(ZBOOT_MAGIC) {
+            return Ok(None);
+        }
+
+        let field = |offset: usize| -> Result<usize> {
+            let bytes = kernel
+                .get(offset..offset + 4)
+                .context("zboot header is truncated")?;
+            Ok(u32::from_le_bytes(bytes.try_into()?).try_into()?)
+        };
+        let start = field(8)?;
+        let end = start.saturating_add(field(12)?);
+        if end > kernel.len() {
+            bail!("zboot payload {start}..{end} is outside of the kernel");
+        }
+        let payload = start..end;
+
+        let compression_type = kernel
+            .get(COMPRESSION_TYPE_OFFSET..COMPRESSION_TYPE_OFFSET + COMPRESSION_TYPE_SIZE)
+            .and_then(|field| field.split(|&byte| byte == 0).next())
+            .context("zboot header is truncated")?;
+        let compression_type = std::str::from_utf8(compression_type)
+            .context("zboot compression type is not valid UTF-8")?
+            .to_string();
+
+        Ok(Some(Self {
+            payload,
+            compression_type,
+        }))
+    }
+}
+
+/// Check that a kernel can be booted by the stub.
+///
+/// The stub verifies the hash of the whole kernel file, which for a zboot kernel covers the
+/// compressed kernel image, before it decompresses it. This catches zboot kernels whose header
+/// does not match their payload before an entry is installed that cannot boot.
+pub fn check_kernel(kernel: &Path) -> Result<()> {
+    let data = fs::read(kernel).with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+    let Some(header) = ZbootHeader::parse(&data)? else {
+        return Ok(());
+    };
+
+    if !STUB_COMPRESSION_TYPES.contains(&header.compression_type.as_str()) {
+        log::info!(
+            "{kernel:?} is a {} zboot kernel, which decompresses itself.",
+            header.compression_type
+        );
+    } else if !data[header.payload.clone()].starts_with(GZIP_MAGIC) {
+        bail!("The payload of the zboot kernel {kernel:?} is not gzip-compressed");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zboot_kernel(compression_type: &str, payload: &[u8]) -> Vec<u8> {
+        let mut kernel = vec![0; 64];
+        kernel[..2].copy_from_slice(b"MZ");
+        kernel[4..8].copy_from_slice(ZBOOT_MAGIC);
+        kernel[8..12].copy_from_slice(&64u32.to_le_bytes());
+        kernel[12..16].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        kernel[24..24 + compression_type.len()].copy_from_slice(compression_type.as_bytes());
+        kernel.extend_from_slice(payload);
+        kernel
+    }
+
+    #[test]
+    fn parse_zboot_header() -> Result<()> {
+        let kernel = zboot_kernel("zstd", b"payload");
+        assert_eq!(
+            ZbootHeader::parse(&kernel)?,
+            Some(ZbootHeader {
+                payload: 64..71,
+                compression_type: "zstd".to_string(),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn other_kernels_have_no_header() -> Result<()> {
+        assert_eq!(ZbootHeader::parse(b"MZ\0\0PE\0\0")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_payload_outside_of_kernel() {
+        let mut kernel = zboot_kernel("gzip", b"payload");
+        kernel.truncate(70);
+        assert!(ZbootHeader::parse(&kernel).is_err());
+    }
+
+    #[test]
+    fn reject_gzip_kernel_with_other_payload() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let kernel = tempdir.path().join("vmlinuz.efi");
+
+        fs::write(&kernel, zboot_kernel("gzip", b"\x28\xb5\x2f\xfd"))?;
+        assert!(check_kernel(&kernel).is_err());
+
+        fs::write(&kernel, zboot_kernel("gzip", b"\x1f\x8b\x08\x00"))?;
+        check_kernel(&kernel)
+    }
+}
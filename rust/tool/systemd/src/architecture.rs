@@ -5,10 +5,15 @@ use lanzaboote_tool::architecture::Architecture;
 /// Systemd-specific architecture helpers
 pub trait SystemdArchitectureExt {
     fn systemd_filename(&self) -> PathBuf;
+    fn systemd_addon_stub_filename(&self) -> PathBuf;
 }
 
 impl SystemdArchitectureExt for Architecture {
     fn systemd_filename(&self) -> PathBuf {
         format!("systemd-boot{}.efi", self.efi_representation()).into()
     }
+
+    fn systemd_addon_stub_filename(&self) -> PathBuf {
+        format!("addon{}.efi.stub", self.efi_representation()).into()
+    }
 }
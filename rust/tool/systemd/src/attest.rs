@@ -0,0 +1,154 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use lanzaboote_tool::authenticode::authenticode_sha256;
+use lanzaboote_tool::pcr::{
+    expected_pcr11, expected_pcr11_phases, measured_sections, TPM_PCR_INDEX_KERNEL_IMAGE,
+};
+use lanzaboote_tool::revocation::format_hash;
+
+use crate::migrate::{generation_from_entry_name, is_lanzaboote_entry};
+
+/// The PCR the firmware measures the Authenticode hashes of started images into.
+const TPM_PCR_INDEX_BOOT_APPLICATIONS: u32 = 4;
+
+/// Compute the reference values for remote attestation of a stub.
+///
+/// The stub's own measurements into PCR 11 are fully determined by the stub, so both the events
+/// and the final value of PCR 11 are included. PCR 4 also contains measurements of the firmware
+/// and the boot loader, so only the event of the stub itself is included. The kernel is not
+/// started by the firmware, so it is not measured into PCR 4 at all.
+fn reference_values(entry: &str, image: &[u8]) -> Result<Value> {
+    let authenticode = authenticode_sha256(image).context("Failed to compute Authenticode hash")?;
+    let sections = measured_sections(image)?
+        .into_iter()
+        .map(|(name, digest)| {
+            json!({
+                "type": "EV_IPL",
+                "description": name,
+                "sha256": format!("{digest:x}"),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // The values after each boot phase of `lzbt pcrphase`, e.g. to seal secrets to the initrd.
+    let phases = expected_pcr11_phases(image)?
+        .into_iter()
+        .map(|(phase, pcr)| (phase.to_string(), json!(format!("{pcr:x}"))))
+        .collect::<serde_json::Map<_, _>>();
+
+    Ok(json!({
+        "entry": entry,
+        "generation": generation_from_entry_name(entry),
+        "pcrs": {
+            TPM_PCR_INDEX_BOOT_APPLICATIONS.to_string(): {
+                "events": [{
+                    "type": "EV_EFI_BOOT_SERVICES_APPLICATION",
+                    "sha256": format_hash(&authenticode),
+                }],
+            },
+            TPM_PCR_INDEX_KERNEL_IMAGE.to_string(): {
+                "events": sections,
+                "sha256": format!("{:x}", expected_pcr11(image)?),
+                "phases": phases,
+            },
+        },
+    }))
+}
+
+/// Compute the reference values of all entries installed in the `EFI/Linux` directory of the ESP.
+pub fn attestation_reference(esp: &Path) -> Result<Value> {
+    let linux = esp.join("EFI/Linux");
+    let mut entries = fs::read_dir(&linux)
+        .with_context(|| format!("Failed to read {linux:?}"))?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.retain(|entry| is_lanzaboote_entry(entry));
+    entries.sort();
+
+    let references = entries
+        .iter()
+        .map(|entry| {
+            let path = linux.join(entry);
+            let image = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            reference_values(entry, &image).with_context(|| format!("In entry {entry}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Value::Array(references))
+}
+
+/// Convert reference values into a Keylime TPM policy.
+///
+/// The policy accepts the PCR 11 values of all entries, so that the machine can be attested no
+/// matter which entry it booted.
+pub fn keylime_tpm_policy(references: &Value) -> Value {
+    let pcr = TPM_PCR_INDEX_KERNEL_IMAGE.to_string();
+    let mut values = references
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|reference| reference["pcrs"][&pcr]["sha256"].as_str())
+        .collect::<Vec<_>>();
+    values.sort_unstable();
+    values.dedup();
+
+    json!({
+        pcr: values,
+        "mask": format!("{:#x}", 1u32 << TPM_PCR_INDEX_KERNEL_IMAGE),
+    })
+}
+
+/// Pass the reference values of all installed entries to a hook, e.g. to update the policy of a
+/// Keylime verifier.
+///
+/// The hook is called with the paths of the reference values and of the Keylime TPM policy.
+pub fn run_hook(hook: &Path, esp: &Path) -> Result<()> {
+    let references = attestation_reference(esp)?;
+    let tempdir = TempDir::new().context("Failed to create temporary directory")?;
+    let references_path = tempdir.path().join("reference-values.json");
+    let policy_path = tempdir.path().join("keylime-tpm-policy.json");
+    fs::write(&references_path, format!("{references:#}"))
+        .context("Failed to write reference values")?;
+    fs::write(&policy_path, keylime_tpm_policy(&references).to_string())
+        .context("Failed to write Keylime TPM policy")?;
+
+    let status = Command::new(hook)
+        .arg(&references_path)
+        .arg(&policy_path)
+        .status()
+        .with_context(|| format!("Failed to run attestation hook {hook:?}"))?;
+    if !status.success() {
+        bail!("Attestation hook {hook:?} failed with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_entries_that_are_not_pe_binaries() {
+        assert!(reference_values("nixos-generation-1-a.efi", b"not a PE binary").is_err());
+    }
+
+    #[test]
+    fn keylime_policy_accepts_all_entries() {
+        let references = json!([
+            { "entry": "nixos-generation-1-a.efi", "pcrs": { "11": { "sha256": "bb" } } },
+            { "entry": "nixos-generation-2-b.efi", "pcrs": { "11": { "sha256": "aa" } } },
+            { "entry": "nixos-generation-2-recovery-c.efi", "pcrs": { "11": { "sha256": "bb" } } },
+        ]);
+
+        assert_eq!(
+            keylime_tpm_policy(&references),
+            json!({ "11": ["aa", "bb"], "mask": "0x800" })
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Warnings for systems that dual-boot Windows with BitLocker.
+//!
+//! The firmware measures PK, KEK, db and dbx into PCR 7, which BitLocker seals its key against by
+//! default. Enrolling own keys or updating dbx therefore makes Windows ask for the BitLocker
+//! recovery key on its next boot. Many users do not know where their recovery key is, so lzbt
+//! warns before these changes if the Windows Boot Manager is on the ESP.
+
+use std::path::{Path, PathBuf};
+
+use lanzaboote_tool::esp_fs::EspFilesystem;
+
+/// The exit code of `lzbt check-bitlocker` if the Windows Boot Manager is on the ESP.
+pub const EXIT_WINDOWS_FOUND: i32 = 2;
+
+/// The Windows Boot Manager, relative to the ESP.
+const WINDOWS_BOOT_MANAGER: &str = "EFI/Microsoft/Boot/bootmgfw.efi";
+
+/// The steps that keep BitLocker from asking for the recovery key, or that recover from it.
+pub const RECOVERY_GUIDANCE: &str = "\
+Before changing the Secure Boot keys, boot Windows and:
+
+  1. Make sure that you have the BitLocker recovery key. `manage-bde -protectors -get C:` in an
+     administrator prompt shows it. If Windows is signed in with a Microsoft account, it is
+     usually also stored at https://aka.ms/myrecoverykey.
+  2. Suspend BitLocker until you resume it: `manage-bde -protectors -disable C: -RebootCount 0`.
+  3. Enroll the keys and boot Windows again. Then resume BitLocker with
+     `manage-bde -protectors -enable C:`, which seals the key to the new PCR 7.
+
+If Windows already asks for the recovery key, enter it. Then suspend and resume BitLocker as in
+step 2 and 3, so that it does not ask again on the next boot.
+
+Windows only boots with Secure Boot enabled if its boot manager is trusted, i.e. if Microsoft's
+certificates are enrolled along with your keys (`sbctl enroll-keys --microsoft`) or if it is
+signed with your key (`lzbt install --other-os sign`).";
+
+/// The Windows Boot Manager on the ESP, if any.
+pub fn windows_boot_manager(esp_fs: &impl EspFilesystem, esp: &Path) -> Option<PathBuf> {
+    let path = esp.join(WINDOWS_BOOT_MANAGER);
+    esp_fs.exists(&path).then_some(path)
+}
+
+/// Warn that `change`, e.g. "Enrolling your own keys", trips BitLocker if Windows is on the ESP.
+///
+/// Returns whether Windows was found.
+pub fn warn_if_windows(esp_fs: &impl EspFilesystem, esp: &Path, change: &str) -> bool {
+    let Some(windows) = windows_boot_manager(esp_fs, esp) else {
+        return false;
+    };
+    log::warn!(
+        "Found the Windows Boot Manager at {windows:?}. {change} changes PCR 7, so BitLocker will \
+         ask for its recovery key on the next boot of Windows unless it is suspended first. Run \
+         `lzbt check-bitlocker --guidance` for the necessary steps."
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    #[test]
+    fn warn_only_with_windows() -> anyhow::Result<()> {
+        let esp = Path::new("/boot");
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(&esp.join("EFI/nixos/kernel.efi"), b"kernel")?;
+        assert!(!warn_if_windows(&esp_fs, esp, "Enrolling your own keys"));
+
+        esp_fs.write(&esp.join(WINDOWS_BOOT_MANAGER), b"MZ")?;
+        assert!(warn_if_windows(&esp_fs, esp, "Enrolling your own keys"));
+        assert_eq!(
+            windows_boot_manager(&esp_fs, esp),
+            Some(esp.join("EFI/Microsoft/Boot/bootmgfw.efi"))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Check that the kernels and initrds on the ESP are intact.
+//!
+//! The ESP is not encrypted, so its files can rot or be modified by anyone with physical access.
+//! The stub refuses to boot files whose hash does not match, but this check finds them from the
+//! running system before the next boot does.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use base32ct::{Base32Unpadded, Encoding};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::pe::read_section_data;
+use lanzaboote_tool::revocation::format_hash;
+use lanzaboote_tool::utils::file_hash;
+
+use crate::diff::find_stub;
+use crate::inspect::{esp_path, HashedFile};
+use crate::migrate::is_lanzaboote_entry;
+use crate::namespace::is_machine_id;
+
+/// The prefixes of the content-addressed kernels and initrds in `EFI/nixos`.
+const CONTENT_ADDRESSED_PREFIXES: [&str; 2] = ["kernel-", "initrd-"];
+
+/// The embedded path and hash sections of the files that a stub loads.
+const HASHED_FILES: [(&str, &str, &str); 2] = [
+    ("kernel", ".linux", ".linuxh"),
+    ("initrd", ".initrd", ".initrdh"),
+];
+
+/// Compare the kernels and initrds on the ESP with the hashes embedded in the installed stubs.
+///
+/// Returns a description of every problem.
+pub fn check_entries(esp: &Path) -> Result<Vec<String>> {
+    let linux = esp.join("EFI/Linux");
+    if !linux.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut problems = Vec::new();
+    for entry in fs::read_dir(&linux).with_context(|| format!("Failed to read {linux:?}"))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !is_lanzaboote_entry(&name) {
+            continue;
+        }
+        let image = fs::read(linux.join(&name))
+            .with_context(|| format!("Failed to read the stub {name}"))?;
+        // The kernel and initrd of these stubs are on another partition, which is not at hand.
+        if read_section_data(&image, ".partsel").is_some() {
+            continue;
+        }
+        for (what, path, hash) in HASHED_FILES {
+            let Some(path) = section_text(&image, path) else {
+                continue;
+            };
+            let hash = read_section_data(&image, hash).map(format_hash);
+            if let Some(problem) = HashedFile::read(esp, path, hash).discrepancy(what) {
+                problems.push(format!("{name}: {problem}"));
+            }
+        }
+    }
+    problems.sort();
+    Ok(problems)
+}
+
+/// Compare the content-addressed kernels and initrds in `EFI/nixos` with the hash in their name.
+///
+/// This also finds damaged files that no installed stub references anymore, including those of
+/// all namespaces.
+pub fn check_content_addressed(esp: &Path) -> Result<Vec<String>> {
+    let nixos = esp.join("EFI/nixos");
+    if !nixos.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut directories = vec![nixos];
+    let mut problems = Vec::new();
+    while let Some(directory) = directories.pop() {
+        for entry in
+            fs::read_dir(&directory).with_context(|| format!("Failed to read {directory:?}"))?
+        {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if path.is_dir() && is_machine_id(&name) {
+                directories.push(path);
+                continue;
+            }
+            if !CONTENT_ADDRESSED_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+            {
+                continue;
+            }
+            let Some((_, expected)) = name
+                .strip_suffix(".efi")
+                .and_then(|name| name.rsplit_once('-'))
+            else {
+                continue;
+            };
+            let data = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            if Base32Unpadded::encode_string(&Sha256::digest(data)) != expected {
+                problems.push(format!(
+                    "{}: The contents do not match the hash in the name, so the file is damaged \
+                     or was modified.",
+                    path.display()
+                ));
+            }
+        }
+    }
+    problems.sort();
+    Ok(problems)
+}
+
+/// Compare the kernels and initrds of generations with their sources in the Nix store.
+///
+/// This finds stubs that were assembled from other files than the generation's, in addition to
+/// damaged copies on the ESP. Initrds with secrets or encryption are assembled during the
+/// installation, so only their embedded hash can be checked. Generations that are not installed
+/// are skipped.
+pub fn check_store(esp: &Path, links: &[PathBuf]) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for link in links {
+        let generation = GenerationLink::from_path(link)
+            .and_then(|link| Generation::from_link(&link))
+            .with_context(|| format!("Failed to read the generation {link:?}"))?;
+        let Ok(stub) = find_stub(esp, generation.version) else {
+            log::debug!("Generation {} is not installed.", generation.version);
+            continue;
+        };
+        let image = fs::read(&stub).with_context(|| format!("Failed to read the stub {stub:?}"))?;
+        let entry = stub
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let assembled_initrd = read_section_data(&image, ".initrdenc").is_some()
+            || bootspec.initrd_secrets.is_some()
+            || generation
+                .spec
+                .lanzaboote_extension
+                .initrd_secrets
+                .as_ref()
+                .is_some_and(|secrets| !secrets.is_empty());
+        let sources = [
+            Some(&bootspec.kernel),
+            bootspec.initrd.as_ref().filter(|_| !assembled_initrd),
+        ];
+
+        for ((what, path, hash), source) in HASHED_FILES.into_iter().zip(sources) {
+            let (Some(source), Some(path)) = (source, section_text(&image, path)) else {
+                continue;
+            };
+            let source_hash = format_hash(
+                &file_hash(source)
+                    .with_context(|| format!("Failed to hash the {what} {source:?}"))?,
+            );
+            if read_section_data(&image, hash).map(format_hash).as_ref() != Some(&source_hash) {
+                problems.push(format!(
+                    "{entry}: The stub was not assembled with the {what} {} of generation {}.",
+                    source.display(),
+                    generation.version
+                ));
+            }
+            let copy = esp_path(esp, &path);
+            let copy_hash = fs::read(&copy)
+                .ok()
+                .map(|data| format_hash(&Sha256::digest(data)));
+            if copy_hash.is_some_and(|copy_hash| copy_hash != source_hash) {
+                problems.push(format!(
+                    "{}: The {what} differs from its source {} in the Nix store.",
+                    copy.display(),
+                    source.display()
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// The text of a section, e.g. an embedded path.
+pub fn section_text(image: &[u8], name: &str) -> Option<String> {
+    read_section_data(image, name).map(|data| String::from_utf8_lossy(data).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_damaged_content_addressed_files() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let nixos = esp.path().join("EFI/nixos");
+        fs::create_dir_all(&nixos)?;
+        let name = |label: &str, contents: &[u8]| {
+            format!(
+                "{label}-{}.efi",
+                Base32Unpadded::encode_string(&Sha256::digest(contents))
+            )
+        };
+        fs::write(nixos.join(name("kernel-6.6.1", b"kernel")), b"kernel")?;
+        fs::write(nixos.join(name("initrd-6.6.1", b"initrd")), b"rotten")?;
+        // Signed copies are named after their source, not their contents.
+        fs::write(nixos.join(name("chainload-windows", b"a")), b"b")?;
+
+        assert_eq!(
+            check_content_addressed(esp.path())?,
+            [format!(
+                "{}: The contents do not match the hash in the name, so the file is damaged or \
+                 was modified.",
+                nixos.join(name("initrd-6.6.1", b"initrd")).display()
+            )]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+
+use lanzaboote_tool::certificate::subject_name;
+use lanzaboote_tool::efivars::{
+    read_variable, EFI_GLOBAL_VARIABLE_GUID, EFI_IMAGE_SECURITY_DATABASE_GUID,
+};
+use lanzaboote_tool::revocation::format_hash;
+use lanzaboote_tool::signature_list::{parse_signature_lists, Guid, Signature, EFI_CERT_X509_GUID};
+
+/// The owner that Microsoft uses for its entries in the signature databases.
+const MICROSOFT_OWNER: Guid = Guid::new(
+    0x77fa9abd,
+    0x0359,
+    0x4d32,
+    [0xbd, 0x60, 0x28, 0xf4, 0xe7, 0x8f, 0x78, 0x4b],
+);
+
+/// What a certificate of Microsoft is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MicrosoftCa {
+    /// Signs updates of db and dbx.
+    Kek,
+    /// Signs the Windows Boot Manager.
+    Windows,
+    /// Signs third-party boot loaders, e.g. shim, and option ROMs.
+    ThirdParty,
+}
+
+/// Microsoft's certificates by subject, and whether they are the ones that expire in 2026.
+const MICROSOFT_CERTIFICATES: &[(&str, MicrosoftCa, bool)] = &[
+    ("Microsoft Corporation KEK CA 2011", MicrosoftCa::Kek, true),
+    (
+        "Microsoft Corporation KEK 2K CA 2023",
+        MicrosoftCa::Kek,
+        false,
+    ),
+    (
+        "Microsoft Windows Production PCA 2011",
+        MicrosoftCa::Windows,
+        true,
+    ),
+    ("Windows UEFI CA 2023", MicrosoftCa::Windows, false),
+    (
+        "Microsoft Corporation UEFI CA 2011",
+        MicrosoftCa::ThirdParty,
+        true,
+    ),
+    ("Microsoft UEFI CA 2023", MicrosoftCa::ThirdParty, false),
+    (
+        "Microsoft Option ROM UEFI CA 2023",
+        MicrosoftCa::ThirdParty,
+        false,
+    ),
+];
+
+/// Subjects of test keys that firmware vendors ship by accident, see PKfail (CVE-2024-8105).
+const TEST_KEY_MARKERS: &[&str] = &["DO NOT TRUST", "DO NOT SHIP"];
+
+/// An entry of one of the signature databases.
+struct Entry {
+    signature: Signature,
+    subject: Option<String>,
+}
+
+impl Entry {
+    fn new(signature: Signature) -> Self {
+        let subject = (signature.signature_type == EFI_CERT_X509_GUID)
+            .then(|| subject_name(&signature.data))
+            .flatten();
+        Self { signature, subject }
+    }
+
+    fn microsoft_ca(&self) -> Option<(MicrosoftCa, bool)> {
+        let subject = self.subject.as_deref()?;
+        MICROSOFT_CERTIFICATES
+            .iter()
+            .find(|(name, _, _)| *name == subject)
+            .map(|&(_, ca, expiring)| (ca, expiring))
+    }
+
+    fn is_certificate(&self, certificate: &[u8]) -> bool {
+        self.signature.signature_type == EFI_CERT_X509_GUID && self.signature.data == certificate
+    }
+
+    fn describe(&self, certificate: Option<&[u8]>) -> String {
+        let name = match (&self.subject, self.signature.sha256()) {
+            (Some(subject), _) => subject.clone(),
+            (None, Some(hash)) => format_hash(hash),
+            (None, None) => format!("{} bytes", self.signature.data.len()),
+        };
+        let note = if certificate.is_some_and(|c| self.is_certificate(c)) {
+            "your signing certificate".to_string()
+        } else if self.signature.owner == MICROSOFT_OWNER {
+            "Microsoft".to_string()
+        } else {
+            format!("owner {}", self.signature.owner)
+        };
+        format!("{} {name} ({note})", self.signature.type_name())
+    }
+}
+
+/// The Secure Boot key databases of the firmware.
+pub struct Databases {
+    pk: Vec<Entry>,
+    kek: Vec<Entry>,
+    db: Vec<Entry>,
+}
+
+impl Databases {
+    /// Read PK, KEK and db from the EFI variables.
+    pub fn read() -> Result<Self> {
+        let read = |name, vendor| -> Result<Vec<Entry>> {
+            let Some(data) = read_variable(name, vendor)? else {
+                return Ok(Vec::new());
+            };
+            Ok(parse_signature_lists(&data)
+                .with_context(|| format!("Failed to parse the firmware's {name}"))?
+                .into_iter()
+                .map(Entry::new)
+                .collect())
+        };
+
+        Ok(Self {
+            pk: read("PK", &EFI_GLOBAL_VARIABLE_GUID)?,
+            kek: read("KEK", &EFI_GLOBAL_VARIABLE_GUID)?,
+            db: read("db", &EFI_IMAGE_SECURITY_DATABASE_GUID)?,
+        })
+    }
+
+    fn variables(&self) -> [(&'static str, &[Entry]); 3] {
+        [("PK", &self.pk), ("KEK", &self.kek), ("db", &self.db)]
+    }
+
+    fn has_microsoft_ca(&self, kind: MicrosoftCa) -> bool {
+        let entries = match kind {
+            MicrosoftCa::Kek => &self.kek,
+            MicrosoftCa::Windows | MicrosoftCa::ThirdParty => &self.db,
+        };
+        entries
+            .iter()
+            .any(|e| e.microsoft_ca().is_some_and(|(ca, _)| ca == kind))
+    }
+
+    /// Find known-problematic configurations.
+    fn problems(&self, certificate: Option<&[u8]>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.pk.is_empty() {
+            problems.push(
+                "No PK is enrolled, so the firmware is in setup mode and does not enforce Secure Boot."
+                    .to_string(),
+            );
+        }
+        for subject in self.pk.iter().filter_map(|e| e.subject.as_deref()) {
+            if TEST_KEY_MARKERS
+                .iter()
+                .any(|marker| subject.contains(marker))
+            {
+                problems.push(format!(
+                    "The PK \"{subject}\" is a test key of the firmware vendor, whose private key may be public (PKfail, CVE-2024-8105). Enroll your own PK."
+                ));
+            }
+        }
+
+        if let Some(certificate) = certificate {
+            if !self.db.iter().any(|e| e.is_certificate(certificate)) {
+                problems.push(
+                    "Your signing certificate is not in db. With Secure Boot enabled, the firmware refuses to start Lanzaboote's entries."
+                        .to_string(),
+                );
+            }
+        }
+
+        if !self.pk.is_empty() && !self.has_microsoft_ca(MicrosoftCa::ThirdParty) {
+            problems.push(
+                "Microsoft's UEFI CA is not in db. Option ROMs, e.g. of discrete GPUs, are usually signed with it. The firmware may not load them with Secure Boot enabled, which can leave the machine without display output."
+                    .to_string(),
+            );
+        }
+        if !self.pk.is_empty() && !self.has_microsoft_ca(MicrosoftCa::Kek) {
+            problems.push(
+                "Microsoft's KEK is not enrolled, so dbx updates from Microsoft cannot be applied unless you sign them with your own KEK."
+                    .to_string(),
+            );
+        }
+
+        for (variable, entries) in self.variables() {
+            for entry in entries {
+                let Some((kind, true)) = entry.microsoft_ca() else {
+                    continue;
+                };
+                let replaced = entries
+                    .iter()
+                    .any(|e| e.microsoft_ca() == Some((kind, false)));
+                if !replaced {
+                    problems.push(format!(
+                        "\"{}\" in {variable} expires in 2026, but its replacement from 2023 is not enrolled.",
+                        entry.subject.as_deref().unwrap_or_default()
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+/// Print the contents of PK, KEK and db and warn about problematic configurations.
+///
+/// `certificate` is the DER encoded certificate that the stubs are signed with.
+pub fn check_db(databases: &Databases, certificate: Option<&[u8]>) {
+    for (variable, entries) in databases.variables() {
+        println!("{variable}:");
+        if entries.is_empty() {
+            println!("  (empty)");
+        }
+        for entry in entries {
+            println!("  {}", entry.describe(certificate));
+        }
+    }
+
+    let yes_no = |present| if present { "yes" } else { "no" };
+    println!();
+    if let Some(certificate) = certificate {
+        println!(
+            "Signing certificate in db:    {}",
+            yes_no(databases.db.iter().any(|e| e.is_certificate(certificate)))
+        );
+    }
+    println!(
+        "Microsoft KEK:                {}",
+        yes_no(databases.has_microsoft_ca(MicrosoftCa::Kek))
+    );
+    println!(
+        "Microsoft Windows CA in db:   {}",
+        yes_no(databases.has_microsoft_ca(MicrosoftCa::Windows))
+    );
+    println!(
+        "Microsoft UEFI CA in db:      {}",
+        yes_no(databases.has_microsoft_ca(MicrosoftCa::ThirdParty))
+    );
+
+    for problem in databases.problems(certificate) {
+        log::warn!("{problem}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: Guid = Guid::new(0x12345678, 0, 0, [0; 8]);
+
+    fn entry(subject: &str, owner: Guid) -> Entry {
+        Entry {
+            signature: Signature {
+                signature_type: EFI_CERT_X509_GUID,
+                owner,
+                data: subject.as_bytes().to_vec(),
+            },
+            subject: Some(subject.to_string()),
+        }
+    }
+
+    fn microsoft(subject: &str) -> Entry {
+        entry(subject, MICROSOFT_OWNER)
+    }
+
+    #[test]
+    fn accept_own_keys_with_microsoft_cas() {
+        let databases = Databases {
+            pk: vec![entry("Platform Key", OWNER)],
+            kek: vec![
+                entry("Key Exchange Key", OWNER),
+                microsoft("Microsoft Corporation KEK 2K CA 2023"),
+            ],
+            db: vec![
+                entry("Database Key", OWNER),
+                microsoft("Windows UEFI CA 2023"),
+                microsoft("Microsoft UEFI CA 2023"),
+                microsoft("Microsoft Option ROM UEFI CA 2023"),
+            ],
+        };
+        assert_eq!(
+            databases.problems(Some(b"Database Key")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            databases.db[0].describe(Some(b"Database Key")),
+            "X509 Database Key (your signing certificate)"
+        );
+        assert_eq!(
+            databases.db[1].describe(None),
+            "X509 Windows UEFI CA 2023 (Microsoft)"
+        );
+    }
+
+    #[test]
+    fn flag_problematic_configurations() {
+        let databases = Databases {
+            pk: vec![entry("DO NOT TRUST - AMI Test PK", OWNER)],
+            kek: vec![microsoft("Microsoft Corporation KEK CA 2011")],
+            db: vec![microsoft("Microsoft Windows Production PCA 2011")],
+        };
+        let problems = databases.problems(Some(b"Database Key"));
+        assert_eq!(problems.len(), 5, "{problems:#?}");
+        assert!(problems[0].contains("PKfail"));
+        assert!(problems[1].contains("signing certificate is not in db"));
+        assert!(problems[2].contains("Option ROMs"));
+        assert!(problems[3].contains("Microsoft Corporation KEK CA 2011"));
+        assert!(problems[4].contains("Microsoft Windows Production PCA 2011"));
+
+        let empty = Databases {
+            pk: Vec::new(),
+            kek: Vec::new(),
+            db: Vec::new(),
+        };
+        assert_eq!(empty.problems(None).len(), 1);
+    }
+}
@@ -1,16 +1,90 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Parser, Subcommand};
+use clap_complete::ArgValueCandidates;
 
+use crate::architecture::SystemdArchitectureExt;
+use crate::attest;
+use crate::bitlocker::{self, EXIT_WINDOWS_FOUND};
+use crate::check;
+use crate::check_db;
+use crate::cmdline::CmdlineFragments;
+use crate::completions::{self, Shell};
+use crate::daemon;
+use crate::dbus_service;
+use crate::dbx::{self, Kek};
+use crate::deployments::Deployments;
+use crate::diff;
+use crate::du::Usage;
+use crate::esp::SystemdEspPaths;
+use crate::expiry;
+use crate::export::{self, ArchiveFormat};
+use crate::firmware_update;
+use crate::hooks::Hook;
+use crate::inspect::{self, Inspection};
 use crate::install;
-use lanzaboote_tool::{architecture::Architecture, signature::local::LocalKeyPair};
+use crate::install_cache::InstallCache;
+use crate::kernel_hooks::{self, DpkgEvent, KernelChange, KernelInstallArgs, KernelInstallCommand};
+use crate::key_backup::{self, Encryption};
+use crate::key_permissions::{self, KeyFiles};
+use crate::manifest;
+use crate::metrics;
+use crate::migrate::{self, ExistingLayout};
+use crate::namespace;
+use crate::oprom::{self, EXIT_OPTION_ROMS_FOUND};
+use crate::other_os::OtherOsMode;
+use crate::pcrphase;
+use crate::polkit;
+use crate::preflight::{self, UnsupportedSystem, EXIT_UNSUPPORTED_SYSTEM};
+use crate::progress::plural;
+use crate::report;
+use crate::secure_boot::{FirmwareState, PolicyAction, SecureBootPolicy};
+use crate::self_test::{self, Qemu};
+use crate::slots;
+use crate::staging;
+use crate::standalone;
+use crate::status::{self, Status};
+use crate::vm_test::{self, VmFirmware};
+use lanzaboote_tool::{
+    architecture::Architecture,
+    certificate::read_der_certificate,
+    efivars::{read_string_variable, LOADER_GUID},
+    entry_naming::{EntrySort, EntryTitle},
+    esp::EspPaths,
+    esp_fs::{EspFilesystem, PhysicalEspFilesystem},
+    fat::{self, FatEspFilesystem},
+    generation::GenerationRange,
+    initrd_encryption::InitrdKey,
+    initrd_pipeline::InitrdStep,
+    lock::{self, InstallLock},
+    pe::{
+        self, ClockCheck, InsecureBootPolicy, LockdownMode, LuksUnlock, PartitionSelector,
+        StubLocale, StubVerbosity,
+    },
+    profile,
+    revocation::{format_hash, hash_from_argument, RevocationList, DEFAULT_REVOCATION_LIST},
+    signature::{
+        local::LocalKeyPair,
+        pkcs12::{self, Pkcs12Bundle},
+        Signer,
+    },
+    utils::disable_core_dumps,
+};
+use serde_json::json;
+use tempfile::TempDir;
 
 /// The default log level.
 ///
 /// 2 corresponds to the level INFO.
 const DEFAULT_LOG_LEVEL: usize = 2;
 
+/// The system profile whose boot loader installation is re-run after the revocation list changed.
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
 #[derive(Parser)]
 pub struct Cli {
     /// Silence all output
@@ -23,44 +97,1599 @@ pub struct Cli {
     commands: Commands,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    Install(InstallCommand),
+#[derive(Subcommand)]
+enum Commands {
+    Install(Box<InstallCommand>),
+    BuildEspImage(Box<BuildEspImageCommand>),
+    Revoke(RevokeCommand),
+    Dbx(DbxCommand),
+    Migrate(MigrateCommand),
+    MarkGood(MarkGoodCommand),
+    SetDefault(SetDefaultCommand),
+    RebootInto(RebootIntoCommand),
+    Addon(AddonCommand),
+    Standalone(StandaloneCommand),
+    Hook(HookCommand),
+    Deployments(DeploymentsCommand),
+    Diff(DiffCommand),
+    AttestReference(AttestReferenceCommand),
+    Pcrphase(PcrphaseCommand),
+    CheckDb(CheckDbCommand),
+    CheckOprom(CheckOpromCommand),
+    CheckBitlocker(CheckBitlockerCommand),
+    FirmwareUpdate(FirmwareUpdateCommand),
+    Daemon(Box<DaemonCommand>),
+    Dbus(DbusCommand),
+    Inspect(InspectCommand),
+    Status(StatusCommand),
+    Du(DuCommand),
+    Verify(VerifyCommand),
+    Check(CheckCommand),
+    SelfTest(SelfTestCommand),
+    VmTest(VmTestCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+    BackupKeys(BackupKeysCommand),
+    RestoreKeys(RestoreKeysCommand),
+    FixPermissions(FixPermissionsCommand),
+    Slot(SlotCommand),
+    Completions(CompletionsCommand),
+    Man(ManCommand),
+}
+
+#[derive(Parser, Clone)]
+struct InstallCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// Systemd-boot loader config
+    #[arg(long)]
+    systemd_boot_loader_config: PathBuf,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: Option<PathBuf>,
+
+    /// PKI bundle to sign with instead of --public-key and --private-key
+    ///
+    /// Either a directory with the keys of sbctl (e.g. /var/lib/sbctl) or a password-protected
+    /// PKCS#12 file (.p12 or .pfx). A PKCS#12 file is unpacked in memory, so that the private key
+    /// is never written to disk unencrypted.
+    #[arg(long, conflicts_with_all = ["public_key", "private_key"])]
+    pki_bundle: Option<PathBuf>,
+
+    /// File with the password of a PKCS#12 --pki-bundle [default: ask on the terminal]
+    #[arg(long, requires = "pki_bundle")]
+    pki_bundle_password_file: Option<PathBuf>,
+
+    /// Timestamp the signatures with this RFC 3161 timestamp authority
+    ///
+    /// The signatures then stay verifiable after the signing certificate expired. Installing new
+    /// files requires network access to the timestamp authority.
+    #[arg(long, value_name = "URL")]
+    timestamp_url: Option<String>,
+
+    /// Sign a manifest of the managed files on the ESP and check it before installing
+    ///
+    /// Files that were modified since the last installation are reported and installed again.
+    /// The manifest is signed with the signing key, unless --manifest-private-key is given.
+    #[arg(long)]
+    manifest: bool,
+
+    /// The certificate of a dedicated key to sign the manifest with
+    #[arg(long, requires_all = ["manifest", "manifest_private_key"])]
+    manifest_public_key: Option<PathBuf>,
+
+    /// The private key of a dedicated key to sign the manifest with
+    #[arg(long, requires = "manifest_public_key")]
+    manifest_private_key: Option<PathBuf>,
+
+    /// Install at most this many of the newest generations, 0 for all
+    #[arg(long, visible_alias = "max-entries", default_value_t = 1)]
+    configuration_limit: usize,
+
+    /// Only install these generations, e.g. 42, 10-20 or 30- (comma-separated)
+    ///
+    /// Entries of other generations are removed from the ESP, except for the known good generation
+    /// with --tentative.
+    #[arg(
+        long,
+        value_name = "GENERATIONS",
+        value_delimiter = ',',
+        add = ArgValueCandidates::new(completions::generations)
+    )]
+    only: Vec<GenerationRange>,
+
+    /// Enforce the Secure Boot policy in the stub even if Secure Boot is disabled
+    #[arg(long)]
+    simulate_secure_boot: bool,
+
+    /// Install a first stage as the EFI fallback, which verifies systemd-boot before starting it
+    ///
+    /// The first stage is a signed Lanzaboote stub that embeds the SHA256 hash of systemd-boot, for
+    /// firmwares that only trust a single signed binary. The firmware boot entry must point to the
+    /// EFI fallback, e.g. EFI/BOOT/BOOTX64.EFI, for systemd-boot to be protected.
+    #[arg(long)]
+    first_stage: bool,
+
+    /// Install the kernels and initrds to the partition mounted at this directory instead of the ESP
+    ///
+    /// The stubs stay on the ESP and load the kernel and initrd from the partition that
+    /// --payload-partition selects. The firmware must be able to read its file system, which
+    /// usually means FAT, unless --fs-driver gives a driver for it.
+    #[arg(long, value_name = "DIR", requires = "payload_partition")]
+    payload_dir: Option<PathBuf>,
+
+    /// The partition mounted at --payload-dir, as PARTUUID=<GUID> or LABEL=<label>
+    #[arg(long, value_name = "PARTITION", requires = "payload_dir")]
+    payload_partition: Option<PartitionSelector>,
+
+    /// A UEFI file system driver that the stubs load for the partition at --payload-dir
+    ///
+    /// The driver is signed and installed to the ESP. The stubs load it only if the firmware cannot
+    /// read the payload partition itself, e.g. because it is formatted with ext4 or btrfs. Can be
+    /// given multiple times.
+    #[arg(long = "fs-driver", value_name = "PATH", requires = "payload_dir")]
+    fs_drivers: Vec<PathBuf>,
+
+    /// The partition at --payload-partition is a LUKS2 volume, which the stubs unlock
+    ///
+    /// The stubs ask for the passphrase on the console, unless it is unsealed from the TPM with
+    /// --payload-luks-tpm2-handle. They can only open keyslots with the pbkdf2 KDF, which
+    /// `cryptsetup luksAddKey --pbkdf pbkdf2` adds. --payload-dir is where the opened volume is
+    /// mounted.
+    #[arg(long, requires = "payload_dir")]
+    payload_luks: bool,
+
+    /// The persistent handle of a TPM2 object with the passphrase of the LUKS2 volume
+    ///
+    /// The object must be sealed to the PCRs of --payload-luks-tpm2-pcrs without an authorization
+    /// value, e.g. with `tpm2_create -L` and `tpm2_evictcontrol`.
+    #[arg(long, value_name = "HANDLE", value_parser = LuksUnlock::parse_tpm2_handle, requires_all = ["payload_luks", "payload_luks_tpm2_pcrs"])]
+    payload_luks_tpm2_handle: Option<u32>,
+
+    /// The PCRs of the SHA256 bank that the TPM2 object is sealed to, e.g. 7+11
+    #[arg(long, value_name = "PCRS", requires = "payload_luks_tpm2_handle")]
+    payload_luks_tpm2_pcrs: Option<String>,
+
+    /// What the stub does if Secure Boot is disabled: warn, confirm or refuse
+    ///
+    /// `confirm` boots only after a key press and `refuse` does not boot at all. This makes it
+    /// visible if someone disabled Secure Boot in the firmware settings, but not if they also
+    /// replaced the stub.
+    #[arg(long, default_value_t = InsecureBootPolicy::Warn)]
+    insecure_boot_policy: InsecureBootPolicy,
+
+    /// How much the stub logs to the console: quiet, normal or debug
+    #[arg(long, default_value_t = StubVerbosity::Normal)]
+    stub_verbosity: StubVerbosity,
+
+    /// The language of the messages of the stub: en, de, fr or es
+    #[arg(long, default_value_t = StubLocale::En)]
+    stub_locale: StubLocale,
+
+    /// The title of entries in systemd-boot, e.g. "{label} ({description})"
+    ///
+    /// Placeholders are {label}, {nixos_version}, {generation}, {specialisation}, {date} and
+    /// {description}.
+    #[arg(long, default_value_t = EntryTitle::default())]
+    entry_title: EntryTitle,
+
+    /// How systemd-boot orders entries: generation, nixos-version or a format string
+    ///
+    /// systemd-boot orders entries by the sort key of the bootspec first and then by this version,
+    /// newest first. A format string, e.g. "{date} {generation}", takes the placeholders of
+    /// --entry-title.
+    #[arg(long, default_value_t = EntrySort::Generation)]
+    entry_sort: EntrySort,
+
+    /// Clear the screen when the stub starts instead of keeping the firmware splash
+    #[arg(long)]
+    clear_screen: bool,
+
+    /// Mirror the output of the stub to serial ports, even if the firmware console already
+    /// includes a serial terminal. Without it, the stub only mirrors if it does not.
+    #[arg(long)]
+    serial_console: bool,
+
+    /// Make the stub append its log to loader/lanzaboote-debug.log on the ESP, to analyze boots
+    /// that end in a reset. The file is written once per boot and capped at 64 KiB.
+    #[arg(long)]
+    stub_log_file: bool,
+
+    /// Uncompressed BMP image that the stub displays while the kernel is loaded
+    #[arg(long)]
+    splash: Option<PathBuf>,
+
+    /// Additionally install a recovery entry per generation that boots into the rescue target
+    #[arg(long)]
+    recovery_entries: bool,
+
+    /// List of revoked kernel and initrd hashes that is embedded into every stub
+    #[arg(long)]
+    revocation_list: Option<PathBuf>,
+
+    /// Arm the firmware watchdog with this timeout in seconds before starting the kernel
+    #[arg(long)]
+    watchdog_timeout: Option<u64>,
+
+    /// Certificate (PEM or DER) that addons and credentials must be signed with (repeatable)
+    ///
+    /// With several certificates, a signature by any one of them suffices, unless
+    /// --authcert-threshold is given. Without it, addons are verified by the firmware and
+    /// credentials are not verified at all.
+    #[arg(long)]
+    authcert: Vec<PathBuf>,
+
+    /// Require addons and credentials to be signed with this many different --authcert
+    /// certificates
+    ///
+    /// Addons then need that many signatures and credentials that many concatenated signatures in
+    /// their `.p7s` file.
+    #[arg(long, value_name = "COUNT", requires = "authcert")]
+    authcert_threshold: Option<usize>,
+
+    /// Extend the kernel command line from SMBIOS OEM strings even if Secure Boot is active
+    ///
+    /// The stub appends the value of the `io.systemd.stub.kernel-cmdline-extra` OEM string, like
+    /// systemd-stub. Without Secure Boot, this is always done.
+    #[arg(long)]
+    allow_smbios_cmdline: bool,
+
+    /// Use the kernel command line and an additional initrd from QEMU's fw_cfg even if Secure
+    /// Boot is active
+    ///
+    /// Only meant for development and CI VMs. Without Secure Boot, this is always done.
+    #[arg(long)]
+    allow_fw_cfg: bool,
+
+    /// Pass the metadata of the cloud instance to the initrd as credentials
+    ///
+    /// The stub reads the SMBIOS serial number and the metadata of an OpenStack config drive.
+    /// They are not authenticated, so they must only configure what the owner of the instance
+    /// may choose anyway.
+    #[arg(long)]
+    instance_metadata: bool,
+
+    /// Refuse to boot a generation whose kernel differs from the one the system hibernated with
+    ///
+    /// Resuming with a different kernel can corrupt the file systems. By default, the stub only
+    /// warns and waits a few seconds before it boots the generation anyway.
+    #[arg(long)]
+    refuse_mismatched_resume: bool,
+
+    /// What the stub does if the firmware clock is before the build date of the generation
+    ///
+    /// A clock that was reset, e.g. by a dead CMOS battery, breaks certificates and time-based
+    /// TPM policies. `warn` prints a warning, `set` also moves the clock forward to the build
+    /// date.
+    #[arg(long, default_value_t = ClockCheck::Warn)]
+    clock_check: ClockCheck,
+
+    /// Kernel lockdown mode that the stub enforces: none, integrity or confidentiality
+    ///
+    /// The stub adds `lockdown=` to the command line again if the boot loader, SMBIOS or an addon
+    /// changed it, so the mode is enforced by the signature of the stub.
+    #[arg(long, default_value_t = LockdownMode::None)]
+    lockdown: LockdownMode,
+
+    /// Make the stub enforce that the kernel only loads signed modules (module.sig_enforce=1)
+    ///
+    /// Only use this with a kernel whose modules are signed, or no module will load.
+    #[arg(long)]
+    module_sig_enforce: bool,
+
+    /// Transform the initrd before installing it, in the given order
+    ///
+    /// A step is `prepend:<archive>`, e.g. for early microcode, `append:<archive>`, or
+    /// `exec:<program>`, which is called with the path of the initrd to modify it in place, e.g.
+    /// to recompress it. Initrd secrets are always appended last.
+    #[arg(long = "initrd-step")]
+    initrd_steps: Vec<InitrdStep>,
+
+    /// Encrypt the initrds on the ESP with the key in this file
+    ///
+    /// If the file does not exist, a new key is created and provisioned for the stub, which moves
+    /// it into a variable that is only accessible to the firmware on the next boot.
+    #[arg(long)]
+    initrd_key: Option<PathBuf>,
+
+    /// Run this program with the attestation reference values after installing
+    ///
+    /// It is called with the paths of the reference values, as printed by `lzbt
+    /// attest-reference`, and of a Keylime TPM policy, e.g. to update the policy of a verifier.
+    #[arg(long)]
+    attestation_hook: Option<PathBuf>,
+
+    /// Run a program at a hook point of the installation, in the given order
+    ///
+    /// A hook is `pre-install=<program>`, `post-sign=<program>` or `post-install=<program>`. The
+    /// program is called with the hook point as its argument and a JSON description of the
+    /// planned generations and command line changes, the signed files, or the installed entries
+    /// and statistics on stdin.
+    /// A failing hook aborts the installation.
+    #[arg(long = "hook")]
+    hooks: Vec<Hook>,
+
+    /// Add the parameters in this file to the command line of every entry
+    ///
+    /// The file is ignored if it does not exist. See `--cmdline-overrides` for the order in which
+    /// the parameters are added.
+    #[arg(long)]
+    cmdline_file: Option<PathBuf>,
+
+    /// Add parameters to the command lines of some generations or specialisations
+    ///
+    /// Lines are `<generations> [<specialisation>]: <parameters>`, e.g. `10-20 gaming: quiet`,
+    /// where `<generations>` is a generation, a range or `*`. The parameters of the bootspec come
+    /// first, then those of `--cmdline-file`, of the matching lines of this file, of the fragments
+    /// in `--cmdline-dir` and of `--append-cmdline`, so later ones take precedence. The file is
+    /// ignored if it does not exist.
+    #[arg(long)]
+    cmdline_overrides: Option<PathBuf>,
+
+    /// Directory of command line fragments, e.g. /etc/lanzaboote/cmdline.d
+    ///
+    /// The `*.conf` files in it use the format of `--cmdline-overrides` and are applied in the
+    /// order of their names. Generations whose command line changes are assembled and signed
+    /// again, which is reported before anything is installed.
+    #[arg(long)]
+    cmdline_dir: Option<PathBuf>,
+
+    /// Append parameters to the command line of every entry, after all other sources
+    #[arg(long)]
+    append_cmdline: Vec<String>,
+
+    /// Write Prometheus metrics about the installation to this file
+    ///
+    /// The file is meant for the textfile collector of the node exporter, e.g.
+    /// /var/lib/node_exporter/lanzaboote.prom. It is updated even if the installation fails.
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+
+    /// Cache file hashes and signed stubs in this directory, e.g. /var/lib/lanzaboote/cache
+    ///
+    /// Generations whose stubs are cached are installed without assembling and signing them
+    /// again, e.g. after the ESP was recreated.
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Recognize files in the cache by a BLAKE3 hash that is computed on all cores
+    ///
+    /// Files with the same contents as a cached file, e.g. initrds that are rebuilt with the same
+    /// contents or that are not in the Nix store, are then not hashed with SHA256 again. The stubs
+    /// still embed SHA256 hashes.
+    #[arg(long, requires = "cache_dir")]
+    blake3: bool,
+
+    /// Print what the installation did as JSON, also if it failed
+    ///
+    /// The object has the versioned schema `{"schemaVersion": 1, "command": "install", "success":
+    /// ..., "result": ..., "error": ...}`, which `lzbt status`, `lzbt verify` and `lzbt check` use
+    /// as well. The result lists the installed entries, statistics, and every file that was signed,
+    /// with its SHA256 hash, or removed by garbage collection.
+    #[arg(long)]
+    json: bool,
+
+    /// Only boot the newest generation once until it is confirmed with `lzbt mark-good`
+    ///
+    /// Until then, the generation that is known to boot stays the default entry.
+    #[arg(long)]
+    tentative: bool,
+
+    /// Also install the newest generation into slot A or B for image-style updates
+    ///
+    /// Make systemd-boot start the slot selector EFI/Linux/nixos-slots.efi, which boots the
+    /// active slot. A new generation goes into the inactive slot, which becomes active and falls
+    /// back to the other slot if it is not confirmed with `lzbt slot mark-good` in time.
+    #[arg(long, conflicts_with = "tentative")]
+    ab_slots: bool,
+
+    /// How often a new slot is booted until it must be confirmed with `lzbt slot mark-good`
+    #[arg(long, default_value_t = 3, requires = "ab_slots")]
+    slot_tries: u8,
+
+    /// Namespace the installed kernels, initrds and entries by the machine ID of the system
+    ///
+    /// Several installations can then share one ESP without replacing or collecting each other's
+    /// files. The files of an installation without namespace are left alone.
+    #[arg(long)]
+    namespace_by_machine_id: bool,
+
+    /// Boot entries for other operating systems on the ESP: ignore, entries or sign
+    ///
+    /// `entries` adds entries for other Linux installations, whose shim or GRUB systemd-boot does
+    /// not detect. `sign` also installs copies of their boot loaders, including the Windows Boot
+    /// Manager, that are signed with the Secure Boot key, so that they still boot if Microsoft's
+    /// keys are not enrolled. Windows may then ask for the BitLocker recovery key once.
+    #[arg(long, default_value_t = OtherOsMode::default())]
+    other_os: OtherOsMode,
+
+    /// UEFI firmware updater to add a signed boot entry for, e.g. fwupd's fwupdx64.efi
+    ///
+    /// This keeps firmware updates with fwupd working with Secure Boot enabled. Boot the entry
+    /// once with `lzbt firmware-update --updater` after fwupd staged an update.
+    #[arg(long)]
+    firmware_updater: Option<PathBuf>,
+
+    /// Install the generations of this profile, by name (e.g. system) or path
+    #[arg(
+        long,
+        value_name = "PROFILE",
+        conflicts_with = "generations",
+        add = ArgValueCandidates::new(completions::profiles)
+    )]
+    profile: Option<String>,
+
+    /// Write to the FAT file system on this device or image instead of the mounted ESP
+    ///
+    /// The device may be the partition itself or a whole disk with a GPT, whose EFI system
+    /// partition is used. This lets image builders populate an ESP without mounting it. The ESP
+    /// argument is then the path where the ESP will be mounted.
+    #[arg(long, conflicts_with_all = ["tentative", "attestation_hook"])]
+    esp_device: Option<PathBuf>,
+
+    /// Wait for another running installation to finish instead of failing
+    #[arg(long)]
+    wait: bool,
+
+    /// Lock file that keeps concurrent installations from interleaving their writes to the ESP
+    ///
+    /// Defaults to /run/lzbt.lock, or to a file in the temporary directory without /run.
+    #[arg(long)]
+    lock_file: Option<PathBuf>,
+
+    /// Install even if the system did not boot in UEFI mode or the ESP is not a mounted EFI
+    /// system partition
+    ///
+    /// Without it, lzbt refuses to install in these cases and exits with code 3. This is useful to
+    /// prepare a disk for another machine.
+    #[arg(long)]
+    force: bool,
+
+    /// Require Secure Boot to be enabled with the signing key enrolled: abort (default) or warn
+    ///
+    /// This catches installing entries that will not boot, e.g. because the firmware's db only
+    /// contains Microsoft's keys. Without it, that case is only warned about.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "abort",
+        value_name = "ACTION"
+    )]
+    require_secure_boot: Option<PolicyAction>,
+
+    /// Require the firmware to be in setup mode, so that the signing key can be enrolled: abort
+    /// (default) or warn
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "abort",
+        value_name = "ACTION"
+    )]
+    require_setup_mode: Option<PolicyAction>,
+
+    /// Sign even with an expired or not yet valid certificate
+    ///
+    /// Some firmware refuses to boot binaries that are signed with such a certificate.
+    #[arg(long)]
+    allow_invalid_certificate: bool,
+
+    /// Sign even with keys that other users can read or replace
+    ///
+    /// Private keys must not be accessible by other users than the owner and the group, and
+    /// certificates and the directories of the PKI bundle must not be writable by them. `lzbt fix-permissions`
+    /// restricts their permissions.
+    #[arg(long)]
+    insecure_permissions: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint) [default: the first ESP that is
+    /// mounted at /efi, /boot or /boot/efi]
+    ///
+    /// The ESP must be a mounted FAT file system on an EFI system partition, unless --force is
+    /// given.
+    #[arg(value_name = "ESP")]
+    esp_arg: Option<PathBuf>,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+
+    /// The ESP after [`Self::resolve_esp`].
+    #[arg(skip)]
+    esp: PathBuf,
+
+    /// The key pair of a PKCS#12 --pki-bundle after [`Self::resolve_keys`].
+    #[arg(skip)]
+    key_pair: Option<LocalKeyPair>,
+}
+
+impl InstallCommand {
+    /// Take the ESP from the arguments or discover it, see [`preflight::discover_esp`].
+    fn resolve_esp(&mut self) -> Result<()> {
+        if !self.esp.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.esp = match &self.esp_arg {
+            Some(esp) => esp.clone(),
+            None if self.esp_device.is_some() => {
+                bail!("Pass the path at which the ESP will be mounted together with --esp-device.")
+            }
+            None => preflight::discover_esp()?,
+        };
+        Ok(())
+    }
+
+    /// Take the keys from `--pki-bundle`, see [`Pkcs12Bundle`] for PKCS#12 files, after checking
+    /// their permissions.
+    fn resolve_keys(&mut self) -> Result<()> {
+        let mut key_files = KeyFiles::new();
+        if let Some(public_key) = &self.public_key {
+            key_files = key_files.with_public_key(public_key);
+        }
+        if let Some(private_key) = &self.private_key {
+            key_files = key_files.with_private_key(private_key);
+        }
+        if let Some(pki_bundle) = &self.pki_bundle {
+            key_files = key_files.with_pki_bundle(pki_bundle)?;
+        }
+        key_permissions::check(&key_files, self.insecure_permissions)?;
+
+        let Some(pki_bundle) = &self.pki_bundle else {
+            return Ok(());
+        };
+        if self.public_key.is_some() {
+            return Ok(());
+        }
+        let key_pair = if pkcs12::is_pkcs12(pki_bundle) {
+            let bundle = Pkcs12Bundle::open(pki_bundle, self.pki_bundle_password_file.as_deref())?;
+            LocalKeyPair::from_pkcs12(bundle)
+        } else {
+            LocalKeyPair::new(
+                &pki_bundle.join("keys/db/db.pem"),
+                &pki_bundle.join("keys/db/db.key"),
+            )
+        };
+        self.public_key = Some(key_pair.public_key.clone());
+        self.private_key = Some(key_pair.private_key.clone());
+        self.key_pair = Some(key_pair);
+        Ok(())
+    }
+
+    /// The generation links to install, either discovered from the profile or given explicitly.
+    fn generation_links(&self) -> Result<Vec<PathBuf>> {
+        match &self.profile {
+            Some(profile) => profile::generation_links(&profile::profile_path(profile)),
+            None => Ok(self.generations.clone()),
+        }
+    }
+
+    /// Check that the system can boot Lanzaboote from the ESP at all.
+    ///
+    /// Only the validity of the signing certificate is checked with `--esp-device`, whose ESP
+    /// usually belongs to another machine.
+    fn check_system(&self) -> Result<()> {
+        let certificate = self.certificate()?;
+        if let Some(certificate) = &certificate {
+            expiry::check_signing_certificate(certificate, self.allow_invalid_certificate)?;
+        }
+        if self.esp_device.is_some() {
+            return Ok(());
+        }
+        preflight::check(&self.esp, self.force)?;
+
+        let Some(certificate) = certificate else {
+            return Ok(());
+        };
+        let policy = SecureBootPolicy {
+            require_secure_boot: self.require_secure_boot,
+            require_setup_mode: self.require_setup_mode,
+        };
+        policy.check(&FirmwareState::read(&certificate)?)
+    }
+
+    fn esp_paths(&self) -> Result<SystemdEspPaths> {
+        Ok(SystemdEspPaths::new(
+            &self.esp,
+            Architecture::from_nixos_system(&self.system)?,
+        ))
+    }
+
+    /// The DER encoded certificate that the stubs are signed with, if known.
+    fn certificate(&self) -> Result<Option<Vec<u8>>> {
+        self.public_key
+            .as_deref()
+            .map(read_der_certificate)
+            .transpose()
+    }
+
+    fn signer(&self) -> Result<LocalKeyPair> {
+        let key_pair = match (&self.key_pair, &self.public_key, &self.private_key) {
+            (Some(key_pair), _, _) => key_pair.clone(),
+            (None, Some(public_key), Some(private_key)) => {
+                LocalKeyPair::new(public_key, private_key)
+            }
+            _ => {
+                bail!("No key to sign with. Pass --public-key and --private-key, or --pki-bundle.")
+            }
+        };
+        Ok(key_pair.with_timestamp_url(self.timestamp_url.clone()))
+    }
+
+    /// A key pair to verify signatures with, which does not need the private key.
+    fn verifier(&self) -> Result<LocalKeyPair> {
+        let Some(public_key) = &self.public_key else {
+            bail!("No certificate to verify with. Pass --public-key.");
+        };
+        Ok(LocalKeyPair::public_only(public_key))
+    }
+
+    /// The key pair that signs the manifest of the ESP, if any.
+    fn manifest_key(&self) -> Result<Option<LocalKeyPair>> {
+        if !self.manifest {
+            return Ok(None);
+        }
+        Ok(Some(
+            match (&self.manifest_public_key, &self.manifest_private_key) {
+                (Some(public_key), Some(private_key)) => LocalKeyPair::new(public_key, private_key),
+                _ => self.signer()?,
+            },
+        ))
+    }
+
+    /// A key pair to verify the manifest of the ESP with.
+    fn manifest_verifier(&self) -> Result<LocalKeyPair> {
+        match &self.manifest_public_key {
+            Some(public_key) => Ok(LocalKeyPair::public_only(public_key)),
+            None => self.verifier(),
+        }
+    }
+
+    /// Take the lock that keeps other installations from writing to the ESP at the same time.
+    fn lock(&self) -> Result<InstallLock> {
+        let path = self
+            .lock_file
+            .clone()
+            .unwrap_or_else(lock::default_lock_file);
+        let lock = InstallLock::acquire(&path, self.wait)?;
+        if let Some(pid) = lock.stale_pid() {
+            log::warn!(
+                "A previous installation (PID {pid}) did not finish. The files it left behind are collected as garbage."
+            );
+        }
+        Ok(lock)
+    }
+}
+
+/// Manage the list of revoked kernel and initrd hashes
+///
+/// Stubs refuse to boot revoked kernels and initrds, even if they are correctly signed. Without
+/// any hashes, the current list is printed.
+#[derive(Parser)]
+struct RevokeCommand {
+    /// The revocation list to modify
+    #[arg(long, default_value = DEFAULT_REVOCATION_LIST)]
+    revocation_list: PathBuf,
+
+    /// Remove the hashes from the list instead of adding them
+    #[arg(long)]
+    remove: bool,
+
+    /// Do not re-install the boot loader of the system profile to re-sign the stubs
+    #[arg(long)]
+    no_reinstall: bool,
+
+    /// SHA256 hashes in hex, or paths of kernels and initrds to hash
+    hashes: Vec<String>,
+}
+
+/// Inspect and update the firmware's forbidden signature database (dbx)
+///
+/// Once you own the Secure Boot keys, you have to keep track of revocations yourself.
+#[derive(Parser)]
+struct DbxCommand {
+    #[clap(subcommand)]
+    action: DbxAction,
+}
+
+#[derive(Subcommand)]
+enum DbxAction {
+    /// List the entries of the firmware's dbx
+    List,
+    /// Check whether binaries on the ESP are revoked by the firmware's dbx or by an update
+    Check {
+        /// A dbx update, e.g. the latest UEFI revocation list, to check against as well
+        #[arg(long)]
+        update: Option<PathBuf>,
+
+        /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+        esp: PathBuf,
+    },
+    /// Apply a dbx update, e.g. the latest UEFI revocation list
+    ///
+    /// The update is refused if it revokes any binary on the ESP.
+    Update(DbxUpdateCommand),
+}
+
+#[derive(Parser)]
+struct DbxUpdateCommand {
+    /// KEK private key to sign the update with if Microsoft's KEK is not enrolled
+    #[arg(long, requires = "kek_public_key")]
+    kek_private_key: Option<PathBuf>,
+
+    /// KEK public key to sign the update with if Microsoft's KEK is not enrolled
+    #[arg(long, requires = "kek_private_key")]
+    kek_public_key: Option<PathBuf>,
+
+    /// Directory to back up the current dbx to before updating it
+    #[arg(long, default_value = "/var/lib/lanzaboote")]
+    backup_dir: PathBuf,
+
+    /// Only check the update without applying it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// The dbx update, e.g. DBXUpdate.bin from uefi.org
+    update: PathBuf,
+}
+
+/// Migrate from an existing systemd-boot or GRUB installation
+#[derive(Parser)]
+struct MigrateCommand {
+    #[clap(subcommand)]
+    action: MigrateAction,
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+    /// Back up the old boot loader and install Lanzaboote
+    ///
+    /// The old boot loader is removed afterwards, so later runs are the same as `install`.
+    Start(Box<MigrateStartCommand>),
+    /// Remove the backup of the old boot loader after a Lanzaboote entry booted successfully
+    Finish {
+        /// Remove the backup even if the system was not booted from a Lanzaboote entry
+        #[arg(long)]
+        force: bool,
+
+        /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+        esp: PathBuf,
+    },
+    /// Restore the old boot loader from the backup
+    Restore {
+        /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+        esp: PathBuf,
+    },
+}
+
+#[derive(Parser)]
+struct MigrateStartCommand {
+    #[clap(flatten)]
+    install: InstallCommand,
+}
+
+/// Make the currently booted entry the default entry
+///
+/// Run this after a generation that was installed with `--tentative` booted successfully.
+#[derive(Parser)]
+struct MarkGoodCommand {}
+
+/// Inspect and switch the A/B slots of `lzbt install --ab-slots`
+#[derive(Parser)]
+struct SlotCommand {
+    #[clap(subcommand)]
+    action: SlotAction,
+}
+
+#[derive(Subcommand)]
+enum SlotAction {
+    /// Show the payloads of the slots and which one is active and booted
+    Status {
+        /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+        #[arg(long, default_value = "/boot")]
+        esp: PathBuf,
+    },
+    /// Confirm the slot of the current boot, so that the selector keeps booting it
+    ///
+    /// Run this once the system booted successfully, e.g. after boot-complete.target.
+    MarkGood,
+    /// Make a slot the active one
+    Switch {
+        /// Boot the slot this many times until it must be confirmed, instead of confirming it
+        #[arg(long)]
+        tries: Option<u8>,
+
+        /// The slot to activate: a or b
+        slot: slots::Slot,
+    },
+}
+
+/// Make the entry of a generation the default entry
+///
+/// The generation stays the default until another one is set as the default, confirmed with
+/// `lzbt mark-good` or staged with `lzbt install --tentative`.
+#[derive(Parser)]
+struct SetDefaultCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// The generation to boot by default
+    #[arg(value_name = "GENERATION", add = ArgValueCandidates::new(completions::generations))]
+    generation: u64,
+}
+
+/// Boot the entry of a generation once and reboot
+///
+/// The boot after that uses the default entry again.
+#[derive(Parser)]
+struct RebootIntoCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Only select the entry for the next boot without rebooting
+    #[arg(long)]
+    no_reboot: bool,
+
+    /// The generation to boot once
+    #[arg(value_name = "GENERATION", add = ArgValueCandidates::new(completions::generations))]
+    generation: u64,
+}
+
+/// Build and sign an addon that extends boot entries with a command line and/or an initrd
+///
+/// Place the addon in the drop-in directory of an entry, e.g.
+/// `EFI/Linux/nixos-generation-42-<hash>.efi.extra/debug.addon.efi`, to apply it to that entry
+/// without re-signing the entry itself.
+#[derive(Parser)]
+struct AddonCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// Command line to append to the kernel command line of the entry
+    #[arg(long)]
+    cmdline: Option<String>,
+
+    /// Initrd to pass to the kernel in addition to the initrd of the entry
+    #[arg(long)]
+    initrd: Option<PathBuf>,
+
+    /// Timestamp the signature with this RFC 3161 timestamp authority
+    #[arg(long, value_name = "URL")]
+    timestamp_url: Option<String>,
+
+    /// Path of the addon, which must end with .addon.efi
+    output: PathBuf,
+}
+
+/// Build and sign a stub for a kernel and initrd given by their paths, without a NixOS generation
+///
+/// This lets other distributions, e.g. Arch Linux or Debian, boot their own kernels with the stub
+/// and signing of Lanzaboote. The kernel and initrd are installed to `EFI/<ID>` on the ESP, with
+/// the ID of the os-release, and the stub at --out boots them. Place it in `EFI/Linux`, where
+/// systemd-boot finds it.
+#[derive(Parser)]
+struct StandaloneCommand {
+    /// The kernel to boot
+    #[arg(long)]
+    kernel: PathBuf,
+
+    /// The initrd of the kernel
+    #[arg(long)]
+    initrd: PathBuf,
+
+    /// The kernel command line
+    #[arg(long)]
+    cmdline: String,
+
+    /// The os-release of the distribution, which systemd-boot shows the entry with
+    #[arg(long, default_value = "/etc/os-release")]
+    os_release: PathBuf,
+
+    /// EFI system partition mountpoint
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// Timestamp the signature with this RFC 3161 timestamp authority
+    #[arg(long, value_name = "URL")]
+    timestamp_url: Option<String>,
+
+    /// Sign even with keys that other users can read or replace
+    #[arg(long)]
+    insecure_permissions: bool,
+
+    /// Path of the stub on the ESP, e.g. /boot/EFI/Linux/arch-linux.efi
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Install the kernels of other distributions from their kernel hooks
+///
+/// The kernels are installed like by `lzbt standalone`, with the stub at
+/// `EFI/Linux/<token>-<version>.efi`, where the token is the ID of the os-release. The command line
+/// is read from /etc/kernel/cmdline, or else taken from the running kernel. Hooks cannot ask for
+/// passwords, so a PKCS#12 --pki-bundle needs --pki-bundle-password-file.
+#[derive(Parser)]
+struct HookCommand {
+    #[clap(subcommand)]
+    action: HookAction,
+}
+
+#[derive(Subcommand)]
+enum HookAction {
+    /// Plugin of systemd's kernel-install, e.g. on Fedora and Arch Linux
+    ///
+    /// Install it as /etc/kernel/install.d/95-lanzaboote.install with
+    /// `exec lzbt hook kernel-install "$@"`, and set `layout=uki` in /etc/kernel/install.conf, so
+    /// that no other plugin installs unsigned entries. The initrd is taken from the arguments or
+    /// the one an earlier plugin, e.g. dracut's, generated.
+    KernelInstall(KernelInstallHookCommand),
+    /// Hook of Debian's kernel packages and initramfs-tools, e.g. on Debian and Ubuntu
+    ///
+    /// Install it as /etc/kernel/postinst.d/zz-lanzaboote with `exec lzbt hook dpkg postinst "$@"`,
+    /// as /etc/kernel/postrm.d/zz-lanzaboote with `exec lzbt hook dpkg postrm "$@"`, and as
+    /// /etc/initramfs/post-update.d/zz-lanzaboote with `exec lzbt hook dpkg initramfs "$@"`, so
+    /// that regenerated initrds are installed too.
+    Dpkg(DpkgHookCommand),
+}
+
+/// How stubs of kernels outside of NixOS generations are signed.
+#[derive(Parser)]
+struct SigningArgs {
+    /// PKI bundle to sign with, either a directory with the keys of sbctl or a PKCS#12 file
+    #[arg(long, default_value = "/var/lib/sbctl")]
+    pki_bundle: PathBuf,
+
+    /// File with the password of a PKCS#12 --pki-bundle
+    #[arg(long)]
+    pki_bundle_password_file: Option<PathBuf>,
+
+    /// Timestamp the signatures with this RFC 3161 timestamp authority
+    #[arg(long, value_name = "URL")]
+    timestamp_url: Option<String>,
+
+    /// Sign even with keys that other users can read or replace
+    #[arg(long)]
+    insecure_permissions: bool,
+}
+
+impl SigningArgs {
+    fn key_pair(&self) -> Result<LocalKeyPair> {
+        key_permissions::check(
+            &KeyFiles::new().with_pki_bundle(&self.pki_bundle)?,
+            self.insecure_permissions,
+        )?;
+        let key_pair = if pkcs12::is_pkcs12(&self.pki_bundle) {
+            let bundle =
+                Pkcs12Bundle::open(&self.pki_bundle, self.pki_bundle_password_file.as_deref())?;
+            LocalKeyPair::from_pkcs12(bundle)
+        } else {
+            LocalKeyPair::new(
+                &self.pki_bundle.join("keys/db/db.pem"),
+                &self.pki_bundle.join("keys/db/db.key"),
+            )
+        };
+        Ok(key_pair.with_timestamp_url(self.timestamp_url.clone()))
+    }
+}
+
+#[derive(Parser)]
+struct KernelInstallHookCommand {
+    #[clap(flatten)]
+    signing: SigningArgs,
+
+    /// The partition to install to [default: $KERNEL_INSTALL_BOOT_ROOT, or else /boot]
+    #[arg(long)]
+    esp: Option<PathBuf>,
+
+    /// add or remove
+    command: KernelInstallCommand,
+
+    /// The version of the kernel
+    kernel_version: String,
+
+    /// The directory of the boot loader entry, which is not used
+    entry_dir: PathBuf,
+
+    /// The kernel image, only for add
+    kernel_image: Option<PathBuf>,
+
+    /// The initrds, only for add
+    initrds: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct DpkgHookCommand {
+    #[clap(flatten)]
+    signing: SigningArgs,
+
+    /// EFI system partition mountpoint
+    #[arg(long, default_value = "/boot/efi")]
+    esp: PathBuf,
+
+    /// The directory of the hook: postinst, postrm or initramfs (post-update.d)
+    event: DpkgEvent,
+
+    /// The version of the kernel
+    kernel_version: String,
+
+    /// The kernel image, or the initrd for initramfs
+    path: Option<PathBuf>,
+}
+
+/// Install a signed stub for every deployment of an image-based system
+///
+/// The deployments of ostree, e.g. on Fedora CoreOS, are read from the boot loader entries that
+/// `ostree admin deploy` writes, those of other update systems from a JSON manifest. Every
+/// deployment gets a stub at `EFI/Linux/<name>-deployment-<id>.efi`, and the stubs of deployments
+/// that no longer exist are removed. Run this after every deployment.
+#[derive(Parser)]
+#[command(group(ArgGroup::new("source").required(true)))]
+struct DeploymentsCommand {
+    #[clap(flatten)]
+    signing: SigningArgs,
+
+    /// Read the ostree deployments of this system root
+    #[arg(long, value_name = "SYSROOT", group = "source")]
+    ostree: Option<PathBuf>,
+
+    /// Read the deployments from this JSON manifest
+    #[arg(long, group = "source")]
+    manifest: Option<PathBuf>,
+
+    /// EFI system partition mountpoint
+    #[arg(long, default_value = "/boot/efi")]
+    esp: PathBuf,
+}
+
+/// Compare the boot-relevant data of the installed entries of two generations
+///
+/// This shows what changes between booting them, e.g. the kernel, the initrd, the command line
+/// and the expected value of PCR 11.
+#[derive(Parser)]
+struct DiffCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Print the entries and their differences as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// The generation to compare from
+    #[arg(value_name = "GENERATION", add = ArgValueCandidates::new(completions::generations))]
+    from: u64,
+
+    /// The generation to compare to
+    #[arg(value_name = "GENERATION", add = ArgValueCandidates::new(completions::generations))]
+    to: u64,
+}
+
+/// List the certificates enrolled in the firmware's PK, KEK and db and check them for problems
+///
+/// This shows whether your signing certificate and Microsoft's certificates, which e.g. option
+/// ROMs of GPUs need, are enrolled, and warns about known-problematic configurations.
+#[derive(Parser)]
+struct CheckDbCommand {
+    /// The certificate that the stubs are signed with, i.e. the public key of `lzbt install`
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+}
+
+/// Check PCI devices for option ROMs that need Microsoft's UEFI CA
+///
+/// Option ROMs, e.g. of GPUs and network cards, contain UEFI drivers. With Secure Boot enabled,
+/// the firmware only loads them if they are signed by a key in db, which is usually Microsoft's
+/// UEFI CA. If you enroll your own keys without it, such a device may not initialize, which can
+/// leave the machine without display output. Exits with code 2 if any such device is found.
+#[derive(Parser)]
+struct CheckOpromCommand {}
+
+/// Check whether enrolling your own Secure Boot keys trips BitLocker of a Windows on the ESP
+///
+/// The firmware measures the Secure Boot keys into PCR 7, which BitLocker seals its key against.
+/// If the Windows Boot Manager is on the ESP, enrolling your own PK, KEK and db makes Windows ask
+/// for the BitLocker recovery key. Exits with code 2 if the Windows Boot Manager is found.
+#[derive(Parser)]
+struct CheckBitlockerCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Print the steps to suspend BitLocker before enrolling, or to recover from it
+    #[arg(long)]
+    guidance: bool,
+}
+
+/// Apply firmware updates without leaving the Secure Boot chain of Lanzaboote
+///
+/// `--stage` delivers a UEFI capsule on disk: it is copied to `EFI/UpdateCapsule` on the ESP and
+/// the firmware applies it on the next boot. `--updater` boots the signed firmware updater of
+/// `lzbt install --firmware-updater` once, e.g. after `fwupdmgr install` staged an update.
+#[derive(Parser)]
+struct FirmwareUpdateCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// The capsule to stage, e.g. a .cap file from the vendor of the machine
+    #[arg(long, required_unless_present = "updater", conflicts_with = "updater")]
+    stage: Option<PathBuf>,
+
+    /// Boot the signed firmware updater once on the next boot
+    #[arg(long)]
+    updater: bool,
+}
+
+/// Serve installation and signing requests of other services on a varlink socket
+///
+/// Other services, e.g. wrappers of nixos-rebuild or auto-update timers, can then install and sign
+/// without access to the private key. Every installation uses the options of the daemon. Anyone
+/// who can connect to the socket can have files signed, so keep its permissions restrictive.
+#[derive(Parser)]
+struct DaemonCommand {
+    /// The socket to listen on if it is not passed by systemd's socket activation
+    #[arg(long, default_value = daemon::DEFAULT_SOCKET)]
+    socket: PathBuf,
+
+    #[clap(flatten)]
+    install: InstallCommand,
+}
+
+/// Build an image of an ESP with a complete signed boot setup
+///
+/// The FAT file system is created from scratch and populated like by `lzbt install --esp-device`,
+/// without loop devices or root, so that CI pipelines can write it to the ESP of a cloud or IoT
+/// disk image with dd. All options of `install` apply, and the ESP argument is the path at which
+/// the ESP will be mounted. With SOURCE_DATE_EPOCH and --volume-id, the image is reproducible.
+#[derive(Parser)]
+struct BuildEspImageCommand {
+    /// The image to create, which must not exist yet
+    #[arg(long)]
+    output: PathBuf,
+
+    /// The size of the image in MiB
+    ///
+    /// Images of at least 34 MiB are formatted with FAT32, smaller ones with FAT16 or FAT12.
+    #[arg(long, default_value_t = 512)]
+    size: u64,
+
+    /// The label of the file system
+    #[arg(long, default_value = "ESP")]
+    label: String,
+
+    /// The volume ID of the file system as 8 hexadecimal digits [default: derived from the time]
+    #[arg(long, value_parser = parse_volume_id)]
+    volume_id: Option<u32>,
+
+    #[clap(flatten)]
+    install: InstallCommand,
+}
+
+fn parse_volume_id(volume_id: &str) -> Result<u32> {
+    u32::from_str_radix(volume_id, 16)
+        .with_context(|| format!("Invalid volume ID {volume_id:?}, expected 8 hexadecimal digits"))
+}
+
+/// Check that the kernels and initrds on the ESP are intact
+///
+/// This compares them with the hashes embedded in the installed stubs and with the hashes in their
+/// names. Exits with 1 if any file is damaged or was modified.
+#[derive(Parser)]
+struct CheckCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Also compare them with their sources in the Nix store, which reads every kernel and initrd
+    #[arg(long)]
+    deep: bool,
+
+    /// The profile whose generations to compare with the Nix store, by name or path
+    #[arg(
+        long,
+        value_name = "PROFILE",
+        default_value = "system",
+        requires = "deep",
+        add = ArgValueCandidates::new(completions::profiles)
+    )]
+    profile: String,
+
+    /// Print the result as JSON, see `lzbt install --json`
+    #[arg(long)]
+    json: bool,
+}
+
+/// Check that the stub, the signing keys and the PE tooling work together
+///
+/// This assembles a stub for a dummy kernel and initrd in a temporary directory, signs it,
+/// verifies its signature and parses it back. With --qemu, it also boots the stub in QEMU, where it
+/// must get as far as loading the dummy kernel. The ESP is not touched.
+#[derive(Parser)]
+struct SelfTestCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// QEMU system emulator to boot the stub in, e.g. qemu-system-x86_64
+    #[arg(long, requires = "firmware")]
+    qemu: Option<PathBuf>,
+
+    /// UEFI firmware for QEMU, e.g. OVMF.fd
+    #[arg(long, requires = "qemu")]
+    firmware: Option<PathBuf>,
+}
+
+/// Boot a generation in QEMU with Secure Boot enforced, before rebooting the machine
+///
+/// This assembles and signs the generation like `lzbt install` does, but into a temporary
+/// directory. OVMF boots it with ephemeral PK and KEK and only the signing certificate in db, so
+/// the firmware, systemd-boot and the stub have to verify the whole boot chain. The test succeeds
+/// when the kernel starts. The UEFI variables are prepared with virt-fw-vars, which must be on
+/// PATH. The ESP is not touched.
+#[derive(Parser)]
+struct VmTestCommand {
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// Systemd-boot loader config
+    #[arg(long)]
+    systemd_boot_loader_config: PathBuf,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// The profile of the generation, by name (e.g. system) or path
+    #[arg(long, default_value = "system")]
+    profile: String,
+
+    /// QEMU system emulator, e.g. qemu-system-x86_64
+    #[arg(long)]
+    qemu: PathBuf,
+
+    /// UEFI firmware code with Secure Boot support, e.g. OVMF_CODE.fd
+    #[arg(long)]
+    firmware: PathBuf,
+
+    /// UEFI variables without any keys to enroll the ephemeral keys in, e.g. OVMF_VARS.fd
+    #[arg(long)]
+    firmware_vars: PathBuf,
+
+    /// The generation to boot
+    generation: u64,
+}
+
+/// Print the Secure Boot state of the firmware and the installed entries
+#[derive(Parser)]
+struct StatusCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// The certificate the entries are signed with, to check whether it is enrolled
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// Only read the ESP, not the EFI variables of the running system
+    ///
+    /// Use this to audit the ESP of another machine, e.g. from rescue media. Like `lzbt verify`
+    /// and `lzbt inspect`, this neither writes anything nor needs the Nix store or /run.
+    #[arg(long)]
+    offline: bool,
+
+    /// Print the status as JSON, see `lzbt install --json`
+    #[arg(long)]
+    json: bool,
 }
 
+/// Break down the space on the ESP by generation and artifact type
+///
+/// Kernels and initrds that several generations share are only freed when all of them are
+/// removed, so the space of each generation is split into what only it uses and what it shares.
 #[derive(Parser)]
-struct InstallCommand {
+struct DuCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
     /// System for lanzaboote binaries, e.g. defines the EFI fallback path
     #[arg(long)]
     system: String,
 
-    /// Systemd path
+    /// Print the usage as JSON, see `lzbt install --json`
     #[arg(long)]
-    systemd: PathBuf,
+    json: bool,
+}
 
-    /// Systemd-boot loader config
+/// Check the signatures of systemd-boot and the installed entries
+///
+/// Exits with 1 if any of them is not signed with the given certificate.
+#[derive(Parser)]
+struct VerifyCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
     #[arg(long)]
-    systemd_boot_loader_config: PathBuf,
+    system: String,
 
-    /// sbsign Public Key
+    /// The certificate the entries must be signed with
     #[arg(long)]
-    public_key: Option<PathBuf>,
+    public_key: PathBuf,
 
-    /// sbsign Private Key
+    /// The certificate that the manifest of the ESP is signed with [default: --public-key]
+    #[arg(long)]
+    manifest_public_key: Option<PathBuf>,
+
+    /// Require a signed manifest of the ESP, see `lzbt install --manifest`
+    ///
+    /// A missing manifest is reported like a modification of the ESP.
+    #[arg(long)]
+    manifest: bool,
+
+    /// Print the result as JSON, see `lzbt install --json`
+    #[arg(long)]
+    json: bool,
+}
+
+/// Export the files that Lanzaboote manages on the ESP into an archive
+///
+/// This includes systemd-boot, the stubs, the kernels and initrds, and the loader configuration
+/// with all boot entries. Use `lzbt import` to restore the archive, e.g. after a risky change or
+/// on a replacement disk.
+#[derive(Parser)]
+struct ExportCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// The format of the archive: tar or tar.zst
+    #[arg(long, default_value_t = ArchiveFormat::TarZst)]
+    format: ArchiveFormat,
+
+    /// The archive to write
+    output: PathBuf,
+}
+
+/// Replace the files that Lanzaboote manages on the ESP with an archive of `lzbt export`
+///
+/// The archive is checked against its manifest before the ESP is modified. Managed files that are
+/// not part of the archive are removed. Files of other operating systems are kept.
+#[derive(Parser)]
+struct ImportCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Wait for a running installation to finish instead of failing
+    #[arg(long)]
+    wait: bool,
+
+    /// The archive to import
+    archive: PathBuf,
+}
+
+/// Back up the Secure Boot keys into an encrypted archive
+///
+/// The archive contains the whole PKI bundle and is encrypted with age or GPG. Losing the db key
+/// after enrolling it means resetting the firmware to setup mode or reflashing it. Use
+/// `lzbt restore-keys` to restore the keys.
+#[derive(Parser)]
+#[command(group(ArgGroup::new("encryption").required(true)))]
+struct BackupKeysCommand {
+    /// The PKI bundle to back up
+    #[arg(long, default_value = "/var/lib/sbctl")]
+    pki_bundle: PathBuf,
+
+    /// Encrypt with age to this recipient, e.g. an age or SSH public key (repeatable)
+    #[arg(long, value_name = "RECIPIENT", group = "encryption")]
+    age_recipient: Vec<String>,
+
+    /// Encrypt with GPG to this recipient (repeatable)
+    #[arg(long, value_name = "RECIPIENT", group = "encryption")]
+    gpg_recipient: Vec<String>,
+
+    /// Encrypt with age and a passphrase that age asks for
+    #[arg(long, group = "encryption")]
+    passphrase: bool,
+
+    /// The backup to write, which must not exist yet
+    output: PathBuf,
+}
+
+/// Restore the Secure Boot keys from a backup of `lzbt backup-keys`
+///
+/// Whether the backup was encrypted with age or GPG is detected automatically.
+#[derive(Parser)]
+struct RestoreKeysCommand {
+    /// The PKI bundle to restore
+    #[arg(long, default_value = "/var/lib/sbctl")]
+    pki_bundle: PathBuf,
+
+    /// The age identity file to decrypt a backup that was encrypted to age recipients
+    #[arg(long)]
+    identity: Option<PathBuf>,
+
+    /// Replace the keys in the PKI bundle if there are any
+    #[arg(long)]
+    force: bool,
+
+    /// The backup to restore
+    backup: PathBuf,
+}
+
+/// Restrict the permissions of the Secure Boot keys, so that no other user can read or replace them
+///
+/// Private keys are made accessible only by their owner, and certificates and directories writable
+/// only by their owner. Files of other users than root are given to the current user. Without
+/// arguments, the keys of sbctl in /var/lib/sbctl are fixed.
+#[derive(Parser)]
+struct FixPermissionsCommand {
+    /// The PKI bundle to fix, either a directory with the keys of sbctl or a PKCS#12 file
+    #[arg(long)]
+    pki_bundle: Option<PathBuf>,
+
+    /// A private key to fix
     #[arg(long)]
     private_key: Option<PathBuf>,
 
-    /// Configuration limit
-    #[arg(long, default_value_t = 1)]
-    configuration_limit: usize,
+    /// A certificate to fix
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+}
+
+/// Inspect a Lanzaboote stub or a unified kernel image and print what is wrong with it
+///
+/// This lists the sections, the embedded command line and os-release, the kernel and initrd a
+/// stub loads from the ESP and the signature, and points out discrepancies that keep the entry
+/// from booting, e.g. a kernel whose hash does not match. Exits with 1 if there are any.
+#[derive(Parser)]
+struct InspectCommand {
+    /// The PE binary to inspect
+    file: PathBuf,
+
+    /// EFI system partition mountpoint that the paths in a stub are relative to [default: the
+    /// parent of the EFI directory the file is in, or /boot]
+    #[arg(long)]
+    esp: Option<PathBuf>,
+
+    /// Verify the signature with this certificate
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// Print the inspection as JSON
+    #[arg(long)]
+    json: bool,
+}
 
+/// Expose the Secure Boot state and the installed generations as org.nixos.Lanzaboote on the
+/// system bus
+///
+/// The service is read-only and never needs the private key.
+#[derive(Parser)]
+struct DbusCommand {
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
     esp: PathBuf,
 
-    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
-    generations: Vec<PathBuf>,
+    /// System for lanzaboote binaries, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// The certificate the entries are signed with, to check signatures and enrollment
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+}
+
+/// Print reference values for remote attestation of all installed entries as JSON
+///
+/// For every entry, this includes the events the stub measures into PCR 11 and its expected
+/// value, as well as the Authenticode hash the firmware measures into PCR 4 when it starts the
+/// entry. Attestation services can validate quotes of machines booted from these entries against
+/// them.
+#[derive(Parser)]
+struct AttestReferenceCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, default_value = "/boot")]
+    esp: PathBuf,
+
+    /// Print a Keylime TPM policy that accepts all entries instead
+    #[arg(long)]
+    keylime: bool,
+
+    /// Write the reference values to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+/// Measure a boot phase into PCR 11, like systemd-pcrphase
+///
+/// Run this with `enter-initrd` early in the initrd and with `leave-initrd` before switching root,
+/// so that secrets sealed to PCR 11 in the initrd, e.g. of LUKS, cannot be unsealed later.
+/// `lzbt attest-reference` prints the expected values of PCR 11 after each phase. Nothing is
+/// measured if the stub did not measure the booted entry.
+#[derive(Parser)]
+struct PcrphaseCommand {
+    /// The phase: enter-initrd, leave-initrd, sysinit, ready, shutdown or final
+    phase: String,
+
+    /// The TPM device
+    #[arg(long, default_value = pcrphase::TPM_DEVICE)]
+    tpm_device: PathBuf,
+}
+
+/// Print the completion script of a shell
+///
+/// Generations and profiles are completed from the system, e.g. for `lzbt set-default`.
+#[derive(Parser)]
+struct CompletionsCommand {
+    /// The shell
+    #[arg(value_enum)]
+    shell: Shell,
 }
 
+/// Print the man page in roff
+#[derive(Parser)]
+struct ManCommand {}
+
 impl Cli {
     pub fn call(self, module: &str) {
         stderrlog::new()
@@ -71,8 +1700,15 @@ impl Cli {
             .init()
             .expect("Failed to setup logger.");
 
+        if let Err(e) = disable_core_dumps() {
+            log::warn!("{e:#}");
+        }
+
         if let Err(e) = self.commands.call() {
             log::error!("{e:#}");
+            if e.downcast_ref::<UnsupportedSystem>().is_some() {
+                std::process::exit(EXIT_UNSUPPORTED_SYSTEM);
+            }
             std::process::exit(1);
         };
     }
@@ -81,21 +1717,582 @@ impl Cli {
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
-            Commands::Install(args) => install(args),
+            Commands::Install(args) => {
+                let json = args.json;
+                let outcome = install(*args);
+                if json {
+                    print_report(
+                        "install",
+                        outcome.as_ref().ok().map(report::install_result),
+                        outcome.as_ref().err(),
+                    );
+                }
+                outcome.map(|_| ())
+            }
+            Commands::BuildEspImage(args) => {
+                let json = args.install.json;
+                let outcome = build_esp_image(*args);
+                if json {
+                    print_report(
+                        "build-esp-image",
+                        outcome.as_ref().ok().map(report::install_result),
+                        outcome.as_ref().err(),
+                    );
+                }
+                outcome.map(|_| ())
+            }
+            Commands::Revoke(args) => revoke(args),
+            Commands::Dbx(args) => match args.action {
+                DbxAction::List => dbx::list(),
+                DbxAction::Check { update, esp } => {
+                    dbx::check(&PhysicalEspFilesystem, &esp, update.as_deref())
+                }
+                DbxAction::Update(args) => dbx_update(args),
+            },
+            Commands::Migrate(args) => match args.action {
+                MigrateAction::Start(args) => migrate_start(*args),
+                MigrateAction::Finish { force, esp } => migrate_finish(force, &esp),
+                MigrateAction::Restore { esp } => {
+                    migrate::restore(&mut PhysicalEspFilesystem, &esp)
+                }
+            },
+            Commands::MarkGood(_) => staging::mark_good(),
+            Commands::Slot(args) => match args.action {
+                SlotAction::Status { esp } => slots::print_status(&namespace::nixos_dir(&esp)),
+                SlotAction::MarkGood => slots::mark_good(),
+                SlotAction::Switch { tries, slot } => slots::switch(slot, tries),
+            },
+            Commands::SetDefault(args) => staging::set_default(&args.esp, args.generation),
+            Commands::RebootInto(args) => {
+                staging::reboot_into(&args.esp, args.generation, args.no_reboot)
+            }
+            Commands::Addon(args) => addon(args),
+            Commands::Standalone(args) => standalone(args),
+            Commands::Hook(args) => hook(args),
+            Commands::Deployments(args) => deployments(args),
+            Commands::AttestReference(args) => attest_reference(args),
+            Commands::Pcrphase(args) => pcrphase::measure_phase(&args.phase, &args.tpm_device),
+            Commands::CheckOprom(_) => {
+                let devices = oprom::scan(Path::new(oprom::SYSFS_PCI_DEVICES), oprom::read_rom)?;
+                if devices.is_empty() {
+                    log::info!("No option ROMs with UEFI drivers found.");
+                    return Ok(());
+                }
+                for device in &devices {
+                    log::warn!(
+                        "{} ({} {}:{}) has an option ROM with UEFI drivers.",
+                        device.address,
+                        device.kind(),
+                        device.vendor,
+                        device.device
+                    );
+                }
+                log::warn!("Enroll Microsoft's UEFI CA along with your keys, e.g. with `sbctl enroll-keys --microsoft`, or these devices may not initialize with Secure Boot enabled.");
+                std::process::exit(EXIT_OPTION_ROMS_FOUND);
+            }
+            Commands::CheckBitlocker(args) => {
+                if !bitlocker::warn_if_windows(
+                    &PhysicalEspFilesystem,
+                    &args.esp,
+                    "Enrolling your own PK, KEK and db",
+                ) {
+                    log::info!("The Windows Boot Manager is not on the ESP.");
+                    return Ok(());
+                }
+                if args.guidance {
+                    println!("{}", bitlocker::RECOVERY_GUIDANCE);
+                }
+                std::process::exit(EXIT_WINDOWS_FOUND);
+            }
+            Commands::FirmwareUpdate(args) => match args.stage {
+                Some(capsule) => {
+                    firmware_update::stage(&mut PhysicalEspFilesystem, &args.esp, &capsule)
+                }
+                None => firmware_update::boot_updater(&args.esp),
+            },
+            Commands::Daemon(args) => run_daemon(*args),
+            Commands::Dbus(args) => run_dbus(args),
+            Commands::Status(args) => {
+                let paths =
+                    SystemdEspPaths::new(&args.esp, Architecture::from_nixos_system(&args.system)?);
+                let certificate = args
+                    .public_key
+                    .as_deref()
+                    .map(read_der_certificate)
+                    .transpose()?;
+                let status = if args.offline {
+                    Status::read_offline(&paths, certificate.as_deref())
+                } else {
+                    Status::read(&paths, certificate.as_deref())
+                };
+                match (&status, args.json) {
+                    (_, true) => print_report(
+                        "status",
+                        status.as_ref().ok().map(Status::to_json),
+                        status.as_ref().err(),
+                    ),
+                    (Ok(status), false) => status.print(),
+                    (Err(_), false) => {}
+                }
+                status.map(|_| ())
+            }
+            Commands::Du(args) => {
+                let paths =
+                    SystemdEspPaths::new(&args.esp, Architecture::from_nixos_system(&args.system)?);
+                let usage = Usage::read(&paths);
+                match (&usage, args.json) {
+                    (_, true) => print_report(
+                        "du",
+                        usage.as_ref().ok().map(Usage::to_json),
+                        usage.as_ref().err(),
+                    ),
+                    (Ok(usage), false) => usage.print(),
+                    (Err(_), false) => {}
+                }
+                usage.map(|_| ())
+            }
+            Commands::Verify(args) => {
+                let paths =
+                    SystemdEspPaths::new(&args.esp, Architecture::from_nixos_system(&args.system)?);
+                let manifest_verifier = LocalKeyPair::public_only(
+                    args.manifest_public_key
+                        .as_ref()
+                        .unwrap_or(&args.public_key),
+                );
+                let result = status::verify(&paths, &LocalKeyPair::public_only(&args.public_key))
+                    .and_then(|files| {
+                        let problems = manifest::verify(
+                            &PhysicalEspFilesystem,
+                            &args.esp,
+                            &manifest_verifier,
+                            args.manifest,
+                        )?;
+                        Ok((files, problems))
+                    });
+                let (result, error) = match result {
+                    Ok((files, problems)) => {
+                        let unsigned = files.iter().filter(|(_, signed)| !signed).count();
+                        let error = if unsigned > 0 {
+                            Some(anyhow::anyhow!(
+                                "Found {unsigned} {} on the ESP.",
+                                plural(unsigned, "unsigned file", "unsigned files")
+                            ))
+                        } else {
+                            (!problems.is_empty()).then(|| {
+                                anyhow::anyhow!(
+                                    "Found {} {} of the ESP.",
+                                    problems.len(),
+                                    plural(problems.len(), "modification", "modifications")
+                                )
+                            })
+                        };
+                        (Some((files, problems)), error)
+                    }
+                    Err(e) => (None, Some(e)),
+                };
+                if args.json {
+                    print_report(
+                        "verify",
+                        result
+                            .as_ref()
+                            .map(|(files, problems)| report::verify_result(files, problems)),
+                        error.as_ref(),
+                    );
+                } else if let Some((files, problems)) = &result {
+                    for (path, signed) in files {
+                        let state = if *signed { "signed" } else { "NOT SIGNED" };
+                        println!("{state:<10}  {}", path.display());
+                    }
+                    for problem in problems {
+                        println!("{problem}");
+                    }
+                }
+                error.map_or(Ok(()), Err)
+            }
+            Commands::Check(args) => {
+                let (problems, error) = match check_problems(&args) {
+                    Ok(problems) => {
+                        let error = (!problems.is_empty()).then(|| {
+                            anyhow::anyhow!(
+                                "Found {} {} on the ESP.",
+                                problems.len(),
+                                plural(problems.len(), "problem", "problems")
+                            )
+                        });
+                        (Some(problems), error)
+                    }
+                    Err(e) => (None, Some(e)),
+                };
+                if args.json {
+                    print_report(
+                        "check",
+                        problems.as_deref().map(report::check_result),
+                        error.as_ref(),
+                    );
+                } else if let Some(problems) = &problems {
+                    if problems.is_empty() {
+                        println!("All kernels and initrds on the ESP are intact.");
+                    }
+                    for problem in problems {
+                        println!("{problem}");
+                    }
+                }
+                error.map_or(Ok(()), Err)
+            }
+            Commands::SelfTest(args) => {
+                let lanzaboote_stub = std::env::var("LANZABOOTE_STUB")
+                    .context("Failed to read LANZABOOTE_STUB env variable")?;
+                let qemu = args
+                    .qemu
+                    .zip(args.firmware)
+                    .map(|(binary, firmware)| Qemu { binary, firmware });
+                self_test::run(
+                    Path::new(&lanzaboote_stub),
+                    &LocalKeyPair::new(&args.public_key, &args.private_key),
+                    Architecture::from_nixos_system(&args.system)?,
+                    qemu.as_ref(),
+                )
+            }
+            Commands::VmTest(args) => {
+                let lanzaboote_stub = std::env::var("LANZABOOTE_STUB")
+                    .context("Failed to read LANZABOOTE_STUB env variable")?;
+                vm_test::run(
+                    Path::new(&lanzaboote_stub),
+                    Architecture::from_nixos_system(&args.system)?,
+                    &args.systemd,
+                    &args.systemd_boot_loader_config,
+                    LocalKeyPair::new(&args.public_key, &args.private_key),
+                    profile::generation_links(&profile::profile_path(&args.profile))?,
+                    args.generation,
+                    &VmFirmware {
+                        qemu: args.qemu,
+                        code: args.firmware,
+                        vars: args.firmware_vars,
+                    },
+                )
+            }
+            Commands::Export(args) => {
+                export::export(&PhysicalEspFilesystem, &args.esp, &args.output, args.format)
+            }
+            Commands::Import(args) => {
+                let _lock = InstallLock::acquire(&lock::default_lock_file(), args.wait)?;
+                export::import(&mut PhysicalEspFilesystem, &args.esp, &args.archive)
+            }
+            Commands::BackupKeys(args) => {
+                let encryption = match (args.age_recipient, args.gpg_recipient) {
+                    (age, _) if !age.is_empty() => Encryption::Age(age),
+                    (_, gpg) if !gpg.is_empty() => Encryption::Gpg(gpg),
+                    _ => Encryption::AgePassphrase,
+                };
+                key_backup::backup(&args.pki_bundle, &encryption, &args.output)
+            }
+            Commands::RestoreKeys(args) => key_backup::restore(
+                &args.pki_bundle,
+                &args.backup,
+                args.identity.as_deref(),
+                args.force,
+            ),
+            Commands::FixPermissions(args) => fix_permissions(args),
+            Commands::Inspect(args) => {
+                let esp = args
+                    .esp
+                    .clone()
+                    .or_else(|| inspect::find_esp(&args.file).map(Path::to_path_buf))
+                    .unwrap_or_else(|| PathBuf::from("/boot"));
+                let verifier = args.public_key.as_deref().map(LocalKeyPair::public_only);
+                let inspection = Inspection::read(&args.file, &esp, verifier.as_ref())?;
+                if args.json {
+                    println!("{:#}", inspection.to_json());
+                } else {
+                    inspection.print();
+                }
+                if !inspection.discrepancies.is_empty() {
+                    let count = inspection.discrepancies.len();
+                    bail!(
+                        "Found {count} {}.",
+                        plural(count, "discrepancy", "discrepancies")
+                    );
+                }
+                Ok(())
+            }
+            Commands::CheckDb(args) => {
+                let certificate = args
+                    .public_key
+                    .as_deref()
+                    .map(read_der_certificate)
+                    .transpose()?;
+                check_db::check_db(&check_db::Databases::read()?, certificate.as_deref());
+                Ok(())
+            }
+            Commands::Completions(args) => {
+                completions::print(&completions::registration(args.shell)?)
+            }
+            Commands::Man(_) => completions::print(&completions::man_page()?),
+            Commands::Diff(args) => {
+                let from = diff::EntrySummary::from_stub(&diff::find_stub(&args.esp, args.from)?)?;
+                let to = diff::EntrySummary::from_stub(&diff::find_stub(&args.esp, args.to)?)?;
+                diff::print_diff(&from, &to, args.json);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Print the JSON report of a command, see [`report`].
+fn print_report(command: &str, result: Option<serde_json::Value>, error: Option<&anyhow::Error>) {
+    println!("{:#}", report::report(command, result, error));
+}
+
+/// The damaged or modified files on the ESP, see [`CheckCommand`].
+fn check_problems(args: &CheckCommand) -> Result<Vec<String>> {
+    let mut problems = check::check_entries(&args.esp)?;
+    problems.extend(check::check_content_addressed(&args.esp)?);
+    if args.deep {
+        let links = profile::generation_links(&profile::profile_path(&args.profile))?;
+        problems.extend(check::check_store(&args.esp, &links)?);
+    }
+    Ok(problems)
+}
+
+fn install(mut args: InstallCommand) -> Result<metrics::InstallOutcome> {
+    args.resolve_esp()?;
+    args.resolve_keys()?;
+    args.check_system()?;
+    let _lock = args.lock()?;
+    install_locked(args)
+}
+
+/// Install while the caller holds the lock, see [`InstallCommand::lock`].
+fn install_locked(args: InstallCommand) -> Result<metrics::InstallOutcome> {
+    match &args.esp_device {
+        Some(device) => {
+            let esp_fs = FatEspFilesystem::open(device, &args.esp)?;
+            install_to(args, esp_fs)
+        }
+        None => install_to(args, PhysicalEspFilesystem),
+    }
+}
+
+/// Create the image of [`BuildEspImageCommand`] and install into it. The image is removed again
+/// if anything fails, so that a rerun of the pipeline starts from scratch.
+fn build_esp_image(mut args: BuildEspImageCommand) -> Result<metrics::InstallOutcome> {
+    if args.install.esp_device.is_some() {
+        bail!("Pass the image with --output instead of --esp-device.");
+    }
+    if args.install.tentative || args.install.attestation_hook.is_some() {
+        bail!("--tentative and --attestation-hook need the ESP of a running system.");
+    }
+    args.install.esp_device = Some(args.output.clone());
+    args.install.resolve_esp()?;
+    args.install.resolve_keys()?;
+    args.install.check_system()?;
+
+    let size = args
+        .size
+        .checked_mul(1 << 20)
+        .context("The image is too large")?;
+    let image = fs::File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&args.output)
+        .with_context(|| format!("Failed to create the image {:?}", args.output))?;
+    let outcome = image
+        .set_len(size)
+        .context("Failed to allocate the image")
+        .and_then(|()| {
+            fat::format(
+                &image,
+                0,
+                size,
+                &args.label,
+                args.volume_id.unwrap_or_else(fat::default_volume_id),
+            )
+        })
+        .and_then(|()| install_locked(args.install));
+    if outcome.is_err() {
+        let _ = fs::remove_file(&args.output);
+    }
+    outcome
+}
+
+fn install_to(args: InstallCommand, esp_fs: impl EspFilesystem) -> Result<metrics::InstallOutcome> {
+    let metrics_file = args.metrics_file.clone();
+    // The free space can only be determined on the mounted ESP.
+    let mounted_esp = args.esp_device.is_none().then(|| args.esp.clone());
+
+    let outcome = install_and_attest(args, esp_fs);
+    if let Some(metrics_file) = metrics_file {
+        if let Err(e) =
+            metrics::update(&metrics_file, mounted_esp.as_deref(), outcome.as_ref().ok())
+        {
+            log::warn!("Failed to update the metrics: {e:#}");
+        }
+    }
+    outcome
+}
+
+fn install_and_attest(
+    args: InstallCommand,
+    esp_fs: impl EspFilesystem,
+) -> Result<metrics::InstallOutcome> {
+    let esp = args.esp.clone();
+    let attestation_hook = args.attestation_hook.clone();
+    // The slot variables of another machine are out of reach, so its slot A is assumed active.
+    let slot_variables = args.ab_slots && args.esp_device.is_none();
+    let active_slot = match (args.ab_slots, slot_variables) {
+        (true, true) => Some(slots::active_slot()?),
+        (true, false) => Some(slots::Slot::A),
+        (false, _) => None,
+    };
+    let slot_tries = args.slot_tries;
+
+    let installer = if args.tentative {
+        let known_good = staging::known_good_generation()?;
+        let mut installer = installer(args, esp_fs)?.with_known_good_generation(known_good);
+        installer.install()?;
+        staging::stage(installer.entries(), known_good)?;
+        installer
+    } else {
+        let mut installer = installer(args, esp_fs)?.with_ab_slots(active_slot);
+        installer.install()?;
+        installer
+    };
+    if let Some(slot) = installer.slot_to_activate() {
+        if slot_variables {
+            slots::switch(slot, Some(slot_tries))?;
+        } else {
+            log::warn!("Installed slot {slot}, but cannot make it active on another machine.");
+        }
+    }
+
+    if let Some(hook) = attestation_hook {
+        attest::run_hook(&hook, &esp)
+            .context("Failed to pass the attestation reference values to the hook")?;
+    }
+    Ok(metrics::InstallOutcome {
+        entries: installer.entries().clone(),
+        statistics: installer.statistics(),
+        signed_files: installer.signed_files()?,
+        removed_files: installer.removed_files().to_vec(),
+        cmdline_changes: installer.cmdline_changes().to_vec(),
+    })
+}
+
+fn run_daemon(mut args: DaemonCommand) -> Result<()> {
+    args.install.resolve_esp()?;
+    args.install.resolve_keys()?;
+    let listener = daemon::listen(&args.socket)?;
+    let authorize =
+        |peer: &daemon::Peer, action: &str| polkit::check_authorization(peer.pid, peer.uid, action);
+    daemon::serve(&listener, authorize, |call| match call.method.as_str() {
+        "Install" | "CollectGarbage" => {
+            let mut install_args = args.install.clone();
+            if let Some(tentative) = call.parameters["tentative"].as_bool() {
+                install_args.tentative = tentative;
+            }
+            let outcome = install(install_args)?;
+            if call.method == "CollectGarbage" {
+                return Ok(json!({}));
+            }
+            Ok(json!({
+                "entries": outcome.entries,
+                "filesWritten": outcome.statistics.files_written,
+                "signaturesCreated": outcome.statistics.signatures_created,
+            }))
+        }
+        "Sign" => {
+            let path = |name: &str| {
+                call.parameters[name]
+                    .as_str()
+                    .map(PathBuf::from)
+                    .with_context(|| format!("Missing parameter {name}"))
+            };
+            args.install
+                .signer()?
+                .sign_and_copy(&path("input")?, &path("output")?)?;
+            Ok(json!({}))
+        }
+        "Status" => {
+            let certificate = args.install.certificate()?;
+            Ok(Status::read(&args.install.esp_paths()?, certificate.as_deref())?.to_json())
+        }
+        "Verify" => {
+            let files = status::verify(&args.install.esp_paths()?, &args.install.verifier()?)?;
+            let problems = manifest::verify(
+                &PhysicalEspFilesystem,
+                &args.install.esp,
+                &args.install.manifest_verifier()?,
+                args.install.manifest,
+            )?;
+            Ok(report::verify_result(&files, &problems))
+        }
+        "RebootInto" => {
+            let generation = call.parameters["generation"]
+                .as_u64()
+                .context("Missing parameter generation")?;
+            let no_reboot = call.parameters["noReboot"].as_bool().unwrap_or(false);
+            staging::reboot_into(&args.install.esp, generation, no_reboot)?;
+            Ok(json!({}))
+        }
+        method => bail!("Unknown method {method}"),
+    })
+}
+
+fn run_dbus(args: DbusCommand) -> Result<()> {
+    let paths = Arc::new(SystemdEspPaths::new(
+        &args.esp,
+        Architecture::from_nixos_system(&args.system)?,
+    ));
+    let certificate = args
+        .public_key
+        .as_deref()
+        .map(read_der_certificate)
+        .transpose()?;
+    let verify_paths = Arc::clone(&paths);
+    dbus_service::serve(
+        move || Status::read(&paths, certificate.as_deref()),
+        move || {
+            let Some(public_key) = &args.public_key else {
+                bail!("No certificate to verify with. Pass --public-key.");
+            };
+            status::verify(&verify_paths, &LocalKeyPair::public_only(public_key))
+        },
+    )
+}
+
+/// The certificates to pin in the stub, concatenated, after checking that the threshold can be met.
+fn authcert(certificates: &[PathBuf], threshold: Option<usize>) -> Result<Option<Vec<u8>>> {
+    if let Some(threshold) = threshold {
+        if threshold == 0 || threshold > certificates.len() {
+            bail!(
+                "The --authcert-threshold must be between 1 and the number of certificates ({}).",
+                certificates.len()
+            );
         }
     }
+    if certificates.is_empty() {
+        return Ok(None);
+    }
+    let certificates = certificates
+        .iter()
+        .map(|path| read_der_certificate(path))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(certificates.concat()))
 }
 
-fn install(args: InstallCommand) -> Result<()> {
+fn installer<F: EspFilesystem>(
+    args: InstallCommand,
+    esp_fs: F,
+) -> Result<install::Installer<LocalKeyPair, F>> {
     let lanzaboote_stub =
         std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
 
-    let local_signer = LocalKeyPair::new(
-        &args.public_key.expect("Failed to obtain public key"),
-        &args.private_key.expect("Failed to obtain private key"),
-    );
+    let generation_links = args.generation_links()?;
+    let local_signer = args.signer()?;
+    let manifest_key = args.manifest_key()?;
 
-    install::Installer::new(
+    Ok(install::Installer::new(
         PathBuf::from(lanzaboote_stub),
         Architecture::from_nixos_system(&args.system)?,
         args.systemd,
@@ -103,7 +2300,399 @@ fn install(args: InstallCommand) -> Result<()> {
         local_signer,
         args.configuration_limit,
         args.esp,
-        args.generations,
+        esp_fs,
+        generation_links,
+    )
+    .with_selected_generations(args.only)
+    .with_manifest(manifest_key)
+    .with_cache(args.cache_dir.as_deref().map(|dir| {
+        let cache = InstallCache::open(dir);
+        if args.blake3 {
+            cache.with_blake3()
+        } else {
+            cache
+        }
+    }))
+    .with_other_os(args.other_os)
+    .with_firmware_updater(args.firmware_updater)
+    .with_hooks(args.hooks)
+    .with_cmdline_fragments(CmdlineFragments::read(
+        args.cmdline_file.as_deref(),
+        args.cmdline_overrides.as_deref(),
+        args.cmdline_dir.as_deref(),
+        &args.append_cmdline,
+    )?)
+    .with_simulate_secure_boot(args.simulate_secure_boot)
+    .with_first_stage(args.first_stage)
+    .with_payload_partition(args.payload_dir.zip(args.payload_partition))
+    .with_fs_drivers(args.fs_drivers)
+    .with_payload_luks(
+        args.payload_luks
+            .then(|| -> Result<_> {
+                Ok(LuksUnlock {
+                    tpm2_handle: args.payload_luks_tpm2_handle,
+                    tpm2_pcrs: args
+                        .payload_luks_tpm2_pcrs
+                        .as_deref()
+                        .map(LuksUnlock::parse_tpm2_pcrs)
+                        .transpose()?
+                        .unwrap_or_default(),
+                })
+            })
+            .transpose()?,
+    )
+    .with_insecure_boot_policy(args.insecure_boot_policy)
+    .with_stub_verbosity(args.stub_verbosity)
+    .with_stub_locale(args.stub_locale)
+    .with_entry_title(args.entry_title)
+    .with_entry_sort(args.entry_sort)
+    .with_clear_screen(args.clear_screen)
+    .with_serial_console(args.serial_console)
+    .with_stub_log_file(args.stub_log_file)
+    .with_splash(args.splash)
+    .with_recovery_entries(args.recovery_entries)
+    .with_watchdog_timeout(args.watchdog_timeout)
+    .with_allow_smbios_cmdline(args.allow_smbios_cmdline)
+    .with_allow_fw_cfg(args.allow_fw_cfg)
+    .with_instance_metadata(args.instance_metadata)
+    .with_refuse_mismatched_resume(args.refuse_mismatched_resume)
+    .with_clock_check(args.clock_check)
+    .with_lockdown(args.lockdown, args.module_sig_enforce)
+    .with_namespace(
+        args.namespace_by_machine_id
+            .then(|| namespace::read_machine_id(Path::new(namespace::MACHINE_ID_FILE)))
+            .transpose()?,
+    )
+    .with_initrd_steps(args.initrd_steps)
+    .with_initrd_key(
+        args.initrd_key
+            .as_deref()
+            .map(InitrdKey::load_or_provision)
+            .transpose()?,
+    )
+    .with_authcert(authcert(&args.authcert, args.authcert_threshold)?)
+    .with_authcert_threshold(args.authcert_threshold)
+    .with_revocation_list(match &args.revocation_list {
+        Some(path) => RevocationList::load(path)?,
+        None => RevocationList::default(),
+    }))
+}
+
+fn attest_reference(args: AttestReferenceCommand) -> Result<()> {
+    let mut references = attest::attestation_reference(&args.esp)?;
+    if args.keylime {
+        references = attest::keylime_tpm_policy(&references);
+    }
+    let references = format!("{references:#}\n");
+    match args.output {
+        Some(output) => fs::write(&output, references)
+            .with_context(|| format!("Failed to write reference values to {output:?}")),
+        None => {
+            print!("{references}");
+            Ok(())
+        }
+    }
+}
+
+fn addon(args: AddonCommand) -> Result<()> {
+    if !args.output.to_string_lossy().ends_with(".addon.efi") {
+        bail!("The stub only discovers addons whose name ends with .addon.efi");
+    }
+
+    let addon_stub = args
+        .systemd
+        .join("lib/systemd/boot/efi")
+        .join(Architecture::from_nixos_system(&args.system)?.systemd_addon_stub_filename());
+    let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+    let image = pe::addon_image(
+        &tempdir,
+        &addon_stub,
+        args.cmdline.as_deref(),
+        args.initrd.as_deref(),
+    )
+    .context("Failed to assemble the addon")?;
+
+    LocalKeyPair::new(&args.public_key, &args.private_key)
+        .with_timestamp_url(args.timestamp_url)
+        .sign_and_copy(&image, &args.output)?;
+    log::info!("Successfully built the addon {:?}.", args.output);
+    Ok(())
+}
+
+fn standalone(args: StandaloneCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    let os_release = fs::read_to_string(&args.os_release)
+        .with_context(|| format!("Failed to read {:?}", args.os_release))?;
+    let entry = standalone::StandaloneEntry {
+        kernel: args.kernel,
+        initrd: args.initrd,
+        cmdline: args.cmdline.split_whitespace().map(String::from).collect(),
+        os_release,
+        output: args.out,
+    };
+
+    let key_files = KeyFiles::new()
+        .with_public_key(&args.public_key)
+        .with_private_key(&args.private_key);
+    key_permissions::check(&key_files, args.insecure_permissions)?;
+    let signer = LocalKeyPair::new(&args.public_key, &args.private_key)
+        .with_timestamp_url(args.timestamp_url);
+    let installed = standalone::install(
+        &mut PhysicalEspFilesystem,
+        &signer,
+        Path::new(&lanzaboote_stub),
+        &args.esp,
+        &entry,
+    )?;
+    for path in installed {
+        log::info!("Installed {path:?}.");
+    }
+    Ok(())
+}
+
+fn fix_permissions(args: FixPermissionsCommand) -> Result<()> {
+    let mut key_files = KeyFiles::new();
+    if let Some(private_key) = &args.private_key {
+        key_files = key_files.with_private_key(private_key);
+    }
+    if let Some(public_key) = &args.public_key {
+        key_files = key_files.with_public_key(public_key);
+    }
+    match &args.pki_bundle {
+        Some(pki_bundle) => key_files = key_files.with_pki_bundle(pki_bundle)?,
+        None if args.private_key.is_none() && args.public_key.is_none() => {
+            key_files = key_files.with_pki_bundle(Path::new("/var/lib/sbctl"))?
+        }
+        None => {}
+    }
+    for path in key_files.fix()? {
+        log::info!("Fixed the permissions of {path:?}.");
+    }
+    Ok(())
+}
+
+fn hook(args: HookCommand) -> Result<()> {
+    let (system, signing, change) = match args.action {
+        HookAction::KernelInstall(args) => {
+            let boot_root = std::env::var_os("KERNEL_INSTALL_BOOT_ROOT").map(PathBuf::from);
+            let staging_area = std::env::var_os("KERNEL_INSTALL_STAGING_AREA").map(PathBuf::from);
+            let system = kernel_hooks::System {
+                root: PathBuf::from("/"),
+                esp: args.esp.or(boot_root).unwrap_or_else(|| "/boot".into()),
+                entry_token: std::env::var("KERNEL_INSTALL_ENTRY_TOKEN").ok(),
+            };
+            let change = KernelInstallArgs {
+                command: args.command,
+                version: args.kernel_version,
+                entry_dir: args.entry_dir,
+                image: args.kernel_image,
+                initrds: args.initrds,
+            }
+            .change(staging_area.as_deref())?;
+            (system, args.signing, Some(change))
+        }
+        HookAction::Dpkg(args) => {
+            let root = PathBuf::from("/");
+            let change =
+                kernel_hooks::dpkg_change(&root, args.event, args.kernel_version, args.path);
+            let system = kernel_hooks::System {
+                root,
+                esp: args.esp,
+                entry_token: None,
+            };
+            (system, args.signing, change)
+        }
+    };
+
+    let installed = match change {
+        None => {
+            log::info!("The initrd was not generated yet, so the kernel is installed once it is.");
+            return Ok(());
+        }
+        Some(KernelChange::Remove { version }) => {
+            for path in system.remove_kernel(&mut PhysicalEspFilesystem, &version)? {
+                log::info!("Removed {path:?}.");
+            }
+            return Ok(());
+        }
+        Some(KernelChange::Add(kernel)) => {
+            let lanzaboote_stub = std::env::var("LANZABOOTE_STUB")
+                .context("Failed to read LANZABOOTE_STUB env variable")?;
+            let signer = signing.key_pair()?;
+            system.install_kernel(
+                &mut PhysicalEspFilesystem,
+                &signer,
+                Path::new(&lanzaboote_stub),
+                &kernel,
+            )?
+        }
+    };
+    for path in installed {
+        log::info!("Installed {path:?}.");
+    }
+    Ok(())
+}
+
+fn deployments(args: DeploymentsCommand) -> Result<()> {
+    let deployments = match (&args.ostree, &args.manifest) {
+        (Some(sysroot), _) => Deployments::from_ostree(sysroot)?,
+        (None, Some(manifest)) => {
+            let manifest =
+                fs::read(manifest).with_context(|| format!("Failed to read {manifest:?}"))?;
+            Deployments::from_manifest(&manifest)?
+        }
+        (None, None) => unreachable!("clap requires a source"),
+    };
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    let signer = args.signing.key_pair()?;
+    let changed = deployments.install(
+        &mut PhysicalEspFilesystem,
+        &signer,
+        Path::new(&lanzaboote_stub),
+        &args.esp,
+    )?;
+    for path in changed.installed {
+        log::info!("Installed {path:?}.");
+    }
+    for path in changed.removed {
+        log::info!("Removed {path:?}.");
+    }
+    Ok(())
+}
+
+fn revoke(args: RevokeCommand) -> Result<()> {
+    let mut revocation_list = RevocationList::load(&args.revocation_list)?;
+
+    if args.hashes.is_empty() {
+        print!("{revocation_list}");
+        return Ok(());
+    }
+
+    let mut changed = false;
+    for argument in &args.hashes {
+        let hash = hash_from_argument(argument)?;
+        if args.remove {
+            changed |= revocation_list.remove(&hash);
+            log::info!(
+                "Removing {} from the revocation list...",
+                format_hash(&hash)
+            );
+        } else {
+            changed |= revocation_list.insert(hash);
+            log::info!("Revoking {}...", format_hash(&hash));
+        }
+    }
+
+    if !changed {
+        log::info!("The revocation list is unchanged.");
+        return Ok(());
+    }
+    revocation_list.save(&args.revocation_list)?;
+
+    if args.no_reinstall {
+        log::warn!("Stubs are only re-signed with the new revocation list on the next rebuild.");
+        return Ok(());
+    }
+    reinstall_boot_loader()
+}
+
+/// Re-install the boot loader of the system profile, which re-signs all stubs.
+fn reinstall_boot_loader() -> Result<()> {
+    let switch = Path::new(SYSTEM_PROFILE).join("bin/switch-to-configuration");
+    log::info!("Re-installing the boot loader to re-sign the stubs...");
+    let status = Command::new(&switch)
+        .arg("boot")
+        .status()
+        .with_context(|| format!("Failed to run {switch:?}"))?;
+    if !status.success() {
+        bail!("Failed to re-install the boot loader: {switch:?} exited with {status}");
+    }
+    Ok(())
+}
+
+fn dbx_update(args: DbxUpdateCommand) -> Result<()> {
+    let kek = match (args.kek_private_key, args.kek_public_key) {
+        (Some(private_key), Some(public_key)) => Some(Kek {
+            private_key,
+            public_key,
+        }),
+        _ => None,
+    };
+
+    dbx::update(
+        &PhysicalEspFilesystem,
+        &args.esp,
+        &args.update,
+        kek.as_ref(),
+        &args.backup_dir,
+        args.dry_run,
     )
-    .install()
+}
+
+fn migrate_start(args: MigrateStartCommand) -> Result<()> {
+    let mut install = args.install;
+    install.resolve_esp()?;
+    install.resolve_keys()?;
+    let esp = install.esp.clone();
+    install.check_system()?;
+    // The backup must not race with another installation either.
+    let _lock = install.lock()?;
+    let mut esp_fs = PhysicalEspFilesystem;
+
+    let Some(layout) = ExistingLayout::detect(&esp_fs, &esp)? else {
+        // The old boot loader is removed by the migration, so this allows always running it, e.g.
+        // from the NixOS module.
+        log::info!("No existing boot loader installation found. Installing normally...");
+        return install_locked(install).map(|_| ());
+    };
+    log::info!(
+        "Migrating from {} with entries for the generations {:?}...",
+        layout.bootloader.name(),
+        layout.generations
+    );
+
+    let backup_dir = esp.join(migrate::BACKUP_DIR);
+    if esp_fs.exists(&backup_dir) {
+        // An earlier migration failed before it removed the old boot loader.
+        log::info!("Keeping the existing backup in {backup_dir:?}.");
+    } else {
+        layout.backup(&mut esp_fs, &esp)?;
+    }
+    if let Err(e) = install_locked(install) {
+        // The installation may have replaced files of the old layout already.
+        log::error!(
+            "The migration failed. The old boot loader is backed up in {backup_dir:?} and can be \
+             restored with `lzbt migrate restore`."
+        );
+        return Err(e);
+    }
+    layout.remove_stale(&mut esp_fs)?;
+
+    log::info!(
+        "Successfully migrated to Lanzaboote. The old boot loader is backed up in {:?}. Run \
+         `lzbt migrate finish` after Lanzaboote booted successfully to remove it.",
+        backup_dir
+    );
+    Ok(())
+}
+
+fn migrate_finish(force: bool, esp: &Path) -> Result<()> {
+    if !force {
+        match read_string_variable("LoaderEntrySelected", &LOADER_GUID)? {
+            Some(entry) if migrate::is_lanzaboote_entry(&entry) => {}
+            Some(entry) => bail!(
+                "The system was booted from {entry:?}, not from a Lanzaboote entry. Boot a \
+                 Lanzaboote entry first, or pass --force."
+            ),
+            None => bail!(
+                "Failed to determine the booted entry. Pass --force if a Lanzaboote entry booted."
+            ),
+        }
+    }
+
+    migrate::remove_backup(&mut PhysicalEspFilesystem, esp)?;
+    log::info!("Removed the backup of the old boot loader.");
+    Ok(())
 }
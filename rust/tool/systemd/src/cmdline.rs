@@ -0,0 +1,430 @@
+//! The kernel command line of an entry, composed from several sources.
+//!
+//! The sources are appended in this order:
+//!
+//! 1. `init=` and the kernel parameters of the bootspec,
+//! 2. the command line file (`--cmdline-file`), which applies to every generation,
+//! 3. the matching lines of the overrides file (`--cmdline-overrides`), in the order of the file,
+//! 4. the matching lines of the `*.conf` fragments in the fragments directory (`--cmdline-dir`,
+//!    e.g. `/etc/lanzaboote/cmdline.d`), in the order of their names,
+//! 5. the parameters of `--append-cmdline`.
+//!
+//! The kernel and systemd use the last value of a parameter that is given more than once, so later
+//! sources take precedence. A parameter that is repeated verbatim is only kept at its last
+//! position.
+//!
+//! Lines of the overrides file are `<generations> [<specialisation>]: <parameters>`, where
+//! `<generations>` is a generation, a range like `10-20` or `10-`, or `*` for all of them. Without
+//! a specialisation, the line applies to the default entries only. Empty lines and lines starting
+//! with `#` are ignored in all files. Fragments use the format of the overrides file.
+//!
+//! The parameters from these sources are part of the name of the stub of an entry, so entries
+//! whose command line changed are assembled and signed again. The installer reports them as
+//! [`CmdlineChange`]s before it writes anything.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use lanzaboote_tool::generation::GenerationRange;
+
+/// The extra parameters of the entries.
+#[derive(Debug, Clone, Default)]
+pub struct CmdlineFragments {
+    file: Vec<String>,
+    overrides: Vec<Override>,
+    append: Vec<String>,
+}
+
+/// A line of the overrides file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Override {
+    /// The generations the line applies to, or all of them.
+    generations: Option<GenerationRange>,
+    specialisation: Option<String>,
+    parameters: Vec<String>,
+}
+
+impl CmdlineFragments {
+    /// Read the command line file, the overrides file and the fragments directory.
+    ///
+    /// Files that do not exist are treated as empty, so that they can be created by the
+    /// administrator at any time.
+    pub fn read(
+        file: Option<&Path>,
+        overrides: Option<&Path>,
+        directory: Option<&Path>,
+        append: &[String],
+    ) -> Result<Self> {
+        let file = match file.map(read_optional).transpose()?.flatten() {
+            Some(contents) => lines(&contents).flat_map(split_parameters).collect(),
+            None => Vec::new(),
+        };
+        let mut override_files = overrides
+            .map(Path::to_path_buf)
+            .into_iter()
+            .collect::<Vec<_>>();
+        if let Some(directory) = directory {
+            override_files.extend(fragment_files(directory)?);
+        }
+        let mut overrides = Vec::new();
+        for path in override_files {
+            if let Some(contents) = read_optional(&path)? {
+                overrides.extend(parse_overrides(&contents).with_context(|| {
+                    format!("Failed to parse the command line overrides {path:?}")
+                })?);
+            }
+        }
+        Ok(Self {
+            file,
+            overrides,
+            append: append.iter().flat_map(|a| split_parameters(a)).collect(),
+        })
+    }
+
+    /// The parameters that are added to the command line of the bootspec of an entry.
+    ///
+    /// `specialisation` is the name of the specialisation of the entry, if it is one.
+    pub fn extra(&self, generation: u64, specialisation: Option<&str>) -> Vec<String> {
+        let overrides = self
+            .overrides
+            .iter()
+            .filter(|o| {
+                o.generations.is_none_or(|range| range.contains(generation))
+                    && o.specialisation.as_deref() == specialisation
+            })
+            .flat_map(|o| o.parameters.iter().cloned());
+
+        self.file
+            .iter()
+            .cloned()
+            .chain(overrides)
+            .chain(self.append.iter().cloned())
+            .collect()
+    }
+
+    /// Compose the command line of an entry from the one of its bootspec.
+    ///
+    /// `specialisation` is the name of the specialisation of the entry, if it is one.
+    pub fn compose(
+        &self,
+        generation: u64,
+        specialisation: Option<&str>,
+        bootspec_cmdline: Vec<String>,
+    ) -> Vec<String> {
+        let composed = bootspec_cmdline
+            .into_iter()
+            .chain(self.extra(generation, specialisation))
+            .collect::<Vec<_>>();
+        // Keep only the last of verbatim repetitions.
+        let mut cmdline = Vec::new();
+        for (index, parameter) in composed.iter().enumerate() {
+            if !composed[index + 1..].contains(parameter) {
+                cmdline.push(parameter.clone());
+            }
+        }
+        cmdline
+    }
+}
+
+/// Parameters that are appended to the composed command line when the stub is assembled, e.g. for
+/// dm-verity or the lockdown policy. They do not come from the fragments.
+const APPENDED_PARAMS: [&str; 3] = ["roothash=", "lockdown=", "module.sig_enforce="];
+
+/// An entry whose command line differs from the one of its installed stub, which is therefore
+/// assembled and signed again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdlineChange {
+    pub generation: u64,
+    pub specialisation: Option<String>,
+    pub previous: Vec<String>,
+    pub current: Vec<String>,
+}
+
+impl CmdlineChange {
+    /// Compare the `.cmdline` section of the installed stub of an entry with its composed command
+    /// line. Returns `None` if they are the same.
+    pub fn detect(
+        generation: u64,
+        specialisation: Option<&str>,
+        installed: &str,
+        current: Vec<String>,
+    ) -> Option<Self> {
+        let previous = split_parameters(installed);
+        if without_appended(&previous) == without_appended(&current) {
+            return None;
+        }
+        Some(Self {
+            generation,
+            specialisation: specialisation.map(str::to_string),
+            previous: without_appended(&previous).to_vec(),
+            current: without_appended(&current).to_vec(),
+        })
+    }
+
+    /// The parameters that are no longer on the command line.
+    pub fn removed(&self) -> Vec<&str> {
+        difference(&self.previous, &self.current)
+    }
+
+    /// The parameters that are new on the command line.
+    pub fn added(&self) -> Vec<&str> {
+        difference(&self.current, &self.previous)
+    }
+
+    pub fn describe(&self) -> String {
+        let entry = match &self.specialisation {
+            Some(specialisation) => {
+                format!(
+                    "Specialisation {specialisation} of generation {}",
+                    self.generation
+                )
+            }
+            None => format!("Generation {}", self.generation),
+        };
+        let mut changes = Vec::new();
+        if !self.removed().is_empty() {
+            changes.push(format!("removed {}", self.removed().join(" ")));
+        }
+        if !self.added().is_empty() {
+            changes.push(format!("added {}", self.added().join(" ")));
+        }
+        if changes.is_empty() {
+            changes.push("reordered".to_string());
+        }
+        format!(
+            "{entry} is assembled and signed again, because its command line changed: {}",
+            changes.join(", ")
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "generation": self.generation,
+            "specialisation": self.specialisation,
+            "previous": self.previous,
+            "current": self.current,
+            "removed": self.removed(),
+            "added": self.added(),
+        })
+    }
+}
+
+/// The parameters without the ones appended when the stub is assembled.
+fn without_appended(parameters: &[String]) -> &[String] {
+    let end = parameters
+        .iter()
+        .rposition(|p| !APPENDED_PARAMS.iter().any(|a| p.starts_with(a)))
+        .map_or(0, |last| last + 1);
+    &parameters[..end]
+}
+
+/// The parameters of `a` that are not in `b`.
+fn difference<'a>(a: &'a [String], b: &[String]) -> Vec<&'a str> {
+    a.iter()
+        .filter(|p| !b.contains(p))
+        .map(String::as_str)
+        .collect()
+}
+
+/// The `*.conf` files in the fragments directory, sorted by name.
+fn fragment_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::debug!("{directory:?} does not exist.");
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {directory:?}")),
+    };
+    let mut files = entries
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("Failed to read {directory:?}"))?;
+    files.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "conf")
+    });
+    files.sort();
+    Ok(files)
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::debug!("{path:?} does not exist.");
+            Ok(None)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+/// The lines of a file without empty lines and comments.
+fn lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn parse_overrides(contents: &str) -> Result<Vec<Override>> {
+    lines(contents)
+        .map(|line| {
+            let Some((selector, parameters)) = line.split_once(':') else {
+                bail!("Missing colon in {line:?}");
+            };
+            let mut selector = selector.split_whitespace();
+            let generations = match selector.next() {
+                Some("*") => None,
+                Some(range) => Some(range.parse()?),
+                None => bail!("Missing generations in {line:?}"),
+            };
+            let specialisation = selector.next().map(str::to_string);
+            if selector.next().is_some() {
+                bail!("Too many words before the colon in {line:?}");
+            }
+            Ok(Override {
+                generations,
+                specialisation,
+                parameters: split_parameters(parameters),
+            })
+        })
+        .collect()
+}
+
+/// Split a command line into parameters, keeping double-quoted values like `a="b c"` together.
+fn split_parameters(cmdline: &str) -> Vec<String> {
+    let mut parameters = Vec::new();
+    let mut parameter = String::new();
+    let mut quoted = false;
+    for c in cmdline.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                parameter.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !parameter.is_empty() {
+                    parameters.push(std::mem::take(&mut parameter));
+                }
+            }
+            c => parameter.push(c),
+        }
+    }
+    if !parameter.is_empty() {
+        parameters.push(parameter);
+    }
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compose_in_order_of_precedence() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("cmdline");
+        let overrides = dir.path().join("cmdline-overrides");
+        fs::write(&file, "# For all generations\nquiet console=ttyS0\n")?;
+        fs::write(
+            &overrides,
+            "* : loglevel=4\n10-: mitigations=off\n12 gaming: nvidia-drm.modeset=1\n",
+        )?;
+        let fragments = CmdlineFragments::read(
+            Some(&file),
+            Some(&overrides),
+            None,
+            &["loglevel=7 dyndbg=\"file x.c +p\"".to_string()],
+        )?;
+
+        let bootspec_cmdline = vec!["init=/nix/store/init".to_string(), "quiet".to_string()];
+
+        assert_eq!(
+            fragments.compose(12, None, bootspec_cmdline.clone()),
+            [
+                "init=/nix/store/init",
+                "quiet",
+                "console=ttyS0",
+                "loglevel=4",
+                "mitigations=off",
+                "loglevel=7",
+                "dyndbg=\"file x.c +p\"",
+            ]
+        );
+
+        assert_eq!(
+            fragments.compose(12, Some("gaming"), bootspec_cmdline)[2..],
+            [
+                "console=ttyS0",
+                "nvidia-drm.modeset=1",
+                "loglevel=7",
+                "dyndbg=\"file x.c +p\""
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_overrides() {
+        assert!(parse_overrides("42 quiet").is_err());
+        assert!(parse_overrides("a-b: quiet").is_err());
+        assert!(parse_overrides("1 a b: quiet").is_err());
+        assert!(CmdlineFragments::read(
+            Some(Path::new("/nonexistent")),
+            None,
+            Some(Path::new("/nonexistent.d")),
+            &[]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn apply_fragments_in_order_of_their_names() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("20-debug.conf"), "*: loglevel=7\n")?;
+        fs::write(dir.path().join("10-console.conf"), "5-: console=ttyS0\n")?;
+        fs::write(dir.path().join("README"), "not a fragment\n")?;
+        let fragments = CmdlineFragments::read(None, None, Some(dir.path()), &[])?;
+
+        assert_eq!(fragments.extra(4, None), ["loglevel=7"]);
+        assert_eq!(fragments.extra(5, None), ["console=ttyS0", "loglevel=7"]);
+
+        fs::write(dir.path().join("30-broken.conf"), "quiet\n")?;
+        assert!(CmdlineFragments::read(None, None, Some(dir.path()), &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_changed_command_lines() {
+        let current = |cmdline: &str| split_parameters(cmdline);
+        assert_eq!(
+            CmdlineChange::detect(
+                3,
+                None,
+                "init=/init quiet lockdown=integrity",
+                current("init=/init quiet")
+            ),
+            None
+        );
+
+        let change = CmdlineChange::detect(
+            3,
+            Some("gaming"),
+            "init=/init quiet roothash=ab",
+            current("init=/init loglevel=7"),
+        )
+        .unwrap();
+        assert_eq!(change.removed(), ["quiet"]);
+        assert_eq!(change.added(), ["loglevel=7"]);
+        assert_eq!(
+            change.describe(),
+            "Specialisation gaming of generation 3 is assembled and signed again, because its \
+             command line changed: removed quiet, added loglevel=7"
+        );
+    }
+}
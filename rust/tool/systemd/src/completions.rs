@@ -0,0 +1,132 @@
+//! Shell completions and the man page, generated from the clap definitions of the CLI.
+//!
+//! Completions use the dynamic engine of clap_complete: the script printed by `lzbt completions`
+//! calls lzbt with `COMPLETE=<shell>` in the environment, and lzbt answers with the candidates
+//! before parsing its arguments. This way, generation numbers and profile names are completed from
+//! the system.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use clap::{Command, CommandFactory, ValueEnum};
+use clap_complete::{env::Shells, CompleteEnv, CompletionCandidate};
+
+use lanzaboote_tool::profile;
+
+use crate::cli::Cli;
+
+/// The name of the binary that users run.
+const BIN: &str = "lzbt";
+
+/// The environment variable that makes lzbt print completion candidates.
+const COMPLETE_VARIABLE: &str = "COMPLETE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bash => "bash",
+            Self::Zsh => "zsh",
+            Self::Fish => "fish",
+        }
+    }
+}
+
+fn command() -> Command {
+    Cli::command().name(BIN)
+}
+
+/// Print the completion candidates and exit if lzbt was called by a completion script.
+pub fn complete() {
+    CompleteEnv::with_factory(command)
+        .var(COMPLETE_VARIABLE)
+        .complete();
+}
+
+/// The completion script of `shell`.
+pub fn registration(shell: Shell) -> Result<Vec<u8>> {
+    let shells = Shells::builtins();
+    let completer = shells
+        .completer(shell.as_str())
+        .with_context(|| format!("clap_complete does not support {}", shell.as_str()))?;
+    let mut script = Vec::new();
+    completer.write_registration(COMPLETE_VARIABLE, BIN, BIN, BIN, &mut script)?;
+    Ok(script)
+}
+
+/// The man page in roff.
+pub fn man_page() -> Result<Vec<u8>> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(command())
+        .section("8")
+        .render(&mut page)?;
+    Ok(page)
+}
+
+/// The generations of the system profile.
+pub fn generations() -> Vec<CompletionCandidate> {
+    profile::generation_versions(&profile::profile_path("system"))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|version| CompletionCandidate::new(version.to_string()))
+        .collect()
+}
+
+/// The profiles on the system.
+pub fn profiles() -> Vec<CompletionCandidate> {
+    profile::profile_names(std::path::Path::new(profile::PROFILES_DIRECTORY))
+        .unwrap_or_default()
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Write `bytes` to stdout.
+pub fn print(bytes: &[u8]) -> Result<()> {
+    std::io::stdout()
+        .write_all(bytes)
+        .context("Failed to write to stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap_complete::ArgValueCandidates;
+
+    #[test]
+    fn registration_calls_lzbt() -> Result<()> {
+        for shell in Shell::value_variants() {
+            let script = String::from_utf8(registration(*shell)?)?;
+            assert!(script.contains(BIN), "{script}");
+            assert!(script.contains(COMPLETE_VARIABLE), "{script}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn generations_are_completed_dynamically() {
+        let command = command();
+        let set_default = command.find_subcommand("set-default").unwrap();
+        let generation = set_default
+            .get_arguments()
+            .find(|arg| arg.get_id() == "generation")
+            .unwrap();
+        assert!(generation.get::<ArgValueCandidates>().is_some());
+    }
+
+    #[test]
+    fn render_man_page() -> Result<()> {
+        let page = String::from_utf8(man_page()?)?;
+        assert!(page.starts_with(".ie"), "{page}");
+        assert!(page.contains(".TH lzbt 8"), "{page}");
+        assert!(page.contains("set\\-default"), "{page}");
+        Ok(())
+    }
+}
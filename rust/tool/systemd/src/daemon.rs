@@ -0,0 +1,531 @@
+//! A varlink service that installs and signs on behalf of other services.
+//!
+//! This way, only the daemon needs access to the private key. Messages are JSON objects that are
+//! terminated by a NUL byte, see <https://varlink.org/Service>, so the service can be called with
+//! `varlinkctl call /run/lzbt.sock org.nixos.lanzaboote.Install '{}'`.
+//!
+//! Root may call every method. Other users may only call the methods that have a polkit action,
+//! e.g. from the settings panel of a desktop, if polkit authorizes them.
+
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use serde_json::{json, Value};
+
+/// The default path of the socket.
+pub const DEFAULT_SOCKET: &str = "/run/lzbt.sock";
+
+/// The name of the interface of the daemon.
+pub const INTERFACE: &str = "org.nixos.lanzaboote";
+
+/// The description of the interface in the varlink interface definition language.
+const INTERFACE_DESCRIPTION: &str = "\
+# Install and sign with the Secure Boot key of Lanzaboote
+interface org.nixos.lanzaboote
+
+# Install the configured profile, like `lzbt install`, and collect garbage on the ESP
+method Install(tentative: ?bool) -> (
+  entries: [string]string,
+  filesWritten: int,
+  signaturesCreated: int
+)
+
+# Sign a PE binary, e.g. an addon, like `sbsign`
+method Sign(input: string, output: string) -> ()
+
+# Remove files from the ESP that no installed generation needs anymore
+#
+# Which files are needed is only known after installing, so this runs a complete installation,
+# which skips entries that are already installed.
+method CollectGarbage() -> ()
+
+# The Secure Boot state of the firmware and the installed entries
+method Status() -> (
+  secureBoot: bool,
+  setupMode: bool,
+  keyEnrolled: ?bool,
+  bootedEntry: ?string,
+  knownGoodGeneration: ?int,
+  entries: []string
+)
+
+# Check the signatures of the installed entries and of systemd-boot
+method Verify() -> (valid: bool, files: []object)
+
+# Boot the entry of a generation once and reboot, unless noReboot is set
+method RebootInto(generation: int, noReboot: ?bool) -> ()
+
+error Failed(message: string)
+";
+
+/// The methods of the interface and the polkit actions that authorize users other than root to
+/// call them.
+const METHODS: &[(&str, Option<&str>)] = &[
+    ("Install", None),
+    ("Sign", None),
+    ("CollectGarbage", None),
+    ("Status", Some("org.nixos.lanzaboote.status")),
+    ("Verify", Some("org.nixos.lanzaboote.verify")),
+    ("RebootInto", Some("org.nixos.lanzaboote.reboot-into")),
+];
+
+/// How long a client may stay connected without sending a message.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many clients may be connected at the same time. Further connections are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The maximum length of a message including its NUL byte. The calls of the interface are far
+/// shorter, but every connection buffers a message until it is complete.
+const MAX_MESSAGE: u64 = 1 << 20;
+
+/// The first file descriptor passed by systemd's socket activation.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The process on the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: u32,
+}
+
+impl Peer {
+    fn of(stream: &UnixStream) -> Result<Self> {
+        let credentials = getsockopt(stream, PeerCredentials)
+            .context("Failed to determine the credentials of the client")?;
+        Ok(Self {
+            pid: credentials.pid(),
+            uid: credentials.uid(),
+        })
+    }
+}
+
+/// A method call of a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    /// The method without the interface, e.g. `Install`.
+    pub method: String,
+    pub parameters: Value,
+}
+
+/// Listen on the socket passed by systemd, or bind `socket` without socket activation.
+pub fn listen(socket: &Path) -> Result<UnixListener> {
+    let pid = std::process::id().to_string();
+    if env::var("LISTEN_PID").is_ok_and(|listen_pid| listen_pid == pid)
+        && env::var("LISTEN_FDS").is_ok_and(|fds| fds == "1")
+    {
+        log::info!("Listening on the socket passed by systemd...");
+        // SAFETY: systemd passes ownership of the listening socket as the first file descriptor.
+        return Ok(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) });
+    }
+
+    if socket.exists() {
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove the stale socket {socket:?}"))?;
+    }
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to bind to {socket:?}"))?;
+    log::info!("Listening on {socket:?}...");
+    Ok(listener)
+}
+
+/// Serve every client in its own thread, so that a client cannot block the others, e.g. while
+/// polkit asks its user for a password. The calls of `handle` never run concurrently. At most
+/// [`MAX_CONNECTIONS`] clients are served at the same time.
+///
+/// `authorize` decides whether a client other than root may call a method with a polkit action,
+/// see [`crate::polkit::check_authorization`].
+pub fn serve(
+    listener: &UnixListener,
+    authorize: impl Fn(&Peer, &str) -> Result<bool> + Sync,
+    handle: impl FnMut(&Call) -> Result<Value> + Send,
+) -> Result<()> {
+    let handle = Mutex::new(handle);
+    let connections = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = stream.context("Failed to accept a connection")?;
+            let Some(connection) = Connection::count(&connections) else {
+                log::warn!("Closing a connection, because {MAX_CONNECTIONS} clients are connected already.");
+                continue;
+            };
+            let (authorize, handle) = (&authorize, &handle);
+            scope.spawn(move || {
+                let _connection = connection;
+                let mut handle = |call: &Call| {
+                    // A handler that panicked leaves nothing behind that the next one relies on.
+                    let mut handle = handle.lock().unwrap_or_else(PoisonError::into_inner);
+                    handle(call)
+                };
+                if let Err(e) = serve_connection(stream, authorize, &mut handle) {
+                    log::warn!("Closing the connection: {e:#}");
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+/// A connection that counts against [`MAX_CONNECTIONS`] until it is dropped.
+struct Connection<'a>(&'a AtomicUsize);
+
+impl<'a> Connection<'a> {
+    /// Count a new connection, unless there are too many already.
+    fn count(connections: &'a AtomicUsize) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| Self(connections))
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answer the calls of a client until it closes the connection or is idle for [`IDLE_TIMEOUT`].
+///
+/// Messages longer than [`MAX_MESSAGE`] close the connection.
+fn serve_connection(
+    stream: UnixStream,
+    authorize: &impl Fn(&Peer, &str) -> Result<bool>,
+    handle: &mut impl FnMut(&Call) -> Result<Value>,
+) -> Result<()> {
+    let peer = Peer::of(&stream)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut message = Vec::new();
+    loop {
+        message.clear();
+        let length = reader
+            .by_ref()
+            .take(MAX_MESSAGE)
+            .read_until(0, &mut message)?;
+        if length == 0 {
+            return Ok(());
+        }
+        if message.pop() != Some(0) {
+            if length as u64 == MAX_MESSAGE {
+                bail!("The message is longer than {MAX_MESSAGE} bytes");
+            }
+            bail!("The connection was closed in the middle of a message");
+        }
+
+        let request: Value =
+            serde_json::from_slice(&message).context("Failed to parse the message")?;
+        let reply = reply(&request, &peer, authorize, handle);
+        // The client asked not to be answered.
+        if request["oneway"].as_bool() == Some(true) {
+            continue;
+        }
+        let mut reply = serde_json::to_vec(&reply)?;
+        reply.push(0);
+        writer.write_all(&reply)?;
+    }
+}
+
+/// The reply to a request, i.e. either its result or an error.
+fn reply(
+    request: &Value,
+    peer: &Peer,
+    authorize: &impl Fn(&Peer, &str) -> Result<bool>,
+    handle: &mut impl FnMut(&Call) -> Result<Value>,
+) -> Value {
+    let Some(method) = request["method"].as_str() else {
+        return error(
+            "org.varlink.service.InvalidParameter",
+            json!({ "parameter": "method" }),
+        );
+    };
+    let parameters = match &request["parameters"] {
+        Value::Null => json!({}),
+        parameters => parameters.clone(),
+    };
+
+    match method.rsplit_once('.') {
+        Some(("org.varlink.service", "GetInfo")) => json!({ "parameters": {
+            "vendor": "Lanzaboote",
+            "product": "lzbt",
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": "https://github.com/nix-community/lanzaboote",
+            "interfaces": ["org.varlink.service", INTERFACE],
+        }}),
+        Some(("org.varlink.service", "GetInterfaceDescription")) => {
+            match parameters["interface"].as_str() {
+                Some(INTERFACE) => {
+                    json!({ "parameters": { "description": INTERFACE_DESCRIPTION } })
+                }
+                interface => error(
+                    "org.varlink.service.InterfaceNotFound",
+                    json!({ "interface": interface }),
+                ),
+            }
+        }
+        Some((INTERFACE, name)) if METHODS.iter().any(|(m, _)| *m == name) => {
+            let action = METHODS
+                .iter()
+                .find_map(|(m, action)| (*m == name).then_some(*action))
+                .flatten();
+            if !is_authorized(peer, action, authorize) {
+                log::warn!("Denied {method} to user {} (PID {}).", peer.uid, peer.pid);
+                return error(
+                    "org.varlink.service.PermissionDenied",
+                    json!({ "method": method }),
+                );
+            }
+            let call = Call {
+                method: name.to_string(),
+                parameters,
+            };
+            log::info!("Handling {method}...");
+            match handle(&call) {
+                Ok(parameters) => json!({ "parameters": parameters }),
+                Err(e) => {
+                    log::error!("{method} failed: {e:#}");
+                    error(
+                        &format!("{INTERFACE}.Failed"),
+                        json!({ "message": format!("{e:#}") }),
+                    )
+                }
+            }
+        }
+        _ => error(
+            "org.varlink.service.MethodNotFound",
+            json!({ "method": method }),
+        ),
+    }
+}
+
+/// Root may call every method, other users only methods with a polkit action that they are
+/// authorized for.
+fn is_authorized(
+    peer: &Peer,
+    action: Option<&str>,
+    authorize: &impl Fn(&Peer, &str) -> Result<bool>,
+) -> bool {
+    if peer.uid == 0 {
+        return true;
+    }
+    let Some(action) = action else {
+        return false;
+    };
+    authorize(peer, action).unwrap_or_else(|e| {
+        log::warn!("Failed to check the authorization for {action}: {e:#}");
+        false
+    })
+}
+
+fn error(name: &str, parameters: Value) -> Value {
+    json!({ "error": name, "parameters": parameters })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(stream: &mut UnixStream, request: Value) -> Result<Value> {
+        let mut message = serde_json::to_vec(&request)?;
+        message.push(0);
+        stream.write_all(&message)?;
+
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte)?;
+            if byte[0] == 0 {
+                return Ok(serde_json::from_slice(&reply)?);
+            }
+            reply.push(byte[0]);
+        }
+    }
+
+    #[test]
+    fn answer_calls_until_the_client_disconnects() -> Result<()> {
+        let (mut client, server) = UnixStream::pair()?;
+        let server =
+            thread::spawn(move || {
+                serve_connection(server, &|_: &Peer, _: &str| Ok(true), &mut |call: &Call| {
+                    match call.method.as_str() {
+                        "Sign" => Ok(json!({ "signed": call.parameters["input"] })),
+                        _ => bail!("{} failed", call.method),
+                    }
+                })
+            });
+
+        assert_eq!(
+            call(
+                &mut client,
+                json!({ "method": "org.nixos.lanzaboote.Sign", "parameters": { "input": "a" } })
+            )?,
+            json!({ "parameters": { "signed": "a" } })
+        );
+        assert_eq!(
+            call(
+                &mut client,
+                json!({ "method": "org.nixos.lanzaboote.Install" })
+            )?,
+            json!({
+                "error": "org.nixos.lanzaboote.Failed",
+                "parameters": { "message": "Install failed" }
+            })
+        );
+        assert_eq!(
+            call(
+                &mut client,
+                json!({ "method": "org.nixos.lanzaboote.Other" })
+            )?["error"],
+            "org.varlink.service.MethodNotFound"
+        );
+        assert_eq!(
+            call(
+                &mut client,
+                json!({ "method": "org.varlink.service.GetInfo" })
+            )?["parameters"]["interfaces"][1],
+            INTERFACE
+        );
+
+        drop(client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn idle_clients_do_not_block_others() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let socket = tmpdir.path().join("lzbt.sock");
+        let listener = listen(&socket)?;
+        // The server runs until the test exits.
+        thread::spawn(move || {
+            serve(
+                &listener,
+                |_: &Peer, _: &str| Ok(true),
+                |_: &Call| Ok(json!({})),
+            )
+        });
+
+        let _idle = UnixStream::connect(&socket)?;
+        let mut client = UnixStream::connect(&socket)?;
+        client.set_read_timeout(Some(Duration::from_secs(10)))?;
+        assert_eq!(
+            call(
+                &mut client,
+                json!({ "method": "org.nixos.lanzaboote.Sign" })
+            )?,
+            json!({ "parameters": {} })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn close_connections_with_long_messages() -> Result<()> {
+        let (mut client, server) = UnixStream::pair()?;
+        let server = thread::spawn(move || {
+            serve_connection(server, &|_: &Peer, _: &str| Ok(true), &mut |_: &Call| {
+                Ok(json!({}))
+            })
+        });
+
+        // A message of exactly the maximum length is fine.
+        let mut message = vec![b' '; MAX_MESSAGE as usize - 1];
+        message[..2].copy_from_slice(b"{}");
+        message.push(0);
+        client.write_all(&message)?;
+        let mut reply = [0; 1];
+        client.read_exact(&mut reply)?;
+
+        // The server stops reading as soon as the message is too long.
+        client.write_all(&vec![b' '; MAX_MESSAGE as usize])?;
+        let error = server.join().unwrap().unwrap_err();
+        assert!(error.to_string().contains("longer than"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn limit_concurrent_connections() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let socket = tmpdir.path().join("lzbt.sock");
+        let listener = listen(&socket)?;
+        // The server runs until the test exits.
+        thread::spawn(move || {
+            serve(
+                &listener,
+                |_: &Peer, _: &str| Ok(true),
+                |_: &Call| Ok(json!({})),
+            )
+        });
+
+        let mut idle = (0..MAX_CONNECTIONS)
+            .map(|_| UnixStream::connect(&socket))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // Make sure that the server has accepted all of them.
+        for client in &mut idle {
+            client.set_read_timeout(Some(Duration::from_secs(10)))?;
+            call(client, json!({ "method": "org.varlink.service.GetInfo" }))?;
+        }
+
+        let mut refused = UnixStream::connect(&socket)?;
+        refused.set_read_timeout(Some(Duration::from_secs(10)))?;
+        assert_eq!(refused.read(&mut [0])?, 0);
+
+        // A slot is free again once a client disconnects.
+        drop(idle.pop());
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let mut client = UnixStream::connect(&socket)?;
+            client.set_read_timeout(Some(Duration::from_secs(10)))?;
+            match call(
+                &mut client,
+                json!({ "method": "org.nixos.lanzaboote.Sign" }),
+            ) {
+                Ok(reply) => {
+                    assert_eq!(reply, json!({ "parameters": {} }));
+                    return Ok(());
+                }
+                Err(_) if std::time::Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[test]
+    fn only_authorize_users_for_methods_with_polkit_actions() {
+        let user = Peer {
+            pid: 4242,
+            uid: 1000,
+        };
+        let root = Peer { pid: 1, uid: 0 };
+        let polkit = |_: &Peer, action: &str| Ok(action == "org.nixos.lanzaboote.status");
+        let mut handle = |_: &Call| Ok(json!({}));
+        let mut call = |peer: &Peer, method: &str| {
+            reply(
+                &json!({ "method": format!("{INTERFACE}.{method}") }),
+                peer,
+                &polkit,
+                &mut handle,
+            )["error"]
+                .as_str()
+                .map(str::to_string)
+        };
+
+        assert_eq!(call(&user, "Status"), None);
+        let denied = Some("org.varlink.service.PermissionDenied".to_string());
+        assert_eq!(call(&user, "RebootInto"), denied);
+        assert_eq!(call(&user, "Sign"), denied);
+        assert_eq!(call(&root, "Sign"), None);
+    }
+}
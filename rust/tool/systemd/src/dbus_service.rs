@@ -0,0 +1,200 @@
+//! A D-Bus service on the system bus that exposes the Secure Boot state and the installed
+//! generations, e.g. for the settings of a desktop or for fwupd.
+//!
+//! The service is read-only. Everything is read again on every call, so that the properties never
+//! go stale and the service does not need to emit `PropertiesChanged`.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use zbus::{blocking::connection, fdo, interface};
+
+use crate::status::Status;
+
+/// The well-known name of the service.
+pub const BUS_NAME: &str = "org.nixos.Lanzaboote";
+
+pub const OBJECT_PATH: &str = "/org/nixos/Lanzaboote";
+
+type StatusFn = Box<dyn Fn() -> Result<Status> + Send + Sync>;
+type VerifyFn = Box<dyn Fn() -> Result<Vec<(PathBuf, bool)>> + Send + Sync>;
+
+/// The `org.nixos.Lanzaboote` interface.
+struct Lanzaboote {
+    status: StatusFn,
+    verify: VerifyFn,
+}
+
+impl Lanzaboote {
+    fn status(&self) -> fdo::Result<Status> {
+        (self.status)().map_err(failed)
+    }
+}
+
+#[interface(name = "org.nixos.Lanzaboote")]
+impl Lanzaboote {
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn secure_boot(&self) -> fdo::Result<bool> {
+        Ok(self.status()?.secure_boot)
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn setup_mode(&self) -> fdo::Result<bool> {
+        Ok(self.status()?.setup_mode)
+    }
+
+    /// D-Bus has no optional values, so an unknown certificate counts as not enrolled.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn key_enrolled(&self) -> fdo::Result<bool> {
+        Ok(self.status()?.key_enrolled.unwrap_or(false))
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn default_entry(&self) -> fdo::Result<String> {
+        Ok(self.status()?.default_entry.unwrap_or_default())
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn booted_entry(&self) -> fdo::Result<String> {
+        Ok(self.status()?.booted_entry.unwrap_or_default())
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn known_good_generation(&self) -> fdo::Result<u64> {
+        Ok(self.status()?.known_good_generation.unwrap_or(0))
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn generations(&self) -> fdo::Result<Vec<u64>> {
+        Ok(self.status()?.generations())
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn entries(&self) -> fdo::Result<Vec<String>> {
+        Ok(self.status()?.entries)
+    }
+
+    /// Check the signatures of the files on the ESP.
+    #[zbus(out_args("valid", "files"))]
+    fn verify(&self) -> fdo::Result<(bool, Vec<(String, bool)>)> {
+        let files = (self.verify)().map_err(failed)?;
+        let valid = files.iter().all(|(_, signed)| *signed);
+        let files = files
+            .into_iter()
+            .map(|(path, signed)| (path.to_string_lossy().into_owned(), signed))
+            .collect();
+        Ok((valid, files))
+    }
+}
+
+/// Log an error and pass it on to the caller.
+fn failed(e: anyhow::Error) -> fdo::Error {
+    log::error!("{e:#}");
+    fdo::Error::Failed(format!("{e:#}"))
+}
+
+/// Serve calls on the system bus until the process is stopped.
+///
+/// `verify` checks the signatures of the files on the ESP, see [`crate::status::verify`].
+pub fn serve(
+    status: impl Fn() -> Result<Status> + Send + Sync + 'static,
+    verify: impl Fn() -> Result<Vec<(PathBuf, bool)>> + Send + Sync + 'static,
+) -> Result<()> {
+    let interface = Lanzaboote {
+        status: Box::new(status),
+        verify: Box::new(verify),
+    };
+    let _connection = connection::Builder::system()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, interface)?
+        .build()?;
+    log::info!("Serving {BUS_NAME} on the system bus...");
+    // zbus answers the calls on its own thread.
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use anyhow::bail;
+    use zbus::blocking::{proxy, Connection, Proxy};
+    use zbus::proxy::CacheProperties;
+    use zbus::Guid;
+
+    use super::*;
+
+    const INTERFACE: &str = "org.nixos.Lanzaboote";
+
+    fn status() -> Result<Status> {
+        Ok(Status {
+            secure_boot: true,
+            setup_mode: false,
+            key_enrolled: None,
+            default_entry: Some("nixos-generation-2-a.efi".to_string()),
+            booted_entry: None,
+            known_good_generation: Some(1),
+            entries: vec![
+                "nixos-generation-1-b.efi".to_string(),
+                "nixos-generation-2-a.efi".to_string(),
+            ],
+            certificates: Vec::new(),
+            offline: false,
+        })
+    }
+
+    /// Connect to the interface over a peer-to-peer connection.
+    fn connect() -> Result<(Connection, Connection)> {
+        let (server, client) = UnixStream::pair()?;
+        let server = std::thread::spawn(move || {
+            connection::Builder::unix_stream(server)
+                .server(Guid::generate())?
+                .p2p()
+                .serve_at(
+                    OBJECT_PATH,
+                    Lanzaboote {
+                        status: Box::new(status),
+                        verify: Box::new(|| bail!("No certificate")),
+                    },
+                )?
+                .build()
+        });
+        let client = connection::Builder::unix_stream(client).p2p().build()?;
+        Ok((server.join().unwrap()?, client))
+    }
+
+    #[test]
+    fn answer_property_calls() -> Result<()> {
+        let (_server, client) = connect()?;
+        let proxy: Proxy = proxy::Builder::new(&client)
+            .destination(BUS_NAME)?
+            .path(OBJECT_PATH)?
+            .interface(INTERFACE)?
+            .cache_properties(CacheProperties::No)
+            .build()?;
+
+        assert_eq!(proxy.get_property::<Vec<u64>>("Generations")?, [1, 2]);
+        assert!(proxy.get_property::<bool>("SecureBoot")?);
+        assert!(!proxy.get_property::<bool>("KeyEnrolled")?);
+        assert_eq!(proxy.get_property::<String>("BootedEntry")?, "");
+
+        let error = proxy.call::<_, _, (bool, Vec<(String, bool)>)>("Verify", &());
+        assert!(matches!(
+            error,
+            Err(zbus::Error::MethodError(name, Some(message), _))
+                if name == "org.freedesktop.DBus.Error.Failed" && message == "No certificate"
+        ));
+
+        let introspection = zbus::blocking::fdo::IntrospectableProxy::builder(&client)
+            .destination(BUS_NAME)?
+            .path(OBJECT_PATH)?
+            .build()?
+            .introspect()?;
+        assert!(
+            introspection.contains("<property name=\"Generations\" type=\"at\" access=\"read\">")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use crate::bitlocker;
+use lanzaboote_tool::authenticode::authenticode_sha256;
+use lanzaboote_tool::efivars::{
+    read_variable, write_variable, EFI_IMAGE_SECURITY_DATABASE_GUID, EFI_VARIABLE_APPEND_WRITE,
+    EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_NON_VOLATILE, EFI_VARIABLE_RUNTIME_ACCESS,
+    EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS,
+};
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::revocation::format_hash;
+use lanzaboote_tool::signature_list::{
+    parse_signature_lists, strip_authentication_header, Signature,
+};
+use lanzaboote_tool::utils::SecureTempDirExt;
+
+/// The attributes of an append write to `dbx`.
+const DBX_APPEND_ATTRIBUTES: u32 = EFI_VARIABLE_NON_VOLATILE
+    | EFI_VARIABLE_BOOTSERVICE_ACCESS
+    | EFI_VARIABLE_RUNTIME_ACCESS
+    | EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS
+    | EFI_VARIABLE_APPEND_WRITE;
+
+/// The same attributes in the notation of `sbvarsign`.
+const SBVARSIGN_ATTRIBUTES: &str = "NON_VOLATILE,BOOTSERVICE_ACCESS,RUNTIME_ACCESS,\
+                                    TIME_BASED_AUTHENTICATED_WRITE_ACCESS,APPEND_WRITE";
+
+/// A KEK to sign `dbx` updates with, for systems that do not have Microsoft's KEK enrolled.
+pub struct Kek {
+    pub private_key: PathBuf,
+    pub public_key: PathBuf,
+}
+
+/// Read the signatures in the firmware's `dbx`.
+fn firmware_dbx() -> Result<Vec<Signature>> {
+    match read_variable("dbx", &EFI_IMAGE_SECURITY_DATABASE_GUID)? {
+        Some(data) => parse_signature_lists(&data).context("Failed to parse the firmware's dbx"),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Read the signatures in a `dbx` update, e.g. the UEFI revocation list.
+fn update_signatures(update: &[u8]) -> Result<Vec<Signature>> {
+    parse_signature_lists(strip_authentication_header(update)?)
+        .context("Failed to parse the dbx update")
+}
+
+/// The entries of `update` that are not in `current` yet, without duplicates.
+fn new_entries<'a>(current: &[Signature], update: &'a [Signature]) -> Vec<&'a Signature> {
+    let mut new: Vec<&Signature> = Vec::new();
+    for signature in update {
+        if !current.contains(signature) && !new.contains(&signature) {
+            new.push(signature);
+        }
+    }
+    new
+}
+
+/// Print all entries of the firmware's `dbx`.
+pub fn list() -> Result<()> {
+    for signature in firmware_dbx()? {
+        let data = match signature.sha256() {
+            Some(hash) => format_hash(hash),
+            None => format!("{} bytes", signature.data.len()),
+        };
+        println!(
+            "{} {data} (owner {})",
+            signature.type_name(),
+            signature.owner
+        );
+    }
+    Ok(())
+}
+
+/// Collect all PE binaries below `dir` on the ESP.
+fn esp_binaries(esp_fs: &impl EspFilesystem, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut binaries = Vec::new();
+    for path in esp_fs.list(dir)? {
+        if esp_fs.is_dir(&path) {
+            binaries.extend(esp_binaries(esp_fs, &path)?);
+        } else if esp_fs.read(&path)?.starts_with(b"MZ") {
+            binaries.push(path);
+        }
+    }
+    Ok(binaries)
+}
+
+/// Find the binaries on the ESP whose Authenticode hash is in `revoked`.
+///
+/// Only hash entries are considered. Revoked certificates are not matched against the
+/// signatures of the binaries.
+fn revoked_binaries(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    revoked: &[Signature],
+) -> Result<Vec<PathBuf>> {
+    let hashes = revoked
+        .iter()
+        .filter_map(Signature::sha256)
+        .collect::<BTreeSet<_>>();
+
+    let mut hits = Vec::new();
+    for binary in esp_binaries(esp_fs, &esp.join("EFI"))? {
+        let hash = match authenticode_sha256(&esp_fs.read(&binary)?) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::warn!("Skipping {binary:?}: {e:#}");
+                continue;
+            }
+        };
+        if hashes.contains(hash.as_slice()) {
+            hits.push(binary);
+        }
+    }
+    Ok(hits)
+}
+
+/// Check whether binaries on the ESP are revoked by the firmware's `dbx` or by an update.
+pub fn check(esp_fs: &impl EspFilesystem, esp: &Path, update: Option<&Path>) -> Result<()> {
+    let mut revoked = firmware_dbx()?;
+    if let Some(update) = update {
+        let data = fs::read(update).with_context(|| format!("Failed to read {update:?}"))?;
+        revoked.extend(update_signatures(&data)?);
+    }
+
+    let hits = revoked_binaries(esp_fs, esp, &revoked)?;
+    for hit in &hits {
+        log::error!("{hit:?} is revoked.");
+    }
+    if !hits.is_empty() {
+        bail!("{} binaries on the ESP are revoked", hits.len());
+    }
+
+    log::info!("No binary on the ESP is revoked.");
+    Ok(())
+}
+
+/// Apply a `dbx` update.
+///
+/// The update is refused if it revokes any binary on the ESP, because that would make the system
+/// unbootable. Before writing, the current `dbx` is backed up into `backup_dir`.
+///
+/// Updates signed by Microsoft only apply if Microsoft's KEK is enrolled. Otherwise, they are
+/// re-signed with the given `kek`.
+pub fn update(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    update: &Path,
+    kek: Option<&Kek>,
+    backup_dir: &Path,
+    dry_run: bool,
+) -> Result<()> {
+    let data = fs::read(update).with_context(|| format!("Failed to read {update:?}"))?;
+    let signature_lists = strip_authentication_header(&data)?;
+    let is_authenticated = signature_lists.len() != data.len();
+    let signatures = update_signatures(&data)?;
+
+    let new = new_entries(&firmware_dbx()?, &signatures);
+    if new.is_empty() {
+        log::info!("The firmware's dbx already contains all entries of the update.");
+        return Ok(());
+    }
+
+    let hits = revoked_binaries(esp_fs, esp, &signatures)?;
+    if !hits.is_empty() {
+        for hit in &hits {
+            log::error!("{hit:?} is revoked by the update.");
+        }
+        bail!("Refusing to apply the update, because it would make the binaries above unbootable. Update them first.");
+    }
+
+    log::info!("The update adds {} entries to the dbx.", new.len());
+    bitlocker::warn_if_windows(esp_fs, esp, "Updating the dbx");
+    if dry_run {
+        return Ok(());
+    }
+
+    let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+    let payload = match kek {
+        Some(kek) => sign_update(&tempdir, kek, signature_lists)?,
+        None if is_authenticated => data.clone(),
+        None => bail!("The update is not signed. Pass a KEK to sign it."),
+    };
+
+    if let Some(current) = read_variable("dbx", &EFI_IMAGE_SECURITY_DATABASE_GUID)? {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let backup = backup_dir.join(format!("dbx-{timestamp}.esl"));
+        fs::create_dir_all(backup_dir)
+            .with_context(|| format!("Failed to create the directory {backup_dir:?}"))?;
+        fs::write(&backup, current)
+            .with_context(|| format!("Failed to back up the dbx to {backup:?}"))?;
+        log::info!("Backed up the current dbx to {backup:?}.");
+    }
+
+    write_variable(
+        "dbx",
+        &EFI_IMAGE_SECURITY_DATABASE_GUID,
+        DBX_APPEND_ATTRIBUTES,
+        &payload,
+    )
+    .context(
+        "The firmware rejected the update. If Microsoft's KEK is not enrolled, pass your own KEK \
+         to re-sign it.",
+    )?;
+
+    let updated = firmware_dbx()?;
+    if new.iter().any(|s| !updated.contains(s)) {
+        bail!("The dbx does not contain all entries of the update after applying it");
+    }
+    log::info!("Successfully updated the dbx.");
+    Ok(())
+}
+
+/// Sign the signature lists of an update with a KEK using `sbvarsign`.
+fn sign_update(tempdir: &TempDir, kek: &Kek, signature_lists: &[u8]) -> Result<Vec<u8>> {
+    let input = tempdir.write_secure_file(signature_lists)?;
+    let output = tempdir.path().join("dbx.auth");
+    let status = Command::new("sbvarsign")
+        .arg("--key")
+        .arg(&kek.private_key)
+        .arg("--cert")
+        .arg(&kek.public_key)
+        .arg("--guid")
+        .arg(EFI_IMAGE_SECURITY_DATABASE_GUID.to_string())
+        .arg("--attrs")
+        .arg(SBVARSIGN_ATTRIBUTES)
+        .arg("--output")
+        .arg(&output)
+        .arg("dbx")
+        .arg(&input)
+        .status()
+        .context("Failed to run sbvarsign. Most likely, the binary is not on PATH.")?;
+    if !status.success() {
+        bail!("Failed to sign the dbx update with the KEK");
+    }
+    fs::read(&output).with_context(|| format!("Failed to read the signed update {output:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+    use lanzaboote_tool::signature_list::EFI_CERT_SHA256_GUID;
+
+    /// A signature list with SHA256 hashes, all owned by the same GUID.
+    fn sha256_list(hashes: &[[u8; 32]]) -> Vec<u8> {
+        // EFI_CERT_SHA256_GUID
+        let mut list = vec![
+            0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93,
+            0x43, 0x28,
+        ];
+        list.extend_from_slice(&(28 + hashes.len() as u32 * 48).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&48u32.to_le_bytes());
+        for hash in hashes {
+            list.extend_from_slice(&[0x77; 16]);
+            list.extend_from_slice(hash);
+        }
+        list
+    }
+
+    /// Prefix `lists` with an `EFI_VARIABLE_AUTHENTICATION_2` header with a bogus signature.
+    fn authenticated(lists: &[u8]) -> Vec<u8> {
+        let pkcs7 = [0x30; 20];
+        let mut update = vec![0; 16];
+        update.extend_from_slice(&(24 + pkcs7.len() as u32).to_le_bytes());
+        update.extend_from_slice(&0x0200u16.to_le_bytes());
+        update.extend_from_slice(&0x0ef1u16.to_le_bytes());
+        // EFI_CERT_TYPE_PKCS7_GUID
+        update.extend_from_slice(&[
+            0x9d, 0xd2, 0xaf, 0x4a, 0xdf, 0x68, 0xee, 0x49, 0x8a, 0xa9, 0x34, 0x7d, 0x37, 0x56,
+            0x65, 0xa7,
+        ]);
+        update.extend_from_slice(&pkcs7);
+        update.extend_from_slice(lists);
+        update
+    }
+
+    #[test]
+    fn parse_plain_and_authenticated_updates() -> Result<()> {
+        let lists = sha256_list(&[[1; 32], [2; 32]]);
+
+        let plain = update_signatures(&lists)?;
+        assert_eq!(plain.len(), 2);
+        assert_eq!(plain[0].signature_type, EFI_CERT_SHA256_GUID);
+        assert_eq!(plain[1].sha256(), Some([2; 32].as_slice()));
+        assert_eq!(update_signatures(&authenticated(&lists))?, plain);
+
+        assert!(update_signatures(&lists[..lists.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_only_new_entries() -> Result<()> {
+        let current = update_signatures(&sha256_list(&[[1; 32], [2; 32]]))?;
+        let update = update_signatures(&sha256_list(&[[2; 32], [3; 32], [3; 32]]))?;
+
+        let new = new_entries(&current, &update);
+        assert_eq!(new, [&update[1]]);
+        assert!(new_entries(&update, &update).is_empty());
+        assert_eq!(new_entries(&[], &current).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_files_that_are_not_binaries() -> Result<()> {
+        let esp = Path::new("/esp");
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(
+            &esp.join("EFI/nixos/kernel.efi"),
+            b"MZ, but not a PE binary",
+        )?;
+        esp_fs.write(&esp.join("EFI/nixos/notes.txt"), b"text")?;
+        let revoked = update_signatures(&sha256_list(&[[1; 32]]))?;
+
+        assert_eq!(esp_binaries(&esp_fs, &esp.join("EFI"))?.len(), 1);
+        assert!(revoked_binaries(&esp_fs, esp, &revoked)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+//! Install the deployments of image-based systems, see `lzbt deployments`.
+//!
+//! Image-based systems, e.g. Fedora CoreOS with ostree, have no bootspec. Instead they deploy whole
+//! images, each with its own kernel, initrd and command line. Every deployment gets a signed stub
+//! at `EFI/Linux/<name>-deployment-<id>.efi`, and its kernel and initrd are installed to
+//! `EFI/<name>` like by `lzbt standalone`. The stubs and files of deployments that no longer exist
+//! are removed. The deployments are read from either
+//!
+//! - the boot loader entries `ostree-*.conf` that `ostree admin deploy` writes to
+//!   `/boot/loader/entries`, with the name `ostree`, or
+//! - a JSON manifest of another update system:
+//!
+//! ```json
+//! {
+//!   "name": "appliance",
+//!   "deployments": [
+//!     {
+//!       "id": "a",
+//!       "title": "Appliance 2024.07 (A)",
+//!       "version": "2024.07",
+//!       "kernel": "/images/a/vmlinuz",
+//!       "initrds": ["/images/a/initrd"],
+//!       "cmdline": "root=PARTLABEL=root-a ro"
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::standalone::{self, StandaloneEntry};
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::signature::Signer;
+
+/// The name of the deployments of ostree.
+const OSTREE_NAME: &str = "ostree";
+
+/// The directory of the boot loader entries of ostree, relative to the system root.
+const OSTREE_ENTRIES: &str = "boot/loader/entries";
+
+/// The directory of the stubs, relative to the ESP.
+const STUB_DIRECTORY: &str = "EFI/Linux";
+
+/// A deployed image with everything to boot it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    pub id: String,
+    pub title: String,
+    /// The version that systemd-boot sorts the entries of the deployments by.
+    pub version: Option<String>,
+    pub kernel: PathBuf,
+    pub initrds: Vec<PathBuf>,
+    pub cmdline: Vec<String>,
+}
+
+/// The deployments of an update system, which manages the stubs with its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployments {
+    pub name: String,
+    pub deployments: Vec<Deployment>,
+}
+
+/// The files that [`Deployments::install`] changed on the ESP.
+#[derive(Debug, Default)]
+pub struct ChangedFiles {
+    pub installed: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Parse a boot loader entry into its keys and values, which may repeat, e.g. `initrd`.
+fn parse_entry(entry: &str) -> Vec<(&str, &str)> {
+    entry
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once(char::is_whitespace) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        })
+        .collect()
+}
+
+impl Deployments {
+    /// Read the deployments from the boot loader entries of ostree in `sysroot`.
+    ///
+    /// The paths in the entries are relative to `/boot`, or to the system root if `/boot` is not a
+    /// separate partition.
+    pub fn from_ostree(sysroot: &Path) -> Result<Self> {
+        let entries_dir = sysroot.join(OSTREE_ENTRIES);
+        let mut files = fs::read_dir(&entries_dir)
+            .with_context(|| format!("Failed to read the ostree entries in {entries_dir:?}"))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+
+        let resolve = |path: &str| {
+            let path = path.trim_start_matches('/');
+            let in_boot = sysroot.join("boot").join(path);
+            if in_boot.exists() {
+                in_boot
+            } else {
+                sysroot.join(path)
+            }
+        };
+        let mut deployments = Vec::new();
+        for file in files {
+            let Some(id) = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("ostree-"))
+                .and_then(|name| name.strip_suffix(".conf"))
+            else {
+                continue;
+            };
+            let contents =
+                fs::read_to_string(&file).with_context(|| format!("Failed to read {file:?}"))?;
+            let entry = parse_entry(&contents);
+            let value = |key: &str| {
+                entry
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, value)| *value)
+            };
+            deployments.push(Deployment {
+                id: id.to_string(),
+                title: value("title").unwrap_or(id).to_string(),
+                version: value("version").map(String::from),
+                kernel: resolve(value("linux").with_context(|| format!("No kernel in {file:?}"))?),
+                initrds: entry
+                    .iter()
+                    .filter(|(key, _)| *key == "initrd")
+                    .map(|(_, path)| resolve(path))
+                    .collect(),
+                cmdline: value("options")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+            });
+        }
+
+        let deployments = Self {
+            name: OSTREE_NAME.to_string(),
+            deployments,
+        };
+        deployments.validate()?;
+        Ok(deployments)
+    }
+
+    /// Read the deployments from a JSON manifest.
+    pub fn from_manifest(manifest: &[u8]) -> Result<Self> {
+        let manifest: Value = serde_json::from_slice(manifest).context("Malformed manifest")?;
+        let string = |value: &Value, key: &str| -> Result<String> {
+            value[key]
+                .as_str()
+                .map(String::from)
+                .with_context(|| format!("Missing {key:?}"))
+        };
+
+        let deployments = manifest["deployments"]
+            .as_array()
+            .context("No deployments")?
+            .iter()
+            .map(|deployment| {
+                let id = string(deployment, "id")?;
+                let parse = || -> Result<Deployment> {
+                    Ok(Deployment {
+                        title: string(deployment, "title").unwrap_or_else(|_| id.clone()),
+                        version: string(deployment, "version").ok(),
+                        kernel: string(deployment, "kernel")?.into(),
+                        initrds: deployment["initrds"]
+                            .as_array()
+                            .context("Missing \"initrds\"")?
+                            .iter()
+                            .map(|initrd| initrd.as_str().map(PathBuf::from))
+                            .collect::<Option<_>>()
+                            .context("Malformed initrd")?,
+                        cmdline: string(deployment, "cmdline")
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect(),
+                        id: id.clone(),
+                    })
+                };
+                parse().with_context(|| format!("Malformed deployment {id:?}"))
+            })
+            .collect::<Result<_>>()?;
+
+        let deployments = Self {
+            name: string(&manifest, "name")?,
+            deployments,
+        };
+        deployments.validate()?;
+        Ok(deployments)
+    }
+
+    /// Check that the names of the stubs are unique and do not escape their directory.
+    fn validate(&self) -> Result<()> {
+        if self.name == "nixos" {
+            bail!("The name \"nixos\" is reserved for the generations of NixOS.");
+        }
+        for name in [&self.name]
+            .into_iter()
+            .chain(self.deployments.iter().map(|d| &d.id))
+        {
+            if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+                bail!("Invalid name {name:?} for a stub");
+            }
+        }
+        let mut ids = BTreeSet::new();
+        for deployment in &self.deployments {
+            if !ids.insert(&deployment.id) {
+                bail!("The deployment {:?} is listed twice.", deployment.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// The common prefix of the names of the stubs of these deployments.
+    fn stub_prefix(&self) -> String {
+        format!("{}-deployment-", self.name)
+    }
+
+    fn stub_path(&self, esp: &Path, deployment: &Deployment) -> PathBuf {
+        esp.join(STUB_DIRECTORY)
+            .join(format!("{}{}.efi", self.stub_prefix(), deployment.id))
+    }
+
+    fn os_release(&self, deployment: &Deployment) -> String {
+        let mut os_release = BTreeMap::from([
+            ("ID".to_string(), self.name.clone()),
+            ("PRETTY_NAME".to_string(), deployment.title.clone()),
+        ]);
+        if let Some(version) = &deployment.version {
+            os_release.insert("VERSION_ID".to_string(), version.clone());
+        }
+        OsRelease(os_release).to_string()
+    }
+
+    fn install_deployment<S: Signer>(
+        &self,
+        esp_fs: &mut impl EspFilesystem,
+        signer: &S,
+        lanzaboote_stub: &Path,
+        esp: &Path,
+        deployment: &Deployment,
+    ) -> Result<Vec<PathBuf>> {
+        let tempdir = tempfile::tempdir()?;
+        let entry = StandaloneEntry {
+            kernel: deployment.kernel.clone(),
+            initrd: standalone::combine_initrds(&deployment.initrds, tempdir.path())?,
+            cmdline: deployment.cmdline.clone(),
+            os_release: self.os_release(deployment),
+            output: self.stub_path(esp, deployment),
+        };
+        standalone::install(esp_fs, signer, lanzaboote_stub, esp, &entry)
+    }
+
+    /// Install a signed stub for every deployment, and remove the stubs, kernels and initrds of
+    /// deployments that no longer exist.
+    pub fn install<S: Signer>(
+        &self,
+        esp_fs: &mut impl EspFilesystem,
+        signer: &S,
+        lanzaboote_stub: &Path,
+        esp: &Path,
+    ) -> Result<ChangedFiles> {
+        let mut changed = ChangedFiles::default();
+        for deployment in &self.deployments {
+            let installed = self
+                .install_deployment(esp_fs, signer, lanzaboote_stub, esp, deployment)
+                .with_context(|| {
+                    format!("Failed to install the deployment {:?}.", deployment.id)
+                })?;
+            changed.installed.extend(installed);
+        }
+
+        // Every file of a current deployment was just installed, so all others are stale.
+        let stub_directory = esp.join(STUB_DIRECTORY);
+        let prefix = self.stub_prefix();
+        let stale_stubs = if esp_fs.is_dir(&stub_directory) {
+            esp_fs
+                .list(&stub_directory)?
+                .into_iter()
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".efi"))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let payload_directory = esp.join("EFI").join(&self.name);
+        let stale_payloads = if esp_fs.is_dir(&payload_directory) {
+            esp_fs.list(&payload_directory)?
+        } else {
+            Vec::new()
+        };
+        for file in stale_stubs.into_iter().chain(stale_payloads) {
+            if !changed.installed.contains(&file) {
+                esp_fs
+                    .delete(&file)
+                    .with_context(|| format!("Failed to remove {file:?}"))?;
+                changed.removed.push(file);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+    use lanzaboote_tool::pe::StubParameters;
+
+    /// Stubs are represented by their parameters.
+    struct MockSigner;
+
+    impl Signer for MockSigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            Ok(fs::read(store_path)?)
+        }
+
+        fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(stub)?)
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(b"mock".to_vec())
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn read_ostree_deployments() -> Result<()> {
+        let sysroot = tempfile::tempdir()?;
+        let boot = sysroot.path().join("boot");
+        let ostree = boot.join("ostree/fedora-coreos-4c5d");
+        fs::create_dir_all(boot.join("loader/entries"))?;
+        fs::create_dir_all(&ostree)?;
+        fs::write(ostree.join("vmlinuz-6.9.7-200.fc40.x86_64"), b"kernel")?;
+        fs::write(
+            boot.join("loader/entries/ostree-2-fedora-coreos.conf"),
+            "title Fedora CoreOS 40.20240701.3.0 (ostree:0)\n\
+             version 2\n\
+             options mitigations=auto rw ostree=/ostree/boot.1/fedora-coreos/4c5d/0\n\
+             linux /ostree/fedora-coreos-4c5d/vmlinuz-6.9.7-200.fc40.x86_64\n\
+             initrd /ostree/fedora-coreos-4c5d/initramfs-6.9.7-200.fc40.x86_64.img\n",
+        )?;
+        fs::write(boot.join("loader/entries/grub.conf"), "title Other\n")?;
+
+        let deployments = Deployments::from_ostree(sysroot.path())?;
+        assert_eq!(deployments.name, "ostree");
+        assert_eq!(
+            deployments.deployments,
+            [Deployment {
+                id: "2-fedora-coreos".into(),
+                title: "Fedora CoreOS 40.20240701.3.0 (ostree:0)".into(),
+                version: Some("2".into()),
+                kernel: ostree.join("vmlinuz-6.9.7-200.fc40.x86_64"),
+                // The initrd is not in /boot, so it is looked up in the system root.
+                initrds: vec![sysroot
+                    .path()
+                    .join("ostree/fedora-coreos-4c5d/initramfs-6.9.7-200.fc40.x86_64.img")],
+                cmdline: vec![
+                    "mitigations=auto".into(),
+                    "rw".into(),
+                    "ostree=/ostree/boot.1/fedora-coreos/4c5d/0".into()
+                ],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_manifests() -> Result<()> {
+        let deployments = Deployments::from_manifest(
+            br#"{"name": "appliance", "deployments": [{"id": "a", "kernel": "/a/vmlinuz", "initrds": ["/a/initrd"], "cmdline": "root=PARTLABEL=root-a ro"}]}"#,
+        )?;
+        assert_eq!(deployments.name, "appliance");
+        assert_eq!(deployments.deployments[0].title, "a");
+        assert_eq!(deployments.deployments[0].version, None);
+        assert_eq!(
+            deployments.deployments[0].cmdline,
+            ["root=PARTLABEL=root-a", "ro"]
+        );
+
+        for manifest in [
+            r#"{"name": "nixos", "deployments": []}"#,
+            r#"{"name": "appliance", "deployments": [{"id": "../a", "kernel": "/k", "initrds": []}]}"#,
+            r#"{"name": "appliance", "deployments": [{"id": "a", "kernel": "/k", "initrds": []}, {"id": "a", "kernel": "/k", "initrds": []}]}"#,
+            r#"{"name": "appliance", "deployments": [{"id": "a", "initrds": []}]}"#,
+        ] {
+            assert!(Deployments::from_manifest(manifest.as_bytes()).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn install_deployments_and_remove_stale_ones() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let deployment = |id: &str| -> Result<Deployment> {
+            let kernel = tmpdir.path().join(format!("vmlinuz-{id}"));
+            let initrd = tmpdir.path().join(format!("initrd-{id}"));
+            fs::write(&kernel, format!("kernel {id}"))?;
+            fs::write(&initrd, format!("initrd {id}"))?;
+            Ok(Deployment {
+                id: id.into(),
+                title: format!("Appliance ({id})"),
+                version: Some("2024.07".into()),
+                kernel,
+                initrds: vec![initrd],
+                cmdline: vec![format!("root=PARTLABEL=root-{id}")],
+            })
+        };
+        let mut deployments = Deployments {
+            name: "appliance".into(),
+            deployments: vec![deployment("a")?, deployment("b")?],
+        };
+        let esp = Path::new("/boot");
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(Path::new("/boot/EFI/Linux/other.efi"), b"other")?;
+
+        let changed = deployments.install(&mut esp_fs, &MockSigner, Path::new("/stub"), esp)?;
+        assert_eq!(changed.installed.len(), 6);
+        assert!(changed.removed.is_empty());
+        let stub_a = Path::new("/boot/EFI/Linux/appliance-deployment-a.efi");
+        let parameters: StubParameters = serde_json::from_slice(&esp_fs.read(stub_a)?)?;
+        assert_eq!(parameters.kernel_cmdline, ["root=PARTLABEL=root-a"]);
+
+        deployments.deployments.remove(0);
+        let changed = deployments.install(&mut esp_fs, &MockSigner, Path::new("/stub"), esp)?;
+        assert_eq!(changed.removed.len(), 3);
+        assert!(!esp_fs.exists(stub_a));
+        assert!(esp_fs.exists(Path::new("/boot/EFI/Linux/appliance-deployment-b.efi")));
+        assert!(esp_fs.exists(Path::new("/boot/EFI/Linux/other.efi")));
+        assert_eq!(esp_fs.list(Path::new("/boot/EFI/appliance"))?.len(), 2);
+        Ok(())
+    }
+}
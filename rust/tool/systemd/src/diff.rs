@@ -0,0 +1,268 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::pcr::expected_pcr11;
+use lanzaboote_tool::pe::read_section_data;
+use lanzaboote_tool::revocation::format_hash;
+
+use crate::migrate::generation_from_entry_name;
+use crate::namespace::{self, split_namespace};
+
+/// The prefix of the name the stub reports, followed by its version.
+const STUB_NAME_PREFIX: &[u8] = b"lanzastub ";
+
+/// The boot-relevant data of an installed boot entry, in the order in which it is compared.
+pub struct EntrySummary {
+    pub entry: String,
+    fields: Vec<(&'static str, Option<String>)>,
+}
+
+impl EntrySummary {
+    /// Read the data that is embedded into an installed stub.
+    pub fn from_stub(path: &Path) -> Result<Self> {
+        let image = fs::read(path).with_context(|| format!("Failed to read stub {path:?}"))?;
+        let entry = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let section = |name| {
+            read_section_data(&image, name).map(|data| String::from_utf8_lossy(data).into_owned())
+        };
+        let hash = |name| read_section_data(&image, name).map(format_hash);
+        let os_release = section(".osrel")
+            .and_then(|osrel| OsRelease::from_str(&osrel).ok())
+            .and_then(|osrel| osrel.0.get("PRETTY_NAME").cloned());
+
+        let fields = vec![
+            ("stub_version", stub_version(&image)),
+            ("os_release", os_release),
+            ("kernel", section(".linux")),
+            ("kernel_hash", hash(".linuxh")),
+            ("initrd", section(".initrd")),
+            ("initrd_hash", hash(".initrdh")),
+            ("cmdline", section(".cmdline")),
+            ("security_version", section(".svn")),
+            ("verity_root_hash", section(".roothash")),
+            ("lockdown_policy", section(".lockdown")),
+            (
+                "pcr11",
+                Some(format!(
+                    "{:x}",
+                    expected_pcr11(&image).context("Failed to compute the expected PCR 11")?
+                )),
+            ),
+        ];
+
+        Ok(Self { entry, fields })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = serde_json::Map::new();
+        object.insert("entry".to_string(), json!(self.entry));
+        for (name, value) in &self.fields {
+            object.insert(name.to_string(), json!(value));
+        }
+        Value::Object(object)
+    }
+}
+
+/// Find the version in the name that the stub reports in `StubInfo`, e.g. `lanzastub 0.4.2`.
+pub fn stub_version(image: &[u8]) -> Option<String> {
+    let start = image
+        .windows(STUB_NAME_PREFIX.len())
+        .position(|window| window == STUB_NAME_PREFIX)?
+        + STUB_NAME_PREFIX.len();
+    let version = image[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'+'))
+        .map(|b| char::from(*b))
+        .collect::<String>();
+    (!version.is_empty()).then_some(version)
+}
+
+/// A field that differs between two entries.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Change<'a> {
+    pub field: &'static str,
+    pub from: Option<&'a str>,
+    pub to: Option<&'a str>,
+}
+
+/// Compare the fields of two entries.
+pub fn changes<'a>(from: &'a EntrySummary, to: &'a EntrySummary) -> Vec<Change<'a>> {
+    from.fields
+        .iter()
+        .zip(&to.fields)
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((field, a), (_, b))| Change {
+            field,
+            from: a.as_deref(),
+            to: b.as_deref(),
+        })
+        .collect()
+}
+
+/// Find the default entry of a generation in a namespace among the installed entries, i.e. not a
+/// specialisation or recovery entry.
+fn default_entry<'a>(
+    entries: &'a [String],
+    namespace: Option<&str>,
+    generation: u64,
+) -> Option<&'a String> {
+    entries.iter().find(|entry| {
+        let (entry_namespace, name) = split_namespace(entry);
+        entry_namespace == namespace
+            && generation_from_entry_name(name) == Some(generation)
+            && name
+                .strip_prefix(&format!("nixos-generation-{generation}-"))
+                .is_some_and(|rest| !rest.contains('-'))
+    })
+}
+
+/// Find the installed stub of a generation in the `EFI/Linux` directory of the ESP.
+///
+/// Only the entries in the namespace of the running system are considered, see
+/// [`crate::namespace::current`].
+pub fn find_stub(esp: &Path, generation: u64) -> Result<PathBuf> {
+    let linux = esp.join("EFI/Linux");
+    let entries = fs::read_dir(&linux)
+        .with_context(|| format!("Failed to read {linux:?}"))?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+
+    default_entry(&entries, namespace::current(esp).as_deref(), generation)
+        .map(|entry| linux.join(entry))
+        .with_context(|| format!("Generation {generation} is not installed in {linux:?}"))
+}
+
+/// Print the differences between two entries, either for humans or as JSON.
+pub fn print_diff(from: &EntrySummary, to: &EntrySummary, as_json: bool) {
+    let changes = changes(from, to);
+
+    if as_json {
+        let changes = changes
+            .iter()
+            .map(|change| json!({ "field": change.field, "from": change.from, "to": change.to }))
+            .collect::<Vec<_>>();
+        let diff = json!({
+            "from": from.to_json(),
+            "to": to.to_json(),
+            "changes": changes,
+        });
+        println!("{diff:#}");
+        return;
+    }
+
+    println!("--- {}", from.entry);
+    println!("+++ {}", to.entry);
+    if changes.is_empty() {
+        println!("No boot-relevant changes.");
+        return;
+    }
+    for change in &changes {
+        println!("{}:", change.field);
+        println!("- {}", change.from.unwrap_or("(none)"));
+        println!("+ {}", change.to.unwrap_or("(none)"));
+    }
+    if changes.iter().any(|change| change.field == "pcr11") {
+        println!();
+        println!(
+            "PCR 11 changes, so secrets sealed against it, e.g. with systemd-cryptenroll, must be \
+             re-enrolled."
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(entry: &str, fields: Vec<(&'static str, Option<&str>)>) -> EntrySummary {
+        EntrySummary {
+            entry: entry.to_string(),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name, value.map(str::to_string)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn only_differing_fields_are_changes() {
+        let from = summary(
+            "nixos-generation-1-a.efi",
+            vec![
+                ("kernel", Some("\\EFI\\nixos\\kernel-1.efi")),
+                ("cmdline", Some("init=/a")),
+                ("security_version", None),
+            ],
+        );
+        let to = summary(
+            "nixos-generation-2-b.efi",
+            vec![
+                ("kernel", Some("\\EFI\\nixos\\kernel-1.efi")),
+                ("cmdline", Some("init=/b")),
+                ("security_version", Some("2")),
+            ],
+        );
+
+        assert_eq!(
+            changes(&from, &to),
+            vec![
+                Change {
+                    field: "cmdline",
+                    from: Some("init=/a"),
+                    to: Some("init=/b")
+                },
+                Change {
+                    field: "security_version",
+                    from: None,
+                    to: Some("2")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn find_default_entry_of_generation() {
+        let entries = [
+            "nixos-generation-1-a.efi",
+            "nixos-generation-10-b.efi",
+            "nixos-generation-1-recovery-c.efi",
+            "nixos-generation-1-specialisation-foo-d.efi",
+            "0123456789abcdef0123456789abcdef-nixos-generation-2-e.efi",
+        ]
+        .map(str::to_string);
+
+        assert_eq!(
+            default_entry(&entries, None, 1).map(String::as_str),
+            Some("nixos-generation-1-a.efi")
+        );
+        assert_eq!(
+            default_entry(&entries, None, 10).map(String::as_str),
+            Some("nixos-generation-10-b.efi")
+        );
+        assert_eq!(default_entry(&entries, None, 2), None);
+        assert_eq!(
+            default_entry(&entries, Some("0123456789abcdef0123456789abcdef"), 2)
+                .map(String::as_str),
+            Some("0123456789abcdef0123456789abcdef-nixos-generation-2-e.efi")
+        );
+    }
+
+    #[test]
+    fn read_stub_version() {
+        assert_eq!(
+            stub_version(b"\0\0lanzastub 0.4.2\0\0"),
+            Some("0.4.2".to_string())
+        );
+        assert_eq!(stub_version(b"lanzastub \0"), None);
+        assert_eq!(stub_version(b"systemd-stub"), None);
+    }
+}
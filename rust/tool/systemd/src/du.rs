@@ -0,0 +1,344 @@
+//! `lzbt du`: what takes up the space on the ESP.
+//!
+//! Every file on the ESP is attributed to an artifact type and to the generations that use it.
+//! The kernels and initrds are content-addressed, so generations that only differ in their
+//! configuration share them. Removing a generation only frees its stubs, its addons and the files
+//! that no other generation refers to, which is what the report shows per generation to help
+//! decide what to prune on a small ESP.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::check::section_text;
+use crate::esp::SystemdEspPaths;
+use crate::inspect::esp_path;
+use crate::metrics::esp_space;
+use crate::migrate::{generation_from_entry_name, is_lanzaboote_entry};
+use crate::progress::format_bytes;
+
+/// What a file on the ESP is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Artifact {
+    Stub,
+    Addon,
+    Kernel,
+    Initrd,
+    /// systemd-boot and the EFI fallback.
+    BootLoader,
+    /// A file in `EFI/nixos` that no installed stub refers to.
+    Unreferenced,
+    Other,
+}
+
+impl Artifact {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stub => "stub",
+            Self::Addon => "addon",
+            Self::Kernel => "kernel",
+            Self::Initrd => "initrd",
+            Self::BootLoader => "boot loader",
+            Self::Unreferenced => "unreferenced",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// A file on the ESP and the generations that use it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EspFile {
+    /// The path relative to the ESP.
+    pub path: PathBuf,
+    pub size: u64,
+    pub artifact: Artifact,
+    pub generations: BTreeSet<u64>,
+}
+
+/// The space that the files of a generation take up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenerationUsage {
+    /// The bytes that only this generation uses, i.e. that removing it frees.
+    pub exclusive: u64,
+    /// The bytes that this generation shares with other generations.
+    pub shared: u64,
+}
+
+/// The usage of the ESP, see [`Usage::read`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub files: Vec<EspFile>,
+    /// The available and the total bytes of the ESP, if they can be determined.
+    pub space: Option<(u64, u64)>,
+}
+
+/// All files below `directory`, recursively.
+fn walk(directory: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    for entry in fs::read_dir(directory).with_context(|| format!("Failed to read {directory:?}"))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if metadata.is_file() {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// The kernel and initrd that a stub refers to, as paths on the mounted ESP.
+fn referenced_files(esp: &Path, stub: &Path) -> Vec<(Artifact, PathBuf)> {
+    let Ok(image) = fs::read(stub) else {
+        log::warn!("Failed to read the stub {stub:?}");
+        return Vec::new();
+    };
+    [(Artifact::Kernel, ".linux"), (Artifact::Initrd, ".initrd")]
+        .into_iter()
+        .filter_map(|(artifact, section)| {
+            let path = section_text(&image, section)?;
+            Some((artifact, esp_path(esp, path.trim_end_matches('\0').trim())))
+        })
+        .collect()
+}
+
+impl Usage {
+    /// Attribute every file on the ESP to an artifact type and to generations.
+    pub fn read(paths: &SystemdEspPaths) -> Result<Self> {
+        let mut found = Vec::new();
+        walk(&paths.esp, &mut found)?;
+
+        let mut files = found
+            .into_iter()
+            .map(|(path, size)| {
+                let (artifact, generation) = classify(paths, &path);
+                EspFile {
+                    path,
+                    size,
+                    artifact,
+                    generations: generation.into_iter().collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The kernels and initrds belong to the generations of the stubs that refer to them.
+        let mut references = BTreeMap::<PathBuf, (Artifact, BTreeSet<u64>)>::new();
+        for stub in files.iter().filter(|file| file.artifact == Artifact::Stub) {
+            for (artifact, path) in referenced_files(&paths.esp, &stub.path) {
+                let (_, generations) = references
+                    .entry(path)
+                    .or_insert_with(|| (artifact, BTreeSet::new()));
+                generations.extend(&stub.generations);
+            }
+        }
+        for file in &mut files {
+            if let Some((artifact, generations)) = references.remove(&file.path) {
+                file.artifact = artifact;
+                file.generations = generations;
+            }
+        }
+
+        for file in &mut files {
+            if let Ok(path) = file.path.strip_prefix(&paths.esp) {
+                file.path = path.to_path_buf();
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self {
+            files,
+            space: esp_space(&paths.esp).ok(),
+        })
+    }
+
+    /// The space that the files of each generation take up.
+    pub fn generations(&self) -> BTreeMap<u64, GenerationUsage> {
+        let mut generations = BTreeMap::<u64, GenerationUsage>::new();
+        for file in &self.files {
+            for generation in &file.generations {
+                let usage = generations.entry(*generation).or_default();
+                if file.generations.len() == 1 {
+                    usage.exclusive += file.size;
+                } else {
+                    usage.shared += file.size;
+                }
+            }
+        }
+        generations
+    }
+
+    /// The bytes of each artifact type.
+    pub fn artifacts(&self) -> BTreeMap<Artifact, u64> {
+        let mut artifacts = BTreeMap::new();
+        for file in &self.files {
+            *artifacts.entry(file.artifact).or_default() += file.size;
+        }
+        artifacts
+    }
+
+    /// The bytes of the kernels and initrds that more than one generation uses.
+    pub fn shared(&self) -> u64 {
+        self.files
+            .iter()
+            .filter(|file| file.generations.len() > 1)
+            .map(|file| file.size)
+            .sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "generations": self
+                .generations()
+                .into_iter()
+                .map(|(generation, usage)| json!({
+                    "generation": generation,
+                    "exclusiveBytes": usage.exclusive,
+                    "sharedBytes": usage.shared,
+                }))
+                .collect::<Vec<_>>(),
+            "artifacts": self
+                .artifacts()
+                .into_iter()
+                .map(|(artifact, bytes)| (artifact.as_str().to_string(), json!(bytes)))
+                .collect::<serde_json::Map<_, _>>(),
+            "sharedBytes": self.shared(),
+            "files": self
+                .files
+                .iter()
+                .map(|file| json!({
+                    "path": file.path,
+                    "size": file.size,
+                    "artifact": file.artifact.as_str(),
+                    "generations": file.generations,
+                }))
+                .collect::<Vec<_>>(),
+            "freeBytes": self.space.map(|(free, _)| free),
+            "totalBytes": self.space.map(|(_, total)| total),
+        })
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{:>10}  {:>12}  {:>12}",
+            "Generation", "Exclusive", "Shared"
+        );
+        for (generation, usage) in self.generations() {
+            println!(
+                "{generation:>10}  {:>12}  {:>12}",
+                format_bytes(usage.exclusive),
+                format_bytes(usage.shared)
+            );
+        }
+        println!();
+        println!("{:<12}  {:>12}", "Artifact", "Size");
+        for (artifact, bytes) in self.artifacts() {
+            println!("{:<12}  {:>12}", artifact.as_str(), format_bytes(bytes));
+        }
+        println!();
+        println!(
+            "Shared between generations: {}",
+            format_bytes(self.shared())
+        );
+        if let Some((free, total)) = self.space {
+            println!("Free: {} of {}", format_bytes(free), format_bytes(total));
+        }
+    }
+}
+
+/// The artifact type of a file by its location and, for stubs and addons, their generation.
+///
+/// Kernels and initrds are only recognized by the stubs that refer to them.
+fn classify(paths: &SystemdEspPaths, path: &Path) -> (Artifact, Option<u64>) {
+    if let Ok(relative) = path.strip_prefix(&paths.linux) {
+        let entry = relative
+            .components()
+            .next()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // Addons are in a drop-in directory next to the stub, e.g. `<stub>.efi.extra`.
+        if let Some(stub) = entry.strip_suffix(".extra") {
+            return (Artifact::Addon, generation_from_entry_name(stub));
+        }
+        if is_lanzaboote_entry(&entry) {
+            return (Artifact::Stub, generation_from_entry_name(&entry));
+        }
+    } else if path.starts_with(&paths.systemd) || path.starts_with(&paths.efi_fallback_dir) {
+        return (Artifact::BootLoader, None);
+    } else if path.starts_with(&paths.nixos) {
+        return (Artifact::Unreferenced, None);
+    }
+    (Artifact::Other, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::architecture::Architecture;
+    use lanzaboote_tool::esp::EspPaths;
+
+    #[test]
+    fn attribute_files_to_generations() {
+        let paths = SystemdEspPaths::new("/boot", Architecture::X86);
+        let classified = |path: &str| classify(&paths, Path::new(path));
+        assert_eq!(
+            classified("/boot/EFI/Linux/nixos-generation-3-abc.efi"),
+            (Artifact::Stub, Some(3))
+        );
+        assert_eq!(
+            classified("/boot/EFI/Linux/nixos-generation-3-abc.efi.extra/debug.addon.efi"),
+            (Artifact::Addon, Some(3))
+        );
+        assert_eq!(
+            classified("/boot/EFI/systemd/systemd-bootx64.efi"),
+            (Artifact::BootLoader, None)
+        );
+        assert_eq!(
+            classified("/boot/EFI/nixos/kernel-6.6-abc.efi"),
+            (Artifact::Unreferenced, None)
+        );
+        assert_eq!(
+            classified("/boot/loader/loader.conf"),
+            (Artifact::Other, None)
+        );
+
+        let file = |size, artifact, generations: &[u64]| EspFile {
+            path: PathBuf::new(),
+            size,
+            artifact,
+            generations: generations.iter().copied().collect(),
+        };
+        let usage = Usage {
+            files: vec![
+                file(1, Artifact::Stub, &[1]),
+                file(2, Artifact::Stub, &[2]),
+                file(40, Artifact::Kernel, &[1, 2]),
+                file(300, Artifact::Initrd, &[1]),
+                file(5000, Artifact::Unreferenced, &[]),
+            ],
+            space: None,
+        };
+        assert_eq!(
+            usage.generations(),
+            BTreeMap::from([
+                (
+                    1,
+                    GenerationUsage {
+                        exclusive: 301,
+                        shared: 40
+                    }
+                ),
+                (
+                    2,
+                    GenerationUsage {
+                        exclusive: 2,
+                        shared: 40
+                    }
+                ),
+            ])
+        );
+        assert_eq!(usage.shared(), 40);
+        assert_eq!(usage.artifacts()[&Artifact::Unreferenced], 5000);
+    }
+}
@@ -0,0 +1,157 @@
+//! Expiry of the signing certificate and of the certificates in the firmware's db.
+//!
+//! Most firmware ignores the validity period of the certificates in db, but some refuses to boot
+//! binaries that are signed with an expired or not yet valid certificate. Because a Secure Boot
+//! PKI is easily set up and then forgotten, `lzbt install` warns well before the signing
+//! certificate expires and refuses to sign with a certificate outside of its validity period,
+//! unless `--allow-invalid-certificate` is given. `lzbt status` lists the validity of all of them.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use time::{Duration, OffsetDateTime};
+
+use lanzaboote_tool::certificate::{subject_name, validity, Validity};
+
+/// How long before the expiry of a certificate it is warned about.
+pub const WARNING_PERIOD: Duration = Duration::days(30);
+
+/// Where a certificate is in its validity period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Valid,
+    /// The certificate expires within [`WARNING_PERIOD`].
+    ExpiresSoon,
+    Expired,
+    NotYetValid,
+}
+
+impl Expiry {
+    pub fn at(validity: &Validity, now: OffsetDateTime) -> Self {
+        if now < validity.not_before {
+            Self::NotYetValid
+        } else if now > validity.not_after {
+            Self::Expired
+        } else if now + WARNING_PERIOD > validity.not_after {
+            Self::ExpiresSoon
+        } else {
+            Self::Valid
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Valid => "valid",
+            Self::ExpiresSoon => "expires soon",
+            Self::Expired => "expired",
+            Self::NotYetValid => "not yet valid",
+        }
+    }
+}
+
+/// Where a certificate comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The certificate that the stubs are signed with.
+    Signing,
+    /// A certificate in the firmware's db.
+    Db,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Signing => "signing",
+            Self::Db => "db",
+        }
+    }
+}
+
+/// The validity of a certificate, as shown by `lzbt status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateStatus {
+    pub source: Source,
+    pub name: Option<String>,
+    pub not_after: OffsetDateTime,
+    pub expiry: Expiry,
+}
+
+impl CertificateStatus {
+    /// The status of a DER encoded certificate, if its validity can be parsed.
+    pub fn read(source: Source, der: &[u8], now: OffsetDateTime) -> Option<Self> {
+        let validity = validity(der)?;
+        Some(Self {
+            source,
+            name: subject_name(der),
+            not_after: validity.not_after,
+            expiry: Expiry::at(&validity, now),
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.source.as_str(),
+            "name": self.name,
+            "notAfter": self.not_after.unix_timestamp(),
+            "expiry": self.expiry.as_str(),
+        })
+    }
+}
+
+/// Check that the DER encoded signing certificate is within its validity period.
+///
+/// A certificate that expires soon is warned about. Signing with an expired or not yet valid
+/// certificate fails unless `allow_invalid` is set.
+pub fn check_signing_certificate(der: &[u8], allow_invalid: bool) -> Result<()> {
+    let Some(validity) = validity(der) else {
+        log::warn!("Failed to read the validity period of the signing certificate.");
+        return Ok(());
+    };
+    let name = subject_name(der).unwrap_or_else(|| "The signing certificate".to_string());
+    let not_after = validity.not_after.date();
+    let message = match Expiry::at(&validity, OffsetDateTime::now_utc()) {
+        Expiry::Valid => return Ok(()),
+        Expiry::ExpiresSoon => {
+            log::warn!("{name} expires on {not_after}. Replace it and enroll the new certificate before then.");
+            return Ok(());
+        }
+        Expiry::Expired => format!("{name} expired on {not_after}."),
+        Expiry::NotYetValid => format!(
+            "{name} is only valid from {}. Check the system clock.",
+            validity.not_before.date()
+        ),
+    };
+    if allow_invalid {
+        log::warn!("{message} Some firmware refuses to boot what it signs.");
+        return Ok(());
+    }
+    bail!("{message} Some firmware refuses to boot what it signs. Pass --allow-invalid-certificate to sign with it anyway.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_expiry() {
+        let now = OffsetDateTime::from_unix_timestamp(1_800_000_000).unwrap();
+        let validity = |not_before: Duration, not_after: Duration| Validity {
+            not_before: now + not_before,
+            not_after: now + not_after,
+        };
+        let year = Duration::days(365);
+
+        assert_eq!(Expiry::at(&validity(-year, year), now), Expiry::Valid);
+        assert_eq!(
+            Expiry::at(&validity(-year, Duration::days(10)), now),
+            Expiry::ExpiresSoon
+        );
+        assert_eq!(
+            Expiry::at(&validity(-year, -Duration::days(1)), now),
+            Expiry::Expired
+        );
+        assert_eq!(
+            Expiry::at(&validity(Duration::hours(1), year), now),
+            Expiry::NotYetValid
+        );
+    }
+}
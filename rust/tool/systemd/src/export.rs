@@ -0,0 +1,324 @@
+//! Export and import of the files that Lanzaboote manages on the ESP.
+//!
+//! An export is a tar archive, optionally compressed with zstd, of systemd-boot, the stubs, the
+//! kernels and initrds, and the loader configuration including all boot entries. The manifest
+//! `lanzaboote-export.json` at the root of the archive lists the SHA256 hash of every file, so that
+//! a damaged archive is refused before anything on the ESP is touched.
+//!
+//! Importing replaces the managed directories of the ESP with the contents of an export. This
+//! restores a backup after a risky change or clones the boot configuration to a replacement disk.
+//! The random seed of systemd-boot is neither exported nor replaced, so that clones do not share
+//! it.
+
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::revocation::format_hash;
+
+/// The directories of the ESP, relative to it, whose files are exported.
+const MANAGED_DIRS: [&str; 5] = [
+    "EFI/BOOT",
+    "EFI/Linux",
+    "EFI/nixos",
+    "EFI/systemd",
+    "loader",
+];
+
+/// Files in the managed directories that are specific to a machine.
+const EXCLUDED_FILES: [&str; 1] = ["loader/random-seed"];
+
+/// The file name of the manifest at the root of the archive.
+const MANIFEST: &str = "lanzaboote-export.json";
+
+/// The version of the manifest format.
+const MANIFEST_VERSION: u64 = 1;
+
+/// The format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::TarZst => "tar.zst",
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar" => Ok(Self::Tar),
+            "tar.zst" => Ok(Self::TarZst),
+            _ => bail!("Unknown archive format {s:?}, expected tar or tar.zst"),
+        }
+    }
+}
+
+/// Whether a path relative to the ESP belongs to the exported state.
+fn is_managed(relative: &Path) -> bool {
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        && MANAGED_DIRS.iter().any(|dir| relative.starts_with(dir))
+        && !EXCLUDED_FILES
+            .iter()
+            .any(|file| relative == Path::new(file))
+}
+
+/// The files in the managed directories, relative to the ESP and in lexicographic order.
+fn managed_files(esp_fs: &impl EspFilesystem, esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut directories = MANAGED_DIRS
+        .iter()
+        .map(|dir| esp.join(dir))
+        .filter(|dir| esp_fs.is_dir(dir))
+        .collect::<Vec<_>>();
+    let mut files = Vec::new();
+    while let Some(directory) = directories.pop() {
+        for child in esp_fs.list(&directory)? {
+            if esp_fs.is_dir(&child) {
+                directories.push(child);
+                continue;
+            }
+            let relative = child.strip_prefix(esp)?.to_path_buf();
+            if is_managed(&relative) {
+                files.push(relative);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Copy the managed files of the ESP and their manifest into `dir`, the root of the archive.
+///
+/// Returns the number of files.
+pub fn stage_export(esp_fs: &impl EspFilesystem, esp: &Path, dir: &Path) -> Result<usize> {
+    let files = managed_files(esp_fs, esp)?;
+    let mut hashes = serde_json::Map::new();
+    for file in &files {
+        let name = file
+            .to_str()
+            .with_context(|| format!("The file name {file:?} is not valid UTF-8"))?;
+        let contents = esp_fs.read(&esp.join(file))?;
+        hashes.insert(
+            name.to_string(),
+            json!(format_hash(&Sha256::digest(&contents))),
+        );
+
+        let target = dir.join(file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::write(&target, contents).with_context(|| format!("Failed to write {target:?}"))?;
+    }
+
+    let manifest = json!({
+        "version": MANIFEST_VERSION,
+        "lzbt_version": env!("CARGO_PKG_VERSION"),
+        "files": hashes,
+    });
+    fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)
+        .context("Failed to write the manifest")?;
+    Ok(files.len())
+}
+
+/// Read the manifest of an extracted export in `dir` and check every file against it.
+///
+/// Returns the files of the export, relative to the ESP.
+fn verify_export(dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest: Value = fs::read(dir.join(MANIFEST))
+        .context("The archive has no manifest, so it is not an export of lzbt")
+        .and_then(|raw| serde_json::from_slice(&raw).context("Failed to parse the manifest"))?;
+    let version = manifest["version"].as_u64();
+    if version != Some(MANIFEST_VERSION) {
+        bail!("Unsupported manifest version {version:?}, expected {MANIFEST_VERSION}");
+    }
+    let hashes = manifest["files"]
+        .as_object()
+        .context("The manifest does not list the files")?;
+
+    let mut files = Vec::new();
+    for (name, hash) in hashes {
+        let file = PathBuf::from(name);
+        if !is_managed(&file) {
+            bail!("The archive contains {name:?}, which is not managed by Lanzaboote");
+        }
+        let contents =
+            fs::read(dir.join(&file)).with_context(|| format!("The archive lacks {name:?}"))?;
+        if hash.as_str() != Some(format_hash(&Sha256::digest(contents)).as_str()) {
+            bail!("The contents of {name:?} do not match the manifest, so the archive is damaged");
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Replace the managed files of the ESP with an extracted export in `dir`.
+///
+/// The export is checked completely before the ESP is modified. Managed files that are not part
+/// of the export are removed only after all files of the export were written. Returns the number
+/// of files that were written.
+pub fn restore(esp_fs: &mut impl EspFilesystem, esp: &Path, dir: &Path) -> Result<usize> {
+    let files = verify_export(dir)?;
+
+    let mut written = 0;
+    for file in &files {
+        let contents = fs::read(dir.join(file))
+            .with_context(|| format!("Failed to read {file:?} from the archive"))?;
+        let target = esp.join(file);
+        if esp_fs.exists(&target) && esp_fs.read(&target)? == contents {
+            continue;
+        }
+        esp_fs.write(&target, &contents)?;
+        written += 1;
+    }
+    for file in managed_files(esp_fs, esp)? {
+        if !files.contains(&file) {
+            log::debug!("Removing {file:?}, which is not part of the export.");
+            esp_fs.delete(&esp.join(file))?;
+        }
+    }
+    esp_fs.sync(esp)?;
+    Ok(written)
+}
+
+/// Export the managed files of the ESP into the archive `output`.
+pub fn export(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    output: &Path,
+    format: ArchiveFormat,
+) -> Result<()> {
+    let staging = tempfile::tempdir().context("Failed to create a temporary directory")?;
+    let count = stage_export(esp_fs, esp, staging.path())?;
+
+    let mut tar = Command::new("tar");
+    tar.arg("--create")
+        .arg("--file")
+        .arg(output)
+        .arg("--directory")
+        .arg(staging.path());
+    if format == ArchiveFormat::TarZst {
+        tar.arg("--zstd");
+    }
+    let status = tar.arg(".").status().context("Failed to run tar")?;
+    if !status.success() {
+        bail!("Failed to create the archive {output:?}: tar exited with {status}");
+    }
+
+    log::info!("Exported {count} files from {esp:?} to {output:?}.");
+    Ok(())
+}
+
+/// Replace the managed files of the ESP with the export in the archive `archive`.
+pub fn import(esp_fs: &mut impl EspFilesystem, esp: &Path, archive: &Path) -> Result<()> {
+    let staging = tempfile::tempdir().context("Failed to create a temporary directory")?;
+    // tar detects the compression itself when it extracts.
+    let status = Command::new("tar")
+        .arg("--extract")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(staging.path())
+        .status()
+        .context("Failed to run tar")?;
+    if !status.success() {
+        bail!("Failed to extract the archive {archive:?}: tar exited with {status}");
+    }
+
+    let written = restore(esp_fs, esp, staging.path())
+        .with_context(|| format!("Failed to import {archive:?}"))?;
+    log::info!("Imported {archive:?} into {esp:?}, {written} files changed.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    const ESP: &str = "/boot";
+
+    fn esp_with(files: &[(&str, &str)]) -> Result<InMemoryEspFilesystem> {
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        for (file, contents) in files {
+            esp_fs.write(&Path::new(ESP).join(file), contents.as_bytes())?;
+        }
+        Ok(esp_fs)
+    }
+
+    #[test]
+    fn restore_managed_files_only() -> Result<()> {
+        let esp = Path::new(ESP);
+        let source = esp_with(&[
+            ("EFI/Linux/nixos-generation-1.efi", "stub"),
+            ("EFI/nixos/kernel-6.1.1-abc.efi", "kernel"),
+            ("loader/loader.conf", "timeout 3"),
+            ("loader/random-seed", "seed"),
+            ("EFI/Microsoft/Boot/bootmgfw.efi", "windows"),
+        ])?;
+        let staging = tempfile::tempdir()?;
+        assert_eq!(stage_export(&source, esp, staging.path())?, 3);
+
+        let mut target = esp_with(&[
+            ("EFI/Linux/nixos-generation-2.efi", "newer stub"),
+            ("loader/loader.conf", "timeout 0"),
+            ("loader/random-seed", "other seed"),
+        ])?;
+        assert_eq!(restore(&mut target, esp, staging.path())?, 3);
+        assert_eq!(
+            target.files().collect::<Vec<_>>(),
+            [
+                "/boot/EFI/Linux/nixos-generation-1.efi",
+                "/boot/EFI/nixos/kernel-6.1.1-abc.efi",
+                "/boot/loader/loader.conf",
+                "/boot/loader/random-seed",
+            ]
+            .map(Path::new)
+        );
+        assert_eq!(target.read(&esp.join("loader/random-seed"))?, b"other seed");
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_damaged_exports() -> Result<()> {
+        let esp = Path::new(ESP);
+        let source = esp_with(&[("EFI/Linux/nixos-generation-1.efi", "stub")])?;
+        let staging = tempfile::tempdir()?;
+        stage_export(&source, esp, staging.path())?;
+        fs::write(
+            staging.path().join("EFI/Linux/nixos-generation-1.efi"),
+            "tampered",
+        )?;
+
+        let mut target = esp_with(&[("EFI/Linux/nixos-generation-2.efi", "stub")])?;
+        assert!(restore(&mut target, esp, staging.path()).is_err());
+        assert!(target.exists(&esp.join("EFI/Linux/nixos-generation-2.efi")));
+
+        assert!(!is_managed(Path::new("EFI/Linux/../../etc/passwd")));
+        assert!(!is_managed(Path::new("/EFI/Linux/stub.efi")));
+        Ok(())
+    }
+}
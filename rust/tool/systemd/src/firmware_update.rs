@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::efivars::{
+    read_variable, write_string_variable, write_variable, EFI_GLOBAL_VARIABLE_GUID,
+    EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_NON_VOLATILE, EFI_VARIABLE_RUNTIME_ACCESS,
+    LOADER_GUID,
+};
+use lanzaboote_tool::esp_fs::EspFilesystem;
+
+/// The loader entry that starts the signed firmware updater.
+pub const UPDATER_ENTRY: &str = "lanzaboote-firmware-update.conf";
+
+/// The title of the loader entry of the firmware updater.
+pub const UPDATER_TITLE: &str = "Firmware Update";
+
+/// The directory in `EFI/nixos` in which fwupd stages capsules for its UEFI updater.
+pub const FWUPD_CAPSULE_DIRECTORY: &str = "fw";
+
+/// The directory on the ESP in which the firmware looks for capsules delivered on disk.
+const CAPSULE_DIRECTORY: &str = "EFI/UpdateCapsule";
+
+/// The bit of `OsIndications` that makes the firmware process capsules on disk on the next boot.
+const EFI_OS_INDICATIONS_FILE_CAPSULE_DELIVERY_SUPPORTED: u64 = 0x4;
+
+/// The size of `EFI_CAPSULE_HEADER`.
+const CAPSULE_HEADER_SIZE: usize = 28;
+
+/// Check that `capsule` starts with a plausible `EFI_CAPSULE_HEADER`.
+fn check_capsule(capsule: &[u8]) -> Result<()> {
+    let field = |offset: usize| {
+        capsule
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().expect("Four bytes")) as usize)
+    };
+    let (Some(header_size), Some(image_size)) = (field(16), field(24)) else {
+        bail!("The capsule is too short");
+    };
+    if header_size < CAPSULE_HEADER_SIZE || header_size > image_size || image_size > capsule.len() {
+        bail!("Not a UEFI capsule: the sizes in its header are inconsistent");
+    }
+    Ok(())
+}
+
+/// Copy a capsule to the ESP for delivery on disk and return the new value of `OsIndications`.
+///
+/// The firmware processes the capsules on the ESP of the boot option it boots next, i.e. of
+/// `BootNext` if set and otherwise of the first entry of `BootOrder`, and removes them afterwards.
+pub fn stage_capsule(
+    esp_fs: &mut impl EspFilesystem,
+    esp: &Path,
+    capsule: &Path,
+    os_indications_supported: u64,
+    os_indications: u64,
+) -> Result<u64> {
+    if os_indications_supported & EFI_OS_INDICATIONS_FILE_CAPSULE_DELIVERY_SUPPORTED == 0 {
+        bail!(
+            "The firmware does not support capsules on disk. Use fwupd with the signed firmware updater of `lzbt install --firmware-updater` instead."
+        );
+    }
+
+    let contents = fs::read(capsule).with_context(|| format!("Failed to read {capsule:?}"))?;
+    check_capsule(&contents).with_context(|| format!("Invalid capsule {capsule:?}"))?;
+    let name = capsule
+        .file_name()
+        .with_context(|| format!("Invalid capsule path {capsule:?}"))?;
+    let target = esp.join(CAPSULE_DIRECTORY).join(name);
+    esp_fs
+        .write(&target, &contents)
+        .with_context(|| format!("Failed to copy the capsule to {target:?}"))?;
+
+    Ok(os_indications | EFI_OS_INDICATIONS_FILE_CAPSULE_DELIVERY_SUPPORTED)
+}
+
+fn read_u64_variable(name: &str) -> Result<u64> {
+    Ok(read_variable(name, &EFI_GLOBAL_VARIABLE_GUID)?
+        .and_then(|value| Some(u64::from_le_bytes(value.get(..8)?.try_into().ok()?)))
+        .unwrap_or_default())
+}
+
+/// Stage a capsule, which the firmware applies on the next boot.
+pub fn stage(esp_fs: &mut impl EspFilesystem, esp: &Path, capsule: &Path) -> Result<()> {
+    let os_indications = stage_capsule(
+        esp_fs,
+        esp,
+        capsule,
+        read_u64_variable("OsIndicationsSupported")?,
+        read_u64_variable("OsIndications")?,
+    )?;
+    write_variable(
+        "OsIndications",
+        &EFI_GLOBAL_VARIABLE_GUID,
+        EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS,
+        &os_indications.to_le_bytes(),
+    )
+    .context("Failed to request the capsule update from the firmware")?;
+
+    if read_variable("BootNext", &EFI_GLOBAL_VARIABLE_GUID)?.is_some() {
+        log::warn!(
+            "BootNext is set, so the firmware only finds the capsule if that boot option is on the ESP {esp:?}."
+        );
+    }
+    log::info!("Staged {capsule:?}. The firmware applies it on the next boot.");
+    Ok(())
+}
+
+/// The path of the loader entry of the firmware updater.
+pub fn updater_entry(esp: &Path) -> PathBuf {
+    esp.join("loader/entries").join(UPDATER_ENTRY)
+}
+
+/// Boot the signed firmware updater once on the next boot, e.g. after fwupd staged an update.
+pub fn boot_updater(esp: &Path) -> Result<()> {
+    if !updater_entry(esp).exists() {
+        bail!(
+            "No firmware updater is installed. Install one with `lzbt install --firmware-updater`."
+        );
+    }
+    write_string_variable("LoaderEntryOneShot", &LOADER_GUID, UPDATER_ENTRY)
+        .context("Failed to set the entry for the next boot")?;
+    if read_variable("BootNext", &EFI_GLOBAL_VARIABLE_GUID)?.is_some() {
+        log::warn!(
+            "BootNext is set, e.g. by fwupd, so the firmware starts that boot option before systemd-boot."
+        );
+    }
+    log::info!("The firmware updater is booted once on the next boot.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    fn capsule(image_size: u32) -> Vec<u8> {
+        let mut capsule = vec![0; 64];
+        capsule[16..20].copy_from_slice(&(CAPSULE_HEADER_SIZE as u32).to_le_bytes());
+        capsule[24..28].copy_from_slice(&image_size.to_le_bytes());
+        capsule
+    }
+
+    #[test]
+    fn stage_capsule_on_disk() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("firmware.cap");
+        fs::write(&path, capsule(64))?;
+        let mut esp = InMemoryEspFilesystem::new();
+
+        let os_indications = stage_capsule(&mut esp, Path::new("/boot"), &path, 0x1f, 0x1)?;
+        assert_eq!(os_indications, 0x5);
+        assert_eq!(
+            esp.read(Path::new("/boot/EFI/UpdateCapsule/firmware.cap"))?,
+            capsule(64)
+        );
+
+        // The firmware does not support capsules on disk.
+        assert!(stage_capsule(&mut esp, Path::new("/boot"), &path, 0x1, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_capsules() {
+        assert!(check_capsule(&capsule(64)).is_ok());
+        assert!(check_capsule(&capsule(65)).is_err());
+        assert!(check_capsule(&capsule(16)).is_err());
+        assert!(check_capsule(b"MZ").is_err());
+    }
+}
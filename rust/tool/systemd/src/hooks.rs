@@ -0,0 +1,153 @@
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+/// When a hook runs during `lzbt install`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    /// Before anything is written to the ESP, with the generations that will be installed and the
+    /// entries that are signed again because their command line changed.
+    PreInstall,
+    /// After all files were signed and written, before garbage collection, with the signed files.
+    PostSign,
+    /// After the installation finished, with the installed entries and statistics.
+    PostInstall,
+}
+
+impl HookPoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PreInstall => "pre-install",
+            Self::PostSign => "post-sign",
+            Self::PostInstall => "post-install",
+        }
+    }
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A program that runs at a hook point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub point: HookPoint,
+    pub program: PathBuf,
+}
+
+impl FromStr for Hook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((point, program)) = s.split_once('=').filter(|(_, program)| !program.is_empty())
+        else {
+            bail!("Expected a hook of the form <point>=<program>, got {s:?}");
+        };
+        let point = match point {
+            "pre-install" => HookPoint::PreInstall,
+            "post-sign" => HookPoint::PostSign,
+            "post-install" => HookPoint::PostInstall,
+            _ => bail!(
+                "Unknown hook point {point:?}, expected pre-install, post-sign or post-install"
+            ),
+        };
+        Ok(Self {
+            point,
+            program: PathBuf::from(program),
+        })
+    }
+}
+
+/// Run the hooks of `point` in the given order.
+///
+/// Every hook is called with the name of the hook point as its only argument and `payload` as
+/// JSON on stdin. A hook that fails aborts the installation.
+pub fn run(hooks: &[Hook], point: HookPoint, payload: &Value) -> Result<()> {
+    let mut payload = payload.clone();
+    payload["hook"] = Value::from(point.as_str());
+    let payload = format!("{payload:#}\n");
+
+    for hook in hooks.iter().filter(|hook| hook.point == point) {
+        let program = &hook.program;
+        log::info!("Running {point} hook {program:?}...");
+        let mut child = Command::new(program)
+            .arg(point.as_str())
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {point} hook {program:?}"))?;
+        // A hook may exit without reading its input, which is not an error by itself.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(payload.as_bytes());
+        }
+        let status = child
+            .wait()
+            .with_context(|| format!("Failed to wait for {point} hook {program:?}"))?;
+        if !status.success() {
+            bail!("The {point} hook {program:?} failed with {status}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_hooks() -> Result<()> {
+        assert_eq!(
+            "post-sign=/bin/copy-stubs".parse::<Hook>()?,
+            Hook {
+                point: HookPoint::PostSign,
+                program: PathBuf::from("/bin/copy-stubs"),
+            }
+        );
+        assert!("post-sign=".parse::<Hook>().is_err());
+        assert!("pre-sign=/bin/true".parse::<Hook>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pass_payload_to_hooks_of_the_point() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let output = tempdir.path().join("output");
+        let script = tempdir.path().join("hook");
+        fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$1\" >> {0:?}\ncat >> {0:?}\n", output),
+        )?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        let hooks = [
+            format!("pre-install={}", script.display()).parse::<Hook>()?,
+            "post-install=/nonexistent".parse::<Hook>()?,
+        ];
+
+        run(
+            &hooks,
+            HookPoint::PreInstall,
+            &json!({ "generations": [1] }),
+        )?;
+        let output = fs::read_to_string(&output)?;
+        let (argument, payload) = output.split_once('\n').unwrap();
+        assert_eq!(argument, "pre-install");
+        assert_eq!(
+            serde_json::from_str::<Value>(payload)?,
+            json!({ "hook": "pre-install", "generations": [1] })
+        );
+
+        assert!(run(&hooks, HookPoint::PostInstall, &json!({})).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+//! Inspect PE binaries that are meant to boot, i.e. Lanzaboote stubs and unified kernel images,
+//! to debug why an entry does not boot.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::authenticode::authenticode_sha256;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::revocation::format_hash;
+use lanzaboote_tool::signature::Signer;
+
+use crate::diff::stub_version;
+
+/// A section of a PE binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    /// The size in the file, which is padded to the file alignment.
+    pub raw_size: u32,
+    data: Vec<u8>,
+}
+
+impl Section {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.data)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string()
+    }
+}
+
+/// A file on the ESP that a Lanzaboote stub loads and checks against an embedded hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashedFile {
+    /// The UEFI path relative to the root of the ESP, e.g. `\EFI\nixos\kernel.efi`.
+    pub path: String,
+    pub expected_hash: Option<String>,
+    /// The hash of the file on the ESP, if it exists.
+    pub actual_hash: Option<String>,
+}
+
+impl HashedFile {
+    pub fn read(esp: &Path, path: String, expected_hash: Option<String>) -> Self {
+        let actual_hash = fs::read(esp_path(esp, &path))
+            .ok()
+            .map(|data| format_hash(&Sha256::digest(data)));
+        Self {
+            path,
+            expected_hash,
+            actual_hash,
+        }
+    }
+
+    /// What keeps the stub from booting the file, if anything.
+    pub fn discrepancy(&self, what: &str) -> Option<String> {
+        match (&self.expected_hash, &self.actual_hash) {
+            (_, None) => Some(format!("The {what} {} is not on the ESP.", self.path)),
+            (None, _) => Some(format!(
+                "The stub has no hash of the {what}, so it refuses to boot."
+            )),
+            (Some(expected), Some(actual)) if expected != actual => Some(format!(
+                "The {what} {} on the ESP has the hash {actual}, but the stub expects {expected}.",
+                self.path
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a file is signed and, if a certificate was given, whether it verifies the signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    Signed,
+    Valid,
+    Invalid,
+}
+
+impl SignatureStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unsigned => "unsigned",
+            Self::Signed => "signed (not verified)",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+/// What a PE binary contains and what is wrong with it.
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub file: PathBuf,
+    pub sections: Vec<Section>,
+    pub stub_version: Option<String>,
+    pub authenticode_hash: String,
+    pub signature: SignatureStatus,
+    pub kernel: Option<HashedFile>,
+    pub initrd: Option<HashedFile>,
+    pub discrepancies: Vec<String>,
+}
+
+impl Inspection {
+    /// Inspect a PE binary.
+    ///
+    /// `esp` is the ESP that the paths in a Lanzaboote stub are relative to. The signature is
+    /// only verified if a `verifier` is given.
+    pub fn read(file: &Path, esp: &Path, verifier: Option<&impl Signer>) -> Result<Self> {
+        let image = fs::read(file).with_context(|| format!("Failed to read {file:?}"))?;
+        let pe = goblin::pe::PE::parse(&image)
+            .with_context(|| format!("{file:?} is not a PE binary"))?;
+        let sections = pe
+            .sections
+            .iter()
+            .map(|section| {
+                let start = section.pointer_to_raw_data as usize;
+                // Sections that are larger in memory than in the file are padded with zeroes.
+                let length = section.virtual_size.min(section.size_of_raw_data) as usize;
+                Ok(Section {
+                    name: section.name().unwrap_or("?").to_string(),
+                    virtual_address: section.virtual_address,
+                    virtual_size: section.virtual_size,
+                    raw_size: section.size_of_raw_data,
+                    data: image
+                        .get(start..start + length)
+                        .context("A section ends after the end of the file")?
+                        .to_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let signed = pe
+            .header
+            .optional_header
+            .and_then(|header| *header.data_directories.get_certificate_table())
+            .is_some_and(|table| table.size > 0);
+        let signature = match (signed, verifier) {
+            (false, _) => SignatureStatus::Unsigned,
+            (true, None) => SignatureStatus::Signed,
+            (true, Some(verifier)) if verifier.verify_path(file)? => SignatureStatus::Valid,
+            (true, Some(_)) => SignatureStatus::Invalid,
+        };
+
+        let mut inspection = Self {
+            file: file.to_path_buf(),
+            stub_version: stub_version(&image),
+            authenticode_hash: format_hash(&authenticode_sha256(&image)?),
+            signature,
+            kernel: None,
+            initrd: None,
+            discrepancies: Vec::new(),
+            sections,
+        };
+        // Lanzaboote stubs embed the paths of the kernel and the initrd on the ESP and their
+        // hashes, unified kernel images embed the files themselves.
+        if inspection.section(".linuxh").is_some() {
+            let hashed_file = |path: &str, hash: &str| {
+                inspection.section(path).map(|path| {
+                    HashedFile::read(
+                        esp,
+                        path.text(),
+                        inspection.section(hash).map(|hash| format_hash(&hash.data)),
+                    )
+                })
+            };
+            let (kernel, initrd) = (
+                hashed_file(".linux", ".linuxh"),
+                hashed_file(".initrd", ".initrdh"),
+            );
+            inspection.kernel = kernel;
+            inspection.initrd = initrd;
+        }
+        inspection.discrepancies = inspection.find_discrepancies();
+        Ok(inspection)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    fn kind(&self) -> String {
+        match (&self.stub_version, self.kernel.is_some()) {
+            (Some(version), _) => format!("Lanzaboote stub {version}"),
+            (None, true) => "Lanzaboote stub".to_string(),
+            (None, false) if self.section(".linux").is_some() => "Unified kernel image".to_string(),
+            (None, false) => "PE binary".to_string(),
+        }
+    }
+
+    /// The kernel parameters that the stub enforces, on one line.
+    fn lockdown_policy(&self) -> Option<String> {
+        let policy = self.section(".lockdown")?.text();
+        Some(policy.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    fn os_release(&self) -> Option<String> {
+        let os_release = OsRelease::from_str(&self.section(".osrel")?.text()).ok()?;
+        os_release
+            .0
+            .get("PRETTY_NAME")
+            .or_else(|| os_release.0.get("NAME"))
+            .cloned()
+    }
+
+    fn find_discrepancies(&self) -> Vec<String> {
+        let mut discrepancies = overlapping_sections(&self.sections);
+        if self.section(".linux").is_none() {
+            discrepancies.push("There is no .linux section, so there is no kernel to boot.".into());
+        }
+        if self.section(".linux").is_some() && self.section(".osrel").is_none() {
+            discrepancies.push(
+                "There is no .osrel section, so systemd-boot does not list the entry.".into(),
+            );
+        }
+        discrepancies.extend(
+            [(&self.kernel, "kernel"), (&self.initrd, "initrd")]
+                .into_iter()
+                .filter_map(|(file, what)| file.as_ref()?.discrepancy(what)),
+        );
+        match self.signature {
+            SignatureStatus::Unsigned => discrepancies
+                .push("The file is not signed, so it does not boot with Secure Boot.".into()),
+            SignatureStatus::Invalid => discrepancies
+                .push("The signature does not verify with the given certificate.".into()),
+            SignatureStatus::Signed | SignatureStatus::Valid => {}
+        }
+        discrepancies
+    }
+
+    pub fn to_json(&self) -> Value {
+        let hashed_file = |file: &Option<HashedFile>| {
+            file.as_ref().map(|file| {
+                json!({
+                    "path": file.path,
+                    "expectedHash": file.expected_hash,
+                    "actualHash": file.actual_hash,
+                })
+            })
+        };
+        let text = |name| self.section(name).map(Section::text);
+        json!({
+            "file": self.file,
+            "kind": self.kind(),
+            "stubVersion": self.stub_version,
+            "sections": self
+                .sections
+                .iter()
+                .map(|section| json!({
+                    "name": section.name,
+                    "virtualAddress": section.virtual_address,
+                    "virtualSize": section.virtual_size,
+                    "rawSize": section.raw_size,
+                }))
+                .collect::<Vec<_>>(),
+            "osRelease": self.os_release(),
+            "cmdline": text(".cmdline"),
+            "uname": text(".uname"),
+            "verityRootHash": text(".roothash"),
+            "lockdownPolicy": self.lockdown_policy(),
+            "kernel": hashed_file(&self.kernel),
+            "initrd": hashed_file(&self.initrd),
+            "authenticodeHash": self.authenticode_hash,
+            "signature": self.signature.as_str(),
+            "discrepancies": self.discrepancies,
+        })
+    }
+
+    pub fn print(&self) {
+        println!("File: {}", self.file.display());
+        println!("Kind: {}", self.kind());
+        println!("Sections:");
+        for section in &self.sections {
+            println!(
+                "  {:<10} at {:#010x}, {} bytes ({} in the file)",
+                section.name, section.virtual_address, section.virtual_size, section.raw_size
+            );
+        }
+        let text = |name| self.section(name).map(Section::text);
+        for (label, value) in [
+            ("OS release", self.os_release()),
+            ("Command line", text(".cmdline")),
+            ("Kernel release", text(".uname")),
+            ("dm-verity root hash", text(".roothash")),
+            ("Lockdown policy", self.lockdown_policy()),
+        ] {
+            if let Some(value) = value {
+                println!("{label}: {value}");
+            }
+        }
+        for (label, file) in [("Kernel", &self.kernel), ("Initrd", &self.initrd)] {
+            if let Some(file) = file {
+                println!(
+                    "{label}: {} (SHA-256 {})",
+                    file.path,
+                    file.expected_hash.as_deref().unwrap_or("unknown")
+                );
+            }
+        }
+        println!("Authenticode SHA-256: {}", self.authenticode_hash);
+        println!("Signature: {}", self.signature.as_str());
+
+        if self.discrepancies.is_empty() {
+            println!("No discrepancies found.");
+            return;
+        }
+        println!("Discrepancies:");
+        for discrepancy in &self.discrepancies {
+            println!("  - {discrepancy}");
+        }
+    }
+}
+
+/// Find the ESP that a file is on, i.e. the parent of its `EFI` directory.
+pub fn find_esp(file: &Path) -> Option<&Path> {
+    file.ancestors()
+        .find(|ancestor| {
+            ancestor
+                .file_name()
+                .is_some_and(|name| name.eq_ignore_ascii_case("EFI"))
+        })?
+        .parent()
+}
+
+/// Convert a UEFI path relative to the root of the ESP to a path on the mounted ESP.
+pub fn esp_path(esp: &Path, path: &str) -> PathBuf {
+    esp.join(path.trim_start_matches('\\').replace('\\', "/"))
+}
+
+/// Sections that overlap in memory, which the firmware or the stub cannot load.
+fn overlapping_sections(sections: &[Section]) -> Vec<String> {
+    let mut sorted = sections.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|section| section.virtual_address);
+    sorted
+        .windows(2)
+        .filter(|pair| {
+            u64::from(pair[0].virtual_address) + u64::from(pair[0].virtual_size)
+                > u64::from(pair[1].virtual_address)
+        })
+        .map(|pair| {
+            format!(
+                "The sections {} and {} overlap in memory.",
+                pair[0].name, pair[1].name
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(name: &str, virtual_address: u32, virtual_size: u32) -> Section {
+        Section {
+            name: name.to_string(),
+            virtual_address,
+            virtual_size,
+            raw_size: virtual_size,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn find_overlapping_sections() {
+        let sections = [
+            section(".text", 0x1000, 0x2000),
+            section(".linux", 0x4000, 0x100),
+            section(".osrel", 0x2800, 0x100),
+        ];
+        assert_eq!(
+            overlapping_sections(&sections),
+            ["The sections .text and .osrel overlap in memory."]
+        );
+    }
+
+    #[test]
+    fn compare_hashed_files_with_the_esp() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        fs::create_dir_all(esp.path().join("EFI/nixos"))?;
+        fs::write(esp.path().join("EFI/nixos/kernel.efi"), b"kernel")?;
+        let hash = format_hash(&Sha256::digest(b"kernel"));
+
+        let kernel = HashedFile::read(
+            esp.path(),
+            "\\EFI\\nixos\\kernel.efi".to_string(),
+            Some(hash),
+        );
+        assert_eq!(kernel.discrepancy("kernel"), None);
+
+        let kernel = HashedFile::read(
+            esp.path(),
+            "\\EFI\\nixos\\kernel.efi".to_string(),
+            Some(format_hash(&[0; 32])),
+        );
+        assert!(kernel
+            .discrepancy("kernel")
+            .is_some_and(|d| d.contains("but the stub expects")));
+
+        let initrd = HashedFile::read(esp.path(), "\\EFI\\nixos\\initrd.efi".to_string(), None);
+        assert_eq!(
+            initrd.discrepancy("initrd").as_deref(),
+            Some("The initrd \\EFI\\nixos\\initrd.efi is not on the ESP.")
+        );
+
+        assert_eq!(
+            find_esp(Path::new("/boot/EFI/Linux/nixos-generation-1.efi")),
+            Some(Path::new("/boot"))
+        );
+        assert_eq!(find_esp(Path::new("/tmp/uki.efi")), None);
+        Ok(())
+    }
+}
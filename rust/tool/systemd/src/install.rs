@@ -1,30 +1,50 @@
-use std::collections::BTreeSet;
-use std::ffi::OsStr;
-use std::fs::{self, File};
-use std::os::fd::AsRawFd;
-use std::os::unix::prelude::{OsStrExt, PermissionsExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::string::ToString;
+use std::time::Instant;
 
 use anyhow::{anyhow, Context, Result};
 use base32ct::{Base32Unpadded, Encoding};
-use nix::unistd::syncfs;
+use serde_json::json;
 use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
 use crate::architecture::SystemdArchitectureExt;
+use crate::cmdline::{CmdlineChange, CmdlineFragments};
 use crate::esp::SystemdEspPaths;
+use crate::firmware_update;
+use crate::hooks::{self, Hook, HookPoint};
+use crate::install_cache::{self, FileHash, InstallCache};
+use crate::manifest::{self, Manifest, Modification, MANIFEST_FILE, SIGNATURE_FILE};
+use crate::namespace;
+use crate::other_os::{self, OtherOsMode};
+use crate::progress::{progress_bar, InstallStatistics};
+use crate::slots::{self, Slot};
 use crate::version::SystemdVersion;
 use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::entry_naming::{EntrySort, EntryTitle};
 use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::esp_fs::EspFilesystem;
 use lanzaboote_tool::gc::Roots;
-use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::generation::{Generation, GenerationLink, GenerationRange};
+use lanzaboote_tool::initrd_encryption::{encrypt_initrd, InitrdKey};
+use lanzaboote_tool::initrd_pipeline::{InitrdPipeline, InitrdStep};
+use lanzaboote_tool::kernel::kernel_release;
+use lanzaboote_tool::mmap::MappedFile;
 use lanzaboote_tool::os_release::OsRelease;
-use lanzaboote_tool::pe::{self, append_initrd_secrets, lanzaboote_image};
-use lanzaboote_tool::signature::Signer;
+use lanzaboote_tool::pe::{
+    self, ClockCheck, InsecureBootPolicy, LockdownMode, LuksUnlock, PartitionSelector, StubLocale,
+    StubVerbosity,
+};
+use lanzaboote_tool::revocation::{format_hash, RevocationList};
+use lanzaboote_tool::signature::{local::LocalKeyPair, Signer};
 use lanzaboote_tool::utils::{file_hash, SecureTempDirExt};
+use lanzaboote_tool::zboot::check_kernel;
 
-pub struct Installer<S: Signer> {
+pub struct Installer<S: Signer, F: EspFilesystem> {
     broken_gens: BTreeSet<u64>,
     gc_roots: Roots,
     lanzaboote_stub: PathBuf,
@@ -33,12 +53,62 @@ pub struct Installer<S: Signer> {
     signer: S,
     configuration_limit: usize,
     esp_paths: SystemdEspPaths,
+    esp_fs: F,
     generation_links: Vec<PathBuf>,
     arch: Architecture,
+    simulate_secure_boot: bool,
+    insecure_boot_policy: InsecureBootPolicy,
+    stub_verbosity: StubVerbosity,
+    stub_locale: StubLocale,
+    entry_title: EntryTitle,
+    entry_sort: EntrySort,
+    clear_screen: bool,
+    serial_console: bool,
+    stub_log_file: bool,
+    splash: Option<PathBuf>,
+    recovery_entries: bool,
+    revocation_list: RevocationList,
+    watchdog_timeout: Option<u64>,
+    authcert: Option<Vec<u8>>,
+    authcert_threshold: Option<usize>,
+    allow_smbios_cmdline: bool,
+    allow_fw_cfg: bool,
+    instance_metadata: bool,
+    refuse_mismatched_resume: bool,
+    clock_check: ClockCheck,
+    lockdown: LockdownMode,
+    module_sig_enforce: bool,
+    initrd_steps: Vec<InitrdStep>,
+    initrd_key: Option<InitrdKey>,
+    known_good_generation: Option<u64>,
+    selected_generations: Vec<GenerationRange>,
+    other_os: OtherOsMode,
+    firmware_updater: Option<PathBuf>,
+    hooks: Vec<Hook>,
+    cmdline_fragments: CmdlineFragments,
+    ab_slots: Option<Slot>,
+    first_stage: bool,
+    payload_partition: Option<(PathBuf, PartitionSelector)>,
+    fs_drivers: Vec<PathBuf>,
+    fs_driver_paths: Vec<String>,
+    payload_luks: Option<LuksUnlock>,
+    slot_to_activate: Option<Slot>,
+    namespace: Option<String>,
+    cache: Option<InstallCache>,
+    manifest_key: Option<LocalKeyPair>,
+    /// The files that the manifest of the last installation vouches for, if there is a manifest.
+    verified_files: Option<BTreeSet<PathBuf>>,
+    /// Files that are kept on the ESP without being verified or installed again.
+    unverified_files: BTreeSet<PathBuf>,
+    entries: BTreeMap<u64, String>,
+    signed_files: Vec<PathBuf>,
+    cmdline_changes: Vec<CmdlineChange>,
+    removed_files: Vec<PathBuf>,
+    statistics: InstallStatistics,
 }
 
 #[allow(clippy::too_many_arguments)]
-impl<S: Signer> Installer<S> {
+impl<S: Signer, F: EspFilesystem> Installer<S, F> {
     pub fn new(
         lanzaboote_stub: PathBuf,
         arch: Architecture,
@@ -47,6 +117,7 @@ impl<S: Signer> Installer<S> {
         signer: S,
         configuration_limit: usize,
         esp: PathBuf,
+        esp_fs: F,
         generation_links: Vec<PathBuf>,
     ) -> Self {
         let mut gc_roots = Roots::new();
@@ -62,56 +133,462 @@ impl<S: Signer> Installer<S> {
             signer,
             configuration_limit,
             esp_paths,
+            esp_fs,
             generation_links,
             arch,
+            simulate_secure_boot: false,
+            insecure_boot_policy: InsecureBootPolicy::default(),
+            stub_verbosity: StubVerbosity::default(),
+            stub_locale: StubLocale::default(),
+            entry_title: EntryTitle::default(),
+            entry_sort: EntrySort::default(),
+            clear_screen: false,
+            serial_console: false,
+            stub_log_file: false,
+            splash: None,
+            recovery_entries: false,
+            revocation_list: RevocationList::default(),
+            watchdog_timeout: None,
+            authcert: None,
+            authcert_threshold: None,
+            allow_smbios_cmdline: false,
+            allow_fw_cfg: false,
+            instance_metadata: false,
+            refuse_mismatched_resume: false,
+            clock_check: ClockCheck::default(),
+            lockdown: LockdownMode::default(),
+            module_sig_enforce: false,
+            initrd_steps: Vec::new(),
+            initrd_key: None,
+            known_good_generation: None,
+            selected_generations: Vec::new(),
+            other_os: OtherOsMode::default(),
+            firmware_updater: None,
+            hooks: Vec::new(),
+            cmdline_fragments: CmdlineFragments::default(),
+            ab_slots: None,
+            first_stage: false,
+            payload_partition: None,
+            fs_drivers: Vec::new(),
+            fs_driver_paths: Vec::new(),
+            payload_luks: None,
+            slot_to_activate: None,
+            namespace: None,
+            cache: None,
+            manifest_key: None,
+            verified_files: None,
+            unverified_files: BTreeSet::new(),
+            entries: BTreeMap::new(),
+            signed_files: Vec::new(),
+            cmdline_changes: Vec::new(),
+            removed_files: Vec::new(),
+            statistics: InstallStatistics::default(),
         }
     }
 
-    pub fn install(&mut self) -> Result<()> {
-        log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
+    /// Make the stub enforce the Secure Boot policy even if Secure Boot is disabled.
+    ///
+    /// This allows validating a setup, e.g. in a VM, before enrolling keys.
+    pub fn with_simulate_secure_boot(mut self, simulate_secure_boot: bool) -> Self {
+        self.simulate_secure_boot = simulate_secure_boot;
+        self
+    }
 
-        let mut links = self
-            .generation_links
-            .iter()
-            .map(GenerationLink::from_path)
-            .collect::<Result<Vec<GenerationLink>>>()?;
+    /// Set what the stub does if Secure Boot is disabled.
+    ///
+    /// `confirm` and `refuse` make it visible if someone disabled Secure Boot in the firmware
+    /// settings, e.g. to boot their own code on an unattended machine.
+    pub fn with_insecure_boot_policy(mut self, insecure_boot_policy: InsecureBootPolicy) -> Self {
+        self.insecure_boot_policy = insecure_boot_policy;
+        self
+    }
 
-        // Sort the links by version, so that the limit actually skips the oldest generations.
-        links.sort_by_key(|l| l.version);
+    /// Set how much the stub logs to the console.
+    pub fn with_stub_verbosity(mut self, stub_verbosity: StubVerbosity) -> Self {
+        self.stub_verbosity = stub_verbosity;
+        self
+    }
 
-        // A configuration limit of 0 means there is no limit.
-        if self.configuration_limit > 0 {
-            // Only install the number of generations configured. Reverse the list to only take the
-            // latest generations and then, after taking them, reverse the list again so that the
-            // generations are installed from oldest to newest, i.e. from smallest to largest
-            // generation version.
-            links = links
-                .into_iter()
-                .rev()
-                .take(self.configuration_limit)
-                .rev()
-                .collect()
-        };
+    /// Set the language of the messages of the stub.
+    pub fn with_stub_locale(mut self, stub_locale: StubLocale) -> Self {
+        self.stub_locale = stub_locale;
+        self
+    }
+
+    /// Set how the entries are titled in systemd-boot.
+    pub fn with_entry_title(mut self, entry_title: EntryTitle) -> Self {
+        self.entry_title = entry_title;
+        self
+    }
+
+    /// Set how systemd-boot orders the entries.
+    pub fn with_entry_sort(mut self, entry_sort: EntrySort) -> Self {
+        self.entry_sort = entry_sort;
+        self
+    }
+
+    /// Make the stub clear the screen instead of keeping the firmware splash.
+    pub fn with_clear_screen(mut self, clear_screen: bool) -> Self {
+        self.clear_screen = clear_screen;
+        self
+    }
+
+    /// Make the stub mirror its output to serial ports, even if the firmware console already
+    /// includes a serial terminal.
+    pub fn with_serial_console(mut self, serial_console: bool) -> Self {
+        self.serial_console = serial_console;
+        self
+    }
+
+    /// Make the stub append its log to `loader/lanzaboote-debug.log` on the ESP.
+    pub fn with_stub_log_file(mut self, stub_log_file: bool) -> Self {
+        self.stub_log_file = stub_log_file;
+        self
+    }
+
+    /// Embed a BMP image into the stub that is displayed while the kernel is loaded.
+    pub fn with_splash(mut self, splash: Option<PathBuf>) -> Self {
+        self.splash = splash;
+        self
+    }
+
+    /// Additionally install a recovery entry for every generation that boots into the rescue
+    /// target.
+    ///
+    /// Because the command line cannot be edited at boot when Secure Boot is active, this is the
+    /// only way to keep a recovery option available.
+    pub fn with_recovery_entries(mut self, recovery_entries: bool) -> Self {
+        self.recovery_entries = recovery_entries;
+        self
+    }
+
+    /// Embed a list of revoked kernel and initrd hashes into every stub.
+    ///
+    /// Generations that use a revoked kernel or initrd are not installed at all.
+    pub fn with_revocation_list(mut self, revocation_list: RevocationList) -> Self {
+        self.revocation_list = revocation_list;
+        self
+    }
+
+    /// Make the stub arm the firmware watchdog with this timeout in seconds before it starts the
+    /// kernel.
+    ///
+    /// Combined with staged generations, a kernel that hangs early resets the machine, which then
+    /// boots the known good generation.
+    pub fn with_watchdog_timeout(mut self, watchdog_timeout: Option<u64>) -> Self {
+        self.watchdog_timeout = watchdog_timeout;
+        self
+    }
+
+    /// Pin the DER encoded certificate that addons and credentials must be signed with.
+    ///
+    /// This allows signing them with a different key than the boot entries, independent of the
+    /// Secure Boot database.
+    pub fn with_authcert(mut self, authcert: Option<Vec<u8>>) -> Self {
+        self.authcert = authcert;
+        self
+    }
+
+    /// Require addons and credentials to be signed with this many of the pinned certificates.
+    ///
+    /// This lets several parties, e.g. teams with their own keys, jointly approve them.
+    pub fn with_authcert_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.authcert_threshold = threshold;
+        self
+    }
+
+    /// Let the stub extend the command line from SMBIOS OEM strings even if Secure Boot is active.
+    ///
+    /// This allows passing per-instance parameters to VMs that share a signed image. Whoever
+    /// controls the SMBIOS tables, e.g. the hypervisor, can then add arbitrary parameters.
+    pub fn with_allow_smbios_cmdline(mut self, allow_smbios_cmdline: bool) -> Self {
+        self.allow_smbios_cmdline = allow_smbios_cmdline;
+        self
+    }
+
+    /// Let the stub take the command line and an additional initrd from QEMU's fw_cfg even if
+    /// Secure Boot is active.
+    ///
+    /// This speeds up testing in VMs, but lets the host boot arbitrary configurations, so it is
+    /// only meant for development and CI.
+    pub fn with_allow_fw_cfg(mut self, allow_fw_cfg: bool) -> Self {
+        self.allow_fw_cfg = allow_fw_cfg;
+        self
+    }
+
+    /// Let the stub pass the metadata of the cloud instance to the initrd as credentials, so that
+    /// one signed image can be booted as many differently configured instances.
+    ///
+    /// The metadata is not authenticated, so the initrd must only use it for what the owner of
+    /// the instance may configure anyway.
+    pub fn with_instance_metadata(mut self, instance_metadata: bool) -> Self {
+        self.instance_metadata = instance_metadata;
+        self
+    }
+
+    /// Make the stub refuse to boot a generation whose kernel differs from the one that the
+    /// system hibernated with, instead of only warning.
+    ///
+    /// Resuming a hibernated system with a different kernel can corrupt its file systems.
+    pub fn with_refuse_mismatched_resume(mut self, refuse_mismatched_resume: bool) -> Self {
+        self.refuse_mismatched_resume = refuse_mismatched_resume;
+        self
+    }
+
+    /// Set what the stub does if the firmware clock is before the build date of the generation.
+    pub fn with_clock_check(mut self, clock_check: ClockCheck) -> Self {
+        self.clock_check = clock_check;
+        self
+    }
+
+    /// Make the stubs enforce a kernel lockdown mode and, with `module_sig_enforce`, that only
+    /// signed kernel modules are loaded.
+    pub fn with_lockdown(mut self, lockdown: LockdownMode, module_sig_enforce: bool) -> Self {
+        self.lockdown = lockdown;
+        self.module_sig_enforce = module_sig_enforce;
+        self
+    }
+
+    /// Transform the initrd of every generation with these steps before it is installed.
+    pub fn with_initrd_steps(mut self, initrd_steps: Vec<InitrdStep>) -> Self {
+        self.initrd_steps = initrd_steps;
+        self
+    }
+
+    /// Encrypt the initrds on the ESP with a key that the stub takes from the firmware.
+    ///
+    /// This keeps initrd secrets confidential if the ESP is read without the machine.
+    pub fn with_initrd_key(mut self, initrd_key: Option<InitrdKey>) -> Self {
+        self.initrd_key = initrd_key;
+        self
+    }
+
+    /// Keep a generation that is known to boot installed, even if it falls out of the
+    /// configuration limit.
+    ///
+    /// This is required to stage new generations, which are only booted once until they are
+    /// confirmed.
+    pub fn with_known_good_generation(mut self, known_good_generation: Option<u64>) -> Self {
+        self.known_good_generation = known_good_generation;
+        self
+    }
+
+    /// Only install the generations in these ranges. Without any, all generations are candidates.
+    ///
+    /// The configuration limit applies to the selected generations.
+    pub fn with_selected_generations(mut self, selected_generations: Vec<GenerationRange>) -> Self {
+        self.selected_generations = selected_generations;
+        self
+    }
+
+    /// Provide boot entries for other operating systems on the ESP, e.g. Windows.
+    pub fn with_other_os(mut self, other_os: OtherOsMode) -> Self {
+        self.other_os = other_os;
+        self
+    }
+
+    /// Provide a boot entry for a UEFI firmware updater, e.g. fwupd's `fwupdx64.efi`.
+    ///
+    /// The updater is signed, so that firmware updates keep working with Secure Boot enabled.
+    pub fn with_firmware_updater(mut self, firmware_updater: Option<PathBuf>) -> Self {
+        self.firmware_updater = firmware_updater;
+        self
+    }
+
+    /// Run these programs at their hook points, see [`hooks::run`].
+    pub fn with_hooks(mut self, hooks: Vec<Hook>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Add these parameters to the command lines of the bootspecs, see [`CmdlineFragments`].
+    pub fn with_cmdline_fragments(mut self, cmdline_fragments: CmdlineFragments) -> Self {
+        self.cmdline_fragments = cmdline_fragments;
+        self
+    }
+
+    /// Also install the newest generation into an A/B slot, see [`crate::slots`].
+    ///
+    /// `active` is the currently active slot. The newest generation goes into the other slot,
+    /// unless the active slot is still empty.
+    pub fn with_ab_slots(mut self, active: Option<Slot>) -> Self {
+        self.ab_slots = active;
+        self
+    }
+
+    /// Install a first stage instead of systemd-boot as the EFI fallback, which verifies
+    /// systemd-boot by its hash before chainloading it.
+    ///
+    /// This protects systemd-boot on firmwares that only trust a single signed binary.
+    pub fn with_first_stage(mut self, first_stage: bool) -> Self {
+        self.first_stage = first_stage;
+        self
+    }
+
+    /// Install the kernels and initrds to the partition that is mounted at the path instead of the
+    /// ESP, and make the stubs load them from there.
+    ///
+    /// The stubs stay on the ESP, so that the firmware and systemd-boot find them.
+    pub fn with_payload_partition(
+        mut self,
+        payload_partition: Option<(PathBuf, PartitionSelector)>,
+    ) -> Self {
+        self.payload_partition = payload_partition;
+        self
+    }
+
+    /// Sign these UEFI file system drivers onto the ESP, and make the stubs load them if the
+    /// firmware cannot read the payload partition, e.g. because it is formatted with ext4.
+    pub fn with_fs_drivers(mut self, fs_drivers: Vec<PathBuf>) -> Self {
+        self.fs_drivers = fs_drivers;
+        self
+    }
+
+    /// Make the stubs unlock the payload partition, which is a LUKS2 volume, see
+    /// [`Self::with_payload_partition`].
+    ///
+    /// The kernels and initrds are then only stored encrypted.
+    pub fn with_payload_luks(mut self, payload_luks: Option<LuksUnlock>) -> Self {
+        self.payload_luks = payload_luks;
+        self
+    }
+
+    /// The root that the kernel and initrd paths in the stubs are relative to.
+    fn payload_root(&self) -> &Path {
+        match &self.payload_partition {
+            Some((mount_point, _)) => mount_point,
+            None => &self.esp_paths.esp,
+        }
+    }
+
+    /// The directory of the kernels and initrds, i.e. `EFI/nixos` on the payload partition.
+    fn payload_directory(&self) -> PathBuf {
+        match &self.payload_partition {
+            Some((mount_point, _)) => mount_point.join(
+                self.esp_paths
+                    .nixos
+                    .strip_prefix(&self.esp_paths.esp)
+                    .unwrap_or(&self.esp_paths.nixos),
+            ),
+            None => self.esp_paths.nixos.clone(),
+        }
+    }
+
+    /// Namespace the installed files by a machine ID, see [`crate::namespace`].
+    ///
+    /// Files that were installed without a namespace are left alone.
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        if let Some(namespace) = &namespace {
+            self.esp_paths.nixos = self.esp_paths.nixos.join(namespace);
+            self.gc_roots.extend([&self.esp_paths.nixos]);
+        }
+        self.namespace = namespace;
+        self
+    }
+
+    /// Reuse file hashes and signed stubs of earlier installations, see [`crate::install_cache`].
+    pub fn with_cache(mut self, cache: Option<InstallCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Sign a manifest of the managed files with this key and check it before installing, see
+    /// [`crate::manifest`].
+    pub fn with_manifest(mut self, key: Option<LocalKeyPair>) -> Self {
+        self.manifest_key = key;
+        self
+    }
+
+    /// The installed boot entries by generation, i.e. the file names of their stubs.
+    ///
+    /// Only the default entries of generations are included, not specialisations or recovery
+    /// entries.
+    pub fn entries(&self) -> &BTreeMap<u64, String> {
+        &self.entries
+    }
+
+    /// The slot that received the newest generation and has to become the active slot.
+    pub fn slot_to_activate(&self) -> Option<Slot> {
+        self.slot_to_activate
+    }
+
+    /// What the installation has done so far.
+    pub fn statistics(&self) -> InstallStatistics {
+        self.statistics
+    }
+
+    /// The files that were signed and written to the ESP, with the SHA256 hash of the signed file.
+    pub fn signed_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        let mut files = self.signed_files.clone();
+        files.sort();
+        files.dedup();
+        files
+            .into_iter()
+            .map(|file| {
+                let hash = Sha256::digest(self.esp_fs.read(&file)?);
+                Ok((file, format_hash(&hash)))
+            })
+            .collect()
+    }
+
+    /// The files and directories that garbage collection removed from the ESP.
+    pub fn removed_files(&self) -> &[PathBuf] {
+        &self.removed_files
+    }
+
+    /// The entries that are assembled and signed again because their command line changed.
+    pub fn cmdline_changes(&self) -> &[CmdlineChange] {
+        &self.cmdline_changes
+    }
+
+    pub fn install(&mut self) -> Result<()> {
+        log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
+        let started = Instant::now();
+        self.check_manifest()?;
+
+        let links = self.links_to_install()?;
+        self.cmdline_changes = self.plan_cmdline_changes(&links)?;
+        for change in &self.cmdline_changes {
+            log::info!("{}", change.describe());
+        }
+        hooks::run(
+            &self.hooks,
+            HookPoint::PreInstall,
+            &json!({
+                "esp": self.esp_paths.esp.to_string_lossy(),
+                "generations": links.iter().map(|l| l.version).collect::<Vec<_>>(),
+                "cmdlineChanges": self
+                    .cmdline_changes
+                    .iter()
+                    .map(CmdlineChange::to_json)
+                    .collect::<Vec<_>>(),
+            }),
+        )?;
+        self.install_fs_drivers()?;
         self.install_generations_from_links(&links)?;
+        self.install_slot_payload()?;
+        self.install_slot_selector()?;
 
         self.install_systemd_boot()?;
+        self.install_other_os_entries()?;
+        self.install_firmware_updater()?;
+
+        hooks::run(
+            &self.hooks,
+            HookPoint::PostSign,
+            &json!({
+                "esp": self.esp_paths.esp.to_string_lossy(),
+                "signed": self
+                    .signed_files
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+            }),
+        )?;
 
         if self.broken_gens.is_empty() {
             log::info!("Collecting garbage...");
-            // Only collect garbage in these two directories. This way, no files that do not belong to
-            // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
-            // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
-            // that need files in this directory will NOT work.
-            self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
-            // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-            // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-            // deleted).
-            self.gc_roots
-                .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
-                    p.file_name()
-                        .and_then(|n| n.to_str())
-                        .map_or(false, |n| n.starts_with("nixos-"))
-                })?;
+            self.collect_garbage()?;
         } else {
             // This might produce a ridiculous message if you have a lot of malformed generations.
             let warning = indoc::formatdoc! {"
@@ -123,11 +600,292 @@ impl<S: Signer> Installer<S> {
             ", self.broken_gens.iter().map(ToString::to_string).collect::<Vec<String>>().join(" ")};
             log::warn!("{warning}");
         };
+        if let Some(cache) = &mut self.cache {
+            cache.save();
+        }
+        self.write_manifest()?;
 
         log::info!("Successfully installed Lanzaboote.");
+        for line in self.statistics.summary(started.elapsed()) {
+            log::info!("{line}");
+        }
+
+        hooks::run(
+            &self.hooks,
+            HookPoint::PostInstall,
+            &json!({
+                "esp": self.esp_paths.esp.to_string_lossy(),
+                "entries": self.entries,
+                "statistics": self.statistics.to_json(),
+            }),
+        )
+    }
+
+    /// Remove the managed files that were modified since the last installation, so that they are
+    /// installed again instead of being signed into the new manifest.
+    ///
+    /// If the manifest is missing or invalid, no file is verified, so existing generations are
+    /// installed again as well.
+    fn check_manifest(&mut self) -> Result<()> {
+        let Some(key) = &self.manifest_key else {
+            return Ok(());
+        };
+        let manifest_files =
+            [MANIFEST_FILE, SIGNATURE_FILE].map(|file| self.esp_paths.nixos.join(file));
+        self.gc_roots.extend(&manifest_files);
+        let (manifest, modifications) = manifest::check(
+            &self.esp_fs,
+            &self.esp_paths.esp,
+            &self.esp_paths.nixos,
+            key,
+        )?;
+        let mut verified_files = manifest
+            .map(|manifest| {
+                manifest
+                    .files
+                    .keys()
+                    .map(|relative| self.esp_paths.esp.join(relative))
+                    .collect::<BTreeSet<_>>()
+            })
+            .unwrap_or_default();
+        for modification in modifications {
+            log::warn!("{}", modification.describe());
+            match modification {
+                Modification::Modified(path) => {
+                    log::warn!("Removing {path:?} to install it again...");
+                    self.esp_fs.delete(&path)?;
+                    verified_files.remove(&path);
+                }
+                Modification::Missing(path) => {
+                    verified_files.remove(&path);
+                }
+                Modification::Unverified { .. } => {}
+            }
+        }
+        self.verified_files = Some(verified_files);
+        Ok(())
+    }
+
+    /// Whether the manifest of the last installation vouches for `path`, or there is none to
+    /// check.
+    fn is_verified(&self, path: &Path) -> bool {
+        match &self.verified_files {
+            Some(files) => files.contains(path),
+            None => true,
+        }
+    }
+
+    /// Keep existing files on the ESP without installing them again.
+    ///
+    /// Files that the manifest does not vouch for are not signed into the new one, unless they
+    /// were installed before.
+    fn keep_files<'a>(&mut self, files: impl IntoIterator<Item = &'a PathBuf>) {
+        for file in files {
+            if !self.is_verified(file) && !self.gc_roots.iter().any(|root| root == file) {
+                self.unverified_files.insert(file.clone());
+            }
+            self.gc_roots.extend([file]);
+        }
+    }
+
+    /// Sign and write the manifest of the managed files, i.e. the garbage collection roots.
+    ///
+    /// The capsules of fwupd are not managed by Lanzaboote, so they are left out, and so are the
+    /// files that were kept without being verified.
+    fn write_manifest(&mut self) -> Result<()> {
+        let Some(key) = &self.manifest_key else {
+            return Ok(());
+        };
+        let capsules = self
+            .esp_paths
+            .nixos
+            .join(firmware_update::FWUPD_CAPSULE_DIRECTORY);
+        let files = self
+            .gc_roots
+            .iter()
+            .filter(|path| !path.starts_with(&capsules))
+            .filter(|path| {
+                let unverified = self.unverified_files.contains(*path);
+                if unverified {
+                    log::warn!(
+                        "Not signing {path:?} into the manifest because it was not verified."
+                    );
+                }
+                !unverified
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let manifest = Manifest::hash(&self.esp_fs, &self.esp_paths.esp, &files)?;
+        manifest.write(&mut self.esp_fs, &self.esp_paths.nixos, key)?;
+        log::info!(
+            "Signed the manifest of {} files on the ESP.",
+            manifest.files.len()
+        );
+        Ok(())
+    }
+
+    /// Select the generation links that should be installed, oldest first.
+    ///
+    /// Only the newest `configuration_limit` generations are selected, plus the known good
+    /// generation.
+    fn links_to_install(&self) -> Result<Vec<GenerationLink>> {
+        let mut links = self
+            .generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<GenerationLink>>>()?;
+
+        // Sort the links by version, so that the limit actually skips the oldest generations.
+        links.sort_by_key(|l| l.version);
+
+        if !self.selected_generations.is_empty() {
+            links.retain(|l| {
+                self.selected_generations
+                    .iter()
+                    .any(|r| r.contains(l.version))
+                    || Some(l.version) == self.known_good_generation
+            });
+        }
+
+        // A configuration limit of 0 means there is no limit.
+        if self.configuration_limit > 0 {
+            // Only install the number of generations configured, i.e. skip the oldest
+            // generations. The known good generation is kept regardless, so that there is
+            // something to fall back to if a staged generation fails to boot. The generations
+            // stay sorted from oldest to newest, i.e. from smallest to largest generation version.
+            let skipped = links.len().saturating_sub(self.configuration_limit);
+            links = links
+                .into_iter()
+                .enumerate()
+                .filter(|(i, l)| *i >= skipped || Some(l.version) == self.known_good_generation)
+                .map(|(_, l)| l)
+                .collect()
+        };
+
+        Ok(links)
+    }
+
+    /// Delete all files on the ESP that are not garbage collection roots.
+    fn collect_garbage(&mut self) -> Result<()> {
+        // Only collect garbage in these two directories. This way, no files that do not belong to
+        // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
+        // directory and deletes ALL files that it doesn't know about, except for the namespaces
+        // of other installations. Dual- or multiboot setups that need files in this directory
+        // will NOT work.
+        let nixos = self.esp_paths.nixos.clone();
+        let namespaced = self.namespace.is_some();
+        let removed = self
+            .gc_roots
+            .collect_garbage_with_filter(&mut self.esp_fs, &nixos, |p| {
+                namespaced || !namespace::is_namespaced(&nixos, p)
+            })?;
+        self.removed_files.extend(removed);
+        // The kernels and initrds on the payload partition are collected like those in
+        // esp/EFI/nixos.
+        if self.payload_partition.is_some() {
+            let payload_directory = self.payload_directory();
+            self.gc_roots.extend([&payload_directory]);
+            let removed = self.gc_roots.collect_garbage_with_filter(
+                &mut self.esp_fs,
+                &payload_directory,
+                |p| namespaced || !namespace::is_namespaced(&payload_directory, p),
+            )?;
+            self.removed_files.extend(removed);
+        }
+        // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
+        // Thus, only files that start with "nixos-" in the namespace of this installation are
+        // garbage collected (i.e. potentially deleted).
+        let namespace = self.namespace.clone();
+        let removed = self.gc_roots.collect_garbage_with_filter(
+            &mut self.esp_fs,
+            &self.esp_paths.linux,
+            |p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(namespace::split_namespace)
+                    .is_some_and(|(n, name)| {
+                        n == namespace.as_deref() && name.starts_with("nixos-")
+                    })
+            },
+        )?;
+        self.removed_files.extend(removed);
         Ok(())
     }
 
+    /// The entries of `links` whose installed stub has another command line than they would get
+    /// now, e.g. because the fragments changed.
+    ///
+    /// Generations that are not installed yet or cannot be read are left out.
+    fn plan_cmdline_changes(&self, links: &[GenerationLink]) -> Result<Vec<CmdlineChange>> {
+        let mut changes = Vec::new();
+        for link in links {
+            let Ok(generation) = Generation::from_link(link) else {
+                continue;
+            };
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec))
+                .collect::<Vec<_>>();
+            for generation in [generation.clone()].into_iter().chain(specialisations) {
+                let target = self.stub_target(&generation, StubVariant::Default)?;
+                if self.esp_fs.exists(&target) {
+                    continue;
+                }
+                let Some(installed) = self.installed_cmdline(&target)? else {
+                    continue;
+                };
+                changes.extend(CmdlineChange::detect(
+                    generation.version,
+                    generation
+                        .specialisation_name
+                        .as_ref()
+                        .map(|name| name.0.as_str()),
+                    &installed,
+                    self.kernel_cmdline(&generation),
+                ));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// The command line of a stub of the same entry as `target` that was installed with other
+    /// inputs, if there is one.
+    ///
+    /// Stub names end with the hash of their inputs, so such stubs only differ in the hash.
+    fn installed_cmdline(&self, target: &Path) -> Result<Option<String>> {
+        let Some((prefix, _)) = target
+            .file_name()
+            .and_then(OsStr::to_str)
+            .and_then(|name| name.rsplit_once('-'))
+        else {
+            return Ok(None);
+        };
+        if !self.esp_fs.is_dir(&self.esp_paths.linux) {
+            return Ok(None);
+        }
+        let mut stubs = self.esp_fs.list(&self.esp_paths.linux)?;
+        stubs.sort();
+        for stub in stubs {
+            let is_same_entry = stub
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| name.strip_prefix(prefix)?.strip_prefix('-'))
+                .and_then(|hash| hash.strip_suffix(".efi"))
+                .is_some_and(|hash| !hash.contains('-'));
+            if !is_same_entry {
+                continue;
+            }
+            let contents = self.esp_fs.read(&stub)?;
+            if let Some(cmdline) = pe::read_section_data(&contents, ".cmdline") {
+                return Ok(Some(String::from_utf8_lossy(cmdline).into_owned()));
+            }
+        }
+        Ok(None)
+    }
+
     /// Install all generations from the provided `GenerationLinks`.
     fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<()> {
         let generations = links
@@ -156,23 +914,49 @@ impl<S: Signer> Installer<S> {
             return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
         }
 
-        for generation in generations {
+        let mut installed_any = false;
+        let total = generations.len();
+        for (i, generation) in generations.into_iter().enumerate() {
+            let before = self.statistics;
+            let started = Instant::now();
+
             // The kernels and initrds are content-addressed.
             // Thus, this cannot overwrite files of old generation with different content.
-            self.install_generation(&generation)
+            installed_any |= self
+                .install_generation(&generation)
                 .with_context(|| format!("Failed to install generation {}", generation.version))?;
             for (name, bootspec) in &generation.spec.bootspec.specialisations {
                 let specialised_generation = generation.specialise(name, bootspec);
-                self.install_generation(&specialised_generation)
+                installed_any |= self
+                    .install_generation(&specialised_generation)
                     .context("Failed to install specialisation.")?;
             }
+
+            log::info!(
+                "{} {}/{total} Generation {}: {}",
+                progress_bar(i + 1, total),
+                i + 1,
+                generation.version,
+                self.statistics.since(&before).describe(started.elapsed())
+            );
+        }
+
+        if !installed_any {
+            // Garbage collection would remove all boot entries.
+            return Err(anyhow!("All generations use a revoked kernel or initrd! Aborting to avoid unbootable system."));
         }
 
         // Sync files to persistent storage. This may improve the
         // chance of a consistent boot directory in case the system
         // crashes.
-        let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
-        syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+        self.esp_fs
+            .sync(&self.esp_paths.esp)
+            .context("Failed to sync ESP filesystem.")?;
+        if let Some((mount_point, _)) = &self.payload_partition {
+            self.esp_fs
+                .sync(mount_point)
+                .context("Failed to sync the payload partition.")?;
+        }
 
         Ok(())
     }
@@ -182,10 +966,17 @@ impl<S: Signer> Installer<S> {
     /// The kernel and initrd are content-addressed, and the stub name identifies the generation.
     /// Hence, this function cannot overwrite files of other generations with different contents.
     /// All installed files are added as garbage collector roots.
-    fn install_generation(&mut self, generation: &Generation) -> Result<()> {
+    ///
+    /// Returns false if the generation was skipped because its kernel or initrd is revoked.
+    fn install_generation(&mut self, generation: &Generation) -> Result<bool> {
         // If the generation is already properly installed, don't overwrite it.
         if self.register_installed_generation(generation).is_ok() {
-            return Ok(());
+            self.statistics.entries_unchanged += 1;
+            return Ok(true);
+        }
+        if self.install_cached_generation(generation)? {
+            self.statistics.entries_installed += 1;
+            return Ok(true);
         }
 
         let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
@@ -204,48 +995,108 @@ impl<S: Signer> Installer<S> {
             .next()
             .context("Failed to extract the kernel version.")?;
 
+        if self.is_revoked(&bootspec.kernel)? {
+            log::warn!(
+                "Skipping generation {} because its kernel is revoked.",
+                generation
+            );
+            self.statistics.entries_skipped += 1;
+            return Ok(false);
+        }
+        check_kernel(&bootspec.kernel)?;
+        let kernel_release = kernel_release(&bootspec.kernel)?;
+
         // Install the kernel and record its path on the ESP.
         let kernel_target = self
             .install_nixos_ca(&bootspec.kernel, &format!("kernel-{}", kernel_version))
             .context("Failed to install the kernel.")?;
 
         // Assemble and install the initrd, and record its path on the ESP.
-        // It is not needed to write the initrd in a temporary directory
-        // if we do not have any initrd secret.
-        let initrd_location = if bootspec.initrd_secrets.is_some() {
-            tempdir
-                .write_secure_file(
-                    fs::read(
-                        bootspec
-                            .initrd
-                            .as_ref()
-                            .context("Lanzaboote does not support missing initrd yet.")?,
-                    )
-                    .context("Failed to read the initrd.")?,
-                )
-                .context("Failed to copy the initrd to the temporary directory.")?
-        } else {
-            bootspec
-                .initrd
-                .clone()
-                .expect("Lanzaboote does not support missing initrd yet.")
-        };
-
-        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
-            append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
+        // The secrets are appended last, so that configured steps, e.g. recompressing the initrd,
+        // do not have to deal with them.
+        let mut initrd_pipeline = InitrdPipeline::new(self.initrd_steps.clone());
+        let initrd_secrets = generation
+            .spec
+            .lanzaboote_extension
+            .initrd_secrets
+            .as_ref()
+            .filter(|secrets| !secrets.is_empty());
+        if let Some(initrd_secrets) = initrd_secrets {
+            initrd_pipeline =
+                initrd_pipeline.with_step(InitrdStep::AppendSecrets(initrd_secrets.clone()));
+        } else if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
+            initrd_pipeline = initrd_pipeline.with_step(InitrdStep::AppendSecretsScript(
+                initrd_secrets_script.clone(),
+            ));
+        }
+        let initrd_location = initrd_pipeline
+            .run(
+                bootspec
+                    .initrd
+                    .as_ref()
+                    .context("Lanzaboote does not support missing initrd yet.")?,
+                &tempdir,
+            )
+            .with_context(|| {
+                format!("Failed to assemble the initrd of generation {generation}.")
+            })?;
+        if self.is_revoked(&initrd_location)? {
+            log::warn!(
+                "Skipping generation {} because its initrd is revoked.",
+                generation
+            );
+            self.statistics.entries_skipped += 1;
+            return Ok(false);
         }
+        // Only stubs that refer to files in the Nix store can be cached, not to initrds that were
+        // processed, e.g. to append secrets.
+        let cache_files = (install_cache::is_store_path(&bootspec.kernel)
+            && install_cache::is_store_path(&initrd_location)
+            && self.initrd_key.is_none())
+        .then_some(vec![
+            (bootspec.kernel.clone(), kernel_target.clone()),
+            (initrd_location.clone(), PathBuf::new()),
+        ]);
+        // The initrd is revoked by its plain hash above, but the stub checks the encrypted one.
+        let initrd_location = match &self.initrd_key {
+            Some(key) => {
+                // The initrd is mapped, so that only the encrypted copy is in memory.
+                let initrd =
+                    MappedFile::open(&initrd_location).context("Failed to read the initrd.")?;
+                let encrypted = encrypt_initrd(key, &initrd);
+                tempdir
+                    .write_secure_file(encrypted)
+                    .context("Failed to write the encrypted initrd.")?
+            }
+            None => initrd_location,
+        };
         let initrd_target = self
             .install_nixos_ca(&initrd_location, &format!("initrd-{}", kernel_version))
             .context("Failed to install the initrd.")?;
+        let cache_files = cache_files.and_then(|mut files| {
+            files[1].1 = initrd_target.clone();
+            files
+                .into_iter()
+                .map(|(source, target)| {
+                    let target = target.strip_prefix(self.payload_root()).ok()?;
+                    Some((source, target.to_path_buf()))
+                })
+                .collect::<Option<Vec<_>>>()
+        });
 
         // Assemble, sign and install the Lanzaboote stub.
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
+        let os_release =
+            OsRelease::from_generation(generation, &self.entry_title, &self.entry_sort)
+                .context("Failed to build OsRelease from generation.")?;
 
         let os_release_contents = os_release.to_string();
 
-        let kernel_cmdline =
-            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
+        let kernel_cmdline = self.kernel_cmdline(generation);
+        log::info!(
+            "Command line of {}: {}",
+            generation.describe(),
+            kernel_cmdline.join(" ")
+        );
 
         let parameters = pe::StubParameters::new(
             &self.lanzaboote_stub,
@@ -253,66 +1104,710 @@ impl<S: Signer> Installer<S> {
             &initrd_location,
             &kernel_target,
             &initrd_target,
-            &self.esp_paths.esp,
+            self.payload_root(),
         )?
         .with_cmdline(&kernel_cmdline)
-        .with_os_release_contents(os_release_contents.as_bytes());
+        .with_os_release_contents(os_release_contents.as_bytes())
+        .with_simulate_secure_boot(self.simulate_secure_boot)
+        .with_insecure_boot_policy(self.insecure_boot_policy)
+        .with_verbosity(self.stub_verbosity)
+        .with_locale(self.stub_locale)
+        .with_clear_screen(self.clear_screen)
+        .with_serial_console(self.serial_console)
+        .with_log_file(self.stub_log_file)
+        .with_revocation_list(&self.revocation_list)
+        .with_watchdog_timeout(self.watchdog_timeout)
+        .with_authcert(self.authcert.as_deref())
+        .with_authcert_threshold(self.authcert_threshold)
+        .with_allow_smbios_cmdline(self.allow_smbios_cmdline)
+        .with_allow_fw_cfg(self.allow_fw_cfg)
+        .with_instance_metadata(self.instance_metadata)
+        .with_encrypted_initrd(self.initrd_key.is_some())
+        .with_kernel_release(kernel_release)
+        .with_refuse_mismatched_resume(self.refuse_mismatched_resume)
+        .with_clock_check(
+            self.clock_check,
+            generation
+                .build_time
+                .and_then(|date| u64::try_from(date.midnight().assume_utc().unix_timestamp()).ok()),
+        )
+        .with_lockdown(self.lockdown, self.module_sig_enforce)
+        .with_payload_partition(
+            self.payload_partition
+                .as_ref()
+                .map(|(_, selector)| selector.clone()),
+        )
+        .with_fs_drivers(&self.fs_driver_paths)
+        .with_payload_luks(
+            self.payload_luks
+                .clone()
+                .filter(|_| self.payload_partition.is_some()),
+        );
+        let extension = &generation.spec.lanzaboote_extension;
+        let parameters = parameters.with_verity_root_hash(extension.verity_root_hash.as_deref());
+        let parameters = parameters.with_security_version(
+            extension.security_version,
+            extension.minimum_security_version,
+        );
+        let parameters = match &self.splash {
+            Some(splash) => parameters.with_splash(splash),
+            None => parameters,
+        };
+
+        self.install_stub(
+            generation,
+            StubVariant::Default,
+            &parameters,
+            cache_files.as_deref(),
+        )?;
+
+        if self.recovery_entries {
+            let mut recovery_cmdline = kernel_cmdline;
+            recovery_cmdline.push(RECOVERY_KERNEL_PARAM.to_string());
+            let parameters = parameters
+                .with_cmdline(&recovery_cmdline)
+                .with_os_release_contents(os_release.into_recovery().to_string().as_bytes());
+            self.install_stub(
+                generation,
+                StubVariant::Recovery,
+                &parameters,
+                cache_files.as_deref(),
+            )?;
+        }
 
-        let lanzaboote_image_path = lanzaboote_image(&tempdir, &parameters)
+        self.statistics.entries_installed += 1;
+        Ok(true)
+    }
+
+    /// The SHA256 hash of a file, from the install cache if there is one.
+    fn file_hash(&mut self, path: &Path) -> Result<FileHash> {
+        match &mut self.cache {
+            Some(cache) => cache.file_hash(path),
+            None => Ok(file_hash(path)?.into()),
+        }
+    }
+
+    /// Check whether the hash of a kernel or initrd is on the revocation list.
+    fn is_revoked(&mut self, path: &Path) -> Result<bool> {
+        if self.revocation_list.is_empty() {
+            return Ok(false);
+        }
+        let hash = self.file_hash(path)?;
+        let revoked = self.revocation_list.contains(&hash);
+        if revoked {
+            log::debug!("{path:?} has the revoked hash {}.", format_hash(&hash));
+        }
+        Ok(revoked)
+    }
+
+    /// Assemble, sign and install a stub for a generation.
+    ///
+    /// With `cache_files`, the files that the stub refers to (see [`InstallCache::insert_stub`]),
+    /// the signed stub is cached.
+    fn install_stub(
+        &mut self,
+        generation: &Generation,
+        variant: StubVariant,
+        parameters: &pe::StubParameters,
+        cache_files: Option<&[(PathBuf, PathBuf)]>,
+    ) -> Result<()> {
+        let lanzaboote_image = self
+            .signer
+            .build_and_sign_stub(parameters)
             .context("Failed to build and sign lanzaboote stub image.")?;
 
         let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.signer).context("Get stub name")?);
+            .stub_target(generation, variant)
+            .context("Get stub name")?;
         self.gc_roots.extend([&stub_target]);
-        install_signed(&self.signer, &lanzaboote_image_path, &stub_target)
+        log::debug!("Installing {stub_target:?}...");
+        atomic_write(&mut self.esp_fs, &stub_target, &lanzaboote_image)
             .context("Failed to install the Lanzaboote stub.")?;
+        self.statistics.record_signed_write(lanzaboote_image.len());
+        self.signed_files.push(stub_target.clone());
+        self.keep_dropin_directory(&stub_target)?;
+        if variant == StubVariant::Default {
+            self.record_entry(generation, &stub_target);
+        }
+        if let (Some(cache), Some(files), Some(name)) = (
+            &mut self.cache,
+            cache_files,
+            stub_target.file_name().and_then(OsStr::to_str),
+        ) {
+            cache.insert_stub(name, &lanzaboote_image, files.to_vec());
+        }
 
         Ok(())
     }
 
-    /// Register the files of an already installed generation as garbage collection roots.
-    ///
+    /// The variants of stubs that are installed for every generation.
+    fn stub_variants(&self) -> Vec<StubVariant> {
+        let mut variants = vec![StubVariant::Default];
+        if self.recovery_entries {
+            variants.push(StubVariant::Recovery);
+        }
+        variants
+    }
+
+    /// Install a generation with the signed stubs from the install cache instead of assembling
+    /// and signing them.
+    ///
+    /// Returns false if any of its stubs is not cached or refers to a file that is missing or
+    /// revoked. The generation is then installed as usual.
+    fn install_cached_generation(&mut self, generation: &Generation) -> Result<bool> {
+        if self.cache.is_none() {
+            return Ok(false);
+        }
+        let mut stubs = Vec::new();
+        for variant in self.stub_variants() {
+            let stub_target = self.stub_target(generation, variant)?;
+            let Some(name) = stub_target.file_name().and_then(OsStr::to_str) else {
+                return Ok(false);
+            };
+            let Some(cached) = self.cache.as_mut().and_then(|cache| cache.stub(name)) else {
+                return Ok(false);
+            };
+            stubs.push((variant, stub_target, cached));
+        }
+        for (_, _, (_, cached)) in &stubs {
+            for (source, _) in &cached.files {
+                if !source.exists() || self.is_revoked(source)? {
+                    return Ok(false);
+                }
+            }
+        }
+
+        for (variant, stub_target, (contents, cached)) in stubs {
+            for (source, target) in &cached.files {
+                let hash = self.file_hash(source)?;
+                let target = self.payload_root().join(target);
+                self.gc_roots.extend([&target]);
+                if let Some(bytes) = install_hashed(&mut self.esp_fs, source, &hash, &target)? {
+                    self.statistics.record_write(bytes);
+                }
+            }
+            log::debug!("Installing {stub_target:?} from the install cache...");
+            self.gc_roots.extend([&stub_target]);
+            atomic_write(&mut self.esp_fs, &stub_target, &contents)
+                .context("Failed to install the Lanzaboote stub.")?;
+            self.statistics.record_write(contents.len());
+            self.signed_files.push(stub_target.clone());
+            self.keep_dropin_directory(&stub_target)?;
+            if variant == StubVariant::Default {
+                self.record_entry(generation, &stub_target);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Keep the drop-in directory of a stub, e.g. with addons or credentials, from being garbage
+    /// collected.
+    fn keep_dropin_directory(&mut self, stub_target: &Path) -> Result<()> {
+        let mut dropin_directory = stub_target.as_os_str().to_owned();
+        dropin_directory.push(".extra");
+        let dropin_directory = PathBuf::from(dropin_directory);
+        if !self.esp_fs.is_dir(&dropin_directory) {
+            return Ok(());
+        }
+
+        let files = self.esp_fs.list(&dropin_directory)?;
+        self.keep_files(&files);
+        self.gc_roots.extend([&dropin_directory]);
+        Ok(())
+    }
+
+    /// Remember the boot entry of a generation, unless it is a specialisation.
+    fn record_entry(&mut self, generation: &Generation, stub_target: &Path) {
+        if generation.specialisation_name.is_some() {
+            return;
+        }
+        if let Some(name) = stub_target.file_name().and_then(OsStr::to_str) {
+            self.entries.insert(generation.version, name.to_string());
+        }
+    }
+
+    /// The command line of the entries of `generation`, see [`CmdlineFragments`].
+    fn kernel_cmdline(&self, generation: &Generation) -> Vec<String> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        self.cmdline_fragments.compose(
+            generation.version,
+            generation
+                .specialisation_name
+                .as_ref()
+                .map(|name| name.0.as_str()),
+            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone()),
+        )
+    }
+
+    /// The path of the stub of a generation in `EFI/Linux`, see [`stub_name`].
+    fn stub_target(&self, generation: &Generation, variant: StubVariant) -> Result<PathBuf> {
+        let mut policy = self.stub_policy()?;
+        // The rest of the command line comes from the toplevel, which is part of the name anyway.
+        let extra_cmdline = self.cmdline_fragments.extra(
+            generation.version,
+            generation
+                .specialisation_name
+                .as_ref()
+                .map(|name| name.0.as_str()),
+        );
+        if !extra_cmdline.is_empty() {
+            policy.push(("cmdline", extra_cmdline.join(" ").into_bytes()));
+        }
+        let name = stub_name(generation, &self.signer, &policy, variant)?;
+        Ok(self.esp_paths.linux.join(self.namespaced(name)))
+    }
+
+    /// Prefix the name of a file in `EFI/Linux` with the namespace, if any.
+    fn namespaced(&self, name: impl AsRef<Path>) -> PathBuf {
+        match &self.namespace {
+            Some(namespace) => {
+                let mut namespaced = OsString::from(format!("{namespace}-"));
+                namespaced.push(name.as_ref());
+                PathBuf::from(namespaced)
+            }
+            None => name.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The settings that are embedded into every stub, in addition to the generation itself.
+    ///
+    /// Only settings that differ from their default are returned, so that stubs installed before
+    /// a setting existed keep their name.
+    fn stub_policy(&self) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        let mut policy = Vec::new();
+        if self.simulate_secure_boot {
+            policy.push(("simulate_secure_boot", b"1".to_vec()));
+        }
+        if self.insecure_boot_policy != InsecureBootPolicy::Warn {
+            policy.push((
+                "insecure_boot_policy",
+                self.insecure_boot_policy.as_str().as_bytes().to_vec(),
+            ));
+        }
+        if self.stub_verbosity != StubVerbosity::Normal {
+            policy.push((
+                "verbosity",
+                self.stub_verbosity.as_str().as_bytes().to_vec(),
+            ));
+        }
+        if self.stub_locale != StubLocale::En {
+            policy.push(("locale", self.stub_locale.as_str().as_bytes().to_vec()));
+        }
+        if self.clear_screen {
+            policy.push(("clear_screen", b"1".to_vec()));
+        }
+        if self.serial_console {
+            policy.push(("serial_console", b"1".to_vec()));
+        }
+        if self.stub_log_file {
+            policy.push(("log_file", b"1".to_vec()));
+        }
+        if let Some(splash) = &self.splash {
+            policy.push(("splash", file_hash(splash)?.to_vec()));
+        }
+        if !self.revocation_list.is_empty() {
+            policy.push(("revoked", self.revocation_list.to_section()));
+        }
+        if let Some(timeout) = self.watchdog_timeout {
+            policy.push(("watchdog_timeout", timeout.to_string().into_bytes()));
+        }
+        if let Some(authcert) = &self.authcert {
+            policy.push(("authcert", authcert.clone()));
+        }
+        if let Some(threshold) = self.authcert_threshold {
+            policy.push(("authcert_threshold", threshold.to_string().into_bytes()));
+        }
+        if self.allow_smbios_cmdline {
+            policy.push(("allow_smbios_cmdline", b"1".to_vec()));
+        }
+        if self.allow_fw_cfg {
+            policy.push(("allow_fw_cfg", b"1".to_vec()));
+        }
+        if self.instance_metadata {
+            policy.push(("instance_metadata", b"1".to_vec()));
+        }
+        if let Some(key) = &self.initrd_key {
+            policy.push(("initrd_key", key.fingerprint()));
+        }
+        if self.refuse_mismatched_resume {
+            policy.push(("refuse_mismatched_resume", b"1".to_vec()));
+        }
+        if self.clock_check != ClockCheck::Warn {
+            policy.push(("clock_check", self.clock_check.as_str().as_bytes().to_vec()));
+        }
+        if let Some((_, selector)) = &self.payload_partition {
+            policy.push(("payload_partition", selector.to_string().into_bytes()));
+        }
+        if !self.fs_driver_paths.is_empty() {
+            policy.push(("fs_drivers", self.fs_driver_paths.join("\n").into_bytes()));
+        }
+        if let Some(luks) = &self.payload_luks {
+            policy.push(("payload_luks", luks.to_string().into_bytes()));
+        }
+        if self.lockdown != LockdownMode::None {
+            policy.push(("lockdown", self.lockdown.as_str().as_bytes().to_vec()));
+        }
+        if self.module_sig_enforce {
+            policy.push(("module_sig_enforce", b"1".to_vec()));
+        }
+        if !self.initrd_steps.is_empty() {
+            // The archives and programs may change without changing their paths.
+            let mut steps = Vec::new();
+            for step in &self.initrd_steps {
+                steps.extend(step.to_string().into_bytes());
+                if let InitrdStep::Prepend(path)
+                | InitrdStep::Append(path)
+                | InitrdStep::Exec(path) = step
+                {
+                    steps.extend(file_hash(path)?);
+                }
+                steps.push(b'\n');
+            }
+            policy.push(("initrd_steps", steps));
+        }
+        // The title and version are part of the embedded os-release.
+        if self.entry_title != EntryTitle::default() {
+            policy.push(("entry_title", self.entry_title.to_string().into_bytes()));
+        }
+        if self.entry_sort != EntrySort::default() {
+            policy.push(("entry_sort", self.entry_sort.to_string().into_bytes()));
+        }
+        Ok(policy)
+    }
+
+    /// Register the files of an already installed generation as garbage collection roots.
+    ///
     /// An error should not be considered fatal; the generation should be (re-)installed instead.
     fn register_installed_generation(&mut self, generation: &Generation) -> Result<()> {
         let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.signer).context("While getting stub name")?);
-        let stub = fs::read(&stub_target)
+            .stub_target(generation, StubVariant::Default)
+            .context("While getting stub name")?;
+        let stub = self
+            .esp_fs
+            .read(&stub_target)
             .with_context(|| format!("Failed to read the stub: {}", stub_target.display()))?;
         let kernel_path = resolve_efi_path(
-            &self.esp_paths.esp,
+            self.payload_root(),
             pe::read_section_data(&stub, ".linux").context("Missing kernel path.")?,
         )?;
         let initrd_path = resolve_efi_path(
-            &self.esp_paths.esp,
+            self.payload_root(),
             pe::read_section_data(&stub, ".initrd").context("Missing initrd path.")?,
         )?;
 
-        if !kernel_path.exists() && !initrd_path.exists() {
+        if !self.esp_fs.exists(&kernel_path) && !self.esp_fs.exists(&initrd_path) {
             anyhow::bail!("Missing kernel or initrd.");
         }
+        if let Some(path) = [&stub_target, &kernel_path, &initrd_path]
+            .into_iter()
+            .find(|path| !self.is_verified(path))
+        {
+            anyhow::bail!("{path:?} is not verified by the manifest.");
+        }
         self.gc_roots
             .extend([&stub_target, &kernel_path, &initrd_path]);
+        self.keep_dropin_directory(&stub_target)?;
+        self.record_entry(generation, &stub_target);
+        self.keep_cached_stub(&stub_target);
+
+        if self.recovery_entries {
+            let recovery_target = self.stub_target(generation, StubVariant::Recovery)?;
+            if !self.esp_fs.exists(&recovery_target) {
+                anyhow::bail!("Missing recovery stub.");
+            }
+            if !self.is_verified(&recovery_target) {
+                anyhow::bail!("{recovery_target:?} is not verified by the manifest.");
+            }
+            self.gc_roots.extend([&recovery_target]);
+            self.keep_dropin_directory(&recovery_target)?;
+            self.keep_cached_stub(&recovery_target);
+        }
+
+        Ok(())
+    }
+
+    /// Keep the cached copy of an installed stub, if any.
+    fn keep_cached_stub(&mut self, stub_target: &Path) {
+        if let (Some(cache), Some(name)) = (
+            &mut self.cache,
+            stub_target.file_name().and_then(OsStr::to_str),
+        ) {
+            cache.keep_stub(name);
+        }
+    }
+
+    /// Copy the entry of the newest generation into a slot, unless a slot already contains it.
+    ///
+    /// The payloads of both slots and the files they reference are kept from being garbage
+    /// collected, so that the other slot stays bootable as a fallback.
+    fn install_slot_payload(&mut self) -> Result<()> {
+        let Some(active) = self.ab_slots else {
+            return Ok(());
+        };
+        let (_, entry) = self
+            .entries
+            .last_key_value()
+            .context("No entry to install into a slot")?;
+        let payload = self.esp_fs.read(&self.esp_paths.linux.join(entry))?;
+
+        let installed = [active, active.other()].into_iter().find(|slot| {
+            let path = slot.payload_path(&self.esp_paths.nixos);
+            self.esp_fs.exists(&path) && self.esp_fs.read(&path).ok().as_ref() == Some(&payload)
+        });
+        match installed {
+            Some(slot) if slot == active => log::debug!("Slot {slot} is up to date."),
+            Some(slot) => log::warn!(
+                "The newest generation is already in slot {slot}, which is not active, e.g. \
+                 because it failed to boot. Run `lzbt slot switch {}` to try it again.",
+                slot.as_str()
+            ),
+            None => {
+                let target = if self
+                    .esp_fs
+                    .exists(&active.payload_path(&self.esp_paths.nixos))
+                {
+                    active.other()
+                } else {
+                    active
+                };
+                log::info!("Installing {entry} into slot {target}...");
+                atomic_write(
+                    &mut self.esp_fs,
+                    &target.payload_path(&self.esp_paths.nixos),
+                    &payload,
+                )
+                .with_context(|| format!("Failed to install slot {target}"))?;
+                self.statistics.record_write(payload.len());
+                if target != active {
+                    self.slot_to_activate = Some(target);
+                }
+            }
+        }
+
+        for slot in [Slot::A, Slot::B] {
+            let path = slot.payload_path(&self.esp_paths.nixos);
+            if !self.esp_fs.exists(&path) {
+                continue;
+            }
+            let stub = self.esp_fs.read(&path)?;
+            let mut files = vec![path.clone()];
+            for section in [".linux", ".initrd"] {
+                if let Some(efi_path) = pe::read_section_data(&stub, section) {
+                    files.push(resolve_efi_path(self.payload_root(), efi_path)?);
+                }
+            }
+            // The slot with the newest entry holds what was just installed.
+            if stub == payload {
+                self.gc_roots.extend(&files);
+            } else {
+                self.keep_files(&files);
+            }
+            self.keep_dropin_directory(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Install the signed slot selector, unless an identical one is already installed.
+    fn install_slot_selector(&mut self) -> Result<()> {
+        if self.ab_slots.is_none() {
+            return Ok(());
+        }
+        let target = self
+            .esp_paths
+            .linux
+            .join(self.namespaced(slots::SELECTOR_ENTRY));
+        self.gc_roots.extend([&target]);
 
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let selector = pe::slot_selector_image(
+            &tempdir,
+            &self.lanzaboote_stub,
+            &self.esp_paths.esp,
+            &[Slot::A, Slot::B].map(|slot| slot.payload_path(&self.esp_paths.nixos)),
+            slots::SELECTOR_OS_RELEASE.as_bytes(),
+        )
+        .context("Failed to assemble the slot selector")?;
+
+        if self.esp_fs.exists(&target) {
+            let installed = self.esp_fs.read(&target)?;
+            let assembled = fs::read(&selector)?;
+            let unchanged = [".text", ".abslots"].iter().all(|section| {
+                pe::read_section_data(&installed, section)
+                    == pe::read_section_data(&assembled, section)
+            });
+            if unchanged && self.signer.verify(&installed)? {
+                return Ok(());
+            }
+        }
+
+        log::info!("Installing the slot selector...");
+        let bytes = install_signed(&mut self.esp_fs, &self.signer, &selector, &target)
+            .context("Failed to install the slot selector")?;
+        self.statistics.record_signed_write(bytes);
+        self.signed_files.push(target);
         Ok(())
     }
 
-    /// Install a content-addressed file to the `EFI/nixos` directory on the ESP.
+    /// Install a content-addressed file to the `EFI/nixos` directory on the ESP, or on the payload
+    /// partition, see [`Self::with_payload_partition`].
     ///
     /// It is automatically added to the garbage collector roots.
     /// The full path to the target file is returned.
     fn install_nixos_ca(&mut self, from: &Path, label: &str) -> Result<PathBuf> {
-        let hash = file_hash(from).context("Failed to read the source file.")?;
-        let to = self.esp_paths.nixos.join(format!(
+        let hash = self
+            .file_hash(from)
+            .context("Failed to read the source file.")?;
+        let to = self.payload_directory().join(format!(
             "{}-{}.efi",
             label,
             Base32Unpadded::encode_string(&hash)
         ));
         self.gc_roots.extend([&to]);
-        install(from, &to)?;
+        if let Some(bytes) = install_hashed(&mut self.esp_fs, from, &hash, &to)? {
+            self.statistics.record_write(bytes);
+        }
+        Ok(to)
+    }
+
+    /// Install loader entries for other operating systems on the ESP and remove stale ones.
+    ///
+    /// With [`OtherOsMode::Sign`], the entries start signed copies of their boot loaders in the
+    /// `EFI/nixos` directory, which are named after the original and the key like the other
+    /// content-addressed files there.
+    fn install_other_os_entries(&mut self) -> Result<()> {
+        let detected = match self.other_os {
+            OtherOsMode::Ignore => Vec::new(),
+            _ => other_os::detect(&self.esp_fs, &self.esp_paths, self.arch)?,
+        };
+
+        let entries_dir = self.esp_paths.loader.join("entries");
+        let mut entries = BTreeSet::new();
+        for os in detected {
+            let binary = match self.other_os {
+                OtherOsMode::Sign => self
+                    .install_signed_copy(&os.loader, &format!("chainload-{}", os.id))
+                    .with_context(|| format!("Failed to sign the boot loader of {}", os.title))?,
+                // systemd-boot already shows an entry for the original.
+                _ if os.auto_detected => continue,
+                _ => os.loader.clone(),
+            };
+
+            let entry = entries_dir.join(os.entry_name());
+            let contents = other_os::loader_entry(&os.title, &self.esp_paths.esp, &binary)?;
+            if !self.esp_fs.exists(&entry) || self.esp_fs.read(&entry)? != contents.as_bytes() {
+                log::info!("Adding a boot entry for {}...", os.title);
+                atomic_write(&mut self.esp_fs, &entry, contents.as_bytes())?;
+                self.statistics.record_write(contents.len());
+            }
+            entries.insert(entry);
+        }
+
+        if !self.esp_fs.is_dir(&entries_dir) {
+            return Ok(());
+        }
+        for entry in self.esp_fs.list(&entries_dir)? {
+            let is_other_os_entry = entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(other_os::ENTRY_PREFIX));
+            if is_other_os_entry && !entries.contains(&entry) {
+                log::info!("Removing the stale boot entry {entry:?}...");
+                self.esp_fs.delete(&entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Install the signed file system drivers that the stubs load, see
+    /// [`Self::with_fs_drivers`].
+    ///
+    /// The drivers stay on the ESP, because the stubs need them to read the payload partition.
+    fn install_fs_drivers(&mut self) -> Result<()> {
+        self.fs_driver_paths.clear();
+        for driver in self.fs_drivers.clone() {
+            let original =
+                fs::read(&driver).with_context(|| format!("Failed to read {driver:?}"))?;
+            let name = driver
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .with_context(|| format!("Invalid file name of the driver {driver:?}"))?;
+            let installed = self
+                .install_signed_contents(&original, &format!("driver-{name}"))
+                .with_context(|| format!("Failed to sign the driver {driver:?}"))?;
+            self.fs_driver_paths
+                .push(pe::esp_relative_uefi_path(&self.esp_paths.esp, &installed)?);
+        }
+        Ok(())
+    }
+
+    /// Install the loader entry of the firmware updater, or remove it if there is none.
+    ///
+    /// The capsules that fwupd stages for its updater in `EFI/nixos` are kept from being garbage
+    /// collected, because the updater only applies them on the next boot.
+    fn install_firmware_updater(&mut self) -> Result<()> {
+        let capsules = self
+            .esp_paths
+            .nixos
+            .join(firmware_update::FWUPD_CAPSULE_DIRECTORY);
+        if self.esp_fs.is_dir(&capsules) {
+            let files = self.esp_fs.list(&capsules)?;
+            self.gc_roots.extend(&files);
+            self.gc_roots.extend([&capsules]);
+        }
+
+        let entry = firmware_update::updater_entry(&self.esp_paths.esp);
+        let Some(updater) = self.firmware_updater.clone() else {
+            if self.esp_fs.exists(&entry) {
+                log::info!("Removing the boot entry of the firmware updater...");
+                self.esp_fs.delete(&entry)?;
+            }
+            return Ok(());
+        };
+
+        let original = fs::read(&updater).with_context(|| format!("Failed to read {updater:?}"))?;
+        let binary = self
+            .install_signed_contents(&original, "fwupd")
+            .context("Failed to sign the firmware updater")?;
+        let contents =
+            other_os::loader_entry(firmware_update::UPDATER_TITLE, &self.esp_paths.esp, &binary)?;
+        if !self.esp_fs.exists(&entry) || self.esp_fs.read(&entry)? != contents.as_bytes() {
+            log::info!("Adding a boot entry for the firmware updater...");
+            atomic_write(&mut self.esp_fs, &entry, contents.as_bytes())?;
+            self.statistics.record_write(contents.len());
+        }
+        Ok(())
+    }
+
+    /// Install a signed copy of a binary on the ESP to the `EFI/nixos` directory.
+    ///
+    /// It is automatically added to the garbage collector roots.
+    fn install_signed_copy(&mut self, from: &Path, label: &str) -> Result<PathBuf> {
+        let original = self.esp_fs.read(from)?;
+        self.install_signed_contents(&original, label)
+    }
+
+    /// Install a signed copy of `original` to the `EFI/nixos` directory, like
+    /// [`Self::install_signed_copy`].
+    fn install_signed_contents(&mut self, original: &[u8], label: &str) -> Result<PathBuf> {
+        let mut hasher = Sha256::new();
+        hasher.update(original);
+        hasher.update(self.signer.get_public_key()?);
+        let to = self.esp_paths.nixos.join(format!(
+            "{}-{}.efi",
+            label,
+            Base32Unpadded::encode_string(&hasher.finalize())
+        ));
+        self.gc_roots.extend([&to]);
+
+        if !self.esp_fs.exists(&to) {
+            let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+            let unsigned = tempdir.path().join("unsigned.efi");
+            fs::write(&unsigned, original)
+                .with_context(|| format!("Failed to write {unsigned:?}"))?;
+            let bytes = install_signed(&mut self.esp_fs, &self.signer, &unsigned, &to)?;
+            self.statistics.record_signed_write(bytes);
+            self.signed_files.push(to.clone());
+        }
         Ok(to)
     }
 
@@ -324,34 +1819,43 @@ impl<S: Signer> Installer<S> {
     /// to the ESP.
     ///
     /// Checking for the version also allows us to skip buggy systemd versions in the future.
-    fn install_systemd_boot(&self) -> Result<()> {
+    fn install_systemd_boot(&mut self) -> Result<()> {
         let systemd_boot = self
             .systemd
             .join("lib/systemd/boot/efi")
             .join(self.arch.systemd_filename());
 
-        let paths = [
-            (&systemd_boot, &self.esp_paths.efi_fallback),
-            (&systemd_boot, &self.esp_paths.systemd_boot),
-        ];
+        let mut paths = vec![(&systemd_boot, &self.esp_paths.systemd_boot)];
+        // The first stage takes the place of systemd-boot as the EFI fallback.
+        if !self.first_stage {
+            paths.push((&systemd_boot, &self.esp_paths.efi_fallback));
+        }
 
         for (from, to) in paths {
-            let newer_systemd_boot_available = newer_systemd_boot(from, to)?;
+            let newer_systemd_boot_available = newer_systemd_boot(&self.esp_fs, from, to)?;
             if newer_systemd_boot_available {
                 log::info!("Updating {to:?}...")
             };
-            let systemd_boot_is_signed = &self.signer.verify_path(to)?;
+            let systemd_boot_is_signed =
+                self.esp_fs.exists(to) && self.signer.verify(&self.esp_fs.read(to)?)?;
             if !systemd_boot_is_signed {
                 log::warn!("${to:?} is not signed. Replacing it with a signed binary...")
             };
 
             if newer_systemd_boot_available || !systemd_boot_is_signed {
-                install_signed(&self.signer, from, to)
+                let bytes = install_signed(&mut self.esp_fs, &self.signer, from, to)
                     .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
+                self.statistics.record_signed_write(bytes);
+                self.signed_files.push(to.clone());
             }
         }
 
-        install(
+        if self.first_stage {
+            self.install_first_stage()?;
+        }
+
+        let written = install(
+            &mut self.esp_fs,
             &self.systemd_boot_loader_config,
             &self.esp_paths.systemd_boot_loader_config,
         )
@@ -361,23 +1865,94 @@ impl<S: Signer> Installer<S> {
                 &self.esp_paths.systemd_boot_loader_config
             )
         })?;
+        if let Some(bytes) = written {
+            self.statistics.record_write(bytes);
+        }
 
         Ok(())
     }
+
+    /// Install the signed first stage as the EFI fallback, unless an identical one is already
+    /// installed.
+    ///
+    /// The first stage embeds the hash of the installed systemd-boot, so it is installed after
+    /// systemd-boot.
+    fn install_first_stage(&mut self) -> Result<()> {
+        let target = self.esp_paths.efi_fallback.clone();
+        let systemd_boot_hash = Sha256::digest(self.esp_fs.read(&self.esp_paths.systemd_boot)?);
+
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let first_stage = pe::first_stage_image(
+            &tempdir,
+            &self.lanzaboote_stub,
+            &self.esp_paths.esp,
+            &self.esp_paths.systemd_boot,
+            &systemd_boot_hash,
+            self.simulate_secure_boot,
+        )
+        .context("Failed to assemble the first stage")?;
+
+        if self.esp_fs.exists(&target) {
+            let installed = self.esp_fs.read(&target)?;
+            let assembled = fs::read(&first_stage)?;
+            let unchanged = [".text", ".sdboot", ".sdbooth", ".sbsim"]
+                .iter()
+                .all(|section| {
+                    pe::read_section_data(&installed, section)
+                        == pe::read_section_data(&assembled, section)
+                });
+            if unchanged && self.signer.verify(&installed)? {
+                return Ok(());
+            }
+        }
+
+        log::info!("Installing the first stage to {target:?}...");
+        let bytes = install_signed(&mut self.esp_fs, &self.signer, &first_stage, &target)
+            .context("Failed to install the first stage")?;
+        self.statistics.record_signed_write(bytes);
+        self.signed_files.push(target);
+        Ok(())
+    }
 }
 
 /// Translate an EFI path to an absolute path on the mounted ESP.
-fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
+pub fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
     Ok(esp.join(std::str::from_utf8(&efi_path[1..])?.replace('\\', "/")))
 }
 
+/// The kernel parameter that makes recovery entries boot into the rescue target.
+const RECOVERY_KERNEL_PARAM: &str = "systemd.unit=rescue.target";
+
+/// The kinds of stubs that are installed for a generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StubVariant {
+    /// The regular boot entry.
+    Default,
+    /// A boot entry that boots into the rescue target.
+    Recovery,
+}
+
+impl StubVariant {
+    fn name_suffix(self) -> &'static str {
+        match self {
+            Self::Default => "",
+            Self::Recovery => "-recovery",
+        }
+    }
+}
+
 /// Compute the file name to be used for the stub of a certain generation, signed with the given key.
 ///
 /// The generated name is input-addressed by the toplevel corresponding to the generation and the public part of the signing key.
-fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf> {
+fn stub_name<S: Signer>(
+    generation: &Generation,
+    signer: &S,
+    policy: &[(&'static str, Vec<u8>)],
+    variant: StubVariant,
+) -> Result<PathBuf> {
     let bootspec = &generation.spec.bootspec.bootspec;
     let public_key = signer.get_public_key()?;
-    let stub_inputs = [
+    let mut stub_inputs = vec![
         // Generation numbers can be reused if the latest generation was deleted.
         // To detect this, the stub path depends on the actual toplevel used.
         ("toplevel", bootspec.toplevel.0.as_os_str().as_bytes()),
@@ -385,18 +1960,21 @@ fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf>
         // So we make their path depend on the public key used for signature.
         ("public_key", &public_key),
     ];
+    // If the embedded policy changes, the stubs must be re-generated as well.
+    stub_inputs.extend(policy.iter().map(|(name, value)| (*name, value.as_slice())));
     let stub_input_hash = Base32Unpadded::encode_string(&Sha256::digest(
         serde_json::to_string(&stub_inputs).unwrap(),
     ));
+    let suffix = variant.name_suffix();
     if let Some(specialisation_name) = &generation.specialisation_name {
         Ok(PathBuf::from(format!(
-            "nixos-generation-{}-specialisation-{}-{}.efi",
-            generation, specialisation_name, stub_input_hash
+            "nixos-generation-{}-specialisation-{}{}-{}.efi",
+            generation, specialisation_name, suffix, stub_input_hash
         )))
     } else {
         Ok(PathBuf::from(format!(
-            "nixos-generation-{}-{}.efi",
-            generation, stub_input_hash
+            "nixos-generation-{}{}-{}.efi",
+            generation, suffix, stub_input_hash
         )))
     }
 }
@@ -405,20 +1983,20 @@ fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf>
 ///
 /// If the file already exists at the destination, it is overwritten.
 ///
-/// This is implemented as an atomic write. The file is first written to the destination with a
-/// `.tmp` suffix and then renamed to its final name. This is atomic, because a rename is an atomic
-/// operation on POSIX platforms.
-fn install_signed(signer: &impl Signer, from: &Path, to: &Path) -> Result<()> {
+/// The PE is signed outside of the ESP and then atomically written to its destination, see
+/// [`atomic_write`]. Returns the number of bytes written.
+fn install_signed(
+    esp: &mut impl EspFilesystem,
+    signer: &impl Signer,
+    from: &Path,
+    to: &Path,
+) -> Result<usize> {
     log::debug!("Signing and installing {to:?}...");
-    let to_tmp = to.with_extension(".tmp");
-    ensure_parent_dir(&to_tmp);
-    signer
-        .sign_and_copy(from, &to_tmp)
-        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))?;
-    fs::rename(&to_tmp, to).with_context(|| {
-        format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
-    })?;
-    Ok(())
+    let signed = signer
+        .sign_store_path(from)
+        .with_context(|| format!("Failed to sign file from {from:?} for {to:?}"))?;
+    atomic_write(esp, to, &signed)?;
+    Ok(signed.len())
 }
 
 /// Install an arbitrary file.
@@ -426,27 +2004,35 @@ fn install_signed(signer: &impl Signer, from: &Path, to: &Path) -> Result<()> {
 /// The file is only copied if
 ///     (1) it doesn't exist at the destination or,
 ///     (2) the hash of the file at the destination does not match the hash of the source file.
-fn install(from: &Path, to: &Path) -> Result<()> {
-    if !to.exists() || file_hash(from)? != file_hash(to)? {
-        force_install(from, to)?;
+///
+/// Returns the number of bytes written if the file was copied.
+fn install(esp: &mut impl EspFilesystem, from: &Path, to: &Path) -> Result<Option<usize>> {
+    install_hashed(esp, from, &file_hash(from)?, to)
+}
+
+/// Install an arbitrary file whose hash is already known, see [`install`].
+pub fn install_hashed(
+    esp: &mut impl EspFilesystem,
+    from: &Path,
+    hash: &[u8],
+    to: &Path,
+) -> Result<Option<usize>> {
+    if !esp.exists(to) || hash != esp.hash(to)?.as_slice() {
+        return force_install(esp, from, to).map(Some);
     }
-    Ok(())
+    Ok(None)
 }
 
 /// Forcibly install an arbitrary file.
 ///
-/// If the file already exists at the destination, it is overwritten.
-///
-/// This function is only designed to copy files to the ESP. It sets the permission bits of the
-/// file at the destination to 0o755, the expected permissions for a vfat ESP. This is useful for
-/// producing file systems trees which can then be converted to a file system image.
-fn force_install(from: &Path, to: &Path) -> Result<()> {
+/// If the file already exists at the destination, it is overwritten. Returns the number of bytes
+/// written.
+fn force_install(esp: &mut impl EspFilesystem, from: &Path, to: &Path) -> Result<usize> {
     log::debug!("Installing {to:?}...");
-    ensure_parent_dir(to);
-    atomic_copy(from, to)?;
-    set_permission_bits(to, 0o755)
-        .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))?;
-    Ok(())
+    let contents = MappedFile::open(from)
+        .with_context(|| format!("Failed to read the source file {from:?}"))?;
+    atomic_write(esp, to, &contents)?;
+    Ok(contents.len())
 }
 
 fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<String> {
@@ -459,48 +2045,33 @@ fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<Strin
     kernel_cmdline
 }
 
-/// Atomically copy a file.
+/// Atomically write a file.
 ///
-/// First, the content is written to a temporary file (with a `.tmp` extension).
-/// Then, this file is synced, to ensure its data and metadata are fully on disk before continuing.
-/// In the last step, the temporary file is renamed to the final destination.
+/// First, the content is written to a temporary file (with a `.tmp` extension), which the ESP
+/// implementation syncs to disk. Then, the temporary file is renamed to the final destination.
+/// If any of this fails, the temporary file is removed again.
 ///
 /// Due to the deficiencies of FAT32, it is possible for the filesystem to become corrupted after power loss.
 /// It is not possible to fully defend against this situation, so this operation is not actually fully atomic.
 /// However, in all other cases, the target file is either present with its correct content or not present at all.
-fn atomic_copy(from: &Path, to: &Path) -> Result<()> {
+pub fn atomic_write(esp: &mut impl EspFilesystem, to: &Path, contents: &[u8]) -> Result<()> {
     let tmp = to.with_extension(".tmp");
-    {
-        let mut from_file =
-            File::open(from).with_context(|| format!("Failed to read the source file {from:?}"))?;
-        let mut tmp_file = File::create(&tmp)
-            .with_context(|| format!("Failed to create the temporary file {tmp:?}"))?;
-        std::io::copy(&mut from_file, &mut tmp_file).with_context(|| {
-            format!("Failed to copy from {from:?} to the temporary file {tmp:?}")
-        })?;
-        tmp_file
-            .sync_all()
-            .with_context(|| format!("Failed to sync the temporary file {tmp:?}"))?;
-    }
-    fs::rename(&tmp, to)
-        .with_context(|| format!("Failed to move temporary file {tmp:?} to target {to:?}"))
-}
-
-/// Set the octal permission bits of the specified file.
-fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
-    let mut perms = fs::metadata(path)
-        .with_context(|| format!("File {path:?} doesn't have any metadata"))?
-        .permissions();
-    perms.set_mode(permission_bits);
-    fs::set_permissions(path, perms)
-        .with_context(|| format!("Failed to set permissions on {path:?}"))
-}
+    let result = esp
+        .write(&tmp, contents)
+        .with_context(|| format!("Failed to write the temporary file {tmp:?}"))
+        .and_then(|()| {
+            esp.rename(&tmp, to)
+                .with_context(|| format!("Failed to move temporary file {tmp:?} to target {to:?}"))
+        });
 
-// Ensures the parent directory of an arbitrary path exists
-fn ensure_parent_dir(path: &Path) {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).ok();
+    if result.is_err() && esp.exists(&tmp) {
+        // Roll back, so that no stale temporary files accumulate on the ESP.
+        if let Err(e) = esp.delete(&tmp) {
+            log::warn!("Failed to remove temporary file {tmp:?}: {e:#}");
+        }
     }
+
+    result
 }
 
 /// Determine if a newer systemd-boot version is available.
@@ -509,9 +2080,9 @@ fn ensure_parent_dir(path: &Path) {
 ///   (1) no file exists at the destination,
 ///   (2) the file at the destination is malformed,
 ///   (3) a binary with a higher version is available.
-fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
+fn newer_systemd_boot(esp: &impl EspFilesystem, from: &Path, to: &Path) -> Result<bool> {
     // If the file doesn't exists at the destination, it should be installed.
-    if !to.exists() {
+    if !esp.exists(to) {
         return Ok(true);
     }
 
@@ -521,10 +2092,945 @@ fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
 
     // If the version cannot be read from the destination binary, it is malformed. It should be
     // forcibly reinstalled.
-    let to_version = match SystemdVersion::from_systemd_boot_binary(to) {
+    let to_version = match esp
+        .read(to)
+        .and_then(|data| SystemdVersion::from_systemd_boot_image(&data))
+    {
         Ok(version) => version,
         _ => return Ok(true),
     };
 
     Ok(from_version > to_version)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+    use lanzaboote_tool::pe::StubParameters;
+    use serde_json::json;
+
+    const ESP: &str = "/esp";
+
+    /// A signer that does not need any keys or external tools.
+    ///
+    /// Signed files are marked by a trailer and stubs are represented by their parameters.
+    struct MockSigner {
+        fail: bool,
+    }
+
+    impl Signer for MockSigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            if self.fail {
+                anyhow::bail!("Mock signer refuses to sign.");
+            }
+            let mut data = fs::read(store_path)?;
+            data.extend_from_slice(b"signed");
+            Ok(data)
+        }
+
+        fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+            if self.fail {
+                anyhow::bail!("Mock signer refuses to sign.");
+            }
+            let mut data = serde_json::to_vec(stub)?;
+            data.extend_from_slice(b"signed");
+            Ok(data)
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(b"mock".to_vec())
+        }
+
+        fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+            Ok(pe_binary.ends_with(b"signed"))
+        }
+    }
+
+    /// Create a generation link with a bootspec below `dir`.
+    ///
+    /// Generations with the same `kernel_version` share kernel and initrd.
+    fn setup_generation_link(dir: &Path, version: u64, kernel_version: &str) -> Result<PathBuf> {
+        let toplevel = dir.join(format!("toplevel-{version}"));
+        let store_path = dir.join(format!("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-{kernel_version}"));
+        fs::create_dir_all(&toplevel)?;
+        fs::create_dir_all(&store_path)?;
+        fs::write(
+            store_path.join("kernel"),
+            format!("kernel {kernel_version}"),
+        )?;
+        fs::write(
+            store_path.join("initrd"),
+            format!("initrd {kernel_version}"),
+        )?;
+
+        let bootspec = json!({
+            "org.nixos.bootspec.v1": {
+                "init": format!("init-v{version}"),
+                "initrd": store_path.join("initrd"),
+                "kernel": store_path.join("kernel"),
+                "kernelParams": ["quiet"],
+                "label": "LanzaOS",
+                "toplevel": toplevel,
+                "system": "x86_64-linux",
+            },
+        });
+
+        let link = dir.join(format!("system-{version}-link"));
+        fs::create_dir_all(&link)?;
+        fs::write(link.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+        Ok(link)
+    }
+
+    fn installer(
+        esp: InMemoryEspFilesystem,
+        signer: MockSigner,
+        configuration_limit: usize,
+        links: Vec<PathBuf>,
+    ) -> Installer<MockSigner, InMemoryEspFilesystem> {
+        Installer::new(
+            PathBuf::from("/nonexistent/stub.efi"),
+            Architecture::X86,
+            PathBuf::from("/nonexistent/systemd"),
+            PathBuf::from("/nonexistent/loader.conf"),
+            signer,
+            configuration_limit,
+            PathBuf::from(ESP),
+            esp,
+            links,
+        )
+    }
+
+    fn files_in(esp: &InMemoryEspFilesystem, dir: &str) -> Vec<String> {
+        let dir = Path::new(ESP).join(dir);
+        esp.files()
+            .filter(|p| p.parent() == Some(&dir))
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn install_links(installer: &mut Installer<MockSigner, InMemoryEspFilesystem>) -> Result<()> {
+        let links = installer.links_to_install()?;
+        installer.install_generations_from_links(&links)
+    }
+
+    /// An installer with an empty ESP and without configuration limit.
+    fn fresh_installer(links: Vec<PathBuf>) -> Installer<MockSigner, InMemoryEspFilesystem> {
+        installer(
+            InMemoryEspFilesystem::new(),
+            MockSigner { fail: false },
+            0,
+            links,
+        )
+    }
+
+    /// The parameters of the stub `name` in `EFI/Linux`.
+    fn stub_parameters(
+        installer: &Installer<MockSigner, InMemoryEspFilesystem>,
+        name: &str,
+    ) -> Result<StubParameters> {
+        let stub = installer
+            .esp_fs
+            .read(&Path::new(ESP).join("EFI/Linux").join(name))?;
+        let parameters = stub
+            .strip_suffix(b"signed")
+            .context("The stub is not signed")?;
+        Ok(serde_json::from_slice(parameters)?)
+    }
+
+    /// Install the generations of `installer` and return the parameters of the only stub.
+    fn install_single_stub(
+        mut installer: Installer<MockSigner, InMemoryEspFilesystem>,
+    ) -> Result<StubParameters> {
+        install_links(&mut installer)?;
+        let linux = files_in(&installer.esp_fs, "EFI/Linux");
+        anyhow::ensure!(linux.len() == 1, "Expected a single stub, found {linux:?}");
+        stub_parameters(&installer, &linux[0])
+    }
+
+    #[test]
+    fn install_generation_to_empty_esp() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut installer = fresh_installer(vec![link]);
+        install_links(&mut installer)?;
+
+        let nixos = files_in(&installer.esp_fs, "EFI/nixos");
+        assert_eq!(nixos.len(), 2);
+        assert!(nixos.iter().any(|f| f.starts_with("kernel-6.1.1-")));
+        assert!(nixos.iter().any(|f| f.starts_with("initrd-6.1.1-")));
+
+        let linux = files_in(&installer.esp_fs, "EFI/Linux");
+        assert_eq!(linux.len(), 1);
+        assert!(linux[0].starts_with("nixos-generation-1-"));
+
+        let stub = installer
+            .esp_fs
+            .read(&Path::new(ESP).join("EFI/Linux").join(&linux[0]))?;
+        assert!(installer.signer.verify(&stub)?);
+        Ok(())
+    }
+
+    #[test]
+    fn share_content_addressed_kernels() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = vec![
+            setup_generation_link(tmpdir.path(), 1, "6.1.1")?,
+            setup_generation_link(tmpdir.path(), 2, "6.1.1")?,
+            setup_generation_link(tmpdir.path(), 3, "6.2.0")?,
+        ];
+
+        let mut installer = fresh_installer(links);
+        install_links(&mut installer)?;
+
+        assert_eq!(files_in(&installer.esp_fs, "EFI/nixos").len(), 4);
+        assert_eq!(files_in(&installer.esp_fs, "EFI/Linux").len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn install_unverified_generations_again() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut first = fresh_installer(vec![link.clone()]);
+        install_links(&mut first)?;
+        let stub = Path::new(ESP).join("EFI/Linux").join(&first.entries()[&1]);
+        let addon = PathBuf::from(format!("{}.extra/addon.efi", stub.display()));
+        first.esp_fs.write(&addon, b"addon")?;
+
+        let mut second = installer(first.esp_fs, MockSigner { fail: false }, 0, vec![link]);
+        // A missing or invalid manifest vouches for no file.
+        second.verified_files = Some(BTreeSet::new());
+        install_links(&mut second)?;
+        assert_eq!(second.statistics.entries_installed, 1);
+        assert_eq!(second.statistics.entries_unchanged, 0);
+        assert_eq!(second.unverified_files, BTreeSet::from([addon]));
+        Ok(())
+    }
+
+    #[test]
+    fn configuration_limit_selects_newest_generations() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = (1..=5)
+            .map(|v| setup_generation_link(tmpdir.path(), v, "6.1.1"))
+            .collect::<Result<Vec<_>>>()?;
+
+        let installer = installer(
+            InMemoryEspFilesystem::new(),
+            MockSigner { fail: false },
+            2,
+            links,
+        );
+        let versions = installer
+            .links_to_install()?
+            .iter()
+            .map(|l| l.version)
+            .collect::<Vec<_>>();
+
+        assert_eq!(versions, vec![4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn select_generations_before_configuration_limit() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = (1..=8)
+            .map(|v| setup_generation_link(tmpdir.path(), v, "6.1.1"))
+            .collect::<Result<Vec<_>>>()?;
+
+        let installer = installer(
+            InMemoryEspFilesystem::new(),
+            MockSigner { fail: false },
+            3,
+            links,
+        )
+        .with_selected_generations(vec!["1".parse()?, "3-5".parse()?])
+        .with_known_good_generation(Some(7));
+        let versions = installer
+            .links_to_install()?
+            .iter()
+            .map(|l| l.version)
+            .collect::<Vec<_>>();
+
+        assert_eq!(versions, vec![4, 5, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_known_good_generation_beyond_configuration_limit() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = (1..=5)
+            .map(|v| setup_generation_link(tmpdir.path(), v, "6.1.1"))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut installer = installer(
+            InMemoryEspFilesystem::new(),
+            MockSigner { fail: false },
+            2,
+            links,
+        )
+        .with_known_good_generation(Some(2));
+        install_links(&mut installer)?;
+
+        assert_eq!(
+            installer.entries().keys().copied().collect::<Vec<_>>(),
+            vec![2, 4, 5]
+        );
+        assert!(files_in(&installer.esp_fs, "EFI/Linux").contains(&installer.entries()[&2]));
+        Ok(())
+    }
+
+    #[test]
+    fn install_entries_for_other_operating_systems() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        let esp_root = Path::new(ESP);
+        esp.write(
+            &esp_root.join("EFI/Microsoft/Boot/bootmgfw.efi"),
+            b"windows",
+        )?;
+        esp.write(&esp_root.join("EFI/ubuntu/shimx64.efi"), b"shim")?;
+        esp.write(
+            &esp_root.join("loader/entries/lanzaboote-other-gone.conf"),
+            b"stale",
+        )?;
+        esp.write(&esp_root.join("loader/entries/custom.conf"), b"custom")?;
+
+        let mut installer = installer(esp, MockSigner { fail: false }, 0, Vec::new())
+            .with_other_os(OtherOsMode::Entries);
+        installer.install_other_os_entries()?;
+
+        // Windows is detected by systemd-boot itself.
+        assert_eq!(
+            files_in(&installer.esp_fs, "loader/entries"),
+            ["custom.conf", "lanzaboote-other-ubuntu.conf"]
+        );
+        assert_eq!(
+            installer
+                .esp_fs
+                .read(&esp_root.join("loader/entries/lanzaboote-other-ubuntu.conf"))?,
+            b"title ubuntu\nefi /EFI/ubuntu/shimx64.efi\n"
+        );
+
+        let mut installer = Installer {
+            other_os: OtherOsMode::Sign,
+            ..installer
+        };
+        installer.install_other_os_entries()?;
+        let windows = installer
+            .esp_fs
+            .read(&esp_root.join("loader/entries/lanzaboote-other-windows.conf"))?;
+        let windows = String::from_utf8(windows)?;
+        let chainload = windows
+            .lines()
+            .find_map(|line| line.strip_prefix("efi /"))
+            .unwrap();
+        assert!(chainload.starts_with("EFI/nixos/chainload-windows-"));
+        assert!(installer
+            .signer
+            .verify(&installer.esp_fs.read(&esp_root.join(chainload))?)?);
+
+        let mut installer = Installer {
+            other_os: OtherOsMode::Ignore,
+            ..installer
+        };
+        installer.install_other_os_entries()?;
+        assert_eq!(
+            files_in(&installer.esp_fs, "loader/entries"),
+            ["custom.conf"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_signed_firmware_updater() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let updater = tmpdir.path().join("fwupdx64.efi");
+        fs::write(&updater, b"fwupd")?;
+        let mut esp = InMemoryEspFilesystem::new();
+        let esp_root = Path::new(ESP);
+        esp.write(&esp_root.join("EFI/nixos/fw/fwupd-capsule.cap"), b"capsule")?;
+
+        let mut installer = installer(esp, MockSigner { fail: false }, 0, Vec::new())
+            .with_firmware_updater(Some(updater));
+        installer.install_firmware_updater()?;
+        let entry = installer
+            .esp_fs
+            .read(&esp_root.join("loader/entries/lanzaboote-firmware-update.conf"))?;
+        let entry = String::from_utf8(entry)?;
+        let binary = entry
+            .lines()
+            .find_map(|line| line.strip_prefix("efi /"))
+            .unwrap();
+        assert!(binary.starts_with("EFI/nixos/fwupd-"));
+        assert!(installer
+            .signer
+            .verify(&installer.esp_fs.read(&esp_root.join(binary))?)?);
+
+        // The staged capsule survives garbage collection.
+        installer.collect_garbage()?;
+        assert_eq!(
+            files_in(&installer.esp_fs, "EFI/nixos/fw"),
+            ["fwupd-capsule.cap"]
+        );
+
+        let mut installer = installer.with_firmware_updater(None);
+        installer.install_firmware_updater()?;
+        assert_eq!(
+            files_in(&installer.esp_fs, "loader/entries"),
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn collect_garbage_of_old_generations_only() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut esp = InMemoryEspFilesystem::new();
+        let esp_root = Path::new(ESP);
+        esp.write(&esp_root.join("EFI/nixos/kernel-5.0-stale.efi"), b"stale")?;
+        esp.write(
+            &esp_root.join("EFI/Linux/nixos-generation-0-stale.efi"),
+            b"stale",
+        )?;
+        esp.write(&esp_root.join("EFI/Linux/otheros-1.efi"), b"foreign")?;
+        esp.write(
+            &esp_root.join("EFI/Microsoft/Boot/bootmgfw.efi"),
+            b"foreign",
+        )?;
+
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let mut installer = installer(esp, MockSigner { fail: false }, 0, vec![link]);
+        install_links(&mut installer)?;
+        installer.collect_garbage()?;
+
+        let esp = &installer.esp_fs;
+        assert!(!esp.exists(&esp_root.join("EFI/nixos/kernel-5.0-stale.efi")));
+        assert!(!esp.exists(&esp_root.join("EFI/Linux/nixos-generation-0-stale.efi")));
+        assert!(esp.exists(&esp_root.join("EFI/Linux/otheros-1.efi")));
+        assert!(esp.exists(&esp_root.join("EFI/Microsoft/Boot/bootmgfw.efi")));
+        assert_eq!(files_in(esp, "EFI/nixos").len(), 2);
+        assert_eq!(files_in(esp, "EFI/Linux").len(), 2);
+        assert_eq!(
+            installer.removed_files(),
+            [
+                esp_root.join("EFI/nixos/kernel-5.0-stale.efi"),
+                esp_root.join("EFI/Linux/nixos-generation-0-stale.efi"),
+            ]
+        );
+        assert_eq!(installer.signed_files()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_dropin_directories_of_installed_entries() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let mut first = fresh_installer(vec![link.clone()]);
+        install_links(&mut first)?;
+
+        let linux = Path::new(ESP).join("EFI/Linux");
+        let addon = linux
+            .join(format!("{}.extra", first.entries()[&1]))
+            .join("debug.addon.efi");
+        let stale_addon = linux.join("nixos-generation-0-stale.efi.extra/debug.addon.efi");
+        let mut esp = first.esp_fs;
+        esp.write(&addon, b"addon")?;
+        esp.write(&stale_addon, b"addon")?;
+
+        let mut second = installer(esp, MockSigner { fail: false }, 0, vec![link]);
+        install_links(&mut second)?;
+        second.collect_garbage()?;
+
+        assert!(second.esp_fs.exists(&addon));
+        assert!(!second.esp_fs.exists(&stale_addon));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_generation_is_recorded_as_broken() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let good = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let broken = tmpdir.path().join("system-2-link");
+        fs::create_dir(&broken)?;
+
+        let mut installer = fresh_installer(vec![good, broken]);
+        install_links(&mut installer)?;
+
+        assert_eq!(installer.broken_gens, BTreeSet::from([2]));
+        assert_eq!(files_in(&installer.esp_fs, "EFI/Linux").len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_install_without_bootable_generations() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let broken = tmpdir.path().join("system-1-link");
+        fs::create_dir(&broken)?;
+
+        let mut installer = fresh_installer(vec![broken]);
+
+        assert!(install_links(&mut installer).is_err());
+        assert_eq!(installer.esp_fs.files().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_signing_keeps_existing_stub() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut working = fresh_installer(vec![link.clone()]);
+        install_links(&mut working)?;
+        let before = working.esp_fs;
+
+        let mut failing = installer(before.clone(), MockSigner { fail: true }, 0, vec![link]);
+        assert!(install_links(&mut failing).is_err());
+
+        assert_eq!(
+            failing.esp_fs.files().collect::<Vec<_>>(),
+            before.files().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_write_rolls_back_temporary_file() -> Result<()> {
+        let mut esp = InMemoryEspFilesystem::new();
+        let target = Path::new(ESP).join("EFI/nixos/target.efi");
+        // A directory at the destination makes the final rename fail.
+        esp.create_dir_all(&target);
+
+        assert!(atomic_write(&mut esp, &target, b"contents").is_err());
+        assert!(!esp.exists(&target.with_extension(".tmp")));
+        assert!(esp.is_dir(&target));
+        Ok(())
+    }
+
+    #[test]
+    fn install_only_rewrites_changed_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let source = tmpdir.path().join("loader.conf");
+        let target = Path::new(ESP).join("loader/loader.conf");
+        let mut esp = InMemoryEspFilesystem::new();
+
+        fs::write(&source, "timeout 0")?;
+        install(&mut esp, &source, &target)?;
+        assert_eq!(esp.read(&target)?, b"timeout 0");
+
+        fs::write(&source, "timeout 5")?;
+        install(&mut esp, &source, &target)?;
+        assert_eq!(esp.read(&target)?, b"timeout 5");
+        Ok(())
+    }
+
+    #[test]
+    fn count_written_files_and_signatures() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = vec![
+            setup_generation_link(tmpdir.path(), 1, "6.1.1")?,
+            setup_generation_link(tmpdir.path(), 2, "6.1.1")?,
+        ];
+
+        let mut first = fresh_installer(links.clone());
+        install_links(&mut first)?;
+        // The kernel and initrd are shared, but each generation has its own stub.
+        assert_eq!(first.statistics.entries_installed, 2);
+        assert_eq!(first.statistics.files_written, 4);
+        assert_eq!(first.statistics.signatures_created, 2);
+
+        let mut second = installer(first.esp_fs, MockSigner { fail: false }, 0, links);
+        install_links(&mut second)?;
+        // The mock stubs cannot be recognized as installed, but unchanged files are not copied.
+        assert_eq!(second.statistics.files_written, 2);
+        assert_eq!(second.statistics.signatures_created, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn changing_stub_policy_regenerates_stubs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut plain = fresh_installer(vec![link.clone()]);
+        install_links(&mut plain)?;
+        let plain_stubs = files_in(&plain.esp_fs, "EFI/Linux");
+
+        let mut simulated = installer(plain.esp_fs, MockSigner { fail: false }, 0, vec![link])
+            .with_simulate_secure_boot(true);
+        install_links(&mut simulated)?;
+        let stubs = files_in(&simulated.esp_fs, "EFI/Linux");
+        let simulated_stub = stubs
+            .iter()
+            .find(|s| !plain_stubs.contains(s))
+            .context("No new stub was installed")?;
+
+        let parameters = stub_parameters(&simulated, simulated_stub)?;
+        assert!(parameters.simulate_secure_boot);
+        Ok(())
+    }
+
+    #[test]
+    fn changing_cmdline_fragments_regenerates_stubs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let fragments = tmpdir.path().join("cmdline.d");
+        fs::create_dir(&fragments)?;
+        let with_fragments = |esp_fs| -> Result<_> {
+            let fragments = CmdlineFragments::read(None, None, Some(&fragments), &[])?;
+            Ok(
+                installer(esp_fs, MockSigner { fail: false }, 0, vec![link.clone()])
+                    .with_cmdline_fragments(fragments),
+            )
+        };
+
+        let mut plain = fresh_installer(vec![link.clone()]);
+        install_links(&mut plain)?;
+        let plain_stubs = files_in(&plain.esp_fs, "EFI/Linux");
+
+        // Without fragments, the stubs keep their names.
+        let mut unchanged = with_fragments(plain.esp_fs)?;
+        install_links(&mut unchanged)?;
+        assert_eq!(files_in(&unchanged.esp_fs, "EFI/Linux"), plain_stubs);
+
+        fs::write(fragments.join("debug.conf"), "1: loglevel=7\n")?;
+        let mut debug = with_fragments(unchanged.esp_fs)?;
+        install_links(&mut debug)?;
+        let stubs = files_in(&debug.esp_fs, "EFI/Linux");
+        let debug_stub = stubs
+            .iter()
+            .find(|s| !plain_stubs.contains(s))
+            .context("No new stub was installed")?;
+        let parameters = stub_parameters(&debug, debug_stub)?;
+        assert_eq!(parameters.kernel_cmdline.last().unwrap(), "loglevel=7");
+        Ok(())
+    }
+
+    #[test]
+    fn install_recovery_entries() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut plain = fresh_installer(vec![link.clone()]);
+        install_links(&mut plain)?;
+
+        // Enabling recovery entries later adds them to already installed generations.
+        let mut installer = installer(plain.esp_fs, MockSigner { fail: false }, 0, vec![link])
+            .with_recovery_entries(true);
+        install_links(&mut installer)?;
+
+        let linux = files_in(&installer.esp_fs, "EFI/Linux");
+        assert_eq!(linux.len(), 2);
+        let recovery = linux
+            .iter()
+            .find(|f| f.starts_with("nixos-generation-1-recovery-"))
+            .context("No recovery stub was installed")?;
+
+        let parameters = stub_parameters(&installer, recovery)?;
+        assert_eq!(
+            parameters.kernel_cmdline.last().map(String::as_str),
+            Some(RECOVERY_KERNEL_PARAM)
+        );
+        assert!(String::from_utf8(parameters.os_release_contents)?.contains(" - Recovery"));
+        Ok(())
+    }
+
+    #[test]
+    fn skip_generations_with_revoked_kernels() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let links = vec![
+            setup_generation_link(tmpdir.path(), 1, "6.1.1")?,
+            setup_generation_link(tmpdir.path(), 2, "6.2.0")?,
+        ];
+        let revoked_kernel = tmpdir
+            .path()
+            .join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+        let mut revocation_list = RevocationList::default();
+        revocation_list.insert(file_hash(&revoked_kernel)?.into());
+
+        let mut partially_revoked =
+            fresh_installer(links.clone()).with_revocation_list(revocation_list.clone());
+        install_links(&mut partially_revoked)?;
+
+        let linux = files_in(&partially_revoked.esp_fs, "EFI/Linux");
+        assert_eq!(linux.len(), 1);
+        assert!(linux[0].starts_with("nixos-generation-2-"));
+        let parameters = stub_parameters(&partially_revoked, &linux[0])?;
+        assert_eq!(
+            parameters.revoked_hashes,
+            revocation_list.iter().copied().collect::<Vec<_>>()
+        );
+
+        // Revoking the only remaining kernel must not remove all boot entries.
+        let mut fully_revoked =
+            fresh_installer(links[..1].to_vec()).with_revocation_list(revocation_list);
+        assert!(install_links(&mut fully_revoked).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn embed_security_version_and_root_hash_from_bootspec() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let mut bootspec: serde_json::Value =
+            serde_json::from_slice(&fs::read(link.join("boot.json"))?)?;
+        bootspec["org.nix-community.lanzaboote"] = json!({
+            "sort_key": "lanza",
+            "security_version": 3,
+            "minimum_security_version": 2,
+            "verity_root_hash": "0123abcd",
+        });
+        fs::write(link.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+
+        let parameters = install_single_stub(fresh_installer(vec![link]))?;
+        assert_eq!(parameters.security_version, Some(3));
+        assert_eq!(parameters.minimum_security_version, Some(2));
+        assert_eq!(parameters.verity_root_hash.as_deref(), Some("0123abcd"));
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_keep_each_others_files() -> Result<()> {
+        const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+        let tmpdir = tempfile::tempdir()?;
+        let first = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let second = setup_generation_link(tmpdir.path(), 2, "6.1.2")?;
+
+        let mut plain = fresh_installer(vec![first]);
+        install_links(&mut plain)?;
+        plain.collect_garbage()?;
+
+        let mut namespaced = installer(plain.esp_fs, MockSigner { fail: false }, 0, vec![second])
+            .with_namespace(Some(MACHINE_ID.to_string()));
+        install_links(&mut namespaced)?;
+        namespaced.collect_garbage()?;
+
+        // Installing without namespace again must not collect the namespaced files.
+        let mut plain = installer(namespaced.esp_fs, MockSigner { fail: false }, 0, vec![]);
+        plain.collect_garbage()?;
+
+        let linux = files_in(&plain.esp_fs, "EFI/Linux");
+        assert_eq!(linux.len(), 1);
+        assert!(linux[0].starts_with(&format!("{MACHINE_ID}-nixos-generation-2-")));
+        assert_eq!(
+            files_in(&plain.esp_fs, &format!("EFI/nixos/{MACHINE_ID}")).len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_build_date_for_clock_check() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        // 2026-10-15 12:00 UTC
+        filetime::set_symlink_file_times(
+            &link,
+            filetime::FileTime::from_unix_time(1_792_065_600, 0),
+            filetime::FileTime::from_unix_time(1_792_065_600, 0),
+        )?;
+
+        let parameters =
+            install_single_stub(fresh_installer(vec![link]).with_clock_check(ClockCheck::Set))?;
+        assert_eq!(parameters.clock_check, ClockCheck::Set);
+        assert_eq!(parameters.not_before, Some(1_792_022_400));
+        Ok(())
+    }
+
+    #[test]
+    fn embed_insecure_boot_policy() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let parameters = install_single_stub(
+            fresh_installer(vec![link]).with_insecure_boot_policy(InsecureBootPolicy::Refuse),
+        )?;
+        assert_eq!(parameters.insecure_boot_policy, InsecureBootPolicy::Refuse);
+        Ok(())
+    }
+
+    #[test]
+    fn install_kernels_to_payload_partition() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let selector: PartitionSelector = "LABEL=boot-data".parse()?;
+        let mut installer = fresh_installer(vec![link])
+            .with_payload_partition(Some((PathBuf::from("/data"), selector.clone())));
+        install_links(&mut installer)?;
+        installer.collect_garbage()?;
+
+        assert!(files_in(&installer.esp_fs, "EFI/nixos").is_empty());
+        let payloads = installer
+            .esp_fs
+            .files()
+            .filter(|path| path.starts_with("/data/EFI/nixos"))
+            .count();
+        assert_eq!(payloads, 2);
+
+        let linux = files_in(&installer.esp_fs, "EFI/Linux");
+        let parameters = stub_parameters(&installer, &linux[0])?;
+        assert_eq!(parameters.payload_partition, Some(selector));
+        assert!(parameters
+            .kernel_path_at_esp
+            .starts_with("\\EFI\\nixos\\kernel-6.1.1-"));
+        Ok(())
+    }
+
+    #[test]
+    fn unlock_encrypted_payload_partition() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let luks = LuksUnlock {
+            tpm2_handle: Some(0x8100_0001),
+            tpm2_pcrs: vec![7],
+        };
+        let parameters = install_single_stub(
+            fresh_installer(vec![link])
+                .with_payload_partition(Some((
+                    PathBuf::from("/data"),
+                    "PARTUUID=0fc63daf-8483-4772-8e79-3d69d8477de4".parse()?,
+                )))
+                .with_payload_luks(Some(luks.clone())),
+        )?;
+        assert_eq!(parameters.payload_luks, Some(luks));
+        Ok(())
+    }
+
+    #[test]
+    fn install_fs_drivers_for_payload_partition() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let driver = tmpdir.path().join("ext2_x64.efi");
+        fs::write(&driver, "driver")?;
+        let mut installer = fresh_installer(vec![link])
+            .with_payload_partition(Some((PathBuf::from("/data"), "LABEL=boot-data".parse()?)))
+            .with_fs_drivers(vec![driver]);
+        installer.install_fs_drivers()?;
+        install_links(&mut installer)?;
+        installer.collect_garbage()?;
+
+        let drivers = files_in(&installer.esp_fs, "EFI/nixos");
+        assert_eq!(drivers.len(), 1);
+        assert!(drivers[0].starts_with("driver-ext2_x64-"));
+
+        let linux = files_in(&installer.esp_fs, "EFI/Linux");
+        let parameters = stub_parameters(&installer, &linux[0])?;
+        assert_eq!(
+            parameters.fs_drivers,
+            vec![format!("\\EFI\\nixos\\{}", drivers[0])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn title_and_order_entries() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 7, "6.1.1")?;
+        fs::write(
+            tmpdir.path().join("toplevel-7/nixos-version"),
+            "24.05.20240501.abcdef\n",
+        )?;
+        let parameters = install_single_stub(
+            fresh_installer(vec![link])
+                .with_entry_title("{label} #{generation}".parse()?)
+                .with_entry_sort(EntrySort::NixosVersion),
+        )?;
+        let os_release: OsRelease = String::from_utf8(parameters.os_release_contents)?.parse()?;
+        assert_eq!(os_release.0["PRETTY_NAME"], "LanzaOS #7");
+        assert!(os_release.0["VERSION_ID"].starts_with("24.05.20240501.abcdef Generation 7, "));
+        Ok(())
+    }
+
+    #[test]
+    fn changing_initrd_steps_regenerates_stubs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+        let archive = tmpdir.path().join("firmware.cpio");
+        let with_archive = |esp_fs| {
+            installer(esp_fs, MockSigner { fail: false }, 0, vec![link.clone()])
+                .with_initrd_steps(vec![InitrdStep::Append(archive.clone())])
+        };
+
+        let mut plain = fresh_installer(vec![link.clone()]);
+        install_links(&mut plain)?;
+        let mut stubs = files_in(&plain.esp_fs, "EFI/Linux");
+
+        // Both adding a step and changing its archive install a new stub.
+        let mut esp_fs = plain.esp_fs;
+        for contents in ["first", "second"] {
+            fs::write(&archive, contents)?;
+            let mut appended = with_archive(esp_fs);
+            install_links(&mut appended)?;
+            let appended_stubs = files_in(&appended.esp_fs, "EFI/Linux");
+            assert!(appended_stubs.iter().any(|s| !stubs.contains(s)));
+            stubs = appended_stubs;
+            esp_fs = appended.esp_fs;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn changing_entry_title_regenerates_stubs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let link = setup_generation_link(tmpdir.path(), 1, "6.1.1")?;
+
+        let mut plain = fresh_installer(vec![link.clone()]);
+        install_links(&mut plain)?;
+        let plain_stubs = files_in(&plain.esp_fs, "EFI/Linux");
+
+        let mut titled = installer(plain.esp_fs, MockSigner { fail: false }, 0, vec![link])
+            .with_entry_title("{label} #{generation}".parse()?)
+            .with_entry_sort(EntrySort::NixosVersion);
+        install_links(&mut titled)?;
+        let stubs = files_in(&titled.esp_fs, "EFI/Linux");
+        let titled_stub = stubs
+            .iter()
+            .find(|s| !plain_stubs.contains(s))
+            .context("No new stub was installed")?;
+
+        let parameters = stub_parameters(&titled, titled_stub)?;
+        let os_release: OsRelease = String::from_utf8(parameters.os_release_contents)?.parse()?;
+        assert_eq!(os_release.0["PRETTY_NAME"], "LanzaOS #1");
+        Ok(())
+    }
+
+    #[test]
+    fn install_newest_generation_into_inactive_slot() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_root = Path::new(ESP);
+        let slot_a = esp_root.join("EFI/nixos/slot-a.efi");
+        let slot_b = esp_root.join("EFI/nixos/slot-b.efi");
+        let install_slot = |esp, version, active| -> Result<_> {
+            let link = setup_generation_link(tmpdir.path(), version, "6.1.1")?;
+            let mut installer = installer(esp, MockSigner { fail: false }, 1, vec![link])
+                .with_ab_slots(Some(active));
+            install_links(&mut installer)?;
+            installer.install_slot_payload()?;
+            installer.collect_garbage()?;
+            let entry = installer.entries()[&version].clone();
+            let payload = installer
+                .esp_fs
+                .read(&esp_root.join("EFI/Linux").join(entry))?;
+            Ok((installer, payload))
+        };
+
+        // The first generation goes into the empty active slot.
+        let (installer, first) = install_slot(InMemoryEspFilesystem::new(), 1, Slot::A)?;
+        assert_eq!(installer.slot_to_activate(), None);
+        assert_eq!(installer.esp_fs.read(&slot_a)?, first);
+        assert!(!installer.esp_fs.exists(&slot_b));
+
+        // The next one goes into the other slot, and the first one is kept as the fallback.
+        let (installer, second) = install_slot(installer.esp_fs, 2, Slot::A)?;
+        assert_eq!(installer.slot_to_activate(), Some(Slot::B));
+        assert_eq!(installer.esp_fs.read(&slot_a)?, first);
+        assert_eq!(installer.esp_fs.read(&slot_b)?, second);
+
+        // Installing again once slot B is active changes nothing.
+        let (installer, _) = install_slot(installer.esp_fs, 2, Slot::B)?;
+        assert_eq!(installer.slot_to_activate(), None);
+        assert_eq!(installer.esp_fs.read(&slot_a)?, first);
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+//! A cache of what `lzbt install` computed and signed before, e.g. in `/var/lib/lanzaboote/cache`.
+//!
+//! Files in the Nix store never change, so their hashes are cached by path. This way, the kernels
+//! and initrds that many generations share are only read once to install them. The signed stubs
+//! are cached as well, by their file name, which is input-addressed (see `stub_name`). If the stub
+//! of a generation is missing from the ESP, e.g. because the ESP was recreated or the generation
+//! was selected again, it is copied from the cache instead of being assembled and signed again.
+//!
+//! With BLAKE3 enabled, files are also recognized by their contents: the BLAKE3 hash of a file,
+//! which is computed on all cores, is mapped to its SHA256 hash. This way, files that are not in
+//! the Nix store or that have the same contents as a cached file under another path are not hashed
+//! with the much slower SHA256 again. The stub still verifies SHA256 hashes.
+//!
+//! The state is a JSON file in the cache directory next to the cached stubs. A cache that cannot
+//! be read is discarded, so it never stops an installation.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::mmap::MappedFile;
+use lanzaboote_tool::revocation::{format_hash, parse_hash, RevokedHash};
+use lanzaboote_tool::utils::file_hash;
+
+/// The version of the state file. A state file of another version is discarded.
+const STATE_VERSION: u64 = 1;
+
+const STATE_FILE: &str = "state.json";
+const STUB_DIRECTORY: &str = "stubs";
+
+/// A SHA256 hash of a file.
+pub type FileHash = RevokedHash;
+
+/// A BLAKE3 hash of the contents of a file.
+type Blake3Hash = [u8; 32];
+
+/// A signed stub in the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedStub {
+    /// The hash of the signed stub.
+    pub hash: FileHash,
+    /// The files in the Nix store that the stub refers to and where they are installed on the
+    /// ESP, relative to the ESP.
+    pub files: Vec<(PathBuf, PathBuf)>,
+}
+
+/// The hashes of files in the Nix store and the signed stubs of earlier installations.
+#[derive(Debug, Default)]
+pub struct InstallCache {
+    dir: PathBuf,
+    hashes: BTreeMap<PathBuf, (u64, FileHash)>,
+    stubs: BTreeMap<String, CachedStub>,
+    /// The SHA256 hashes of files by the BLAKE3 hash of their contents.
+    contents: BTreeMap<Blake3Hash, FileHash>,
+    /// The stubs that were used in this installation, which are kept when saving.
+    used_stubs: BTreeSet<String>,
+    /// The contents that were hashed in this installation, which are kept when saving.
+    used_contents: BTreeSet<Blake3Hash>,
+    /// Whether files are recognized by their BLAKE3 hash.
+    blake3: bool,
+}
+
+/// Whether `path` is in the Nix store, i.e. its contents never change.
+pub fn is_store_path(path: &Path) -> bool {
+    path.starts_with("/nix/store")
+}
+
+/// The BLAKE3 hash of `contents`, computed on all cores.
+fn blake3_hash(contents: &[u8]) -> Blake3Hash {
+    *blake3::Hasher::new()
+        .update_rayon(contents)
+        .finalize()
+        .as_bytes()
+}
+
+impl InstallCache {
+    /// Open the cache in `dir`, which does not have to exist.
+    pub fn open(dir: &Path) -> Self {
+        let mut cache = Self {
+            dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(state) => state,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return cache,
+            Err(e) => {
+                log::warn!("Failed to read the install cache in {dir:?}, ignoring it: {e}");
+                return cache;
+            }
+        };
+        if let Err(e) = serde_json::from_slice(&state)
+            .context("Invalid JSON")
+            .and_then(|state| cache.load(&state))
+        {
+            log::warn!("Discarding the install cache in {dir:?}: {e:#}");
+            cache.hashes.clear();
+            cache.stubs.clear();
+            cache.contents.clear();
+        }
+        cache
+    }
+
+    fn load(&mut self, state: &Value) -> Result<()> {
+        let version = state["version"].as_u64();
+        if version != Some(STATE_VERSION) {
+            anyhow::bail!("Unsupported version {version:?}, expected {STATE_VERSION}");
+        }
+        let hashes = state["hashes"].as_object().context("No hashes")?;
+        for (path, entry) in hashes {
+            let size = entry["size"].as_u64().context("Missing size")?;
+            let hash = parse_hash(entry["sha256"].as_str().context("Missing hash")?)?;
+            self.hashes.insert(PathBuf::from(path), (size, hash));
+        }
+        let stubs = state["stubs"].as_object().context("No stubs")?;
+        for (name, entry) in stubs {
+            let hash = parse_hash(entry["sha256"].as_str().context("Missing hash")?)?;
+            let files = entry["files"]
+                .as_array()
+                .context("Missing files")?
+                .iter()
+                .map(|file| {
+                    let path = |key: &str| file[key].as_str().map(PathBuf::from);
+                    path("source").zip(path("target")).context("Malformed file")
+                })
+                .collect::<Result<_>>()?;
+            self.stubs.insert(name.clone(), CachedStub { hash, files });
+        }
+        // Caches of versions without BLAKE3 support have no contents.
+        if let Some(contents) = state.get("contents") {
+            let contents = contents.as_object().context("Malformed contents")?;
+            for (blake3, sha256) in contents {
+                let sha256 = parse_hash(sha256.as_str().context("Missing hash")?)?;
+                self.contents.insert(parse_hash(blake3)?, sha256);
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let hashes = self
+            .hashes
+            .iter()
+            .map(|(path, (size, hash))| {
+                (
+                    path.to_string_lossy().into_owned(),
+                    json!({ "size": size, "sha256": format_hash(hash) }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let stubs = self
+            .stubs
+            .iter()
+            .map(|(name, stub)| {
+                let files = stub
+                    .files
+                    .iter()
+                    .map(|(source, target)| json!({ "source": source, "target": target }))
+                    .collect::<Vec<_>>();
+                (
+                    name.clone(),
+                    json!({ "sha256": format_hash(&stub.hash), "files": files }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let contents = self
+            .contents
+            .iter()
+            .map(|(blake3, sha256)| (format_hash(blake3), json!(format_hash(sha256))))
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "version": STATE_VERSION,
+            "hashes": hashes,
+            "stubs": stubs,
+            "contents": contents,
+        })
+    }
+
+    /// Recognize files by their BLAKE3 hash, computed on all cores.
+    pub fn with_blake3(mut self) -> Self {
+        self.blake3 = true;
+        self
+    }
+
+    /// The SHA256 hash of a file, from the cache if it is in the Nix store.
+    pub fn file_hash(&mut self, path: &Path) -> Result<FileHash> {
+        let size = fs::metadata(path)
+            .with_context(|| format!("Failed to read the metadata of {path:?}"))?
+            .len();
+        if let Some((cached_size, hash)) = self.hashes.get(path) {
+            if *cached_size == size {
+                return Ok(*hash);
+            }
+        }
+        let hash = if self.blake3 {
+            self.content_hash(path)?
+        } else {
+            file_hash(path)?.into()
+        };
+        if is_store_path(path) {
+            self.hashes.insert(path.to_path_buf(), (size, hash));
+        }
+        Ok(hash)
+    }
+
+    /// The SHA256 hash of a file, from the cache if its BLAKE3 hash is known.
+    fn content_hash(&mut self, path: &Path) -> Result<FileHash> {
+        let file = MappedFile::open(path)?;
+        let blake3 = blake3_hash(&file);
+        let hash = match self.contents.get(&blake3) {
+            Some(hash) => *hash,
+            None => Sha256::digest(&*file).into(),
+        };
+        self.contents.insert(blake3, hash);
+        self.used_contents.insert(blake3);
+        Ok(hash)
+    }
+
+    /// The signed stub with the file name `name` and what it refers to, if it is cached.
+    pub fn stub(&mut self, name: &str) -> Option<(Vec<u8>, CachedStub)> {
+        let cached = self.stubs.get(name)?.clone();
+        let contents = fs::read(self.dir.join(STUB_DIRECTORY).join(name)).ok()?;
+        if <FileHash>::from(Sha256::digest(&contents)) != cached.hash {
+            log::warn!("The cached stub {name} is damaged, assembling it again.");
+            self.stubs.remove(name);
+            return None;
+        }
+        self.used_stubs.insert(name.to_string());
+        Some((contents, cached))
+    }
+
+    /// Cache the signed stub with the file name `name`.
+    ///
+    /// `files` are the files in the Nix store that the stub refers to and their paths relative to
+    /// the ESP. Stubs that refer to anything else, e.g. an initrd with secrets, must not be cached.
+    pub fn insert_stub(&mut self, name: &str, contents: &[u8], files: Vec<(PathBuf, PathBuf)>) {
+        let stubs = self.dir.join(STUB_DIRECTORY);
+        if let Err(e) =
+            fs::create_dir_all(&stubs).and_then(|()| fs::write(stubs.join(name), contents))
+        {
+            log::warn!("Failed to cache the stub {name}: {e}");
+            return;
+        }
+        self.stubs.insert(
+            name.to_string(),
+            CachedStub {
+                hash: Sha256::digest(contents).into(),
+                files,
+            },
+        );
+        self.used_stubs.insert(name.to_string());
+    }
+
+    /// Keep the cached stub with the file name `name`, because it is still installed.
+    pub fn keep_stub(&mut self, name: &str) {
+        self.used_stubs.insert(name.to_string());
+    }
+
+    /// Write the state and remove what this installation did not use.
+    ///
+    /// The cache is only an optimization, so failing to save it is not an error.
+    pub fn save(&mut self) {
+        let unused = self
+            .stubs
+            .keys()
+            .filter(|name| !self.used_stubs.contains(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in unused {
+            self.stubs.remove(&name);
+            let _ = fs::remove_file(self.dir.join(STUB_DIRECTORY).join(&name));
+        }
+        self.hashes.retain(|path, _| path.exists());
+        self.contents
+            .retain(|blake3, _| self.used_contents.contains(blake3));
+
+        let state = serde_json::to_vec_pretty(&self.to_json()).expect("JSON is serializable");
+        let path = self.dir.join(STATE_FILE);
+        let tmp = path.with_extension("json.tmp");
+        if let Err(e) = fs::create_dir_all(&self.dir)
+            .and_then(|()| fs::write(&tmp, state))
+            .and_then(|()| fs::rename(&tmp, &path))
+        {
+            log::warn!("Failed to save the install cache to {path:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_open_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("kernel");
+        fs::write(&file, b"kernel")?;
+
+        let mut cache = InstallCache::open(dir.path());
+        let hash = cache.file_hash(&file)?;
+        assert_eq!(hash, <FileHash>::from(Sha256::digest(b"kernel")));
+        // Only files in the Nix store are cached.
+        assert!(cache.hashes.is_empty());
+        cache
+            .hashes
+            .insert(PathBuf::from("/nix/store/a-linux/bzImage"), (6, hash));
+        let files = vec![(
+            PathBuf::from("/nix/store/a-linux/bzImage"),
+            PathBuf::from("EFI/nixos/kernel.efi"),
+        )];
+        cache.insert_stub("nixos-generation-1-a.efi", b"stub 1", files.clone());
+        cache.insert_stub("nixos-generation-2-b.efi", b"stub 2", files.clone());
+        cache.used_stubs.remove("nixos-generation-2-b.efi");
+        cache.save();
+
+        let mut cache = InstallCache::open(dir.path());
+        assert_eq!(
+            cache.stub("nixos-generation-1-a.efi"),
+            Some((
+                b"stub 1".to_vec(),
+                CachedStub {
+                    hash: Sha256::digest(b"stub 1").into(),
+                    files
+                }
+            ))
+        );
+        // Unused stubs are removed.
+        assert_eq!(cache.stub("nixos-generation-2-b.efi"), None);
+        // The hashes of files that no longer exist are removed as well.
+        assert!(cache.hashes.is_empty());
+
+        fs::write(
+            dir.path()
+                .join(STUB_DIRECTORY)
+                .join("nixos-generation-1-a.efi"),
+            b"damaged",
+        )?;
+        assert_eq!(cache.stub("nixos-generation-1-a.efi"), None);
+
+        fs::write(dir.path().join(STATE_FILE), b"{")?;
+        assert!(InstallCache::open(dir.path()).stubs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn recognize_files_by_contents() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let initrd = dir.path().join("initrd");
+        fs::write(&initrd, b"initrd")?;
+        let sha256 = <FileHash>::from(Sha256::digest(b"initrd"));
+        let blake3 = blake3_hash(b"initrd");
+
+        let mut cache = InstallCache::open(dir.path()).with_blake3();
+        assert_eq!(cache.file_hash(&initrd)?, sha256);
+        assert_eq!(cache.contents.get(&blake3), Some(&sha256));
+        cache.save();
+
+        // A file with the same contents under another path is not hashed with SHA256 again.
+        let mut cache = InstallCache::open(dir.path()).with_blake3();
+        cache.contents.insert(blake3, [1; 32]);
+        let copy = dir.path().join("copy");
+        fs::copy(&initrd, &copy)?;
+        assert_eq!(cache.file_hash(&copy)?, [1; 32]);
+
+        // Contents that were not hashed are removed when saving.
+        cache.contents.insert([2; 32], [2; 32]);
+        cache.save();
+        let cache = InstallCache::open(dir.path());
+        assert_eq!(cache.contents.keys().collect::<Vec<_>>(), [&blake3]);
+        Ok(())
+    }
+}
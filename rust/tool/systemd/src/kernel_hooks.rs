@@ -0,0 +1,445 @@
+//! Install the kernels of other distributions from their kernel hooks, see `lzbt hook`.
+//!
+//! Fedora and Arch Linux run the plugins of systemd's `kernel-install` whenever a kernel is
+//! installed or removed, with the kernel image and the initrds that earlier plugins generated.
+//! Debian and Ubuntu run the scripts in `/etc/kernel/postinst.d` and `/etc/kernel/postrm.d`
+//! instead, and initramfs-tools runs the ones in `/etc/initramfs/post-update.d` whenever it
+//! regenerated an initrd. Both protocols are mapped to a [`KernelChange`], whose kernel is
+//! installed like by `lzbt standalone`, with the stub at `EFI/Linux/<token>-<version>.efi`.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::standalone::{self, StandaloneEntry};
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::signature::Signer;
+
+/// The os-release files of the system, relative to its root, in the order they are read.
+const OS_RELEASE_FILES: [&str; 2] = ["etc/os-release", "usr/lib/os-release"];
+
+/// The files with the kernel command line, relative to the root, in the order `kernel-install`
+/// reads them.
+const CMDLINE_FILES: [&str; 2] = ["etc/kernel/cmdline", "usr/lib/kernel/cmdline"];
+
+/// Parameters that the boot loader of the running kernel added to its command line.
+const BOOT_LOADER_PARAMS: [&str; 2] = ["BOOT_IMAGE=", "initrd="];
+
+/// The entry token of distributions whose os-release has no `ID`.
+const DEFAULT_ENTRY_TOKEN: &str = "linux";
+
+/// A kernel of a distribution package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kernel {
+    pub version: String,
+    pub image: PathBuf,
+    /// The initrds, which are concatenated in this order, e.g. with the microcode first.
+    pub initrds: Vec<PathBuf>,
+}
+
+/// A kernel that the package manager installed or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelChange {
+    Add(Kernel),
+    Remove { version: String },
+}
+
+/// The commands that `kernel-install` runs its plugins with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelInstallCommand {
+    Add,
+    Remove,
+}
+
+impl FromStr for KernelInstallCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "add" => Ok(Self::Add),
+            "remove" => Ok(Self::Remove),
+            _ => bail!("Unknown kernel-install command {s:?}, expected add or remove"),
+        }
+    }
+}
+
+/// The arguments of a `kernel-install` plugin:
+/// `COMMAND KERNEL_VERSION ENTRY_DIR [KERNEL_IMAGE [INITRD...]]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelInstallArgs {
+    pub command: KernelInstallCommand,
+    pub version: String,
+    pub entry_dir: PathBuf,
+    pub image: Option<PathBuf>,
+    pub initrds: Vec<PathBuf>,
+}
+
+impl KernelInstallArgs {
+    /// The change that `kernel-install` announces, with `$KERNEL_INSTALL_STAGING_AREA`.
+    ///
+    /// Without initrds in the arguments, the one that an earlier plugin generated is used. dracut
+    /// and mkinitcpio write it to the staging area with the `uki` layout, and to the entry
+    /// directory with the `bls` layout.
+    pub fn change(self, staging_area: Option<&Path>) -> Result<KernelChange> {
+        if self.command == KernelInstallCommand::Remove {
+            return Ok(KernelChange::Remove {
+                version: self.version,
+            });
+        }
+
+        let image = self
+            .image
+            .context("kernel-install did not pass a kernel image.")?;
+        let mut initrds = self.initrds;
+        if initrds.is_empty() {
+            initrds.extend(
+                staging_area
+                    .into_iter()
+                    .chain([self.entry_dir.as_path()])
+                    .map(|directory| directory.join("initrd"))
+                    .find(|initrd| initrd.is_file()),
+            );
+        }
+        Ok(KernelChange::Add(Kernel {
+            version: self.version,
+            image,
+            initrds,
+        }))
+    }
+}
+
+/// The hooks of Debian's kernel packages and initramfs-tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DpkgEvent {
+    /// `/etc/kernel/postinst.d`, after a kernel package was installed.
+    Postinst,
+    /// `/etc/kernel/postrm.d`, after a kernel package was removed.
+    Postrm,
+    /// `/etc/initramfs/post-update.d`, after an initrd was generated.
+    Initramfs,
+}
+
+impl FromStr for DpkgEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "postinst" => Ok(Self::Postinst),
+            "postrm" => Ok(Self::Postrm),
+            "initramfs" => Ok(Self::Initramfs),
+            _ => bail!("Unknown hook {s:?}, expected postinst, postrm or initramfs"),
+        }
+    }
+}
+
+/// The change that a Debian hook announces when it is run with `VERSION [PATH]`.
+///
+/// The kernel hooks get the path of the kernel image, the initramfs-tools hooks the path of the
+/// initrd. The other file is the one in `/boot` of `root`. Returns `None` if the initrd was not
+/// generated yet, because dpkg deferred it to its triggers. initramfs-tools then runs the hook
+/// again.
+pub fn dpkg_change(
+    root: &Path,
+    event: DpkgEvent,
+    version: String,
+    path: Option<PathBuf>,
+) -> Option<KernelChange> {
+    let boot = root.join("boot");
+    let image = boot.join(format!("vmlinuz-{version}"));
+    let initrd = boot.join(format!("initrd.img-{version}"));
+    let (image, initrd) = match event {
+        DpkgEvent::Postrm => return Some(KernelChange::Remove { version }),
+        DpkgEvent::Postinst => (path.unwrap_or(image), initrd),
+        DpkgEvent::Initramfs => (image, path.unwrap_or(initrd)),
+    };
+    initrd.is_file().then(|| {
+        KernelChange::Add(Kernel {
+            version,
+            image,
+            initrds: vec![initrd],
+        })
+    })
+}
+
+/// Read a file that may not exist.
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+/// The distribution whose kernels are installed, with its root file system at `root`.
+pub struct System {
+    pub root: PathBuf,
+    pub esp: PathBuf,
+    /// The prefix of the names of the stubs [default: the ID of the os-release].
+    pub entry_token: Option<String>,
+}
+
+impl System {
+    fn os_release(&self) -> Result<String> {
+        for file in OS_RELEASE_FILES {
+            if let Some(os_release) = read_optional(&self.root.join(file))? {
+                return Ok(os_release);
+            }
+        }
+        bail!("Failed to find the os-release of {:?}", self.root);
+    }
+
+    /// The kernel command line from `/etc/kernel/cmdline` or `/usr/lib/kernel/cmdline`, or else
+    /// the one of the running kernel without the parameters of its boot loader.
+    pub fn kernel_cmdline(&self) -> Result<Vec<String>> {
+        let split = |cmdline: &str| -> Vec<String> {
+            cmdline
+                .split_whitespace()
+                .filter(|param| !BOOT_LOADER_PARAMS.iter().any(|p| param.starts_with(p)))
+                .map(String::from)
+                .collect()
+        };
+        for file in CMDLINE_FILES {
+            if let Some(cmdline) = read_optional(&self.root.join(file))? {
+                return Ok(split(&cmdline));
+            }
+        }
+        let proc_cmdline = self.root.join("proc/cmdline");
+        let cmdline = fs::read_to_string(&proc_cmdline)
+            .with_context(|| format!("Failed to read {proc_cmdline:?}"))?;
+        Ok(split(&cmdline))
+    }
+
+    /// The path of the stub of the kernel `version`.
+    fn stub_path(&self, version: &str, os_release: &str) -> Result<PathBuf> {
+        let os_release: OsRelease = os_release.parse()?;
+        let token = self
+            .entry_token
+            .as_deref()
+            .or(os_release.0.get("ID").map(String::as_str))
+            .filter(|token| !token.is_empty())
+            .unwrap_or(DEFAULT_ENTRY_TOKEN);
+        for name in [token, version] {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                bail!("Invalid name {name:?} for the stub of a kernel");
+            }
+        }
+        Ok(self
+            .esp
+            .join("EFI/Linux")
+            .join(format!("{token}-{version}.efi")))
+    }
+
+    /// Install `kernel` with a signed stub.
+    ///
+    /// Several initrds are concatenated into one. Returns the paths of all installed files.
+    pub fn install_kernel<S: Signer>(
+        &self,
+        esp_fs: &mut impl EspFilesystem,
+        signer: &S,
+        lanzaboote_stub: &Path,
+        kernel: &Kernel,
+    ) -> Result<Vec<PathBuf>> {
+        let tempdir = tempfile::tempdir()?;
+        let initrd = standalone::combine_initrds(&kernel.initrds, tempdir.path())
+            .with_context(|| format!("Failed to prepare the initrd of {}.", kernel.version))?;
+
+        let os_release = self.os_release()?;
+        let entry = StandaloneEntry {
+            kernel: kernel.image.clone(),
+            initrd,
+            cmdline: self.kernel_cmdline()?,
+            output: self.stub_path(&kernel.version, &os_release)?,
+            os_release,
+        };
+        standalone::install(esp_fs, signer, lanzaboote_stub, &self.esp, &entry)
+    }
+
+    /// Remove the stub of the kernel `version` and its files.
+    ///
+    /// Returns the paths of all removed files.
+    pub fn remove_kernel(
+        &self,
+        esp_fs: &mut impl EspFilesystem,
+        version: &str,
+    ) -> Result<Vec<PathBuf>> {
+        let stub = self.stub_path(version, &self.os_release()?)?;
+        standalone::remove(esp_fs, &self.esp, &stub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+    use lanzaboote_tool::pe::StubParameters;
+
+    /// Stubs are represented by their parameters.
+    struct MockSigner;
+
+    impl Signer for MockSigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            Ok(fs::read(store_path)?)
+        }
+
+        fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(stub)?)
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(b"mock".to_vec())
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn map_kernel_install_arguments() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let staging_area = tmpdir.path().join("staging");
+        fs::create_dir(&staging_area)?;
+        let args = KernelInstallArgs {
+            command: KernelInstallCommand::Add,
+            version: "6.9.7-200.fc40.x86_64".into(),
+            entry_dir: tmpdir.path().join("entry"),
+            image: Some("/usr/lib/modules/6.9.7-200.fc40.x86_64/vmlinuz".into()),
+            initrds: Vec::new(),
+        };
+        let initrds = |change: KernelChange| match change {
+            KernelChange::Add(kernel) => kernel.initrds,
+            KernelChange::Remove { .. } => panic!("Expected a new kernel"),
+        };
+
+        assert!(initrds(args.clone().change(Some(&staging_area))?).is_empty());
+        fs::write(staging_area.join("initrd"), b"initrd")?;
+        assert_eq!(
+            initrds(args.clone().change(Some(&staging_area))?),
+            [staging_area.join("initrd")]
+        );
+
+        let explicit = KernelInstallArgs {
+            initrds: vec!["/boot/microcode.cpio".into(), "/boot/initrd".into()],
+            ..args.clone()
+        };
+        assert_eq!(
+            initrds(explicit.change(Some(&staging_area))?),
+            [PathBuf::from("/boot/microcode.cpio"), "/boot/initrd".into()]
+        );
+
+        let remove = KernelInstallArgs {
+            command: KernelInstallCommand::Remove,
+            image: None,
+            ..args
+        };
+        assert_eq!(
+            remove.change(None)?,
+            KernelChange::Remove {
+                version: "6.9.7-200.fc40.x86_64".into()
+            }
+        );
+        assert!("inspect".parse::<KernelInstallCommand>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn map_debian_hooks() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let version = "6.1.0-18-amd64";
+        let change = |event, path| dpkg_change(root.path(), event, version.into(), path);
+
+        // dpkg deferred generating the initrd.
+        assert_eq!(change(DpkgEvent::Postinst, None), None);
+
+        fs::create_dir(root.path().join("boot"))?;
+        fs::write(
+            root.path().join("boot/initrd.img-6.1.0-18-amd64"),
+            b"initrd",
+        )?;
+        let kernel = Kernel {
+            version: version.into(),
+            image: root.path().join("boot/vmlinuz-6.1.0-18-amd64"),
+            initrds: vec![root.path().join("boot/initrd.img-6.1.0-18-amd64")],
+        };
+        assert_eq!(
+            change(DpkgEvent::Postinst, None),
+            Some(KernelChange::Add(kernel.clone()))
+        );
+        assert_eq!(
+            change(
+                DpkgEvent::Initramfs,
+                Some(root.path().join("boot/initrd.img-6.1.0-18-amd64"))
+            ),
+            Some(KernelChange::Add(kernel))
+        );
+        assert_eq!(
+            change(
+                DpkgEvent::Postrm,
+                Some("/boot/vmlinuz-6.1.0-18-amd64".into())
+            ),
+            Some(KernelChange::Remove {
+                version: version.into()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_and_remove_kernels() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        fs::create_dir_all(root.path().join("etc"))?;
+        fs::create_dir_all(root.path().join("proc"))?;
+        fs::write(root.path().join("etc/os-release"), "ID=debian\n")?;
+        fs::write(
+            root.path().join("proc/cmdline"),
+            "BOOT_IMAGE=/vmlinuz-6.1.0-17-amd64 root=UUID=1234 ro quiet\n",
+        )?;
+        let file = |name: &str, contents: &[u8]| -> Result<PathBuf> {
+            let path = root.path().join(name);
+            fs::write(&path, contents)?;
+            Ok(path)
+        };
+        let system = System {
+            root: root.path().to_path_buf(),
+            esp: PathBuf::from("/boot/efi"),
+            entry_token: None,
+        };
+        let kernel = Kernel {
+            version: "6.1.0-18-amd64".into(),
+            image: file("vmlinuz", b"kernel")?,
+            initrds: vec![file("microcode", b"microcode")?, file("initrd", b"initrd")?],
+        };
+
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        let installed =
+            system.install_kernel(&mut esp_fs, &MockSigner, Path::new("/stub"), &kernel)?;
+        let stub = PathBuf::from("/boot/efi/EFI/Linux/debian-6.1.0-18-amd64.efi");
+        assert_eq!(installed[2], stub);
+        assert_eq!(esp_fs.read(&installed[1])?, b"microcodeinitrd");
+        let parameters: StubParameters = serde_json::from_slice(&esp_fs.read(&stub)?)?;
+        assert_eq!(parameters.kernel_cmdline, ["root=UUID=1234", "ro", "quiet"]);
+
+        fs::create_dir_all(root.path().join("etc/kernel"))?;
+        fs::write(
+            root.path().join("etc/kernel/cmdline"),
+            "root=LABEL=root rw\n",
+        )?;
+        assert_eq!(system.kernel_cmdline()?, ["root=LABEL=root", "rw"]);
+
+        assert_eq!(
+            system.remove_kernel(&mut esp_fs, "6.1.0-18-amd64")?,
+            std::slice::from_ref(&stub)
+        );
+        assert!(!esp_fs.exists(&stub));
+        assert!(system
+            .remove_kernel(&mut esp_fs, "6.1.0-18-amd64")?
+            .is_empty());
+        assert!(system.remove_kernel(&mut esp_fs, "../../vmlinuz").is_err());
+        Ok(())
+    }
+}
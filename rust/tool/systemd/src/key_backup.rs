@@ -0,0 +1,263 @@
+//! Encrypted backups of the Secure Boot keys.
+//!
+//! Losing the db key after enrolling it means that nothing can be signed for the machine anymore,
+//! so that the firmware has to be reset to setup mode or even reflashed. `lzbt backup-keys` writes
+//! the whole PKI bundle (e.g. `/var/lib/sbctl`) into a tar archive that is encrypted with age or
+//! GPG, either to recipients or with a passphrase. `lzbt restore-keys` decrypts such a backup and
+//! puts the bundle back in place. The keys are never written anywhere unencrypted except into the
+//! PKI bundle itself.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+/// The key that signs the boot files, which must be part of every backup.
+const DB_KEY: &str = "keys/db/db.key";
+
+/// How a backup is encrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encryption {
+    /// age, to the given recipients, e.g. `age1...` or SSH public keys.
+    Age(Vec<String>),
+    /// age, with a passphrase that age asks for.
+    AgePassphrase,
+    /// GPG, to the given recipients.
+    Gpg(Vec<String>),
+}
+
+impl Encryption {
+    /// The command that encrypts stdin to stdout.
+    fn command(&self) -> Command {
+        match self {
+            Self::Age(recipients) => {
+                let mut command = Command::new("age");
+                command.arg("--encrypt");
+                for recipient in recipients {
+                    command.arg("--recipient").arg(recipient);
+                }
+                command
+            }
+            Self::AgePassphrase => {
+                let mut command = Command::new("age");
+                command.args(["--encrypt", "--passphrase"]);
+                command
+            }
+            Self::Gpg(recipients) => {
+                let mut command = Command::new("gpg");
+                command.arg("--encrypt");
+                for recipient in recipients {
+                    command.arg("--recipient").arg(recipient);
+                }
+                command
+            }
+        }
+    }
+}
+
+/// The tool that a backup was encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Age,
+    Gpg,
+}
+
+impl Tool {
+    /// Recognize the tool by the start of the backup.
+    fn detect(header: &[u8]) -> Self {
+        if header.starts_with(b"age-encryption.org/")
+            || header.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+        {
+            Self::Age
+        } else {
+            Self::Gpg
+        }
+    }
+
+    /// The command that decrypts `backup` to stdout.
+    fn decrypt_command(self, backup: &Path, identity: Option<&Path>) -> Command {
+        let mut command = match self {
+            Self::Age => {
+                let mut command = Command::new("age");
+                command.arg("--decrypt");
+                if let Some(identity) = identity {
+                    command.arg("--identity").arg(identity);
+                }
+                command
+            }
+            Self::Gpg => {
+                let mut command = Command::new("gpg");
+                command.args(["--decrypt", "--quiet"]);
+                command
+            }
+        };
+        command.arg(backup);
+        command
+    }
+}
+
+/// Wait for a process of a pipeline and fail if it failed.
+fn wait(mut child: Child, what: &str) -> Result<()> {
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for {what}"))?;
+    if !status.success() {
+        bail!("{what} exited with {status}");
+    }
+    Ok(())
+}
+
+/// Write an encrypted backup of the PKI bundle at `pki_bundle` to `output`.
+pub fn backup(pki_bundle: &Path, encryption: &Encryption, output: &Path) -> Result<()> {
+    if !pki_bundle.join(DB_KEY).exists() {
+        bail!("{pki_bundle:?} is not a PKI bundle, {DB_KEY} is missing.");
+    }
+
+    // Only the owner may read the backup, even though it is encrypted.
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)
+        .with_context(|| format!("Failed to create {output:?}"))?;
+
+    let mut tar = Command::new("tar")
+        .args(["--create", "--file", "-", "--directory"])
+        .arg(pki_bundle)
+        .arg(".")
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run tar")?;
+    let archive = tar.stdout.take().context("tar has no stdout")?;
+    let encrypt = encryption
+        .command()
+        .stdin(archive)
+        .stdout(file)
+        .spawn()
+        .context("Failed to run the encryption tool. Most likely, it is not on PATH.");
+
+    let result = encrypt
+        .and_then(|encrypt| wait(encrypt, "Encrypting the backup"))
+        .and(wait(tar, "tar"));
+    if let Err(e) = result {
+        let _ = fs::remove_file(output);
+        return Err(e).with_context(|| format!("Failed to back up {pki_bundle:?}"));
+    }
+
+    log::info!("Backed up the keys in {pki_bundle:?} to {output:?}.");
+    Ok(())
+}
+
+/// Copy the decrypted bundle at `from` to `to`, readable only by the owner.
+fn install_bundle(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).with_context(|| format!("Failed to create {to:?}"))?;
+    fs::set_permissions(to, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(from).with_context(|| format!("Failed to read {from:?}"))? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            install_bundle(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("Failed to restore {target:?}"))?;
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    Ok(())
+}
+
+/// Restore the PKI bundle at `pki_bundle` from an encrypted `backup`.
+///
+/// `identity` is the age identity for backups that were encrypted to age recipients. Existing
+/// keys are only replaced with `force`.
+pub fn restore(
+    pki_bundle: &Path,
+    backup: &Path,
+    identity: Option<&Path>,
+    force: bool,
+) -> Result<()> {
+    if pki_bundle.join(DB_KEY).exists() && !force {
+        bail!("{pki_bundle:?} already contains keys. Pass --force to replace them.");
+    }
+
+    let mut header = [0; 64];
+    let length = File::open(backup)
+        .and_then(|mut file| file.read(&mut header))
+        .with_context(|| format!("Failed to read {backup:?}"))?;
+    let tool = Tool::detect(&header[..length]);
+
+    // The staging directory is next to the bundle, so that the keys stay on the same file system.
+    let parent = pki_bundle.parent().unwrap_or(Path::new("/"));
+    let staging = tempfile::tempdir_in(parent)
+        .with_context(|| format!("Failed to create a temporary directory in {parent:?}"))?;
+    fs::set_permissions(staging.path(), fs::Permissions::from_mode(0o700))?;
+
+    let mut decrypt = tool
+        .decrypt_command(backup, identity)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run the decryption tool. Most likely, it is not on PATH.")?;
+    let archive = decrypt
+        .stdout
+        .take()
+        .context("The decryption tool has no stdout")?;
+    let tar = Command::new("tar")
+        .args(["--extract", "--file", "-", "--directory"])
+        .arg(staging.path())
+        .stdin(archive)
+        .spawn()
+        .context("Failed to run tar")?;
+    wait(decrypt, "Decrypting the backup")
+        .and(wait(tar, "tar"))
+        .with_context(|| format!("Failed to decrypt {backup:?}"))?;
+
+    if !staging.path().join(DB_KEY).exists() {
+        bail!("{backup:?} does not contain a PKI bundle, {DB_KEY} is missing.");
+    }
+    install_bundle(staging.path(), pki_bundle)?;
+
+    log::info!("Restored the keys in {pki_bundle:?} from {backup:?}.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_tool() {
+        assert_eq!(
+            Tool::detect(b"age-encryption.org/v1\n-> X25519 abc"),
+            Tool::Age
+        );
+        assert_eq!(
+            Tool::detect(b"-----BEGIN AGE ENCRYPTED FILE-----\n"),
+            Tool::Age
+        );
+        assert_eq!(Tool::detect(&[0x85, 0x02, 0x0c]), Tool::Gpg);
+    }
+
+    #[test]
+    fn encryption_commands() {
+        let command = Encryption::Age(vec!["age1abc".into(), "age1def".into()]).command();
+        assert_eq!(command.get_program(), "age");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "--encrypt",
+                "--recipient",
+                "age1abc",
+                "--recipient",
+                "age1def"
+            ]
+        );
+        let command = Encryption::Gpg(vec!["alice@example.com".into()]).command();
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--encrypt", "--recipient", "alice@example.com"]
+        );
+    }
+}
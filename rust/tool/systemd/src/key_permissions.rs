@@ -0,0 +1,291 @@
+//! Check the permissions of the signing keys before they are used, see `lzbt fix-permissions`.
+//!
+//! Keys are often copied around with modes that let everyone read them, e.g. from a backup, with
+//! `scp` or into the Nix store. Whoever can read the db key can sign binaries that the firmware
+//! boots, and whoever can write a key or its directory can replace it. Therefore:
+//!
+//! - Private keys and PKCS#12 bundles that other users can read or write, and certificates and
+//!   directories of the PKI bundle that other users can write, refuse the command, unless
+//!   `--insecure-permissions` is given.
+//! - Private keys that their group can access, certificates and directories that their group can
+//!   write, and files that are owned by another user than the current one or root are only warned
+//!   about, since the group or owner is usually trusted, e.g. an administrators' group.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// What a file of the keys holds, which decides the permissions it may have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyFileKind {
+    PrivateKey,
+    Certificate,
+    Directory,
+}
+
+impl KeyFileKind {
+    /// The permission bits that are refused.
+    fn forbidden_mode(self) -> u32 {
+        match self {
+            Self::PrivateKey => 0o007,
+            Self::Certificate | Self::Directory => 0o002,
+        }
+    }
+
+    /// The permission bits that are only warned about.
+    fn discouraged_mode(self) -> u32 {
+        match self {
+            Self::PrivateKey => 0o070,
+            Self::Certificate | Self::Directory => 0o020,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A key file with unsafe permissions or owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionProblem {
+    pub path: PathBuf,
+    pub severity: Severity,
+    description: String,
+}
+
+impl fmt::Display for PermissionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}", self.path, self.description)
+    }
+}
+
+/// The files of the signing keys, either given explicitly or as a PKI bundle.
+#[derive(Debug, Default)]
+pub struct KeyFiles {
+    files: Vec<(PathBuf, KeyFileKind)>,
+}
+
+impl KeyFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_private_key(mut self, path: &Path) -> Self {
+        self.files.push((path.to_owned(), KeyFileKind::PrivateKey));
+        self
+    }
+
+    pub fn with_public_key(mut self, path: &Path) -> Self {
+        self.files.push((path.to_owned(), KeyFileKind::Certificate));
+        self
+    }
+
+    /// Add a PKCS#12 file, or a directory with the keys of sbctl, where `*.key` files are private
+    /// keys.
+    pub fn with_pki_bundle(mut self, path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            self.add_directory(path)?;
+        } else {
+            self.files.push((path.to_owned(), KeyFileKind::PrivateKey));
+        }
+        Ok(self)
+    }
+
+    fn add_directory(&mut self, directory: &Path) -> Result<()> {
+        self.files
+            .push((directory.to_owned(), KeyFileKind::Directory));
+        let mut entries = fs::read_dir(directory)
+            .with_context(|| format!("Failed to read {directory:?}"))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                self.add_directory(&path)?;
+            } else if path.extension().is_some_and(|extension| extension == "key") {
+                self.files.push((path, KeyFileKind::PrivateKey));
+            } else {
+                self.files.push((path, KeyFileKind::Certificate));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the files with unsafe permissions or owners. Files that do not exist are skipped, since
+    /// using them fails anyway.
+    pub fn audit(&self) -> Result<Vec<PermissionProblem>> {
+        let user = nix::unistd::geteuid().as_raw();
+        let mut problems = Vec::new();
+        for (path, kind) in &self.files {
+            let Some(metadata) = metadata(path)? else {
+                continue;
+            };
+            let mode = metadata.mode() & 0o777;
+            let mut problem = |severity, description: String| {
+                problems.push(PermissionProblem {
+                    path: path.clone(),
+                    severity,
+                    description,
+                })
+            };
+            let access = match kind {
+                KeyFileKind::PrivateKey => "accessible",
+                KeyFileKind::Certificate | KeyFileKind::Directory => "writable",
+            };
+            if mode & kind.forbidden_mode() != 0 {
+                problem(
+                    Severity::Error,
+                    format!("is {access} by all users (mode {mode:03o})"),
+                );
+            } else if mode & kind.discouraged_mode() != 0 {
+                problem(
+                    Severity::Warning,
+                    format!("is {access} by its group (mode {mode:03o})"),
+                );
+            }
+            if metadata.uid() != user && metadata.uid() != 0 {
+                problem(
+                    Severity::Warning,
+                    format!("is owned by user {} instead of {user}", metadata.uid()),
+                );
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Take the permissions that [`Self::audit`] complains about away and give files of other
+    /// users to the current user.
+    ///
+    /// Returns the paths of the changed files.
+    pub fn fix(&self) -> Result<Vec<PathBuf>> {
+        let user = nix::unistd::geteuid().as_raw();
+        let mut changed = Vec::new();
+        for (path, kind) in &self.files {
+            let Some(metadata) = metadata(path)? else {
+                continue;
+            };
+            let mut fixed = false;
+            if metadata.uid() != user && metadata.uid() != 0 {
+                chown(path, Some(user), None)
+                    .with_context(|| format!("Failed to change the owner of {path:?}"))?;
+                fixed = true;
+            }
+            let mode = metadata.mode() & 0o7777;
+            let safe_mode = mode & !(kind.forbidden_mode() | kind.discouraged_mode());
+            if safe_mode != mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(safe_mode))
+                    .with_context(|| format!("Failed to change the permissions of {path:?}"))?;
+                fixed = true;
+            }
+            if fixed {
+                changed.push(path.clone());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+fn metadata(path: &Path) -> Result<Option<fs::Metadata>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => {
+            Err(error).with_context(|| format!("Failed to read the metadata of {path:?}"))
+        }
+    }
+}
+
+/// Warn about all problems of `files` and refuse to continue on errors, unless
+/// `insecure_permissions` is set.
+pub fn check(files: &KeyFiles, insecure_permissions: bool) -> Result<()> {
+    let problems = files.audit()?;
+    let mut errors = 0;
+    for problem in &problems {
+        match problem.severity {
+            Severity::Warning => log::warn!("The key file {problem}."),
+            Severity::Error if insecure_permissions => {
+                log::warn!("The key file {problem}, ignored because of --insecure-permissions.")
+            }
+            Severity::Error => {
+                log::error!("The key file {problem}.");
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        bail!(
+            "Refusing to sign with keys that other users can access or replace. Restrict their \
+             permissions with `lzbt fix-permissions`, or pass --insecure-permissions."
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_mode(path: &Path, mode: u32) -> Result<()> {
+        Ok(fs::set_permissions(path, fs::Permissions::from_mode(mode))?)
+    }
+
+    #[test]
+    fn audit_and_fix_sbctl_bundle() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let bundle = tmpdir.path().join("sbctl");
+        let db = bundle.join("keys/db");
+        fs::create_dir_all(&db)?;
+        fs::write(db.join("db.key"), b"private")?;
+        fs::write(db.join("db.pem"), b"public")?;
+        set_mode(&bundle, 0o700)?;
+        set_mode(&bundle.join("keys"), 0o755)?;
+        set_mode(&db, 0o755)?;
+        set_mode(&db.join("db.key"), 0o600)?;
+        set_mode(&db.join("db.pem"), 0o644)?;
+
+        let files = KeyFiles::new().with_pki_bundle(&bundle)?;
+        assert_eq!(files.audit()?, []);
+
+        set_mode(&db.join("db.key"), 0o644)?;
+        set_mode(&db.join("db.pem"), 0o666)?;
+        let problems = files.audit()?;
+        assert_eq!(
+            problems.iter().map(|p| &p.path).collect::<Vec<_>>(),
+            [&db.join("db.key"), &db.join("db.pem")]
+        );
+        assert!(problems.iter().all(|p| p.severity == Severity::Error));
+        assert!(check(&files, false).is_err());
+        assert!(check(&files, true).is_ok());
+
+        assert_eq!(files.fix()?, [db.join("db.key"), db.join("db.pem")]);
+        assert_eq!(fs::metadata(db.join("db.key"))?.mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(db.join("db.pem"))?.mode() & 0o777, 0o644);
+        assert_eq!(files.audit()?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn warn_about_group_readable_private_keys() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let key = tmpdir.path().join("db.key");
+        fs::write(&key, b"private")?;
+        set_mode(&key, 0o640)?;
+
+        let files = KeyFiles::new()
+            .with_private_key(&key)
+            .with_public_key(&tmpdir.path().join("missing.pem"));
+        let problems = files.audit()?;
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert!(check(&files, false).is_ok());
+
+        files.fix()?;
+        assert_eq!(fs::metadata(&key)?.mode() & 0o777, 0o600);
+        Ok(())
+    }
+}
@@ -1,13 +1,53 @@
 mod architecture;
+mod attest;
+mod bitlocker;
+mod check;
+mod check_db;
 mod cli;
+mod cmdline;
+mod completions;
+mod daemon;
+mod dbus_service;
+mod dbx;
+mod deployments;
+mod diff;
+mod du;
 mod esp;
+mod expiry;
+mod export;
+mod firmware_update;
+mod hooks;
+mod inspect;
 mod install;
+mod install_cache;
+mod kernel_hooks;
+mod key_backup;
+mod key_permissions;
+mod manifest;
+mod metrics;
+mod migrate;
+mod namespace;
+mod oprom;
+mod other_os;
+mod pcrphase;
+mod polkit;
+mod preflight;
+mod progress;
+mod report;
+mod secure_boot;
+mod self_test;
+mod slots;
+mod staging;
+mod standalone;
+mod status;
 mod version;
+mod vm_test;
 
 use clap::Parser;
 
 use cli::Cli;
 
 fn main() {
+    completions::complete();
     Cli::parse().call(module_path!())
 }
@@ -0,0 +1,314 @@
+//! A signed manifest of the files that `lzbt install` manages on the ESP.
+//!
+//! The ESP is not encrypted and the stub only verifies the files that it loads itself, so
+//! modifications between installations, e.g. of `loader.conf`, of a stub that is not booted by
+//! default or of a kernel that no stub refers to anymore, would otherwise go unnoticed. After
+//! every installation, the paths and SHA256 hashes of all managed files are written to
+//! `EFI/nixos/manifest.json` together with a detached CMS signature in `manifest.json.p7s`, made
+//! with the signing key or a dedicated key.
+//!
+//! The next installation verifies the manifest first and removes modified files, so that they are
+//! installed again from the Nix store instead of being signed into the new manifest. A missing or
+//! invalid manifest is reported like a modification, and then no file on the ESP is signed into
+//! the new manifest unless it is installed again. `lzbt verify` reports them.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::revocation::format_hash;
+use lanzaboote_tool::signature::local::LocalKeyPair;
+
+use crate::namespace;
+
+/// The version of the manifest format.
+const MANIFEST_VERSION: u64 = 1;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const SIGNATURE_FILE: &str = "manifest.json.p7s";
+
+/// The managed files on the ESP and their SHA256 hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The hashes by path relative to the ESP, with `/` as separator.
+    pub files: BTreeMap<String, String>,
+}
+
+/// How a managed file differs from the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Modification {
+    Modified(PathBuf),
+    Missing(PathBuf),
+    /// The manifest itself is missing or invalid, so no file can be verified.
+    Unverified {
+        manifest: PathBuf,
+        reason: String,
+    },
+}
+
+impl Modification {
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Modified(path) => format!(
+                "{} was modified since the last installation.",
+                path.display()
+            ),
+            Self::Missing(path) => format!(
+                "{} was removed since the last installation.",
+                path.display()
+            ),
+            Self::Unverified { manifest, reason } => format!(
+                "{reason}, so the files managed by {} cannot be verified.",
+                manifest.display()
+            ),
+        }
+    }
+}
+
+impl Manifest {
+    /// Hash the `files` on the ESP, skipping directories and the manifest itself.
+    pub fn hash(esp_fs: &impl EspFilesystem, esp: &Path, files: &[PathBuf]) -> Result<Self> {
+        let mut manifest = Self::default();
+        for file in files {
+            let Ok(relative) = file.strip_prefix(esp) else {
+                continue;
+            };
+            let name = file.file_name().and_then(|name| name.to_str());
+            if esp_fs.is_dir(file)
+                || !esp_fs.exists(file)
+                || matches!(name, Some(MANIFEST_FILE | SIGNATURE_FILE))
+            {
+                continue;
+            }
+            let hash = esp_fs.hash(file)?;
+            manifest
+                .files
+                .insert(relative.to_string_lossy().into_owned(), format_hash(&hash));
+        }
+        Ok(manifest)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "version": MANIFEST_VERSION, "files": self.files })
+    }
+
+    fn from_json(manifest: &Value) -> Result<Self> {
+        let version = manifest["version"].as_u64();
+        if version != Some(MANIFEST_VERSION) {
+            bail!("Unsupported version {version:?}, expected {MANIFEST_VERSION}");
+        }
+        let files = manifest["files"]
+            .as_object()
+            .context("No files")?
+            .iter()
+            .map(|(path, hash)| {
+                let hash = hash.as_str().context("Malformed hash")?;
+                Ok((path.clone(), hash.to_string()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    /// Sign the manifest with `key` and write it to `directory`, e.g. `EFI/nixos`.
+    pub fn write(
+        &self,
+        esp_fs: &mut impl EspFilesystem,
+        directory: &Path,
+        key: &LocalKeyPair,
+    ) -> Result<()> {
+        let manifest = serde_json::to_vec_pretty(&self.to_json()).expect("JSON is serializable");
+        let signature = key
+            .sign_detached(&manifest)
+            .context("Failed to sign the manifest of the ESP")?;
+        // The signature is written last, so that a manifest without a matching signature is
+        // detected as such.
+        esp_fs.write(&directory.join(MANIFEST_FILE), &manifest)?;
+        esp_fs.write(&directory.join(SIGNATURE_FILE), &signature)?;
+        Ok(())
+    }
+
+    /// Read the manifest in `directory` and verify its signature with `key`.
+    ///
+    /// Returns `None` if there is no manifest, e.g. before the first installation with one.
+    pub fn read(
+        esp_fs: &impl EspFilesystem,
+        directory: &Path,
+        key: &LocalKeyPair,
+    ) -> Result<Option<Self>> {
+        let path = directory.join(MANIFEST_FILE);
+        if !esp_fs.exists(&path) {
+            return Ok(None);
+        }
+        let manifest = esp_fs.read(&path)?;
+        let signature = esp_fs
+            .read(&directory.join(SIGNATURE_FILE))
+            .with_context(|| format!("The manifest {path:?} is not signed"))?;
+        if !key.verify_detached(&manifest, &signature)? {
+            bail!("The signature of the manifest {path:?} does not verify, so it was modified or signed with another key");
+        }
+        let manifest = serde_json::from_slice(&manifest)
+            .context("Invalid JSON")
+            .and_then(|manifest| Self::from_json(&manifest))
+            .with_context(|| format!("Failed to parse the manifest {path:?}"))?;
+        Ok(Some(manifest))
+    }
+
+    /// The managed files on the ESP that differ from the manifest.
+    pub fn modifications(
+        &self,
+        esp_fs: &impl EspFilesystem,
+        esp: &Path,
+    ) -> Result<Vec<Modification>> {
+        let mut modifications = Vec::new();
+        for (relative, expected) in &self.files {
+            let path = esp.join(relative);
+            if !esp_fs.exists(&path) {
+                modifications.push(Modification::Missing(path));
+            } else if format_hash(&esp_fs.hash(&path)?) != *expected {
+                modifications.push(Modification::Modified(path));
+            }
+        }
+        Ok(modifications)
+    }
+}
+
+/// Read and verify the manifest in `directory`, and find the managed files that differ from it.
+///
+/// A missing or invalid manifest is reported as [`Modification::Unverified`].
+pub fn check(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    directory: &Path,
+    key: &LocalKeyPair,
+) -> Result<(Option<Manifest>, Vec<Modification>)> {
+    let unverified = |reason| Modification::Unverified {
+        manifest: directory.join(MANIFEST_FILE),
+        reason,
+    };
+    Ok(match Manifest::read(esp_fs, directory, key) {
+        Ok(Some(manifest)) => {
+            let modifications = manifest.modifications(esp_fs, esp)?;
+            (Some(manifest), modifications)
+        }
+        Ok(None) => (
+            None,
+            vec![unverified(format!(
+                "The manifest {:?} is missing",
+                directory.join(MANIFEST_FILE)
+            ))],
+        ),
+        Err(e) => (None, vec![unverified(format!("{e:#}"))]),
+    })
+}
+
+/// The directories of all manifests on the ESP, i.e. `EFI/nixos` and its namespaces.
+///
+/// If manifests are `required`, these directories are returned even if they have no manifest.
+/// Otherwise, only the directories with a manifest are.
+pub fn manifest_directories(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    required: bool,
+) -> Result<Vec<PathBuf>> {
+    let nixos = esp.join("EFI/nixos");
+    if !esp_fs.is_dir(&nixos) {
+        return Ok(Vec::new());
+    }
+    let mut directories = vec![nixos.clone()];
+    directories.extend(
+        esp_fs
+            .list(&nixos)?
+            .into_iter()
+            .filter(|path| esp_fs.is_dir(path)),
+    );
+    directories.retain(|directory| {
+        esp_fs.exists(&directory.join(MANIFEST_FILE))
+            || required && (*directory == nixos || namespace::is_namespaced(&nixos, directory))
+    });
+    Ok(directories)
+}
+
+/// Verify every manifest on the ESP and describe the problems.
+///
+/// If manifests are `required`, a missing manifest is a problem as well.
+pub fn verify(
+    esp_fs: &impl EspFilesystem,
+    esp: &Path,
+    key: &LocalKeyPair,
+    required: bool,
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for directory in manifest_directories(esp_fs, esp, required)? {
+        let (_, modifications) = check(esp_fs, esp, &directory, key)?;
+        problems.extend(modifications.iter().map(Modification::describe));
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    #[test]
+    fn find_modifications() -> Result<()> {
+        let esp = Path::new("/boot");
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        let files =
+            ["EFI/Linux/nixos-generation-1-a.efi", "loader/loader.conf"].map(|file| esp.join(file));
+        esp_fs.write(&files[0], b"stub")?;
+        esp_fs.write(&files[1], b"timeout 5")?;
+        esp_fs.write(&esp.join("EFI/nixos").join(MANIFEST_FILE), b"{}")?;
+
+        let mut roots = files.to_vec();
+        roots.extend([esp.join("EFI"), esp.join("EFI/nixos").join(MANIFEST_FILE)]);
+        let manifest = Manifest::hash(&esp_fs, esp, &roots)?;
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            ["EFI/Linux/nixos-generation-1-a.efi", "loader/loader.conf"]
+        );
+        assert_eq!(Manifest::from_json(&manifest.to_json())?, manifest);
+        assert!(manifest.modifications(&esp_fs, esp)?.is_empty());
+
+        esp_fs.write(&files[1], b"timeout 0\nauto-entries 1")?;
+        esp_fs.delete(&files[0])?;
+        assert_eq!(
+            manifest.modifications(&esp_fs, esp)?,
+            [
+                Modification::Missing(files[0].clone()),
+                Modification::Modified(files[1].clone())
+            ]
+        );
+        Ok(())
+    }
+    #[test]
+    fn report_missing_manifests_if_required() -> Result<()> {
+        let esp = Path::new("/boot");
+        let nixos = esp.join("EFI/nixos");
+        let namespace = nixos.join("0123456789abcdef0123456789abcdef");
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(&namespace.join("kernel.efi"), b"kernel")?;
+        esp_fs.write(&nixos.join("capsules/capsule.cap"), b"capsule")?;
+        let key = LocalKeyPair::public_only(Path::new("/nonexistent/db.pem"));
+
+        assert!(manifest_directories(&esp_fs, esp, false)?.is_empty());
+        assert!(verify(&esp_fs, esp, &key, false)?.is_empty());
+        assert_eq!(
+            manifest_directories(&esp_fs, esp, true)?,
+            [nixos.clone(), namespace.clone()]
+        );
+
+        let (manifest, modifications) = check(&esp_fs, esp, &nixos, &key)?;
+        assert_eq!(manifest, None);
+        assert!(matches!(
+            &modifications[..],
+            [Modification::Unverified { manifest, .. }] if *manifest == nixos.join(MANIFEST_FILE)
+        ));
+        assert_eq!(verify(&esp_fs, esp, &key, true)?.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Metrics about `lzbt install` for the textfile collector of the Prometheus node exporter.
+//!
+//! Every installation rewrites the whole file. Values that only a successful installation can
+//! provide, e.g. the number of installed generations, are kept from the previous file if the
+//! installation fails, so that alerts on them do not flap.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tempfile::NamedTempFile;
+
+use crate::cmdline::CmdlineChange;
+use crate::progress::InstallStatistics;
+
+/// The metrics in the order they are written, with their type and help text.
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "lanzaboote_install_success",
+        "gauge",
+        "Whether the last installation succeeded.",
+    ),
+    (
+        "lanzaboote_install_last_run_timestamp_seconds",
+        "gauge",
+        "When the last installation finished.",
+    ),
+    (
+        "lanzaboote_install_last_success_timestamp_seconds",
+        "gauge",
+        "When the last successful installation finished.",
+    ),
+    (
+        "lanzaboote_install_failures_total",
+        "counter",
+        "Failed installations.",
+    ),
+    (
+        "lanzaboote_generations_installed",
+        "gauge",
+        "Generations with a boot entry on the ESP.",
+    ),
+    (
+        "lanzaboote_signatures_created",
+        "gauge",
+        "Files signed by the last successful installation.",
+    ),
+    (
+        "lanzaboote_bytes_written",
+        "gauge",
+        "Bytes written to the ESP by the last successful installation.",
+    ),
+    (
+        "lanzaboote_esp_bytes_free",
+        "gauge",
+        "Bytes available on the ESP.",
+    ),
+    (
+        "lanzaboote_esp_bytes_total",
+        "gauge",
+        "Size of the ESP in bytes.",
+    ),
+];
+
+/// What a successful installation did.
+#[derive(Debug, Clone)]
+pub struct InstallOutcome {
+    /// The installed boot entries by generation, see [`crate::install::Installer::entries`].
+    pub entries: BTreeMap<u64, String>,
+    pub statistics: InstallStatistics,
+    /// The signed files on the ESP and their SHA256 hashes.
+    pub signed_files: Vec<(PathBuf, String)>,
+    /// The files and directories that garbage collection removed from the ESP.
+    pub removed_files: Vec<PathBuf>,
+    /// The entries that were signed again because their command line changed.
+    pub cmdline_changes: Vec<CmdlineChange>,
+}
+
+/// Update the metrics file after an installation, which failed if `outcome` is `None`.
+///
+/// The space on the ESP is only reported if `esp` is given, i.e. if the ESP is mounted.
+pub fn update(path: &Path, esp: Option<&Path>, outcome: Option<&InstallOutcome>) -> Result<()> {
+    let mut values = fs::read_to_string(path)
+        .map(|previous| parse(&previous))
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("The system clock is before 1970")?
+        .as_secs();
+    let esp_space = esp.map(esp_space).transpose()?;
+    apply(&mut values, now, esp_space, outcome);
+
+    let directory = path
+        .parent()
+        .with_context(|| format!("Invalid metrics file {path:?}"))?;
+    // The node exporter must never see a partially written file.
+    let mut file = NamedTempFile::new_in(directory)
+        .with_context(|| format!("Failed to create a temporary file in {directory:?}"))?;
+    file.write_all(render(&values).as_bytes())?;
+    file.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o644))?;
+    file.persist(path)
+        .with_context(|| format!("Failed to write the metrics to {path:?}"))?;
+    Ok(())
+}
+
+/// The available and the total bytes of the file system of the ESP.
+pub fn esp_space(esp: &Path) -> Result<(u64, u64)> {
+    let stat = nix::sys::statvfs::statvfs(esp)
+        .with_context(|| format!("Failed to determine the free space of {esp:?}"))?;
+    let fragment_size = stat.fragment_size() as u64;
+    Ok((
+        stat.blocks_available() as u64 * fragment_size,
+        stat.blocks() as u64 * fragment_size,
+    ))
+}
+
+fn apply(
+    values: &mut BTreeMap<String, f64>,
+    now: u64,
+    esp_space: Option<(u64, u64)>,
+    outcome: Option<&InstallOutcome>,
+) {
+    let mut set = |name: &str, value: f64| {
+        values.insert(name.to_string(), value);
+    };
+    set("lanzaboote_install_last_run_timestamp_seconds", now as f64);
+    if let Some((free, total)) = esp_space {
+        set("lanzaboote_esp_bytes_free", free as f64);
+        set("lanzaboote_esp_bytes_total", total as f64);
+    }
+
+    match outcome {
+        Some(outcome) => {
+            set("lanzaboote_install_success", 1.0);
+            set(
+                "lanzaboote_install_last_success_timestamp_seconds",
+                now as f64,
+            );
+            set(
+                "lanzaboote_generations_installed",
+                outcome.entries.len() as f64,
+            );
+            set(
+                "lanzaboote_signatures_created",
+                outcome.statistics.signatures_created as f64,
+            );
+            set(
+                "lanzaboote_bytes_written",
+                outcome.statistics.bytes_written as f64,
+            );
+        }
+        None => {
+            set("lanzaboote_install_success", 0.0);
+        }
+    }
+
+    let failures = values
+        .entry("lanzaboote_install_failures_total".to_string())
+        .or_default();
+    if outcome.is_none() {
+        *failures += 1.0;
+    }
+}
+
+/// Read the samples of a metrics file written by [`render`].
+fn parse(contents: &str) -> BTreeMap<String, f64> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, value) = line.split_once(' ')?;
+            Some((name.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+fn render(values: &BTreeMap<String, f64>) -> String {
+    let mut output = String::new();
+    for (name, kind, help) in METRICS {
+        if let Some(value) = values.get(*name) {
+            output.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_values_of_last_success_on_failure() {
+        let outcome = InstallOutcome {
+            entries: BTreeMap::from([
+                (1, "nixos-generation-1.efi".to_string()),
+                (2, "nixos-generation-2.efi".to_string()),
+                (3, "nixos-generation-3.efi".to_string()),
+            ]),
+            statistics: InstallStatistics {
+                signatures_created: 2,
+                ..Default::default()
+            },
+            signed_files: Vec::new(),
+            removed_files: Vec::new(),
+            cmdline_changes: Vec::new(),
+        };
+        let mut values = BTreeMap::new();
+        apply(&mut values, 1000, Some((512, 1024)), Some(&outcome));
+        let rendered = render(&values);
+        assert!(rendered.contains("# TYPE lanzaboote_install_failures_total counter\n"));
+        assert!(rendered.contains("\nlanzaboote_install_failures_total 0\n"));
+
+        let mut values = parse(&rendered);
+        apply(&mut values, 2000, None, None);
+        assert_eq!(values["lanzaboote_install_success"], 0.0);
+        assert_eq!(values["lanzaboote_install_failures_total"], 1.0);
+        assert_eq!(
+            values["lanzaboote_install_last_run_timestamp_seconds"],
+            2000.0
+        );
+        assert_eq!(
+            values["lanzaboote_install_last_success_timestamp_seconds"],
+            1000.0
+        );
+        assert_eq!(values["lanzaboote_generations_installed"], 3.0);
+        assert_eq!(values["lanzaboote_signatures_created"], 2.0);
+        assert_eq!(values["lanzaboote_esp_bytes_free"], 512.0);
+    }
+}
@@ -0,0 +1,380 @@
+//! Migration from an existing systemd-boot or GRUB installation.
+//!
+//! The files of the old boot loader are first copied to a backup directory on the ESP. Only after
+//! Lanzaboote is installed successfully, the files that would otherwise leave stale boot entries
+//! behind are removed. The backup is kept until a Lanzaboote entry has booted successfully, so
+//! that the old layout can be restored.
+//!
+//! The old boot loader is only detected by its files on the ESP. They are removed by the
+//! migration, so it happens once and later runs install normally.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::esp_fs::EspFilesystem;
+
+use crate::namespace::split_namespace;
+
+/// The directory on the ESP that holds the files of the old boot loader.
+pub const BACKUP_DIR: &str = "lanzaboote-migration";
+
+/// The boot loaders that can be migrated from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootloader {
+    SystemdBoot,
+    Grub,
+}
+
+impl Bootloader {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SystemdBoot => "systemd-boot",
+            Self::Grub => "GRUB",
+        }
+    }
+}
+
+/// The layout of an existing boot loader installation on the ESP.
+#[derive(Debug)]
+pub struct ExistingLayout {
+    pub bootloader: Bootloader,
+    /// The NixOS generations that have a boot entry.
+    pub generations: BTreeSet<u64>,
+    /// Files that only the old boot loader uses and that are removed after the migration.
+    pub stale: Vec<PathBuf>,
+    /// Files that Lanzaboote overwrites.
+    pub replaced: Vec<PathBuf>,
+}
+
+impl ExistingLayout {
+    /// Inspect the ESP for an existing boot loader installation.
+    pub fn detect(esp_fs: &impl EspFilesystem, esp: &Path) -> Result<Option<Self>> {
+        if let Some(layout) = Self::detect_systemd_boot(esp_fs, esp)? {
+            return Ok(Some(layout));
+        }
+        Self::detect_grub(esp_fs, esp)
+    }
+
+    fn detect_systemd_boot(esp_fs: &impl EspFilesystem, esp: &Path) -> Result<Option<Self>> {
+        let entries_dir = esp.join("loader/entries");
+        if !esp_fs.is_dir(&entries_dir) {
+            return Ok(None);
+        }
+
+        let mut generations = BTreeSet::new();
+        let mut stale = Vec::new();
+        for entry in esp_fs.list(&entries_dir)? {
+            if entry.extension().and_then(|e| e.to_str()) != Some("conf") {
+                continue;
+            }
+            let name = entry
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .context("Boot entry has an invalid file name")?;
+            match generation_from_entry_name(name) {
+                Some(generation) => generations.insert(generation),
+                // Entries of other distributions are left alone.
+                None => continue,
+            };
+
+            let contents = String::from_utf8(esp_fs.read(&entry)?)
+                .with_context(|| format!("Boot entry {entry:?} is not valid UTF-8"))?;
+            for line in contents.lines() {
+                if let Some(("linux" | "initrd" | "efi", path)) = line.trim().split_once(' ') {
+                    let path = esp.join(path.trim().trim_start_matches('/'));
+                    if esp_fs.exists(&path) && !stale.contains(&path) {
+                        stale.push(path);
+                    }
+                }
+            }
+            stale.push(entry);
+        }
+
+        if generations.is_empty() {
+            return Ok(None);
+        }
+
+        let replaced = existing_files(
+            esp_fs,
+            &[
+                esp.join("loader/loader.conf"),
+                esp.join("EFI/systemd"),
+                esp.join("EFI/BOOT"),
+            ],
+        )?;
+
+        Ok(Some(Self {
+            bootloader: Bootloader::SystemdBoot,
+            generations,
+            stale,
+            replaced,
+        }))
+    }
+
+    /// Detect the GRUB of NixOS by its binary in `EFI/NixOS*`, where NixOS installs it by default,
+    /// or its configuration on the ESP. The GRUBs of other distributions are left alone.
+    fn detect_grub(esp_fs: &impl EspFilesystem, esp: &Path) -> Result<Option<Self>> {
+        let grub_dir = esp.join("grub");
+        let config = grub_dir.join("grub.cfg");
+        let generations = if esp_fs.exists(&config) {
+            String::from_utf8(esp_fs.read(&config)?)
+                .context("GRUB configuration is not valid UTF-8")?
+                .lines()
+                .filter(|line| line.trim_start().starts_with("menuentry"))
+                .filter_map(generation_from_menuentry)
+                .collect::<BTreeSet<_>>()
+        } else {
+            BTreeSet::new()
+        };
+
+        // The GRUB binary lives in a directory named after the boot loader ID.
+        let mut stale = Vec::new();
+        for dir in esp_fs.list(&esp.join("EFI")).unwrap_or_default() {
+            let is_nixos = dir
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("NixOS"));
+            if esp_fs.is_dir(&dir) && is_nixos {
+                let has_grub = esp_fs.list(&dir)?.iter().any(|f| {
+                    f.file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| n.to_ascii_lowercase().starts_with("grub"))
+                });
+                if has_grub {
+                    stale.push(dir);
+                }
+            }
+        }
+        if stale.is_empty() && generations.is_empty() {
+            return Ok(None);
+        }
+        // NixOS copies the kernels to the ESP if it is mounted at /boot.
+        stale.extend(existing_files(esp_fs, &[grub_dir, esp.join("kernels")])?);
+
+        let replaced = existing_files(esp_fs, &[esp.join("EFI/BOOT")])?;
+
+        Ok(Some(Self {
+            bootloader: Bootloader::Grub,
+            generations,
+            stale,
+            replaced,
+        }))
+    }
+
+    /// Copy all files of the old boot loader to the backup directory.
+    pub fn backup(&self, esp_fs: &mut impl EspFilesystem, esp: &Path) -> Result<()> {
+        let backup_dir = esp.join(BACKUP_DIR);
+        if esp_fs.exists(&backup_dir) {
+            bail!("A backup of a previous migration exists at {backup_dir:?}. Finish or restore it first.");
+        }
+
+        for path in self.stale.iter().chain(&self.replaced) {
+            for file in files_below(esp_fs, path)? {
+                let relative = file.strip_prefix(esp)?;
+                let contents = esp_fs.read(&file)?;
+                esp_fs
+                    .write(&backup_dir.join(relative), &contents)
+                    .with_context(|| format!("Failed to back up {file:?}"))?;
+            }
+        }
+        esp_fs.sync(esp).context("Failed to sync ESP filesystem.")
+    }
+
+    /// Remove the files that only the old boot loader uses.
+    pub fn remove_stale(&self, esp_fs: &mut impl EspFilesystem) -> Result<()> {
+        for path in &self.stale {
+            if esp_fs.exists(path) {
+                esp_fs
+                    .delete(path)
+                    .with_context(|| format!("Failed to remove {path:?}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restore the files of the old boot loader from the backup directory.
+///
+/// The Lanzaboote entries are kept, so that both can be chosen in the boot menu.
+pub fn restore(esp_fs: &mut impl EspFilesystem, esp: &Path) -> Result<()> {
+    let backup_dir = esp.join(BACKUP_DIR);
+    if !esp_fs.exists(&backup_dir) {
+        bail!("There is no backup of a migration at {backup_dir:?}");
+    }
+
+    for file in files_below(esp_fs, &backup_dir)? {
+        let target = esp.join(file.strip_prefix(&backup_dir)?);
+        let contents = esp_fs.read(&file)?;
+        esp_fs
+            .write(&target, &contents)
+            .with_context(|| format!("Failed to restore {target:?}"))?;
+    }
+    esp_fs.sync(esp).context("Failed to sync ESP filesystem.")?;
+    esp_fs.delete(&backup_dir)
+}
+
+/// Remove the backup of the old boot loader.
+pub fn remove_backup(esp_fs: &mut impl EspFilesystem, esp: &Path) -> Result<()> {
+    let backup_dir = esp.join(BACKUP_DIR);
+    if esp_fs.exists(&backup_dir) {
+        esp_fs.delete(&backup_dir)?;
+    }
+    Ok(())
+}
+
+/// Whether systemd-boot booted a Lanzaboote entry, given its `LoaderEntrySelected` variable.
+pub fn is_lanzaboote_entry(entry: &str) -> bool {
+    let (_, name) = split_namespace(entry);
+    name.starts_with("nixos-") && name.ends_with(".efi")
+}
+
+/// Extract the generation from the name of a boot entry written by the NixOS systemd-boot
+/// installer, e.g. `nixos-generation-42` or `nixos-generation-42-specialisation-foo`, or by
+/// Lanzaboote, e.g. `nixos-generation-42-<hash>.efi`, optionally in a namespace.
+pub fn generation_from_entry_name(name: &str) -> Option<u64> {
+    let (_, name) = split_namespace(name);
+    let rest = name.strip_prefix("nixos-generation-")?;
+    let digits = rest.split('-').next()?;
+    digits.parse().ok()
+}
+
+/// Extract the generation from a GRUB menu entry written by NixOS, e.g.
+/// `menuentry "NixOS - Configuration 42 (2024-01-01 - 6.1.1)" ...`.
+fn generation_from_menuentry(line: &str) -> Option<u64> {
+    let (_, rest) = line.split_once("Configuration ")?;
+    let digits = rest
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    digits.parse().ok()
+}
+
+/// Filter the paths that exist.
+fn existing_files(esp_fs: &impl EspFilesystem, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    Ok(paths.iter().filter(|p| esp_fs.exists(p)).cloned().collect())
+}
+
+/// List all files below a path. A file itself is returned as is.
+fn files_below(esp_fs: &impl EspFilesystem, path: &Path) -> Result<Vec<PathBuf>> {
+    if !esp_fs.is_dir(path) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for child in esp_fs.list(path)? {
+        files.extend(files_below(esp_fs, &child)?);
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    const ESP: &str = "/esp";
+
+    fn systemd_boot_esp() -> Result<InMemoryEspFilesystem> {
+        let esp = Path::new(ESP);
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(&esp.join("loader/loader.conf"), b"timeout 5")?;
+        esp_fs.write(
+            &esp.join("loader/entries/nixos-generation-41.conf"),
+            b"title NixOS\nlinux /EFI/nixos/aaa-linux-6.1.1-bzImage.efi\ninitrd /EFI/nixos/bbb-initrd-linux-6.1.1-initrd.efi\n",
+        )?;
+        esp_fs.write(
+            &esp.join("loader/entries/nixos-generation-42-specialisation-foo.conf"),
+            b"title NixOS\nlinux /EFI/nixos/aaa-linux-6.1.1-bzImage.efi\n",
+        )?;
+        esp_fs.write(&esp.join("loader/entries/otheros.conf"), b"title Other")?;
+        esp_fs.write(
+            &esp.join("EFI/nixos/aaa-linux-6.1.1-bzImage.efi"),
+            b"kernel",
+        )?;
+        esp_fs.write(
+            &esp.join("EFI/nixos/bbb-initrd-linux-6.1.1-initrd.efi"),
+            b"initrd",
+        )?;
+        esp_fs.write(&esp.join("EFI/systemd/systemd-bootx64.efi"), b"sd-boot")?;
+        Ok(esp_fs)
+    }
+
+    #[test]
+    fn detect_systemd_boot() -> Result<()> {
+        let esp = Path::new(ESP);
+        let layout = ExistingLayout::detect(&systemd_boot_esp()?, esp)?
+            .context("No boot loader detected")?;
+
+        assert_eq!(layout.bootloader, Bootloader::SystemdBoot);
+        assert_eq!(layout.generations, BTreeSet::from([41, 42]));
+        assert_eq!(layout.stale.len(), 4);
+        assert!(!layout
+            .stale
+            .contains(&esp.join("loader/entries/otheros.conf")));
+        assert_eq!(
+            layout.replaced,
+            vec![esp.join("loader/loader.conf"), esp.join("EFI/systemd")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detect_grub() -> Result<()> {
+        let esp = Path::new(ESP);
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        esp_fs.write(&esp.join("EFI/ubuntu/grubx64.efi"), b"grub")?;
+        esp_fs.write(&esp.join("EFI/BOOT/BOOTX64.EFI"), b"grub")?;
+        // The GRUB of another distribution is not migrated.
+        assert!(ExistingLayout::detect(&esp_fs, esp)?.is_none());
+
+        esp_fs.write(&esp.join("EFI/NixOS-boot/grubx64.efi"), b"grub")?;
+        let layout = ExistingLayout::detect(&esp_fs, esp)?.context("No boot loader detected")?;
+        assert_eq!(layout.bootloader, Bootloader::Grub);
+        assert!(layout.generations.is_empty());
+        assert_eq!(layout.stale, vec![esp.join("EFI/NixOS-boot")]);
+        assert_eq!(layout.replaced, vec![esp.join("EFI/BOOT")]);
+
+        // Once the old boot loader is removed, it is not migrated again.
+        layout.remove_stale(&mut esp_fs)?;
+        assert!(ExistingLayout::detect(&esp_fs, esp)?.is_none());
+
+        let config = "menuentry \"NixOS\" --class nixos --unrestricted {\n}\n\
+                      submenu \"NixOS - All configurations\" --class submenu {\n\
+                      menuentry \"NixOS - Configuration 7 (2024-01-01 - 6.1.1)\" --class nixos {\n}\n\
+                      menuentry \"NixOS - Configuration 8 (2024-01-02 - 6.1.1)\" --class nixos {\n}\n}\n";
+        esp_fs.write(&esp.join("grub/grub.cfg"), config.as_bytes())?;
+        let layout = ExistingLayout::detect(&esp_fs, esp)?.context("No boot loader detected")?;
+        assert_eq!(layout.generations, BTreeSet::from([7, 8]));
+        assert_eq!(layout.stale, vec![esp.join("grub")]);
+        Ok(())
+    }
+
+    #[test]
+    fn backup_and_restore() -> Result<()> {
+        let esp = Path::new(ESP);
+        let original = systemd_boot_esp()?;
+        let mut esp_fs = original.clone();
+        let layout = ExistingLayout::detect(&esp_fs, esp)?.context("No boot loader detected")?;
+
+        layout.backup(&mut esp_fs, esp)?;
+        // A second migration must not overwrite the backup.
+        assert!(layout.backup(&mut esp_fs, esp).is_err());
+
+        layout.remove_stale(&mut esp_fs)?;
+        esp_fs.write(&esp.join("loader/loader.conf"), b"timeout 0")?;
+        assert!(!esp_fs.exists(&esp.join("loader/entries/nixos-generation-41.conf")));
+        assert!(esp_fs.exists(&esp.join("loader/entries/otheros.conf")));
+
+        restore(&mut esp_fs, esp)?;
+        assert_eq!(
+            esp_fs.files().collect::<Vec<_>>(),
+            original.files().collect::<Vec<_>>()
+        );
+        for file in original.files() {
+            assert_eq!(esp_fs.read(file)?, original.read(file)?);
+        }
+        Ok(())
+    }
+}
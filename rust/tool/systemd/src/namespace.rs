@@ -0,0 +1,99 @@
+//! Namespaces of NixOS installations that share an ESP.
+//!
+//! Like the entry token of `bootctl`, the machine ID can namespace the files of an installation,
+//! so that several installations on one ESP, e.g. for multi-boot, do not replace or collect each
+//! other's kernels and entries. The kernels and initrds of a namespaced installation live in
+//! `EFI/nixos/<machine-id>` and the names of its stubs in `EFI/Linux` start with `<machine-id>-`.
+//!
+//! Every installation only collects garbage in its own namespace. An installation without a
+//! namespace leaves the namespaced files alone, but owns everything else in `EFI/nixos`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// The file that holds the machine ID of the running system.
+pub const MACHINE_ID_FILE: &str = "/etc/machine-id";
+
+/// Whether `s` looks like a machine ID, i.e. 32 lowercase hex digits.
+pub fn is_machine_id(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Read the machine ID from `path`, e.g. [`MACHINE_ID_FILE`].
+pub fn read_machine_id(path: &Path) -> Result<String> {
+    let machine_id = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the machine ID from {path:?}"))?;
+    let machine_id = machine_id.trim();
+    if !is_machine_id(machine_id) {
+        bail!("{path:?} does not contain a valid machine ID");
+    }
+    Ok(machine_id.to_string())
+}
+
+/// Split the name of a file in `EFI/Linux` into its namespace, if any, and the rest of the name.
+pub fn split_namespace(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('-') {
+        Some((namespace, rest)) if is_machine_id(namespace) => (Some(namespace), rest),
+        _ => (None, name),
+    }
+}
+
+/// Whether `path` is inside a namespace directory directly below `dir`, e.g. `EFI/nixos`.
+pub fn is_namespaced(dir: &Path, path: &Path) -> bool {
+    path.strip_prefix(dir)
+        .ok()
+        .and_then(|relative| relative.components().next())
+        .and_then(|component| component.as_os_str().to_str())
+        .is_some_and(is_machine_id)
+}
+
+/// The namespace that the running system was installed with on `esp`, if any.
+///
+/// The system is namespaced if the ESP has a directory for its machine ID in `EFI/nixos`.
+pub fn current(esp: &Path) -> Option<String> {
+    read_machine_id(Path::new(MACHINE_ID_FILE))
+        .ok()
+        .filter(|machine_id| esp.join("EFI/nixos").join(machine_id).is_dir())
+}
+
+/// The directory of the kernels and initrds of the running system on `esp`.
+pub fn nixos_dir(esp: &Path) -> PathBuf {
+    let nixos = esp.join("EFI/nixos");
+    match current(esp) {
+        Some(machine_id) => nixos.join(machine_id),
+        None => nixos,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn split_namespaced_names() {
+        assert_eq!(
+            split_namespace(&format!("{MACHINE_ID}-nixos-generation-1-a.efi")),
+            (Some(MACHINE_ID), "nixos-generation-1-a.efi")
+        );
+        assert_eq!(
+            split_namespace("nixos-generation-1-a.efi"),
+            (None, "nixos-generation-1-a.efi")
+        );
+        assert_eq!(
+            split_namespace("0123456789ABCDEF0123456789ABCDEF-nixos.efi"),
+            (None, "0123456789ABCDEF0123456789ABCDEF-nixos.efi")
+        );
+
+        let nixos = Path::new("/boot/EFI/nixos");
+        assert!(is_namespaced(
+            nixos,
+            &nixos.join(MACHINE_ID).join("kernel.efi")
+        ));
+        assert!(!is_namespaced(nixos, &nixos.join("kernel.efi")));
+        assert!(!is_namespaced(nixos, nixos));
+    }
+}
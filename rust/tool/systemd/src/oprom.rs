@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// The exit code of `lzbt check-oprom` if devices have option ROMs that need Microsoft's UEFI CA.
+pub const EXIT_OPTION_ROMS_FOUND: i32 = 2;
+
+/// Where the kernel lists PCI devices.
+pub const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// The signature at the start of every image in an expansion ROM.
+const ROM_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// The offset of the pointer to the PCI data structure in a ROM image.
+const PCI_DATA_POINTER_OFFSET: usize = 0x18;
+/// The signature of the PCI data structure.
+const PCI_DATA_SIGNATURE: &[u8] = b"PCIR";
+/// The offset of the image length in 512 byte units in the PCI data structure.
+const IMAGE_LENGTH_OFFSET: usize = 0x10;
+/// The offset of the code type in the PCI data structure.
+const CODE_TYPE_OFFSET: usize = 0x14;
+/// The offset of the indicator, whose highest bit marks the last image, in the PCI data structure.
+const INDICATOR_OFFSET: usize = 0x15;
+/// The code type of UEFI drivers, as opposed to legacy BIOS code.
+const CODE_TYPE_EFI: u8 = 0x03;
+
+/// A PCI device with an option ROM that contains UEFI drivers.
+///
+/// With Secure Boot enabled, the firmware only loads the drivers if they are signed by a key in
+/// db. They are usually signed with Microsoft's UEFI CA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionRomDevice {
+    /// The PCI address, e.g. `0000:01:00.0`.
+    pub address: String,
+    /// The PCI class code, e.g. `0x030000` for a VGA compatible controller.
+    pub class: u32,
+    pub vendor: String,
+    pub device: String,
+}
+
+impl OptionRomDevice {
+    /// A human-readable description of the kind of device.
+    pub fn kind(&self) -> &'static str {
+        match self.class >> 16 {
+            0x01 => "storage controller",
+            0x02 => "network controller",
+            0x03 => "display controller",
+            _ => "device",
+        }
+    }
+}
+
+/// Count the UEFI driver images in a PCI expansion ROM.
+///
+/// A ROM may contain several images, e.g. legacy BIOS code and a UEFI driver.
+pub fn efi_image_count(rom: &[u8]) -> usize {
+    let mut count = 0;
+    let mut offset = 0;
+    while let Some(image) = rom.get(offset..) {
+        if !image.starts_with(&ROM_SIGNATURE) {
+            break;
+        }
+        let Some(pci_data) = read_u16(image, PCI_DATA_POINTER_OFFSET)
+            .and_then(|pointer| image.get(usize::from(pointer)..))
+            .filter(|pci_data| pci_data.starts_with(PCI_DATA_SIGNATURE))
+        else {
+            break;
+        };
+        if pci_data.get(CODE_TYPE_OFFSET) == Some(&CODE_TYPE_EFI) {
+            count += 1;
+        }
+
+        let length = read_u16(pci_data, IMAGE_LENGTH_OFFSET).map_or(0, usize::from) * 512;
+        let last = pci_data
+            .get(INDICATOR_OFFSET)
+            .is_none_or(|indicator| indicator & 0x80 != 0);
+        if last || length == 0 {
+            break;
+        }
+        offset += length;
+    }
+    count
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Read the option ROM of a PCI device from sysfs.
+///
+/// The ROM has to be enabled for reading and is disabled again afterwards. This requires root.
+pub fn read_rom(device: &Path) -> Result<Vec<u8>> {
+    let rom = device.join("rom");
+    fs::write(&rom, "1").with_context(|| format!("Failed to enable {rom:?}"))?;
+    let contents = fs::read(&rom).with_context(|| format!("Failed to read {rom:?}"));
+    fs::write(&rom, "0").with_context(|| format!("Failed to disable {rom:?}"))?;
+    contents
+}
+
+/// Find the PCI devices whose option ROMs contain UEFI drivers.
+///
+/// Devices whose ROM cannot be read, e.g. without root, are skipped with a warning.
+pub fn scan(
+    devices: &Path,
+    read_rom: impl Fn(&Path) -> Result<Vec<u8>>,
+) -> Result<Vec<OptionRomDevice>> {
+    let mut paths = fs::read_dir(devices)
+        .with_context(|| format!("Failed to list the PCI devices in {devices:?}"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    paths.sort();
+
+    let mut found = Vec::new();
+    for path in paths {
+        if !path.join("rom").exists() {
+            continue;
+        }
+        let rom = match read_rom(&path) {
+            Ok(rom) => rom,
+            Err(e) => {
+                log::warn!("Skipping the option ROM of {path:?}: {e:#}");
+                continue;
+            }
+        };
+        if efi_image_count(&rom) == 0 {
+            continue;
+        }
+
+        let attribute = |name| {
+            fs::read_to_string(path.join(name))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        found.push(OptionRomDevice {
+            address: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            class: u32::from_str_radix(attribute("class").trim_start_matches("0x"), 16)
+                .unwrap_or_default(),
+            vendor: attribute("vendor"),
+            device: attribute("device"),
+        });
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a ROM image of the given code type, 512 bytes long.
+    fn image(code_type: u8, last: bool) -> Vec<u8> {
+        let mut image = vec![0; 512];
+        image[..2].copy_from_slice(&ROM_SIGNATURE);
+        image[PCI_DATA_POINTER_OFFSET..PCI_DATA_POINTER_OFFSET + 2]
+            .copy_from_slice(&0x40u16.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(PCI_DATA_SIGNATURE);
+        image[0x40 + IMAGE_LENGTH_OFFSET] = 1;
+        image[0x40 + CODE_TYPE_OFFSET] = code_type;
+        image[0x40 + INDICATOR_OFFSET] = if last { 0x80 } else { 0 };
+        image
+    }
+
+    #[test]
+    fn count_efi_images() {
+        let legacy_and_efi = [image(0, false), image(CODE_TYPE_EFI, true)].concat();
+        assert_eq!(efi_image_count(&legacy_and_efi), 1);
+        assert_eq!(efi_image_count(&image(0, true)), 0);
+        // Images after the last one are ignored.
+        let after_last = [image(0, true), image(CODE_TYPE_EFI, true)].concat();
+        assert_eq!(efi_image_count(&after_last), 0);
+        assert_eq!(efi_image_count(b"not a rom"), 0);
+    }
+
+    #[test]
+    fn scan_pci_devices() -> Result<()> {
+        let sysfs = tempfile::tempdir()?;
+        for (address, class, rom) in [
+            ("0000:00:02.0", "0x030000", None),
+            ("0000:01:00.0", "0x030000", Some(image(CODE_TYPE_EFI, true))),
+            ("0000:02:00.0", "0x020000", Some(image(0, true))),
+        ] {
+            let device = sysfs.path().join(address);
+            fs::create_dir(&device)?;
+            fs::write(device.join("class"), format!("{class}\n"))?;
+            fs::write(device.join("vendor"), "0x10de\n")?;
+            fs::write(device.join("device"), "0x2684\n")?;
+            if let Some(rom) = rom {
+                fs::write(device.join("rom"), rom)?;
+            }
+        }
+
+        let found = scan(sysfs.path(), |device| Ok(fs::read(device.join("rom"))?))?;
+        assert_eq!(
+            found,
+            [OptionRomDevice {
+                address: "0000:01:00.0".to_string(),
+                class: 0x030000,
+                vendor: "0x10de".to_string(),
+                device: "0x2684".to_string(),
+            }]
+        );
+        assert_eq!(found[0].kind(), "display controller");
+        Ok(())
+    }
+}
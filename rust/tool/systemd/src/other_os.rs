@@ -0,0 +1,220 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::esp::SystemdEspPaths;
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp_fs::EspFilesystem;
+
+/// The prefix of the loader entries that are generated for other operating systems.
+pub const ENTRY_PREFIX: &str = "lanzaboote-other-";
+
+/// Directories of `EFI` that do not belong to another operating system.
+const OWN_DIRECTORIES: &[&str] = &["nixos", "linux", "boot", "systemd", "microsoft"];
+
+/// How boot entries for other operating systems on the ESP are provided.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OtherOsMode {
+    /// Leave other operating systems alone. systemd-boot still detects Windows by itself.
+    #[default]
+    Ignore,
+    /// Add loader entries for other Linux installations, which systemd-boot does not detect.
+    Entries,
+    /// Add loader entries for all other operating systems, including Windows, that start copies
+    /// of their boot loaders signed with our key.
+    ///
+    /// This keeps them bootable if Microsoft's keys are not enrolled.
+    Sign,
+}
+
+impl OtherOsMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ignore => "ignore",
+            Self::Entries => "entries",
+            Self::Sign => "sign",
+        }
+    }
+}
+
+impl fmt::Display for OtherOsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for OtherOsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "entries" => Ok(Self::Entries),
+            "sign" => Ok(Self::Sign),
+            _ => bail!("Unknown other OS mode {s:?}, expected ignore, entries or sign"),
+        }
+    }
+}
+
+/// Another operating system on the ESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtherOs {
+    /// Identifies the operating system in the names of its files on the ESP.
+    pub id: String,
+    pub title: String,
+    /// Its boot loader on the ESP.
+    pub loader: PathBuf,
+    /// Whether systemd-boot shows an entry for it by itself.
+    pub auto_detected: bool,
+}
+
+impl OtherOs {
+    /// The name of its loader entry in `loader/entries`.
+    pub fn entry_name(&self) -> String {
+        format!("{ENTRY_PREFIX}{}.conf", self.id)
+    }
+}
+
+/// Find the boot loaders of other operating systems on the ESP.
+///
+/// Windows is found at its fixed location. Other Linux distributions are found by their shim or
+/// GRUB in their own directory in `EFI`, e.g. `EFI/ubuntu/shimx64.efi`.
+pub fn detect(
+    esp_fs: &impl EspFilesystem,
+    esp_paths: &SystemdEspPaths,
+    arch: Architecture,
+) -> Result<Vec<OtherOs>> {
+    let mut found = Vec::new();
+
+    let windows = esp_paths.efi.join("Microsoft/Boot/bootmgfw.efi");
+    if esp_fs.exists(&windows) {
+        found.push(OtherOs {
+            id: "windows".to_string(),
+            title: "Windows Boot Manager".to_string(),
+            loader: windows,
+            auto_detected: true,
+        });
+    }
+
+    if !esp_fs.is_dir(&esp_paths.efi) {
+        return Ok(found);
+    }
+    let candidates = [
+        format!("shim{}.efi", arch.efi_representation()),
+        format!("grub{}.efi", arch.efi_representation()),
+    ];
+    for directory in esp_fs.list(&esp_paths.efi)? {
+        let Some(name) = directory.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !esp_fs.is_dir(&directory) || OWN_DIRECTORIES.contains(&&*name.to_ascii_lowercase()) {
+            continue;
+        }
+
+        // FAT is case-insensitive, so the loaders may be named in any case.
+        let files = esp_fs.list(&directory)?;
+        let loader = candidates.iter().find_map(|candidate| {
+            files.iter().find(|file| {
+                file.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.eq_ignore_ascii_case(candidate))
+            })
+        });
+        if let Some(loader) = loader {
+            found.push(OtherOs {
+                id: entry_id(name),
+                title: name.to_string(),
+                loader: loader.clone(),
+                auto_detected: false,
+            });
+        }
+    }
+
+    Ok(found)
+}
+
+/// Turn a directory name into an identifier that is safe to use in file names.
+fn entry_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Render a systemd-boot loader entry that starts an EFI binary on the ESP.
+pub fn loader_entry(title: &str, esp: &Path, binary: &Path) -> Result<String> {
+    let Ok(relative) = binary.strip_prefix(esp) else {
+        bail!("{binary:?} is not on the ESP {esp:?}");
+    };
+    Ok(format!(
+        "title {title}\nefi /{}\n",
+        relative.to_string_lossy()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::esp::EspPaths;
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    #[test]
+    fn detect_windows_and_other_linux() -> Result<()> {
+        let esp_paths = SystemdEspPaths::new("/esp", Architecture::X86);
+        let mut esp = InMemoryEspFilesystem::new();
+        for file in [
+            "EFI/Microsoft/Boot/bootmgfw.efi",
+            "EFI/ubuntu/shimx64.efi",
+            "EFI/ubuntu/grubx64.efi",
+            "EFI/Fedora Linux/GRUBX64.EFI",
+            "EFI/nixos/kernel-6.1.1-abc.efi",
+            "EFI/BOOT/BOOTX64.EFI",
+            "EFI/arm/shimaa64.efi",
+        ] {
+            esp.write(&Path::new("/esp").join(file), b"MZ")?;
+        }
+
+        let found = detect(&esp, &esp_paths, Architecture::X86)?;
+        let summary = found
+            .iter()
+            .map(|os| {
+                (
+                    os.id.as_str(),
+                    os.loader.to_str().unwrap(),
+                    os.auto_detected,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("windows", "/esp/EFI/Microsoft/Boot/bootmgfw.efi", true),
+                ("fedora_linux", "/esp/EFI/Fedora Linux/GRUBX64.EFI", false),
+                ("ubuntu", "/esp/EFI/ubuntu/shimx64.efi", false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_loader_entry() -> Result<()> {
+        assert_eq!(
+            loader_entry(
+                "ubuntu",
+                Path::new("/esp"),
+                Path::new("/esp/EFI/ubuntu/shimx64.efi")
+            )?,
+            "title ubuntu\nefi /EFI/ubuntu/shimx64.efi\n"
+        );
+        assert!(loader_entry("x", Path::new("/esp"), Path::new("/boot/x.efi")).is_err());
+        Ok(())
+    }
+}
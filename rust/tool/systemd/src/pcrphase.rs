@@ -0,0 +1,129 @@
+//! Measurement of boot phases into PCR 11.
+//!
+//! The stub measures the sections of the booted entry into PCR 11 and records this in the
+//! `StubPcrKernelImage` variable. `lzbt pcrphase` then extends PCR 11 with a word for each phase of
+//! the boot, e.g. `enter-initrd` from a unit in the initrd and `leave-initrd` before switching
+//! root. Secrets sealed to PCR 11 after `enter-initrd`, e.g. of LUKS, are then only unsealable in
+//! the initrd.
+//!
+//! Only the SHA256 bank is extended, with a plain `TPM2_PCR_Extend` command on the kernel's
+//! resource manager. Do not run this alongside `systemd-pcrphase`, which measures the same words.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::efivars::{read_variable, LOADER_GUID};
+use lanzaboote_tool::pcr::{phase_digest, BOOT_PHASES, TPM_PCR_INDEX_KERNEL_IMAGE};
+
+/// The TPM resource manager of the kernel.
+pub const TPM_DEVICE: &str = "/dev/tpmrm0";
+
+/// The variable in which the stub records the PCR that it measured the entry into.
+const STUB_PCR_KERNEL_IMAGE: &str = "StubPcrKernelImage";
+
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_PCR_EXTEND: u32 = 0x0000_0182;
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_ALG_SHA256: u16 = 0x000b;
+/// The size of the header of commands and responses: tag, size and command or response code.
+const HEADER_SIZE: usize = 10;
+
+/// A `TPM2_PCR_Extend` command that extends the SHA256 bank of `pcr` with `digest`.
+///
+/// The PCR is authorized with an empty password, which is what PCRs 0-15 require.
+fn pcr_extend_command(pcr: u32, digest: &[u8]) -> Vec<u8> {
+    let mut authorization = Vec::new();
+    authorization.extend(TPM_RS_PW.to_be_bytes());
+    authorization.extend(0u16.to_be_bytes()); // nonce
+    authorization.push(0); // session attributes
+    authorization.extend(0u16.to_be_bytes()); // password
+
+    let mut body = Vec::new();
+    body.extend(pcr.to_be_bytes());
+    body.extend((authorization.len() as u32).to_be_bytes());
+    body.extend(authorization);
+    body.extend(1u32.to_be_bytes()); // number of digests
+    body.extend(TPM_ALG_SHA256.to_be_bytes());
+    body.extend(digest);
+
+    let mut command = Vec::new();
+    command.extend(TPM_ST_SESSIONS.to_be_bytes());
+    command.extend(((body.len() + HEADER_SIZE) as u32).to_be_bytes());
+    command.extend(TPM_CC_PCR_EXTEND.to_be_bytes());
+    command.extend(body);
+    command
+}
+
+/// Check the response code of a TPM response.
+fn check_response(response: &[u8]) -> Result<()> {
+    let Some(code) = response.get(6..HEADER_SIZE) else {
+        bail!("The response of the TPM is truncated");
+    };
+    let code = u32::from_be_bytes(code.try_into()?);
+    if code != 0 {
+        bail!("The TPM failed to extend the PCR with response code {code:#x}");
+    }
+    Ok(())
+}
+
+/// Whether the stub measured the booted entry into PCR 11.
+///
+/// Without this measurement, PCR 11 does not identify the entry, so phases are not measured
+/// either. This is the same condition as `ConditionSecurity=measured-uki` of systemd.
+fn stub_measured() -> Result<bool> {
+    Ok(read_variable(STUB_PCR_KERNEL_IMAGE, &LOADER_GUID)?.is_some())
+}
+
+/// Extend PCR 11 with a boot phase, e.g. `enter-initrd`.
+pub fn measure_phase(phase: &str, device: &Path) -> Result<()> {
+    if !BOOT_PHASES.contains(&phase) {
+        bail!(
+            "Unknown boot phase {phase:?}, expected one of {}",
+            BOOT_PHASES.join(", ")
+        );
+    }
+    if !stub_measured()? {
+        log::info!("The booted entry was not measured into PCR 11, not measuring {phase:?}.");
+        return Ok(());
+    }
+
+    let digest = phase_digest(phase);
+    let mut tpm = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .with_context(|| format!("Failed to open the TPM at {device:?}"))?;
+    tpm.write_all(&pcr_extend_command(TPM_PCR_INDEX_KERNEL_IMAGE, &digest))
+        .context("Failed to send the command to the TPM")?;
+    let mut response = [0; 4096];
+    let size = tpm
+        .read(&mut response)
+        .context("Failed to read the response of the TPM")?;
+    check_response(&response[..size])?;
+
+    log::info!("Measured the boot phase {phase:?} into PCR {TPM_PCR_INDEX_KERNEL_IMAGE}.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_pcr_extend() {
+        let command = pcr_extend_command(11, &[0xaa; 32]);
+        assert_eq!(command.len(), 65);
+        assert_eq!(command[..10], [0x80, 0x02, 0, 0, 0, 65, 0, 0, 0x01, 0x82]);
+        assert_eq!(command[10..14], [0, 0, 0, 11]);
+        assert_eq!(command[14..18], [0, 0, 0, 9]);
+        assert_eq!(command[27..33], [0, 0, 0, 1, 0x00, 0x0b]);
+        assert_eq!(command[33..], [0xaa; 32]);
+
+        assert!(check_response(&[0x80, 0x02, 0, 0, 0, 19, 0, 0, 0, 0]).is_ok());
+        assert!(check_response(&[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x84]).is_err());
+        assert!(check_response(&[0x80, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+/// Check whether a process is authorized for a polkit action with `pkcheck`.
+///
+/// The process is identified by its start time in addition to its PID, so that a process that
+/// reuses the PID of the caller cannot inherit its authorization. The user may be asked to
+/// authenticate, e.g. by the polkit agent of their desktop.
+pub fn check_authorization(pid: i32, uid: u32, action: &str) -> Result<bool> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))
+        .with_context(|| format!("Failed to read the status of the process {pid}"))?;
+    let start_time =
+        start_time(&stat).with_context(|| format!("Invalid status of the process {pid}"))?;
+
+    let status = Command::new("pkcheck")
+        .args([
+            "--action-id",
+            action,
+            "--allow-user-interaction",
+            "--process",
+        ])
+        .arg(format!("{pid},{start_time},{uid}"))
+        .status()
+        .context("Failed to run pkcheck. Most likely, polkit is not installed.")?;
+    match status.code() {
+        Some(0) => Ok(true),
+        // Not authorized, or the authentication was dismissed or failed.
+        Some(1..=3) => Ok(false),
+        _ => bail!("pkcheck failed with {status}"),
+    }
+}
+
+/// The start time of a process in clock ticks since boot, from the contents of `/proc/<pid>/stat`.
+fn start_time(stat: &str) -> Option<u64> {
+    // The command name in parentheses may contain spaces and parentheses itself, so the fields
+    // are counted from the last parenthesis, which is followed by the third field.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_start_time() {
+        let stat = "4242 (gnome (shell)) S 1 4242 4242 0 -1 4194560 1 2 3 4 5 6 7 8 20 0 12 0 987654 1000 200";
+        assert_eq!(start_time(stat), Some(987654));
+        assert_eq!(start_time("4242 (truncated) S 1"), None);
+    }
+}
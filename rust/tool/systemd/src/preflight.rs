@@ -0,0 +1,219 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use nix::sys::stat::{major, minor};
+use nix::sys::statfs::{statfs, MSDOS_SUPER_MAGIC};
+
+use lanzaboote_tool::fat::is_esp_partition;
+
+/// The exit code of lzbt if the system cannot boot Lanzaboote, e.g. because it booted in legacy
+/// BIOS mode or the ESP is missing.
+pub const EXIT_UNSUPPORTED_SYSTEM: i32 = 3;
+
+/// Only exists if the system booted in UEFI mode.
+const FIRMWARE_EFI_DIRECTORY: &str = "/sys/firmware/efi";
+
+/// Where the ESP is looked for if it is not given, in this order, like `bootctl` does.
+const ESP_CANDIDATES: [&str; 3] = ["/efi", "/boot", "/boot/efi"];
+
+/// A reason why installing Lanzaboote would not result in a bootable system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The system booted in legacy BIOS mode, e.g. with GRUB or syslinux.
+    NotUefiBooted,
+    /// The ESP does not exist or is not a directory.
+    EspMissing(PathBuf),
+    /// The ESP is a directory on the file system of its parent, e.g. `/boot` on the root file
+    /// system, and not the EFI system partition.
+    EspNotMounted(PathBuf),
+    /// The ESP is not a FAT file system.
+    EspNotFat(PathBuf),
+    /// The ESP is on a partition of a GPT whose type is not the one of an EFI system partition,
+    /// so the firmware does not boot from it.
+    NotEspPartition(PathBuf),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotUefiBooted => write!(
+                f,
+                "The system did not boot in UEFI mode ({FIRMWARE_EFI_DIRECTORY} does not exist). Lanzaboote only supports UEFI. Switch the firmware from legacy BIOS (CSM) to UEFI mode, or keep using GRUB."
+            ),
+            Self::EspMissing(esp) => write!(
+                f,
+                "The ESP {esp:?} does not exist. Mount the EFI system partition there."
+            ),
+            Self::EspNotMounted(esp) => write!(
+                f,
+                "{esp:?} is not a mount point, so it is not the EFI system partition. Mount the EFI system partition there."
+            ),
+            Self::EspNotFat(esp) => write!(
+                f,
+                "{esp:?} is not a FAT file system, so it is not the EFI system partition. Mount the EFI system partition there."
+            ),
+            Self::NotEspPartition(esp) => write!(
+                f,
+                "{esp:?} is not on a partition with the type of an EFI system partition (C12A7328-F81F-11D2-BA4B-00A0C93EC93B), so the firmware does not boot from it. Mount the EFI system partition there or change the partition type."
+            ),
+        }
+    }
+}
+
+/// The system cannot boot Lanzaboote. See [`EXIT_UNSUPPORTED_SYSTEM`].
+#[derive(Debug)]
+pub struct UnsupportedSystem(pub Vec<Problem>);
+
+impl fmt::Display for UnsupportedSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Refusing to install Lanzaboote:")?;
+        for problem in &self.0 {
+            writeln!(f, "  {problem}")?;
+        }
+        write!(
+            f,
+            "Use --force to install anyway, e.g. to prepare a disk for another machine."
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedSystem {}
+
+/// Check that the system booted in UEFI mode and that the ESP is mounted.
+///
+/// With `force`, the problems are only reported as warnings.
+pub fn check(esp: &Path, force: bool) -> Result<(), UnsupportedSystem> {
+    let problems = diagnose(Path::new(FIRMWARE_EFI_DIRECTORY), esp);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if !force {
+        return Err(UnsupportedSystem(problems));
+    }
+    for problem in problems {
+        log::warn!("{problem}");
+    }
+    Ok(())
+}
+
+/// Find the ESP like `bootctl --print-esp-path`, i.e. the first of `/efi`, `/boot` and
+/// `/boot/efi` that passes the checks of [`check`].
+pub fn discover_esp() -> Result<PathBuf> {
+    discover_esp_in(&ESP_CANDIDATES.map(Path::new))
+}
+
+fn discover_esp_in(candidates: &[&Path]) -> Result<PathBuf> {
+    for candidate in candidates {
+        match diagnose_esp(candidate) {
+            None => {
+                log::info!("Found the ESP at {candidate:?}.");
+                return Ok(candidate.to_path_buf());
+            }
+            Some(problem) => log::debug!("{problem}"),
+        }
+    }
+    bail!(
+        "Failed to find the ESP at {}. Mount the EFI system partition at one of them or pass \
+         its mount point.",
+        candidates
+            .iter()
+            .map(|candidate| candidate.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+fn diagnose(firmware_efi: &Path, esp: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if !firmware_efi.is_dir() {
+        problems.push(Problem::NotUefiBooted);
+    }
+    problems.extend(diagnose_esp(esp));
+    problems
+}
+
+/// Check that `esp` is a mounted FAT file system on an EFI system partition.
+fn diagnose_esp(esp: &Path) -> Option<Problem> {
+    let Some(metadata) = esp.metadata().ok().filter(|metadata| metadata.is_dir()) else {
+        return Some(Problem::EspMissing(esp.to_path_buf()));
+    };
+    // A mount point is on another device than its parent. Without a parent, the ESP would be the
+    // root file system, which cannot be FAT.
+    let parent = esp.canonicalize().ok().and_then(|esp| {
+        esp.parent()
+            .and_then(|parent| parent.metadata().ok())
+            .map(|parent| parent.dev())
+    });
+    if parent.is_none_or(|parent| parent == metadata.dev()) {
+        return Some(Problem::EspNotMounted(esp.to_path_buf()));
+    }
+    if statfs(esp).is_ok_and(|fs| fs.filesystem_type() != MSDOS_SUPER_MAGIC) {
+        return Some(Problem::EspNotFat(esp.to_path_buf()));
+    }
+    if partition_is_esp(metadata.dev()) == Some(false) {
+        return Some(Problem::NotEspPartition(esp.to_path_buf()));
+    }
+    None
+}
+
+/// Whether the block device `dev` is an EFI system partition.
+///
+/// This is `None` if it cannot be told, e.g. because the device is not a partition of a disk with
+/// a GPT or the disk cannot be read.
+fn partition_is_esp(dev: u64) -> Option<bool> {
+    let sysfs = fs::canonicalize(format!("/sys/dev/block/{}:{}", major(dev), minor(dev))).ok()?;
+    let number = fs::read_to_string(sysfs.join("partition"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let disk = Path::new("/dev").join(sysfs.parent()?.file_name()?);
+    match is_esp_partition(&disk, number) {
+        Ok(is_esp) => is_esp,
+        Err(e) => {
+            log::debug!("Failed to check the partition type of the ESP: {e:#}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnose_legacy_boot_and_missing_esp() -> anyhow::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let firmware_efi = tempdir.path().join("efi");
+        let esp = tempdir.path().join("boot");
+
+        assert_eq!(
+            diagnose(&firmware_efi, &esp),
+            [Problem::NotUefiBooted, Problem::EspMissing(esp.clone())]
+        );
+
+        std::fs::create_dir(&firmware_efi)?;
+        std::fs::create_dir(&esp)?;
+        assert_eq!(
+            diagnose(&firmware_efi, &esp),
+            [Problem::EspNotMounted(esp.clone())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_file_systems_other_than_fat() -> anyhow::Result<()> {
+        let proc = Path::new("/proc");
+        assert_eq!(
+            diagnose_esp(proc),
+            Some(Problem::EspNotFat(proc.to_path_buf()))
+        );
+
+        let tempdir = tempfile::tempdir()?;
+        assert!(discover_esp_in(&[proc, tempdir.path()]).is_err());
+        Ok(())
+    }
+}
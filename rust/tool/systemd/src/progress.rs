@@ -0,0 +1,207 @@
+//! Progress reporting and statistics for `lzbt install`.
+//!
+//! Progress is reported through the log, so that it respects `--quiet` and ends up in the
+//! journal when lzbt runs from `nixos-rebuild`, instead of relying on terminal escape codes.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+/// The width of the progress bar in characters.
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// What an installation has done so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InstallStatistics {
+    /// Boot entries, i.e. generations and their specialisations, that were (re-)installed.
+    pub entries_installed: usize,
+    /// Boot entries that were already installed.
+    pub entries_unchanged: usize,
+    /// Boot entries that were skipped because their kernel or initrd is revoked.
+    pub entries_skipped: usize,
+    pub files_written: usize,
+    pub bytes_written: u64,
+    pub signatures_created: usize,
+}
+
+impl InstallStatistics {
+    /// Count a file that was written to the ESP.
+    pub fn record_write(&mut self, bytes: usize) {
+        self.files_written += 1;
+        self.bytes_written += bytes as u64;
+    }
+
+    /// Count a file that was signed and written to the ESP.
+    pub fn record_signed_write(&mut self, bytes: usize) {
+        self.signatures_created += 1;
+        self.record_write(bytes);
+    }
+
+    /// What happened since `earlier`, a copy of these statistics.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            entries_installed: self.entries_installed - earlier.entries_installed,
+            entries_unchanged: self.entries_unchanged - earlier.entries_unchanged,
+            entries_skipped: self.entries_skipped - earlier.entries_skipped,
+            files_written: self.files_written - earlier.files_written,
+            bytes_written: self.bytes_written - earlier.bytes_written,
+            signatures_created: self.signatures_created - earlier.signatures_created,
+        }
+    }
+
+    /// The statistics as JSON, e.g. for hooks.
+    pub fn to_json(self) -> Value {
+        json!({
+            "entries_installed": self.entries_installed,
+            "entries_unchanged": self.entries_unchanged,
+            "entries_skipped": self.entries_skipped,
+            "files_written": self.files_written,
+            "bytes_written": self.bytes_written,
+            "signatures_created": self.signatures_created,
+        })
+    }
+
+    /// Describe the work done for a single generation, e.g. for a progress line.
+    pub fn describe(&self, elapsed: Duration) -> String {
+        if self.files_written == 0 {
+            return if self.entries_installed + self.entries_unchanged == 0 {
+                "skipped".to_string()
+            } else {
+                "already installed".to_string()
+            };
+        }
+        format!(
+            "{} {} ({}), {} {} in {}",
+            self.files_written,
+            plural(self.files_written, "file", "files"),
+            format_bytes(self.bytes_written),
+            self.signatures_created,
+            plural(self.signatures_created, "signature", "signatures"),
+            format_duration(elapsed),
+        )
+    }
+
+    /// Render the statistics as a table with one row per line.
+    pub fn summary(&self, elapsed: Duration) -> Vec<String> {
+        let rows = [
+            ("Entries installed", self.entries_installed.to_string()),
+            ("Entries unchanged", self.entries_unchanged.to_string()),
+            ("Entries skipped", self.entries_skipped.to_string()),
+            ("Files written", self.files_written.to_string()),
+            ("Bytes written", format_bytes(self.bytes_written)),
+            ("Signatures created", self.signatures_created.to_string()),
+            ("Time taken", format_duration(elapsed)),
+        ];
+        let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+        let value_width = rows.iter().map(|(_, value)| value.len()).max().unwrap_or(0);
+        rows.iter()
+            .map(|(label, value)| format!("  {label:<label_width$}  {value:>value_width$}"))
+            .collect()
+    }
+}
+
+/// Render a progress bar like `[#####---------------]` for `done` out of `total` steps.
+pub fn progress_bar(done: usize, total: usize) -> String {
+    let filled = (PROGRESS_BAR_WIDTH * done.min(total))
+        .checked_div(total)
+        .unwrap_or(PROGRESS_BAR_WIDTH);
+    format!(
+        "[{}{}]",
+        "#".repeat(filled),
+        "-".repeat(PROGRESS_BAR_WIDTH - filled)
+    )
+}
+
+/// Format a number of bytes with a binary unit, e.g. `12.3 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Format a duration with a precision that is useful for humans, e.g. `1.5 s` or `2 min 5 s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 60 {
+        format!("{:.1} s", duration.as_secs_f64())
+    } else {
+        format!("{} min {} s", seconds / 60, seconds % 60)
+    }
+}
+
+pub fn plural<'a>(count: usize, singular: &'a str, plural: &'a str) -> &'a str {
+    if count == 1 {
+        singular
+    } else {
+        plural
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_progress_bar() {
+        assert_eq!(progress_bar(0, 4), "[--------------------]");
+        assert_eq!(progress_bar(1, 4), "[#####---------------]");
+        assert_eq!(progress_bar(4, 4), "[####################]");
+        assert_eq!(progress_bar(0, 0), "[####################]");
+    }
+
+    #[test]
+    fn format_sizes_and_durations() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(64 * 1024 * 1024), "64.0 MiB");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.5 s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2 min 5 s");
+    }
+
+    #[test]
+    fn summarize_statistics() {
+        let mut statistics = InstallStatistics {
+            entries_installed: 2,
+            entries_unchanged: 10,
+            ..Default::default()
+        };
+        let before = statistics;
+        statistics.record_write(2048);
+        statistics.record_signed_write(1024);
+
+        let generation = statistics.since(&before);
+        assert_eq!(generation.files_written, 2);
+        assert_eq!(generation.entries_unchanged, 0);
+        assert_eq!(
+            generation.describe(Duration::from_secs(2)),
+            "2 files (3.0 KiB), 1 signature in 2.0 s"
+        );
+        assert_eq!(before.describe(Duration::from_secs(2)), "already installed");
+        let skipped = InstallStatistics {
+            entries_skipped: 1,
+            ..Default::default()
+        };
+        assert_eq!(skipped.describe(Duration::ZERO), "skipped");
+
+        assert_eq!(
+            statistics.summary(Duration::from_secs(3)),
+            [
+                "  Entries installed         2",
+                "  Entries unchanged        10",
+                "  Entries skipped           0",
+                "  Files written             2",
+                "  Bytes written       3.0 KiB",
+                "  Signatures created        1",
+                "  Time taken            3.0 s",
+            ]
+        );
+    }
+}
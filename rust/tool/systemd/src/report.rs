@@ -0,0 +1,148 @@
+//! Machine-readable results of `lzbt install`, `lzbt status`, `lzbt verify` and `lzbt check`.
+//!
+//! With `--json`, these commands print a single object to stdout, whether they succeed or not:
+//!
+//! ```json
+//! { "schemaVersion": 1, "command": "install", "success": true, "result": { ... }, "error": null }
+//! ```
+//!
+//! `result` depends on the command and may be `null` if the command failed early. `error` is the
+//! error message with its causes if the command failed. Fields are only ever added within a
+//! schema version. Removing or changing a field increments [`SCHEMA_VERSION`].
+
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use crate::cmdline::CmdlineChange;
+use crate::metrics::InstallOutcome;
+
+/// The version of the JSON schema.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// The report of `command` with its `result`, or the `error` that it failed with.
+pub fn report(command: &str, result: Option<Value>, error: Option<&anyhow::Error>) -> Value {
+    json!({
+        "schemaVersion": SCHEMA_VERSION,
+        "command": command,
+        "success": error.is_none(),
+        "result": result,
+        "error": error.map(|e| format!("{e:#}")),
+    })
+}
+
+/// The result of `lzbt install`: the installed entries and every file it signed or removed.
+pub fn install_result(outcome: &InstallOutcome) -> Value {
+    let signed = outcome.signed_files.iter().map(
+        |(path, hash)| json!({ "action": "sign", "path": path.to_string_lossy(), "sha256": hash }),
+    );
+    let removed = outcome
+        .removed_files
+        .iter()
+        .map(|path| json!({ "action": "remove", "path": path.to_string_lossy() }));
+    json!({
+        "entries": outcome.entries,
+        "statistics": {
+            "entriesInstalled": outcome.statistics.entries_installed,
+            "entriesUnchanged": outcome.statistics.entries_unchanged,
+            "entriesSkipped": outcome.statistics.entries_skipped,
+            "filesWritten": outcome.statistics.files_written,
+            "bytesWritten": outcome.statistics.bytes_written,
+            "signaturesCreated": outcome.statistics.signatures_created,
+        },
+        "actions": signed.chain(removed).collect::<Vec<_>>(),
+        "cmdlineChanges": outcome
+            .cmdline_changes
+            .iter()
+            .map(CmdlineChange::to_json)
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// The result of `lzbt verify`: every checked file and whether it is signed, and how the ESP was
+/// modified since the manifest was signed.
+pub fn verify_result(files: &[(PathBuf, bool)], manifest_problems: &[String]) -> Value {
+    json!({
+        "valid": files.iter().all(|(_, signed)| *signed) && manifest_problems.is_empty(),
+        "manifestProblems": manifest_problems,
+        "files": files
+            .iter()
+            .map(|(path, signed)| json!({ "path": path.to_string_lossy(), "signed": signed }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// The result of `lzbt check`: the damaged or modified files on the ESP.
+pub fn check_result(problems: &[String]) -> Value {
+    json!({
+        "intact": problems.is_empty(),
+        "problems": problems,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::progress::InstallStatistics;
+
+    #[test]
+    fn report_install() {
+        let outcome = InstallOutcome {
+            entries: BTreeMap::from([(2, "nixos-generation-2-abc.efi".to_string())]),
+            statistics: InstallStatistics {
+                signatures_created: 1,
+                ..Default::default()
+            },
+            signed_files: vec![(
+                PathBuf::from("/boot/EFI/Linux/nixos-generation-2-abc.efi"),
+                "00ff".to_string(),
+            )],
+            removed_files: vec![PathBuf::from("/boot/EFI/Linux/nixos-generation-1-def.efi")],
+            cmdline_changes: vec![CmdlineChange {
+                generation: 2,
+                specialisation: None,
+                previous: vec!["quiet".to_string()],
+                current: vec!["quiet".to_string(), "loglevel=7".to_string()],
+            }],
+        };
+        let report = report("install", Some(install_result(&outcome)), None);
+        assert_eq!(report["schemaVersion"], 1);
+        assert_eq!(report["success"], true);
+        assert_eq!(report["error"], Value::Null);
+        assert_eq!(
+            report["result"]["entries"]["2"],
+            "nixos-generation-2-abc.efi"
+        );
+        assert_eq!(report["result"]["statistics"]["signaturesCreated"], 1);
+        assert_eq!(
+            report["result"]["actions"],
+            json!([
+                {
+                    "action": "sign",
+                    "path": "/boot/EFI/Linux/nixos-generation-2-abc.efi",
+                    "sha256": "00ff"
+                },
+                { "action": "remove", "path": "/boot/EFI/Linux/nixos-generation-1-def.efi" }
+            ])
+        );
+        assert_eq!(
+            report["result"]["cmdlineChanges"][0]["added"],
+            json!(["loglevel=7"])
+        );
+    }
+
+    #[test]
+    fn report_error_with_causes() {
+        let error = anyhow::anyhow!("No space left on device").context("Failed to write stub");
+        let problems = ["Kernel of generation 1 was modified".to_string()];
+        let report = report("check", Some(check_result(&problems)), Some(&error));
+        assert_eq!(report["success"], false);
+        assert_eq!(
+            report["error"],
+            "Failed to write stub: No space left on device"
+        );
+        assert_eq!(report["result"]["intact"], false);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::efivars::{
+    read_variable, EFI_GLOBAL_VARIABLE_GUID, EFI_IMAGE_SECURITY_DATABASE_GUID,
+};
+use lanzaboote_tool::signature_list::{parse_signature_lists, EFI_CERT_X509_GUID};
+
+/// What happens if the firmware is not in the required Secure Boot state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    Warn,
+    Abort,
+}
+
+impl PolicyAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Abort => "abort",
+        }
+    }
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PolicyAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "abort" => Ok(Self::Abort),
+            _ => bail!("Unknown policy action {s:?}, expected warn or abort"),
+        }
+    }
+}
+
+/// The Secure Boot state of the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareState {
+    pub secure_boot: bool,
+    pub setup_mode: bool,
+    /// Whether `db` contains the certificate that the stubs are signed with.
+    pub key_enrolled: bool,
+}
+
+impl FirmwareState {
+    /// Read the state from the EFI variables. Without them, e.g. on a legacy BIOS system,
+    /// Secure Boot is disabled.
+    pub fn read(certificate: &[u8]) -> Result<Self> {
+        let flag = |name| -> Result<bool> {
+            Ok(read_variable(name, &EFI_GLOBAL_VARIABLE_GUID)?
+                .is_some_and(|value| value.first() == Some(&1)))
+        };
+        let key_enrolled = enrolled_certificates()?
+            .iter()
+            .any(|enrolled| enrolled == certificate);
+
+        Ok(Self {
+            secure_boot: flag("SecureBoot")?,
+            setup_mode: flag("SetupMode")?,
+            key_enrolled,
+        })
+    }
+}
+
+/// The DER encoded X.509 certificates in the firmware's db.
+pub fn enrolled_certificates() -> Result<Vec<Vec<u8>>> {
+    let Some(db) = read_variable("db", &EFI_IMAGE_SECURITY_DATABASE_GUID)? else {
+        return Ok(Vec::new());
+    };
+    Ok(parse_signature_lists(&db)
+        .context("Failed to parse the firmware's db")?
+        .into_iter()
+        .filter(|s| s.signature_type == EFI_CERT_X509_GUID)
+        .map(|s| s.data)
+        .collect())
+}
+
+/// The Secure Boot state that the user expects the firmware to be in when installing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SecureBootPolicy {
+    /// Secure Boot must be enabled and the signing key enrolled, i.e. the stubs must boot.
+    pub require_secure_boot: Option<PolicyAction>,
+    /// The firmware must be in setup mode, i.e. the signing key can still be enrolled.
+    pub require_setup_mode: Option<PolicyAction>,
+}
+
+impl SecureBootPolicy {
+    /// Check the firmware state against the policy.
+    ///
+    /// Even without any requirement, installing while Secure Boot is enforced with other keys,
+    /// e.g. only Microsoft's, is warned about, because the installed entries will not boot.
+    pub fn check(&self, state: &FirmwareState) -> Result<()> {
+        let violations = self.violations(state);
+        for (_, message) in violations.iter().filter(|(a, _)| *a == PolicyAction::Warn) {
+            log::warn!("{message}");
+        }
+
+        let errors = violations
+            .iter()
+            .filter(|(a, _)| *a == PolicyAction::Abort)
+            .map(|(_, message)| message.as_str())
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            bail!(
+                "The firmware is not in the required Secure Boot state:\n  {}",
+                errors.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    fn violations(&self, state: &FirmwareState) -> Vec<(PolicyAction, String)> {
+        let mut violations = Vec::new();
+        let not_enrolled = state.secure_boot && !state.setup_mode && !state.key_enrolled;
+
+        if let Some(action) = self.require_secure_boot {
+            if !state.secure_boot {
+                violations.push((action, "Secure Boot is disabled.".to_string()));
+            }
+        }
+        if not_enrolled {
+            violations.push((
+                self.require_secure_boot.unwrap_or(PolicyAction::Warn),
+                "Secure Boot is enabled, but the signing key is not enrolled in the firmware's db, e.g. because it only contains Microsoft's keys. The installed entries will not boot until the key is enrolled.".to_string(),
+            ));
+        }
+        if let Some(action) = self.require_setup_mode {
+            if !state.setup_mode {
+                violations.push((
+                    action,
+                    "The firmware is not in setup mode, so the signing key cannot be enrolled. Clear the Secure Boot keys in the firmware settings first.".to_string(),
+                ));
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENFORCED_WITHOUT_KEY: FirmwareState = FirmwareState {
+        secure_boot: true,
+        setup_mode: false,
+        key_enrolled: false,
+    };
+
+    fn actions(policy: SecureBootPolicy, state: FirmwareState) -> Vec<PolicyAction> {
+        policy
+            .violations(&state)
+            .into_iter()
+            .map(|(action, _)| action)
+            .collect()
+    }
+
+    #[test]
+    fn warn_about_missing_key_by_default() {
+        let policy = SecureBootPolicy::default();
+        assert_eq!(actions(policy, ENFORCED_WITHOUT_KEY), [PolicyAction::Warn]);
+        assert!(policy.check(&ENFORCED_WITHOUT_KEY).is_ok());
+
+        let enrolled = FirmwareState {
+            key_enrolled: true,
+            ..ENFORCED_WITHOUT_KEY
+        };
+        assert_eq!(actions(policy, enrolled), []);
+    }
+
+    #[test]
+    fn enforce_required_state() {
+        let policy = SecureBootPolicy {
+            require_secure_boot: Some(PolicyAction::Abort),
+            require_setup_mode: Some(PolicyAction::Warn),
+        };
+        assert_eq!(
+            actions(policy, ENFORCED_WITHOUT_KEY),
+            [PolicyAction::Abort, PolicyAction::Warn]
+        );
+        assert!(policy.check(&ENFORCED_WITHOUT_KEY).is_err());
+
+        let disabled_in_setup_mode = FirmwareState {
+            secure_boot: false,
+            setup_mode: true,
+            key_enrolled: false,
+        };
+        assert_eq!(
+            actions(policy, disabled_in_setup_mode),
+            [PolicyAction::Abort]
+        );
+        let setup_mode_only = SecureBootPolicy {
+            require_setup_mode: Some(PolicyAction::Abort),
+            ..Default::default()
+        };
+        assert!(setup_mode_only.check(&disabled_in_setup_mode).is_ok());
+    }
+}
@@ -0,0 +1,239 @@
+//! `lzbt self-test`: check that the stub, the signer and the PE tooling work together.
+//!
+//! A stub for a dummy kernel and initrd is assembled and signed in a temporary directory, which
+//! stands in for the ESP. Its signature is verified and the stub is parsed back like `lzbt inspect`
+//! does. Optionally, the stub is booted in QEMU. It cannot boot the dummy kernel, but getting as
+//! far as loading it shows that the firmware started the stub and that the stub verified the
+//! kernel and the initrd. The real ESP is never touched.
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::pe::StubParameters;
+use lanzaboote_tool::signature::Signer;
+
+use crate::inspect::Inspection;
+
+/// The command line embedded into the stub, which the parsed stub must contain.
+const CMDLINE: &str = "lanzaboote.self-test=1";
+
+/// The os-release embedded into the stub.
+const OS_RELEASE: &str = "ID=lanzaboote-self-test\nPRETTY_NAME=\"Lanzaboote self-test\"\n";
+
+/// How long the stub may take to boot in QEMU.
+const QEMU_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The step of the stub that fails for the dummy kernel, after the kernel and the initrd were
+/// verified.
+const QEMU_SUCCESS_MARKER: &str = "Loading the kernel";
+
+/// Lines of the stub that show that it failed before it got to the dummy kernel.
+const QEMU_FAILURE_MARKERS: [&str; 3] = ["Verifying the", "hash does not match", "Error code: P-"];
+
+/// A QEMU system emulator and the UEFI firmware it boots.
+#[derive(Debug, Clone)]
+pub struct Qemu {
+    pub binary: PathBuf,
+    pub firmware: PathBuf,
+}
+
+/// The outcome of a line that the stub printed in QEMU, if it decides the test.
+fn classify_qemu_line(line: &str) -> Option<Result<()>> {
+    if let Some(marker) = QEMU_FAILURE_MARKERS
+        .iter()
+        .find(|marker| line.contains(*marker))
+    {
+        return Some(Err(anyhow::anyhow!(
+            "The stub failed in QEMU ({marker:?}): {}",
+            line.trim()
+        )));
+    }
+    line.contains(QEMU_SUCCESS_MARKER).then_some(Ok(()))
+}
+
+fn print_ok(step: &str) {
+    println!("ok  {step}");
+}
+
+/// Run the self-test with the stub at `lanzaboote_stub`.
+pub fn run(
+    lanzaboote_stub: &Path,
+    signer: &impl Signer,
+    architecture: Architecture,
+    qemu: Option<&Qemu>,
+) -> Result<()> {
+    let tempdir = TempDir::new().context("Failed to create a temporary directory")?;
+    let esp = tempdir.path().join("esp");
+    let store = tempdir.path().join("store");
+    fs::create_dir_all(esp.join("EFI/nixos"))?;
+    fs::create_dir_all(esp.join("EFI/BOOT"))?;
+    fs::create_dir_all(&store)?;
+
+    let (kernel, initrd) = (store.join("kernel"), store.join("initrd"));
+    fs::write(&kernel, b"lanzaboote self-test kernel")?;
+    fs::write(&initrd, b"lanzaboote self-test initrd")?;
+    let (kernel_target, initrd_target) = (
+        esp.join("EFI/nixos/self-test-kernel.efi"),
+        esp.join("EFI/nixos/self-test-initrd.efi"),
+    );
+    fs::copy(&kernel, &kernel_target)?;
+    fs::copy(&initrd, &initrd_target)?;
+
+    let parameters = StubParameters::new(
+        lanzaboote_stub,
+        &kernel,
+        &initrd,
+        &kernel_target,
+        &initrd_target,
+        &esp,
+    )?
+    .with_cmdline(&[CMDLINE.to_string()])
+    .with_os_release_contents(OS_RELEASE.as_bytes())
+    // Hash mismatches are only fatal with Secure Boot, which QEMU usually boots without.
+    .with_simulate_secure_boot(true);
+    let stub = signer
+        .build_and_sign_stub(&parameters)
+        .context("Failed to assemble and sign the stub")?;
+    print_ok("Assembled and signed a stub");
+
+    if !signer
+        .verify(&stub)
+        .context("Failed to verify the signature of the stub")?
+    {
+        bail!("The signature of the stub does not verify with the public key.");
+    }
+    print_ok("Verified the signature of the stub");
+
+    let stub_path = esp
+        .join("EFI/BOOT")
+        .join(architecture.efi_fallback_filename());
+    fs::write(&stub_path, &stub).with_context(|| format!("Failed to write {stub_path:?}"))?;
+    let inspection = Inspection::read(&stub_path, &esp, Some(signer))?;
+    if let Some(discrepancy) = inspection.discrepancies.first() {
+        bail!("The stub does not parse back correctly: {discrepancy}");
+    }
+    let cmdline = inspection.section(".cmdline").map(|section| section.text());
+    if cmdline.as_deref() != Some(CMDLINE) {
+        bail!("The stub embeds the command line {cmdline:?} instead of {CMDLINE:?}.");
+    }
+    print_ok("Parsed the stub back");
+
+    if let Some(qemu) = qemu {
+        boot_in_qemu(qemu, architecture, &esp)?;
+        print_ok("Booted the stub in QEMU");
+    }
+    Ok(())
+}
+
+/// Boot the stub from `esp` in QEMU and watch its output on the serial console.
+fn boot_in_qemu(qemu: &Qemu, architecture: Architecture, esp: &Path) -> Result<()> {
+    let mut command = qemu_command(&qemu.binary, architecture, esp)?;
+    command.arg("-bios").arg(&qemu.firmware);
+    watch_qemu(command, QEMU_TIMEOUT, classify_qemu_line)
+}
+
+/// A QEMU command for `architecture` that boots from the ESP at `esp`, without firmware.
+pub fn qemu_command(binary: &Path, architecture: Architecture, esp: &Path) -> Result<Command> {
+    let mut command = Command::new(binary);
+    match architecture {
+        Architecture::X86 => command.args(["-machine", "q35"]),
+        Architecture::AArch64 => command.args(["-machine", "virt", "-cpu", "max"]),
+        _ => bail!("Booting {architecture:?} in QEMU is not supported."),
+    };
+    command
+        .args(["-m", "512", "-nographic", "-no-reboot", "-net", "none"])
+        .arg("-drive")
+        .arg(format!("format=raw,file=fat:{}", esp.display()));
+    Ok(command)
+}
+
+/// Run QEMU and classify each line of its serial console with `classify` until one decides the
+/// outcome or `timeout` passes.
+pub fn watch_qemu(
+    mut command: Command,
+    timeout: Duration,
+    classify: impl Fn(&str) -> Option<Result<()>>,
+) -> Result<()> {
+    let binary = command.get_program().to_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to start {binary:?}"))?;
+
+    let stdout = child.stdout.take().context("QEMU has no stdout")?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout)
+            .split(b'\n')
+            .map_while(|line| line.ok())
+        {
+            if sender
+                .send(String::from_utf8_lossy(&line).into_owned())
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let outcome = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                log::debug!("QEMU: {}", line.trim_end());
+                if let Some(outcome) = classify(&line) {
+                    break outcome;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                break Err(anyhow::anyhow!(
+                    "The stub did not get to the kernel in QEMU within {} seconds.",
+                    timeout.as_secs()
+                ))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err(anyhow::anyhow!(
+                    "QEMU exited before the stub got to the kernel."
+                ))
+            }
+        }
+    };
+
+    let _ = child.kill();
+    let _ = child.wait();
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_stub_output() {
+        assert!(classify_qemu_line("[ INFO]: Booting lanzastub").is_none());
+        assert!(matches!(
+            classify_qemu_line("[ERROR]: Failed to boot: Loading the kernel (LOAD_ERROR)"),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            classify_qemu_line("[ERROR]: Kernel hash does not match!"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            classify_qemu_line("[ERROR]: Error code: P-1KD0XA. See https://..."),
+            Some(Err(_))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! A/B slots for image-style updates of appliances.
+//!
+//! With `lzbt install --ab-slots`, the entry of the newest generation is also copied into one of
+//! two slots, `EFI/nixos/slot-a.efi` and `EFI/nixos/slot-b.efi`. systemd-boot starts the slot
+//! selector `EFI/Linux/nixos-slots.efi`, a stub that boots the active slot and falls back to the
+//! other one once the active slot ran out of tries.
+//!
+//! A new generation is written to the inactive slot, which then becomes active with a number of
+//! tries. `lzbt slot mark-good` confirms the booted slot, e.g. from a unit after
+//! `boot-complete.target`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::efivars::{
+    delete_variable, read_string_variable, read_variable, write_string_variable, write_variable,
+    EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_NON_VOLATILE, EFI_VARIABLE_RUNTIME_ACCESS,
+    LANZABOOTE_GUID,
+};
+
+/// The file name of the slot selector in `EFI/Linux`.
+pub const SELECTOR_ENTRY: &str = "nixos-slots.efi";
+
+/// The os-release of the slot selector, which systemd-boot shows as its title.
+pub const SELECTOR_OS_RELEASE: &str = "NAME=NixOS\nID=nixos\nPRETTY_NAME=NixOS (A/B slots)\n";
+
+/// The active slot, `a` or `b`.
+const SLOT: &str = "LanzabooteSlot";
+/// The number of boots that are left for the active slot until it is confirmed.
+const SLOT_TRIES: &str = "LanzabooteSlotTries";
+/// The slot that the stub booted.
+const BOOTED_SLOT: &str = "LanzabooteBootedSlot";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::A => "a",
+            Self::B => "b",
+        }
+    }
+
+    /// The path of the payload of the slot in `nixos`, the `EFI/nixos` directory of the ESP.
+    pub fn payload_path(self, nixos: &Path) -> PathBuf {
+        nixos.join(format!("slot-{}.efi", self.as_str()))
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.as_str().to_uppercase())
+    }
+}
+
+impl FromStr for Slot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "a" | "A" => Ok(Self::A),
+            "b" | "B" => Ok(Self::B),
+            _ => bail!("Unknown slot {s:?}, expected a or b"),
+        }
+    }
+}
+
+/// The active slot. Like in the stub, slot A is active until a slot is selected.
+pub fn active_slot() -> Result<Slot> {
+    match read_string_variable(SLOT, &LANZABOOTE_GUID)? {
+        Some(slot) => slot.parse(),
+        None => Ok(Slot::A),
+    }
+}
+
+/// The slot of the current boot, if the system was booted by the slot selector.
+pub fn booted_slot() -> Result<Option<Slot>> {
+    read_string_variable(BOOTED_SLOT, &LANZABOOTE_GUID)?
+        .map(|slot| slot.parse())
+        .transpose()
+}
+
+/// The boots that are left for the active slot, or `None` if it is confirmed.
+pub fn remaining_tries() -> Result<Option<u8>> {
+    Ok(read_variable(SLOT_TRIES, &LANZABOOTE_GUID)?.and_then(|data| data.first().copied()))
+}
+
+/// Make a slot the active one.
+///
+/// With `tries`, the stub boots the slot that many times until it is confirmed with
+/// [`mark_good`], and then falls back to the other slot. Without, the slot is confirmed right
+/// away.
+pub fn switch(slot: Slot, tries: Option<u8>) -> Result<()> {
+    // The tries are written first, so that the previous slot is never left without a fallback.
+    match tries {
+        Some(tries) => write_variable(
+            SLOT_TRIES,
+            &LANZABOOTE_GUID,
+            EFI_VARIABLE_NON_VOLATILE
+                | EFI_VARIABLE_BOOTSERVICE_ACCESS
+                | EFI_VARIABLE_RUNTIME_ACCESS,
+            &[tries],
+        ),
+        None => delete_variable(SLOT_TRIES, &LANZABOOTE_GUID),
+    }
+    .context("Failed to set the tries of the slot")?;
+    write_string_variable(SLOT, &LANZABOOTE_GUID, slot.as_str())
+        .context("Failed to set the active slot")?;
+
+    match tries {
+        Some(tries) => log::info!(
+            "Slot {slot} is now active and is booted up to {tries} times until it is confirmed \
+             with `lzbt slot mark-good`."
+        ),
+        None => log::info!("Slot {slot} is now active."),
+    }
+    Ok(())
+}
+
+/// Confirm the slot of the current boot, so that the stub keeps booting it.
+pub fn mark_good() -> Result<()> {
+    let slot = booted_slot()?.context("The system was not booted by the slot selector")?;
+    switch(slot, None)?;
+    log::info!("Marked slot {slot} as good.");
+    Ok(())
+}
+
+/// Print the active and the booted slot.
+pub fn print_status(nixos: &Path) -> Result<()> {
+    let active = active_slot()?;
+    for slot in [Slot::A, Slot::B] {
+        let payload = slot.payload_path(nixos);
+        println!(
+            "Slot {slot}: {}",
+            if payload.exists() {
+                payload.display().to_string()
+            } else {
+                "empty".to_string()
+            }
+        );
+    }
+    match remaining_tries()? {
+        Some(tries) => println!("Active: {active} ({tries} tries left until it is confirmed)"),
+        None => println!("Active: {active}"),
+    }
+    match booted_slot()? {
+        Some(booted) => println!("Booted: {booted}"),
+        None => println!("Booted: not by the slot selector"),
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use lanzaboote_tool::efivars::{
+    read_string_variable, write_string_variable, LANZABOOTE_GUID, LOADER_GUID,
+};
+
+use crate::diff::find_stub;
+use crate::migrate::{generation_from_entry_name, is_lanzaboote_entry};
+
+/// The variable in which the stub records the entry it was booted from.
+const ATTEMPTED_ENTRY: &str = "LanzabooteAttemptedEntry";
+
+/// The entry of the current boot.
+///
+/// The variable of the stub is preferred, because it is also set if the stub was not started by
+/// systemd-boot.
+pub fn booted_entry() -> Result<Option<String>> {
+    match read_string_variable(ATTEMPTED_ENTRY, &LANZABOOTE_GUID)? {
+        Some(entry) => Ok(Some(entry)),
+        None => read_string_variable("LoaderEntrySelected", &LOADER_GUID),
+    }
+}
+
+/// The generation that is known to boot.
+///
+/// This is the generation of the default entry, which is only set by staging and confirming
+/// generations. Otherwise, the currently booted generation is assumed to work.
+pub fn known_good_generation() -> Result<Option<u64>> {
+    let entry = match read_string_variable("LoaderEntryDefault", &LOADER_GUID)? {
+        Some(entry) => Some(entry),
+        None => booted_entry()?,
+    };
+    Ok(entry
+        .filter(|entry| is_lanzaboote_entry(entry))
+        .and_then(|entry| generation_from_entry_name(&entry)))
+}
+
+/// Select the default entry and the entry to boot once from the installed entries.
+///
+/// Returns `None` if there is no known good generation to fall back to.
+fn staged_entries(
+    entries: &BTreeMap<u64, String>,
+    known_good: Option<u64>,
+) -> Option<(&str, &str)> {
+    let (_, newest) = entries.last_key_value()?;
+    let known_good = entries.get(&known_good?)?;
+    Some((known_good, newest))
+}
+
+/// Make the newest installed generation boot once, while the known good generation stays the
+/// default until the newest one is confirmed with [`mark_good`].
+pub fn stage(entries: &BTreeMap<u64, String>, known_good: Option<u64>) -> Result<()> {
+    let Some((default, oneshot)) = staged_entries(entries, known_good) else {
+        log::warn!("No generation is known to boot. The newest generation is the default.");
+        return Ok(());
+    };
+
+    write_string_variable("LoaderEntryDefault", &LOADER_GUID, default)
+        .context("Failed to set the default entry")?;
+    if default == oneshot {
+        return Ok(());
+    }
+    write_string_variable("LoaderEntryOneShot", &LOADER_GUID, oneshot)
+        .context("Failed to set the entry for the next boot")?;
+    log::info!(
+        "Staged {oneshot} for the next boot. Run `lzbt mark-good` after it booted successfully \
+         to make it the default."
+    );
+    Ok(())
+}
+
+/// Make the entry of the current boot the default entry.
+pub fn mark_good() -> Result<()> {
+    let entry = booted_entry()?.context("Failed to determine the booted entry")?;
+    if !is_lanzaboote_entry(&entry) {
+        bail!("The system was booted from {entry:?}, not from a Lanzaboote entry");
+    }
+
+    write_string_variable("LoaderEntryDefault", &LOADER_GUID, &entry)
+        .context("Failed to set the default entry")?;
+    log::info!("Marked {entry} as good.");
+    Ok(())
+}
+
+/// The name of the installed entry of a generation, as systemd-boot refers to it.
+fn entry_of_generation(esp: &Path, generation: u64) -> Result<String> {
+    let stub = find_stub(esp, generation)?;
+    stub.file_name()
+        .and_then(|name| name.to_str())
+        .map(ToOwned::to_owned)
+        .with_context(|| format!("Invalid entry name {stub:?}"))
+}
+
+/// Make the entry of a generation the default entry.
+///
+/// This also makes it the known good generation for `lzbt install --tentative`.
+pub fn set_default(esp: &Path, generation: u64) -> Result<()> {
+    let entry = entry_of_generation(esp, generation)?;
+    write_string_variable("LoaderEntryDefault", &LOADER_GUID, &entry)
+        .context("Failed to set the default entry")?;
+    log::info!("Generation {generation} ({entry}) is now the default.");
+    Ok(())
+}
+
+/// Boot the entry of a generation once on the next boot, and reboot unless `no_reboot` is set.
+pub fn reboot_into(esp: &Path, generation: u64, no_reboot: bool) -> Result<()> {
+    let entry = entry_of_generation(esp, generation)?;
+    write_string_variable("LoaderEntryOneShot", &LOADER_GUID, &entry)
+        .context("Failed to set the entry for the next boot")?;
+    log::info!("Generation {generation} ({entry}) is booted once on the next boot.");
+
+    if no_reboot {
+        return Ok(());
+    }
+    let status = Command::new("systemctl")
+        .arg("reboot")
+        .status()
+        .context("Failed to run systemctl reboot")?;
+    if !status.success() {
+        bail!("Failed to reboot: systemctl exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_newest_entry() {
+        let entries = BTreeMap::from([
+            (1, "nixos-generation-1-a.efi".to_string()),
+            (2, "nixos-generation-2-b.efi".to_string()),
+            (3, "nixos-generation-3-c.efi".to_string()),
+        ]);
+
+        assert_eq!(
+            staged_entries(&entries, Some(2)),
+            Some(("nixos-generation-2-b.efi", "nixos-generation-3-c.efi"))
+        );
+        assert_eq!(
+            staged_entries(&entries, Some(3)),
+            Some(("nixos-generation-3-c.efi", "nixos-generation-3-c.efi"))
+        );
+        // The known good generation was not installed, e.g. because it was deleted.
+        assert_eq!(staged_entries(&entries, Some(4)), None);
+        assert_eq!(staged_entries(&entries, None), None);
+    }
+
+    #[test]
+    fn find_entry_of_generation() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let linux = esp.path().join("EFI/Linux");
+        std::fs::create_dir_all(&linux)?;
+        for entry in [
+            "nixos-generation-4-a.efi",
+            "nixos-generation-4-specialisation-debug-b.efi",
+            "nixos-generation-40-c.efi",
+        ] {
+            std::fs::write(linux.join(entry), b"")?;
+        }
+
+        assert_eq!(
+            entry_of_generation(esp.path(), 4)?,
+            "nixos-generation-4-a.efi"
+        );
+        assert!(entry_of_generation(esp.path(), 5).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! Build a signed stub for a kernel and initrd outside of NixOS, see `lzbt standalone`.
+//!
+//! Other distributions have no bootspec, so the kernel, initrd and command line are given
+//! explicitly. They are installed like the files of a generation, only to `EFI/<ID>` instead of
+//! `EFI/nixos`, where `ID` is the one of the os-release of the distribution.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use base32ct::{Base32Unpadded, Encoding};
+
+use crate::install::{atomic_write, install_hashed, resolve_efi_path};
+use lanzaboote_tool::esp_fs::EspFilesystem;
+use lanzaboote_tool::kernel::kernel_release;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::pe::{self, StubParameters};
+use lanzaboote_tool::signature::Signer;
+use lanzaboote_tool::utils::file_hash;
+use lanzaboote_tool::zboot::check_kernel;
+
+/// The directory in `EFI` for distributions whose os-release has no `ID`.
+const DEFAULT_ID: &str = "linux";
+
+/// A kernel and initrd that are not part of a NixOS generation.
+pub struct StandaloneEntry {
+    pub kernel: PathBuf,
+    pub initrd: PathBuf,
+    pub cmdline: Vec<String>,
+    /// The os-release of the distribution, which systemd-boot shows the entry with.
+    pub os_release: String,
+    /// The path of the stub, which must be on the ESP.
+    pub output: PathBuf,
+}
+
+impl StandaloneEntry {
+    /// The directory in which the kernel and initrd are installed.
+    fn payload_directory(&self, esp: &Path) -> Result<PathBuf> {
+        let os_release: OsRelease = self.os_release.parse()?;
+        let id = os_release
+            .0
+            .get("ID")
+            .map(String::as_str)
+            .filter(|id| !id.is_empty() && !id.contains(['/', '\\']))
+            .unwrap_or(DEFAULT_ID);
+        Ok(esp.join("EFI").join(id))
+    }
+}
+
+/// Install a file under a name that contains its hash, like the files of generations.
+fn install_content_addressed(
+    esp_fs: &mut impl EspFilesystem,
+    from: &Path,
+    directory: &Path,
+    label: &str,
+) -> Result<PathBuf> {
+    let hash = file_hash(from).with_context(|| format!("Failed to read {from:?}"))?;
+    let to = directory.join(format!(
+        "{label}-{}.efi",
+        Base32Unpadded::encode_string(&hash)
+    ));
+    install_hashed(esp_fs, from, &hash, &to)?;
+    Ok(to)
+}
+
+/// Concatenate `initrds` in this order into one initrd in `directory`, unless there is only one.
+///
+/// The kernel unpacks concatenated archives one after another, so early ones, e.g. with microcode,
+/// must come first.
+pub fn combine_initrds(initrds: &[PathBuf], directory: &Path) -> Result<PathBuf> {
+    match initrds {
+        [] => bail!("There is no initrd."),
+        [initrd] => Ok(initrd.clone()),
+        initrds => {
+            let combined = directory.join("initrd");
+            let mut file = File::create(&combined)
+                .with_context(|| format!("Failed to create {combined:?}"))?;
+            for initrd in initrds {
+                let mut part =
+                    File::open(initrd).with_context(|| format!("Failed to open {initrd:?}"))?;
+                io::copy(&mut part, &mut file)
+                    .with_context(|| format!("Failed to concatenate {initrd:?}"))?;
+            }
+            Ok(combined)
+        }
+    }
+}
+
+/// Install the kernel and initrd of `entry` and a signed stub that boots them at its output path.
+///
+/// Returns the paths of all installed files. Files of earlier kernels are not removed.
+pub fn install<S: Signer>(
+    esp_fs: &mut impl EspFilesystem,
+    signer: &S,
+    lanzaboote_stub: &Path,
+    esp: &Path,
+    entry: &StandaloneEntry,
+) -> Result<Vec<PathBuf>> {
+    if !entry.output.starts_with(esp) {
+        bail!("The stub {:?} must be on the ESP {esp:?}", entry.output);
+    }
+    check_kernel(&entry.kernel)?;
+
+    let directory = entry.payload_directory(esp)?;
+    let kernel_target = install_content_addressed(esp_fs, &entry.kernel, &directory, "kernel")
+        .context("Failed to install the kernel.")?;
+    let initrd_target = install_content_addressed(esp_fs, &entry.initrd, &directory, "initrd")
+        .context("Failed to install the initrd.")?;
+
+    let parameters = StubParameters::new(
+        lanzaboote_stub,
+        &entry.kernel,
+        &entry.initrd,
+        &kernel_target,
+        &initrd_target,
+        esp,
+    )?
+    .with_cmdline(&entry.cmdline)
+    .with_os_release_contents(entry.os_release.as_bytes())
+    .with_kernel_release(kernel_release(&entry.kernel)?);
+    let stub = signer
+        .build_and_sign_stub(&parameters)
+        .context("Failed to build and sign the stub.")?;
+    atomic_write(esp_fs, &entry.output, &stub).context("Failed to install the stub.")?;
+
+    Ok(vec![kernel_target, initrd_target, entry.output.clone()])
+}
+
+/// The kernel and initrd that a stub boots.
+fn payloads(esp: &Path, stub: &[u8]) -> Vec<PathBuf> {
+    [".linux", ".initrd"]
+        .into_iter()
+        .filter_map(|section| pe::read_section_data(stub, section))
+        .filter(|efi_path| !efi_path.is_empty())
+        .filter_map(|efi_path| resolve_efi_path(esp, efi_path).ok())
+        .collect()
+}
+
+/// Remove the stub at `output` and the kernel and initrd it boots, unless another stub in the same
+/// directory boots them too.
+///
+/// Returns the paths of all removed files, which is empty if there is no stub at `output`.
+pub fn remove(esp_fs: &mut impl EspFilesystem, esp: &Path, output: &Path) -> Result<Vec<PathBuf>> {
+    if !esp_fs.exists(output) {
+        return Ok(Vec::new());
+    }
+    let candidates = payloads(esp, &esp_fs.read(output)?);
+    esp_fs
+        .delete(output)
+        .with_context(|| format!("Failed to remove {output:?}"))?;
+    let mut removed = vec![output.to_path_buf()];
+
+    let mut referenced = BTreeSet::new();
+    if let Some(directory) = output.parent().filter(|d| esp_fs.is_dir(d)) {
+        for stub in esp_fs.list(directory)? {
+            if !esp_fs.is_dir(&stub) {
+                referenced.extend(payloads(esp, &esp_fs.read(&stub)?));
+            }
+        }
+    }
+    for file in candidates {
+        if !referenced.contains(&file) && esp_fs.exists(&file) {
+            esp_fs
+                .delete(&file)
+                .with_context(|| format!("Failed to remove {file:?}"))?;
+            removed.push(file);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use lanzaboote_tool::esp_fs::InMemoryEspFilesystem;
+
+    /// Stubs are represented by their parameters.
+    struct MockSigner;
+
+    impl Signer for MockSigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            Ok(fs::read(store_path)?)
+        }
+
+        fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(stub)?)
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(b"mock".to_vec())
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn install_stub_for_explicit_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let kernel = tmpdir.path().join("vmlinuz-linux");
+        let initrd = tmpdir.path().join("initramfs-linux.img");
+        fs::write(&kernel, b"kernel")?;
+        fs::write(&initrd, b"initrd")?;
+        let esp = Path::new("/boot");
+        let entry = StandaloneEntry {
+            kernel: kernel.clone(),
+            initrd,
+            cmdline: vec!["root=LABEL=root".into(), "rw".into()],
+            os_release: "NAME=\"Arch Linux\"\nID=arch\n".into(),
+            output: esp.join("EFI/Linux/arch-linux.efi"),
+        };
+
+        let mut esp_fs = InMemoryEspFilesystem::new();
+        let installed = install(&mut esp_fs, &MockSigner, Path::new("/stub"), esp, &entry)?;
+        assert_eq!(installed.len(), 3);
+        assert!(installed[0].starts_with("/boot/EFI/arch"));
+        assert_eq!(esp_fs.read(&installed[0])?, b"kernel");
+
+        let parameters: StubParameters = serde_json::from_slice(&esp_fs.read(&entry.output)?)?;
+        assert_eq!(parameters.kernel_cmdline, ["root=LABEL=root", "rw"]);
+        assert_eq!(parameters.kernel_store_path, kernel);
+        assert!(parameters
+            .kernel_path_at_esp
+            .starts_with("\\EFI\\arch\\kernel-"));
+
+        let outside = StandaloneEntry {
+            output: PathBuf::from("/tmp/arch-linux.efi"),
+            ..entry
+        };
+        assert!(install(&mut esp_fs, &MockSigner, Path::new("/stub"), esp, &outside).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use lanzaboote_tool::efivars::{read_string_variable, LOADER_GUID};
+use lanzaboote_tool::signature::Signer;
+
+use crate::esp::SystemdEspPaths;
+use crate::expiry::{CertificateStatus, Source};
+use crate::migrate::{generation_from_entry_name, is_lanzaboote_entry};
+use crate::secure_boot::{enrolled_certificates, FirmwareState};
+use crate::staging;
+
+/// The Secure Boot state of the firmware and the installed entries, e.g. for desktop integration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub secure_boot: bool,
+    pub setup_mode: bool,
+    /// Whether the signing certificate is enrolled in db, if the certificate is known.
+    pub key_enrolled: Option<bool>,
+    pub default_entry: Option<String>,
+    pub booted_entry: Option<String>,
+    pub known_good_generation: Option<u64>,
+    /// The file names of the installed entries in `EFI/Linux`.
+    pub entries: Vec<String>,
+    /// The validity of the signing certificate and of the certificates in db.
+    pub certificates: Vec<CertificateStatus>,
+    /// Whether only the ESP was read, so the firmware state is unknown.
+    pub offline: bool,
+}
+
+impl Status {
+    /// Read the status from the EFI variables and the ESP.
+    ///
+    /// `certificate` is the DER encoded certificate that the stubs are signed with.
+    pub fn read(paths: &SystemdEspPaths, certificate: Option<&[u8]>) -> Result<Self> {
+        let state = FirmwareState::read(certificate.unwrap_or_default())?;
+        let now = OffsetDateTime::now_utc();
+        let certificates = certificate
+            .map(|certificate| (Source::Signing, certificate.to_vec()))
+            .into_iter()
+            .chain(
+                enrolled_certificates()?
+                    .into_iter()
+                    .map(|certificate| (Source::Db, certificate)),
+            )
+            .filter_map(|(source, certificate)| CertificateStatus::read(source, &certificate, now))
+            .collect();
+        Ok(Self {
+            secure_boot: state.secure_boot,
+            setup_mode: state.setup_mode,
+            key_enrolled: certificate.is_some().then_some(state.key_enrolled),
+            default_entry: read_string_variable("LoaderEntryDefault", &LOADER_GUID)?,
+            booted_entry: staging::booted_entry()?,
+            known_good_generation: staging::known_good_generation()?,
+            entries: installed_entries(paths)?,
+            certificates,
+            offline: false,
+        })
+    }
+
+    /// Read the status from the ESP alone, without the EFI variables and without writing anything.
+    ///
+    /// The EFI variables are those of the running system, which is not the one that the ESP
+    /// belongs to when it is audited from rescue media.
+    pub fn read_offline(paths: &SystemdEspPaths, certificate: Option<&[u8]>) -> Result<Self> {
+        let now = OffsetDateTime::now_utc();
+        Ok(Self {
+            secure_boot: false,
+            setup_mode: false,
+            key_enrolled: None,
+            default_entry: None,
+            booted_entry: None,
+            known_good_generation: None,
+            entries: installed_entries(paths)?,
+            certificates: certificate
+                .and_then(|certificate| CertificateStatus::read(Source::Signing, certificate, now))
+                .into_iter()
+                .collect(),
+            offline: true,
+        })
+    }
+
+    /// The generations that have an installed entry, in ascending order.
+    pub fn generations(&self) -> Vec<u64> {
+        let mut generations = self
+            .entries
+            .iter()
+            .filter_map(|entry| generation_from_entry_name(entry))
+            .collect::<Vec<_>>();
+        generations.dedup();
+        generations
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "secureBoot": (!self.offline).then_some(self.secure_boot),
+            "setupMode": (!self.offline).then_some(self.setup_mode),
+            "offline": self.offline,
+            "keyEnrolled": self.key_enrolled,
+            "defaultEntry": self.default_entry,
+            "bootedEntry": self.booted_entry,
+            "knownGoodGeneration": self.known_good_generation,
+            "entries": self.entries,
+            "certificates": self
+                .certificates
+                .iter()
+                .map(CertificateStatus::to_json)
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn print(&self) {
+        let enabled = |value: bool| match (self.offline, value) {
+            (true, _) => "unknown (offline)",
+            (false, true) => "enabled",
+            (false, false) => "disabled",
+        };
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "unknown".to_string());
+        println!("Secure Boot:           {}", enabled(self.secure_boot));
+        println!("Setup Mode:            {}", enabled(self.setup_mode));
+        println!(
+            "Key enrolled:          {}",
+            optional(self.key_enrolled.map(|enrolled| enrolled.to_string()))
+        );
+        println!(
+            "Default entry:         {}",
+            optional(self.default_entry.clone())
+        );
+        println!(
+            "Booted entry:          {}",
+            optional(self.booted_entry.clone())
+        );
+        println!(
+            "Known good generation: {}",
+            optional(self.known_good_generation.map(|g| g.to_string()))
+        );
+        println!("Entries:");
+        for entry in &self.entries {
+            println!("  {entry}");
+        }
+        println!("Certificates:");
+        for certificate in &self.certificates {
+            println!(
+                "  {:<7} {:<13} until {}  {}",
+                certificate.source.as_str(),
+                certificate.expiry.as_str(),
+                certificate.not_after.date(),
+                certificate.name.as_deref().unwrap_or("unknown")
+            );
+        }
+    }
+}
+
+/// The file names of the installed entries in `EFI/Linux`, sorted by generation.
+fn installed_entries(paths: &SystemdEspPaths) -> Result<Vec<String>> {
+    if !paths.linux.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&paths.linux)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_lanzaboote_entry(&name) {
+            entries.push(name);
+        }
+    }
+    entries.sort_by_key(|name| (generation_from_entry_name(name), name.clone()));
+    Ok(entries)
+}
+
+/// Check the signatures of systemd-boot and the installed entries.
+///
+/// Returns every file on the ESP that was checked and whether it is signed by `signer`.
+pub fn verify(paths: &SystemdEspPaths, signer: &impl Signer) -> Result<Vec<(PathBuf, bool)>> {
+    let mut files = vec![paths.systemd_boot.clone(), paths.efi_fallback.clone()];
+    files.extend(
+        installed_entries(paths)?
+            .into_iter()
+            .map(|name| paths.linux.join(name)),
+    );
+
+    let mut results = Vec::new();
+    for file in files.into_iter().filter(|file| file.exists()) {
+        let signed = signer.verify_path(&file)?;
+        results.push((file, signed));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::architecture::Architecture;
+    use lanzaboote_tool::esp::EspPaths;
+
+    #[test]
+    fn list_installed_entries_by_generation() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let paths = SystemdEspPaths::new(esp.path(), Architecture::X86);
+        fs::create_dir_all(&paths.linux)?;
+        for name in [
+            "nixos-generation-10-b.efi",
+            "nixos-generation-9-a.efi",
+            "nixos-generation-9-recovery-c.efi",
+            "ubuntu.efi",
+        ] {
+            fs::write(paths.linux.join(name), b"")?;
+        }
+
+        let status = Status::read_offline(&paths, None)?;
+        assert_eq!(
+            status.entries,
+            [
+                "nixos-generation-9-a.efi",
+                "nixos-generation-9-recovery-c.efi",
+                "nixos-generation-10-b.efi"
+            ]
+        );
+        assert_eq!(status.generations(), [9, 10]);
+        // The firmware state of the running system is not reported for another ESP.
+        assert_eq!(status.to_json()["secureBoot"], Value::Null);
+        Ok(())
+    }
+}
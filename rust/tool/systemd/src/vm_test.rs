@@ -0,0 +1,244 @@
+//! `lzbt vm-test`: boot a generation in QEMU with Secure Boot enforced.
+//!
+//! The generation is assembled and signed like `lzbt install` does, but into a temporary directory
+//! that stands in for the ESP. The UEFI variables of OVMF are prepared with `virt-fw-vars`: PK
+//! and KEK are ephemeral certificates that are generated for the test and db only contains the
+//! signing certificate. The firmware then has to verify systemd-boot, which has to verify the stub,
+//! which has to verify the kernel and the initrd and hand off to the kernel. The kernel prints to
+//! the serial console, so the test succeeds as soon as it starts. Signing and layout errors are
+//! caught this way before the machine itself is rebooted. The real ESP and the firmware of the
+//! machine are never touched.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp_fs::PhysicalEspFilesystem;
+use lanzaboote_tool::generation::GenerationRange;
+use lanzaboote_tool::signature::local::LocalKeyPair;
+
+use crate::cmdline::CmdlineFragments;
+use crate::install::Installer;
+use crate::self_test::{qemu_command, watch_qemu};
+
+/// The owner of the enrolled certificates in the signature lists.
+const OWNER_GUID: &str = "a6f1b5c2-6c7e-4f38-9c4d-3e1f0b7d2a58";
+
+/// How long the generation may take to get to the kernel in QEMU.
+const QEMU_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The first line that the kernel prints, i.e. after the stub handed off to it.
+const SUCCESS_MARKER: &str = "Linux version";
+
+/// Lines of the firmware, systemd-boot and the stub that show that verification failed, in lower
+/// case.
+const FAILURE_MARKERS: [&str; 5] = [
+    "access denied",
+    "security violation",
+    "hash does not match",
+    "failed to boot",
+    "error code: p-",
+];
+
+/// The QEMU system emulator and the split OVMF firmware that it boots.
+#[derive(Debug, Clone)]
+pub struct VmFirmware {
+    pub qemu: PathBuf,
+    /// The code of the firmware, e.g. `OVMF_CODE.fd`, built with Secure Boot support.
+    pub code: PathBuf,
+    /// The template of the UEFI variables without any keys, e.g. `OVMF_VARS.fd`.
+    pub vars: PathBuf,
+}
+
+/// The outcome of a line on the serial console, if it decides the test.
+fn classify_line(line: &str) -> Option<Result<()>> {
+    let lowercase = line.to_lowercase();
+    if FAILURE_MARKERS
+        .iter()
+        .any(|marker| lowercase.contains(marker))
+    {
+        return Some(Err(anyhow::anyhow!(
+            "The generation failed to boot in QEMU: {}",
+            line.trim()
+        )));
+    }
+    line.contains(SUCCESS_MARKER).then_some(Ok(()))
+}
+
+/// The kernel parameter that sends the kernel's output to the serial console of QEMU.
+fn console_parameter(architecture: Architecture) -> Result<&'static str> {
+    match architecture {
+        Architecture::X86 => Ok("console=ttyS0"),
+        Architecture::AArch64 => Ok("console=ttyAMA0"),
+        _ => bail!("Booting {architecture:?} in QEMU is not supported."),
+    }
+}
+
+fn print_ok(step: &str) {
+    println!("ok  {step}");
+}
+
+/// Assemble, sign and boot `generation` from `generation_links` in QEMU.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    lanzaboote_stub: &Path,
+    architecture: Architecture,
+    systemd: &Path,
+    systemd_boot_loader_config: &Path,
+    key_pair: LocalKeyPair,
+    generation_links: Vec<PathBuf>,
+    generation: u64,
+    firmware: &VmFirmware,
+) -> Result<()> {
+    let tempdir = TempDir::new().context("Failed to create a temporary directory")?;
+    let esp = tempdir.path().join("esp");
+    fs::create_dir_all(&esp)?;
+    let certificate = key_pair.public_key.clone();
+
+    let mut installer = Installer::new(
+        lanzaboote_stub.to_path_buf(),
+        architecture,
+        systemd.to_path_buf(),
+        systemd_boot_loader_config.to_path_buf(),
+        key_pair,
+        1,
+        esp.clone(),
+        PhysicalEspFilesystem,
+        generation_links,
+    )
+    .with_selected_generations(vec![GenerationRange {
+        start: generation,
+        end: Some(generation),
+    }])
+    .with_cmdline_fragments(CmdlineFragments::read(
+        None,
+        None,
+        None,
+        &[console_parameter(architecture)?.to_string()],
+    )?);
+    installer.install()?;
+    if !installer.entries().contains_key(&generation) {
+        bail!("Generation {generation} does not exist or could not be assembled.");
+    }
+    print_ok(&format!("Assembled and signed generation {generation}"));
+
+    let vars = tempdir.path().join("vars.fd");
+    enroll_keys(tempdir.path(), &certificate, &firmware.vars, &vars)?;
+    print_ok("Enrolled ephemeral PK and KEK and the signing certificate in db");
+
+    let mut command = qemu_command(&firmware.qemu, architecture, &esp)?;
+    if architecture == Architecture::X86 {
+        // OVMF with Secure Boot protects its variables with SMM.
+        command.args([
+            "-machine",
+            "smm=on",
+            "-global",
+            "driver=cfi.pflash01,property=secure,value=on",
+        ]);
+    }
+    command
+        .arg("-drive")
+        .arg(pflash(&firmware.code, 0, true))
+        .arg("-drive")
+        .arg(pflash(&vars, 1, false));
+    watch_qemu(command, QEMU_TIMEOUT, classify_line)?;
+    print_ok("Verified the boot chain and handed off to the kernel in QEMU");
+    Ok(())
+}
+
+/// The `-drive` option for a flash device of the firmware.
+fn pflash(path: &Path, unit: u8, readonly: bool) -> String {
+    let readonly = if readonly { ",readonly=on" } else { "" };
+    format!(
+        "if=pflash,format=raw,unit={unit},file={}{readonly}",
+        path.display()
+    )
+}
+
+/// Generate an ephemeral self-signed certificate in `dir` and return its path.
+fn ephemeral_certificate(dir: &Path, name: &str) -> Result<PathBuf> {
+    let certificate = dir.join(format!("{name}.pem"));
+    let output = Command::new("openssl")
+        .args([
+            "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+        ])
+        .arg("-subj")
+        .arg(format!("/CN=lzbt vm-test {name}/"))
+        .arg("-keyout")
+        .arg(dir.join(format!("{name}.key")))
+        .arg("-out")
+        .arg(&certificate)
+        .output()
+        .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to generate the ephemeral {name}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(certificate)
+}
+
+/// Write the variables of `template` with ephemeral PK and KEK, `certificate` in db and Secure
+/// Boot enabled to `output`.
+fn enroll_keys(dir: &Path, certificate: &Path, template: &Path, output: &Path) -> Result<()> {
+    let pk = ephemeral_certificate(dir, "PK")?;
+    let kek = ephemeral_certificate(dir, "KEK")?;
+    let status = Command::new("virt-fw-vars")
+        .arg("--input")
+        .arg(template)
+        .arg("--output")
+        .arg(output)
+        .args(["--set-pk", OWNER_GUID])
+        .arg(&pk)
+        .args(["--add-kek", OWNER_GUID])
+        .arg(&kek)
+        .args(["--add-db", OWNER_GUID])
+        .arg(certificate)
+        .arg("--secure-boot")
+        .status()
+        .context("Failed to run virt-fw-vars. Most likely, the binary is not on PATH.")?;
+    if !status.success() {
+        bail!("Failed to enroll the keys in {template:?}: virt-fw-vars exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_console_output() {
+        assert!(classify_line("BdsDxe: loading Boot0001 \"UEFI QEMU HARDDISK\"").is_none());
+        assert!(matches!(
+            classify_line("[    0.000000] Linux version 6.6.1 (nixbld@localhost)"),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            classify_line("BdsDxe: failed to load Boot0001: Access Denied"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            classify_line("Error loading \\EFI\\Linux\\nixos-generation-1.efi: Security violation"),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn flash_drives() {
+        assert_eq!(
+            pflash(Path::new("/ovmf/OVMF_CODE.fd"), 0, true),
+            "if=pflash,format=raw,unit=0,file=/ovmf/OVMF_CODE.fd,readonly=on"
+        );
+        assert_eq!(
+            pflash(Path::new("/tmp/vars.fd"), 1, false),
+            "if=pflash,format=raw,unit=1,file=/tmp/vars.fd"
+        );
+    }
+}
@@ -154,6 +154,9 @@ pub fn lanzaboote_install(
     let test_loader_config = r"timeout 0\nconsole-mode 1\n";
     fs::write(test_loader_config_path.path(), test_loader_config)?;
 
+    // The tests run concurrently on their own ESPs, so they must not share the global lock file.
+    let lock_dir = tempfile::tempdir()?;
+
     let mut cmd = Command::cargo_bin("lzbt-systemd")?;
     let output = cmd
         .env("LANZABOOTE_STUB", test_systemd_stub)
@@ -169,8 +172,14 @@ pub fn lanzaboote_install(
         .arg("tests/fixtures/uefi-keys/db.pem")
         .arg("--private-key")
         .arg("tests/fixtures/uefi-keys/db.key")
+        // The fixtures are checked out readable by everyone.
+        .arg("--insecure-permissions")
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .arg("--lock-file")
+        .arg(lock_dir.path().join("lzbt.lock"))
+        // The ESP is a plain directory and the tests may not run on a UEFI system.
+        .arg("--force")
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -1,3 +1,5 @@
+use std::ffi::OsStr;
+
 use anyhow::Result;
 use base32ct::{Base32Unpadded, Encoding};
 use tempfile::tempdir;
@@ -34,6 +36,28 @@ fn do_not_install_duplicates() -> Result<()> {
     Ok(())
 }
 
+/// Install the generations of a profile without listing them.
+#[test]
+fn install_generations_of_profile() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let profile = profiles.path().join("system");
+
+    let output = common::lanzaboote_install(
+        0,
+        esp.path(),
+        [OsStr::new("--profile"), profile.as_os_str()],
+    )?;
+    assert!(output.status.success());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 2);
+    Ok(())
+}
+
 #[test]
 fn do_not_overwrite_images() -> Result<()> {
     let esp = tempdir()?;
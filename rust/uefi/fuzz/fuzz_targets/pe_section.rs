@@ -0,0 +1,14 @@
+//! The stub parses its own image, but also the PE files of addons that it loads from the ESP,
+//! which anyone with physical access can replace.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use linux_bootloader::embedded_config::{FatConfiguration, ThinConfiguration};
+use linux_bootloader::pe_section::pe_sections;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = pe_sections(data);
+    let _ = ThinConfiguration::new(data);
+    let _ = FatConfiguration::new(data);
+});
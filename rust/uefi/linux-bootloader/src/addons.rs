@@ -0,0 +1,160 @@
+//! Addons are small signed PE binaries that extend a boot entry without re-signing it.
+//!
+//! Like systemd-stub's addons, they are named `*.addon.efi` and contain a `.cmdline` section,
+//! which is appended to the kernel command line, and/or an `.initrd` section, which is passed to
+//! the kernel as an additional initrd. Global addons in `\loader\addons` apply to all entries,
+//! while the addons in the drop-in directory of the booted image only apply to that image.
+//!
+//! Addons are verified by the firmware, unless certificates are pinned in the stub. Then they
+//! must be signed by these certificates instead, see [`crate::pkcs7`]. If Secure Boot is only
+//! simulated, the firmware accepts any binary, so addons are only used with pinned certificates.
+
+use alloc::vec::Vec;
+use log::warn;
+use uefi::{
+    boot::{self, LoadImageSource},
+    cstr16,
+    fs::{FileSystem, Path, PathBuf},
+};
+
+use crate::{
+    cmdline_template::append_cmdline, companions::find_files, pe_section::pe_section,
+    pkcs7::TrustPolicy,
+};
+
+/// A verified addon.
+pub struct Addon {
+    /// The path of the addon, for diagnostics.
+    pub path: PathBuf,
+    /// Additional kernel command line, as UTF-8.
+    pub cmdline: Option<Vec<u8>>,
+    /// An additional initrd.
+    pub initrd: Option<Vec<u8>>,
+}
+
+/// Verify the signature of a PE binary with the firmware.
+///
+/// If Secure Boot is active, `LoadImage()` only succeeds for binaries that are allowed by `db`
+/// and `dbx`. The image is never started.
+fn verify_with_firmware(data: &[u8]) -> uefi::Result<()> {
+    let handle = boot::load_image(
+        boot::image_handle(),
+        LoadImageSource::FromBuffer {
+            buffer: data,
+            file_path: None,
+        },
+    )?;
+    boot::unload_image(handle)
+}
+
+/// Parse the sections of an addon.
+///
+/// Addons must not contain a kernel, because that would allow booting an arbitrary kernel with
+/// the command line of the entry.
+fn parse_addon(path: PathBuf, data: &[u8]) -> Option<Addon> {
+    if pe_section(data, ".linux").is_some() {
+        warn!("Ignoring addon {path} because it contains a kernel.");
+        return None;
+    }
+
+    let cmdline = pe_section(data, ".cmdline").map(|c| {
+        // Trailing newlines and NUL bytes are not part of the command line.
+        let end = c
+            .iter()
+            .rposition(|b| !matches!(b, b'\0' | b'\n' | b' '))
+            .map_or(0, |i| i + 1);
+        c[..end].to_vec()
+    });
+    let initrd = pe_section(data, ".initrd").map(<[u8]>::to_vec);
+    if cmdline.is_none() && initrd.is_none() {
+        warn!("Ignoring addon {path} because it contains neither a command line nor an initrd.");
+        return None;
+    }
+
+    Some(Addon {
+        path,
+        cmdline,
+        initrd,
+    })
+}
+
+/// Discover, verify and parse all addons in a drop-in directory.
+///
+/// Addons that fail verification are skipped. The addons are sorted by their path, so that they
+/// are applied and measured in a stable order.
+///
+/// `secure_boot_simulated` is set if the stub enforces Secure Boot without the firmware.
+pub fn discover_addons(
+    fs: &mut FileSystem,
+    dropin_dir: &Path,
+    trust_policy: Option<&TrustPolicy>,
+    secure_boot_simulated: bool,
+) -> uefi::Result<Vec<Addon>> {
+    let mut paths = find_files(fs, dropin_dir, ".addon.efi")?;
+    paths.sort();
+
+    let mut addons = Vec::new();
+    for path in paths {
+        let Ok(data) = fs.read(&*path) else {
+            warn!("Failed to read addon {path}.");
+            continue;
+        };
+        let verified = match trust_policy {
+            Some(trust_policy) => trust_policy.verify_pe(&data),
+            None if secure_boot_simulated => {
+                warn!("Ignoring addon {path}, because Secure Boot is only simulated and no certificates are pinned to verify it.");
+                continue;
+            }
+            None => verify_with_firmware(&data),
+        };
+        if let Err(err) = verified {
+            warn!("Ignoring addon {path}, because it failed verification: {err}");
+            continue;
+        }
+        addons.extend(parse_addon(path, &data));
+    }
+
+    Ok(addons)
+}
+
+/// Discover, verify and parse the addons that apply to all entries.
+///
+/// These are applied before the addons of the booted image, so that entries can override them.
+pub fn discover_global_addons(
+    fs: &mut FileSystem,
+    trust_policy: Option<&TrustPolicy>,
+    secure_boot_simulated: bool,
+) -> uefi::Result<Vec<Addon>> {
+    let global_dropin_dir = cstr16!("\\loader\\addons");
+    let is_directory =
+        matches!(fs.metadata(global_dropin_dir), Ok(metadata) if metadata.is_directory());
+    if !is_directory {
+        return Ok(Vec::new());
+    }
+
+    discover_addons(
+        fs,
+        global_dropin_dir.as_ref(),
+        trust_policy,
+        secure_boot_simulated,
+    )
+}
+
+/// Append the command lines of addons to a kernel command line.
+///
+/// The command line is UTF-16 encoded and may be NUL-terminated, as the load options of an
+/// image. The result is always NUL-terminated.
+pub fn extend_cmdline(cmdline: &[u8], addons: &[Addon]) -> Vec<u8> {
+    let fragments = addons
+        .iter()
+        .filter_map(|a| a.cmdline.as_ref())
+        .filter_map(|fragment| {
+            let fragment = core::str::from_utf8(fragment).ok();
+            if fragment.is_none() {
+                warn!("Ignoring the command line of an addon, because it is not valid UTF-8.");
+            }
+            fragment
+        });
+
+    append_cmdline(cmdline, fragments)
+}
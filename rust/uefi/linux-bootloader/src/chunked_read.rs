@@ -0,0 +1,91 @@
+//! Reading large files, such as the kernel and the initrd, in chunks.
+//!
+//! A file is read into a buffer of exactly its size and hashed while it is read, so that huge
+//! initrds neither need a second pass for hashing nor a growing buffer. For files that take a
+//! noticeable time to read, the progress is shown on the console.
+
+use alloc::{vec, vec::Vec};
+use core::fmt::Write;
+use log::LevelFilter;
+use sha2::{digest::Output, Digest, Sha256};
+use uefi::{
+    proto::media::file::{Directory, File, FileAttribute, FileInfo, FileMode},
+    system, CStr16, Result, Status,
+};
+
+/// The amount of data that is read and hashed at once.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Files from this size on show their progress.
+const PROGRESS_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// A progress display on a single console line.
+struct Progress<'a> {
+    name: &'a str,
+    percent: Option<usize>,
+}
+
+impl Progress<'_> {
+    fn update(&mut self, done: usize, total: usize) {
+        let percent = done * 100 / total;
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            let name = self.name;
+            system::with_stdout(|stdout| {
+                let _ = write!(stdout, "\rReading {name}: {percent:3}%");
+            });
+        }
+    }
+
+    fn finish(&self) {
+        system::with_stdout(|stdout| {
+            let _ = writeln!(stdout);
+        });
+    }
+}
+
+/// Read a file and compute its SHA256 hash.
+///
+/// `name` is shown in the progress display, e.g. `initrd`. The progress is only shown if the stub
+/// logs informational messages.
+pub fn read_hashed(
+    volume: &mut Directory,
+    path: &CStr16,
+    name: &str,
+) -> Result<(Vec<u8>, Output<Sha256>)> {
+    let mut file = volume
+        .open(path, FileMode::Read, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(Status::INVALID_PARAMETER)?;
+    let size = usize::try_from(file.get_boxed_info::<FileInfo>()?.file_size())
+        .map_err(|_| Status::OUT_OF_RESOURCES)?;
+
+    let mut data = vec![0; size];
+    let mut hasher = Sha256::new();
+    let mut progress = (size >= PROGRESS_THRESHOLD && log::max_level() >= LevelFilter::Info)
+        .then_some(Progress {
+            name,
+            percent: None,
+        });
+
+    let mut offset = 0;
+    while offset < size {
+        let chunk = &mut data[offset..size.min(offset + CHUNK_SIZE)];
+        let read = file.read(chunk)?;
+        if read == 0 {
+            // The file shrank while it was read.
+            return Err(Status::END_OF_FILE.into());
+        }
+        hasher.update(&chunk[..read]);
+        offset += read;
+
+        if let Some(progress) = &mut progress {
+            progress.update(offset, size);
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+
+    Ok((data, hasher.finalize()))
+}
@@ -0,0 +1,148 @@
+//! A sanity check of the firmware clock.
+//!
+//! A dead CMOS battery resets the clock to a date long before the generation was built. The
+//! kernel then starts with this date, so that certificates are not valid yet and TPM policies
+//! that depend on the time fail in confusing ways. lzbt embeds the build date of the generation
+//! into the `.notbefore` section, which is a lower bound for the current time. With `.setclock`,
+//! the stub also moves the clock forward to this date.
+
+use log::warn;
+use uefi::{
+    runtime::{self, Daylight, Time, TimeParams},
+    Result, Status,
+};
+
+/// How far the clock may lag behind the build date, e.g. due to time zones, in seconds.
+const TOLERANCE: u64 = 24 * 60 * 60;
+
+/// The days between 1970-01-01 and a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of a number of days since 1970-01-01, as (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The seconds since the Unix epoch of a firmware time. The time zone is ignored.
+fn unix_timestamp(time: &Time) -> i64 {
+    let days = days_from_civil(
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+    days * 86400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second())
+}
+
+/// The firmware time of a Unix timestamp, in an unspecified time zone.
+fn time_from_unix(timestamp: u64) -> Option<Time> {
+    let timestamp = i64::try_from(timestamp).ok()?;
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let seconds = timestamp.rem_euclid(86400);
+    Time::new(TimeParams {
+        year: u16::try_from(year).ok()?,
+        month: month as u8,
+        day: day as u8,
+        hour: (seconds / 3600) as u8,
+        minute: (seconds / 60 % 60) as u8,
+        second: (seconds % 60) as u8,
+        nanosecond: 0,
+        time_zone: None,
+        daylight: Daylight::empty(),
+    })
+    .ok()
+}
+
+/// Whether the clock lags more than [`TOLERANCE`] behind `not_before`.
+fn is_behind(now: i64, not_before: u64) -> bool {
+    match i64::try_from(not_before.saturating_sub(TOLERANCE)) {
+        Ok(bound) => now < bound,
+        Err(_) => false,
+    }
+}
+
+/// Warn if the firmware clock is before `not_before`, a Unix timestamp, and move it forward to
+/// `not_before` if `set_clock` is true.
+///
+/// A clock that cannot be read is only reported, because it cannot be fixed either.
+pub fn check_clock(not_before: u64, set_clock: bool) -> Result<()> {
+    let now = match runtime::get_time() {
+        Ok(now) => now,
+        Err(err) => {
+            warn!("Failed to read the firmware clock: {}", err.status());
+            return Ok(());
+        }
+    };
+    if !is_behind(unix_timestamp(&now), not_before) {
+        return Ok(());
+    }
+
+    warn!(
+        "The firmware clock ({:04}-{:02}-{:02}) is before this generation was built. Certificates \
+         and TPM policies may not work until the clock is corrected.",
+        now.year(),
+        now.month(),
+        now.day()
+    );
+    if set_clock {
+        let time = time_from_unix(not_before).ok_or(Status::INVALID_PARAMETER)?;
+        warn!(
+            "Setting the firmware clock to {:04}-{:02}-{:02}.",
+            time.year(),
+            time.month(),
+            time.day()
+        );
+        // SAFETY: The stub is the only code running, so the clock is not accessed concurrently.
+        unsafe { runtime::set_time(&time) }?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2026, 10, 15), 20741);
+        for days in [0, 11016, 11017, 20741, -1] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+
+        let time = time_from_unix(1_792_000_000).unwrap();
+        assert_eq!(unix_timestamp(&time), 1_792_000_000);
+    }
+
+    #[test]
+    fn tolerate_a_day_of_lag() {
+        let not_before = 1_792_000_000;
+        assert!(!is_behind(not_before as i64 - 3600, not_before));
+        assert!(is_behind(
+            not_before as i64 - 2 * TOLERANCE as i64,
+            not_before
+        ));
+        assert!(!is_behind(0, 0));
+    }
+}
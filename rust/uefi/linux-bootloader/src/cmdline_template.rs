@@ -0,0 +1,389 @@
+//! Placeholders in the embedded kernel command line that the stub substitutes at boot.
+//!
+//! This allows a single signed stub to carry host-identifying parameters. The supported
+//! placeholders are:
+//!
+//! - `${machine-id}`: the SMBIOS system UUID as 32 lowercase hex digits, which is how systemd
+//!   derives the machine ID in VMs.
+//! - `${esp-partuuid}`: the UUID of the partition the stub was loaded from.
+//! - `${entry}`: the file name of the stub, e.g. `nixos-generation-42-<hash>.efi`.
+//!
+//! Values that contain anything but ASCII alphanumerics and `-_.+` are not substituted, so that
+//! they cannot add parameters, e.g. by renaming the stub. Placeholders without a value are kept
+//! as is.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::warn;
+use uefi::{boot, proto::loaded_image::LoadedImage};
+
+use crate::{
+    efivars::{disk_get_part_uuid, image_file_name},
+    smbios::system_uuid,
+};
+
+/// The values of the placeholders on this boot.
+#[derive(Default)]
+pub struct TemplateVariables {
+    machine_id: Option<String>,
+    esp_partuuid: Option<String>,
+    entry: Option<String>,
+}
+
+impl TemplateVariables {
+    /// Collect the values of the placeholders from the firmware.
+    pub fn from_firmware() -> Self {
+        let machine_id = system_uuid().map(|uuid| {
+            uuid.to_ascii_hex_lower()
+                .iter()
+                .filter(|c| **c != b'-')
+                .map(|c| char::from(*c))
+                .collect()
+        });
+
+        let Ok(loaded_image) = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+        else {
+            return Self {
+                machine_id,
+                ..Self::default()
+            };
+        };
+        let esp_partuuid = loaded_image
+            .device()
+            .and_then(|device| disk_get_part_uuid(device).ok())
+            .map(|uuid| uuid.to_string());
+        let entry = loaded_image
+            .file_path()
+            .and_then(|path| image_file_name(path).ok())
+            .map(|name| name.to_string());
+
+        Self {
+            machine_id,
+            esp_partuuid,
+            entry,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "machine-id" => self.machine_id.as_deref(),
+            "esp-partuuid" => self.esp_partuuid.as_deref(),
+            "entry" => self.entry.as_deref(),
+            _ => {
+                warn!("Unknown placeholder ${{{name}}} in the command line.");
+                None
+            }
+        }
+    }
+
+    /// Substitute the placeholders in a command line.
+    pub fn expand(&self, template: &str) -> String {
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            let Some(length) = rest[start..].find('}') else {
+                rest = &rest[start..];
+                break;
+            };
+            let placeholder = &rest[start..start + length + 1];
+            let name = &placeholder[2..placeholder.len() - 1];
+
+            match self.get(name) {
+                Some(value) if is_safe(value) => expanded.push_str(value),
+                Some(_) => {
+                    warn!("Not substituting ${{{name}}}, because its value is not safe.");
+                    expanded.push_str(placeholder);
+                }
+                None => expanded.push_str(placeholder),
+            }
+            rest = &rest[start + length + 1..];
+        }
+        expanded.push_str(rest);
+
+        expanded
+    }
+}
+
+/// Whether a value can be substituted without changing the structure of the command line.
+fn is_safe(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'+'))
+}
+
+/// Whether a command line contains placeholders.
+pub fn has_placeholders(cmdline: &str) -> bool {
+    cmdline.contains("${")
+}
+
+/// Append fragments, separated by spaces, to a command line.
+///
+/// The command line is UTF-16 encoded and may be NUL-terminated, as the load options of an
+/// image. The result is always NUL-terminated.
+pub fn append_cmdline<'a>(cmdline: &[u8], fragments: impl IntoIterator<Item = &'a str>) -> Vec<u8> {
+    let mut utf16 = cmdline
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<u16>>();
+    while utf16.last() == Some(&0) {
+        utf16.pop();
+    }
+
+    for fragment in fragments {
+        if fragment.is_empty() {
+            continue;
+        }
+        if !utf16.is_empty() {
+            utf16.push(u16::from(b' '));
+        }
+        utf16.extend(fragment.encode_utf16());
+    }
+    utf16.push(0);
+
+    utf16.into_iter().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Decode a UTF-16 command line up to its NUL terminator.
+fn from_utf16_bytes(cmdline: &[u8]) -> String {
+    let utf16 = cmdline
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<u16>>();
+    String::from_utf16_lossy(&utf16)
+}
+
+/// The kernel parameters of a command line, split like the kernel's `next_arg()`.
+struct KernelParameters<'a> {
+    /// The names and values of the parameters, without their quotes.
+    parameters: Vec<(&'a str, Option<&'a str>)>,
+    /// The offset of `--`, after which the arguments are passed to init.
+    end: Option<usize>,
+    /// Whether the command line ends within double quotes.
+    open_quote: bool,
+}
+
+impl<'a> KernelParameters<'a> {
+    fn parse(cmdline: &'a str) -> Self {
+        let bytes = cmdline.as_bytes();
+        let mut parameters = Vec::new();
+        let mut position = 0;
+        loop {
+            while matches!(bytes.get(position), Some(byte) if byte.is_ascii_whitespace()) {
+                position += 1;
+            }
+            if position == bytes.len() {
+                return Self {
+                    parameters,
+                    end: None,
+                    open_quote: false,
+                };
+            }
+
+            // Double quotes protect whitespace, e.g. `param="a b"` or `"param=a b"`.
+            let start = position;
+            let mut in_quote = false;
+            let mut equals = None;
+            while let Some(&byte) = bytes.get(position) {
+                if byte.is_ascii_whitespace() && !in_quote {
+                    break;
+                }
+                if byte == b'=' && equals.is_none() {
+                    equals = Some(position);
+                }
+                if byte == b'"' {
+                    in_quote = !in_quote;
+                }
+                position += 1;
+            }
+
+            let argument = &cmdline[start..position];
+            let quoted = argument.starts_with('"');
+            let (name, value) = match equals {
+                Some(equals) => (
+                    &cmdline[start..equals],
+                    Some(&cmdline[equals + 1..position]),
+                ),
+                None => (argument, None),
+            };
+            let name = if quoted { &name[1..] } else { name };
+            let strip_end_quote = |s: &'a str, quoted| match s.strip_suffix('"') {
+                Some(stripped) if quoted => stripped,
+                _ => s,
+            };
+            let parameter = match value {
+                Some(value) => {
+                    let value_quoted = value.starts_with('"');
+                    let value = value.strip_prefix('"').unwrap_or(value);
+                    (name, Some(strip_end_quote(value, quoted || value_quoted)))
+                }
+                None => (strip_end_quote(name, quoted), None),
+            };
+
+            if parameter == ("--", None) {
+                return Self {
+                    parameters,
+                    end: Some(start),
+                    open_quote: false,
+                };
+            }
+            parameters.push(parameter);
+            if in_quote {
+                return Self {
+                    parameters,
+                    end: None,
+                    open_quote: true,
+                };
+            }
+        }
+    }
+}
+
+/// Whether two parameter names are the same. Like the kernel and systemd, dashes and
+/// underscores are not distinguished.
+fn same_name(a: &str, b: &str) -> bool {
+    let normalize = |c| if c == '-' { '_' } else { c };
+    a.len() == b.len() && a.chars().map(normalize).eq(b.chars().map(normalize))
+}
+
+/// The value of the last occurrence of a kernel parameter, e.g. `roothash`, in a UTF-16 command
+/// line.
+///
+/// The kernel and systemd use the last occurrence of most parameters. Arguments after `--` are
+/// passed to init and are ignored.
+pub fn last_value(cmdline: &[u8], name: &str) -> Option<String> {
+    let cmdline = from_utf16_bytes(cmdline);
+    KernelParameters::parse(&cmdline)
+        .parameters
+        .into_iter()
+        .rev()
+        .find(|(parameter, _)| same_name(parameter, name))
+        .and_then(|(_, value)| value.map(String::from))
+}
+
+/// Add kernel parameters to a UTF-16 command line, so that they come after all other kernel
+/// parameters.
+///
+/// They are inserted before `--`, which passes the following arguments to init, and an
+/// unterminated quote is closed first. The result is always NUL-terminated.
+pub fn insert_parameters<'a>(
+    cmdline: &[u8],
+    parameters: impl IntoIterator<Item = &'a str>,
+) -> Vec<u8> {
+    let cmdline = from_utf16_bytes(cmdline);
+    let parsed = KernelParameters::parse(&cmdline);
+    let (kernel, init) = cmdline.split_at(parsed.end.unwrap_or(cmdline.len()));
+
+    let mut inserted = String::from(kernel.trim_end());
+    if parsed.open_quote {
+        inserted.push('"');
+    }
+    for parameter in parameters {
+        if !inserted.is_empty() {
+            inserted.push(' ');
+        }
+        inserted.push_str(parameter);
+    }
+    if !init.is_empty() {
+        inserted.push(' ');
+        inserted.push_str(init);
+    }
+    to_utf16_bytes(&inserted)
+}
+
+/// Encode a command line as NUL-terminated UTF-16, like the load options of an image.
+pub fn to_utf16_bytes(cmdline: &str) -> Vec<u8> {
+    cmdline
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> TemplateVariables {
+        TemplateVariables {
+            machine_id: Some("0123456789abcdef0123456789abcdef".to_string()),
+            esp_partuuid: None,
+            entry: Some("nixos-generation-42.efi".to_string()),
+        }
+    }
+
+    #[test]
+    fn expand_known_placeholders() {
+        assert_eq!(
+            variables().expand("systemd.machine_id=${machine-id} entry=${entry} quiet"),
+            "systemd.machine_id=0123456789abcdef0123456789abcdef entry=nixos-generation-42.efi quiet"
+        );
+    }
+
+    #[test]
+    fn keep_placeholders_without_value() {
+        // Unknown placeholders and placeholders without a value on this boot are kept.
+        assert_eq!(
+            variables().expand("a=${unknown} b=${esp-partuuid}"),
+            "a=${unknown} b=${esp-partuuid}"
+        );
+    }
+
+    #[test]
+    fn keep_unterminated_placeholder() {
+        assert_eq!(
+            variables().expand("a=${entry} b=${machine-id"),
+            "a=nixos-generation-42.efi b=${machine-id"
+        );
+    }
+
+    #[test]
+    fn do_not_substitute_unsafe_values() {
+        let variables = TemplateVariables {
+            entry: Some("x init=/bin/sh".to_string()),
+            ..TemplateVariables::default()
+        };
+        assert_eq!(variables.expand("entry=${entry}"), "entry=${entry}");
+
+        assert!(is_safe("nixos-generation-1+2_3.efi"));
+        assert!(!is_safe(""));
+        assert!(!is_safe("a\"b"));
+        assert!(!is_safe("a=b"));
+    }
+
+    #[test]
+    fn last_value_of_kernel_parameters() {
+        let cmdline = to_utf16_bytes(
+            "roothash=ab \"roothash=cd\" x=\"roothash=ef\" module.sig-enforce=1 -- roothash=01",
+        );
+        assert_eq!(last_value(&cmdline, "roothash").as_deref(), Some("cd"));
+        assert_eq!(
+            last_value(&cmdline, "module.sig_enforce").as_deref(),
+            Some("1")
+        );
+        assert_eq!(last_value(&cmdline, "x").as_deref(), Some("roothash=ef"));
+        assert_eq!(last_value(&cmdline, "root"), None);
+        assert_eq!(
+            last_value(&to_utf16_bytes("a=\"b c\" a=d"), "a").as_deref(),
+            Some("d")
+        );
+    }
+
+    #[test]
+    fn insert_parameters_before_init_arguments() {
+        let insert = |cmdline: &str| {
+            from_utf16_bytes(&insert_parameters(&to_utf16_bytes(cmdline), ["a=1", "b=2"]))
+        };
+        assert_eq!(insert(""), "a=1 b=2");
+        assert_eq!(insert("quiet "), "quiet a=1 b=2");
+        assert_eq!(insert("quiet -- single"), "quiet a=1 b=2 -- single");
+        // A quoted `--` is a value.
+        assert_eq!(insert("x=\"--\" -- -a"), "x=\"--\" a=1 b=2 -- -a");
+        assert_eq!(insert("x=\"y -- z"), "x=\"y -- z\" a=1 b=2");
+    }
+}
@@ -1,4 +1,8 @@
-use crate::cpio::{pack_cpio, Cpio};
+use crate::{
+    cpio::{pack_cpio, Cpio},
+    pkcs7::TrustPolicy,
+    zeroize::zeroize,
+};
 use alloc::{string::ToString, vec::Vec};
 use uefi::{
     cstr16,
@@ -79,16 +83,56 @@ pub struct CompanionInitrd {
     pub cpio: Cpio,
 }
 
+/// Keep only the credentials that are signed by the pinned certificates.
+///
+/// The signature of a credential is a detached DER encoded PKCS#7 signature next to it, i.e.
+/// `foo.cred` is signed by `foo.cred.p7s`. To meet a threshold, several signatures can be
+/// concatenated in this file. Without pinned certificates, all credentials are kept.
+fn verified_credentials(
+    fs: &mut uefi::fs::FileSystem,
+    credentials: Vec<PathBuf>,
+    trust_policy: Option<&TrustPolicy>,
+) -> Vec<PathBuf> {
+    let Some(trust_policy) = trust_policy else {
+        return credentials;
+    };
+
+    credentials
+        .into_iter()
+        .filter(|credential| {
+            let mut signature_path = CString16::from(credential.to_cstr16());
+            signature_path.push_str(cstr16!(".p7s"));
+            let signature_path = PathBuf::from(signature_path);
+
+            let verified = match (fs.read(&**credential), fs.read(&*signature_path)) {
+                (Ok(mut data), Ok(signature)) => {
+                    let verified = trust_policy.verify_detached(&signature, &data).is_ok();
+                    zeroize(&mut data);
+                    verified
+                }
+                _ => false,
+            };
+            if !verified {
+                log::warn!("Ignoring credential {credential}, because it is not correctly signed.");
+            }
+            verified
+        })
+        .collect()
+}
+
 /// Collect all credentials and return them as CPIO archive.
 ///
 /// There are two variants of credentials:
 ///   - global: `$ESP/loader.credentials/*.cred`
 ///   - image-specific: `$path_to_image.extra/*.cred`
 ///
+/// If certificates are pinned, only credentials signed by them are collected.
+///
 /// The credentials are not measured.
 pub fn discover_credentials(
     fs: &mut uefi::fs::FileSystem,
     default_dropin_dir: Option<&Path>,
+    trust_policy: Option<&TrustPolicy>,
 ) -> uefi::Result<Vec<CompanionInitrd>> {
     let mut companions = Vec::new();
 
@@ -99,8 +143,9 @@ pub fn discover_credentials(
             uefi::Error::new(uefi::Status::VOLUME_CORRUPTED, ())
         })?;
         if metadata.is_directory() {
+            let global_credentials = find_files(fs, default_global_dropin_dir.as_ref(), ".cred")?;
             let global_credentials: Vec<PathBuf> =
-                find_files(fs, default_global_dropin_dir.as_ref(), ".cred")?;
+                verified_credentials(fs, global_credentials, trust_policy);
 
             if !global_credentials.is_empty() {
                 companions.push(CompanionInitrd {
@@ -119,7 +164,9 @@ pub fn discover_credentials(
     }
 
     if let Some(default_dropin_dir) = default_dropin_dir {
-        let local_credentials: Vec<PathBuf> = find_files(fs, default_dropin_dir, ".cred")?;
+        let local_credentials = find_files(fs, default_dropin_dir, ".cred")?;
+        let local_credentials: Vec<PathBuf> =
+            verified_credentials(fs, local_credentials, trust_policy);
 
         if !local_credentials.is_empty() {
             companions.push(CompanionInitrd {
@@ -0,0 +1,302 @@
+//! Text output of the stub that copes with the limits of firmware consoles.
+//!
+//! Firmware consoles only take UCS-2, and their fonts often lack glyphs beyond ASCII. The text
+//! writer of the uefi crate gives up on the first character outside of UCS-2 and drops the rest of
+//! the text after a glyph that the font lacks, which truncates e.g. non-ASCII NixOS labels or
+//! translated messages. This module replaces characters outside of UCS-2, skips missing glyphs and
+//! wraps long lines, e.g. command lines, at spaces instead of leaving that to the firmware.
+//!
+//! Output can be mirrored to the serial ports of the firmware, so that e.g. verification failures
+//! are visible over IPMI Serial-over-LAN on headless servers, see [`mirror_to_serial`].
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, LevelFilter, Log, Metadata, Record};
+use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
+    proto::{
+        console::{
+            serial::Serial,
+            text::{Output, OutputMode},
+        },
+        device_path::{DevicePath, DeviceSubType, DeviceType},
+        ProtocolPointer,
+    },
+    system, CStr16, Handle,
+};
+
+use crate::log_file;
+
+/// Replaces characters that UCS-2 cannot represent.
+const REPLACEMENT_CHARACTER: u16 = b'?' as u16;
+
+/// The width of the console if the firmware does not report it.
+const DEFAULT_COLUMNS: usize = 80;
+
+/// The number of UCS-2 characters that are written to the console at once.
+const BUFFER_SIZE: usize = 128;
+
+/// Whether output is also written to the serial ports.
+static MIRROR_TO_SERIAL: AtomicBool = AtomicBool::new(false);
+
+/// Open a protocol without taking it away from the drivers that use it.
+pub(crate) fn open_shared<P: ProtocolPointer + ?Sized>(
+    handle: Handle,
+) -> uefi::Result<boot::ScopedProtocol<P>> {
+    // SAFETY: The protocols are only used briefly, while no driver is started or stopped.
+    unsafe {
+        boot::open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+}
+
+/// Check whether the firmware console already includes a serial terminal.
+///
+/// The terminal driver of the firmware installs a text output on top of each serial port it
+/// drives, whose device path ends in a UART node.
+fn console_on_serial() -> bool {
+    let Ok(handles) = boot::find_handles::<Output>() else {
+        return false;
+    };
+    handles.into_iter().any(|handle| {
+        open_shared::<DevicePath>(handle).map_or(false, |device_path| {
+            device_path.node_iter().any(|node| {
+                node.device_type() == DeviceType::MESSAGING
+                    && node.sub_type() == DeviceSubType::MESSAGING_UART
+            })
+        })
+    })
+}
+
+/// Mirror all following output to the serial ports of the firmware.
+///
+/// Unless `force` is set, output is only mirrored if the firmware console does not include a
+/// serial terminal already, as otherwise every line would show up twice.
+pub fn mirror_to_serial(force: bool) {
+    let has_serial = boot::find_handles::<Serial>().map_or(false, |handles| !handles.is_empty());
+    if has_serial && (force || !console_on_serial()) {
+        MIRROR_TO_SERIAL.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Write `text` to all serial ports.
+fn write_serial(text: &str) {
+    let Ok(handles) = boot::find_handles::<Serial>() else {
+        return;
+    };
+    for handle in handles {
+        let Ok(mut serial) = open_shared::<Serial>(handle) else {
+            continue;
+        };
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                let _ = serial.write(b"\r\n");
+            }
+            let _ = serial.write(line.as_bytes());
+        }
+    }
+}
+
+/// Switch to the text mode with the most rows and columns.
+///
+/// Firmware often starts in 80x25 even if the display fits much more text. Switching the mode
+/// clears the screen.
+pub fn select_best_mode() {
+    system::with_stdout(|stdout| {
+        let best = stdout
+            .modes()
+            .max_by_key(|mode| mode.columns() * mode.rows());
+        let Some(best) = best else {
+            return;
+        };
+        let current = stdout.current_mode().ok().flatten();
+        if current.as_ref().map(OutputMode::index) == Some(best.index()) {
+            return;
+        }
+        match stdout.set_mode(best) {
+            Ok(()) => debug!("Switched to a text mode with {} columns.", best.columns()),
+            Err(err) => debug!("Failed to switch the text mode: {}", err.status()),
+        }
+    });
+}
+
+/// The number of characters that fit on a line of the console.
+fn columns() -> usize {
+    system::with_stdout(|stdout| stdout.current_mode().ok().flatten())
+        .map(|mode| mode.columns())
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+/// Write `text` to the console and possibly the serial ports, translating line feeds to the CRLF that consoles expect.
+fn write(text: &str) {
+    let mut buffer = [0u16; BUFFER_SIZE + 1];
+    let mut length = 0;
+    let flush = |buffer: &mut [u16], length: &mut usize| {
+        buffer[*length] = 0;
+        if let Ok(text) = CStr16::from_u16_with_nul(&buffer[..=*length]) {
+            // Characters without a glyph are skipped instead of dropping the rest of the text.
+            let _ = system::with_stdout(|stdout| stdout.output_string_lossy(text));
+        }
+        *length = 0;
+    };
+
+    for c in text.chars() {
+        if c == '\n' {
+            buffer[length] = u16::from(b'\r');
+            length += 1;
+        }
+        buffer[length] = u16::try_from(u32::from(c)).unwrap_or(REPLACEMENT_CHARACTER);
+        length += 1;
+        // Leave space for a CRLF.
+        if length >= BUFFER_SIZE - 1 {
+            flush(&mut buffer, &mut length);
+        }
+    }
+    if length > 0 {
+        flush(&mut buffer, &mut length);
+    }
+
+    // Serial terminals understand UTF-8, so the text is sent as it is.
+    if MIRROR_TO_SERIAL.load(Ordering::Relaxed) {
+        write_serial(text);
+    }
+}
+
+/// Break `text` into lines of at most `width` characters at spaces.
+///
+/// Lines that fit are kept as they are, e.g. the logo. Continuation lines are indented by `indent`
+/// spaces, the indentation of the first line is kept. Words that do not fit on a line on their own
+/// are split.
+pub fn wrap(text: &str, width: usize, indent: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        if paragraph.chars().count() <= width {
+            lines.push(paragraph.to_string());
+            continue;
+        }
+
+        let leading = paragraph.len() - paragraph.trim_start_matches(' ').len();
+        let mut line = format!("{:leading$}", "");
+        let mut start = leading;
+        let mut length = leading;
+
+        for word in paragraph.split_whitespace() {
+            let mut word_length = word.chars().count();
+            if length > start && length + 1 + word_length > width {
+                lines.push(core::mem::replace(&mut line, format!("{:indent$}", "")));
+                start = indent;
+                length = indent;
+            }
+            if length > start {
+                line.push(' ');
+                length += 1;
+            }
+
+            let mut chars = word.chars();
+            // Only split words if a line has space for more than the indentation.
+            while width > start + 1 && length + word_length > width {
+                let fitting = width - length;
+                line.extend(chars.by_ref().take(fitting));
+                word_length -= fitting;
+                lines.push(core::mem::replace(&mut line, format!("{:indent$}", "")));
+                start = indent;
+                length = indent;
+            }
+            line.extend(chars);
+            length += word_length;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Print `text` and a line feed, wrapped to the width of the console.
+///
+/// Continuation lines are indented by `indent` spaces.
+pub fn print_wrapped(text: &str, indent: usize) {
+    // Writing into the last column makes some firmware wrap the line on its own.
+    for line in wrap(text, columns().saturating_sub(1), indent) {
+        write(&line);
+        write("\n");
+    }
+}
+
+/// Logs to the console through [`print_wrapped`].
+///
+/// Records are prefixed with their level, and at debug verbosity also with their origin.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        // The maximum level is already checked by the log macros.
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut message = format!("[{:>5}]: ", record.level());
+        let indent = message.len();
+        if log::max_level() >= LevelFilter::Debug {
+            let _ = write!(
+                message,
+                "{}@{:03}: ",
+                record.file().unwrap_or("<unknown file>"),
+                record.line().unwrap_or(0)
+            );
+        }
+        let _ = write!(message, "{}", record.args());
+        log_file::append(&message);
+        print_wrapped(&message, indent);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Log to the console.
+///
+/// Nothing must be logged after boot services have been exited, which the stub leaves to the
+/// kernel.
+pub fn init_logger() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(log::STATIC_MAX_LEVEL);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn wrap_at_spaces() {
+        assert_eq!(
+            wrap("  .cmdline: init=/init quiet loglevel=3", 20, 4),
+            vec!["  .cmdline:", "    init=/init quiet", "    loglevel=3"]
+        );
+        assert_eq!(
+            wrap("[ WARN]: Ünïcödé is kept intact", 20, 9),
+            vec!["[ WARN]: Ünïcödé is", "         kept intact"]
+        );
+        assert_eq!(
+            wrap("root=/dev/disk/by-uuid/0123456789", 16, 2),
+            vec!["root=/dev/disk/b", "  y-uuid/0123456", "  789"]
+        );
+        assert_eq!(
+            wrap(" | |  _ \n  first   second", 10, 0),
+            vec![" | |  _ ", "  first", "second"]
+        );
+    }
+}
@@ -4,6 +4,8 @@ use alloc::{string::String, vec::Vec};
 use pio::errors::CPIOError;
 use uefi::fs::{Path, PathBuf};
 
+use crate::zeroize::zeroize;
+
 pub type Cpio = pio::writer::Cpio<Infallible>;
 pub type Result = core::result::Result<Cpio, CPIOError<Infallible>>;
 
@@ -62,8 +64,11 @@ pub fn pack_cpio(
                 .last()
                 .expect("Expected the filename to possess a file name!"),
         );
-        let contents = fs.read(file).expect("failed to read");
-        cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode)?;
+        let mut contents = fs.read(file).expect("failed to read");
+        let packed = cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode);
+        // The files are usually credentials.
+        zeroize(&mut contents);
+        packed?;
     }
     cpio.pack_trailer()?;
 
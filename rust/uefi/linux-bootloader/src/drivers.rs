@@ -0,0 +1,100 @@
+//! Load UEFI file system drivers from the ESP.
+//!
+//! Firmwares usually only read FAT. To load the kernel and initrd from a partition with another
+//! file system, e.g. ext4 or btrfs, see [`crate::partition`], a stub with a `.drivers` section
+//! first loads the drivers at the paths in the section, one per line, like systemd-boot does with
+//! `EFI/systemd/drivers`. The firmware checks their signatures like for any other image. Then all
+//! controllers are connected again, so that the drivers bind to the partitions they support.
+
+use alloc::vec::Vec;
+use log::{info, warn};
+use uefi::{
+    boot::{self, LoadImageSource, MemoryType, SearchType},
+    proto::{loaded_image::LoadedImage, BootPolicy},
+    CString16, Handle, Result, Status,
+};
+
+use crate::uefi_helpers::file_device_path;
+
+/// The paths of the drivers, one per line of the `.drivers` section.
+pub fn parse_driver_paths(section: &[u8]) -> Option<Vec<&str>> {
+    Some(
+        core::str::from_utf8(section)
+            .ok()?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect(),
+    )
+}
+
+/// Load and start the driver at `path` on the file system of the stub.
+fn load_driver(path: &str) -> Result<()> {
+    let path_name = CString16::try_from(path).map_err(|_| Status::INVALID_PARAMETER)?;
+    let mut buffer = Vec::new();
+    let device_path = file_device_path(&path_name, &mut buffer)?;
+    let handle = boot::load_image(
+        boot::image_handle(),
+        LoadImageSource::FromDevicePath {
+            device_path,
+            boot_policy: BootPolicy::ExactMatch,
+        },
+    )?;
+
+    // Applications would take over the boot instead of returning.
+    let code_type = boot::open_protocol_exclusive::<LoadedImage>(handle)?.code_type();
+    if code_type != MemoryType::BOOT_SERVICES_CODE && code_type != MemoryType::RUNTIME_SERVICES_CODE
+    {
+        let _ = boot::unload_image(handle);
+        return Err(Status::UNSUPPORTED.into());
+    }
+    boot::start_image(handle)
+}
+
+/// Connect all drivers to all controllers, so that newly loaded drivers bind to them.
+///
+/// This also connects the devices that the firmware did not need for its own boot, e.g. network
+/// cards, whose iSCSI initiator then logs in to the targets configured in the firmware or by DHCP.
+pub fn reconnect_all() -> Result<()> {
+    let handles = boot::locate_handle_buffer(SearchType::AllHandles)?;
+    for handle in handles.iter() {
+        // Most handles are not controllers that any driver supports.
+        let _ = boot::connect_controller(*handle, None::<Handle>, None, true);
+    }
+    Ok(())
+}
+
+/// Load the drivers that the `.drivers` section lists.
+///
+/// Drivers that fail to load are skipped, so that the others are still available. Returns the
+/// number of drivers that were loaded. They only bind to controllers after [`reconnect_all`].
+pub fn load_drivers(section: &[u8]) -> Result<usize> {
+    let paths = parse_driver_paths(section).ok_or(Status::INVALID_PARAMETER)?;
+    let mut loaded = 0;
+    for path in paths {
+        info!("Loading the driver {path}...");
+        match load_driver(path) {
+            Ok(()) => loaded += 1,
+            Err(err) => warn!("Failed to load the driver {path}: {}", err.status()),
+        }
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_drivers() {
+        assert_eq!(
+            parse_driver_paths(b"\\EFI\\nixos\\ext4-abc.efi\n\\EFI\\nixos\\btrfs-def.efi\n"),
+            Some(alloc::vec![
+                "\\EFI\\nixos\\ext4-abc.efi",
+                "\\EFI\\nixos\\btrfs-def.efi"
+            ])
+        );
+        assert_eq!(parse_driver_paths(b""), Some(Vec::new()));
+        assert_eq!(parse_driver_paths(&[0xff]), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Starting x86 kernels with the legacy EFI handover protocol.
+//!
+//! Instead of starting the kernel as a PE image, the boot loader fills in `struct boot_params`
+//! with the command line and the initrd and jumps to the handover entry point of the kernel's EFI
+//! stub. This works for kernels whose PE image cannot be loaded, but it is deprecated and newer
+//! kernels may be built without it, see [`SetupHeader::supports_efi_handover`].
+//!
+//! See <https://www.kernel.org/doc/html/latest/arch/x86/boot.html#efi-handover-protocol-deprecated>.
+
+use core::ptr::NonNull;
+
+use uefi::{
+    boot::{self, MemoryType},
+    Handle, Result, Status,
+};
+
+use crate::memory::{allocate, bytes_to_pages, Placement};
+use crate::setup_header::{SetupHeader, SETUP_HEADER_OFFSET};
+use crate::zeroize::zeroize;
+
+/// `struct boot_params` is exactly one page.
+const BOOT_PARAMS_SIZE: usize = 4096;
+
+const EXT_RAMDISK_IMAGE_OFFSET: usize = 0x0c0;
+const EXT_RAMDISK_SIZE_OFFSET: usize = 0x0c4;
+const EXT_CMD_LINE_PTR_OFFSET: usize = 0x0c8;
+const SETUP_SECTS_OFFSET: usize = 0x1f1;
+const TYPE_OF_LOADER_OFFSET: usize = 0x210;
+const CODE32_START_OFFSET: usize = 0x214;
+const RAMDISK_IMAGE_OFFSET: usize = 0x218;
+const RAMDISK_SIZE_OFFSET: usize = 0x21c;
+const CMD_LINE_PTR_OFFSET: usize = 0x228;
+
+/// The boot loader type for loaders without an assigned ID.
+const TYPE_OF_LOADER_UNDEFINED: u8 = 0xff;
+/// The sector size that `setup_sects` counts in.
+const SECTOR_SIZE: usize = 512;
+/// The offset of the 64-bit entry point from the 32-bit one.
+const ENTRY_64_OFFSET: usize = 0x200;
+
+/// Pages that are freed when they go out of scope, i.e. if the kernel returns.
+struct Pages(&'static mut [u8]);
+
+impl Pages {
+    fn new(size: usize, memory_type: MemoryType, placement: Placement) -> Result<Self> {
+        // Empty allocations have no address to pass to the kernel.
+        allocate(size.max(1), memory_type, placement).map(Self)
+    }
+
+    fn address(&self) -> u64 {
+        self.0.as_ptr() as u64
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        // The initrd may contain secrets.
+        zeroize(self.0);
+        let pages = bytes_to_pages(self.0.len());
+        // SAFETY: The pages were allocated by `allocate` and are no longer used.
+        let _ = unsafe { boot::free_pages(NonNull::from(&mut *self.0).cast(), pages) };
+    }
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Split an address into the low 32 bits for the setup header and the high 32 bits for the
+/// extension fields of `struct boot_params`.
+fn split_address(address: u64) -> (u32, u32) {
+    (address as u32, (address >> 32) as u32)
+}
+
+/// Convert the UTF-16 load options into the ASCII command line that the kernel expects.
+///
+/// Characters outside of ASCII are replaced by spaces, like systemd-stub does.
+fn ascii_cmdline(cmdline: &[u8], max_length: usize) -> impl Iterator<Item = u8> + '_ {
+    cmdline
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .take(max_length)
+        .map(|c| {
+            if (0x20..0x7f).contains(&c) {
+                c as u8
+            } else {
+                b' '
+            }
+        })
+}
+
+/// Start a kernel with the EFI handover protocol.
+///
+/// `cmdline` are the UTF-16 load options that would be passed to the PE image. This only returns
+/// if the kernel cannot be started or returns, which it is not supposed to do.
+///
+/// # Safety
+/// The kernel is assumed to be trusted, see [`crate::pe_loader::Image::start`].
+pub unsafe fn start(
+    handle: Handle,
+    kernel_data: &[u8],
+    header: &SetupHeader,
+    cmdline: &[u8],
+    initrd: &[u8],
+) -> Result<()> {
+    if !header.supports_efi_handover() {
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    let setup_sects = match header.setup_sects {
+        // For backwards compatibility, 0 means 4.
+        0 => 4,
+        setup_sects => setup_sects,
+    };
+    let entry_32 = (usize::from(setup_sects) + 1) * SECTOR_SIZE;
+    let entry = entry_32 + ENTRY_64_OFFSET + header.handover_offset as usize;
+    if entry >= kernel_data.len() {
+        return Err(Status::LOAD_ERROR.into());
+    }
+
+    // The kernel decompresses itself in place, so it needs `init_size` bytes, aligned as it asks
+    // for. `code32_start` has no high bits, so the kernel always stays below 4 GiB.
+    let kernel = Pages::new(
+        kernel_data.len().max(header.init_size as usize),
+        MemoryType::LOADER_CODE,
+        header.kernel_placement().below_4g(),
+    )?;
+    kernel.0[..kernel_data.len()].copy_from_slice(kernel_data);
+
+    let data_placement = if header.can_be_loaded_above_4g() {
+        Placement::default()
+    } else {
+        Placement::default().below_4g()
+    };
+    let initrd_pages = Pages::new(
+        initrd.len(),
+        MemoryType::LOADER_DATA,
+        header.initrd_placement(),
+    )?;
+    initrd_pages.0[..initrd.len()].copy_from_slice(initrd);
+
+    let cmdline_length = ascii_cmdline(cmdline, header.cmdline_size as usize).count();
+    let cmdline_pages = Pages::new(cmdline_length + 1, MemoryType::LOADER_DATA, data_placement)?;
+    for (byte, c) in cmdline_pages
+        .0
+        .iter_mut()
+        .zip(ascii_cmdline(cmdline, cmdline_length))
+    {
+        *byte = c;
+    }
+
+    let boot_params = Pages::new(BOOT_PARAMS_SIZE, MemoryType::LOADER_DATA, data_placement)?;
+    let header_range = SETUP_HEADER_OFFSET..SETUP_HEADER_OFFSET + header.size;
+    boot_params.0[header_range.clone()].copy_from_slice(&kernel_data[header_range]);
+    boot_params.0[SETUP_SECTS_OFFSET] = setup_sects;
+    boot_params.0[TYPE_OF_LOADER_OFFSET] = TYPE_OF_LOADER_UNDEFINED;
+    // Old kernels need this, newer ones ignore it.
+    write_u32(
+        boot_params.0,
+        CODE32_START_OFFSET,
+        (kernel.address() + entry_32 as u64) as u32,
+    );
+
+    let (cmdline_low, cmdline_high) = split_address(cmdline_pages.address());
+    write_u32(boot_params.0, CMD_LINE_PTR_OFFSET, cmdline_low);
+    write_u32(boot_params.0, EXT_CMD_LINE_PTR_OFFSET, cmdline_high);
+
+    if !initrd.is_empty() {
+        let (image_low, image_high) = split_address(initrd_pages.address());
+        let (size_low, size_high) = split_address(initrd.len() as u64);
+        write_u32(boot_params.0, RAMDISK_IMAGE_OFFSET, image_low);
+        write_u32(boot_params.0, EXT_RAMDISK_IMAGE_OFFSET, image_high);
+        write_u32(boot_params.0, RAMDISK_SIZE_OFFSET, size_low);
+        write_u32(boot_params.0, EXT_RAMDISK_SIZE_OFFSET, size_high);
+    }
+
+    // SAFETY: The entry point is within the kernel, which the caller trusts.
+    unsafe {
+        jump(
+            kernel.0.as_ptr().add(entry),
+            handle,
+            boot_params.0.as_mut_ptr(),
+        )
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn jump(entry: *const u8, handle: Handle, boot_params: *mut u8) -> Result<()> {
+    // The 64-bit handover entry point uses the System V calling convention.
+    type Handover = unsafe extern "sysv64" fn(Handle, *mut core::ffi::c_void, *mut u8);
+
+    let system_table = uefi::table::system_table_raw().ok_or(Status::NOT_STARTED)?;
+    unsafe {
+        let handover = core::mem::transmute::<*const u8, Handover>(entry);
+        handover(handle, system_table.as_ptr().cast(), boot_params);
+    }
+    Err(Status::LOAD_ERROR.into())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn jump(_entry: *const u8, _handle: Handle, _boot_params: *mut u8) -> Result<()> {
+    Err(Status::UNSUPPORTED.into())
+}
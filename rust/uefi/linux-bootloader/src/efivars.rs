@@ -5,18 +5,21 @@ use uefi::{
     proto::{
         device_path::{
             media::{HardDrive, PartitionSignature},
-            text::DevicePathToText,
+            text::{AllowShortcuts, DevicePathToText, DisplayOnly},
             DevicePath, DeviceSubType, DeviceType,
         },
         loaded_image::LoadedImage,
     },
     runtime::{self, VariableAttributes, VariableVendor},
-    system, CStr16, Guid, Handle, Result, Status,
+    system, CStr16, CString16, Guid, Handle, Result, Status,
 };
 
 use bitflags::bitflags;
 
-fn disk_get_part_uuid(disk_handle: Handle) -> Result<Guid> {
+use crate::security_version::LANZABOOTE_VENDOR_UUID;
+
+/// The UUID of the GPT partition of a disk handle.
+pub fn disk_get_part_uuid(disk_handle: Handle) -> Result<Guid> {
     let dp = boot::open_protocol_exclusive::<DevicePath>(disk_handle)?;
 
     for node in dp.node_iter() {
@@ -195,11 +198,7 @@ pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
                     boot::get_handle_for_protocol::<DevicePathToText>()?,
                 )?;
                 dp_protocol
-                    .convert_device_path_to_text(
-                        dp,
-                        uefi::proto::device_path::text::DisplayOnly(false),
-                        uefi::proto::device_path::text::AllowShortcuts(false),
-                    )
+                    .convert_device_path_to_text(dp, DisplayOnly(false), AllowShortcuts(false))
                     .map(|ps| cstr16_to_bytes(&ps).to_vec())
             } else {
                 // If we cannot retrieve the filepath of the loaded image
@@ -265,3 +264,38 @@ pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// The file name of an image, e.g. `nixos-generation-42-<hash>.efi`.
+pub fn image_file_name(image_path: &DevicePath) -> Result<CString16> {
+    let path = image_path
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .map_err(|_| uefi::Error::from(Status::NOT_FOUND))?;
+    let path = path.to_u16_slice();
+    let file_name = match path.iter().rposition(|c| *c == u16::from(b'\\')) {
+        Some(separator) => &path[separator + 1..],
+        None => path,
+    };
+    if file_name.is_empty() {
+        return Err(Status::NOT_FOUND.into());
+    }
+
+    let mut file_name = file_name.to_vec();
+    file_name.push(0);
+    CString16::try_from(file_name).map_err(|_| Status::NOT_FOUND.into())
+}
+
+/// Records the file name of the booted entry, e.g. `nixos-generation-42-<hash>.efi`, in the
+/// volatile `LanzabooteAttemptedEntry` variable.
+///
+/// `lzbt mark-good` promotes this entry to the default entry once the system booted
+/// successfully. As the variable is volatile, it always refers to the current boot.
+pub fn export_attempted_entry(image_path: &DevicePath) -> Result<()> {
+    let file_name = image_file_name(image_path)?;
+
+    runtime::set_variable(
+        cstr16!("LanzabooteAttemptedEntry"),
+        &LANZABOOTE_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        cstr16_to_bytes(&file_name),
+    )
+}
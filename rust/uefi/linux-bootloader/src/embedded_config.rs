@@ -0,0 +1,489 @@
+//! The configuration that lzbt embeds into the stub.
+//!
+//! After the stub is built, lzbt embeds the configuration of a generation into the binary by
+//! adding PE sections. This module reads them back. It only looks at the PE data it is given, so
+//! it can be tested on the host with synthetic PE files.
+
+use alloc::{string::String, vec::Vec};
+use sha2::{digest::Output, Digest, Sha256};
+use uefi::{CString16, Result, Status};
+
+use crate::insecure_boot::InsecureBootPolicy;
+use crate::luks::LuksConfiguration;
+use crate::partition::PartitionSelector;
+use crate::pe_section::{pe_section, pe_section_as_string};
+
+/// A SHA256 hash as embedded by lzbt.
+pub type Hash = Output<Sha256>;
+
+/// Extract a string, stored as UTF-8, from a PE section.
+pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
+    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+
+    Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
+}
+
+/// Extract a number, stored as a decimal string, from an optional PE section.
+///
+/// Unlike a missing section, a section that is not a number is an error, so that a broken
+/// security version cannot lower the version that is enforced.
+pub fn extract_u64(pe_data: &[u8], section: &str) -> Result<Option<u64>> {
+    pe_section(pe_data, section)
+        .map(|data| {
+            core::str::from_utf8(data)
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .ok_or_else(|| Status::INVALID_PARAMETER.into())
+        })
+        .transpose()
+}
+
+/// Extract a string, stored as UTF-8, from an optional PE section.
+pub fn extract_optional_string(pe_data: &[u8], section: &str) -> Result<Option<String>> {
+    pe_section(pe_data, section)
+        .map(|data| {
+            core::str::from_utf8(data)
+                .map(|s| String::from(s.trim()))
+                .map_err(|_| Status::INVALID_PARAMETER.into())
+        })
+        .transpose()
+}
+
+/// Check whether a PE section is present.
+///
+/// This is used for flags that lzbt embeds into the binary.
+pub fn extract_flag(pe_data: &[u8], section: &str) -> bool {
+    pe_section(pe_data, section).is_some()
+}
+
+/// Extract bytes from a PE section.
+pub fn extract_bytes(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
+    let bytes: Vec<u8> = pe_section(pe_data, section)
+        .ok_or(Status::INVALID_PARAMETER)?
+        .into();
+
+    Ok(bytes)
+}
+
+/// Extract a SHA256 hash from a PE section.
+pub fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
+    let array: [u8; 32] = pe_section(pe_data, section)
+        .ok_or(Status::INVALID_PARAMETER)?
+        .try_into()
+        .map_err(|_| Status::INVALID_PARAMETER)?;
+
+    Ok(array.into())
+}
+
+/// The hash of a kernel or initrd, as the `.revoked` section lists it.
+pub fn payload_hash(data: &[u8]) -> Hash {
+    Sha256::digest(data)
+}
+
+/// Extract the list of revoked hashes from the optional `.revoked` section.
+pub fn extract_revoked_hashes(pe_data: &[u8]) -> Result<Vec<Hash>> {
+    let Some(data) = pe_section(pe_data, ".revoked") else {
+        return Ok(Vec::new());
+    };
+    let hashes = data.chunks_exact(32);
+    if !hashes.remainder().is_empty() {
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+
+    Ok(hashes.map(Hash::clone_from_slice).collect())
+}
+
+/// The configuration of a thin stub, which loads the kernel and initrd from the ESP.
+pub struct ThinConfiguration {
+    /// The filename of the kernel to be booted. This filename is
+    /// relative to the root of the volume that contains the
+    /// lanzaboote binary.
+    pub kernel_filename: CString16,
+
+    /// The cryptographic hash of the kernel.
+    pub kernel_hash: Hash,
+
+    /// The filename of the initrd to be passed to the kernel. See
+    /// `kernel_filename` for how to interpret these filenames.
+    pub initrd_filename: CString16,
+
+    /// The cryptographic hash of the initrd. This hash is computed
+    /// over the whole PE binary, not only the embedded initrd.
+    pub initrd_hash: Hash,
+
+    /// The kernel command-line.
+    pub cmdline: CString16,
+
+    /// Whether to enforce the Secure Boot policy even if Secure Boot is disabled.
+    pub simulate_secure_boot: bool,
+
+    /// What to do if Secure Boot is disabled, see [`crate::insecure_boot`].
+    pub insecure_boot_policy: InsecureBootPolicy,
+
+    /// The security version number of this generation. Stubs without one have version 0.
+    pub security_version: u64,
+
+    /// The lowest security version that may still boot after this stub has started.
+    pub minimum_security_version: Option<u64>,
+
+    /// The timeout of the watchdog that is armed before the kernel is started, in seconds.
+    pub watchdog_timeout: Option<u64>,
+
+    /// Whether to extend the command line from SMBIOS even if Secure Boot is active.
+    pub allow_smbios_cmdline: bool,
+
+    /// Whether to use the command line and initrd from QEMU's fw_cfg even if Secure Boot is active.
+    pub allow_fw_cfg: bool,
+
+    /// Whether to pass the metadata of the cloud instance to the initrd, see
+    /// [`crate::instance_metadata`].
+    pub instance_metadata: bool,
+
+    /// Whether the initrd is encrypted with the key that is held by the firmware.
+    pub encrypted_initrd: bool,
+
+    /// Hashes of kernels and initrds that must not be booted, even if they match the hashes above.
+    pub revoked_hashes: Vec<Hash>,
+
+    /// The release of the kernel, which is compared with the one of a hibernated system.
+    pub kernel_release: Option<String>,
+
+    /// Whether to refuse to boot if the system hibernated with a different kernel.
+    pub refuse_mismatched_resume: bool,
+
+    /// The dm-verity root hash of the root file system, see [`crate::verity`].
+    pub verity_root_hash: Option<String>,
+
+    /// The Unix timestamp that the firmware clock must not be before, see [`crate::clock`].
+    pub not_before: Option<u64>,
+
+    /// Whether to move a clock that is before `not_before` forward.
+    pub set_clock: bool,
+
+    /// The hardening policy that is enforced on the command line, see [`crate::lockdown`].
+    pub lockdown_policy: Option<String>,
+
+    /// The partition to load the kernel and initrd from instead of the volume of the stub, see
+    /// [`crate::partition`].
+    pub payload_partition: Option<PartitionSelector>,
+
+    /// The `.drivers` section, which lists the file system drivers that are loaded if the
+    /// payload partition is not found, see [`crate::drivers`].
+    pub drivers: Option<Vec<u8>>,
+
+    /// How to unlock the payload partition if it is encrypted, see [`crate::luks`].
+    pub luks: Option<LuksConfiguration>,
+}
+
+impl ThinConfiguration {
+    pub fn new(file_data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            kernel_filename: extract_string(file_data, ".linux")?,
+            kernel_hash: extract_hash(file_data, ".linuxh")?,
+
+            initrd_filename: extract_string(file_data, ".initrd")?,
+            initrd_hash: extract_hash(file_data, ".initrdh")?,
+
+            cmdline: extract_string(file_data, ".cmdline")?,
+            simulate_secure_boot: extract_flag(file_data, ".sbsim"),
+            insecure_boot_policy: InsecureBootPolicy::from_section(pe_section(
+                file_data,
+                ".insecure",
+            )),
+            security_version: extract_u64(file_data, ".svn")?.unwrap_or(0),
+            minimum_security_version: extract_u64(file_data, ".svnmin")?,
+            watchdog_timeout: extract_u64(file_data, ".wdog")?,
+            allow_smbios_cmdline: extract_flag(file_data, ".smbcmd"),
+            allow_fw_cfg: extract_flag(file_data, ".fwcfg"),
+            instance_metadata: extract_flag(file_data, ".imds"),
+            encrypted_initrd: extract_flag(file_data, ".initrdenc"),
+            revoked_hashes: extract_revoked_hashes(file_data)?,
+            kernel_release: extract_optional_string(file_data, ".uname")?,
+            refuse_mismatched_resume: extract_flag(file_data, ".hibchk"),
+            verity_root_hash: extract_optional_string(file_data, ".roothash")?,
+            not_before: extract_u64(file_data, ".notbefore")?,
+            set_clock: extract_flag(file_data, ".setclock"),
+            lockdown_policy: extract_optional_string(file_data, ".lockdown")?,
+            payload_partition: extract_optional_string(file_data, ".partsel")?
+                .map(|selector| {
+                    PartitionSelector::parse(&selector).ok_or(Status::INVALID_PARAMETER)
+                })
+                .transpose()?,
+            drivers: pe_section(file_data, ".drivers").map(Vec::from),
+            luks: extract_optional_string(file_data, ".luks")?
+                .map(|luks| LuksConfiguration::parse(&luks).ok_or(Status::INVALID_PARAMETER))
+                .transpose()?,
+        })
+    }
+}
+
+/// The configuration of a fat stub, which embeds the kernel and initrd.
+pub struct FatConfiguration {
+    /// The kernel command-line.
+    pub cmdline: CString16,
+
+    /// Whether to enforce the Secure Boot policy even if Secure Boot is disabled.
+    pub simulate_secure_boot: bool,
+
+    /// What to do if Secure Boot is disabled, see [`crate::insecure_boot`].
+    pub insecure_boot_policy: InsecureBootPolicy,
+
+    /// The security version number of this generation. Stubs without one have version 0.
+    pub security_version: u64,
+
+    /// The lowest security version that may still boot after this stub has started.
+    pub minimum_security_version: Option<u64>,
+
+    /// The timeout of the watchdog that is armed before the kernel is started, in seconds.
+    pub watchdog_timeout: Option<u64>,
+
+    /// Whether to extend the command line from SMBIOS even if Secure Boot is active.
+    pub allow_smbios_cmdline: bool,
+
+    /// Whether to use the command line and initrd from QEMU's fw_cfg even if Secure Boot is active.
+    pub allow_fw_cfg: bool,
+
+    /// Whether to pass the metadata of the cloud instance to the initrd, see
+    /// [`crate::instance_metadata`].
+    pub instance_metadata: bool,
+
+    /// The kernel as raw bytes.
+    pub kernel: Vec<u8>,
+
+    /// The initrd as raw bytes.
+    pub initrd: Vec<u8>,
+
+    /// The release of the kernel, which is compared with the one of a hibernated system.
+    pub kernel_release: Option<String>,
+
+    /// Whether to refuse to boot if the system hibernated with a different kernel.
+    pub refuse_mismatched_resume: bool,
+
+    /// The dm-verity root hash of the root file system, see [`crate::verity`].
+    pub verity_root_hash: Option<String>,
+
+    /// The Unix timestamp that the firmware clock must not be before, see [`crate::clock`].
+    pub not_before: Option<u64>,
+
+    /// Whether to move a clock that is before `not_before` forward.
+    pub set_clock: bool,
+
+    /// The hardening policy that is enforced on the command line, see [`crate::lockdown`].
+    pub lockdown_policy: Option<String>,
+
+    /// The hashes of kernels and initrds that must not be booted.
+    pub revoked_hashes: Vec<Hash>,
+}
+
+impl FatConfiguration {
+    pub fn new(file_data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            kernel: extract_bytes(file_data, ".linux")?,
+            initrd: extract_bytes(file_data, ".initrd")?,
+            cmdline: extract_string(file_data, ".cmdline")?,
+            simulate_secure_boot: extract_flag(file_data, ".sbsim"),
+            insecure_boot_policy: InsecureBootPolicy::from_section(pe_section(
+                file_data,
+                ".insecure",
+            )),
+            security_version: extract_u64(file_data, ".svn")?.unwrap_or(0),
+            minimum_security_version: extract_u64(file_data, ".svnmin")?,
+            watchdog_timeout: extract_u64(file_data, ".wdog")?,
+            allow_smbios_cmdline: extract_flag(file_data, ".smbcmd"),
+            allow_fw_cfg: extract_flag(file_data, ".fwcfg"),
+            instance_metadata: extract_flag(file_data, ".imds"),
+            kernel_release: extract_optional_string(file_data, ".uname")?,
+            refuse_mismatched_resume: extract_flag(file_data, ".hibchk"),
+            verity_root_hash: extract_optional_string(file_data, ".roothash")?,
+            not_before: extract_u64(file_data, ".notbefore")?,
+            set_clock: extract_flag(file_data, ".setclock"),
+            lockdown_policy: extract_optional_string(file_data, ".lockdown")?,
+            revoked_hashes: extract_revoked_hashes(file_data)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    const FILE_ALIGNMENT: usize = 0x200;
+    const SIZE_OF_HEADERS: usize = 0x400;
+    const OPTIONAL_HEADER: usize = 0x58;
+    const SECTION_TABLE: usize = OPTIONAL_HEADER + 240;
+
+    fn align(size: usize) -> usize {
+        (size + FILE_ALIGNMENT - 1) / FILE_ALIGNMENT * FILE_ALIGNMENT
+    }
+
+    /// Build a PE32+ image with the given sections.
+    ///
+    /// Sections have the same offset in the file and in memory, like the stub is seen when it
+    /// looks at itself.
+    fn pe_with_sections(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut image = vec![0u8; SIZE_OF_HEADERS];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+
+        // COFF header
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x46..0x48].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        image[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+
+        // Optional header
+        let optional = OPTIONAL_HEADER;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 32..optional + 36].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        image[optional + 36..optional + 40].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        image[optional + 60..optional + 64]
+            .copy_from_slice(&(SIZE_OF_HEADERS as u32).to_le_bytes());
+        image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+        for (index, (name, data)) in sections.iter().enumerate() {
+            let offset = image.len();
+            let header = SECTION_TABLE + index * 40;
+            image[header..header + name.len()].copy_from_slice(name.as_bytes());
+            image[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            image[header + 12..header + 16].copy_from_slice(&(offset as u32).to_le_bytes());
+            image[header + 16..header + 20]
+                .copy_from_slice(&(align(data.len()) as u32).to_le_bytes());
+            image[header + 20..header + 24].copy_from_slice(&(offset as u32).to_le_bytes());
+
+            image.extend_from_slice(data);
+            image.resize(offset + align(data.len()), 0);
+        }
+
+        let size_of_image = image.len() as u32;
+        image[optional + 56..optional + 60].copy_from_slice(&size_of_image.to_le_bytes());
+        image
+    }
+
+    fn thin_sections<'a>(kernel_hash: &'a [u8]) -> Vec<(&'static str, &'a [u8])> {
+        vec![
+            (".linux", b"\\EFI\\nixos\\kernel.efi"),
+            (".linuxh", kernel_hash),
+            (".initrd", b"\\EFI\\nixos\\initrd.efi"),
+            (".initrdh", &[0x22; 32]),
+            (".cmdline", b"init=/nix/store/init"),
+        ]
+    }
+
+    #[test]
+    fn read_thin_configuration() -> Result<()> {
+        let mut sections = thin_sections(&[0x11; 32]);
+        sections.push((".svn", b"3\n"));
+        sections.push((".sbsim", b""));
+        sections.push((".uname", b"6.1.1"));
+        let config = ThinConfiguration::new(&pe_with_sections(&sections))?;
+
+        assert_eq!(
+            config.kernel_filename.to_string(),
+            "\\EFI\\nixos\\kernel.efi"
+        );
+        assert_eq!(config.kernel_hash.as_slice(), &[0x11; 32]);
+        assert_eq!(config.initrd_hash.as_slice(), &[0x22; 32]);
+        assert_eq!(config.cmdline.to_string(), "init=/nix/store/init");
+        assert_eq!(config.security_version, 3);
+        assert_eq!(config.minimum_security_version, None);
+        assert!(config.simulate_secure_boot);
+        assert!(!config.encrypted_initrd);
+        assert!(config.revoked_hashes.is_empty());
+        assert_eq!(config.kernel_release.as_deref(), Some("6.1.1"));
+        assert!(!config.refuse_mismatched_resume);
+        Ok(())
+    }
+
+    #[test]
+    fn read_fat_configuration() -> Result<()> {
+        let config = FatConfiguration::new(&pe_with_sections(&[
+            (".cmdline", b"quiet"),
+            (".linux", b"kernel"),
+            (".initrd", b"initrd"),
+            (".wdog", b"30"),
+            (".revoked", &payload_hash(b"kernel")),
+        ]))?;
+
+        assert_eq!(config.kernel, b"kernel");
+        assert_eq!(config.initrd, b"initrd");
+        assert_eq!(config.watchdog_timeout, Some(30));
+        assert_eq!(config.kernel_release, None);
+        assert_eq!(config.revoked_hashes, [payload_hash(b"kernel")]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_wrong_hash_size() {
+        for hash in [&[0x11; 31][..], &[0x11; 33], &[]] {
+            let pe_data = pe_with_sections(&thin_sections(hash));
+            assert_eq!(
+                extract_hash(&pe_data, ".linuxh").unwrap_err().status(),
+                Status::INVALID_PARAMETER
+            );
+            assert!(ThinConfiguration::new(&pe_data).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_partial_revoked_hash() {
+        let mut sections = thin_sections(&[0x11; 32]);
+        sections.push((".revoked", &[0x33; 48]));
+        assert!(ThinConfiguration::new(&pe_with_sections(&sections)).is_err());
+
+        sections.pop();
+        sections.push((".revoked", &[0x33; 64]));
+        let config = ThinConfiguration::new(&pe_with_sections(&sections)).unwrap();
+        assert_eq!(config.revoked_hashes.len(), 2);
+    }
+
+    #[test]
+    fn reject_missing_sections() {
+        for missing in [".linux", ".linuxh", ".initrd", ".initrdh", ".cmdline"] {
+            let mut sections = thin_sections(&[0x11; 32]);
+            sections.retain(|(name, _)| *name != missing);
+            assert!(
+                ThinConfiguration::new(&pe_with_sections(&sections)).is_err(),
+                "{missing} is required"
+            );
+        }
+
+        let pe_data = pe_with_sections(&[(".cmdline", b"quiet")]);
+        assert!(!extract_flag(&pe_data, ".sbsim"));
+        assert_eq!(extract_u64(&pe_data, ".svnmin").unwrap(), None);
+        assert!(extract_bytes(&pe_data, ".linux").is_err());
+    }
+
+    #[test]
+    fn reject_truncated_sections() {
+        let pe_data = pe_with_sections(&thin_sections(&[0x11; 32]));
+        let cmdline_start = pe_data.len() - FILE_ALIGNMENT;
+
+        // The last section, the command line, ends beyond the data.
+        let truncated = &pe_data[..cmdline_start + 4];
+        assert!(extract_string(truncated, ".cmdline").is_err());
+        assert!(ThinConfiguration::new(truncated).is_err());
+
+        // Everything before it is still intact.
+        assert_eq!(
+            extract_hash(truncated, ".linuxh").unwrap().as_slice(),
+            &[0x11; 32]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_strings() {
+        let pe_data = pe_with_sections(&[
+            (".cmdline", b"\xff\xfe"),
+            (".svn", b"three"),
+            (".svnmin", b"\xff"),
+            (".uname", b"6.1\xff"),
+        ]);
+        assert!(extract_string(&pe_data, ".cmdline").is_err());
+        assert!(extract_u64(&pe_data, ".svn").is_err());
+        assert!(extract_u64(&pe_data, ".svnmin").is_err());
+        assert!(extract_optional_string(&pe_data, ".uname").is_err());
+    }
+}
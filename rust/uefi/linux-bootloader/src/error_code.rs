@@ -0,0 +1,108 @@
+//! Short error codes that users can report instead of describing a failed boot.
+//!
+//! A code names the kind of failure and a hash of where it happened, e.g. `E-4QZ7M2` for the step
+//! of the boot that failed or `P-1KD0XA` for a panic. The hash of a step only changes when its
+//! description does. The hash of a panic is that of its location in the source code, so it only
+//! identifies the panic together with the version of the stub. Along with the code, the stub shows
+//! a link to the documentation of the codes, also as a QR code if the screen supports graphics.
+
+use alloc::string::String;
+use core::fmt;
+
+use log::error;
+
+use crate::qr::QrCode;
+
+/// The documentation of the error codes.
+pub const DOCUMENTATION_URL: &str =
+    "https://github.com/nix-community/lanzaboote/blob/master/docs/TROUBLESHOOTING.md#error-codes";
+
+/// Crockford's Base32, which avoids letters that are easily confused with digits.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A step of the boot failed.
+    Step,
+    /// The stub panicked.
+    Panic,
+}
+
+/// A short code for a failure, see the [module](self) documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode {
+    kind: ErrorKind,
+    hash: u32,
+}
+
+/// The 32-bit FNV-1a hash, which is stable across Rust versions unlike `core::hash`.
+fn fnv1a(parts: &[&[u8]]) -> u32 {
+    parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0x811c9dc5, |hash, &byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+        })
+}
+
+impl ErrorCode {
+    /// The code of a failed step of the boot, e.g. `Reading the kernel`.
+    pub fn step(step: &str) -> Self {
+        Self {
+            kind: ErrorKind::Step,
+            hash: fnv1a(&[step.as_bytes()]),
+        }
+    }
+
+    /// The code of a panic at a location in the source code.
+    pub fn panic(file: &str, line: u32) -> Self {
+        Self {
+            kind: ErrorKind::Panic,
+            hash: fnv1a(&[file.as_bytes(), b":", &line.to_le_bytes()]),
+        }
+    }
+
+    /// Log the code and the link to its documentation, and draw the link as a QR code.
+    pub fn show(&self) {
+        error!("Error code: {self}. See {DOCUMENTATION_URL}");
+        if let Some(code) = QrCode::encode(DOCUMENTATION_URL.as_bytes()) {
+            let _ = code.draw();
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = match self.kind {
+            ErrorKind::Step => 'E',
+            ErrorKind::Panic => 'P',
+        };
+        // Six characters take 30 bits of the hash.
+        let hash: String = (0..6)
+            .rev()
+            .map(|i| char::from(ALPHABET[(self.hash >> (5 * i)) as usize & 31]))
+            .collect();
+        write!(f, "{prefix}-{hash}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn stable_codes() {
+        assert_eq!(fnv1a(&[b"a"]), 0xe40c292c);
+        let code = ErrorCode::step("Reading the kernel").to_string();
+        assert_eq!(code.len(), 8);
+        assert!(code.starts_with("E-"));
+        assert_eq!(code, ErrorCode::step("Reading the kernel").to_string());
+        assert_ne!(code, ErrorCode::step("Reading the initrd").to_string());
+        assert_ne!(
+            ErrorCode::panic("stub/src/main.rs", 1),
+            ErrorCode::panic("stub/src/main.rs", 2)
+        );
+        assert!(DOCUMENTATION_URL.len() <= 106);
+    }
+}
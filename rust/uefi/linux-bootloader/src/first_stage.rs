@@ -0,0 +1,67 @@
+//! Verify systemd-boot before chainloading it.
+//!
+//! Some firmwares only trust a single signed binary, e.g. because `db` only contains its hash. A
+//! stub with an `.sdboot` section is then installed as that binary, the first stage. It does not
+//! boot Linux itself, but reads systemd-boot from the path in the section, checks it against the
+//! SHA256 hash in the `.sdbooth` section and chainloads it. So the boot manager is protected by
+//! the signature of the first stage, even if the firmware would not accept its own signature.
+//!
+//! Like the kernel and initrd of a stub, systemd-boot is only rejected on a hash mismatch if the
+//! Secure Boot policy is enforced.
+
+use alloc::vec::Vec;
+use log::{error, info, warn};
+use uefi::{
+    boot::{self, LoadImageSource},
+    Result, Status,
+};
+
+use crate::{
+    chunked_read::read_hashed,
+    embedded_config::{extract_hash, extract_string},
+    messages::Message,
+    security_override::SecurityOverride,
+    uefi_helpers::file_device_path,
+};
+
+/// Verify the systemd-boot binary that the sections of `pe_data` point to and chainload it.
+///
+/// This only returns if systemd-boot cannot be loaded or returns itself.
+pub fn boot_systemd_boot(pe_data: &[u8], secure_boot: bool) -> Result<()> {
+    let path = extract_string(pe_data, ".sdboot")?;
+    let expected_hash = extract_hash(pe_data, ".sdbooth")?;
+
+    let (image, hash) = {
+        let mut file_system = boot::get_image_file_system(boot::image_handle())?;
+        let mut volume = file_system.open_volume()?;
+        read_hashed(&mut volume, &path, "systemd-boot")?
+    };
+
+    if hash != expected_hash {
+        if secure_boot {
+            error!("{}", Message::HashMismatch("systemd-boot"));
+            return Err(Status::SECURITY_VIOLATION.into());
+        }
+        warn!(
+            "{} {}",
+            Message::HashMismatch("systemd-boot"),
+            Message::ContinuingAnyway
+        );
+    }
+
+    info!("Starting systemd-boot from {path}...");
+    let mut buffer = Vec::new();
+    let file_path = file_device_path(&path, &mut buffer)?;
+    let handle = {
+        // The firmware may not trust the signature of systemd-boot, but its hash was verified.
+        let _override = SecurityOverride::install(&image);
+        boot::load_image(
+            boot::image_handle(),
+            LoadImageSource::FromBuffer {
+                buffer: &image,
+                file_path: Some(file_path),
+            },
+        )?
+    };
+    boot::start_image(handle)
+}
@@ -0,0 +1,114 @@
+//! Reading files from QEMU's firmware configuration device (fw_cfg).
+//!
+//! QEMU passes files to the guest with `-fw_cfg name=opt/...,file=...` or
+//! `-fw_cfg name=opt/...,string=...`. The device is only accessed through its I/O ports on x86_64
+//! and only if a hypervisor is present, so that the ports are never touched on real hardware.
+
+use alloc::vec::Vec;
+
+/// `FW_CFG_SIGNATURE`
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+/// `FW_CFG_FILE_DIR`
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+/// The size of a `FWCfgFile` entry in the file directory.
+const FILE_ENTRY_SIZE: usize = 64;
+/// The size of the name in a `FWCfgFile` entry, including the NUL terminator.
+const FILE_NAME_SIZE: usize = 56;
+
+#[cfg(target_arch = "x86_64")]
+mod port {
+    use core::arch::asm;
+    use core::arch::x86_64::__cpuid;
+
+    const FW_CFG_PORT_SELECTOR: u16 = 0x510;
+    const FW_CFG_PORT_DATA: u16 = 0x511;
+
+    /// Whether the CPU reports that it runs on a hypervisor.
+    pub fn hypervisor_present() -> bool {
+        // SAFETY: CPUID leaf 1 is available on every x86_64 CPU. Newer toolchains consider
+        // `__cpuid` safe.
+        #[allow(unused_unsafe)]
+        let features = unsafe { __cpuid(1) };
+        features.ecx & (1 << 31) != 0
+    }
+
+    /// Select an item and read its data from the start.
+    pub fn select(key: u16) {
+        // SAFETY: Only called if a hypervisor is present, which provides the fw_cfg ports.
+        unsafe {
+            asm!("out dx, ax", in("dx") FW_CFG_PORT_SELECTOR, in("ax") key, options(nomem, nostack, preserves_flags));
+        }
+    }
+
+    /// Read the next bytes of the selected item.
+    pub fn read(buffer: &mut [u8]) {
+        // SAFETY: `rep insb` writes exactly `buffer.len()` bytes to `buffer`.
+        unsafe {
+            asm!(
+                "rep insb",
+                in("dx") FW_CFG_PORT_DATA,
+                inout("rdi") buffer.as_mut_ptr() => _,
+                inout("rcx") buffer.len() => _,
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod port {
+    pub fn hypervisor_present() -> bool {
+        false
+    }
+
+    pub fn select(_key: u16) {}
+
+    pub fn read(_buffer: &mut [u8]) {}
+}
+
+/// Whether QEMU's fw_cfg device is present.
+fn available() -> bool {
+    if !port::hypervisor_present() {
+        return false;
+    }
+
+    let mut signature = [0; 4];
+    port::select(FW_CFG_SIGNATURE);
+    port::read(&mut signature);
+    &signature == b"QEMU"
+}
+
+/// Read a file from fw_cfg, e.g. `opt/org.nix-community.lanzaboote/cmdline`.
+///
+/// Returns `None` if fw_cfg is not available or does not contain the file.
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    if !available() {
+        return None;
+    }
+
+    let mut count = [0; 4];
+    port::select(FW_CFG_FILE_DIR);
+    port::read(&mut count);
+    // The file directory is big-endian.
+    let count = u32::from_be_bytes(count);
+
+    let mut file = None;
+    for _ in 0..count {
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        port::read(&mut entry);
+        let entry_name = &entry[8..8 + FILE_NAME_SIZE];
+        let entry_name = &entry_name[..entry_name.iter().position(|b| *b == 0)?];
+        if entry_name == name.as_bytes() {
+            let size = u32::from_be_bytes(entry[0..4].try_into().ok()?);
+            let key = u16::from_be_bytes(entry[4..6].try_into().ok()?);
+            file = Some((key, size));
+            break;
+        }
+    }
+
+    let (key, size) = file?;
+    let mut data = alloc::vec![0; usize::try_from(size).ok()?];
+    port::select(key);
+    port::read(&mut data);
+    Some(data)
+}
@@ -0,0 +1,137 @@
+//! Decompressing gzip streams, e.g. the payload of EFI zboot kernels.
+//!
+//! miniz_oxide inflates the DEFLATE stream, this module only handles the gzip container
+//! (RFC 1952). The decompressed data is checked against the CRC32 and size in the gzip trailer.
+
+use alloc::vec::Vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use uefi::{Result, Status};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// The CRC32 and the size of the uncompressed data.
+const TRAILER_SIZE: usize = 8;
+
+fn corrupt<T>() -> Result<T> {
+    Err(Status::LOAD_ERROR.into())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or(Status::LOAD_ERROR)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or(Status::LOAD_ERROR)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Skip a NUL-terminated field of the gzip header.
+fn skip_string(data: &[u8], offset: usize) -> Result<usize> {
+    let length = data
+        .get(offset..)
+        .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+        .ok_or(Status::LOAD_ERROR)?;
+    Ok(offset + length + 1)
+}
+
+/// Decompress a gzip stream with a single member.
+///
+/// A mismatch of the CRC32 or the size in the trailer is a `CRC_ERROR`.
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    if data.get(..2) != Some(&GZIP_MAGIC[..]) || data.get(2) != Some(&GZIP_METHOD_DEFLATE) {
+        return corrupt();
+    }
+    let flags = data.get(3).copied().ok_or(Status::LOAD_ERROR)?;
+
+    // Skip the modification time, extra flags and operating system.
+    let mut offset = 10;
+    if flags & FEXTRA != 0 {
+        offset += 2 + usize::from(read_u16(data, offset)?);
+    }
+    if flags & FNAME != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & FCOMMENT != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+    let trailer = data
+        .len()
+        .checked_sub(TRAILER_SIZE)
+        .filter(|&trailer| trailer >= offset)
+        .ok_or(Status::LOAD_ERROR)?;
+    let crc = read_u32(data, trailer)?;
+    let size = read_u32(data, trailer + 4)?;
+
+    // The size in the trailer is truncated to 32 bits, which is plenty for a kernel. It bounds
+    // the output, so that a corrupt stream cannot exhaust the memory.
+    let output = decompress_to_vec_with_limit(&data[offset..trailer], size as usize)
+        .or_else(|_| corrupt())?;
+
+    if crc != crc32fast::hash(&output) || size != output.len() as u32 {
+        return Err(Status::CRC_ERROR.into());
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
+    /// Text that compresses well.
+    fn text() -> Vec<u8> {
+        (0..2000)
+            .flat_map(|i: u32| {
+                alloc::format!("line {} of {}\n", i * 7919 % 1000, i % 13).into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gunzip_with_header_fields() {
+        let mut encoder = GzBuilder::new()
+            .filename("vmlinux")
+            .comment("kernel")
+            .extra(b"extra".to_vec())
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(&text()).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        assert_eq!(gunzip(&gzip).unwrap(), text());
+    }
+
+    #[test]
+    fn reject_truncated_input() {
+        let mut encoder = GzBuilder::new().write(Vec::new(), Compression::default());
+        encoder.write_all(&text()).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        for length in [0, 5, 10, gzip.len() / 2, gzip.len() - 8, gzip.len() - 1] {
+            assert!(gunzip(&gzip[..length]).is_err(), "length {length}");
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_trailer() {
+        let mut encoder = GzBuilder::new().write(Vec::new(), Compression::default());
+        encoder.write_all(b"initrd").unwrap();
+        let mut gzip = encoder.finish().unwrap();
+
+        let crc = gzip.len() - 8;
+        gzip[crc] ^= 1;
+        assert_eq!(gunzip(&gzip).unwrap_err().status(), Status::CRC_ERROR);
+    }
+}
@@ -0,0 +1,153 @@
+//! Protection against resuming a hibernated system with a different kernel.
+//!
+//! Before the system hibernates, systemd records where the hibernation image is in the
+//! `HibernateLocation` EFI variable, together with the release of the running kernel. The initrd
+//! then resumes from that image. If a generation with a different kernel is booted in the
+//! meantime, that kernel takes over the memory of the old one, which can corrupt file systems.
+//!
+//! See <https://systemd.io/BOOT_LOADER_INTERFACE/> and `systemd-hibernate-resume(8)`.
+
+use alloc::{boxed::Box, string::String};
+use log::{error, info, warn};
+use uefi::{
+    boot, cstr16, guid,
+    runtime::{self, VariableVendor},
+    Result, Status,
+};
+
+/// The vendor GUID of systemd's own variables, which differs from the one of the boot loader
+/// interface.
+pub const SYSTEMD_VENDOR_UUID: VariableVendor =
+    VariableVendor(guid!("8cf2644b-4b0b-428f-9387-6d876050dc67"));
+
+/// The variable that holds the location of the hibernation image as JSON.
+const HIBERNATE_LOCATION_VARIABLE: &uefi::CStr16 = cstr16!("HibernateLocation");
+
+/// How long a warning stays on the screen before the kernel is started anyway.
+const WARNING_DELAY_SECONDS: usize = 5;
+
+/// Find the value of a string field in a flat JSON object, like `HibernateLocation`.
+///
+/// This is not a JSON parser. Values with escape sequences are not supported and yield `None`,
+/// which does not happen for kernel releases.
+pub fn json_string_field<'a>(json: &'a str, field: &str) -> Option<&'a str> {
+    let mut rest = json;
+    loop {
+        let start = rest.find('"')?;
+        let after_key = rest[start + 1..].strip_prefix(field);
+        rest = &rest[start + 1..];
+
+        let value = after_key
+            .and_then(|s| s.strip_prefix('"'))
+            .and_then(|s| s.trim_start().strip_prefix(':'))
+            .and_then(|s| s.trim_start().strip_prefix('"'));
+        if let Some(value) = value {
+            let value = &value[..value.find('"')?];
+            return if value.contains('\\') {
+                None
+            } else {
+                Some(value)
+            };
+        }
+
+        // Skip to the end of this string, so that the contents of values are never taken for
+        // keys.
+        let end = rest.find('"')?;
+        rest = &rest[end + 1..];
+    }
+}
+
+/// Read the `HibernateLocation` variable. Returns `None` if the system is not hibernated.
+fn read_hibernate_location() -> Option<Box<[u8]>> {
+    match runtime::get_variable_boxed(HIBERNATE_LOCATION_VARIABLE, &SYSTEMD_VENDOR_UUID) {
+        Ok((data, _)) => Some(data),
+        Err(err) if err.status() == Status::NOT_FOUND => None,
+        Err(err) => {
+            warn!("Failed to read the hibernation location: {}", err.status());
+            None
+        }
+    }
+}
+
+/// The kernel release that the system hibernated with, if it is hibernated.
+///
+/// The inner `None` means that the system is hibernated, but its kernel is unknown.
+fn hibernated_kernel_release() -> Option<Option<String>> {
+    let location = read_hibernate_location()?;
+    Some(
+        core::str::from_utf8(&location)
+            .ok()
+            .and_then(|json| json_string_field(json, "kernelVersion"))
+            .map(String::from),
+    )
+}
+
+/// Warn about or refuse booting `kernel_release` if the system hibernated with another kernel.
+///
+/// If `refuse` is false, the warning stays on the screen for a few seconds before the boot
+/// continues, so that the user can still reset the machine and choose another generation.
+pub fn check_resume(kernel_release: Option<&str>, refuse: bool) -> Result<()> {
+    let Some(hibernated) = hibernated_kernel_release() else {
+        return Ok(());
+    };
+    let (Some(hibernated), Some(kernel_release)) = (hibernated, kernel_release) else {
+        warn!(
+            "The system is hibernated, but it is unknown whether it hibernated with this kernel."
+        );
+        return Ok(());
+    };
+
+    if hibernated == kernel_release {
+        info!("Resuming the system that hibernated with kernel {hibernated}.");
+        return Ok(());
+    }
+
+    if refuse {
+        error!(
+            "The system hibernated with kernel {hibernated}, but this generation boots kernel {kernel_release}. Refusing to boot, choose the generation that the system hibernated with."
+        );
+        return Err(Status::ABORTED.into());
+    }
+    warn!(
+        "The system hibernated with kernel {hibernated}, but this generation boots kernel {kernel_release}! Resuming may corrupt the file systems. Continuing in {WARNING_DELAY_SECONDS} seconds."
+    );
+    boot::stall(WARNING_DELAY_SECONDS * 1_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCATION: &str = r#"{"uuid":"d9f8b5a6-4c3e-4f7a-9b2d-1e0f3c4a5b6c","offset":0,"kernelVersion":"6.1.1","osReleaseId":"nixos"}"#;
+
+    #[test]
+    fn find_kernel_version() {
+        assert_eq!(json_string_field(LOCATION, "kernelVersion"), Some("6.1.1"));
+        assert_eq!(json_string_field(LOCATION, "osReleaseId"), Some("nixos"));
+        assert_eq!(
+            json_string_field(r#"{ "kernelVersion" : "6.6.0-rc1" }"#, "kernelVersion"),
+            Some("6.6.0-rc1")
+        );
+    }
+
+    #[test]
+    fn ignore_missing_and_unsupported_fields() {
+        assert_eq!(json_string_field(LOCATION, "kernel"), None);
+        // A number is not a string.
+        assert_eq!(json_string_field(LOCATION, "offset"), None);
+        // A value that looks like the key is not the key.
+        assert_eq!(
+            json_string_field(r#"{"osReleaseId":"kernelVersion"}"#, "kernelVersion"),
+            None
+        );
+        assert_eq!(
+            json_string_field(r#"{"kernelVersion":"6.1\"1"}"#, "kernelVersion"),
+            None
+        );
+        assert_eq!(
+            json_string_field(r#"{"kernelVersion":"6.1"#, "kernelVersion"),
+            None
+        );
+    }
+}
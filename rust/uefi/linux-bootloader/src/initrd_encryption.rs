@@ -0,0 +1,153 @@
+//! Decryption of an initrd that lzbt encrypted with AES-256-GCM.
+//!
+//! The key is held by the firmware. lzbt provisions a new key in a variable that is accessible at
+//! runtime, which the stub moves into a variable that is only accessible during boot services.
+//! After that, the key cannot be read from the operating system, and the initrd on the ESP is
+//! useless without the machine.
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit};
+use alloc::vec::Vec;
+use log::{error, info, warn};
+use uefi::{
+    cstr16,
+    runtime::{self, VariableAttributes},
+    CStr16, Result, Status,
+};
+
+use crate::messages::Message;
+use crate::security_version::LANZABOOTE_VENDOR_UUID;
+use crate::zeroize::zeroize;
+
+/// The header of an encrypted initrd, including the version of the format.
+const HEADER: &[u8; 8] = b"LZBTENC\x01";
+
+/// The variable that holds the key. It is only accessible during boot services.
+const KEY_VARIABLE: &CStr16 = cstr16!("LanzabooteInitrdKey");
+/// The variable in which lzbt provisions a new key.
+const PROVISION_VARIABLE: &CStr16 = cstr16!("LanzabooteInitrdKeyProvision");
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// The attributes of a trustworthy key variable. Notably, it is not accessible at runtime.
+fn key_attributes() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+}
+
+/// Take a newly provisioned key, or read the key that is held by the firmware.
+///
+/// Anyone who can write variables at runtime can provision a key. This does not reveal the
+/// previous key, it only makes the existing encrypted initrds unbootable.
+fn initrd_key() -> Option<[u8; 32]> {
+    let mut key = [0u8; 32];
+
+    if let Ok((data, _)) =
+        runtime::get_variable(PROVISION_VARIABLE, &LANZABOOTE_VENDOR_UUID, &mut key)
+    {
+        if data.len() == key.len() {
+            info!("Storing the newly provisioned initrd key.");
+            match runtime::set_variable(
+                KEY_VARIABLE,
+                &LANZABOOTE_VENDOR_UUID,
+                key_attributes(),
+                &key,
+            ) {
+                // Only delete the provisioned key once it is stored, so that it is not lost.
+                Ok(()) => {
+                    if runtime::delete_variable(PROVISION_VARIABLE, &LANZABOOTE_VENDOR_UUID)
+                        .is_err()
+                    {
+                        warn!("Failed to delete the provisioned initrd key.");
+                    }
+                }
+                Err(err) => warn!("Failed to store the initrd key: {}", err.status()),
+            }
+            return Some(key);
+        }
+    }
+
+    match runtime::get_variable(KEY_VARIABLE, &LANZABOOTE_VENDOR_UUID, &mut key) {
+        Ok((data, attributes)) if attributes == key_attributes() && data.len() == 32 => Some(key),
+        Ok(_) => {
+            warn!("Ignoring initrd key with unexpected attributes or size.");
+            zeroize(&mut key);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Decrypt an initrd with the key that is held by the firmware.
+pub fn decrypt_initrd(encrypted: &[u8]) -> Result<Vec<u8>> {
+    let Some(mut key) = initrd_key() else {
+        error!("{}", Message::InitrdKeyMissing);
+        return Err(Status::NOT_FOUND.into());
+    };
+    let initrd = decrypt_with_key(&key, encrypted);
+    zeroize(&mut key);
+    initrd
+}
+
+/// Decrypt an initrd that consists of the header, the nonce, the ciphertext and the tag. The
+/// header is authenticated as associated data.
+fn decrypt_with_key(key: &[u8; 32], encrypted: &[u8]) -> Result<Vec<u8>> {
+    let rest = encrypted
+        .strip_prefix(HEADER)
+        .filter(|rest| rest.len() >= NONCE_SIZE + TAG_SIZE)
+        .ok_or_else(|| {
+            error!("The initrd is not encrypted or truncated.");
+            Status::VOLUME_CORRUPTED
+        })?;
+    let (nonce, rest) = rest.split_at(NONCE_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+    let mut initrd = ciphertext.to_vec();
+    Aes256Gcm::new(key.into())
+        .decrypt_in_place_detached(nonce.into(), HEADER, &mut initrd, tag.into())
+        .map_err(|_| {
+            error!("{}", Message::InitrdKeyMismatch);
+            Status::SECURITY_VIOLATION
+        })?;
+    Ok(initrd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// `b"initrd"` encrypted by lzbt with `KEY`: the header, the nonce, the ciphertext and the
+    /// tag.
+    const ENCRYPTED: &[u8] = b"LZBTENC\x01\
+        \x99\x29\xcf\xb2\xb5\x83\x7f\x66\xb0\xd7\x6a\x87\
+        \xf3\x76\x07\xcd\xa2\xba\
+        \xc9\xa5\x04\xd7\x86\x30\xa2\x63\xa6\x52\x03\x66\xf7\x2c\xf9\xc5";
+
+    #[test]
+    fn decrypt_initrd_of_lzbt() {
+        assert_eq!(decrypt_with_key(&KEY, ENCRYPTED).unwrap(), b"initrd");
+    }
+
+    #[test]
+    fn reject_tampered_initrd() {
+        assert!(decrypt_with_key(&[8; 32], ENCRYPTED).is_err());
+
+        let mut tampered = ENCRYPTED.to_vec();
+        tampered[HEADER.len() + NONCE_SIZE] ^= 1;
+        assert!(decrypt_with_key(&KEY, &tampered).is_err());
+
+        let mut header = ENCRYPTED.to_vec();
+        header[7] = 2;
+        assert!(decrypt_with_key(&KEY, &header).is_err());
+    }
+
+    #[test]
+    fn reject_truncated_initrd() {
+        assert!(decrypt_with_key(&KEY, &ENCRYPTED[..ENCRYPTED.len() - 1]).is_err());
+        assert!(
+            decrypt_with_key(&KEY, &ENCRYPTED[..HEADER.len() + NONCE_SIZE + TAG_SIZE - 1]).is_err()
+        );
+        assert!(decrypt_with_key(&KEY, b"070701").is_err());
+    }
+}
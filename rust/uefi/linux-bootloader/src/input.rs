@@ -0,0 +1,115 @@
+//! Keyboard input on the firmware console.
+//!
+//! The stub waits for key presses in a few places: to confirm a boot without Secure Boot, to leave
+//! the diagnostic screen and to dismiss an error. Waiting is done with `WaitForEvent` on the key
+//! event and, if there is a timeout, a timer event, so that the CPU is idle in the meantime.
+
+use core::time::Duration;
+
+use uefi::{
+    boot::{self, EventType, TimerTrigger, Tpl},
+    proto::console::text::{Key, ScanCode},
+    system, Event,
+};
+
+/// A key or key combination that the stub reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chord {
+    /// A printable key, regardless of Shift and Caps Lock.
+    Key(char),
+    /// Ctrl and a letter, which the console reports as the ASCII control character.
+    Ctrl(char),
+    /// A special key, e.g. Escape or F1.
+    Special(ScanCode),
+}
+
+impl Chord {
+    /// Check whether `key` was produced by this chord.
+    pub fn matches(&self, key: &Key) -> bool {
+        match (*self, *key) {
+            (Self::Key(expected), Key::Printable(c)) => {
+                char::from(c).to_lowercase().eq(expected.to_lowercase())
+            }
+            (Self::Ctrl(letter), Key::Printable(c)) => {
+                letter.is_ascii_alphabetic()
+                    && u16::from(c) == u16::from(letter.to_ascii_uppercase() as u8 - b'@')
+            }
+            (Self::Special(expected), Key::Special(scan_code)) => expected == scan_code,
+            _ => false,
+        }
+    }
+}
+
+/// Drop the key strokes that are pending, e.g. from before a prompt.
+pub fn flush() {
+    system::with_stdin(|stdin| while let Ok(Some(_)) = stdin.read_key() {});
+}
+
+/// Check whether `chord` is among the pending key strokes, e.g. because it was pressed while the
+/// firmware was starting.
+///
+/// All pending key strokes are consumed, so that other keys pressed before do not hide it.
+pub fn pending(chord: Chord) -> bool {
+    system::with_stdin(|stdin| {
+        let mut found = false;
+        while let Ok(Some(key)) = stdin.read_key() {
+            found |= chord.matches(&key);
+        }
+        found
+    })
+}
+
+/// Create an event that is signaled once `timeout` has passed.
+fn timer(timeout: Duration) -> uefi::Result<Event> {
+    // SAFETY: The event has no notification function.
+    let timer = unsafe { boot::create_event(EventType::TIMER, Tpl::APPLICATION, None, None) }?;
+    // The timer counts in units of 100 ns.
+    let ticks = u64::try_from(timeout.as_nanos() / 100).unwrap_or(u64::MAX);
+    if let Err(err) = boot::set_timer(&timer, TimerTrigger::Relative(ticks)) {
+        let _ = boot::close_event(timer);
+        return Err(err);
+    }
+    Ok(timer)
+}
+
+/// Wait until a key is pressed or `timeout` has passed, and return the key.
+///
+/// Without a timeout, this waits forever. `None` is returned on a timeout or if there is no
+/// keyboard.
+pub fn read_key(timeout: Option<Duration>) -> Option<Key> {
+    let key_event = system::with_stdin(|stdin| stdin.wait_for_key_event())?;
+    let signaled = match timeout {
+        Some(timeout) => {
+            let timer = timer(timeout).ok()?;
+            // SAFETY: The copy is only used while waiting, the timer is closed afterwards.
+            let signaled = boot::wait_for_event(&mut [key_event, unsafe { timer.unsafe_clone() }]);
+            let _ = boot::close_event(timer);
+            signaled
+        }
+        None => boot::wait_for_event(&mut [key_event]),
+    };
+    match signaled {
+        Ok(0) => system::with_stdin(|stdin| stdin.read_key()).ok().flatten(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uefi::Char16;
+
+    fn printable(c: char) -> Key {
+        Key::Printable(Char16::try_from(c).unwrap())
+    }
+
+    #[test]
+    fn match_chords() {
+        assert!(Chord::Key('d').matches(&printable('D')));
+        assert!(!Chord::Key('d').matches(&printable('x')));
+        assert!(Chord::Ctrl('c').matches(&printable('\u{3}')));
+        assert!(!Chord::Ctrl('c').matches(&printable('c')));
+        assert!(Chord::Special(ScanCode::ESCAPE).matches(&Key::Special(ScanCode::ESCAPE)));
+        assert!(!Chord::Special(ScanCode::ESCAPE).matches(&printable('\u{1b}')));
+    }
+}
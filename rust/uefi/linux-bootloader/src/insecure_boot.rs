@@ -0,0 +1,99 @@
+//! What the stub does if Secure Boot is disabled.
+//!
+//! lzbt embeds the policy into the `.insecure` section. An attacker with access to the firmware
+//! setup can disable Secure Boot to boot their own code. The policy does not stop an attacker who
+//! also replaces the stub, but it makes the downgrade visible the next time the owner boots:
+//! `confirm` waits for a key press before booting and `refuse` does not boot at all.
+
+use crate::console::print_wrapped;
+use crate::input::{self, Chord};
+use crate::messages::Message;
+use alloc::format;
+use log::{error, warn};
+use uefi::{Result, Status};
+
+/// The key that confirms booting without Secure Boot.
+const CONFIRM_KEY: char = 'y';
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InsecureBootPolicy {
+    /// Boot with a warning.
+    #[default]
+    Warn,
+    /// Boot only after the user confirmed it with a key press.
+    Confirm,
+    /// Do not boot.
+    Refuse,
+}
+
+impl InsecureBootPolicy {
+    /// Parse the policy embedded by lzbt. A missing section means [`Self::Warn`].
+    ///
+    /// An unknown policy is treated as the strictest one, so that a newer policy is never
+    /// weakened by an older stub.
+    pub fn from_section(data: Option<&[u8]>) -> Self {
+        match data {
+            None | Some(b"warn") => Self::Warn,
+            Some(b"confirm") => Self::Confirm,
+            _ => Self::Refuse,
+        }
+    }
+}
+
+/// Wait for a key press and return whether it was [`CONFIRM_KEY`].
+fn confirmed() -> bool {
+    // Drop key strokes from before the prompt, so that they do not confirm it.
+    input::flush();
+    input::read_key(None).map_or(false, |key| Chord::Key(CONFIRM_KEY).matches(&key))
+}
+
+/// Apply the policy to a boot without Secure Boot.
+///
+/// Returns an error if the stub must not boot.
+pub fn check_insecure_boot(policy: InsecureBootPolicy) -> Result<()> {
+    match policy {
+        InsecureBootPolicy::Warn => {
+            warn!("{}", Message::SecureBootDisabled);
+            Ok(())
+        }
+        InsecureBootPolicy::Confirm => {
+            warn!("{}", Message::SecureBootDisabledUnexpectedly);
+            print_wrapped(&format!("{}", Message::ConfirmInsecureBoot(CONFIRM_KEY)), 0);
+            if confirmed() {
+                Ok(())
+            } else {
+                error!("{}", Message::InsecureBootAborted);
+                Err(Status::ACCESS_DENIED.into())
+            }
+        }
+        InsecureBootPolicy::Refuse => {
+            error!("{}", Message::InsecureBootRefused);
+            Err(Status::SECURITY_VIOLATION.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(
+            InsecureBootPolicy::from_section(None),
+            InsecureBootPolicy::Warn
+        );
+        assert_eq!(
+            InsecureBootPolicy::from_section(Some(b"confirm")),
+            InsecureBootPolicy::Confirm
+        );
+        assert_eq!(
+            InsecureBootPolicy::from_section(Some(b"refuse")),
+            InsecureBootPolicy::Refuse
+        );
+        assert_eq!(
+            InsecureBootPolicy::from_section(Some(b"lax")),
+            InsecureBootPolicy::Refuse
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Pass the metadata of a cloud instance to the initrd as credentials.
+//!
+//! Cloud images are built once and booted as many instances, so their stub is signed once and
+//! cannot embed anything that differs between instances. A stub with an `.imds` section collects
+//! the metadata of the instance before booting and passes it to the initrd as system credentials
+//! in `/.extra/credentials`, like the credentials next to the stub, see [`crate::companions`]:
+//!
+//! - The serial number of the system from SMBIOS as `instance.serial`, which hypervisors usually
+//!   set to an identifier of the instance.
+//! - From an OpenStack config drive, i.e. a file system labeled `config-2`, the whole
+//!   `openstack/latest/meta_data.json` as `instance.metadata`, the ID of the instance as
+//!   `instance.id`, its host name as `system.hostname` and its SSH keys as
+//!   `ssh.authorized_keys.root`, which systemd picks up by itself.
+//!
+//! The metadata services of EC2 and GCE are only reachable over HTTP, which the stub does not
+//! speak, so their metadata has to be fetched by the initrd. Nothing of this is authenticated,
+//! so the credentials must only configure what the owner of the instance may choose anyway.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use log::{info, warn};
+use serde::Deserialize;
+use uefi::{cstr16, fs::FileSystem, CString16};
+
+use crate::{
+    cpio::{self, Cpio},
+    json,
+    partition::{open_partition, PartitionSelector},
+    smbios::system_serial_number,
+};
+
+/// The label of the file system of an OpenStack config drive.
+const CONFIG_DRIVE_LABEL: &str = "config-2";
+
+/// Larger metadata is ignored, since it has to fit into memory twice.
+const MAX_METADATA_SIZE: usize = 1024 * 1024;
+
+/// A credential with its name and value.
+pub type Credential = (String, Vec<u8>);
+
+/// The fields of the `meta_data.json` of an OpenStack config drive that become credentials.
+#[derive(Deserialize)]
+struct ConfigDriveMetadata {
+    uuid: Option<String>,
+    hostname: Option<String>,
+    /// The SSH keys by name.
+    #[serde(default)]
+    public_keys: BTreeMap<json::Name, String>,
+}
+
+/// The credentials that are derived from the `meta_data.json` of an OpenStack config drive.
+///
+/// The document itself is always passed on, even if the stub fails to deserialize it.
+pub fn config_drive_credentials(metadata: &[u8]) -> Vec<Credential> {
+    let mut credentials = Vec::from([(String::from("instance.metadata"), metadata.to_vec())]);
+    let Some(document) = json::from_slice::<ConfigDriveMetadata>(metadata) else {
+        warn!("Failed to parse the metadata of the config drive.");
+        return credentials;
+    };
+
+    let string = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(Vec::from)
+    };
+    if let Some(id) = string(&document.uuid) {
+        credentials.push(("instance.id".into(), id));
+    }
+    if let Some(hostname) = string(&document.hostname) {
+        credentials.push(("system.hostname".into(), hostname));
+    }
+
+    let keys = document
+        .public_keys
+        .values()
+        .fold(String::new(), |mut keys, key| {
+            keys.push_str(key.trim());
+            keys.push('\n');
+            keys
+        });
+    if !keys.is_empty() {
+        credentials.push(("ssh.authorized_keys.root".into(), keys.into()));
+    }
+
+    credentials
+}
+
+/// Read the `meta_data.json` of an OpenStack config drive, if there is one.
+fn read_config_drive() -> Option<Vec<u8>> {
+    let label = CString16::try_from(CONFIG_DRIVE_LABEL).ok()?;
+    let file_system = open_partition(&PartitionSelector::Label(label)).ok()?;
+    let metadata = FileSystem::new(file_system)
+        .read(cstr16!("\\openstack\\latest\\meta_data.json"))
+        .ok()?;
+    if metadata.len() > MAX_METADATA_SIZE {
+        warn!("Ignoring the metadata of the config drive, which is too large.");
+        return None;
+    }
+    Some(metadata)
+}
+
+/// Pack the credentials into an initrd. They are sorted by name, so that the measurement of the
+/// initrd only depends on their contents.
+pub fn pack_credentials(mut credentials: Vec<Credential>) -> cpio::Result {
+    credentials.sort();
+    credentials.dedup_by(|a, b| a.0 == b.0);
+
+    let mut cpio = Cpio::new();
+    cpio.pack_prefix(".extra/credentials", 0o500)?;
+    for (name, value) in &credentials {
+        cpio.pack_one(&format!("{name}.cred"), value, ".extra/credentials", 0o400)?;
+    }
+    cpio.pack_trailer()?;
+    Ok(cpio)
+}
+
+/// Collect the metadata of the instance into an initrd with credentials.
+///
+/// Returns `None` if no metadata was found.
+pub fn instance_metadata_initrd() -> Option<Vec<u8>> {
+    let mut credentials = Vec::new();
+    if let Some(serial) = system_serial_number() {
+        credentials.push(("instance.serial".into(), serial.into()));
+    }
+    if let Some(metadata) = read_config_drive() {
+        info!("Found the metadata of an OpenStack config drive.");
+        credentials.append(&mut config_drive_credentials(&metadata));
+    }
+    if credentials.is_empty() {
+        return None;
+    }
+
+    match pack_credentials(credentials) {
+        Ok(cpio) => Some(cpio.into_inner()),
+        Err(_) => {
+            warn!("Failed to pack the metadata of the instance.");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_config_drive_credentials() {
+        let metadata = br#"{"uuid": "83679162-1378-4288-a2d4-70e13ec132aa", "hostname": "web-1.novalocal", "launch_index": 0, "public_keys": {"alice": "ssh-ed25519 AAAA alice\n", "bob": "ssh-ed25519 BBBB bob"}, "meta": {}}"#;
+        let credentials = config_drive_credentials(metadata);
+        let value = |name: &str| {
+            credentials
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value.as_slice())
+        };
+        assert_eq!(value("instance.metadata"), Some(&metadata[..]));
+        assert_eq!(
+            value("instance.id"),
+            Some(&b"83679162-1378-4288-a2d4-70e13ec132aa"[..])
+        );
+        assert_eq!(value("system.hostname"), Some(&b"web-1.novalocal"[..]));
+        assert_eq!(
+            value("ssh.authorized_keys.root"),
+            Some(&b"ssh-ed25519 AAAA alice\nssh-ed25519 BBBB bob\n"[..])
+        );
+
+        // Documents that cannot be parsed are still passed on.
+        assert_eq!(
+            config_drive_credentials(b"{\"hostname\": 1.5}"),
+            [("instance.metadata".into(), b"{\"hostname\": 1.5}".to_vec())]
+        );
+    }
+
+    #[test]
+    fn pack_sorted_credentials() {
+        let packed =
+            |credentials: Vec<Credential>| pack_credentials(credentials).unwrap().into_inner();
+        let a = ("instance.id".into(), b"a".to_vec());
+        let b = ("instance.serial".into(), b"b".to_vec());
+        let initrd = packed(alloc::vec![b.clone(), a.clone()]);
+        assert_eq!(initrd, packed(alloc::vec![a, b]));
+
+        let position = |needle: &[u8]| initrd.windows(needle.len()).position(|w| w == needle);
+        assert!(
+            position(b".extra/credentials/instance.id.cred").unwrap()
+                < position(b".extra/credentials/instance.serial.cred").unwrap()
+        );
+    }
+}
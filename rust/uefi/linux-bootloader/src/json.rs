@@ -0,0 +1,128 @@
+//! Deserialize the JSON metadata of LUKS2 headers and cloud instances with serde-json-core.
+//!
+//! serde-json-core has no recursion limit, so documents are checked for their nesting depth
+//! first. Strings are unescaped into a buffer of the size of the document, so that they can be
+//! deserialized into owned strings.
+
+use alloc::{string::String, vec};
+use core::{borrow::Borrow, fmt};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::Deserialize;
+
+/// Deeper documents are rejected, so that a malicious header cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// The name of a member of an object, to deserialize objects with arbitrary names into a
+/// `BTreeMap`, e.g. the keyslots of LUKS2 by their ID.
+///
+/// serde-json-core only deserializes the names of members as `&str`, not as `String`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Name(pub String);
+
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl Visitor<'_> for NameVisitor {
+            type Value = Name;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a string")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Name, E> {
+                Ok(Name(name.into()))
+            }
+        }
+
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+/// Deserialize a JSON document, ignoring trailing NUL bytes, which pad the LUKS2 metadata area.
+pub fn from_slice<T: DeserializeOwned>(text: &[u8]) -> Option<T> {
+    let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+    let text = &text[..end];
+    if nesting_depth(text) > MAX_DEPTH {
+        return None;
+    }
+    // Unescaped strings are never longer than the document.
+    let mut buffer = vec![0; text.len()];
+    serde_json_core::from_slice_escaped(text, &mut buffer)
+        .ok()
+        .map(|(value, _)| value)
+}
+
+/// The deepest nesting of arrays and objects in a document.
+fn nesting_depth(text: &[u8]) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for &byte in text {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::BTreeMap, vec::Vec};
+
+    #[derive(Debug, PartialEq, Eq, Deserialize)]
+    struct Keyslot {
+        #[serde(rename = "type")]
+        kind: String,
+        key_size: u64,
+    }
+
+    #[derive(Debug, PartialEq, Eq, Deserialize)]
+    struct Document {
+        keyslots: BTreeMap<Name, Keyslot>,
+        salt: String,
+    }
+
+    #[test]
+    fn deserialize_documents() {
+        let document: Document = from_slice(
+            br#"{"keyslots":{"0":{"type":"luks2","key_size":64}},"salt":"a\/b\n","list":[1, -2.5, true, null, {"[": "]"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            document.keyslots["0"],
+            Keyslot {
+                kind: "luks2".into(),
+                key_size: 64
+            }
+        );
+        assert_eq!(document.salt, "a/b\n");
+
+        assert_eq!(from_slice::<Vec<u64>>(b"[]\0\0\0"), Some(Vec::new()));
+        assert_eq!(from_slice::<Vec<u64>>(b"[1"), None);
+        assert_eq!(from_slice::<Vec<u64>>(b"[1] x"), None);
+        assert_eq!(from_slice::<Vec<u64>>(&[b'['; 100]), None);
+        assert_eq!(nesting_depth(br#"[{"a": "[[[\"[["}]"#), 2);
+    }
+}
@@ -2,13 +2,48 @@
 
 extern crate alloc;
 
+pub mod addons;
+pub mod chunked_read;
+pub mod clock;
+pub mod cmdline_template;
 pub mod companions;
+pub mod console;
 pub mod cpio;
+pub mod drivers;
+pub mod efi_handover;
 pub mod efivars;
+pub mod embedded_config;
+pub mod error_code;
+pub mod first_stage;
+pub mod fw_cfg;
+pub mod gzip;
+pub mod hibernate;
+pub mod initrd_encryption;
+pub mod input;
+pub mod insecure_boot;
+pub mod instance_metadata;
+pub mod json;
 pub mod linux_loader;
+pub mod lockdown;
+pub mod log_file;
+pub mod luks;
 pub mod measure;
+pub mod memory;
+pub mod messages;
+pub mod partition;
 pub mod pe_loader;
 pub mod pe_section;
+pub mod pkcs7;
+pub mod qr;
+pub mod security_override;
+pub mod security_version;
+pub mod setup_header;
+pub mod slots;
+pub mod smbios;
+pub mod splash;
 pub mod tpm;
 pub mod uefi_helpers;
 pub mod unified_sections;
+pub mod verity;
+pub mod zboot;
+pub mod zeroize;
@@ -7,7 +7,7 @@
 
 use core::{ffi::c_void, pin::Pin, ptr::slice_from_raw_parts_mut};
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::boxed::Box;
 use uefi::{
     boot,
     proto::{
@@ -17,6 +17,8 @@ use uefi::{
     Handle, Identify, Result, ResultExt, Status,
 };
 
+use crate::zeroize::Zeroizing;
+
 /// The Linux kernel's initrd loading device path.
 ///
 /// The Linux kernel points us to
@@ -60,7 +62,7 @@ struct LoadFile2Protocol {
     ) -> Status,
 
     // This is not part of the official protocol struct.
-    initrd_data: Vec<u8>,
+    initrd_data: Zeroizing,
 }
 
 impl LoadFile2Protocol {
@@ -119,7 +121,7 @@ impl InitrdLoader {
     ///
     /// `handle` is the handle where the protocols are registered
     /// on. `file` is the file that is served to Linux.
-    pub fn new(handle: Handle, initrd_data: Vec<u8>) -> Result<Self> {
+    pub fn new(handle: Handle, initrd_data: Zeroizing) -> Result<Self> {
         let mut proto = Box::pin(LoadFile2Protocol {
             load_file: raw_load_file,
             initrd_data,
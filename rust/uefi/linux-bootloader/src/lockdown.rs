@@ -0,0 +1,132 @@
+//! A hardening policy that is enforced by the signature of the stub.
+//!
+//! lzbt embeds the policy into the `.lockdown` section, one kernel parameter per line, e.g.
+//! `lockdown=integrity` and `module.sig_enforce=1`. The kernel uses the last occurrence of these
+//! parameters, so the stub adds every parameter of the policy whose last value on the command
+//! line is weaker, e.g. `lockdown=confidentiality` satisfies `lockdown=integrity`. Neither the boot
+//! loader, SMBIOS, fw_cfg nor an addon can then weaken the policy.
+
+use alloc::{format, vec::Vec};
+use log::warn;
+
+use crate::cmdline_template::{insert_parameters, last_value};
+
+/// The parameters that a policy may contain, and their valid values from the weakest to the
+/// strongest.
+const PARAMETERS: [(&str, &[&str]); 2] = [
+    ("lockdown", &["integrity", "confidentiality"]),
+    ("module.sig_enforce", &["1"]),
+];
+
+/// The parameters of a policy, as `(name, value)`, or `None` if it contains anything else.
+pub fn parse_policy(policy: &str) -> Option<Vec<(&'static str, &str)>> {
+    policy
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, value) = line.split_once('=')?;
+            PARAMETERS
+                .iter()
+                .find(|(known, values)| *known == name && values.contains(&value))
+                .map(|(name, _)| (*name, value))
+        })
+        .collect()
+}
+
+/// How strong a value of a parameter is, if it is a valid one.
+fn strength(name: &str, value: &str) -> Option<usize> {
+    let (_, values) = PARAMETERS.iter().find(|(known, _)| *known == name)?;
+    values.iter().position(|known| *known == value)
+}
+
+/// Make sure that the command line ends up with the parameters of the policy, or stronger ones.
+pub fn enforce_policy(cmdline: Vec<u8>, policy: &[(&str, &str)]) -> Vec<u8> {
+    let missing = policy
+        .iter()
+        .filter(|(name, value)| {
+            let existing =
+                last_value(&cmdline, name).and_then(|existing| strength(name, &existing));
+            existing < strength(name, value)
+        })
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return cmdline;
+    }
+    warn!("Restoring the embedded lockdown policy on the command line.");
+    insert_parameters(&cmdline, missing.iter().map(|parameter| parameter.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdline_template::to_utf16_bytes;
+    use alloc::vec;
+
+    #[test]
+    fn parse_known_parameters_only() {
+        assert_eq!(
+            parse_policy("lockdown=integrity\nmodule.sig_enforce=1\n"),
+            Some(vec![("lockdown", "integrity"), ("module.sig_enforce", "1")])
+        );
+        assert_eq!(parse_policy(""), Some(vec![]));
+        assert_eq!(parse_policy("lockdown=none"), None);
+        assert_eq!(parse_policy("init=/bin/sh"), None);
+    }
+
+    #[test]
+    fn keep_the_policy_last() {
+        let policy = [("lockdown", "integrity"), ("module.sig_enforce", "1")];
+        let cmdline = to_utf16_bytes("init=/init lockdown=integrity module.sig_enforce=1");
+        assert_eq!(enforce_policy(cmdline.clone(), &policy), cmdline);
+
+        assert_eq!(
+            enforce_policy(
+                to_utf16_bytes("init=/init lockdown=integrity module.sig_enforce=0"),
+                &policy
+            ),
+            to_utf16_bytes(
+                "init=/init lockdown=integrity module.sig_enforce=0 module.sig_enforce=1"
+            )
+        );
+        assert_eq!(
+            enforce_policy(to_utf16_bytes("init=/init"), &policy),
+            to_utf16_bytes("init=/init lockdown=integrity module.sig_enforce=1")
+        );
+    }
+
+    #[test]
+    fn keep_stronger_parameters() {
+        let policy = [("lockdown", "integrity")];
+        let cmdline = to_utf16_bytes("lockdown=confidentiality");
+        assert_eq!(enforce_policy(cmdline.clone(), &policy), cmdline);
+
+        let policy = [("lockdown", "confidentiality")];
+        assert_eq!(
+            enforce_policy(to_utf16_bytes("lockdown=integrity"), &policy),
+            to_utf16_bytes("lockdown=integrity lockdown=confidentiality")
+        );
+        // Unknown values do not satisfy the policy.
+        assert_eq!(
+            enforce_policy(to_utf16_bytes("lockdown=none"), &policy),
+            to_utf16_bytes("lockdown=none lockdown=confidentiality")
+        );
+    }
+
+    #[test]
+    fn respect_quotes_and_init_arguments() {
+        let policy = [("lockdown", "integrity")];
+        let cmdline = to_utf16_bytes("\"lockdown=integrity\" -- single");
+        assert_eq!(enforce_policy(cmdline.clone(), &policy), cmdline);
+
+        assert_eq!(
+            enforce_policy(to_utf16_bytes("quiet -- lockdown=integrity"), &policy),
+            to_utf16_bytes("quiet lockdown=integrity -- lockdown=integrity")
+        );
+        assert_eq!(
+            enforce_policy(to_utf16_bytes("x=\"lockdown=integrity"), &policy),
+            to_utf16_bytes("x=\"lockdown=integrity\" lockdown=integrity")
+        );
+    }
+}
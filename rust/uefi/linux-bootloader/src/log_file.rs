@@ -0,0 +1,107 @@
+//! A log file on the ESP for boots that fail without anyone watching the console.
+//!
+//! If lzbt embeds the `.logfile` section, the log lines of the stub are also appended to
+//! [`LOG_FILE`] on the file system of the stub, so that a boot that ends in a reset can be
+//! analyzed afterwards. To spare the flash of the ESP, the lines are collected in memory and
+//! written once per boot, right before the kernel is started or the stub gives up, see [`flush`].
+//! The file is capped at [`MAX_SIZE`] by dropping the oldest lines.
+
+use alloc::{format, vec::Vec};
+use core::cell::UnsafeCell;
+
+use uefi::{boot, cstr16, fs::FileSystem, runtime, CStr16};
+
+/// The log file, relative to the root of the file system of the stub.
+pub const LOG_FILE: &CStr16 = cstr16!("\\loader\\lanzaboote-debug.log");
+
+/// The maximum size of the log file in bytes.
+pub const MAX_SIZE: usize = 64 * 1024;
+
+/// The log lines that have not been written yet, or `None` if there is no log file.
+struct Buffer(UnsafeCell<Option<Vec<u8>>>);
+
+// SAFETY: The stub runs on a single CPU and does not log from event notification functions, so
+// the buffer is never accessed concurrently.
+unsafe impl Sync for Buffer {}
+
+static BUFFER: Buffer = Buffer(UnsafeCell::new(None));
+
+fn with_buffer<R>(f: impl FnOnce(&mut Option<Vec<u8>>) -> R) -> R {
+    // SAFETY: See the `Sync` implementation. `f` does not log, so the reference is unique.
+    f(unsafe { &mut *BUFFER.0.get() })
+}
+
+/// Start collecting log lines for the log file.
+///
+/// A header with the current time separates the lines from those of previous boots.
+pub fn enable(stub_name: &str) {
+    let time = runtime::get_time()
+        .map(|time| format!("{time}"))
+        .unwrap_or_else(|_| "unknown time".into());
+    with_buffer(|buffer| *buffer = Some(format!("--- {stub_name} at {time}\n").into_bytes()));
+}
+
+/// Collect a log line, if the log file is enabled.
+pub fn append(line: &str) {
+    with_buffer(|buffer| {
+        if let Some(buffer) = buffer {
+            buffer.extend_from_slice(line.as_bytes());
+            buffer.push(b'\n');
+            // A boot that logs in a loop must not exhaust the memory.
+            cap(buffer, MAX_SIZE);
+        }
+    });
+}
+
+/// Drop the oldest lines of `log` until it is at most `max_size` bytes long.
+fn cap(log: &mut Vec<u8>, max_size: usize) {
+    if log.len() <= max_size {
+        return;
+    }
+    // Search from the last byte that is dropped anyway, which may end a line.
+    let excess = log.len() - max_size;
+    let start = log[excess - 1..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(log.len(), |newline| excess + newline);
+    log.drain(..start);
+}
+
+/// Append the collected log lines to the log file.
+///
+/// Errors are ignored, because the log file must never stop a boot.
+pub fn flush() {
+    let Some(lines) = with_buffer(Option::take) else {
+        return;
+    };
+    let Ok(file_system) = boot::get_image_file_system(boot::image_handle()) else {
+        return;
+    };
+    let mut file_system = FileSystem::new(file_system);
+
+    let mut log = file_system.read(LOG_FILE).unwrap_or_default();
+    log.extend_from_slice(&lines);
+    cap(&mut log, MAX_SIZE);
+    let _ = file_system.write(LOG_FILE, &log);
+
+    // Lines logged after this are kept for another flush, e.g. if the kernel returns.
+    with_buffer(|buffer| *buffer = Some(Vec::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_oldest_lines() {
+        let mut log = b"first\nsecond\nthird\n".to_vec();
+        cap(&mut log, 64);
+        assert_eq!(log, b"first\nsecond\nthird\n");
+        cap(&mut log, 13);
+        assert_eq!(log, b"second\nthird\n");
+        cap(&mut log, 12);
+        assert_eq!(log, b"third\n");
+        cap(&mut log, 3);
+        assert_eq!(log, b"");
+    }
+}
@@ -0,0 +1,1051 @@
+//! Unlock a LUKS2-encrypted partition with the kernel and initrd.
+//!
+//! A stub with a `.luks` section expects the payload partition, see [`crate::partition`], to be
+//! a LUKS2 volume. The stub finds it by its GPT partition GUID or the label in the LUKS2 header,
+//! opens a keyslot with a passphrase, and installs a block device that decrypts the volume. The
+//! firmware then reads the file system inside it like any other partition, so the kernel and
+//! initrd never exist in plaintext on the disk.
+//!
+//! The passphrase is unsealed from the TPM if the section names a sealed object, see
+//! [`LuksConfiguration`], and asked for on the console otherwise, or if unsealing fails, e.g.
+//! because the PCRs changed.
+//!
+//! Only what is needed to boot is implemented: `aes-xts-plain64` with 512-bit keys and keyslots
+//! with the `pbkdf2` KDF. Memory-hard KDFs such as `argon2id`, the default of cryptsetup, are too
+//! slow without the optimizations of the operating system, so a keyslot for the stub has to be
+//! added with `cryptsetup luksAddKey --pbkdf pbkdf2`.
+
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes256,
+};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use core::{ffi::c_void, ptr};
+
+use log::{debug, error, warn};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use uefi::{
+    boot::{self, ScopedProtocol},
+    guid,
+    proto::{
+        console::text::{Key, ScanCode},
+        device_path::DevicePath,
+        media::{block::BlockIO, disk::DiskIo},
+    },
+    CString16, Guid, Handle, Identify, Result, Status,
+};
+use xts_mode::{get_tweak_default, Xts128};
+
+use crate::{
+    console::{open_shared, print_wrapped},
+    input, json,
+    messages::Message,
+    partition::PartitionSelector,
+    tpm::tpm_unseal,
+    zeroize::{zeroize, Zeroizing},
+};
+
+const MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+/// The size of the binary header, which is followed by the JSON metadata.
+const BINARY_HEADER_SIZE: usize = 4096;
+/// The largest header that cryptsetup creates.
+const MAX_HEADER_SIZE: u64 = 4 << 20;
+/// Keyslot areas are always encrypted in sectors of this size.
+const KEYSLOT_SECTOR_SIZE: usize = 512;
+/// The size of the volume key of `aes-xts-plain64` with AES-256.
+const KEY_SIZE: usize = 64;
+/// How often the user may enter a wrong passphrase.
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// The vendor node that is appended to the device path of the partition for the decrypted volume.
+const DECRYPTED_DEVICE_GUID: Guid = guid!("9f601d1f-7b95-4159-8ae7-799a8b7e1e81");
+
+/// How the stub gets the passphrase, embedded in the `.luks` section.
+///
+/// The section starts with the line `luks2`, followed by optional `key=value` lines:
+/// * `tpm2-handle=0x81000001`: the persistent handle of a sealed object with the passphrase.
+/// * `tpm2-pcrs=7+11`: the PCRs of the SHA256 bank that the object is sealed to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LuksConfiguration {
+    pub tpm2_handle: Option<u32>,
+    pub tpm2_pcrs: Vec<u32>,
+}
+
+impl LuksConfiguration {
+    /// Parse the contents of the `.luks` section. Unknown keys are rejected, so that the stub
+    /// does not silently ignore a way of unlocking that it does not understand.
+    pub fn parse(section: &str) -> Option<Self> {
+        let mut lines = section
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        if lines.next()? != "luks2" {
+            return None;
+        }
+        let mut configuration = Self::default();
+        for line in lines {
+            match line.split_once('=')? {
+                ("tpm2-handle", handle) => {
+                    let handle = handle.strip_prefix("0x")?;
+                    configuration.tpm2_handle = Some(u32::from_str_radix(handle, 16).ok()?);
+                }
+                ("tpm2-pcrs", pcrs) => {
+                    configuration.tpm2_pcrs = pcrs
+                        .split('+')
+                        .map(|pcr| pcr.parse().ok())
+                        .collect::<Option<_>>()?;
+                }
+                _ => return None,
+            }
+        }
+        Some(configuration)
+    }
+}
+
+/// The JSON metadata of a LUKS2 header, limited to the fields that the stub uses.
+///
+/// Most fields are optional, so that keyslots, segments and digests of types that the stub does
+/// not support are only skipped.
+#[derive(Debug, Deserialize)]
+struct JsonMetadata {
+    keyslots: BTreeMap<json::Name, JsonKeyslot>,
+    segments: BTreeMap<json::Name, JsonSegment>,
+    digests: BTreeMap<json::Name, JsonDigest>,
+    config: Option<JsonConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonConfig {
+    requirements: Option<JsonRequirements>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRequirements {
+    #[serde(default)]
+    mandatory: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonKeyslot {
+    #[serde(rename = "type")]
+    kind: String,
+    key_size: Option<u64>,
+    priority: Option<u64>,
+    area: Option<JsonArea>,
+    af: Option<JsonAntiForensic>,
+    kdf: Option<JsonKdf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonArea {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: Option<String>,
+    size: Option<String>,
+    encryption: Option<String>,
+    key_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonAntiForensic {
+    #[serde(rename = "type")]
+    kind: String,
+    stripes: Option<u64>,
+    hash: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonKdf {
+    #[serde(rename = "type")]
+    kind: String,
+    hash: Option<String>,
+    iterations: Option<u64>,
+    salt: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonDigest {
+    #[serde(rename = "type")]
+    kind: String,
+    hash: Option<String>,
+    iterations: Option<u64>,
+    salt: Option<String>,
+    digest: Option<String>,
+    #[serde(default)]
+    keyslots: Vec<String>,
+    #[serde(default)]
+    segments: Vec<String>,
+}
+
+impl JsonDigest {
+    /// The KDF of a digest is described by the same fields as that of a keyslot.
+    fn kdf(&self) -> JsonKdf {
+        JsonKdf {
+            kind: self.kind.clone(),
+            hash: self.hash.clone(),
+            iterations: self.iterations,
+            salt: self.salt.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonSegment {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: Option<String>,
+    size: Option<String>,
+    iv_tweak: Option<String>,
+    encryption: Option<String>,
+    sector_size: Option<u64>,
+}
+
+/// A 64-bit value, which LUKS2 stores as a string of digits.
+fn parse_u64(value: &Option<String>) -> Option<u64> {
+    value.as_deref()?.parse().ok()
+}
+
+/// The fields of the binary header that the stub uses.
+struct BinaryHeader {
+    /// The size of the binary header and the JSON metadata.
+    size: usize,
+    label: String,
+}
+
+impl BinaryHeader {
+    fn parse(header: &[u8]) -> Option<Self> {
+        if header.get(..6)? != MAGIC || header.get(6..8)? != [0, 2] {
+            return None;
+        }
+        let size = u64::from_be_bytes(header.get(8..16)?.try_into().ok()?);
+        if !(BINARY_HEADER_SIZE as u64 + 1..=MAX_HEADER_SIZE).contains(&size) {
+            return None;
+        }
+        Some(Self {
+            size: size as usize,
+            label: String::from(nul_terminated(header.get(24..72)?)?),
+        })
+    }
+}
+
+/// The string in a fixed-size, NUL-padded field of the binary header.
+fn nul_terminated(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).ok()
+}
+
+/// Check the checksum of the binary header and the JSON metadata, which cryptsetup computes with
+/// the checksum field set to zero.
+fn verify_checksum(header: &[u8]) -> bool {
+    if nul_terminated(&header[72..104]) != Some("sha256") {
+        return false;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&header[..448]);
+    hasher.update([0; 64]);
+    hasher.update(&header[512..]);
+    hasher.finalize()[..] == header[448..480]
+}
+
+/// The parameters of PBKDF2 with HMAC-SHA256.
+#[derive(Debug)]
+struct Pbkdf2 {
+    salt: Vec<u8>,
+    iterations: u32,
+}
+
+impl Pbkdf2 {
+    fn parse(kdf: &JsonKdf) -> Option<Self> {
+        if kdf.kind != "pbkdf2" || kdf.hash.as_deref()? != "sha256" {
+            return None;
+        }
+        Some(Self {
+            salt: STANDARD.decode(kdf.salt.as_deref()?).ok()?,
+            iterations: u32::try_from(kdf.iterations?).ok()?,
+        })
+    }
+
+    fn derive(&self, password: &[u8], output: &mut [u8]) {
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, &self.salt, self.iterations, output);
+    }
+}
+
+/// A keyslot that the stub can open.
+#[derive(Debug)]
+struct Keyslot {
+    name: String,
+    area_offset: u64,
+    /// The size of the encrypted key material at the start of the area.
+    material_size: usize,
+    stripes: usize,
+    kdf: Pbkdf2,
+}
+
+impl Keyslot {
+    fn parse(name: &str, keyslot: &JsonKeyslot) -> Option<Self> {
+        let area = keyslot.area.as_ref()?;
+        let af = keyslot.af.as_ref()?;
+        if keyslot.kind != "luks2"
+            || keyslot.key_size? != KEY_SIZE as u64
+            || area.kind != "raw"
+            || area.encryption.as_deref()? != "aes-xts-plain64"
+            || area.key_size? != KEY_SIZE as u64
+            || af.kind != "luks1"
+            || af.hash.as_deref()? != "sha256"
+        {
+            return None;
+        }
+        // Keyslots with priority 0 are only used if they are selected explicitly.
+        if keyslot.priority == Some(0) {
+            return None;
+        }
+
+        let stripes = usize::try_from(af.stripes?).ok()?;
+        let material_size = KEY_SIZE
+            .checked_mul(stripes)?
+            .checked_add(KEYSLOT_SECTOR_SIZE - 1)?
+            / KEYSLOT_SECTOR_SIZE
+            * KEYSLOT_SECTOR_SIZE;
+        if stripes == 0 || material_size as u64 > parse_u64(&area.size)? {
+            return None;
+        }
+        Some(Self {
+            name: String::from(name),
+            area_offset: parse_u64(&area.offset)?,
+            material_size,
+            stripes,
+            kdf: Pbkdf2::parse(keyslot.kdf.as_ref()?)?,
+        })
+    }
+
+    /// Decrypt the key material of the keyslot with a passphrase and merge it into a candidate
+    /// for the volume key, which is only correct if the passphrase was.
+    fn open(&self, material: &[u8], passphrase: &[u8]) -> Zeroizing {
+        let mut key = [0; KEY_SIZE];
+        self.kdf.derive(passphrase, &mut key);
+        let xts = aes_xts(&key);
+        zeroize(&mut key);
+
+        let mut material = Zeroizing::new(material.to_vec());
+        for (sector, chunk) in (0..).zip(material.chunks_exact_mut(KEYSLOT_SECTOR_SIZE)) {
+            xts.decrypt_sector(chunk, get_tweak_default(sector));
+        }
+        af_merge(&material, KEY_SIZE, self.stripes)
+    }
+}
+
+/// The digest that a volume key is checked against.
+#[derive(Debug)]
+struct KeyDigest {
+    kdf: Pbkdf2,
+    digest: Vec<u8>,
+    keyslots: Vec<String>,
+}
+
+impl KeyDigest {
+    fn parse(digest: &JsonDigest) -> Option<Self> {
+        Some(Self {
+            kdf: Pbkdf2::parse(&digest.kdf())?,
+            digest: STANDARD.decode(digest.digest.as_deref()?).ok()?,
+            keyslots: digest.keyslots.clone(),
+        })
+    }
+
+    fn verify(&self, key: &[u8]) -> bool {
+        let mut digest = Zeroizing::new(vec![0; self.digest.len()]);
+        self.kdf.derive(key, &mut digest);
+        // Compare in constant time.
+        digest
+            .iter()
+            .zip(&self.digest)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+}
+
+/// The encrypted data of the volume.
+#[derive(Debug)]
+struct Segment {
+    offset: u64,
+    /// The size in bytes, or `None` if the segment extends to the end of the partition.
+    size: Option<u64>,
+    iv_tweak: u64,
+    sector_size: u32,
+}
+
+impl Segment {
+    fn parse(segment: &JsonSegment) -> Option<Self> {
+        if segment.encryption.as_deref()? != "aes-xts-plain64" {
+            return None;
+        }
+        let sector_size = u32::try_from(segment.sector_size?).ok()?;
+        if !(512..=4096).contains(&sector_size) || !sector_size.is_power_of_two() {
+            return None;
+        }
+        Some(Self {
+            offset: parse_u64(&segment.offset)?,
+            size: match segment.size.as_deref()? {
+                "dynamic" => None,
+                _ => Some(parse_u64(&segment.size)?),
+            },
+            iv_tweak: parse_u64(&segment.iv_tweak)?,
+            sector_size,
+        })
+    }
+}
+
+/// The parts of the JSON metadata that the stub uses.
+#[derive(Debug)]
+struct Metadata {
+    segment: Segment,
+    digest: KeyDigest,
+    /// The keyslots of the volume key that the stub can open.
+    keyslots: Vec<Keyslot>,
+}
+
+impl Metadata {
+    fn parse(json: &JsonMetadata) -> Option<Self> {
+        // Requirements such as an interrupted reencryption change how the data is encrypted.
+        let requirements = json
+            .config
+            .as_ref()
+            .and_then(|config| config.requirements.as_ref());
+        if requirements.map_or(false, |requirements| !requirements.mandatory.is_empty()) {
+            warn!("The LUKS2 volume has requirements that are not supported.");
+            return None;
+        }
+
+        let segments = json
+            .segments
+            .iter()
+            .filter(|(_, segment)| segment.kind == "crypt")
+            .collect::<Vec<_>>();
+        let [(segment_name, segment)] = segments[..] else {
+            return None;
+        };
+
+        let digest = json
+            .digests
+            .values()
+            .find(|digest| digest.segments.contains(&segment_name.0))?;
+        let digest = KeyDigest::parse(digest)?;
+
+        let mut keyslots = Vec::new();
+        for (name, keyslot) in &json.keyslots {
+            if !digest.keyslots.contains(&name.0) {
+                continue;
+            }
+            match Keyslot::parse(&name.0, keyslot) {
+                Some(keyslot) => keyslots.push(keyslot),
+                None => debug!("Skipping the unsupported LUKS2 keyslot {}.", name.0),
+            }
+        }
+
+        Some(Self {
+            segment: Segment::parse(segment)?,
+            digest,
+            keyslots,
+        })
+    }
+
+    /// Open the volume key with a passphrase, trying all keyslots with their material.
+    fn open(&self, materials: &[Vec<u8>], passphrase: &[u8]) -> Option<Zeroizing> {
+        self.keyslots
+            .iter()
+            .zip(materials)
+            .map(|(keyslot, material)| keyslot.open(material, passphrase))
+            .find(|key| self.digest.verify(key))
+    }
+}
+
+/// AES-256 in XTS mode, i.e. `aes-xts-plain64`. `key` is the data key followed by the tweak key.
+fn aes_xts(key: &[u8; KEY_SIZE]) -> Xts128<Aes256> {
+    let (data, tweak) = key.split_at(KEY_SIZE / 2);
+    Xts128::new(
+        Aes256::new(GenericArray::from_slice(data)),
+        Aes256::new(GenericArray::from_slice(tweak)),
+    )
+}
+
+/// Hash every 32 bytes of `block` with their index, as the anti-forensic splitter does.
+fn diffuse(block: &mut [u8]) {
+    for (index, chunk) in (0u32..).zip(block.chunks_mut(32)) {
+        let mut hasher = Sha256::new();
+        hasher.update(index.to_be_bytes());
+        hasher.update(&*chunk);
+        chunk.copy_from_slice(&hasher.finalize()[..chunk.len()]);
+    }
+}
+
+/// Merge the stripes of the anti-forensic splitter of LUKS back into the key.
+fn af_merge(material: &[u8], key_size: usize, stripes: usize) -> Zeroizing {
+    let mut key = Zeroizing::new(vec![0; key_size]);
+    for (index, stripe) in material.chunks_exact(key_size).take(stripes).enumerate() {
+        for (byte, stripe) in key.iter_mut().zip(stripe) {
+            *byte ^= stripe;
+        }
+        if index + 1 < stripes {
+            diffuse(&mut key);
+        }
+    }
+    key
+}
+
+/// Read the header of the LUKS2 volume on a partition, if it is one.
+fn read_binary_header(disk: &DiskIo, media_id: u32) -> Option<(BinaryHeader, Vec<u8>)> {
+    let mut buffer = vec![0; BINARY_HEADER_SIZE];
+    disk.read_disk(media_id, 0, &mut buffer).ok()?;
+    Some((BinaryHeader::parse(&buffer)?, buffer))
+}
+
+/// Find the LUKS2 volume that the selector matches.
+///
+/// Fails with `NOT_FOUND` if no volume matches, and with `ABORTED` if several do.
+fn find_volume(selector: &PartitionSelector) -> Result<Handle> {
+    let mut found = Vec::new();
+    for handle in boot::find_handles::<BlockIO>()? {
+        let Ok(media_id) = open_shared::<BlockIO>(handle).map(|block| block.media().media_id())
+        else {
+            continue;
+        };
+        let Some((header, _)) = open_shared::<DiskIo>(handle)
+            .ok()
+            .and_then(|disk| read_binary_header(&disk, media_id))
+        else {
+            continue;
+        };
+        let matches = match selector {
+            PartitionSelector::PartUuid(_) => selector.matches(handle),
+            PartitionSelector::Label(label) => {
+                CString16::try_from(header.label.as_str()).map_or(false, |l| l == *label)
+            }
+        };
+        if matches {
+            found.push(handle);
+        }
+    }
+    match found[..] {
+        [handle] => Ok(handle),
+        [] => Err(Status::NOT_FOUND.into()),
+        _ => Err(Status::ABORTED.into()),
+    }
+}
+
+/// Ask for the passphrase on the console. Returns `None` if the user pressed Escape.
+///
+/// The passphrase is not echoed, like cryptsetup does.
+fn read_passphrase() -> Option<Zeroizing> {
+    print_wrapped(&format!("{}", Message::EnterPassphrase), 0);
+    input::flush();
+    let mut passphrase = Zeroizing::new(Vec::new());
+    loop {
+        match input::read_key(None)? {
+            Key::Printable(c) => match u16::from(c) {
+                0x0d => return Some(passphrase),
+                // Backspace removes the last character, which may be several bytes in UTF-8.
+                0x08 => while passphrase.pop().map_or(false, |b| b & 0xc0 == 0x80) {},
+                _ => {
+                    let mut encoded = [0; 4];
+                    passphrase
+                        .extend_from_slice(char::from(c).encode_utf8(&mut encoded).as_bytes());
+                    zeroize(&mut encoded);
+                }
+            },
+            Key::Special(ScanCode::ESCAPE) => return None,
+            Key::Special(_) => {}
+        }
+    }
+}
+
+/// Get the volume key with the sealed passphrase from the TPM, or by asking the user.
+fn unlock_volume_key(
+    metadata: &Metadata,
+    materials: &[Vec<u8>],
+    configuration: &LuksConfiguration,
+) -> Result<Zeroizing> {
+    if let Some(handle) = configuration.tpm2_handle {
+        match tpm_unseal(handle, &configuration.tpm2_pcrs) {
+            Ok(passphrase) => match metadata.open(materials, &passphrase) {
+                Some(key) => return Ok(key),
+                None => warn!("The passphrase from the TPM does not unlock the boot partition."),
+            },
+            Err(err) => warn!(
+                "Failed to unseal the passphrase of the boot partition: {}",
+                err.status()
+            ),
+        }
+    }
+
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        let Some(passphrase) = read_passphrase() else {
+            break;
+        };
+        if let Some(key) = metadata.open(materials, &passphrase) {
+            return Ok(key);
+        }
+        error!("{}", Message::WrongPassphrase);
+    }
+    Err(Status::ACCESS_DENIED.into())
+}
+
+/// The media of a Block I/O protocol of revision 1.
+#[repr(C)]
+struct BlockIoMedia {
+    media_id: u32,
+    removable_media: bool,
+    media_present: bool,
+    logical_partition: bool,
+    read_only: bool,
+    write_caching: bool,
+    block_size: u32,
+    io_align: u32,
+    last_block: u64,
+}
+
+/// The Block I/O protocol, which the stub provides for the decrypted volume.
+#[repr(C)]
+struct BlockIoProtocol {
+    revision: u64,
+    media: *const BlockIoMedia,
+    reset: unsafe extern "efiapi" fn(this: *mut BlockIoProtocol, extended: bool) -> Status,
+    read_blocks: unsafe extern "efiapi" fn(
+        this: *const BlockIoProtocol,
+        media_id: u32,
+        lba: u64,
+        buffer_size: usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    write_blocks: unsafe extern "efiapi" fn(
+        this: *mut BlockIoProtocol,
+        media_id: u32,
+        lba: u64,
+        buffer_size: usize,
+        buffer: *const c_void,
+    ) -> Status,
+    flush_blocks: unsafe extern "efiapi" fn(this: *mut BlockIoProtocol) -> Status,
+}
+
+/// The decrypted volume. The protocol is the first field, so that the functions of the protocol
+/// find the volume from the pointer to it.
+#[repr(C)]
+struct DecryptedDevice {
+    protocol: BlockIoProtocol,
+    media: BlockIoMedia,
+    disk: ScopedProtocol<DiskIo>,
+    disk_media_id: u32,
+    xts: Xts128<Aes256>,
+    /// The offset of the segment on the partition in bytes.
+    offset: u64,
+    /// The sector number of the first block, as used for the IV.
+    first_sector: u64,
+}
+
+unsafe extern "efiapi" fn reset(_this: *mut BlockIoProtocol, _extended: bool) -> Status {
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn read_blocks(
+    this: *const BlockIoProtocol,
+    media_id: u32,
+    lba: u64,
+    buffer_size: usize,
+    buffer: *mut c_void,
+) -> Status {
+    // SAFETY: The protocol is only installed as part of a `DecryptedDevice`.
+    let device = unsafe { &*this.cast::<DecryptedDevice>() };
+    let block_size = device.media.block_size as usize;
+    if media_id != device.media.media_id {
+        return Status::MEDIA_CHANGED;
+    }
+    if buffer_size % block_size != 0 {
+        return Status::BAD_BUFFER_SIZE;
+    }
+    let blocks = (buffer_size / block_size) as u64;
+    if buffer.is_null()
+        || lba
+            .checked_add(blocks)
+            .map_or(true, |end| end > device.media.last_block + 1)
+    {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // SAFETY: The caller passes a buffer of `buffer_size` bytes.
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer.cast::<u8>(), buffer_size) };
+    let offset = device.offset + lba * block_size as u64;
+    if let Err(err) = device.disk.read_disk(device.disk_media_id, offset, buffer) {
+        return err.status();
+    }
+    for (sector, block) in (device.first_sector + lba..).zip(buffer.chunks_exact_mut(block_size)) {
+        device
+            .xts
+            .decrypt_sector(block, get_tweak_default(u128::from(sector)));
+    }
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn write_blocks(
+    _this: *mut BlockIoProtocol,
+    _media_id: u32,
+    _lba: u64,
+    _buffer_size: usize,
+    _buffer: *const c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn flush_blocks(_this: *mut BlockIoProtocol) -> Status {
+    Status::SUCCESS
+}
+
+/// The decrypted volume, which is available to the firmware until this is dropped.
+///
+/// Dropping it removes the volume again and overwrites the key, so it must only be dropped once
+/// the files have been read from it.
+pub struct UnlockedPartition {
+    handle: Handle,
+    device: Option<Box<DecryptedDevice>>,
+    device_path: Option<Box<[u8]>>,
+}
+
+impl UnlockedPartition {
+    fn install(
+        partition: Handle,
+        disk: ScopedProtocol<DiskIo>,
+        disk_media_id: u32,
+        partition_size: u64,
+        segment: &Segment,
+        key: &[u8],
+    ) -> Result<Self> {
+        let size = match segment.size {
+            Some(size) => size,
+            None => partition_size
+                .checked_sub(segment.offset)
+                .ok_or(Status::VOLUME_CORRUPTED)?,
+        };
+        let blocks = size / u64::from(segment.sector_size);
+        if blocks == 0 {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        // Like the device mapper, the IV counts in sectors of the segment, while the tweak
+        // counts in sectors of 512 bytes.
+        let first_sector = segment.iv_tweak / u64::from(segment.sector_size / 512);
+        let key: &[u8; KEY_SIZE] = key.try_into().map_err(|_| Status::UNSUPPORTED)?;
+        let mut device = Box::new(DecryptedDevice {
+            protocol: BlockIoProtocol {
+                revision: 0x0001_0000,
+                media: ptr::null(),
+                reset,
+                read_blocks,
+                write_blocks,
+                flush_blocks,
+            },
+            media: BlockIoMedia {
+                media_id: 0,
+                removable_media: false,
+                media_present: true,
+                logical_partition: true,
+                read_only: true,
+                write_caching: false,
+                block_size: segment.sector_size,
+                io_align: 1,
+                last_block: blocks - 1,
+            },
+            disk,
+            disk_media_id,
+            xts: aes_xts(key),
+            offset: segment.offset,
+            first_sector,
+        });
+        device.protocol.media = ptr::addr_of!(device.media);
+
+        let path = open_shared::<DevicePath>(partition)?;
+        let path = path.as_bytes();
+        // Replace the end node with a vendor node and an end node.
+        let mut device_path = path[..path.len().saturating_sub(4)].to_vec();
+        device_path.extend_from_slice(&[0x04, 0x03]);
+        device_path.extend_from_slice(&20u16.to_le_bytes());
+        device_path.extend_from_slice(&DECRYPTED_DEVICE_GUID.to_bytes());
+        device_path.extend_from_slice(&[0x7f, 0xff, 0x04, 0x00]);
+        let device_path = device_path.into_boxed_slice();
+
+        // SAFETY: The device path and the device are kept alive until they are uninstalled.
+        let handle = unsafe {
+            boot::install_protocol_interface(None, &DevicePath::GUID, device_path.as_ptr().cast())
+        }?;
+        let mut unlocked = Self {
+            handle,
+            device: None,
+            device_path: Some(device_path),
+        };
+        // SAFETY: See above.
+        unsafe {
+            boot::install_protocol_interface(
+                Some(handle),
+                &BlockIO::GUID,
+                ptr::addr_of!(device.protocol).cast(),
+            )
+        }?;
+        unlocked.device = Some(device);
+
+        // The file system drivers of the firmware bind to the new device.
+        if let Err(err) = boot::connect_controller(handle, None, None, true) {
+            debug!(
+                "No driver supports the decrypted volume yet: {}",
+                err.status()
+            );
+        }
+        Ok(unlocked)
+    }
+}
+
+impl Drop for UnlockedPartition {
+    fn drop(&mut self) {
+        let _ = boot::disconnect_controller(self.handle, None, None);
+        if let Some(device) = self.device.take() {
+            // SAFETY: This is the interface that was installed.
+            let result = unsafe {
+                boot::uninstall_protocol_interface(
+                    self.handle,
+                    &BlockIO::GUID,
+                    ptr::addr_of!(device.protocol).cast(),
+                )
+            };
+            if result.is_err() {
+                // The firmware may still call into the device, so it must stay valid.
+                warn!("Failed to remove the decrypted boot partition.");
+                core::mem::forget(device);
+                core::mem::forget(self.device_path.take());
+                return;
+            }
+        }
+        if let Some(device_path) = self.device_path.take() {
+            // SAFETY: This is the interface that was installed.
+            let result = unsafe {
+                boot::uninstall_protocol_interface(
+                    self.handle,
+                    &DevicePath::GUID,
+                    device_path.as_ptr().cast(),
+                )
+            };
+            if result.is_err() {
+                core::mem::forget(device_path);
+            }
+        }
+    }
+}
+
+/// Find the LUKS2 volume of the selected partition, unlock it, and make its contents available
+/// to the firmware until the returned value is dropped.
+pub fn unlock_partition(
+    selector: &PartitionSelector,
+    configuration: &LuksConfiguration,
+) -> Result<UnlockedPartition> {
+    let handle = find_volume(selector)?;
+    let (media_id, partition_size) = {
+        let block = open_shared::<BlockIO>(handle)?;
+        let media = block.media();
+        let size = (media.last_block() + 1) * u64::from(media.block_size());
+        (media.media_id(), size)
+    };
+    let disk = open_shared::<DiskIo>(handle)?;
+
+    let (binary_header, mut header) =
+        read_binary_header(&disk, media_id).ok_or(Status::VOLUME_CORRUPTED)?;
+    header.resize(binary_header.size, 0);
+    disk.read_disk(media_id, 0, &mut header)?;
+    if !verify_checksum(&header) {
+        error!("The LUKS2 header of the boot partition is corrupted.");
+        return Err(Status::VOLUME_CORRUPTED.into());
+    }
+    let metadata = json::from_slice(&header[BINARY_HEADER_SIZE..])
+        .as_ref()
+        .and_then(Metadata::parse)
+        .ok_or(Status::UNSUPPORTED)?;
+    if metadata.keyslots.is_empty() {
+        error!("The boot partition has no keyslot with the pbkdf2 KDF.");
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    let mut materials = Vec::new();
+    for keyslot in &metadata.keyslots {
+        let mut material = vec![0; keyslot.material_size];
+        disk.read_disk(media_id, keyslot.area_offset, &mut material)?;
+        debug!("Read the LUKS2 keyslot {}.", keyslot.name);
+        materials.push(material);
+    }
+
+    let key = unlock_volume_key(&metadata, &materials, configuration)?;
+    UnlockedPartition::install(
+        handle,
+        disk,
+        media_id,
+        partition_size,
+        &metadata.segment,
+        &key,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        hex.as_bytes()
+            .chunks(2)
+            .map(|digits| u8::from_str_radix(core::str::from_utf8(digits).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_configuration() {
+        assert_eq!(
+            LuksConfiguration::parse("luks2\n"),
+            Some(LuksConfiguration::default())
+        );
+        assert_eq!(
+            LuksConfiguration::parse("luks2\ntpm2-handle=0x81000001\ntpm2-pcrs=7+11\n"),
+            Some(LuksConfiguration {
+                tpm2_handle: Some(0x8100_0001),
+                tpm2_pcrs: vec![7, 11],
+            })
+        );
+        assert_eq!(LuksConfiguration::parse("luks1"), None);
+        assert_eq!(LuksConfiguration::parse("luks2\nfido2-device=auto"), None);
+    }
+
+    #[test]
+    fn derive_pbkdf2_sha256() {
+        // From RFC 7914.
+        let mut output = [0; 64];
+        let kdf = Pbkdf2 {
+            salt: b"salt".to_vec(),
+            iterations: 1,
+        };
+        kdf.derive(b"passwd", &mut output);
+        assert_eq!(
+            output[..],
+            from_hex(concat!(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+            ))
+        );
+    }
+
+    #[test]
+    fn decrypt_xts_sector() {
+        let key = core::array::from_fn(|i| i as u8);
+        let xts = aes_xts(&key);
+
+        // Encrypted with the XTS implementation of OpenSSL.
+        let mut sector = [0; 512];
+        sector[..32].copy_from_slice(&from_hex(
+            "f87ca2f29b117c1b024a6ec8e8c5994e76f7d16b43eed21e6936126969e00dab",
+        ));
+        sector[480..].copy_from_slice(&from_hex(
+            "eb6523fbfb5ca033725f703578b7dbb0e790ce5900c47286caaef5e457fecc4b",
+        ));
+        xts.decrypt_sector(&mut sector, get_tweak_default(5));
+        assert_eq!(sector[..32], core::array::from_fn::<u8, 32, _>(|i| i as u8));
+        assert_eq!(
+            sector[480..],
+            core::array::from_fn::<u8, 32, _>(|i| (480 + i) as u8)
+        );
+    }
+
+    #[test]
+    fn open_keyslot() {
+        // A volume with a keyslot of 4 stripes for the passphrase "lanzaboote", created with
+        // Python as cryptsetup would.
+        let json = json::from_slice(
+            br#"{
+                "keyslots": {
+                    "0": {
+                        "type": "luks2", "key_size": 64,
+                        "af": {"type": "luks1", "stripes": 4, "hash": "sha256"},
+                        "area": {"type": "raw", "offset": "32768", "size": "4096",
+                                 "encryption": "aes-xts-plain64", "key_size": 64},
+                        "kdf": {"type": "pbkdf2", "hash": "sha256", "iterations": 1000,
+                                "salt": "ZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXp7fH1+f4CBgoM="}
+                    },
+                    "1": {
+                        "type": "luks2", "key_size": 64,
+                        "af": {"type": "luks1", "stripes": 4000, "hash": "sha256"},
+                        "area": {"type": "raw", "offset": "290816", "size": "258048",
+                                 "encryption": "aes-xts-plain64", "key_size": 64},
+                        "kdf": {"type": "argon2id", "time": 4, "memory": 1048576, "cpus": 4,
+                                "salt": "ZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXp7fH1+f4CBgoM="}
+                    }
+                },
+                "segments": {
+                    "0": {"type": "crypt", "offset": "16777216", "size": "dynamic",
+                          "iv_tweak": "0", "encryption": "aes-xts-plain64", "sector_size": 4096}
+                },
+                "digests": {
+                    "0": {"type": "pbkdf2", "keyslots": ["0", "1"], "segments": ["0"],
+                          "hash": "sha256", "iterations": 1000,
+                          "salt": "yMnKy8zNzs/Q0dLT1NXW19jZ2tvc3d7f4OHi4+Tl5uc=",
+                          "digest": "r7qHbeRW6Inzz8yItJaBGeGaLeCf2NlQQ6yTFmALNA0="}
+                },
+                "config": {"json_size": "12288", "keyslots_size": "16744448"}
+            }"#,
+        )
+        .unwrap();
+        let metadata = Metadata::parse(&json).unwrap();
+        assert_eq!(metadata.keyslots.len(), 1);
+        assert_eq!(metadata.keyslots[0].area_offset, 32768);
+        assert_eq!(metadata.keyslots[0].material_size, 512);
+        assert_eq!(metadata.segment.offset, 16777216);
+        assert_eq!(metadata.segment.size, None);
+        assert_eq!(metadata.segment.sector_size, 4096);
+
+        let material = from_hex(concat!(
+            "0de8b39b5aa94099e6d970f356529460c416ed1b011670d943fe30c56c985f55f442e65c71a895e4",
+            "1c590cfb796ba35502de3b190585956d6b909bdaa5cf44c1fab2d9e2273ebe69db56ddb4ef4fb910",
+            "6aed8bbcccc1b5803acfa8bd535234fb3347eb3f504487a191098bbdb3e90244f3c7f3b7f283e955",
+            "208a281404cba9ac610e67f2b6fea1dac28ef4c85a6a4f31297d3601d34db849ed3e3166b14d6507",
+            "ea8c28c5662fb39c126fc6cac3b83d28116c766a4f3cb41e18de5cd74573928db740ecf83b1dd740",
+            "565c935eebad5c7aade36d7c24374b40000e7c6dcbf6871037679f680473ad41e01da1186b429c02",
+            "b62917d41952bbd7613717931b87a7809dfff5cca35e4ea369c34cbb6d4fdbf953dfcdfa8795e69f",
+            "a0a1e57e430bbc9e8d96a1238a199702a4e1cd733e5f1df8a09134f0425c0a07351afe533ca33300",
+            "d3f8a1337217ddf378f514345311d4abc2f7205c2db3e615e2f93e119cbcdbea387f66d55e9d42ec",
+            "ff72a8b4129993b32cef3a96731170eecc5a0a1bad812b3dafc1ed8b4f0242e6b6f61fc40585a554",
+            "d5e2beefc128adbbbf66f7723e99dd3df3d2e1a5a1f8515246eb46a2bf614e4294f7d59137d4f2e8",
+            "92b9486fff82f8278be0bfe64452cbd7c41f1ffdea3b949280ff4f668088633f691382c73651b0e2",
+            "721df53c79f1af3fa5f93c3e993e89365215f345cc7bca4b67a8ac66849841c1",
+        ));
+        let key = metadata.open(&[material.clone()], b"lanzaboote").unwrap();
+        assert_eq!(
+            key[..],
+            from_hex(concat!(
+                "030a11181f262d343b424950575e656c737a81888f969da4abb2b9c0c7ced5dc",
+                "e3eaf1f8ff060d141b222930373e454c535a61686f767d848b9299a0a7aeb5bc"
+            ))
+        );
+        assert!(metadata.open(&[material], b"wrong").is_none());
+    }
+
+    #[test]
+    fn parse_binary_header() {
+        let mut header = vec![0; 16384];
+        header[..8].copy_from_slice(b"LUKS\xba\xbe\x00\x02");
+        header[8..16].copy_from_slice(&16384u64.to_be_bytes());
+        header[24..33].copy_from_slice(b"boot-data");
+        header[72..78].copy_from_slice(b"sha256");
+        header[4096..4098].copy_from_slice(b"{}");
+        let checksum = Sha256::digest(&header);
+        header[448..480].copy_from_slice(&checksum);
+
+        let binary_header = BinaryHeader::parse(&header).unwrap();
+        assert_eq!(binary_header.size, 16384);
+        assert_eq!(binary_header.label, "boot-data");
+        assert!(verify_checksum(&header));
+
+        header[4097] = b' ';
+        assert!(!verify_checksum(&header));
+        header[7] = 1;
+        assert!(BinaryHeader::parse(&header).is_none());
+    }
+}
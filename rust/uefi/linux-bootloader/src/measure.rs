@@ -7,6 +7,7 @@ use uefi::{
 };
 
 use crate::{
+    addons::Addon,
     companions::{CompanionInitrd, CompanionInitrdType},
     efivars::BOOT_LOADER_VENDOR_UUID,
     pe_section::pe_section_data,
@@ -140,3 +141,61 @@ pub fn measure_companion_initrds(companions: &[CompanionInitrd]) -> uefi::Result
 
     Ok(measurements)
 }
+
+/// Measures a kernel command line that differs from the embedded one into the kernel
+/// configuration PCR, e.g. because placeholders were substituted.
+pub fn measure_cmdline(cmdline: &str) -> uefi::Result<u32> {
+    measure_kernel_config(cmdline.as_bytes(), "Kernel command line")
+}
+
+/// Measures an additional initrd that does not come from an addon into the kernel configuration
+/// PCR, e.g. one passed by the hypervisor.
+pub fn measure_initrd(initrd: &[u8]) -> uefi::Result<u32> {
+    measure_kernel_config(initrd, "Initrd")
+}
+
+fn measure_kernel_config(data: &[u8], description: &str) -> uefi::Result<u32> {
+    if !tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_CONFIG, data, description)? {
+        return Ok(0);
+    }
+
+    runtime::set_variable(
+        cstr16!("StubPcrKernelParameters"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &TPM_PCR_INDEX_KERNEL_CONFIG.0.to_le_bytes(),
+    )?;
+
+    Ok(1)
+}
+
+/// Measures the command lines and initrds of addons into the kernel configuration PCR.
+///
+/// Relies on the passed order of `addons` for measurement stability.
+pub fn measure_addons(addons: &[Addon]) -> uefi::Result<u32> {
+    let mut measurements = 0;
+
+    for addon in addons {
+        if let Some(cmdline) = &addon.cmdline {
+            if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_CONFIG, cmdline, "Addon command line")? {
+                measurements += 1;
+            }
+        }
+        if let Some(initrd) = &addon.initrd {
+            if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_CONFIG, initrd, "Addon initrd")? {
+                measurements += 1;
+            }
+        }
+    }
+
+    if measurements > 0 {
+        runtime::set_variable(
+            cstr16!("StubPcrKernelParameters"),
+            &BOOT_LOADER_VENDOR_UUID,
+            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+            &TPM_PCR_INDEX_KERNEL_CONFIG.0.to_le_bytes(),
+        )?;
+    }
+
+    Ok(measurements)
+}
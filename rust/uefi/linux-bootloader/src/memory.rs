@@ -0,0 +1,205 @@
+//! Allocating the memory that the kernel is loaded into.
+//!
+//! Some kernels can only run below 4 GiB or need more alignment than a page, and some firmware
+//! hands out allocations that its memory map does not describe as such. Allocations made here
+//! honor the requirements of the kernel and are checked against the memory map, so that such a
+//! machine fails with an error instead of crashing in the kernel.
+
+use core::ptr::NonNull;
+
+use log::warn;
+use uefi::{
+    boot::{self, AllocateType, MemoryType},
+    mem::memory_map::MemoryMap,
+    Result, Status,
+};
+
+/// UEFI mandates 4 KiB pages.
+const UEFI_PAGE_BITS: usize = 12;
+const UEFI_PAGE_MASK: usize = (1 << UEFI_PAGE_BITS) - 1;
+pub const UEFI_PAGE_SIZE: usize = 1 << UEFI_PAGE_BITS;
+
+/// The highest address below 4 GiB.
+const MAX_ADDRESS_BELOW_4G: u64 = 0xffff_ffff;
+
+/// Converts a length in bytes to the number of required pages.
+pub fn bytes_to_pages(bytes: usize) -> usize {
+    bytes
+        .checked_add(UEFI_PAGE_MASK)
+        .map(|rounded_up| rounded_up >> UEFI_PAGE_BITS)
+        .unwrap_or(1 << (usize::try_from(usize::BITS).unwrap() - UEFI_PAGE_BITS))
+}
+
+/// Where an allocation may be placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    /// The highest address the allocation may cover, if any.
+    pub max_address: Option<u64>,
+    /// The alignment of the start of the allocation. This is a power of two of at least a page.
+    pub alignment: usize,
+    /// The alignment that is tried first, which is at least [`Placement::alignment`].
+    pub preferred_alignment: usize,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Self {
+            max_address: None,
+            alignment: UEFI_PAGE_SIZE,
+            preferred_alignment: UEFI_PAGE_SIZE,
+        }
+    }
+}
+
+impl Placement {
+    /// Restrict the allocation to the memory up to `max_address`.
+    pub fn below(mut self, max_address: u64) -> Self {
+        self.max_address = Some(
+            self.max_address
+                .map_or(max_address, |max| max.min(max_address)),
+        );
+        self
+    }
+
+    /// Restrict the allocation to the memory below 4 GiB.
+    pub fn below_4g(self) -> Self {
+        self.below(MAX_ADDRESS_BELOW_4G)
+    }
+
+    /// Require at least this alignment. Alignments that are not a power of two are ignored.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        if valid_alignment(alignment) {
+            self.alignment = self.alignment.max(alignment);
+            self.preferred_alignment = self.preferred_alignment.max(alignment);
+        }
+        self
+    }
+
+    /// Try this alignment first, and smaller ones down to the required alignment if the memory
+    /// is too fragmented for it.
+    pub fn with_preferred_alignment(mut self, alignment: usize) -> Self {
+        if valid_alignment(alignment) {
+            self.preferred_alignment = self.preferred_alignment.max(alignment);
+        }
+        self
+    }
+}
+
+fn valid_alignment(alignment: usize) -> bool {
+    if alignment != 0 && !alignment.is_power_of_two() {
+        warn!("Ignoring the invalid alignment {alignment:#x}.");
+    }
+    alignment.is_power_of_two()
+}
+
+/// Allocate zeroed pages for `size` bytes according to a placement.
+///
+/// The allocation covers exactly [`bytes_to_pages`] pages, so it can be freed with
+/// [`boot::free_pages`] like any other page allocation.
+pub fn allocate(
+    size: usize,
+    memory_type: MemoryType,
+    placement: Placement,
+) -> Result<&'static mut [u8]> {
+    let mut alignment = placement.preferred_alignment;
+    loop {
+        match allocate_aligned(size, memory_type, placement, alignment) {
+            Err(error)
+                if error.status() == Status::OUT_OF_RESOURCES
+                    && alignment > placement.alignment =>
+            {
+                alignment /= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn allocate_aligned(
+    size: usize,
+    memory_type: MemoryType,
+    placement: Placement,
+    alignment: usize,
+) -> Result<&'static mut [u8]> {
+    let pages = bytes_to_pages(size);
+    // Allocate enough to find an aligned start and free the rest afterwards.
+    let extra_pages = bytes_to_pages(alignment) - 1;
+    let total_pages = pages
+        .checked_add(extra_pages)
+        .ok_or(Status::OUT_OF_RESOURCES)?;
+    let allocate_type = match placement.max_address {
+        Some(max_address) => AllocateType::MaxAddress(max_address),
+        None => AllocateType::AnyPages,
+    };
+    let allocation = boot::allocate_pages(allocate_type, memory_type, total_pages)?;
+
+    let start = allocation.as_ptr() as usize;
+    let aligned = (start + alignment - 1) & !(alignment - 1);
+    let head_pages = (aligned - start) >> UEFI_PAGE_BITS;
+    let tail_pages = extra_pages - head_pages;
+    // SAFETY: The head and the tail are the parts of the allocation outside of the aligned pages.
+    unsafe {
+        if head_pages > 0 {
+            boot::free_pages(allocation, head_pages)?;
+        }
+        if tail_pages > 0 {
+            let tail = NonNull::new_unchecked((aligned + (pages << UEFI_PAGE_BITS)) as *mut u8);
+            boot::free_pages(tail, tail_pages)?;
+        }
+    }
+    // SAFETY: `aligned` is the start of `pages` allocated pages.
+    let base = unsafe { NonNull::new_unchecked(aligned as *mut u8) };
+
+    if let Err(error) = check_allocation(aligned, pages, memory_type, placement) {
+        // SAFETY: The pages were allocated above and are not used anywhere.
+        let _ = unsafe { boot::free_pages(base, pages) };
+        return Err(error);
+    }
+
+    // SAFETY: The pages are allocated and nothing else refers to them.
+    unsafe {
+        core::ptr::write_bytes(base.as_ptr(), 0, size);
+        Ok(core::slice::from_raw_parts_mut(base.as_ptr(), size))
+    }
+}
+
+/// Check that an allocation satisfies its placement and is described as allocated by the memory
+/// map.
+fn check_allocation(
+    start: usize,
+    pages: usize,
+    memory_type: MemoryType,
+    placement: Placement,
+) -> Result<()> {
+    let start = start as u64;
+    let end = start + ((pages as u64) << UEFI_PAGE_BITS);
+
+    if matches!(placement.max_address, Some(max_address) if end - 1 > max_address) {
+        warn!("The firmware allocated {start:#x}..{end:#x} above the requested maximum address.");
+        return Err(Status::OUT_OF_RESOURCES.into());
+    }
+
+    let memory_map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    // The firmware may split the allocation into several descriptors, but they must cover it
+    // contiguously.
+    let mut covered = start;
+    while covered < end {
+        let Some(descriptor) = memory_map.entries().find(|descriptor| {
+            let descriptor_end = descriptor.phys_start + (descriptor.page_count << UEFI_PAGE_BITS);
+            descriptor.phys_start <= covered && covered < descriptor_end
+        }) else {
+            break;
+        };
+        if descriptor.ty != memory_type {
+            break;
+        }
+        covered = descriptor.phys_start + (descriptor.page_count << UEFI_PAGE_BITS);
+    }
+
+    if covered < end {
+        warn!("The memory map does not describe the allocation {start:#x}..{end:#x} as {memory_type:?}.");
+        return Err(Status::OUT_OF_RESOURCES.into());
+    }
+
+    Ok(())
+}
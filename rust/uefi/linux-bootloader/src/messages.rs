@@ -0,0 +1,317 @@
+//! Translations of the messages that users see when the boot fails or needs their attention.
+//!
+//! lzbt embeds the language into the `.locale` section, e.g. `de`. Diagnostics that are meant for
+//! developers stay in English. Firmware fonts often only cover Latin-1, so the catalog avoids
+//! characters beyond it.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Locale {
+    #[default]
+    English,
+    German,
+    French,
+    Spanish,
+}
+
+impl Locale {
+    /// Parse the language embedded by lzbt, e.g. `de` or `de_DE.UTF-8`.
+    ///
+    /// A missing section or an unknown language means English.
+    pub fn from_section(data: Option<&[u8]>) -> Self {
+        match data.and_then(|data| data.get(..2)) {
+            Some(b"de") => Self::German,
+            Some(b"fr") => Self::French,
+            Some(b"es") => Self::Spanish,
+            _ => Self::English,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::German,
+            2 => Self::French,
+            3 => Self::Spanish,
+            _ => Self::English,
+        }
+    }
+}
+
+static LOCALE: AtomicU8 = AtomicU8::new(Locale::English as u8);
+
+/// Set the language of all following messages.
+pub fn set_locale(locale: Locale) {
+    LOCALE.store(locale as u8, Ordering::Relaxed);
+}
+
+fn locale() -> Locale {
+    Locale::from_u8(LOCALE.load(Ordering::Relaxed))
+}
+
+/// A message that is displayed in the language set with [`set_locale`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// The hash of e.g. the kernel does not match the one in the stub.
+    HashMismatch(&'a str),
+    /// The hash of e.g. the kernel is in the revocation list.
+    Revoked(&'a str),
+    /// Follows a problem that is not enforced because Secure Boot is disabled.
+    ContinuingAnyway,
+    /// Precedes the failed step of the boot.
+    FailedToBoot,
+    /// Asks to press a key to dismiss an error, which is dismissed after the given seconds anyway.
+    DismissError(u64),
+    SecureBootDisabled,
+    SecureBootDisabledUnexpectedly,
+    /// Asks to press the given key to boot without Secure Boot.
+    ConfirmInsecureBoot(char),
+    InsecureBootAborted,
+    InsecureBootRefused,
+    SecurityVersionTooLow {
+        version: u64,
+        minimum: u64,
+    },
+    InitrdKeyMissing,
+    InitrdKeyMismatch,
+    /// Asks for the passphrase of the encrypted partition with the kernel and initrd.
+    EnterPassphrase,
+    WrongPassphrase,
+}
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match locale() {
+            Locale::English => self.english(f),
+            Locale::German => self.german(f),
+            Locale::French => self.french(f),
+            Locale::Spanish => self.spanish(f),
+        }
+    }
+}
+
+impl Message<'_> {
+    fn english(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::HashMismatch(name) => write!(f, "{name} hash does not match!"),
+            Self::Revoked(name) => write!(f, "{name} is revoked!"),
+            Self::ContinuingAnyway => write!(f, "Continuing anyway."),
+            Self::FailedToBoot => write!(f, "Failed to boot"),
+            Self::DismissError(seconds) => {
+                write!(f, "Press any key to continue, or wait {seconds} seconds.")
+            }
+            Self::SecureBootDisabled => write!(
+                f,
+                "Secure Boot is disabled. The integrity of the boot is not verified."
+            ),
+            Self::SecureBootDisabledUnexpectedly => write!(
+                f,
+                "Secure Boot is disabled. If you did not disable it, someone else did."
+            ),
+            Self::ConfirmInsecureBoot(key) => {
+                write!(f, "Press {key} to boot anyway, or any other key to abort.")
+            }
+            Self::InsecureBootAborted => write!(f, "Not booting without Secure Boot."),
+            Self::InsecureBootRefused => write!(
+                f,
+                "Secure Boot is disabled. Enable it in the firmware settings to boot."
+            ),
+            Self::SecurityVersionTooLow { version, minimum } => write!(
+                f,
+                "Security version {version} is below the minimum of {minimum}."
+            ),
+            Self::InitrdKeyMissing => write!(
+                f,
+                "The initrd is encrypted, but no key has been provisioned."
+            ),
+            Self::InitrdKeyMismatch => write!(
+                f,
+                "The initrd was encrypted with a different key or is corrupted."
+            ),
+            Self::EnterPassphrase => write!(f, "Enter the passphrase of the boot partition:"),
+            Self::WrongPassphrase => write!(f, "Wrong passphrase."),
+        }
+    }
+
+    fn german(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::HashMismatch(name) => write!(f, "Der Hash von {name} stimmt nicht!"),
+            Self::Revoked(name) => write!(f, "{name} wurde widerrufen!"),
+            Self::ContinuingAnyway => write!(f, "Der Start wird trotzdem fortgesetzt."),
+            Self::FailedToBoot => write!(f, "Start fehlgeschlagen"),
+            Self::DismissError(seconds) => write!(
+                f,
+                "Drücken Sie eine beliebige Taste, um fortzufahren, oder warten Sie {seconds} \
+                 Sekunden."
+            ),
+            Self::SecureBootDisabled => write!(
+                f,
+                "Secure Boot ist deaktiviert. Die Integrität des Starts wird nicht geprüft."
+            ),
+            Self::SecureBootDisabledUnexpectedly => write!(
+                f,
+                "Secure Boot ist deaktiviert. Wenn Sie es nicht deaktiviert haben, hat es jemand \
+                 anderes getan."
+            ),
+            Self::ConfirmInsecureBoot(key) => write!(
+                f,
+                "Drücken Sie {key}, um trotzdem zu starten, oder eine andere Taste, um \
+                 abzubrechen."
+            ),
+            Self::InsecureBootAborted => write!(f, "Ohne Secure Boot wird nicht gestartet."),
+            Self::InsecureBootRefused => write!(
+                f,
+                "Secure Boot ist deaktiviert. Aktivieren Sie es in den Firmware-Einstellungen, um \
+                 zu starten."
+            ),
+            Self::SecurityVersionTooLow { version, minimum } => write!(
+                f,
+                "Die Sicherheitsversion {version} liegt unter dem Minimum von {minimum}."
+            ),
+            Self::InitrdKeyMissing => write!(
+                f,
+                "Die initrd ist verschlüsselt, aber es wurde kein Schlüssel \
+                 hinterlegt."
+            ),
+            Self::InitrdKeyMismatch => write!(
+                f,
+                "Die initrd wurde mit einem anderen Schlüssel verschlüsselt oder ist \
+                 beschädigt."
+            ),
+            Self::EnterPassphrase => {
+                write!(f, "Geben Sie die Passphrase der Boot-Partition ein:")
+            }
+            Self::WrongPassphrase => write!(f, "Falsche Passphrase."),
+        }
+    }
+
+    fn french(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::HashMismatch(name) => write!(f, "Le hachage de {name} ne correspond pas !"),
+            Self::Revoked(name) => write!(f, "{name} est révoqué !"),
+            Self::ContinuingAnyway => write!(f, "Le démarrage continue malgré tout."),
+            Self::FailedToBoot => write!(f, "Échec du démarrage"),
+            Self::DismissError(seconds) => write!(
+                f,
+                "Appuyez sur une touche pour continuer, ou attendez {seconds} secondes."
+            ),
+            Self::SecureBootDisabled => write!(
+                f,
+                "Secure Boot est désactivé. L'intégrité du démarrage \
+                 n'est pas vérifiée."
+            ),
+            Self::SecureBootDisabledUnexpectedly => write!(
+                f,
+                "Secure Boot est désactivé. Si vous ne l'avez pas désactivé, \
+                 quelqu'un d'autre l'a fait."
+            ),
+            Self::ConfirmInsecureBoot(key) => write!(
+                f,
+                "Appuyez sur {key} pour démarrer quand même, ou sur une autre touche \
+                 pour annuler."
+            ),
+            Self::InsecureBootAborted => {
+                write!(f, "Pas de démarrage sans Secure Boot.")
+            }
+            Self::InsecureBootRefused => write!(
+                f,
+                "Secure Boot est désactivé. Activez-le dans les paramètres du \
+                 firmware pour démarrer."
+            ),
+            Self::SecurityVersionTooLow { version, minimum } => write!(
+                f,
+                "La version de sécurité {version} est inférieure au minimum de \
+                 {minimum}."
+            ),
+            Self::InitrdKeyMissing => write!(
+                f,
+                "L'initrd est chiffré, mais aucune clé n'a été \
+                 provisionnée."
+            ),
+            Self::InitrdKeyMismatch => write!(
+                f,
+                "L'initrd a été chiffré avec une autre clé ou est \
+                 corrompu."
+            ),
+            Self::EnterPassphrase => write!(
+                f,
+                "Saisissez la phrase secrète de la partition de démarrage :"
+            ),
+            Self::WrongPassphrase => write!(f, "Phrase secrète incorrecte."),
+        }
+    }
+
+    fn spanish(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::HashMismatch(name) => write!(f, "¡El hash de {name} no coincide!"),
+            Self::Revoked(name) => write!(f, "¡{name} está revocado!"),
+            Self::ContinuingAnyway => write!(f, "Se continúa de todos modos."),
+            Self::FailedToBoot => write!(f, "Error al arrancar"),
+            Self::DismissError(seconds) => write!(
+                f,
+                "Pulse cualquier tecla para continuar, o espere {seconds} segundos."
+            ),
+            Self::SecureBootDisabled => write!(
+                f,
+                "Secure Boot está desactivado. No se verifica la integridad del arranque."
+            ),
+            Self::SecureBootDisabledUnexpectedly => write!(
+                f,
+                "Secure Boot está desactivado. Si usted no lo desactivó, lo hizo otra \
+                 persona."
+            ),
+            Self::ConfirmInsecureBoot(key) => write!(
+                f,
+                "Pulse {key} para arrancar de todos modos, o cualquier otra tecla para cancelar."
+            ),
+            Self::InsecureBootAborted => write!(f, "No se arranca sin Secure Boot."),
+            Self::InsecureBootRefused => write!(
+                f,
+                "Secure Boot está desactivado. Actívelo en la configuración del \
+                 firmware para arrancar."
+            ),
+            Self::SecurityVersionTooLow { version, minimum } => write!(
+                f,
+                "La versión de seguridad {version} es inferior al mínimo de {minimum}."
+            ),
+            Self::InitrdKeyMissing => write!(
+                f,
+                "La initrd está cifrada, pero no se ha aprovisionado ninguna clave."
+            ),
+            Self::InitrdKeyMismatch => {
+                write!(f, "La initrd se cifró con otra clave o está dañada.")
+            }
+            Self::EnterPassphrase => write!(
+                f,
+                "Introduzca la frase de contraseña de la partición de arranque:"
+            ),
+            Self::WrongPassphrase => write!(f, "Frase de contraseña incorrecta."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn translate_messages() {
+        assert_eq!(Locale::from_section(None), Locale::English);
+        assert_eq!(Locale::from_section(Some(b"de_DE.UTF-8")), Locale::German);
+        assert_eq!(Locale::from_section(Some(b"x")), Locale::English);
+
+        set_locale(Locale::French);
+        assert_eq!(
+            Message::Revoked("Kernel").to_string(),
+            "Kernel est révoqué !"
+        );
+        set_locale(Locale::English);
+        assert_eq!(
+            Message::HashMismatch("Initrd").to_string(),
+            "Initrd hash does not match!"
+        );
+    }
+}
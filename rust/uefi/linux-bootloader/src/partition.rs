@@ -0,0 +1,107 @@
+//! Load the kernel and initrd from another partition than the stub.
+//!
+//! The ESP is often too small for the kernels and initrds of many generations. A stub with a
+//! `.partsel` section loads them from the partition that the section selects instead, like in
+//! `/etc/fstab`: `PARTUUID=<GPT partition GUID>` or `LABEL=<file system label>`. The firmware must
+//! be able to read the file system of that partition, which usually means FAT, unless the stub
+//! loads a driver for it, see [`crate::drivers`]. The partition may also be on a network disk,
+//! e.g. an iSCSI LUN that the iSCSI initiator of the firmware logs in to. The paths in the
+//! `.linux` and `.initrd` sections are relative to the root of the selected partition, and the
+//! files are verified by their hashes as usual.
+
+use alloc::vec::Vec;
+use uefi::{
+    boot::{self, ScopedProtocol},
+    proto::{
+        device_path::{media::PartitionSignature, DevicePath, DevicePathNodeEnum},
+        media::{
+            file::{File, FileSystemVolumeLabel},
+            fs::SimpleFileSystem,
+        },
+    },
+    CString16, Guid, Handle, Result, Status,
+};
+
+use crate::console::open_shared;
+
+/// How the partition with the kernel and initrd is found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionSelector {
+    /// The unique GUID of a GPT partition.
+    PartUuid(Guid),
+    /// The label of the file system.
+    Label(CString16),
+}
+
+impl PartitionSelector {
+    /// Parse the contents of the `.partsel` section.
+    pub fn parse(section: &str) -> Option<Self> {
+        let section = section.trim();
+        if let Some(uuid) = section.strip_prefix("PARTUUID=") {
+            Guid::try_parse(uuid).ok().map(Self::PartUuid)
+        } else if let Some(label) = section.strip_prefix("LABEL=") {
+            CString16::try_from(label).ok().map(Self::Label)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the file system on `handle` is on the selected partition.
+    pub(crate) fn matches(&self, handle: Handle) -> bool {
+        match self {
+            Self::PartUuid(uuid) => open_shared::<DevicePath>(handle).map_or(false, |path| {
+                path.node_iter().any(|node| {
+                    matches!(
+                        node.as_enum(),
+                        Ok(DevicePathNodeEnum::MediaHardDrive(drive))
+                            if drive.partition_signature() == PartitionSignature::Guid(*uuid)
+                    )
+                })
+            }),
+            Self::Label(label) => open_shared::<SimpleFileSystem>(handle)
+                .and_then(|mut file_system| file_system.open_volume())
+                .and_then(|mut volume| volume.get_boxed_info::<FileSystemVolumeLabel>())
+                .map_or(false, |info| info.volume_label() == &**label),
+        }
+    }
+}
+
+/// Open the file system of the selected partition.
+///
+/// Fails with `NOT_FOUND` if no file system is on the partition, and with `ABORTED` if several
+/// are, e.g. because a label is not unique.
+pub fn open_partition(selector: &PartitionSelector) -> Result<ScopedProtocol<SimpleFileSystem>> {
+    let handles = boot::find_handles::<SimpleFileSystem>()?
+        .into_iter()
+        .filter(|handle| selector.matches(*handle))
+        .collect::<Vec<_>>();
+    match handles[..] {
+        [handle] => boot::open_protocol_exclusive::<SimpleFileSystem>(handle),
+        [] => Err(Status::NOT_FOUND.into()),
+        _ => Err(Status::ABORTED.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uefi::guid;
+
+    #[test]
+    fn parse_selectors() {
+        assert_eq!(
+            PartitionSelector::parse("PARTUUID=0fc63daf-8483-4772-8e79-3d69d8477de4\n"),
+            Some(PartitionSelector::PartUuid(guid!(
+                "0fc63daf-8483-4772-8e79-3d69d8477de4"
+            )))
+        );
+        assert_eq!(
+            PartitionSelector::parse("LABEL=boot-data"),
+            Some(PartitionSelector::Label(
+                CString16::try_from("boot-data").unwrap()
+            ))
+        );
+        assert_eq!(PartitionSelector::parse("PARTUUID=not-a-guid"), None);
+        assert_eq!(PartitionSelector::parse("UUID=1234-ABCD"), None);
+    }
+}
@@ -4,14 +4,12 @@ use core::ptr::NonNull;
 use alloc::vec::Vec;
 use goblin::pe::PE;
 use uefi::{
-    boot::{self, AllocateType, MemoryType},
+    boot::{self, MemoryType},
     proto::loaded_image::LoadedImage,
     table, Handle, Status,
 };
 
-/// UEFI mandates 4 KiB pages.
-const UEFI_PAGE_BITS: usize = 12;
-const UEFI_PAGE_MASK: usize = (1 << UEFI_PAGE_BITS) - 1;
+use crate::memory::{allocate, bytes_to_pages, Placement};
 
 #[cfg(target_arch = "aarch64")]
 fn make_instruction_cache_coherent(memory: &[u8]) {
@@ -70,23 +68,28 @@ pub struct Image {
     entry: extern "efiapi" fn(Handle, Option<NonNull<c_void>>) -> Status,
 }
 
-/// Converts a length in bytes to the number of required pages.
-fn bytes_to_pages(bytes: usize) -> usize {
-    bytes
-        .checked_add(UEFI_PAGE_MASK)
-        .map(|rounded_up| rounded_up >> UEFI_PAGE_BITS)
-        .unwrap_or(1 << (usize::try_from(usize::BITS).unwrap() - UEFI_PAGE_BITS))
-}
-
 impl Image {
     /// Loads and relocates a PE file.
     ///
+    /// The image is placed according to `placement` and the section alignment of the PE file.
+    ///
     /// The image must be handed to [`start`] later. If this does not
     /// happen, the memory allocated for the unpacked PE binary will
     /// leak.
-    pub fn load(file_data: &[u8]) -> uefi::Result<Image> {
+    pub fn load(file_data: &[u8], placement: Placement) -> uefi::Result<Image> {
         let pe = PE::parse(file_data).map_err(|_| Status::LOAD_ERROR)?;
 
+        // Image base relocations are not supported. This is checked before allocating, so that a
+        // caller can fall back to another way of starting the image.
+        if pe
+            .header
+            .optional_header
+            .and_then(|h| *h.data_directories.get_base_relocation_table())
+            .is_some()
+        {
+            return Err(Status::INCOMPATIBLE_VERSION.into());
+        }
+
         // Allocate all memory the image will need in virtual memory.
         // We follow shim here and allocate as EfiLoaderCode.
         let image = {
@@ -103,16 +106,13 @@ impl Image {
 
             let length = usize::try_from(section_lengths.into_iter().max().unwrap_or(0)).unwrap();
 
-            let base = boot::allocate_pages(
-                AllocateType::AnyPages,
-                MemoryType::LOADER_CODE,
-                bytes_to_pages(length),
-            )?;
+            let section_alignment = pe
+                .header
+                .optional_header
+                .map_or(0, |h| h.windows_fields.section_alignment);
+            let placement = placement.with_alignment(usize::try_from(section_alignment).unwrap());
 
-            unsafe {
-                core::ptr::write_bytes(base.as_ptr(), 0, length);
-                core::slice::from_raw_parts_mut(base.as_ptr(), length)
-            }
+            allocate(length, MemoryType::LOADER_CODE, placement)?
         };
 
         // Populate all sections in virtual memory.
@@ -132,16 +132,6 @@ impl Image {
             image[virt_start..virt_end].copy_from_slice(&file_data[raw_start..raw_end]);
         }
 
-        // Image base relocations are not supported.
-        if pe
-            .header
-            .optional_header
-            .and_then(|h| *h.data_directories.get_base_relocation_table())
-            .is_some()
-        {
-            return Err(Status::INCOMPATIBLE_VERSION.into());
-        }
-
         // On some platforms, the instruction cache is not coherent with the data cache.
         // We don't want to execute stale icache contents instead of the code we just loaded.
         // Platform-specific flushes need to be performed to prevent this from happening.
@@ -4,18 +4,24 @@
 // and_then below and this can't be expressed with map.
 #![allow(clippy::bind_instead_of_map)]
 
-use alloc::{borrow::ToOwned, string::String};
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
 use goblin::pe::section_table::SectionTable;
 
 /// Extracts the data of a section in a loaded PE file
 /// based on the section table.
+///
+/// Sections that are larger than their data in the file or that
+/// extend beyond `pe_data` have no data.
 pub fn pe_section_data<'a>(pe_data: &'a [u8], section: &SectionTable) -> Option<&'a [u8]> {
-    let section_start: usize = section.virtual_address.try_into().ok()?;
+    if section.virtual_size > section.size_of_raw_data {
+        return None;
+    }
 
-    assert!(section.virtual_size <= section.size_of_raw_data);
-    let section_end: usize = section_start + usize::try_from(section.virtual_size).ok()?;
+    let section_start: usize = section.virtual_address.try_into().ok()?;
+    let section_end: usize =
+        section_start.checked_add(usize::try_from(section.virtual_size).ok()?)?;
 
-    Some(&pe_data[section_start..section_end])
+    pe_data.get(section_start..section_end)
 }
 
 /// Extracts the data of a section of a loaded PE file
@@ -31,6 +37,21 @@ pub fn pe_section<'a>(pe_data: &'a [u8], section_name: &str) -> Option<&'a [u8]>
 }
 
 /// Extracts the data of a section of a loaded PE image and returns it as a string.
+///
+/// Sections that are not valid UTF-8 are treated like missing ones.
 pub fn pe_section_as_string<'a>(pe_data: &'a [u8], section_name: &str) -> Option<String> {
-    pe_section(pe_data, section_name).map(|data| core::str::from_utf8(data).unwrap().to_owned())
+    pe_section(pe_data, section_name)
+        .and_then(|data| core::str::from_utf8(data).ok())
+        .map(|s| s.to_owned())
+}
+
+/// Extracts the names and data of all sections of a loaded PE file.
+pub fn pe_sections<'a>(pe_data: &'a [u8]) -> Option<Vec<(String, &'a [u8])>> {
+    let pe_binary = goblin::pe::PE::parse(pe_data).ok()?;
+
+    pe_binary
+        .sections
+        .iter()
+        .map(|s| Some((s.name().ok()?.to_owned(), pe_section_data(pe_data, s)?)))
+        .collect()
 }
@@ -0,0 +1,560 @@
+//! Verification of signatures against a pinned certificate.
+//!
+//! lzbt can embed the DER encoded certificates that sign auxiliary payloads, like addons and
+//! credentials, into the stub (`.authcert` section). These payloads are verified against these
+//! certificates alone, independent of the contents of `db`, so they can be signed with different
+//! keys than the stub itself, e.g. by different teams.
+//!
+//! By default, a payload must be signed with any one of the certificates. With a threshold
+//! (`.auththreshold` section), it must be signed by at least that many different signers, each of
+//! which is credited with a different certificate. A PE binary can carry several signatures in its
+//! certificate table, and the detached signature of a credential can be several concatenated
+//! signatures.
+//!
+//! The signatures are verified by the firmware's `EFI_PKCS7_VERIFY_PROTOCOL`, which is given a
+//! signature database that only contains one pinned certificate at a time.
+
+use alloc::{vec, vec::Vec};
+use core::ffi::c_void;
+use core::ptr;
+use log::warn;
+use sha2::{Digest, Sha256};
+use uefi::{boot, guid, proto::unsafe_protocol, Guid, Status, StatusExt};
+
+/// `EFI_CERT_X509_GUID`
+const EFI_CERT_X509_GUID: Guid = guid!("a5c059a1-94e4-4aa7-87b5-ab155c2bf072");
+
+/// The owner of the signature in the signature database, i.e. lanzaboote.
+const SIGNATURE_OWNER: Guid = guid!("14406d1c-93f7-4a09-a0d5-d4863451bd7e");
+
+/// `WIN_CERT_TYPE_PKCS_SIGNED_DATA`
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+/// `EFI_PKCS7_VERIFY_PROTOCOL`
+#[repr(C)]
+#[unsafe_protocol("47889fb2-d671-4fab-a0ca-df0e44df70d6")]
+struct Pkcs7Verify {
+    verify_buffer: unsafe extern "efiapi" fn(
+        this: *mut Pkcs7Verify,
+        signed_data: *const c_void,
+        signed_data_size: usize,
+        in_data: *const c_void,
+        in_data_size: usize,
+        allowed_db: *const *const c_void,
+        revoked_db: *const *const c_void,
+        time_stamp_db: *const *const c_void,
+        content: *mut c_void,
+        content_size: *mut usize,
+    ) -> Status,
+    // Part of the protocol, but only detached signatures of whole buffers are verified.
+    #[allow(dead_code)]
+    verify_signature: unsafe extern "efiapi" fn(
+        this: *mut Pkcs7Verify,
+        signature: *const c_void,
+        signature_size: usize,
+        in_hash: *const c_void,
+        in_hash_size: usize,
+        allowed_db: *const *const c_void,
+        revoked_db: *const *const c_void,
+        time_stamp_db: *const *const c_void,
+    ) -> Status,
+}
+
+/// The pinned certificates and how many of them must have signed a payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustPolicy<'a> {
+    certificates: Vec<&'a [u8]>,
+    threshold: usize,
+}
+
+impl<'a> TrustPolicy<'a> {
+    /// Parse the `.authcert` and `.auththreshold` sections.
+    ///
+    /// A malformed policy rejects every payload, so that it never falls back to weaker checks.
+    pub fn parse(authcert: &'a [u8], threshold: Option<&[u8]>) -> Self {
+        let mut certificates = split_der(authcert).unwrap_or_else(|| {
+            warn!("The pinned certificates are malformed, rejecting all signed payloads.");
+            Vec::new()
+        });
+        // A certificate that is pinned twice must not count twice.
+        let mut i = 0;
+        while i < certificates.len() {
+            if certificates[..i].contains(&certificates[i]) {
+                certificates.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        let threshold = match threshold {
+            None => 1,
+            Some(threshold) => core::str::from_utf8(threshold)
+                .ok()
+                .and_then(|threshold| threshold.trim().parse().ok())
+                .unwrap_or_else(|| {
+                    warn!("The signature threshold is malformed, rejecting all signed payloads.");
+                    usize::MAX
+                }),
+        };
+        Self {
+            certificates,
+            threshold,
+        }
+    }
+
+    /// Check that at least the threshold of signers signed `data` with any of the DER encoded
+    /// PKCS#7 `signatures`.
+    ///
+    /// Every signer is credited with at most one of the certificates that its signatures verify
+    /// against, and every certificate with at most one signer. So a signature that chains up to
+    /// several pinned certificates, or a signer that signed several times, counts only once.
+    fn check<'s>(
+        &self,
+        signatures: impl IntoIterator<Item = &'s [u8]>,
+        data: impl Fn(&'s [u8]) -> Option<&'s [u8]>,
+    ) -> uefi::Result<()> {
+        let mut signers: Vec<Signer> = Vec::new();
+        for signature in signatures {
+            let (Some(data), Some(identities)) = (data(signature), signer_identities(signature))
+            else {
+                continue;
+            };
+            let certificates = (0..self.certificates.len())
+                .filter(|i| verify_pkcs7(signature, data, self.certificates[*i]).is_ok())
+                .collect::<Vec<_>>();
+            if !certificates.is_empty() {
+                add_signer(
+                    &mut signers,
+                    Signer {
+                        identities,
+                        certificates,
+                    },
+                );
+            }
+        }
+
+        if credited_signers(&signers, self.certificates.len()) >= self.threshold.max(1) {
+            Ok(())
+        } else {
+            Err(Status::SECURITY_VIOLATION.into())
+        }
+    }
+
+    /// Verify the detached signatures of `data`, which are concatenated in `signatures`.
+    pub fn verify_detached(&self, signatures: &[u8], data: &[u8]) -> uefi::Result<()> {
+        let signatures = split_der(signatures).ok_or(Status::SECURITY_VIOLATION)?;
+        self.check(signatures, |_| Some(data))
+    }
+
+    /// Verify the Authenticode signatures of a PE binary.
+    pub fn verify_pe(&self, image: &[u8]) -> uefi::Result<()> {
+        let (hash, signatures) = authenticode(image).ok_or(Status::SECURITY_VIOLATION)?;
+
+        // The signed content ends with the digest of the image, an OCTET STRING.
+        let mut digest = Vec::with_capacity(34);
+        digest.extend_from_slice(&[0x04, 0x20]);
+        digest.extend_from_slice(&hash);
+
+        self.check(signatures, |signature| {
+            spc_indirect_data_content(signature).filter(|content| content.ends_with(&digest))
+        })
+    }
+}
+
+/// The signatures of a signer and the pinned certificates that they verify against.
+#[derive(Debug)]
+struct Signer<'s> {
+    /// The encoded `SignerIdentifier`s of the signatures, e.g. the issuer and serial number of
+    /// the signing certificate.
+    identities: Vec<&'s [u8]>,
+    /// Indices into the pinned certificates.
+    certificates: Vec<usize>,
+}
+
+/// Add `signer` to `signers`, merging it with the signers that share an identity with it.
+fn add_signer<'s>(signers: &mut Vec<Signer<'s>>, mut signer: Signer<'s>) {
+    signers.retain(|other| {
+        if !other
+            .identities
+            .iter()
+            .any(|identity| signer.identities.contains(identity))
+        {
+            return true;
+        }
+        signer.identities.extend_from_slice(&other.identities);
+        signer.certificates.extend_from_slice(&other.certificates);
+        false
+    });
+    signers.push(signer);
+}
+
+/// The number of signers that can each be credited with a different certificate, i.e. the size
+/// of a maximum matching between signers and certificates.
+fn credited_signers(signers: &[Signer], certificate_count: usize) -> usize {
+    /// Credit `signer` with a certificate, if necessary by crediting the signer that has it with
+    /// another one.
+    fn credit(
+        signers: &[Signer],
+        signer: usize,
+        credited: &mut [Option<usize>],
+        visited: &mut [bool],
+    ) -> bool {
+        for &certificate in &signers[signer].certificates {
+            if visited[certificate] {
+                continue;
+            }
+            visited[certificate] = true;
+            let available = match credited[certificate] {
+                None => true,
+                Some(other) => credit(signers, other, credited, visited),
+            };
+            if available {
+                credited[certificate] = Some(signer);
+                return true;
+            }
+        }
+        false
+    }
+
+    let mut credited = vec![None; certificate_count];
+    (0..signers.len())
+        .filter(|signer| {
+            credit(
+                signers,
+                *signer,
+                &mut credited,
+                &mut vec![false; certificate_count],
+            )
+        })
+        .count()
+}
+
+/// Build an `EFI_SIGNATURE_LIST` that contains a single X.509 certificate.
+fn signature_list(certificate: &[u8]) -> Vec<u8> {
+    let signature_size = 16 + certificate.len();
+    let list_size = 28 + signature_size;
+
+    let mut list = Vec::with_capacity(list_size);
+    list.extend_from_slice(&EFI_CERT_X509_GUID.to_bytes());
+    list.extend_from_slice(&(list_size as u32).to_le_bytes());
+    list.extend_from_slice(&0u32.to_le_bytes());
+    list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+    list.extend_from_slice(&SIGNATURE_OWNER.to_bytes());
+    list.extend_from_slice(certificate);
+    list
+}
+
+/// Verify a DER encoded PKCS#7 signature of `data`, which must be signed by `certificate`.
+///
+/// The signature must be detached, unless `data` is the embedded content of the signature.
+fn verify_pkcs7(signature: &[u8], data: &[u8], certificate: &[u8]) -> uefi::Result<()> {
+    let handle = boot::get_handle_for_protocol::<Pkcs7Verify>()?;
+    let mut protocol = boot::open_protocol_exclusive::<Pkcs7Verify>(handle)?;
+
+    let allowed = signature_list(certificate);
+    let allowed_db: [*const c_void; 2] = [allowed.as_ptr().cast(), ptr::null()];
+    let mut content_size = 0;
+
+    let this: *mut Pkcs7Verify = &mut *protocol;
+    // SAFETY: All buffers outlive the call and their sizes are passed along. The signature
+    // database is a NULL-terminated array of valid signature lists. No content is requested, so
+    // no content buffer is needed.
+    unsafe {
+        ((*this).verify_buffer)(
+            this,
+            signature.as_ptr().cast(),
+            signature.len(),
+            data.as_ptr().cast(),
+            data.len(),
+            allowed_db.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null_mut(),
+            &mut content_size,
+        )
+    }
+    .to_result()
+}
+
+/// Read a DER TLV. Returns its tag, its value and the remaining data.
+fn der_any(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, data) = data.split_first()?;
+    let (length, data) = if first < 0x80 {
+        (usize::from(first), data)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > core::mem::size_of::<usize>() {
+            return None;
+        }
+        let length = data
+            .get(..count)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | usize::from(*byte));
+        (length, &data[count..])
+    };
+
+    Some((tag, data.get(..length)?, &data[length..]))
+}
+
+/// Read a DER TLV with the expected tag. Returns its value and the remaining data.
+fn der(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_any(data)? {
+        (actual_tag, value, rest) if actual_tag == tag => Some((value, rest)),
+        _ => None,
+    }
+}
+
+/// Split concatenated DER SEQUENCEs, e.g. certificates or signatures, into their encodings.
+fn split_der(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (value, rest) = der(data, 0x30)?;
+        // The encoding ends where the value ends.
+        let length = value.as_ptr() as usize - data.as_ptr() as usize + value.len();
+        elements.push(&data[..length]);
+        data = rest;
+    }
+    (!elements.is_empty()).then_some(elements)
+}
+
+/// The `SignedData` of a DER encoded PKCS#7 signature, without its tag and length.
+fn signed_data(pkcs7: &[u8]) -> Option<&[u8]> {
+    // ContentInfo
+    let (content_info, _) = der(pkcs7, 0x30)?;
+    let (_, rest) = der(content_info, 0x06)?;
+    let (explicit, _) = der(rest, 0xa0)?;
+    let (signed_data, _) = der(explicit, 0x30)?;
+    Some(signed_data)
+}
+
+/// Extract the `SpcIndirectDataContent` from an Authenticode signature, i.e. the content that
+/// is signed. Like EDK2, the tag and length of the content are not part of it.
+fn spc_indirect_data_content(pkcs7: &[u8]) -> Option<&[u8]> {
+    let (_, rest) = der(signed_data(pkcs7)?, 0x02)?;
+    let (_, rest) = der(rest, 0x31)?;
+    // ContentInfo of the SpcIndirectDataContent
+    let (content_info, _) = der(rest, 0x30)?;
+    let (_, rest) = der(content_info, 0x06)?;
+    let (explicit, _) = der(rest, 0xa0)?;
+    let (content, _) = der(explicit, 0x30)?;
+    Some(content)
+}
+
+/// The encoded `SignerIdentifier`s of the `SignerInfo`s of a PKCS#7 signature.
+fn signer_identities(pkcs7: &[u8]) -> Option<Vec<&[u8]>> {
+    let (_, rest) = der(signed_data(pkcs7)?, 0x02)?;
+    let (_, rest) = der(rest, 0x31)?;
+    let (_, mut rest) = der(rest, 0x30)?;
+    // The optional certificates and CRLs.
+    for tag in [0xa0, 0xa1] {
+        if let Some((_, next)) = der(rest, tag) {
+            rest = next;
+        }
+    }
+    let (mut signer_infos, _) = der(rest, 0x31)?;
+
+    let mut identities = Vec::new();
+    while !signer_infos.is_empty() {
+        let (signer_info, next) = der(signer_infos, 0x30)?;
+        let (_, identifier) = der(signer_info, 0x02)?;
+        let (_, _, after) = der_any(identifier)?;
+        identities.push(&identifier[..identifier.len() - after.len()]);
+        signer_infos = next;
+    }
+    (!identities.is_empty()).then_some(identities)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+    let value = u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+    usize::try_from(value).ok()
+}
+
+/// The Authenticode SHA256 hash of a PE binary and its PKCS#7 signatures.
+fn authenticode(image: &[u8]) -> Option<([u8; 32], Vec<&[u8]>)> {
+    let pe = goblin::pe::PE::parse(image).ok()?;
+    let optional_header = pe.header.optional_header?;
+
+    let optional_header_offset = read_u32(image, 0x3c)? + 4 + 20;
+    let checksum_offset = optional_header_offset + 64;
+    let certificate_entry_offset = optional_header_offset
+        + match read_u16(image, optional_header_offset)? {
+            // PE32
+            0x10b => 128,
+            // PE32+
+            0x20b => 144,
+            _ => return None,
+        };
+    let size_of_headers = usize::try_from(optional_header.windows_fields.size_of_headers).ok()?;
+
+    let table = optional_header
+        .data_directories
+        .get_certificate_table()
+        .as_ref()?;
+    // The address of the certificate table is a file offset.
+    let table_offset = usize::try_from(table.virtual_address).ok()?;
+    let table_size = usize::try_from(table.size).ok()?;
+    let certificate_table = image.get(table_offset..table_offset.checked_add(table_size)?)?;
+    // The WIN_CERTIFICATE entries are aligned to 8 bytes.
+    let mut signatures = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= certificate_table.len() {
+        let certificate_length = read_u32(certificate_table, offset)?;
+        if certificate_length < 8 {
+            return None;
+        }
+        if read_u16(certificate_table, offset + 6)? == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            signatures
+                .push(certificate_table.get(offset + 8..offset.checked_add(certificate_length)?)?);
+        }
+        offset = offset.checked_add(certificate_length.checked_add(7)? & !7)?;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(image.get(..checksum_offset)?);
+    hasher.update(image.get(checksum_offset + 4..certificate_entry_offset)?);
+    hasher.update(image.get(certificate_entry_offset + 8..size_of_headers)?);
+
+    let mut sections = pe
+        .sections
+        .iter()
+        .filter(|s| s.size_of_raw_data > 0)
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|s| s.pointer_to_raw_data);
+
+    let mut bytes_hashed = size_of_headers;
+    for section in sections {
+        let start = usize::try_from(section.pointer_to_raw_data).ok()?;
+        let size = usize::try_from(section.size_of_raw_data).ok()?;
+        hasher.update(image.get(start..start.checked_add(size)?)?);
+        bytes_hashed += size;
+    }
+
+    // Data behind the sections is hashed as well, except for the certificate table at the end.
+    let trailing_end = image
+        .len()
+        .checked_sub(table_size)
+        .filter(|end| *end <= table_offset)?;
+    if trailing_end > bytes_hashed {
+        hasher.update(&image[bytes_hashed..trailing_end]);
+    }
+
+    Some((hasher.finalize().into(), signatures))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trust_policy() {
+        let authcert = [0x30, 0x01, 0xaa, 0x30, 0x82, 0x00, 0x02, 0xbb, 0xcc];
+        let policy = TrustPolicy::parse(&authcert, None);
+        assert_eq!(
+            policy.certificates,
+            [
+                &[0x30, 0x01, 0xaa][..],
+                &[0x30, 0x82, 0x00, 0x02, 0xbb, 0xcc]
+            ]
+        );
+        assert_eq!(policy.threshold, 1);
+        assert_eq!(TrustPolicy::parse(&authcert, Some(b"2\n")).threshold, 2);
+        assert_eq!(
+            TrustPolicy::parse(&authcert, Some(b"two")).threshold,
+            usize::MAX
+        );
+        assert!(TrustPolicy::parse(&authcert[..5], None)
+            .certificates
+            .is_empty());
+        assert_eq!(
+            TrustPolicy::parse(&[0x30, 0x01, 0xaa, 0x30, 0x01, 0xaa], None).certificates,
+            [&[0x30, 0x01, 0xaa][..]]
+        );
+    }
+
+    fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoding = vec![tag, value.len() as u8];
+        encoding.extend_from_slice(value);
+        encoding
+    }
+
+    #[test]
+    fn read_signer_identities() {
+        let issuer_and_serial = tlv(0x30, &[tlv(0x30, b"issuer"), tlv(0x02, &[1])].concat());
+        let subject_key_identifier = tlv(0x80, b"key");
+        let signer_info = |identifier: &[u8]| {
+            tlv(
+                0x30,
+                &[tlv(0x02, &[1]), identifier.to_vec(), tlv(0x30, b"rest")].concat(),
+            )
+        };
+        let signed_data = tlv(
+            0x30,
+            &[
+                tlv(0x02, &[1]),
+                tlv(0x31, b""),
+                tlv(0x30, &tlv(0x06, b"data")),
+                tlv(0xa0, b"certificates"),
+                tlv(
+                    0x31,
+                    &[
+                        signer_info(&issuer_and_serial),
+                        signer_info(&subject_key_identifier),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let pkcs7 = tlv(
+            0x30,
+            &[tlv(0x06, b"signed"), tlv(0xa0, &signed_data)].concat(),
+        );
+
+        assert_eq!(
+            signer_identities(&pkcs7),
+            Some(vec![&issuer_and_serial[..], &subject_key_identifier])
+        );
+        assert_eq!(signer_identities(&pkcs7[..pkcs7.len() - 1]), None);
+    }
+
+    fn signer<'s>(identities: &[&'s [u8]], certificates: &[usize]) -> Signer<'s> {
+        Signer {
+            identities: identities.to_vec(),
+            certificates: certificates.to_vec(),
+        }
+    }
+
+    #[test]
+    fn credit_one_certificate_per_signer() {
+        // A signature that verifies against two certificates, e.g. a CA and its leaf, counts once.
+        assert_eq!(credited_signers(&[signer(&[b"a"], &[0, 1])], 2), 1);
+        // Another signer can still be credited with the other certificate.
+        assert_eq!(
+            credited_signers(&[signer(&[b"a"], &[0, 1]), signer(&[b"b"], &[0])], 2),
+            2
+        );
+        // Two signers that verify against the same certificate only count once.
+        assert_eq!(
+            credited_signers(&[signer(&[b"a"], &[0]), signer(&[b"b"], &[0])], 2),
+            1
+        );
+    }
+
+    #[test]
+    fn merge_signatures_of_the_same_signer() {
+        let mut signers = Vec::new();
+        add_signer(&mut signers, signer(&[b"a"], &[0]));
+        add_signer(&mut signers, signer(&[b"b"], &[1]));
+        assert_eq!(credited_signers(&signers, 2), 2);
+        // A signature by both signers ties them together.
+        add_signer(&mut signers, signer(&[b"a", b"b"], &[0]));
+        assert_eq!(signers.len(), 1);
+        assert_eq!(credited_signers(&signers, 2), 1);
+    }
+}
@@ -0,0 +1,121 @@
+//! QR codes for links that the stub displays when it fails.
+
+use alloc::{vec, vec::Vec};
+
+use qrcodegen_no_heap::{QrCodeEcc, Version};
+use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
+    proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput},
+    Result, Status,
+};
+
+/// The light modules around the code that scanners need to find it.
+const QUIET_ZONE: usize = 4;
+
+/// A QR code as a square of modules, `true` being dark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `data` into the smallest version that fits it.
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let mut buffer = vec![0u8; Version::MAX.buffer_len()];
+        let mut code = vec![0u8; Version::MAX.buffer_len()];
+        buffer.get_mut(..data.len())?.copy_from_slice(data);
+        let code = qrcodegen_no_heap::QrCode::encode_binary(
+            &mut buffer,
+            data.len(),
+            &mut code,
+            QrCodeEcc::Low,
+            Version::MIN,
+            Version::MAX,
+            None,
+            true,
+        )
+        .ok()?;
+
+        let size = code.size() as usize;
+        let modules = (0..size * size)
+            .map(|i| code.get_module((i % size) as i32, (i / size) as i32))
+            .collect();
+        Some(Self { size, modules })
+    }
+
+    /// The number of modules per side.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module in column `x` and row `y` is dark.
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Draw the code in the top right corner of the screen.
+    ///
+    /// Each module is a square of pixels, so that the code takes up at most a third of the
+    /// height of the screen.
+    pub fn draw(&self) -> Result<()> {
+        let handle = boot::get_handle_for_protocol::<GraphicsOutput>()?;
+        // SAFETY: See `draw_splash`, the protocol is only used for the duration of this function.
+        let mut gop = unsafe {
+            boot::open_protocol::<GraphicsOutput>(
+                OpenProtocolParams {
+                    handle,
+                    agent: boot::image_handle(),
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )?
+        };
+
+        let (screen_width, screen_height) = gop.current_mode_info().resolution();
+        let modules = self.size + 2 * QUIET_ZONE;
+        let scale = (screen_height / 3 / modules).min(8);
+        if scale == 0 || modules * scale > screen_width {
+            return Err(Status::BAD_BUFFER_SIZE.into());
+        }
+
+        let pixels = modules * scale;
+        let mut buffer = Vec::with_capacity(pixels * pixels);
+        for py in 0..pixels {
+            for px in 0..pixels {
+                let (x, y) = (px / scale, py / scale);
+                let dark = (QUIET_ZONE..QUIET_ZONE + self.size).contains(&x)
+                    && (QUIET_ZONE..QUIET_ZONE + self.size).contains(&y)
+                    && self.module(x - QUIET_ZONE, y - QUIET_ZONE);
+                let value = if dark { 0 } else { 0xff };
+                buffer.push(BltPixel::new(value, value, value));
+            }
+        }
+
+        gop.blt(BltOp::BufferToVideo {
+            buffer: &buffer,
+            src: BltRegion::Full,
+            dest: (screen_width - pixels, 0),
+            dims: (pixels, pixels),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choose_version() {
+        assert_eq!(QrCode::encode(b"lanzaboote").unwrap().size(), 21);
+        assert_eq!(QrCode::encode(b"https://example.com").unwrap().size(), 25);
+        assert_eq!(QrCode::encode(&[b'x'; 106]).unwrap().size(), 37);
+        assert!(QrCode::encode(&[b'x'; 4096]).is_none());
+
+        let code = QrCode::encode(b"lanzaboote").unwrap();
+        // The finder pattern in the top left corner and the dark module.
+        assert!(code.module(0, 0) && !code.module(1, 1) && code.module(3, 3));
+        assert!(!code.module(7, 7));
+        assert!(code.module(8, code.size() - 8));
+    }
+}
@@ -0,0 +1,159 @@
+//! Let the firmware load an image that is not signed by a key it trusts.
+//!
+//! `LoadImage` asks the security architecture protocols of the firmware whether an image may be
+//! loaded, which is where Secure Boot checks the signature against `db`. An image that the stub
+//! verified itself, e.g. by its hash, is accepted by temporarily replacing these checks, like shim
+//! and systemd-boot do. Every other image is still passed on to the original checks.
+
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use uefi::{
+    boot::{self, ScopedProtocol},
+    proto::{device_path::FfiDevicePath, unsafe_protocol, ProtocolPointer},
+    Status,
+};
+
+use crate::console::open_shared;
+
+type FileAuthenticationState = unsafe extern "efiapi" fn(
+    this: *const SecurityArchProtocol,
+    authentication_status: u32,
+    file: *const FfiDevicePath,
+) -> Status;
+
+type FileAuthentication = unsafe extern "efiapi" fn(
+    this: *const Security2ArchProtocol,
+    device_path: *const FfiDevicePath,
+    file_buffer: *const c_void,
+    file_size: usize,
+    boot_policy: bool,
+) -> Status;
+
+/// The UEFI PI Security Architecture Protocol, which older firmwares check images with.
+#[repr(C)]
+#[unsafe_protocol("a46423e3-4617-49f1-b9ff-d1bfa9115839")]
+struct SecurityArchProtocol {
+    file_authentication_state: FileAuthenticationState,
+}
+
+/// The UEFI PI Security2 Architecture Protocol, which checks the signature of images.
+#[repr(C)]
+#[unsafe_protocol("94ab2f58-1438-4ef1-9152-18941a3a0e68")]
+struct Security2ArchProtocol {
+    file_authentication: FileAuthentication,
+}
+
+/// The image that is accepted while an override is installed.
+static TRUSTED_IMAGE: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+static TRUSTED_IMAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The replaced checks, which are called for all other images.
+static ORIGINAL_FILE_AUTHENTICATION_STATE: AtomicPtr<c_void> =
+    AtomicPtr::new(core::ptr::null_mut());
+static ORIGINAL_FILE_AUTHENTICATION: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The Security protocol only receives the device path of the image, not its contents, so it
+/// accepts every image while the override is installed. The firmware only consults it for images
+/// from firmware volumes if the Security2 protocol is present, and the override is only installed
+/// for a single `LoadImage` call of the stub.
+unsafe extern "efiapi" fn file_authentication_state(
+    this: *const SecurityArchProtocol,
+    authentication_status: u32,
+    file: *const FfiDevicePath,
+) -> Status {
+    if !TRUSTED_IMAGE.load(Ordering::SeqCst).is_null() {
+        return Status::SUCCESS;
+    }
+    let original = ORIGINAL_FILE_AUTHENTICATION_STATE.load(Ordering::SeqCst);
+    // SAFETY: The original function pointer was stored when the override was installed.
+    unsafe {
+        let original = core::mem::transmute::<*mut c_void, FileAuthenticationState>(original);
+        original(this, authentication_status, file)
+    }
+}
+
+unsafe extern "efiapi" fn file_authentication(
+    this: *const Security2ArchProtocol,
+    device_path: *const FfiDevicePath,
+    file_buffer: *const c_void,
+    file_size: usize,
+    boot_policy: bool,
+) -> Status {
+    let trusted = TRUSTED_IMAGE.load(Ordering::SeqCst);
+    if !trusted.is_null()
+        && file_buffer == trusted.cast_const()
+        && file_size == TRUSTED_IMAGE_SIZE.load(Ordering::SeqCst)
+    {
+        return Status::SUCCESS;
+    }
+    let original = ORIGINAL_FILE_AUTHENTICATION.load(Ordering::SeqCst);
+    // SAFETY: The original function pointer was stored when the override was installed.
+    unsafe {
+        let original = core::mem::transmute::<*mut c_void, FileAuthentication>(original);
+        original(this, device_path, file_buffer, file_size, boot_policy)
+    }
+}
+
+/// Open an architecture protocol without taking it away from the firmware.
+fn open_arch_protocol<P: ProtocolPointer + ?Sized>() -> Option<ScopedProtocol<P>> {
+    open_shared::<P>(boot::get_handle_for_protocol::<P>().ok()?).ok()
+}
+
+/// Accepts `image` in `LoadImage` until it is dropped.
+///
+/// Without Secure Boot, firmwares may not install the security protocols at all, in which case
+/// there is nothing to override.
+pub struct SecurityOverride {
+    security: Option<ScopedProtocol<SecurityArchProtocol>>,
+    security2: Option<ScopedProtocol<Security2ArchProtocol>>,
+}
+
+impl SecurityOverride {
+    pub fn install(image: &[u8]) -> Self {
+        TRUSTED_IMAGE.store(image.as_ptr().cast_mut().cast(), Ordering::SeqCst);
+        TRUSTED_IMAGE_SIZE.store(image.len(), Ordering::SeqCst);
+
+        let mut security = open_arch_protocol::<SecurityArchProtocol>();
+        if let Some(protocol) = security.as_mut() {
+            ORIGINAL_FILE_AUTHENTICATION_STATE.store(
+                protocol.file_authentication_state as *mut c_void,
+                Ordering::SeqCst,
+            );
+            protocol.file_authentication_state = file_authentication_state;
+        }
+        let mut security2 = open_arch_protocol::<Security2ArchProtocol>();
+        if let Some(protocol) = security2.as_mut() {
+            ORIGINAL_FILE_AUTHENTICATION.store(
+                protocol.file_authentication as *mut c_void,
+                Ordering::SeqCst,
+            );
+            protocol.file_authentication = file_authentication;
+        }
+        Self {
+            security,
+            security2,
+        }
+    }
+}
+
+impl Drop for SecurityOverride {
+    fn drop(&mut self) {
+        if let Some(protocol) = self.security.as_mut() {
+            let original = ORIGINAL_FILE_AUTHENTICATION_STATE.load(Ordering::SeqCst);
+            // SAFETY: This is the function pointer that was replaced in `install`.
+            protocol.file_authentication_state =
+                unsafe { core::mem::transmute::<*mut c_void, FileAuthenticationState>(original) };
+        }
+        if let Some(protocol) = self.security2.as_mut() {
+            let original = ORIGINAL_FILE_AUTHENTICATION.load(Ordering::SeqCst);
+            // SAFETY: This is the function pointer that was replaced in `install`.
+            protocol.file_authentication =
+                unsafe { core::mem::transmute::<*mut c_void, FileAuthentication>(original) };
+        }
+        TRUSTED_IMAGE.store(core::ptr::null_mut(), Ordering::SeqCst);
+        TRUSTED_IMAGE_SIZE.store(0, Ordering::SeqCst);
+    }
+}
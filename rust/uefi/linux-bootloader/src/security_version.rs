@@ -0,0 +1,145 @@
+//! Anti-rollback protection based on security version numbers (SVN).
+//!
+//! Every stub carries the SVN of its generation. The lowest SVN that may still boot (the floor)
+//! is stored in an EFI variable that is only accessible during boot services. The operating
+//! system cannot modify such a variable after `ExitBootServices()`, so only signed boot code, i.e.
+//! the stub, can change it. A stub may request to raise the floor, which makes all generations
+//! with a lower SVN unbootable.
+
+use log::{info, warn};
+use uefi::{
+    cstr16, guid,
+    runtime::{self, VariableAttributes, VariableVendor},
+    Result, Status,
+};
+
+use crate::messages::Message;
+
+/// The vendor GUID for variables owned by lanzaboote.
+pub const LANZABOOTE_VENDOR_UUID: VariableVendor =
+    VariableVendor(guid!("14406d1c-93f7-4a09-a0d5-d4863451bd7e"));
+
+/// The name of the variable that stores the SVN floor as a little-endian u64.
+const SECURITY_VERSION_VARIABLE: &uefi::CStr16 = cstr16!("LanzabooteSecurityVersion");
+
+/// The attributes of a trustworthy floor variable. Notably, it is not accessible at runtime.
+fn floor_attributes() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+}
+
+/// Read the SVN floor.
+///
+/// A missing variable means there is no floor yet. A variable that is accessible at runtime was
+/// not created by us and is deleted.
+fn read_floor() -> u64 {
+    let mut buffer = [0u8; 8];
+    match runtime::get_variable(
+        SECURITY_VERSION_VARIABLE,
+        &LANZABOOTE_VENDOR_UUID,
+        &mut buffer,
+    ) {
+        Ok((data, attributes)) if attributes == floor_attributes() && data.len() == 8 => {
+            u64::from_le_bytes(buffer)
+        }
+        Ok(_) => {
+            warn!("Ignoring security version floor with unexpected attributes or size.");
+            if runtime::delete_variable(SECURITY_VERSION_VARIABLE, &LANZABOOTE_VENDOR_UUID).is_err()
+            {
+                warn!("Failed to delete the untrustworthy security version floor.");
+            }
+            0
+        }
+        Err(err) if err.status() == Status::NOT_FOUND => 0,
+        Err(err) => {
+            warn!(
+                "Failed to read the security version floor: {}",
+                err.status()
+            );
+            0
+        }
+    }
+}
+
+/// What a stub does about the floor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FloorDecision {
+    /// The SVN of the stub is below the floor.
+    Violation,
+    /// The floor stays as it is.
+    Keep,
+    /// The floor is raised to this SVN.
+    Raise(u64),
+}
+
+/// Decide about the floor for a stub with `security_version` that requests `requested_floor`.
+fn decide_floor(floor: u64, security_version: u64, requested_floor: Option<u64>) -> FloorDecision {
+    if security_version < floor {
+        return FloorDecision::Violation;
+    }
+    // A stub cannot lock itself out.
+    match requested_floor.map(|requested| requested.min(security_version)) {
+        Some(new_floor) if new_floor > floor => FloorDecision::Raise(new_floor),
+        _ => FloorDecision::Keep,
+    }
+}
+
+/// Refuse to boot if `security_version` is below the recorded floor and raise the floor to
+/// `requested_floor`.
+///
+/// If `enforce` is false, a violation is only logged.
+pub fn check_security_version(
+    security_version: u64,
+    requested_floor: Option<u64>,
+    enforce: bool,
+) -> Result<()> {
+    let floor = read_floor();
+
+    match decide_floor(floor, security_version, requested_floor) {
+        FloorDecision::Violation => {
+            let message = Message::SecurityVersionTooLow {
+                version: security_version,
+                minimum: floor,
+            };
+            if enforce {
+                log::error!("{message}");
+                return Err(Status::SECURITY_VIOLATION.into());
+            }
+            // Do not lower the floor, even if the check is not enforced.
+            warn!("{message} {}", Message::ContinuingAnyway);
+        }
+        FloorDecision::Keep => {}
+        FloorDecision::Raise(new_floor) => {
+            info!("Raising the security version floor from {floor} to {new_floor}.");
+            runtime::set_variable(
+                SECURITY_VERSION_VARIABLE,
+                &LANZABOOTE_VENDOR_UUID,
+                floor_attributes(),
+                &new_floor.to_le_bytes(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_versions_below_the_floor() {
+        assert_eq!(decide_floor(3, 2, None), FloorDecision::Violation);
+        assert_eq!(decide_floor(3, 2, Some(5)), FloorDecision::Violation);
+        assert_eq!(decide_floor(3, 3, None), FloorDecision::Keep);
+        assert_eq!(decide_floor(0, 0, None), FloorDecision::Keep);
+    }
+
+    #[test]
+    fn raise_the_floor_at_most_to_the_own_version() {
+        assert_eq!(decide_floor(1, 4, Some(3)), FloorDecision::Raise(3));
+        assert_eq!(decide_floor(1, 4, Some(9)), FloorDecision::Raise(4));
+        // The floor is never lowered.
+        assert_eq!(decide_floor(3, 4, Some(2)), FloorDecision::Keep);
+        assert_eq!(decide_floor(4, 4, Some(9)), FloorDecision::Keep);
+    }
+}
@@ -0,0 +1,229 @@
+//! The setup header of the Linux x86 boot protocol.
+//!
+//! An x86 bzImage starts with the real-mode setup code, which contains a header describing what
+//! the kernel supports and where it can be placed. See
+//! <https://www.kernel.org/doc/html/latest/arch/x86/boot.html> for the format and the history of
+//! its fields. Kernels for other architectures have no such header.
+
+use uefi::{Result, Status};
+
+use crate::memory::Placement;
+
+const SETUP_HEADER_MAGIC: &[u8] = b"HdrS";
+const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+
+/// Where the setup header starts, both in the kernel image and in `struct boot_params`.
+pub const SETUP_HEADER_OFFSET: usize = 0x1f1;
+
+const SETUP_SECTS_OFFSET: usize = 0x1f1;
+const JUMP_OFFSET: usize = 0x200;
+const VERSION_OFFSET: usize = 0x206;
+const LOADFLAGS_OFFSET: usize = 0x211;
+const INITRD_ADDR_MAX_OFFSET: usize = 0x22c;
+const KERNEL_ALIGNMENT_OFFSET: usize = 0x230;
+const RELOCATABLE_KERNEL_OFFSET: usize = 0x234;
+const MIN_ALIGNMENT_OFFSET: usize = 0x235;
+const XLOADFLAGS_OFFSET: usize = 0x236;
+const CMDLINE_SIZE_OFFSET: usize = 0x238;
+const PREF_ADDRESS_OFFSET: usize = 0x258;
+const INIT_SIZE_OFFSET: usize = 0x260;
+const HANDOVER_OFFSET_OFFSET: usize = 0x264;
+
+/// The oldest boot protocol, which introduced the header.
+const VERSION_2_00: u16 = 0x0200;
+/// Adds `initrd_addr_max`.
+const VERSION_2_03: u16 = 0x0203;
+/// Adds `kernel_alignment` and `relocatable_kernel`.
+const VERSION_2_05: u16 = 0x0205;
+/// Adds `cmdline_size`.
+const VERSION_2_06: u16 = 0x0206;
+/// Adds `min_alignment`, `pref_address` and `init_size`.
+const VERSION_2_10: u16 = 0x020a;
+/// Adds `handover_offset`.
+const VERSION_2_11: u16 = 0x020b;
+/// Adds `xloadflags`.
+const VERSION_2_12: u16 = 0x020c;
+
+/// The highest address of the initrd for kernels that do not declare it.
+const DEFAULT_INITRD_ADDR_MAX: u32 = 0x37ff_ffff;
+/// The maximum command line length for kernels that do not declare it, excluding the terminator.
+const DEFAULT_CMDLINE_SIZE: u32 = 255;
+/// Where kernels that do not declare it are loaded.
+const DEFAULT_PREF_ADDRESS: u64 = 0x10_0000;
+
+/// `LOADED_HIGH`: The protected-mode code is loaded at 0x100000.
+pub const LOADED_HIGH: u8 = 1 << 0;
+
+/// `XLF_KERNEL_64`: The kernel has the legacy 64-bit entry point at 0x200.
+pub const XLF_KERNEL_64: u16 = 1 << 0;
+/// `XLF_CAN_BE_LOADED_ABOVE_4G`: The kernel, boot parameters, command line and initrd can be
+/// above 4 GiB.
+pub const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+/// `XLF_EFI_HANDOVER_32`: The kernel supports the 32-bit EFI handover entry point.
+pub const XLF_EFI_HANDOVER_32: u16 = 1 << 2;
+/// `XLF_EFI_HANDOVER_64`: The kernel supports the 64-bit EFI handover entry point.
+pub const XLF_EFI_HANDOVER_64: u16 = 1 << 3;
+
+/// The fields of the setup header that matter for booting a kernel.
+///
+/// Fields that the kernel's boot protocol version does not have are set to the values that the
+/// boot protocol documents for such kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupHeader {
+    /// The size of the header, starting at [`SETUP_HEADER_OFFSET`].
+    pub size: usize,
+    /// The version of the boot protocol, e.g. `0x020f` for 2.15.
+    pub version: u16,
+    /// The number of 512-byte sectors of the setup code, excluding the boot sector.
+    pub setup_sects: u8,
+    pub loadflags: u8,
+    /// The highest address that the initrd may cover.
+    pub initrd_addr_max: u32,
+    /// The alignment that the kernel prefers to be loaded at.
+    pub kernel_alignment: u32,
+    pub relocatable_kernel: bool,
+    /// The alignment that the kernel requires to be loaded at, as a power of two.
+    pub min_alignment: u8,
+    pub xloadflags: u16,
+    /// The maximum command line length, excluding the terminator.
+    pub cmdline_size: u32,
+    /// Where a non-relocatable kernel must be loaded.
+    pub pref_address: u64,
+    /// The memory that the kernel needs from where it is loaded until it has set itself up.
+    pub init_size: u32,
+    /// The offset of the EFI handover entry point from the 32-bit entry point, or 0.
+    pub handover_offset: u32,
+}
+
+impl SetupHeader {
+    /// Parse the setup header of a kernel.
+    ///
+    /// Returns `None` if the kernel has no setup header, e.g. because it is not an x86 kernel.
+    /// A header that is truncated or claims an impossible version is an error.
+    pub fn parse(kernel_data: &[u8]) -> Result<Option<Self>> {
+        if kernel_data.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4)
+            != Some(SETUP_HEADER_MAGIC)
+        {
+            return Ok(None);
+        }
+
+        // The header ends where the jump at its start leads to.
+        let header_end = JUMP_OFFSET + 2 + usize::from(read_u8(kernel_data, JUMP_OFFSET + 1)?);
+        // Reading beyond it means that the header is shorter than its version requires.
+        let kernel_data = kernel_data.get(..header_end).ok_or(Status::LOAD_ERROR)?;
+
+        let version = read_u16(kernel_data, VERSION_OFFSET)?;
+        if version < VERSION_2_00 {
+            return Err(Status::INCOMPATIBLE_VERSION.into());
+        }
+
+        let mut header = Self {
+            size: header_end - SETUP_HEADER_OFFSET,
+            version,
+            setup_sects: read_u8(kernel_data, SETUP_SECTS_OFFSET)?,
+            loadflags: read_u8(kernel_data, LOADFLAGS_OFFSET)?,
+            initrd_addr_max: DEFAULT_INITRD_ADDR_MAX,
+            kernel_alignment: 0,
+            relocatable_kernel: false,
+            min_alignment: 0,
+            xloadflags: 0,
+            cmdline_size: DEFAULT_CMDLINE_SIZE,
+            pref_address: DEFAULT_PREF_ADDRESS,
+            init_size: 0,
+            handover_offset: 0,
+        };
+        if version >= VERSION_2_03 {
+            header.initrd_addr_max = read_u32(kernel_data, INITRD_ADDR_MAX_OFFSET)?;
+        }
+        if version >= VERSION_2_05 {
+            header.kernel_alignment = read_u32(kernel_data, KERNEL_ALIGNMENT_OFFSET)?;
+            header.relocatable_kernel = read_u8(kernel_data, RELOCATABLE_KERNEL_OFFSET)? != 0;
+        }
+        if version >= VERSION_2_06 {
+            header.cmdline_size = read_u32(kernel_data, CMDLINE_SIZE_OFFSET)?;
+        }
+        if version >= VERSION_2_10 {
+            header.min_alignment = read_u8(kernel_data, MIN_ALIGNMENT_OFFSET)?;
+            header.pref_address = read_u64(kernel_data, PREF_ADDRESS_OFFSET)?;
+            header.init_size = read_u32(kernel_data, INIT_SIZE_OFFSET)?;
+        }
+        if version >= VERSION_2_11 {
+            header.handover_offset = read_u32(kernel_data, HANDOVER_OFFSET_OFFSET)?;
+        }
+        if version >= VERSION_2_12 {
+            header.xloadflags = read_u16(kernel_data, XLOADFLAGS_OFFSET)?;
+        }
+
+        Ok(Some(header))
+    }
+
+    /// Whether the kernel, its boot parameters, command line and initrd can be placed above 4 GiB.
+    pub fn can_be_loaded_above_4g(&self) -> bool {
+        self.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0
+    }
+
+    /// Whether the kernel can be started with the EFI handover protocol on this architecture.
+    ///
+    /// Kernels before boot protocol 2.12 cannot declare it, so their handover offset is trusted.
+    pub fn supports_efi_handover(&self) -> bool {
+        let flag = if cfg!(target_arch = "x86_64") {
+            XLF_EFI_HANDOVER_64
+        } else {
+            XLF_EFI_HANDOVER_32
+        };
+        self.version >= VERSION_2_11
+            && self.relocatable_kernel
+            && self.handover_offset != 0
+            && (self.version < VERSION_2_12 || self.xloadflags & flag != 0)
+    }
+
+    /// Where the kernel image can be placed.
+    ///
+    /// A relocatable kernel is aligned as it prefers, falling back to the alignment it requires.
+    /// A kernel that cannot be loaded above 4 GiB is placed below.
+    pub fn kernel_placement(&self) -> Placement {
+        let mut placement = Placement::default();
+        if self.relocatable_kernel {
+            let required = match self.min_alignment {
+                0 => self.kernel_alignment,
+                // Alignments beyond 2^31 do not fit the preferred alignment either.
+                shift => 1u32.checked_shl(u32::from(shift)).unwrap_or(0),
+            };
+            placement = placement
+                .with_alignment(required as usize)
+                .with_preferred_alignment(self.kernel_alignment as usize);
+        }
+        if !self.can_be_loaded_above_4g() {
+            placement = placement.below_4g();
+        }
+        placement
+    }
+
+    /// Where the initrd can be placed.
+    pub fn initrd_placement(&self) -> Placement {
+        if self.can_be_loaded_above_4g() {
+            Placement::default()
+        } else {
+            Placement::default().below(u64::from(self.initrd_addr_max))
+        }
+    }
+}
+
+fn read_u8(data: &[u8], offset: usize) -> Result<u8> {
+    Ok(*data.get(offset).ok_or(Status::LOAD_ERROR)?)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or(Status::LOAD_ERROR)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or(Status::LOAD_ERROR)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data.get(offset..offset + 8).ok_or(Status::LOAD_ERROR)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
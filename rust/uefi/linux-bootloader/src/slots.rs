@@ -0,0 +1,280 @@
+//! A/B slots for image-style updates of appliances.
+//!
+//! A stub with an `.abslots` section does not boot Linux itself. It chainloads one of exactly two
+//! signed payloads, the slots A and B, whose paths are the two lines of the section. The payloads
+//! are regular Lanzaboote stubs, so the firmware checks their signature when loading them.
+//!
+//! Userspace selects the active slot in the `LanzabooteSlot` variable and gives an unconfirmed
+//! slot a number of tries in `LanzabooteSlotTries`. Every boot of an unconfirmed slot uses one
+//! try. Once none are left, the stub falls back to the other slot for good. Confirming the slot
+//! after a successful boot (`lzbt slot mark-good`) deletes the tries. The booted slot is recorded
+//! in the volatile `LanzabooteBootedSlot` variable.
+
+use alloc::vec::Vec;
+use core::fmt;
+use log::{info, warn};
+use uefi::{
+    boot::{self, LoadImageSource},
+    cstr16,
+    proto::BootPolicy,
+    runtime::{self, VariableAttributes},
+    CStr16, CString16, Handle, Result, Status,
+};
+
+use crate::{
+    efivars::cstr16_to_bytes, security_version::LANZABOOTE_VENDOR_UUID,
+    uefi_helpers::file_device_path,
+};
+
+const SLOT_VARIABLE: &CStr16 = cstr16!("LanzabooteSlot");
+const TRIES_VARIABLE: &CStr16 = cstr16!("LanzabooteSlotTries");
+const BOOTED_SLOT_VARIABLE: &CStr16 = cstr16!("LanzabooteBootedSlot");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::A => 0,
+            Self::B => 1,
+        }
+    }
+
+    fn name(self) -> &'static CStr16 {
+        match self {
+            Self::A => cstr16!("a"),
+            Self::B => cstr16!("b"),
+        }
+    }
+
+    /// Parse the contents of `LanzabooteSlot`, a NUL-terminated UTF-16 string.
+    fn from_variable(data: &[u8]) -> Option<Self> {
+        match data {
+            [b'a', 0, 0, 0] | [b'a', 0] => Some(Self::A),
+            [b'b', 0, 0, 0] | [b'b', 0] => Some(Self::B),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A => f.write_str("A"),
+            Self::B => f.write_str("B"),
+        }
+    }
+}
+
+/// The outcome of the slot selection for this boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    /// The slot to boot.
+    pub slot: Slot,
+    /// The tries that are left after this boot, or `None` if the slot is confirmed.
+    pub tries: Option<u8>,
+    /// Whether the active slot ran out of tries, so that the other slot becomes the active one.
+    pub fell_back: bool,
+}
+
+/// Select the slot to boot from the active slot and its remaining tries.
+pub fn select(active: Slot, tries: Option<u8>) -> Selection {
+    match tries {
+        None => Selection {
+            slot: active,
+            tries: None,
+            fell_back: false,
+        },
+        Some(0) => Selection {
+            slot: active.other(),
+            tries: None,
+            fell_back: true,
+        },
+        Some(tries) => Selection {
+            slot: active,
+            tries: Some(tries - 1),
+            fell_back: false,
+        },
+    }
+}
+
+/// The paths of the payloads of slot A and B, one per line of the `.abslots` section.
+pub fn parse_slot_paths(section: &[u8]) -> Option<[&str; 2]> {
+    let mut lines = core::str::from_utf8(section)
+        .ok()?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let paths = [lines.next()?, lines.next()?];
+    lines.next().is_none().then_some(paths)
+}
+
+fn persistent_attributes() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS
+}
+
+/// The active slot. Without a valid variable, slot A is active.
+fn read_active_slot() -> Slot {
+    let mut buffer = [0u8; 4];
+    match runtime::get_variable(SLOT_VARIABLE, &LANZABOOTE_VENDOR_UUID, &mut buffer) {
+        Ok((data, _)) => Slot::from_variable(data).unwrap_or_else(|| {
+            warn!("Ignoring the invalid active slot, booting slot A.");
+            Slot::A
+        }),
+        Err(_) => Slot::A,
+    }
+}
+
+/// The remaining tries of the active slot, or `None` if it is confirmed.
+fn read_tries() -> Option<u8> {
+    let mut buffer = [0u8; 1];
+    match runtime::get_variable(TRIES_VARIABLE, &LANZABOOTE_VENDOR_UUID, &mut buffer) {
+        Ok(([tries], _)) => Some(*tries),
+        Ok(_) => {
+            warn!("Ignoring the remaining tries of the slot with an unexpected size.");
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Record the selection, so that the next boot continues from it.
+fn persist(selection: Selection) -> Result<()> {
+    if selection.fell_back {
+        runtime::set_variable(
+            SLOT_VARIABLE,
+            &LANZABOOTE_VENDOR_UUID,
+            persistent_attributes(),
+            cstr16_to_bytes(selection.slot.name()),
+        )?;
+    }
+    match selection.tries {
+        Some(tries) => runtime::set_variable(
+            TRIES_VARIABLE,
+            &LANZABOOTE_VENDOR_UUID,
+            persistent_attributes(),
+            &[tries],
+        ),
+        None if selection.fell_back => {
+            runtime::delete_variable(TRIES_VARIABLE, &LANZABOOTE_VENDOR_UUID)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Load the payload at `path` on the file system of the stub.
+///
+/// The full device path is passed to the firmware, so that the payload finds its kernel and
+/// initrd on the same file system.
+fn load_payload(path: &str) -> Result<Handle> {
+    let path_name = CString16::try_from(path).map_err(|_| Status::INVALID_PARAMETER)?;
+    let mut buffer = Vec::new();
+    let payload_path = file_device_path(&path_name, &mut buffer)?;
+
+    boot::load_image(
+        boot::image_handle(),
+        LoadImageSource::FromDevicePath {
+            device_path: payload_path,
+            boot_policy: BootPolicy::ExactMatch,
+        },
+    )
+}
+
+/// Select a slot and chainload its payload.
+///
+/// If the payload of the selected slot cannot be loaded, e.g. because its signature is not
+/// accepted, or returns, the payload of the other slot is booted instead. This only returns if
+/// neither slot boots.
+pub fn boot_slots(section: &[u8]) -> Result<()> {
+    let paths = parse_slot_paths(section).ok_or(Status::LOAD_ERROR)?;
+
+    let selection = select(read_active_slot(), read_tries());
+    if selection.fell_back {
+        warn!(
+            "Slot {} ran out of tries, falling back to slot {}.",
+            selection.slot.other(),
+            selection.slot
+        );
+    }
+    if persist(selection).is_err() {
+        warn!("Failed to record the slot selection, the next boot may select the same slot.");
+    }
+
+    for slot in [selection.slot, selection.slot.other()] {
+        info!("Booting slot {slot} from {}...", paths[slot.index()]);
+        if runtime::set_variable(
+            BOOTED_SLOT_VARIABLE,
+            &LANZABOOTE_VENDOR_UUID,
+            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+            cstr16_to_bytes(slot.name()),
+        )
+        .is_err()
+        {
+            warn!("Failed to record the booted slot, `lzbt slot mark-good` will not work.");
+        }
+        match load_payload(paths[slot.index()]).and_then(boot::start_image) {
+            Ok(()) => warn!("The payload of slot {slot} returned."),
+            Err(err) => warn!("Failed to boot slot {slot}: {}", err.status()),
+        }
+    }
+
+    Err(Status::LOAD_ERROR.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fall_back_once_out_of_tries() {
+        assert_eq!(
+            select(Slot::B, None),
+            Selection {
+                slot: Slot::B,
+                tries: None,
+                fell_back: false
+            }
+        );
+        assert_eq!(
+            select(Slot::B, Some(3)),
+            Selection {
+                slot: Slot::B,
+                tries: Some(2),
+                fell_back: false
+            }
+        );
+        assert_eq!(
+            select(Slot::B, Some(0)),
+            Selection {
+                slot: Slot::A,
+                tries: None,
+                fell_back: true
+            }
+        );
+    }
+
+    #[test]
+    fn parse_slots() {
+        assert_eq!(
+            parse_slot_paths(b"\\EFI\\nixos\\slot-a.efi\n\\EFI\\nixos\\slot-b.efi\n"),
+            Some(["\\EFI\\nixos\\slot-a.efi", "\\EFI\\nixos\\slot-b.efi"])
+        );
+        assert_eq!(parse_slot_paths(b"\\EFI\\nixos\\slot-a.efi"), None);
+        assert_eq!(parse_slot_paths(b"a\nb\nc"), None);
+        assert_eq!(Slot::from_variable(&[b'b', 0, 0, 0]), Some(Slot::B));
+        assert_eq!(Slot::from_variable(&[b'c', 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Minimal reader for the SMBIOS tables of the firmware.
+//!
+//! Only what the stub needs is parsed: the structures of the table and their strings.
+
+use core::slice;
+use uefi::{
+    system,
+    table::cfg::{SMBIOS3_GUID, SMBIOS_GUID},
+    Guid,
+};
+
+/// `System Information (Type 1)`
+const TYPE_SYSTEM_INFORMATION: u8 = 1;
+/// `OEM Strings (Type 11)`
+const TYPE_OEM_STRINGS: u8 = 11;
+/// `End-of-Table (Type 127)`
+const TYPE_END_OF_TABLE: u8 = 127;
+
+/// A structure of the SMBIOS table.
+pub struct Structure<'a> {
+    /// The type of the structure.
+    pub kind: u8,
+    /// The formatted area of the structure, including the header.
+    pub formatted: &'a [u8],
+    /// The unformatted area of the structure, i.e. its NUL-terminated strings.
+    pub strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// The strings of the structure. The first string is referenced as 1 by the formatted area.
+    pub fn strings(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.strings
+            .split(|b| *b == 0)
+            .take_while(|string| !string.is_empty())
+    }
+}
+
+/// Iterator over the structures of an SMBIOS table.
+pub struct Structures<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let kind = *self.table.first()?;
+        let length = usize::from(*self.table.get(1)?);
+        if kind == TYPE_END_OF_TABLE || length < 4 {
+            return None;
+        }
+        let formatted = self.table.get(..length)?;
+
+        // The strings end with two NUL bytes, even if there are none.
+        let rest = &self.table[length..];
+        let end = rest.windows(2).position(|w| w == [0, 0])? + 2;
+        let strings = &rest[..end];
+        self.table = &rest[end..];
+
+        Some(Structure {
+            kind,
+            formatted,
+            strings,
+        })
+    }
+}
+
+/// Locate the SMBIOS table of the firmware. The SMBIOS 3 table is preferred.
+fn table() -> Option<&'static [u8]> {
+    let (smbios3, smbios) = system::with_config_table(|entries| {
+        let address = |guid: Guid| {
+            entries
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map(|entry| entry.address.cast::<u8>())
+        };
+        (address(SMBIOS3_GUID), address(SMBIOS_GUID))
+    });
+
+    // SAFETY: The entry point structures and the tables they point to are provided by the
+    // firmware and stay valid while boot services are active. Their anchors are checked before
+    // anything else is read.
+    unsafe {
+        if let Some(entry_point) = smbios3 {
+            let entry_point = slice::from_raw_parts(entry_point, 0x18);
+            if entry_point.starts_with(b"_SM3_") {
+                let length = u32::from_le_bytes(entry_point[0x0c..0x10].try_into().ok()?);
+                let address = u64::from_le_bytes(entry_point[0x10..0x18].try_into().ok()?);
+                return Some(slice::from_raw_parts(
+                    usize::try_from(address).ok()? as *const u8,
+                    usize::try_from(length).ok()?,
+                ));
+            }
+        }
+        if let Some(entry_point) = smbios {
+            let entry_point = slice::from_raw_parts(entry_point, 0x1f);
+            if entry_point.starts_with(b"_SM_") {
+                let length = u16::from_le_bytes(entry_point[0x16..0x18].try_into().ok()?);
+                let address = u32::from_le_bytes(entry_point[0x18..0x1c].try_into().ok()?);
+                return Some(slice::from_raw_parts(
+                    usize::try_from(address).ok()? as *const u8,
+                    usize::from(length),
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+/// The structures of the SMBIOS table of the firmware. Empty if there is no SMBIOS table.
+pub fn structures() -> Structures<'static> {
+    Structures {
+        table: table().unwrap_or(&[]),
+    }
+}
+
+/// The UUID of the system from the `System Information` structure.
+///
+/// Like the kernel, UUIDs that are all zeroes or all ones are considered to be not set.
+pub fn system_uuid() -> Option<Guid> {
+    let system_information = structures().find(|s| s.kind == TYPE_SYSTEM_INFORMATION)?;
+    let uuid: [u8; 16] = system_information.formatted.get(8..24)?.try_into().ok()?;
+    if uuid.iter().all(|b| *b == 0) || uuid.iter().all(|b| *b == 0xff) {
+        return None;
+    }
+
+    // Since SMBIOS 2.6, the UUID uses the same mixed-endian encoding as EFI GUIDs.
+    Some(Guid::from_bytes(uuid))
+}
+
+/// The serial number of the system from the `System Information` structure, if it is set.
+pub fn system_serial_number() -> Option<&'static str> {
+    let system_information = structures().find(|s| s.kind == TYPE_SYSTEM_INFORMATION)?;
+    // The string is referenced by its index, starting at 1, and 0 means that there is none.
+    let index = usize::from(*system_information.formatted.get(7)?);
+    let serial = system_information.strings().nth(index.checked_sub(1)?)?;
+    let serial = core::str::from_utf8(serial).ok()?.trim();
+    (!serial.is_empty()).then_some(serial)
+}
+
+/// Look up the value of a `key=value` OEM string.
+///
+/// Hypervisors let the host pass such strings to a guest, e.g. QEMU with
+/// `-smbios type=11,value=key=value`. If a key appears multiple times, the first value is used.
+pub fn oem_string(key: &str) -> Option<&'static str> {
+    structures()
+        .filter(|s| s.kind == TYPE_OEM_STRINGS)
+        .flat_map(|s| s.strings())
+        .find_map(|string| {
+            let value = string.strip_prefix(key.as_bytes())?.strip_prefix(b"=")?;
+            core::str::from_utf8(value).ok()
+        })
+}
@@ -0,0 +1,123 @@
+//! Display a splash image while the kernel is loaded.
+//!
+//! Like with systemd-stub, the image is an uncompressed BMP file that is embedded into the
+//! `.splash` PE section. It is drawn centered on the screen using the Graphics Output Protocol.
+
+use alloc::vec::Vec;
+use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
+    proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput},
+    Result, Status,
+};
+
+/// A decoded image, stored top-down.
+struct Bitmap {
+    width: usize,
+    height: usize,
+    pixels: Vec<BltPixel>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+impl Bitmap {
+    /// Decode an uncompressed 24 or 32 bits per pixel BMP file.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.get(0..2)? != b"BM" {
+            return None;
+        }
+
+        let pixel_offset = usize::try_from(read_u32(data, 10)?).ok()?;
+        let dib_header_size = read_u32(data, 14)?;
+        let width = read_u32(data, 18)? as i32;
+        let height = read_u32(data, 22)? as i32;
+        let bits_per_pixel = read_u16(data, 28)?;
+        let compression = read_u32(data, 30)?;
+
+        // BITMAPINFOHEADER or one of its successors.
+        if dib_header_size < 40 {
+            return None;
+        }
+        // Only BI_RGB and, for 32 bits per pixel, BI_BITFIELDS with the default masks are
+        // supported.
+        let bytes_per_pixel = match (bits_per_pixel, compression) {
+            (24, 0) => 3,
+            (32, 0 | 3) => 4,
+            _ => return None,
+        };
+        if width <= 0 || height == 0 {
+            return None;
+        }
+
+        // Rows are stored bottom-up, unless the height is negative.
+        let top_down = height < 0;
+        let width = usize::try_from(width).ok()?;
+        let height = usize::try_from(height.unsigned_abs()).ok()?;
+        // Rows are padded to a multiple of 4 bytes.
+        let stride = (width * bytes_per_pixel + 3) & !3;
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = if top_down { y } else { height - 1 - y };
+            let start = pixel_offset.checked_add(row.checked_mul(stride)?)?;
+            let row_data = data.get(start..start + width * bytes_per_pixel)?;
+            pixels.extend(
+                row_data
+                    .chunks_exact(bytes_per_pixel)
+                    .map(|bgr| BltPixel::new(bgr[2], bgr[1], bgr[0])),
+            );
+        }
+
+        Some(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+/// Draw a BMP image centered on the screen.
+///
+/// Images that do not fit on the screen are not drawn.
+pub fn draw_splash(bmp: &[u8]) -> Result<()> {
+    let bitmap = Bitmap::parse(bmp).ok_or(Status::UNSUPPORTED)?;
+
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>()?;
+    // SAFETY: Opening the protocol exclusively would disconnect the console from the graphics
+    // output on some firmware. The protocol is only used for the duration of this function and
+    // nothing else uses it concurrently.
+    let mut gop = unsafe {
+        boot::open_protocol::<GraphicsOutput>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+
+    let (screen_width, screen_height) = gop.current_mode_info().resolution();
+    if bitmap.width > screen_width || bitmap.height > screen_height {
+        return Err(Status::BAD_BUFFER_SIZE.into());
+    }
+
+    gop.blt(BltOp::BufferToVideo {
+        buffer: &bitmap.pixels,
+        src: BltRegion::Full,
+        dest: (
+            (screen_width - bitmap.width) / 2,
+            (screen_height - bitmap.height) / 2,
+        ),
+        dims: (bitmap.width, bitmap.height),
+    })
+}
@@ -1,5 +1,7 @@
 use alloc::vec::Vec;
 use log::warn;
+
+use crate::zeroize::{zeroize, Zeroizing};
 use uefi::{
     boot::{self, ScopedProtocol},
     proto::tcg::{v2, EventType, PcrIndex},
@@ -56,3 +58,222 @@ pub fn tpm_log_event_ascii(
 
     Ok(true)
 }
+
+/// `TPM_ST_NO_SESSIONS`
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+/// `TPM_CC_PCR_Read`
+const TPM_CC_PCR_READ: u32 = 0x0000_017e;
+/// `TPM_ALG_SHA256`
+const TPM_ALG_SHA256: u16 = 0x000b;
+/// The size of the PCR bitmap in a `TPMS_PCR_SELECTION`, enough for the 24 PCRs of a PC client TPM.
+const PCR_SELECT_SIZE: u8 = 3;
+
+/// The bitmap of a `TPMS_PCR_SELECTION` with the given PCRs.
+fn pcr_select(pcr_indices: &[u32]) -> uefi::Result<[u8; PCR_SELECT_SIZE as usize]> {
+    let mut select = [0u8; PCR_SELECT_SIZE as usize];
+    for &index in pcr_indices {
+        let byte = select
+            .get_mut(index as usize / 8)
+            .ok_or(uefi::Status::INVALID_PARAMETER)?;
+        *byte |= 1 << (index % 8);
+    }
+    Ok(select)
+}
+
+/// Read the values of the given PCRs from the SHA256 bank.
+///
+/// The TPM may return fewer PCRs than requested, e.g. if the SHA256 bank is not active. The
+/// returned values are ordered by PCR index.
+pub fn tpm_read_pcrs(pcr_indices: &[u32]) -> uefi::Result<Vec<(u32, [u8; 32])>> {
+    let mut tpm2 = open_capable_tpm2()?;
+    let select = pcr_select(pcr_indices)?;
+
+    let mut command = Vec::new();
+    command.extend_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    // The command size is filled in below.
+    command.extend_from_slice(&0u32.to_be_bytes());
+    command.extend_from_slice(&TPM_CC_PCR_READ.to_be_bytes());
+    // TPML_PCR_SELECTION with a single TPMS_PCR_SELECTION.
+    command.extend_from_slice(&1u32.to_be_bytes());
+    command.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+    command.push(PCR_SELECT_SIZE);
+    command.extend_from_slice(&select);
+    let size = (command.len() as u32).to_be_bytes();
+    command[2..6].copy_from_slice(&size);
+
+    let mut response = [0u8; 1024];
+    tpm2.submit_command(&command, &mut response)?;
+
+    parse_pcr_read_response(&response).ok_or(uefi::Status::DEVICE_ERROR.into())
+}
+
+/// Parse the response to a `TPM2_PCR_Read` command.
+fn parse_pcr_read_response(response: &[u8]) -> Option<Vec<(u32, [u8; 32])>> {
+    let mut cursor = response;
+    let mut take = |n: usize| -> Option<&[u8]> {
+        let (head, tail) = (cursor.get(..n)?, cursor.get(n..)?);
+        cursor = tail;
+        Some(head)
+    };
+    let be_u32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+
+    // Header: tag, size and response code.
+    take(6)?;
+    if be_u32(take(4)?) != 0 {
+        warn!("TPM2_PCR_Read failed");
+        return None;
+    }
+    // pcrUpdateCounter
+    take(4)?;
+
+    // pcrSelectionOut tells which PCRs were actually read, in the order of the digests.
+    let mut indices = Vec::new();
+    for _ in 0..be_u32(take(4)?) {
+        let algorithm = take(2)?;
+        let select_size = usize::from(*take(1)?.first()?);
+        let select = take(select_size)?;
+        if u16::from_be_bytes([algorithm[0], algorithm[1]]) != TPM_ALG_SHA256 {
+            continue;
+        }
+        for (byte_index, byte) in select.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 {
+                    indices.push((byte_index * 8 + bit) as u32);
+                }
+            }
+        }
+    }
+
+    let digest_count = be_u32(take(4)?) as usize;
+    let mut values = Vec::with_capacity(digest_count);
+    for index in indices.into_iter().take(digest_count) {
+        let size = take(2)?;
+        let size = usize::from(u16::from_be_bytes([size[0], size[1]]));
+        values.push((index, take(size)?.try_into().ok()?));
+    }
+
+    Some(values)
+}
+
+/// `TPM_ST_SESSIONS`
+const TPM_ST_SESSIONS: u16 = 0x8002;
+/// `TPM_CC_StartAuthSession`
+const TPM_CC_START_AUTH_SESSION: u32 = 0x0000_0176;
+/// `TPM_CC_PolicyPCR`
+const TPM_CC_POLICY_PCR: u32 = 0x0000_017f;
+/// `TPM_CC_Unseal`
+const TPM_CC_UNSEAL: u32 = 0x0000_015e;
+/// `TPM_CC_FlushContext`
+const TPM_CC_FLUSH_CONTEXT: u32 = 0x0000_0165;
+/// `TPM_RH_NULL`
+const TPM_RH_NULL: u32 = 0x4000_0007;
+/// `TPM_SE_POLICY`
+const TPM_SE_POLICY: u8 = 0x01;
+/// `TPM_ALG_NULL`
+const TPM_ALG_NULL: u16 = 0x0010;
+
+/// Start a command with its header. The size is filled in by [`submit`].
+fn command_header(tag: u16, code: u32) -> Vec<u8> {
+    let mut command = Vec::new();
+    command.extend_from_slice(&tag.to_be_bytes());
+    command.extend_from_slice(&0u32.to_be_bytes());
+    command.extend_from_slice(&code.to_be_bytes());
+    command
+}
+
+/// Submit a command and return the response if it succeeded.
+fn submit(tpm2: &mut v2::Tcg, mut command: Vec<u8>) -> uefi::Result<Vec<u8>> {
+    let size = (command.len() as u32).to_be_bytes();
+    command[2..6].copy_from_slice(&size);
+
+    let mut response = [0u8; 1024];
+    tpm2.submit_command(&command, &mut response)?;
+    let size = u32::from_be_bytes([response[2], response[3], response[4], response[5]]) as usize;
+    let code = u32::from_be_bytes([response[6], response[7], response[8], response[9]]);
+    let result = match response.get(..size) {
+        Some(response) if code == 0 && size >= 10 => Ok(response.to_vec()),
+        _ => {
+            warn!(
+                "TPM command {:#x} failed with {code:#x}",
+                u32::from_be_bytes([command[6], command[7], command[8], command[9]])
+            );
+            Err(uefi::Status::DEVICE_ERROR.into())
+        }
+    };
+    // The response may contain a secret, e.g. of `TPM2_Unseal`.
+    zeroize(&mut response);
+    result
+}
+
+/// Unseal the data of the sealed object at the persistent `handle`, whose policy requires the
+/// given PCRs of the SHA256 bank to have the values they had when it was sealed.
+///
+/// This is the equivalent of `tpm2_unseal -c <handle> -p pcr:sha256:<pcrs>`. The object must not
+/// require an authorization value.
+pub fn tpm_unseal(handle: u32, pcr_indices: &[u32]) -> uefi::Result<Zeroizing> {
+    let mut tpm2 = open_capable_tpm2()?;
+    let select = pcr_select(pcr_indices)?;
+
+    // An unsalted and unbound policy session. Without an HMAC, the nonces do not protect anything.
+    let mut command = command_header(TPM_ST_NO_SESSIONS, TPM_CC_START_AUTH_SESSION);
+    command.extend_from_slice(&TPM_RH_NULL.to_be_bytes());
+    command.extend_from_slice(&TPM_RH_NULL.to_be_bytes());
+    command.extend_from_slice(&32u16.to_be_bytes());
+    command.extend_from_slice(&[0; 32]);
+    command.extend_from_slice(&0u16.to_be_bytes());
+    command.push(TPM_SE_POLICY);
+    command.extend_from_slice(&TPM_ALG_NULL.to_be_bytes());
+    command.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+    let response = submit(&mut tpm2, command)?;
+    let session = response
+        .get(10..14)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(uefi::Status::DEVICE_ERROR)?;
+
+    let result = unseal_with_session(&mut tpm2, session, handle, &select);
+    if result.is_err() {
+        // A successful `TPM2_Unseal` already flushed the session, because it is not continued.
+        let mut command = command_header(TPM_ST_NO_SESSIONS, TPM_CC_FLUSH_CONTEXT);
+        command.extend_from_slice(&session.to_be_bytes());
+        let _ = submit(&mut tpm2, command);
+    }
+    result
+}
+
+fn unseal_with_session(
+    tpm2: &mut v2::Tcg,
+    session: u32,
+    handle: u32,
+    select: &[u8; PCR_SELECT_SIZE as usize],
+) -> uefi::Result<Zeroizing> {
+    // Without a digest, the TPM uses the current values of the PCRs, which the policy of the
+    // object then compares with the values it was sealed to.
+    let mut command = command_header(TPM_ST_NO_SESSIONS, TPM_CC_POLICY_PCR);
+    command.extend_from_slice(&session.to_be_bytes());
+    command.extend_from_slice(&0u16.to_be_bytes());
+    command.extend_from_slice(&1u32.to_be_bytes());
+    command.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+    command.push(PCR_SELECT_SIZE);
+    command.extend_from_slice(select);
+    submit(tpm2, command)?;
+
+    let mut command = command_header(TPM_ST_SESSIONS, TPM_CC_UNSEAL);
+    command.extend_from_slice(&handle.to_be_bytes());
+    // The authorization area with the policy session, an empty nonce, no attributes and no HMAC.
+    command.extend_from_slice(&9u32.to_be_bytes());
+    command.extend_from_slice(&session.to_be_bytes());
+    command.extend_from_slice(&0u16.to_be_bytes());
+    command.push(0);
+    command.extend_from_slice(&0u16.to_be_bytes());
+    let response = Zeroizing::new(submit(tpm2, command)?);
+
+    // The header, the size of the parameters and the size of the data.
+    let size = response
+        .get(14..16)
+        .map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+        .ok_or(uefi::Status::DEVICE_ERROR)?;
+    let data = response
+        .get(16..16 + size)
+        .ok_or(uefi::Status::DEVICE_ERROR)?;
+    Ok(Zeroizing::new(data.to_vec()))
+}
@@ -1,12 +1,13 @@
 use core::ffi::c_void;
 
+use alloc::vec::Vec;
 use uefi::{
     boot,
     proto::{
-        device_path::{DevicePath, FfiDevicePath},
+        device_path::{build, DevicePath, FfiDevicePath},
         loaded_image::LoadedImage,
     },
-    Result,
+    CStr16, Result, Status,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -60,3 +61,23 @@ pub fn booted_image_file() -> Result<PeInMemory> {
         image_size: usize::try_from(image_size).map_err(|_| uefi::Status::INVALID_PARAMETER)?,
     })
 }
+
+/// Build the full device path of the file at `path` on the file system of the stub in `buffer`.
+///
+/// Images that are loaded with the full device path find their own files on the same file
+/// system.
+pub fn file_device_path<'a>(path: &CStr16, buffer: &'a mut Vec<u8>) -> Result<&'a DevicePath> {
+    let device = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?
+        .device()
+        .ok_or(Status::NOT_FOUND)?;
+
+    let mut builder = build::DevicePathBuilder::with_vec(buffer);
+    // The nodes are copied, so that the device path is closed again before loading an image.
+    for node in boot::open_protocol_exclusive::<DevicePath>(device)?.node_iter() {
+        builder = builder.push(&node).map_err(|_| Status::OUT_OF_RESOURCES)?;
+    }
+    builder
+        .push(&build::media::FilePath { path_name: path })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| Status::OUT_OF_RESOURCES.into())
+}
@@ -0,0 +1,56 @@
+//! The dm-verity root hash of image-based systems.
+//!
+//! lzbt embeds the root hash into the `.roothash` section and as `roothash=` into the command
+//! line. systemd uses the last `roothash=` of the command line, so the stub adds the embedded one
+//! again if addons, SMBIOS or fw_cfg added another. This way, the integrity of the root file
+//! system always chains up to the signature of the stub.
+
+use alloc::{format, vec::Vec};
+use log::warn;
+
+use crate::cmdline_template::{insert_parameters, last_value};
+
+/// The kernel parameter that systemd-veritysetup-generator reads the root hash from.
+const ROOT_HASH_PARAMETER: &str = "roothash";
+
+/// Make sure that the embedded root hash is the one the system uses.
+pub fn enforce_root_hash(cmdline: Vec<u8>, root_hash: &str) -> Vec<u8> {
+    if last_value(&cmdline, ROOT_HASH_PARAMETER).as_deref() == Some(root_hash) {
+        return cmdline;
+    }
+    warn!("Restoring the embedded dm-verity root hash on the command line.");
+    let parameter = format!("{ROOT_HASH_PARAMETER}={root_hash}");
+    insert_parameters(&cmdline, [parameter.as_str()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdline_template::to_utf16_bytes;
+
+    #[test]
+    fn keep_the_embedded_root_hash_last() {
+        let cmdline = to_utf16_bytes("init=/init roothash=abcd");
+        assert_eq!(enforce_root_hash(cmdline.clone(), "abcd"), cmdline);
+
+        assert_eq!(
+            enforce_root_hash(
+                to_utf16_bytes("init=/init roothash=abcd roothash=ef01"),
+                "abcd"
+            ),
+            to_utf16_bytes("init=/init roothash=abcd roothash=ef01 roothash=abcd")
+        );
+        assert_eq!(
+            enforce_root_hash(to_utf16_bytes(""), "abcd"),
+            to_utf16_bytes("roothash=abcd")
+        );
+        assert_eq!(
+            enforce_root_hash(to_utf16_bytes("roothash=\"abcd\" -- roothash=ef01"), "abcd"),
+            to_utf16_bytes("roothash=\"abcd\" -- roothash=ef01")
+        );
+        assert_eq!(
+            enforce_root_hash(to_utf16_bytes("init=/init -- roothash=abcd"), "abcd"),
+            to_utf16_bytes("init=/init roothash=abcd -- roothash=abcd")
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! EFI zboot kernels.
+//!
+//! On arm64 and other architectures without a decompressor of their own, kernels are increasingly
+//! packaged as `vmlinuz.efi`: a small EFI application that decompresses the actual kernel image
+//! and starts it. Its header tells where the compressed image is and how it is compressed, see
+//! `drivers/firmware/efi/libstub/zboot-header.S` in the kernel.
+//!
+//! Gzip-compressed images are unpacked by the stub, so that the actual kernel image is loaded like
+//! any other. For other compression types, the zboot application itself is started, which then
+//! decompresses the kernel.
+
+use alloc::vec::Vec;
+use log::info;
+use uefi::{Result, Status};
+
+use crate::gzip::gunzip;
+
+const ZBOOT_MAGIC: &[u8] = b"zimg";
+const ZBOOT_MAGIC_OFFSET: usize = 4;
+const PAYLOAD_OFFSET_OFFSET: usize = 8;
+const PAYLOAD_SIZE_OFFSET: usize = 12;
+const COMPRESSION_TYPE_OFFSET: usize = 24;
+/// The compression type is stored NUL-terminated before the PE header pointer.
+const COMPRESSION_TYPE_SIZE: usize = 32;
+
+/// The header of an EFI zboot kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZbootHeader<'a> {
+    /// The compressed kernel image.
+    pub payload: &'a [u8],
+    /// How the kernel image is compressed, e.g. `gzip` or `zstd`.
+    pub compression_type: &'a str,
+}
+
+impl<'a> ZbootHeader<'a> {
+    /// Parse the header of a zboot kernel.
+    ///
+    /// Returns `None` if the kernel is not a zboot kernel. A header that points outside of the
+    /// kernel is an error.
+    pub fn parse(kernel_data: &'a [u8]) -> Result<Option<Self>> {
+        if kernel_data.get(..2) != Some(b"MZ")
+            || kernel_data.get(ZBOOT_MAGIC_OFFSET..ZBOOT_MAGIC_OFFSET + 4) != Some(ZBOOT_MAGIC)
+        {
+            return Ok(None);
+        }
+
+        let offset = read_u32(kernel_data, PAYLOAD_OFFSET_OFFSET)? as usize;
+        let size = read_u32(kernel_data, PAYLOAD_SIZE_OFFSET)? as usize;
+        let payload = offset
+            .checked_add(size)
+            .and_then(|end| kernel_data.get(offset..end))
+            .ok_or(Status::LOAD_ERROR)?;
+
+        let compression_type = kernel_data
+            .get(COMPRESSION_TYPE_OFFSET..COMPRESSION_TYPE_OFFSET + COMPRESSION_TYPE_SIZE)
+            .and_then(|field| field.split(|&byte| byte == 0).next())
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(Status::LOAD_ERROR)?;
+
+        Ok(Some(Self {
+            payload,
+            compression_type,
+        }))
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or(Status::LOAD_ERROR)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Unpack the kernel image from a zboot kernel, if the stub can decompress it.
+///
+/// Other kernels, including zboot kernels with other compression types, are returned as they are,
+/// because they are EFI applications that can be started directly.
+pub fn unpack(kernel_data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(header) = ZbootHeader::parse(&kernel_data)? else {
+        return Ok(kernel_data);
+    };
+
+    match header.compression_type {
+        "gzip" => gunzip(header.payload),
+        compression_type => {
+            info!("Starting the {compression_type} zboot kernel, which decompresses itself.");
+            Ok(kernel_data)
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Overwriting buffers that may contain secrets, e.g. credentials, before they are freed.
+//!
+//! The memory of the stub is not cleared when it returns to the firmware after a failed boot, so
+//! the next boot option could otherwise read the secrets.
+
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Overwrite a buffer with zeroes in a way the compiler does not optimize away.
+pub fn zeroize(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        // SAFETY: `byte` is a valid, aligned reference.
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// A buffer that is zeroed, including its spare capacity, when it is dropped.
+#[derive(Default)]
+pub struct Zeroizing(Vec<u8>);
+
+impl Zeroizing {
+    pub fn new(buffer: Vec<u8>) -> Self {
+        Self(buffer)
+    }
+}
+
+impl Deref for Zeroizing {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Zeroizing {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Zeroizing {
+    fn drop(&mut self) {
+        // Growing up to the capacity does not reallocate.
+        self.0.resize(self.0.capacity(), 0);
+        zeroize(&mut self.0);
+    }
+}
@@ -1,38 +1,245 @@
-use alloc::vec::Vec;
-use log::warn;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{error, info, warn, LevelFilter};
 use uefi::{
     boot, guid, prelude::*, proto::loaded_image::LoadedImage, runtime, runtime::VariableVendor,
-    CStr16, CString16, Result,
+    CStr16,
 };
 
+use linux_bootloader::cmdline_template::{
+    append_cmdline, has_placeholders, to_utf16_bytes, TemplateVariables,
+};
+use linux_bootloader::console::{mirror_to_serial, select_best_mode};
+use linux_bootloader::efi_handover;
+use linux_bootloader::embedded_config::{extract_flag, Hash};
+use linux_bootloader::fw_cfg::read_file;
+use linux_bootloader::instance_metadata::instance_metadata_initrd;
 use linux_bootloader::linux_loader::InitrdLoader;
+use linux_bootloader::log_file;
+use linux_bootloader::measure::{measure_cmdline, measure_initrd};
+use linux_bootloader::messages::{set_locale, Locale, Message};
 use linux_bootloader::pe_loader::Image;
-use linux_bootloader::pe_section::pe_section_as_string;
+use linux_bootloader::pe_section::pe_section;
+use linux_bootloader::setup_header::SetupHeader;
+use linux_bootloader::smbios::oem_string;
+use linux_bootloader::zboot;
+use linux_bootloader::zeroize::Zeroizing;
+
+use crate::error::{self, Context};
+use crate::STUB_NAME;
+
+/// How much the stub logs to the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Debug,
+}
+
+impl Verbosity {
+    /// Parse the verbosity embedded by lzbt. A missing section means normal verbosity.
+    fn from_section(data: Option<&[u8]>) -> Self {
+        match data {
+            None | Some(b"normal") => Self::Normal,
+            Some(b"quiet") => Self::Quiet,
+            Some(b"debug") => Self::Debug,
+            Some(_) => {
+                warn!("Unknown verbosity embedded in the stub. Using normal verbosity.");
+                Self::Normal
+            }
+        }
+    }
+
+    fn level_filter(self) -> LevelFilter {
+        match self {
+            Self::Quiet => LevelFilter::Error,
+            // Release builds only log warnings by default.
+            Self::Normal if cfg!(debug_assertions) => LevelFilter::Info,
+            Self::Normal => LevelFilter::Warn,
+            Self::Debug => LevelFilter::Debug,
+        }
+    }
+}
+
+/// Configure the console according to the configuration embedded by lzbt.
+///
+/// This sets the log level from the `.loglvl` section and the language of messages from the
+/// `.locale` section, and clears the screen if a `.clrscr` section is present. Otherwise, whatever
+/// the firmware displays (e.g. its splash) is kept. Log lines also go to the log file on the ESP
+/// with a `.logfile` section, and to serial ports as described in
+/// [`linux_bootloader::console::mirror_to_serial`].
+pub fn setup_console(pe_data: &[u8]) {
+    // Parsing our own image already logs at debug level, so start out with normal verbosity.
+    log::set_max_level(Verbosity::Normal.level_filter());
+    log::set_max_level(Verbosity::from_section(pe_section(pe_data, ".loglvl")).level_filter());
+    set_locale(Locale::from_section(pe_section(pe_data, ".locale")));
+    if extract_flag(pe_data, ".logfile") {
+        log_file::enable(STUB_NAME);
+    }
+    mirror_to_serial(extract_flag(pe_data, ".serial"));
+
+    // Switching the text mode clears the screen, so only pick a larger one if that is wanted.
+    if extract_flag(pe_data, ".clrscr") {
+        select_best_mode();
+        if uefi::system::with_stdout(|stdout| stdout.clear()).is_err() {
+            warn!("Failed to clear the screen.");
+        }
+    }
+}
 
-/// Extract a string, stored as UTF-8, from a PE section.
-pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
-    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+/// Check whether the Secure Boot policy should be enforced.
+///
+/// This is the case if Secure Boot is active, or if Secure Boot is simulated. Simulation is
+/// enabled either at build time with the `simulate-secure-boot` feature or by lzbt embedding a
+/// `.sbsim` section. It allows testing a setup in a VM before enrolling keys.
+pub fn get_secure_boot_policy(simulate_secure_boot: bool) -> bool {
+    if get_secure_boot_status() {
+        return true;
+    }
+
+    let simulated = simulate_secure_boot || cfg!(feature = "simulate-secure-boot");
+    if simulated {
+        info!("Simulating Secure Boot. Integrity checks will be enforced.");
+    }
 
-    Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
+    simulated
+}
+
+/// Check whether Secure Boot is simulated while the firmware does not enforce it.
+///
+/// The firmware then loads any binary, so it cannot be used to verify addons.
+pub fn is_secure_boot_simulated(simulate_secure_boot: bool) -> bool {
+    (simulate_secure_boot || cfg!(feature = "simulate-secure-boot")) && !get_secure_boot_status()
 }
 
 /// Obtain the kernel command line that should be used for booting.
 ///
 /// If Secure Boot is active, this is always the embedded one (since the one passed from the bootloader may come from a malicious type 1 entry).
 /// If Secure Boot is not active, the command line passed from the bootloader is used, falling back to the embedded one.
-pub fn get_cmdline(embedded: &CStr16, secure_boot_enabled: bool) -> Vec<u8> {
+///
+/// The `io.systemd.stub.kernel-cmdline-extra` SMBIOS OEM string is appended if Secure Boot is not
+/// active or `allow_smbios_cmdline` is set, i.e. the stub was built with the `.smbcmd` section.
+///
+/// In QEMU, a command line passed with fw_cfg replaces all of the above if Secure Boot is not
+/// active or `allow_fw_cfg` is set, i.e. the stub was built with the `.fwcfg` section.
+pub fn get_cmdline(
+    embedded: &CStr16,
+    secure_boot_enabled: bool,
+    allow_smbios_cmdline: bool,
+    allow_fw_cfg: bool,
+) -> Vec<u8> {
+    if !secure_boot_enabled || allow_fw_cfg {
+        if let Some(cmdline) = read_file(FW_CFG_CMDLINE) {
+            let cmdline = String::from_utf8_lossy(&cmdline);
+            let cmdline = cmdline.trim_end_matches(['\0', '\n', ' ']);
+            info!("Using the command line from fw_cfg.");
+            let _ = measure_cmdline(cmdline);
+            return to_utf16_bytes(cmdline);
+        }
+    }
+
+    let cmdline = base_cmdline(embedded, secure_boot_enabled);
+    if secure_boot_enabled && !allow_smbios_cmdline {
+        return cmdline;
+    }
+
+    let Some(extra) = oem_string(SMBIOS_CMDLINE_EXTRA) else {
+        return cmdline;
+    };
+    info!("Extending the command line from SMBIOS.");
+    let _ = measure_cmdline(extra);
+    append_cmdline(&cmdline, [extra])
+}
+
+/// The OEM string whose value is appended to the command line, like in systemd-stub.
+const SMBIOS_CMDLINE_EXTRA: &str = "io.systemd.stub.kernel-cmdline-extra";
+
+/// The fw_cfg file that replaces the command line, e.g. passed to QEMU with
+/// `-fw_cfg name=opt/org.nix-community.lanzaboote/cmdline,string=...`.
+const FW_CFG_CMDLINE: &str = "opt/org.nix-community.lanzaboote/cmdline";
+
+/// The fw_cfg file that is passed to the kernel as an additional initrd.
+const FW_CFG_INITRD: &str = "opt/org.nix-community.lanzaboote/initrd";
+
+/// Obtain the additional initrd passed with fw_cfg, under the same policy as the command line
+/// from fw_cfg, see [`get_cmdline`].
+///
+/// As it comes after all other initrds, its files replace theirs.
+pub fn get_fw_cfg_initrd(secure_boot_enabled: bool, allow_fw_cfg: bool) -> Option<Vec<u8>> {
+    if secure_boot_enabled && !allow_fw_cfg {
+        return None;
+    }
+
+    let initrd = read_file(FW_CFG_INITRD)?;
+    info!("Adding the initrd from fw_cfg.");
+    let _ = measure_initrd(&initrd);
+    Some(initrd)
+}
+
+/// Obtain the initrd with the metadata of the cloud instance if the stub was built with the
+/// `.imds` section, see [`linux_bootloader::instance_metadata`].
+///
+/// It is measured like the initrd from fw_cfg, since it does not come from the image either.
+pub fn get_instance_metadata_initrd(instance_metadata: bool) -> Option<Vec<u8>> {
+    if !instance_metadata {
+        return None;
+    }
+
+    let initrd = instance_metadata_initrd()?;
+    info!("Adding the metadata of the instance as credentials.");
+    let _ = measure_initrd(&initrd);
+    Some(initrd)
+}
+
+fn base_cmdline(embedded: &CStr16, secure_boot_enabled: bool) -> Vec<u8> {
     if secure_boot_enabled {
         // The command line passed from the bootloader cannot be trusted, so it is not used when Secure Boot is active.
-        embedded.as_bytes().to_vec()
+        embedded_cmdline(embedded)
     } else {
         let passed = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
             .map(|loaded_image| loaded_image.load_options_as_bytes().map(|b| b.to_vec()));
         match passed {
             Ok(Some(passed)) => passed,
             // If anything went wrong, fall back to the embedded command line.
-            _ => embedded.as_bytes().to_vec(),
+            _ => embedded_cmdline(embedded),
+        }
+    }
+}
+
+/// The embedded command line with its placeholders substituted, see
+/// [`linux_bootloader::cmdline_template`].
+fn embedded_cmdline(embedded: &CStr16) -> Vec<u8> {
+    let template = embedded.to_string();
+    if !has_placeholders(&template) {
+        return embedded.as_bytes().to_vec();
+    }
+
+    let cmdline = TemplateVariables::from_firmware().expand(&template);
+    // The template itself is measured with the other sections, but not the substituted values.
+    let _ = measure_cmdline(&cmdline);
+    to_utf16_bytes(&cmdline)
+}
+
+/// Refuse to boot a revoked kernel or initrd.
+///
+/// Like a hash mismatch, this is only enforced if the Secure Boot policy is.
+pub fn check_revoked(
+    hash: Hash,
+    revoked_hashes: &[Hash],
+    name: &str,
+    secure_boot: bool,
+) -> uefi::Result<()> {
+    if revoked_hashes.contains(&hash) {
+        if secure_boot {
+            error!("{}", Message::Revoked(name));
+            return Err(Status::SECURITY_VIOLATION.into());
+        } else {
+            warn!("{} {}", Message::Revoked(name), Message::ContinuingAnyway);
         }
     }
+    Ok(())
 }
 
 /// Check whether Secure Boot is active, and we should be enforcing integrity checks.
@@ -73,22 +280,82 @@ pub fn get_secure_boot_status() -> bool {
     secure_boot_enabled
 }
 
+/// The watchdog code that identifies resets caused by the stub's watchdog.
+///
+/// Codes up to 0xFFFF are reserved for the firmware.
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// Arm the watchdog, replacing the timeout of the firmware. A timeout of 0 disables it.
+///
+/// The firmware resets the machine if the timeout expires before the kernel exits boot services.
+/// As `ExitBootServices()` stops the watchdog, a hang later in the boot has to be caught by a
+/// hardware watchdog.
+fn arm_watchdog(timeout: u64) {
+    let timeout = usize::try_from(timeout).unwrap_or(usize::MAX);
+    if boot::set_watchdog_timer(timeout, WATCHDOG_CODE, None).is_err() {
+        warn!("Failed to arm the watchdog.");
+    }
+}
+
 /// Boot the Linux kernel without checking the PE signature.
 ///
 /// We assume that the caller has made sure that the image is safe to
 /// be loaded using other means.
+///
+/// EFI zboot kernels are unpacked first, see [`linux_bootloader::zboot`]. The kernel is started
+/// as a PE image, or with the EFI handover protocol if that fails and the kernel supports it, see
+/// [`linux_bootloader::efi_handover`].
+///
+/// If a watchdog timeout is given, the watchdog is armed right before the kernel is started.
 pub fn boot_linux_unchecked(
     handle: Handle,
     kernel_data: Vec<u8>,
     kernel_cmdline: &[u8],
-    initrd_data: Vec<u8>,
-) -> uefi::Result<()> {
-    let kernel = Image::load(&kernel_data).expect("Failed to load the kernel");
+    initrd_data: Zeroizing,
+    watchdog_timeout: Option<u64>,
+) -> error::Result<()> {
+    let kernel_data = zboot::unpack(kernel_data).context("Decompressing the kernel")?;
+    let setup_header =
+        SetupHeader::parse(&kernel_data).context("Parsing the setup header of the kernel")?;
+    let placement = setup_header
+        .map(|header| header.kernel_placement())
+        .unwrap_or_default();
 
-    let mut initrd_loader = InitrdLoader::new(handle, initrd_data)?;
+    let kernel = match Image::load(&kernel_data, placement) {
+        Ok(kernel) => kernel,
+        Err(error) => {
+            // Kernels whose PE image cannot be loaded may still support the EFI handover
+            // protocol, which does not load the kernel as a PE image.
+            let Some(header) = setup_header.filter(SetupHeader::supports_efi_handover) else {
+                return Err(error).context("Loading the kernel");
+            };
+            warn!(
+                "Failed to load the kernel as a PE image ({:?}). Using the EFI handover protocol.",
+                error.status()
+            );
+            log_file::flush();
+            if let Some(timeout) = watchdog_timeout {
+                arm_watchdog(timeout);
+            }
+            return unsafe {
+                efi_handover::start(handle, &kernel_data, &header, kernel_cmdline, &initrd_data)
+            }
+            .context("Starting the kernel with the EFI handover protocol");
+        }
+    };
+
+    let mut initrd_loader =
+        InitrdLoader::new(handle, initrd_data).context("Installing the initrd loader")?;
+
+    log_file::flush();
+    if let Some(timeout) = watchdog_timeout {
+        arm_watchdog(timeout);
+    }
 
     let status = unsafe { kernel.start(handle, kernel_cmdline) };
 
-    initrd_loader.uninstall()?;
-    status.to_result()
+    initrd_loader
+        .uninstall()
+        .context("Uninstalling the initrd loader")?;
+    status.to_result().context("Starting the kernel")
 }
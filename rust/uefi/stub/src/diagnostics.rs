@@ -0,0 +1,92 @@
+//! A diagnostic screen that can be requested by pressing a key when the stub starts.
+//!
+//! It shows everything that is needed to debug a failing boot without access to a serial console:
+//! the embedded configuration, the Secure Boot status and the PCR values.
+
+use alloc::{format, string::String};
+use core::fmt::Write;
+use uefi::println;
+
+use crate::common::get_secure_boot_status;
+use crate::STUB_NAME;
+use linux_bootloader::console::{print_wrapped, select_best_mode};
+use linux_bootloader::input::{self, Chord};
+use linux_bootloader::pe_section::pe_sections;
+use linux_bootloader::tpm::tpm_read_pcrs;
+
+/// The key that requests the diagnostic screen.
+const DIAGNOSTIC_KEY: char = 'd';
+
+/// Sections that contain a SHA256 hash.
+const HASH_SECTIONS: [&str; 2] = [".linuxh", ".initrdh"];
+
+/// Embedded strings longer than this are most likely not strings, e.g. an embedded kernel.
+const MAX_STRING_LENGTH: usize = 512;
+
+/// The PCRs that are shown: kernel image, kernel configuration and system extensions.
+const SHOWN_PCRS: [u32; 3] = [11, 12, 13];
+
+/// Check whether the diagnostic key is pressed, or was pressed while the firmware was starting.
+pub fn diagnostics_requested() -> bool {
+    input::pending(Chord::Key(DIAGNOSTIC_KEY))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Print the diagnostic screen and wait for a key press.
+pub fn show_diagnostics(pe_data: &[u8]) {
+    // Long command lines are easier to read with more columns.
+    select_best_mode();
+    println!("{STUB_NAME} diagnostics");
+    println!();
+    println!("Secure Boot active: {}", get_secure_boot_status());
+    println!();
+
+    println!("Embedded configuration:");
+    match pe_sections(pe_data) {
+        Some(sections) => {
+            for (name, data) in sections {
+                // Skip the sections of the stub itself.
+                if matches!(
+                    name.as_str(),
+                    ".text" | ".data" | ".rdata" | ".reloc" | ".pdata"
+                ) {
+                    continue;
+                }
+
+                if HASH_SECTIONS.contains(&name.as_str()) {
+                    println!("  {name}: {}", hex(data));
+                } else {
+                    match core::str::from_utf8(data) {
+                        Ok(s) if s.len() <= MAX_STRING_LENGTH => {
+                            print_wrapped(&format!("  {name}: {}", s.trim_end()), 4)
+                        }
+                        _ => println!("  {name}: {} bytes", data.len()),
+                    }
+                }
+            }
+        }
+        None => println!("  Failed to parse the stub image."),
+    }
+    println!();
+
+    println!("PCR values (SHA256):");
+    match tpm_read_pcrs(&SHOWN_PCRS) {
+        Ok(values) if !values.is_empty() => {
+            for (index, value) in values {
+                println!("  {index:>2}: {}", hex(&value));
+            }
+        }
+        Ok(_) => println!("  The SHA256 bank of the TPM is not active."),
+        Err(_) => println!("  No TPM available."),
+    }
+    println!();
+
+    println!("Press any key to continue booting.");
+    input::read_key(None);
+}
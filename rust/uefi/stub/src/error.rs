@@ -0,0 +1,85 @@
+//! The errors that stop the stub from booting.
+//!
+//! Every error names the step that failed, so that a failed boot can be diagnosed from the
+//! console instead of a panic message.
+
+use alloc::format;
+use core::fmt;
+use core::time::Duration;
+
+use linux_bootloader::console::print_wrapped;
+use linux_bootloader::error_code::ErrorCode;
+use linux_bootloader::input;
+use linux_bootloader::log_file;
+use linux_bootloader::messages::Message;
+use log::error;
+use uefi::Status;
+
+/// How long the error stays on the screen before the firmware takes over again, unless a key is
+/// pressed.
+const ERROR_DISPLAY_TIME: Duration = Duration::from_secs(10);
+
+/// A failed step of the boot and the status it failed with.
+#[derive(Debug)]
+pub struct BootError {
+    step: &'static str,
+    status: Status,
+}
+
+pub type Result<T> = core::result::Result<T, BootError>;
+
+impl BootError {
+    pub fn new(step: &'static str, status: Status) -> Self {
+        Self { step, status }
+    }
+
+    /// Print the error and return its status, which is returned to the firmware.
+    pub fn report(&self) -> Status {
+        error!("{}: {self}", Message::FailedToBoot);
+        ErrorCode::step(self.step).show();
+        log_file::flush();
+        print_wrapped(
+            &format!("{}", Message::DismissError(ERROR_DISPLAY_TIME.as_secs())),
+            0,
+        );
+        input::flush();
+        input::read_key(Some(ERROR_DISPLAY_TIME));
+        self.status
+    }
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.step, self.status)
+    }
+}
+
+/// Errors without context are attributed to an unknown step.
+impl<Data: fmt::Debug> From<uefi::Error<Data>> for BootError {
+    fn from(error: uefi::Error<Data>) -> Self {
+        Self::new("Unknown step", error.status())
+    }
+}
+
+/// Attach the step that failed to an error.
+pub trait Context<T> {
+    fn context(self, step: &'static str) -> Result<T>;
+}
+
+impl<T, Data: fmt::Debug> Context<T> for core::result::Result<T, uefi::Error<Data>> {
+    fn context(self, step: &'static str) -> Result<T> {
+        self.map_err(|error| BootError::new(step, error.status()))
+    }
+}
+
+impl<T> Context<T> for core::result::Result<T, Status> {
+    fn context(self, step: &'static str) -> Result<T> {
+        self.map_err(|status| BootError::new(step, status))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context(self, step: &'static str) -> Result<T> {
+        self.ok_or(BootError::new(step, Status::NOT_FOUND))
+    }
+}
@@ -1,58 +1,108 @@
 use alloc::vec::Vec;
-use uefi::{prelude::*, CString16, Result};
+use log::warn;
+use uefi::prelude::*;
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
-use linux_bootloader::pe_section::pe_section;
+use crate::common::{
+    boot_linux_unchecked, check_revoked, get_cmdline, get_fw_cfg_initrd,
+    get_instance_metadata_initrd, get_secure_boot_policy,
+};
+use crate::error::{self, Context};
+use linux_bootloader::addons::{extend_cmdline, Addon};
+use linux_bootloader::clock::check_clock;
+use linux_bootloader::embedded_config::{payload_hash, FatConfiguration};
+use linux_bootloader::hibernate::check_resume;
+use linux_bootloader::insecure_boot::check_insecure_boot;
+use linux_bootloader::lockdown::{enforce_policy, parse_policy};
+use linux_bootloader::security_version::check_security_version;
 use linux_bootloader::uefi_helpers::booted_image_file;
+use linux_bootloader::verity::enforce_root_hash;
+use linux_bootloader::zeroize::Zeroizing;
 
-/// Extract bytes from a PE section.
-pub fn extract_bytes(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
-    let bytes: Vec<u8> = pe_section(pe_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .into();
+pub fn boot_linux(
+    handle: Handle,
+    mut dynamic_initrds: Vec<Zeroizing>,
+    addons: &[Addon],
+) -> error::Result<()> {
+    let image = booted_image_file().context("Locating the stub in memory")?;
+    // SAFETY: We get a slice that represents our currently running
+    // image and then parse the PE data structures from it. This is
+    // safe, because we don't touch any data in the data sections that
+    // might conceivably change while we look at the slice.
+    let mut config = unsafe { FatConfiguration::new(image.as_slice()) }
+        .context("Reading the embedded configuration")?;
 
-    Ok(bytes)
-}
+    let secure_boot_enabled = get_secure_boot_policy(config.simulate_secure_boot);
+    if !secure_boot_enabled {
+        check_insecure_boot(config.insecure_boot_policy)
+            .context("Checking the policy for booting without Secure Boot")?;
+    }
 
-/// The configuration that is embedded at build time.
-///
-/// After this stub is built, configuration need to be embedded into the binary by adding PE
-/// sections. This struct represents that information.
-struct EmbeddedConfiguration {
-    /// The kernel command-line.
-    cmdline: CString16,
+    // A wrong clock is not a reason to refuse booting, so this only warns.
+    if let Some(not_before) = config.not_before {
+        if let Err(err) = check_clock(not_before, config.set_clock) {
+            warn!("Failed to set the firmware clock: {}", err.status());
+        }
+    }
 
-    /// The kernel as raw bytes.
-    kernel: Vec<u8>,
+    check_security_version(
+        config.security_version,
+        config.minimum_security_version,
+        secure_boot_enabled,
+    )
+    .context("Checking the security version")?;
 
-    /// The initrd as raw bytes.
-    initrd: Vec<u8>,
-}
+    check_resume(
+        config.kernel_release.as_deref(),
+        config.refuse_mismatched_resume,
+    )
+    .context("Checking the hibernated kernel")?;
 
-impl EmbeddedConfiguration {
-    fn new(file_data: &[u8]) -> Result<Self> {
-        Ok(Self {
-            kernel: extract_bytes(file_data, ".linux")?,
-            initrd: extract_bytes(file_data, ".initrd")?,
-            cmdline: extract_string(file_data, ".cmdline")?,
-        })
-    }
-}
+    check_revoked(
+        payload_hash(&config.kernel),
+        &config.revoked_hashes,
+        "Kernel",
+        secure_boot_enabled,
+    )
+    .context("Checking whether the kernel is revoked")?;
+    check_revoked(
+        payload_hash(&config.initrd),
+        &config.revoked_hashes,
+        "Initrd",
+        secure_boot_enabled,
+    )
+    .context("Checking whether the initrd is revoked")?;
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> Status {
-    // SAFETY: We get a slice that represents our currently running
-    // image and then parse the PE data structures from it. This is
-    // safe, because we don't touch any data in the data sections that
-    // might conceivably change while we look at the slice.
-    let mut config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary.")
+    let cmdline = extend_cmdline(
+        &get_cmdline(
+            &config.cmdline,
+            secure_boot_enabled,
+            config.allow_smbios_cmdline,
+            config.allow_fw_cfg,
+        ),
+        addons,
+    );
+    let cmdline = match &config.verity_root_hash {
+        Some(root_hash) => enforce_root_hash(cmdline, root_hash),
+        None => cmdline,
     };
+    let cmdline = match &config.lockdown_policy {
+        Some(policy) => enforce_policy(
+            cmdline,
+            &parse_policy(policy)
+                .ok_or(Status::LOAD_ERROR)
+                .context("Parsing the lockdown policy")?,
+        ),
+        None => cmdline,
+    };
+    dynamic_initrds
+        .extend(get_instance_metadata_initrd(config.instance_metadata).map(Zeroizing::new));
+    dynamic_initrds
+        .extend(get_fw_cfg_initrd(secure_boot_enabled, config.allow_fw_cfg).map(Zeroizing::new));
 
-    let secure_boot_enabled = get_secure_boot_status();
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
-
-    let mut final_initrd = Vec::new();
+    // Allocated once, so that no copy of the credentials is left behind when it grows.
+    let mut final_initrd = Zeroizing::new(Vec::with_capacity(
+        config.initrd.len() + dynamic_initrds.iter().map(|i| i.len()).sum::<usize>(),
+    ));
     final_initrd.append(&mut config.initrd);
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
@@ -63,5 +113,11 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> Status {
         final_initrd.append(&mut extra_initrd);
     }
 
-    boot_linux_unchecked(handle, config.kernel, &cmdline, final_initrd).status()
+    boot_linux_unchecked(
+        handle,
+        config.kernel,
+        &cmdline,
+        final_initrd,
+        config.watchdog_timeout,
+    )
 }
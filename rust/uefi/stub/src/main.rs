@@ -5,6 +5,9 @@
 extern crate alloc;
 
 mod common;
+mod diagnostics;
+mod error;
+mod panic;
 
 #[cfg(feature = "fat")]
 mod fat;
@@ -16,13 +19,24 @@ mod thin;
 compile_error!("A thin and fat stub cannot be produced at the same time, disable either `thin` or `fat` feature");
 
 use alloc::vec::Vec;
+use error::Context;
+use linux_bootloader::addons::{discover_addons, discover_global_addons, Addon};
 use linux_bootloader::companions::{
     discover_credentials, discover_system_extensions, get_default_dropin_directory,
 };
-use linux_bootloader::efivars::{export_efi_variables, get_loader_features, EfiLoaderFeatures};
-use linux_bootloader::measure::{measure_companion_initrds, measure_image};
+use linux_bootloader::console::init_logger;
+use linux_bootloader::efivars::{
+    export_attempted_entry, export_efi_variables, get_loader_features, EfiLoaderFeatures,
+};
+use linux_bootloader::first_stage::boot_systemd_boot;
+use linux_bootloader::measure::{measure_addons, measure_companion_initrds, measure_image};
+use linux_bootloader::pe_section::pe_section;
+use linux_bootloader::pkcs7::TrustPolicy;
+use linux_bootloader::slots::boot_slots;
+use linux_bootloader::splash::draw_splash;
 use linux_bootloader::tpm::tpm_available;
 use linux_bootloader::uefi_helpers::booted_image_file;
+use linux_bootloader::zeroize::Zeroizing;
 use log::{info, warn};
 use uefi::boot;
 use uefi::prelude::*;
@@ -47,13 +61,31 @@ fn print_logo() {
 
 #[entry]
 fn main() -> Status {
-    uefi::helpers::init().unwrap();
+    // Without logging, there is no way to report an error.
+    if init_logger().is_err() {
+        return Status::ABORTED;
+    }
+
+    let pe_in_memory = match booted_image_file().context("Locating the stub in memory") {
+        Ok(pe_in_memory) => pe_in_memory,
+        Err(err) => return err.report(),
+    };
+
+    // SAFETY: We only look at the PE data structures of our own image, which don't change while
+    // we look at them. See `ThinConfiguration` for details.
+    let pe_data = unsafe { pe_in_memory.as_slice() };
+    common::setup_console(pe_data);
 
     print_logo();
 
+    // Without an embedded splash, whatever the firmware displays (e.g. its BGRT logo) is kept.
+    if let Some(splash) = pe_section(pe_data, ".splash") {
+        if draw_splash(splash).is_err() {
+            warn!("Failed to draw the splash image.");
+        }
+    }
+
     let is_tpm_available = tpm_available();
-    let pe_in_memory = booted_image_file()
-        .expect("Failed to extract the in-memory information about our own image");
 
     if is_tpm_available {
         info!("TPM available, will proceed to measurements.");
@@ -75,10 +107,48 @@ fn main() -> Status {
         warn!("Failed to export stub EFI variables, some features related to measured boot will not be available");
     }
 
-    let status;
+    // A slot selector only chainloads the payload of a slot, which is a stub on its own.
+    if let Some(slots) = pe_section(pe_data, ".abslots") {
+        return match boot_slots(slots).context("Booting an A/B slot") {
+            Ok(()) => Status::SUCCESS,
+            Err(err) => err.report(),
+        };
+    }
+
+    // A first stage only chainloads systemd-boot, which then boots the stubs of the generations.
+    if pe_section(pe_data, ".sdboot").is_some() {
+        let secure_boot = common::get_secure_boot_policy(pe_section(pe_data, ".sbsim").is_some());
+        return match boot_systemd_boot(pe_data, secure_boot).context("Booting systemd-boot") {
+            Ok(()) => Status::SUCCESS,
+            Err(err) => err.report(),
+        };
+    }
+
+    if let Some(image_path) = pe_in_memory.file_path() {
+        if export_attempted_entry(image_path).is_err() {
+            warn!("Failed to record the booted entry, `lzbt mark-good` will not be able to confirm it");
+        }
+    }
+
+    // This comes after the measurement of the sections of the stub, so that the diagnostic screen
+    // shows PCR 11 as the kernel sees it. Addons, credentials and the initrds from fw_cfg and the
+    // instance metadata are only measured afterwards, so PCR 12 and 13 may still change.
+    if diagnostics::diagnostics_requested() {
+        diagnostics::show_diagnostics(pe_data);
+    }
+
+    // Addons and credentials must be signed by these certificates instead of a key in `db`.
+    let trust_policy = pe_section(pe_data, ".authcert")
+        .map(|authcert| TrustPolicy::parse(authcert, pe_section(pe_data, ".auththreshold")));
+    let secure_boot_simulated =
+        common::is_secure_boot_simulated(pe_section(pe_data, ".sbsim").is_some());
+
+    let result;
     // A list of dynamically assembled initrds, e.g. credential initrds or system extension
     // initrds.
-    let mut dynamic_initrds: Vec<Vec<u8>> = Vec::new();
+    let mut dynamic_initrds: Vec<Zeroizing> = Vec::new();
+    // Verified addons whose command lines extend the embedded one.
+    let mut addons: Vec<Addon> = Vec::new();
 
     {
         // This is a block for doing filesystem operations once and for all, related to companion
@@ -108,12 +178,23 @@ fn main() -> Status {
             if let Ok(mut system_credentials) = discover_credentials(
                 &mut filesystem,
                 default_dropin_directory.as_ref().map(|x| x.as_ref()),
+                trust_policy.as_ref(),
             ) {
                 companions.append(&mut system_credentials);
             } else {
                 warn!("Failed to discover any system credential");
             }
 
+            if let Ok(mut global_addons) = discover_global_addons(
+                &mut filesystem,
+                trust_policy.as_ref(),
+                secure_boot_simulated,
+            ) {
+                addons.append(&mut global_addons);
+            } else {
+                warn!("Failed to discover any global addon");
+            }
+
             if let Some(default_dropin_dir) = default_dropin_directory {
                 if let Ok(mut system_extensions) =
                     discover_system_extensions(&mut filesystem, &default_dropin_dir)
@@ -122,20 +203,37 @@ fn main() -> Status {
                 } else {
                     warn!("Failed to discover any system extension");
                 }
+
+                if let Ok(mut discovered_addons) = discover_addons(
+                    &mut filesystem,
+                    &default_dropin_dir,
+                    trust_policy.as_ref(),
+                    secure_boot_simulated,
+                ) {
+                    addons.append(&mut discovered_addons);
+                } else {
+                    warn!("Failed to discover any addon");
+                }
             }
 
             if is_tpm_available {
                 // TODO: in the future, devise a threat model where this can fail, see above
                 // measurements to understand the context.
                 let _ = measure_companion_initrds(&companions);
+                let _ = measure_addons(&addons);
             }
 
             dynamic_initrds.append(
                 &mut companions
                     .into_iter()
-                    .map(|initrd| initrd.cpio.into_inner())
+                    .map(|initrd| Zeroizing::new(initrd.cpio.into_inner()))
                     .collect(),
             );
+            dynamic_initrds.extend(
+                addons
+                    .iter_mut()
+                    .filter_map(|addon| addon.initrd.take().map(Zeroizing::new)),
+            );
         } else {
             warn!("Failed to open the simple filesystem for the booted image, this is expected for netbooted systems, skipping companion extension...");
         }
@@ -143,13 +241,16 @@ fn main() -> Status {
 
     #[cfg(feature = "fat")]
     {
-        status = fat::boot_linux(boot::image_handle(), dynamic_initrds)
+        result = fat::boot_linux(boot::image_handle(), dynamic_initrds, &addons)
     }
 
     #[cfg(feature = "thin")]
     {
-        status = thin::boot_linux(boot::image_handle(), dynamic_initrds).status()
+        result = thin::boot_linux(boot::image_handle(), dynamic_initrds, &addons)
     }
 
-    status
+    match result {
+        Ok(()) => Status::SUCCESS,
+        Err(err) => err.report(),
+    }
 }
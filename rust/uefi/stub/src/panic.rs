@@ -0,0 +1,42 @@
+//! What the stub does when it panics.
+//!
+//! Instead of a raw panic message and a shutdown, the stub shows the error code of the panic and
+//! a link to its documentation, see [`linux_bootloader::error_code`]. After a key press or a
+//! timeout, it returns to the firmware, which usually continues with the next boot entry. The
+//! stub never exits the boot services itself, so they are always available here.
+
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use linux_bootloader::error_code::ErrorCode;
+use linux_bootloader::input;
+use linux_bootloader::log_file;
+use log::error;
+use uefi::{boot, Status};
+
+/// How long the panic stays on the screen without a key press.
+const PANIC_DISPLAY_TIME: Duration = Duration::from_secs(60);
+
+/// Whether the stub is already panicking, e.g. because showing a panic panicked as well.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        error!("{info}");
+        let code = info
+            .location()
+            .map(|location| ErrorCode::panic(location.file(), location.line()))
+            .unwrap_or_else(|| ErrorCode::panic("", 0));
+        code.show();
+        log_file::flush();
+        input::flush();
+        input::read_key(Some(PANIC_DISPLAY_TIME));
+    }
+
+    // SAFETY: Nothing is used after returning to the firmware, which closes the protocols that the
+    // stub opened and frees its memory.
+    unsafe { boot::exit(boot::image_handle(), Status::ABORTED, 0, ptr::null_mut()) }
+}
@@ -1,124 +1,217 @@
 use alloc::vec;
 use alloc::vec::Vec;
 use log::{error, warn};
-use sha2::{Digest, Sha256};
-use uefi::{fs::FileSystem, prelude::*, CString16, Result};
-
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
-use linux_bootloader::pe_section::pe_section;
+use uefi::prelude::*;
+
+use crate::common::{
+    boot_linux_unchecked, check_revoked, get_cmdline, get_fw_cfg_initrd,
+    get_instance_metadata_initrd, get_secure_boot_policy,
+};
+use crate::error::{self, Context};
+use linux_bootloader::addons::{extend_cmdline, Addon};
+use linux_bootloader::chunked_read::read_hashed;
+use linux_bootloader::clock::check_clock;
+use linux_bootloader::drivers::{load_drivers, reconnect_all};
+use linux_bootloader::embedded_config::{Hash, ThinConfiguration};
+use linux_bootloader::hibernate::check_resume;
+use linux_bootloader::initrd_encryption::decrypt_initrd;
+use linux_bootloader::insecure_boot::check_insecure_boot;
+use linux_bootloader::lockdown::{enforce_policy, parse_policy};
+use linux_bootloader::luks::unlock_partition;
+use linux_bootloader::messages::Message;
+use linux_bootloader::partition::open_partition;
+use linux_bootloader::security_version::check_security_version;
 use linux_bootloader::uefi_helpers::booted_image_file;
+use linux_bootloader::verity::enforce_root_hash;
+use linux_bootloader::zeroize::Zeroizing;
 
-type Hash = sha2::digest::Output<Sha256>;
-
-/// The configuration that is embedded at build time.
-///
-/// After this stub is built, lzbt needs to embed configuration into the binary by adding PE
-/// sections. This struct represents that information.
-struct EmbeddedConfiguration {
-    /// The filename of the kernel to be booted. This filename is
-    /// relative to the root of the volume that contains the
-    /// lanzaboote binary.
-    kernel_filename: CString16,
-
-    /// The cryptographic hash of the kernel.
-    kernel_hash: Hash,
-
-    /// The filename of the initrd to be passed to the kernel. See
-    /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
-
-    /// The cryptographic hash of the initrd. This hash is computed
-    /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
-
-    /// The kernel command-line.
-    cmdline: CString16,
-}
-
-/// Extract a SHA256 hash from a PE section.
-fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
-    let array: [u8; 32] = pe_section(pe_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .try_into()
-        .map_err(|_| Status::INVALID_PARAMETER)?;
-
-    Ok(array.into())
-}
-
-impl EmbeddedConfiguration {
-    fn new(file_data: &[u8]) -> Result<Self> {
-        Ok(Self {
-            kernel_filename: extract_string(file_data, ".linux")?,
-            kernel_hash: extract_hash(file_data, ".linuxh")?,
-
-            initrd_filename: extract_string(file_data, ".initrd")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
-
-            cmdline: extract_string(file_data, ".cmdline")?,
-        })
-    }
-}
-
-/// Verify some data against its expected hash.
+/// Verify the hash of some data, which was computed while reading it, against its expected hash.
 ///
 /// In case of a mismatch:
 /// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
 /// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
-fn check_hash(data: &[u8], expected_hash: Hash, name: &str, secure_boot: bool) -> uefi::Result<()> {
-    let hash_correct = Sha256::digest(data) == expected_hash;
-    if !hash_correct {
+fn check_hash(hash: Hash, expected_hash: Hash, name: &str, secure_boot: bool) -> uefi::Result<()> {
+    if hash != expected_hash {
         if secure_boot {
-            error!("{name} hash does not match!");
+            error!("{}", Message::HashMismatch(name));
             return Err(Status::SECURITY_VIOLATION.into());
         } else {
-            warn!("{name} hash does not match! Continuing anyway.");
+            warn!(
+                "{} {}",
+                Message::HashMismatch(name),
+                Message::ContinuingAnyway
+            );
         }
     }
     Ok(())
 }
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result<()> {
+/// Connects the devices that the firmware did not connect for its own boot.
+///
+/// The payload partition may only appear afterwards, e.g. because it is on the LUN of an iSCSI
+/// target, which the iSCSI initiator of the firmware logs in to when the network card is
+/// connected, or because its file system needs one of the drivers of the `.drivers` section.
+struct DeviceConnector<'a> {
+    drivers: Option<&'a [u8]>,
+    connected: bool,
+}
+
+impl DeviceConnector<'_> {
+    /// Call `open` again after connecting all devices if it did not find the partition.
+    fn retry<T>(&mut self, open: impl Fn() -> uefi::Result<T>) -> uefi::Result<T> {
+        match open() {
+            Err(err) if err.status() == Status::NOT_FOUND && !self.connected => {
+                self.connected = true;
+                if let Some(drivers) = self.drivers {
+                    load_drivers(drivers)?;
+                }
+                reconnect_all()?;
+                open()
+            }
+            result => result,
+        }
+    }
+}
+
+pub fn boot_linux(
+    handle: Handle,
+    mut dynamic_initrds: Vec<Zeroizing>,
+    addons: &[Addon],
+) -> error::Result<()> {
+    let image = booted_image_file().context("Locating the stub in memory")?;
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary. Did you run lzbt?")
-    };
+    let config = unsafe { ThinConfiguration::new(image.as_slice()) }
+        .context("Reading the embedded configuration. Did you run lzbt?")?;
 
-    let secure_boot_enabled = get_secure_boot_status();
+    let secure_boot_enabled = get_secure_boot_policy(config.simulate_secure_boot);
+    if !secure_boot_enabled {
+        check_insecure_boot(config.insecure_boot_policy)
+            .context("Checking the policy for booting without Secure Boot")?;
+    }
 
-    let kernel_data;
-    let mut initrd_data;
+    // A wrong clock is not a reason to refuse booting, so this only warns.
+    if let Some(not_before) = config.not_before {
+        if let Err(err) = check_clock(not_before, config.set_clock) {
+            warn!("Failed to set the firmware clock: {}", err.status());
+        }
+    }
+
+    check_security_version(
+        config.security_version,
+        config.minimum_security_version,
+        secure_boot_enabled,
+    )
+    .context("Checking the security version")?;
+
+    check_resume(
+        config.kernel_release.as_deref(),
+        config.refuse_mismatched_resume,
+    )
+    .context("Checking the hibernated kernel")?;
+
+    // The files are checked against these hashes below, so they are known before reading them.
+    check_revoked(
+        config.kernel_hash,
+        &config.revoked_hashes,
+        "Kernel",
+        secure_boot_enabled,
+    )
+    .context("Checking whether the kernel is revoked")?;
+    check_revoked(
+        config.initrd_hash,
+        &config.revoked_hashes,
+        "Initrd",
+        secure_boot_enabled,
+    )
+    .context("Checking whether the initrd is revoked")?;
+
+    let (kernel_data, kernel_hash);
+    let (mut initrd_data, initrd_hash);
+
+    // The decrypted partition and its key stay in memory only until the files are read.
+    let mut connector = DeviceConnector {
+        drivers: config.drivers.as_deref(),
+        connected: false,
+    };
+    let unlocked = match (&config.payload_partition, &config.luks) {
+        (Some(selector), Some(luks)) => Some(
+            connector
+                .retry(|| unlock_partition(selector, luks))
+                .context("Unlocking the encrypted partition of the kernel and initrd")?,
+        ),
+        _ => None,
+    };
 
     {
-        let file_system =
-            uefi::boot::get_image_file_system(handle).expect("Failed to get file system handle");
-        let mut file_system = FileSystem::new(file_system);
-
-        kernel_data = file_system
-            .read(&*config.kernel_filename)
-            .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
+        let mut file_system = match &config.payload_partition {
+            Some(selector) => connector
+                .retry(|| open_partition(selector))
+                .context("Opening the partition of the kernel and initrd")?,
+            None => uefi::boot::get_image_file_system(handle)
+                .context("Opening the file system of the stub")?,
+        };
+        let mut volume = file_system
+            .open_volume()
+            .context("Opening the volume of the stub")?;
+
+        // Large initrds are read in chunks and hashed on the way, see `read_hashed`.
+        (kernel_data, kernel_hash) = read_hashed(&mut volume, &config.kernel_filename, "kernel")
+            .context("Reading the kernel")?;
+        let initrd;
+        (initrd, initrd_hash) = read_hashed(&mut volume, &config.initrd_filename, "initrd")
+            .context("Reading the initrd")?;
+        initrd_data = Zeroizing::new(initrd);
     }
-
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
+    drop(unlocked);
+
+    let cmdline = extend_cmdline(
+        &get_cmdline(
+            &config.cmdline,
+            secure_boot_enabled,
+            config.allow_smbios_cmdline,
+            config.allow_fw_cfg,
+        ),
+        addons,
+    );
+    let cmdline = match &config.verity_root_hash {
+        Some(root_hash) => enforce_root_hash(cmdline, root_hash),
+        None => cmdline,
+    };
+    let cmdline = match &config.lockdown_policy {
+        Some(policy) => enforce_policy(
+            cmdline,
+            &parse_policy(policy)
+                .ok_or(Status::LOAD_ERROR)
+                .context("Parsing the lockdown policy")?,
+        ),
+        None => cmdline,
+    };
+    dynamic_initrds
+        .extend(get_instance_metadata_initrd(config.instance_metadata).map(Zeroizing::new));
+    dynamic_initrds
+        .extend(get_fw_cfg_initrd(secure_boot_enabled, config.allow_fw_cfg).map(Zeroizing::new));
 
     check_hash(
-        &kernel_data,
+        kernel_hash,
         config.kernel_hash,
         "Kernel",
         secure_boot_enabled,
-    )?;
+    )
+    .context("Verifying the kernel")?;
     check_hash(
-        &initrd_data,
+        initrd_hash,
         config.initrd_hash,
         "Initrd",
         secure_boot_enabled,
-    )?;
+    )
+    .context("Verifying the initrd")?;
+    if config.encrypted_initrd {
+        initrd_data =
+            Zeroizing::new(decrypt_initrd(&initrd_data).context("Decrypting the initrd")?);
+    }
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials
@@ -131,15 +224,31 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result
         vec![0u8; (4 - (len % 4)) % 4]
     }
 
-    initrd_data.append(&mut compute_pad4(initrd_data.len()));
+    // Allocated once, so that no copy of the credentials is left behind when it grows. Every
+    // initrd is padded by at most 3 bytes.
+    let mut final_initrd = Zeroizing::new(Vec::with_capacity(
+        dynamic_initrds.iter().map(|i| i.len() + 3).sum::<usize>() + initrd_data.len() + 3,
+    ));
+    final_initrd.append(&mut initrd_data);
+    let mut initrd_data = final_initrd;
+
+    let mut padding = compute_pad4(initrd_data.len());
+    initrd_data.append(&mut padding);
     for mut extra_initrd in dynamic_initrds {
         // Uncomment for maximal debugging pleasure.
         // let debug_representation = extra_initrd.as_slice().escape_ascii().collect::<Vec<u8>>();
         // log::warn!("{:?}", String::from_utf8_lossy(&debug_representation));
         initrd_data.append(&mut extra_initrd);
         // Extra initrds ideally should be aligned, but just in case, let's verify this.
-        initrd_data.append(&mut compute_pad4(initrd_data.len()));
+        let mut padding = compute_pad4(initrd_data.len());
+        initrd_data.append(&mut padding);
     }
 
-    boot_linux_unchecked(handle, kernel_data, &cmdline, initrd_data)
+    boot_linux_unchecked(
+        handle,
+        kernel_data,
+        &cmdline,
+        initrd_data,
+        config.watchdog_timeout,
+    )
 }